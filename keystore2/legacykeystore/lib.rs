@@ -16,8 +16,9 @@
 
 use android_security_legacykeystore::aidl::android::security::legacykeystore::{
     ILegacyKeystore::BnLegacyKeystore, ILegacyKeystore::ILegacyKeystore,
-    ILegacyKeystore::ERROR_ENTRY_NOT_FOUND, ILegacyKeystore::ERROR_PERMISSION_DENIED,
-    ILegacyKeystore::ERROR_SYSTEM_ERROR, ILegacyKeystore::UID_SELF,
+    ILegacyKeystore::ERROR_ENTRY_NOT_FOUND, ILegacyKeystore::ERROR_LOCKED,
+    ILegacyKeystore::ERROR_PERMISSION_DENIED, ILegacyKeystore::ERROR_SYSTEM_ERROR,
+    ILegacyKeystore::UID_SELF,
 };
 use android_security_legacykeystore::binder::{
     BinderFeatures, ExceptionCode, Result as BinderResult, Status as BinderStatus, Strong,
@@ -25,10 +26,12 @@ use android_security_legacykeystore::binder::{
 };
 use anyhow::{Context, Result};
 use keystore2::{
-    async_task::AsyncTask, error::anyhow_error_to_cstring, globals::SUPER_KEY,
+    async_task::AsyncTask, config, error::anyhow_error_to_cstring, globals::SUPER_KEY,
     legacy_blob::LegacyBlobLoader, maintenance::DeleteListener, maintenance::Domain,
-    utils::uid_to_android_user, utils::watchdog as wd,
+    permission::KeystorePerm, utils::check_keystore_permission, utils::uid_to_android_user,
+    utils::watchdog as wd, utils::AesGcm, utils::AesGcmKey,
 };
+use keystore2_selinux as selinux;
 use rusqlite::{
     params, Connection, OptionalExtension, Transaction, TransactionBehavior, NO_PARAMS,
 };
@@ -42,6 +45,13 @@ struct DB {
     conn: Connection,
 }
 
+/// An entry as stored in the database. If `encryption` is Some, `data` is the ciphertext and
+/// `encryption` holds the initialization vector and the AEAD tag.
+struct StoredEntry {
+    data: Vec<u8>,
+    encryption: Option<(Vec<u8>, Vec<u8>)>,
+}
+
 impl DB {
     fn new(db_file: &Path) -> Result<Self> {
         let mut db = Self {
@@ -98,6 +108,21 @@ impl DB {
                 NO_PARAMS,
             )
             .context("Failed to initialize \"profiles\" table.")?;
+
+            // Databases created before entries were encrypted lack the encryption columns.
+            let has_iv_column: i64 = tx
+                .query_row(
+                    "SELECT COUNT(*) FROM pragma_table_info('profiles') WHERE name = 'iv';",
+                    NO_PARAMS,
+                    |row| row.get(0),
+                )
+                .context("Failed to query \"profiles\" table columns.")?;
+            if has_iv_column == 0 {
+                tx.execute("ALTER TABLE profiles ADD COLUMN iv BLOB;", NO_PARAMS)
+                    .context("Failed to add iv column.")?;
+                tx.execute("ALTER TABLE profiles ADD COLUMN tag BLOB;", NO_PARAMS)
+                    .context("Failed to add tag column.")?;
+            }
             Ok(())
         })
     }
@@ -117,28 +142,63 @@ impl DB {
     }
 
     fn put(&mut self, caller_uid: u32, alias: &str, entry: &[u8]) -> Result<()> {
+        self.put_entry(caller_uid, alias, entry, None)
+    }
+
+    fn put_encrypted(
+        &mut self,
+        caller_uid: u32,
+        alias: &str,
+        ciphertext: &[u8],
+        iv: &[u8],
+        tag: &[u8],
+    ) -> Result<()> {
+        self.put_entry(caller_uid, alias, ciphertext, Some((iv, tag)))
+    }
+
+    fn put_entry(
+        &mut self,
+        caller_uid: u32,
+        alias: &str,
+        data: &[u8],
+        encryption: Option<(&[u8], &[u8])>,
+    ) -> Result<()> {
+        let (iv, tag) = match encryption {
+            Some((iv, tag)) => (Some(iv), Some(tag)),
+            None => (None, None),
+        };
         self.with_transaction(TransactionBehavior::Immediate, |tx| {
             tx.execute(
-                "INSERT OR REPLACE INTO profiles (owner, alias, profile) values (?, ?, ?)",
-                params![caller_uid, alias, entry,],
+                "INSERT OR REPLACE INTO profiles (owner, alias, profile, iv, tag)
+                 values (?, ?, ?, ?, ?)",
+                params![caller_uid, alias, data, iv, tag],
             )
-            .context("In put: Failed to insert or replace.")?;
+            .context("In put_entry: Failed to insert or replace.")?;
             Ok(())
         })
     }
 
-    fn get(&mut self, caller_uid: u32, alias: &str) -> Result<Option<Vec<u8>>> {
+    fn get_entry(&mut self, caller_uid: u32, alias: &str) -> Result<Option<StoredEntry>> {
         self.with_transaction(TransactionBehavior::Deferred, |tx| {
             tx.query_row(
-                "SELECT profile FROM profiles WHERE owner = ? AND alias = ?;",
+                "SELECT profile, iv, tag FROM profiles WHERE owner = ? AND alias = ?;",
                 params![caller_uid, alias],
-                |row| row.get(0),
+                |row| {
+                    let iv: Option<Vec<u8>> = row.get(1)?;
+                    let tag: Option<Vec<u8>> = row.get(2)?;
+                    Ok(StoredEntry { data: row.get(0)?, encryption: iv.zip(tag) })
+                },
             )
             .optional()
-            .context("In get: failed loading entry.")
+            .context("In get_entry: failed loading entry.")
         })
     }
 
+    #[cfg(test)]
+    fn get(&mut self, caller_uid: u32, alias: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self.get_entry(caller_uid, alias)?.map(|entry| entry.data))
+    }
+
     fn remove(&mut self, caller_uid: u32, alias: &str) -> Result<bool> {
         let removed = self.with_transaction(TransactionBehavior::Immediate, |tx| {
             tx.execute(
@@ -197,6 +257,11 @@ impl Error {
     pub fn perm() -> Self {
         Error::Error(ERROR_PERMISSION_DENIED)
     }
+
+    /// Short hand for `Error::Error(ERROR_LOCKED)`
+    pub fn locked() -> Self {
+        Error::Error(ERROR_LOCKED)
+    }
 }
 
 /// This function should be used by legacykeystore service calls to translate error conditions
@@ -206,7 +271,9 @@ impl Error {
 ///
 /// `Error::Error(x)` variants get mapped onto a service specific error code of `x`.
 ///
-/// All non `Error` error conditions get mapped onto `ERROR_SYSTEM_ERROR`.
+/// `selinux::Error::PermissionDenied` gets mapped onto `ERROR_PERMISSION_DENIED`.
+///
+/// All other non `Error` error conditions get mapped onto `ERROR_SYSTEM_ERROR`.
 ///
 /// `handle_ok` will be called if `result` is `Ok(value)` where `value` will be passed
 /// as argument to `handle_ok`. `handle_ok` must generate a `BinderResult<T>`, but it
@@ -222,7 +289,11 @@ where
                 // Make the entry not found errors silent.
                 Some(Error::Error(ERROR_ENTRY_NOT_FOUND)) => (ERROR_ENTRY_NOT_FOUND, false),
                 Some(Error::Error(e)) => (*e, true),
-                Some(Error::Binder(_, _)) | None => (ERROR_SYSTEM_ERROR, true),
+                Some(Error::Binder(_, _)) => (ERROR_SYSTEM_ERROR, true),
+                None => match root_cause.downcast_ref::<selinux::Error>() {
+                    Some(selinux::Error::PermissionDenied) => (ERROR_PERMISSION_DENIED, true),
+                    _ => (ERROR_SYSTEM_ERROR, true),
+                },
             };
             if log_error {
                 log::error!("{:?}", e);
@@ -268,6 +339,7 @@ impl LegacyKeystore {
 
     const WIFI_NAMESPACE: i64 = 102;
     const AID_WIFI: u32 = 1010;
+    const AID_APP_START: u32 = 10000;
 
     /// Creates a new LegacyKeystore instance.
     pub fn new_native_binder(
@@ -281,7 +353,10 @@ impl LegacyKeystore {
         let service = LegacyKeystoreService { legacy_keystore: legacy_keystore.clone() };
         (
             Box::new(LegacyKeystoreDeleteListener { legacy_keystore }),
-            BnLegacyKeystore::new_binder(service, BinderFeatures::default()),
+            BnLegacyKeystore::new_binder(
+                service,
+                BinderFeatures { set_requesting_sid: true, ..BinderFeatures::default() },
+            ),
         )
     }
 
@@ -289,6 +364,21 @@ impl LegacyKeystore {
         DB::new(&self.db_path).context("In open_db: Failed to open db.")
     }
 
+    /// The blob store is guarded by its own permission, so that policy can allow a client to
+    /// store blobs without allowing it to manage keys. Unless enforcement is configured, a
+    /// denial is only logged and access is governed by the uid checks alone, as before.
+    fn check_permission() -> Result<()> {
+        let result = check_keystore_permission(KeystorePerm::UseLegacyBlobStore)
+            .context("In check_permission: Caller may not use the blob store.");
+        match result {
+            Err(e) if !config::LEGACY_BLOB_STORE_PERMISSION_ENFORCED.get() => {
+                log::warn!("In check_permission: Permission not enforced yet: {:?}", e);
+                Ok(())
+            }
+            r => r,
+        }
+    }
+
     fn get_effective_uid(uid: i32) -> Result<u32> {
         const AID_SYSTEM: u32 = 1000;
         let calling_uid = ThreadState::get_calling_uid();
@@ -309,18 +399,68 @@ impl LegacyKeystore {
         }
     }
 
+    /// Returns the super key that protects the entries of the given uid, or None if the entries
+    /// of the uid are not encrypted. Entries of apps are encrypted with the per-boot super key
+    /// of the app's user. Entries of system components, i.e., the Wi-Fi configuration and the
+    /// VPN profiles of the system uid, must be available before the user unlocked the device
+    /// for the first time, so they are stored without encryption, as they were in the legacy
+    /// keystore.
+    fn get_super_key(uid: u32) -> Result<Option<Arc<dyn AesGcmKey + Send + Sync>>> {
+        if uid % rustutils::users::AID_USER_OFFSET < Self::AID_APP_START {
+            return Ok(None);
+        }
+        SUPER_KEY
+            .read()
+            .unwrap()
+            .get_per_boot_key_by_user_id(uid_to_android_user(uid))
+            .map(Some)
+            .ok_or_else(Error::locked)
+            .context("In get_super_key: No key found for user. Device may be locked.")
+    }
+
+    fn get_from_db(db: &mut DB, uid: u32, alias: &str) -> Result<Option<Vec<u8>>> {
+        let entry = match db.get_entry(uid, alias).context("In get_from_db: Loading entry.")? {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        match entry.encryption {
+            None => Ok(Some(entry.data)),
+            Some((iv, tag)) => {
+                let super_key = Self::get_super_key(uid)
+                    .context("In get_from_db.")?
+                    .ok_or_else(Error::sys)
+                    .context("In get_from_db: Encrypted entry of unencrypted namespace.")?;
+                let plaintext = super_key
+                    .decrypt(&entry.data, &iv, &tag)
+                    .context("In get_from_db: Failed to decrypt entry.")?;
+                Ok(Some(plaintext.to_vec()))
+            }
+        }
+    }
+
+    fn put_into_db(db: &mut DB, uid: u32, alias: &str, entry: &[u8]) -> Result<()> {
+        match Self::get_super_key(uid).context("In put_into_db.")? {
+            Some(super_key) => {
+                let (ciphertext, iv, tag) =
+                    super_key.encrypt(entry).context("In put_into_db: Failed to encrypt entry.")?;
+                db.put_encrypted(uid, alias, &ciphertext, &iv, &tag)
+            }
+            None => db.put(uid, alias, entry),
+        }
+        .context("In put_into_db: Trying to insert entry into DB.")
+    }
+
     fn get(&self, alias: &str, uid: i32) -> Result<Vec<u8>> {
+        Self::check_permission().context("In get.")?;
         let mut db = self.open_db().context("In get.")?;
         let uid = Self::get_effective_uid(uid).context("In get.")?;
 
-        if let Some(entry) = db.get(uid, alias).context("In get: Trying to load entry from DB.")? {
+        if let Some(entry) = Self::get_from_db(&mut db, uid, alias).context("In get.")? {
             return Ok(entry);
         }
         if self.get_legacy(uid, alias).context("In get: Trying to import legacy blob.")? {
             // If we were able to import a legacy blob try again.
-            if let Some(entry) =
-                db.get(uid, alias).context("In get: Trying to load entry from DB.")?
-            {
+            if let Some(entry) = Self::get_from_db(&mut db, uid, alias).context("In get.")? {
                 return Ok(entry);
             }
         }
@@ -328,15 +468,17 @@ impl LegacyKeystore {
     }
 
     fn put(&self, alias: &str, uid: i32, entry: &[u8]) -> Result<()> {
+        Self::check_permission().context("In put.")?;
         let uid = Self::get_effective_uid(uid).context("In put.")?;
         let mut db = self.open_db().context("In put.")?;
-        db.put(uid, alias, entry).context("In put: Trying to insert entry into DB.")?;
+        Self::put_into_db(&mut db, uid, alias, entry).context("In put.")?;
         // When replacing an entry, make sure that there is no stale legacy file entry.
         let _ = self.remove_legacy(uid, alias);
         Ok(())
     }
 
     fn remove(&self, alias: &str, uid: i32) -> Result<()> {
+        Self::check_permission().context("In remove.")?;
         let uid = Self::get_effective_uid(uid).context("In remove.")?;
         let mut db = self.open_db().context("In remove.")?;
 
@@ -383,6 +525,7 @@ impl LegacyKeystore {
     }
 
     fn list(&self, prefix: &str, uid: i32) -> Result<Vec<String>> {
+        Self::check_permission().context("In list.")?;
         let mut db = self.open_db().context("In list.")?;
         let uid = Self::get_effective_uid(uid).context("In list.")?;
         let mut result = self.list_legacy(uid).context("In list.")?;
@@ -503,12 +646,12 @@ impl LegacyKeystore {
                 {
                     key.decrypt(ciphertext, iv, tag)
                 } else {
-                    Err(Error::sys()).context("No key found for user. Device may be locked.")
+                    Err(Error::locked()).context("No key found for user. Device may be locked.")
                 }
             })
             .context("In import_one_legacy_entry: Trying to read legacy keystore entry.")?;
         if let Some(entry) = blob {
-            db.put(uid, alias, &entry)
+            Self::put_into_db(db, uid, alias, &entry)
                 .context("In import_one_legacy_entry: Trying to insert entry into DB.")?;
             legacy_loader
                 .remove_legacy_keystore_entry(uid, alias)
@@ -603,6 +746,56 @@ mod db_test {
         assert_eq!(Some(TEST_BLOB4), db.get(2, "test1").expect("Failed to get entry.").as_deref());
     }
 
+    #[test]
+    fn test_encrypted_entry_db() {
+        let test_dir = TempDir::new("encrypted_entrydb_test_").expect("Failed to create temp dir.");
+        let mut db = DB::new(&test_dir.build().push(LegacyKeystore::LEGACY_KEYSTORE_FILE_NAME))
+            .expect("Failed to open database.");
+
+        db.put_encrypted(2, "test1", TEST_BLOB1, TEST_BLOB2, TEST_BLOB3)
+            .expect("Failed to insert test1.");
+        let entry = db.get_entry(2, "test1").expect("Failed to get entry.").unwrap();
+        assert_eq!(TEST_BLOB1, entry.data.as_slice());
+        assert_eq!(Some((TEST_BLOB2.to_vec(), TEST_BLOB3.to_vec())), entry.encryption);
+
+        // Replacing an encrypted entry with a plaintext entry drops the iv and tag.
+        db.put(2, "test1", TEST_BLOB4).expect("Failed to replace test1.");
+        let entry = db.get_entry(2, "test1").expect("Failed to get entry.").unwrap();
+        assert_eq!(TEST_BLOB4, entry.data.as_slice());
+        assert!(entry.encryption.is_none());
+    }
+
+    #[test]
+    fn test_upgrade_unencrypted_db() {
+        let test_dir = TempDir::new("upgrade_entrydb_test_").expect("Failed to create temp dir.");
+        let db_path = test_dir.build().push(LegacyKeystore::LEGACY_KEYSTORE_FILE_NAME).to_owned();
+        {
+            let conn = Connection::open(&db_path).expect("Failed to open connection.");
+            conn.execute(
+                "CREATE TABLE profiles (
+                     owner INTEGER,
+                     alias BLOB,
+                     profile BLOB,
+                     UNIQUE(owner, alias));",
+                NO_PARAMS,
+            )
+            .expect("Failed to create table.");
+            conn.execute(
+                "INSERT INTO profiles (owner, alias, profile) values (?, ?, ?)",
+                params![2, "test1", TEST_BLOB1],
+            )
+            .expect("Failed to insert test1.");
+        }
+
+        let mut db = DB::new(&db_path).expect("Failed to open database.");
+        let entry = db.get_entry(2, "test1").expect("Failed to get entry.").unwrap();
+        assert_eq!(TEST_BLOB1, entry.data.as_slice());
+        assert!(entry.encryption.is_none());
+
+        // Opening the upgraded database again must not fail.
+        DB::new(&db_path).expect("Failed to reopen database.");
+    }
+
     #[test]
     fn test_delete_uid() {
         let test_dir = TempDir::new("test_delete_uid_").expect("Failed to create temp dir.");
@@ -649,6 +842,39 @@ mod db_test {
         assert_eq!(vec!["test3".to_string(),], db.list(3).expect("Failed to list entries."));
     }
 
+    #[test]
+    fn test_get_put_while_locked() {
+        let test_dir =
+            TempDir::new("test_get_put_while_locked_").expect("Failed to create temp dir.");
+        let mut db = DB::new(&test_dir.build().push(LegacyKeystore::LEGACY_KEYSTORE_FILE_NAME))
+            .expect("Failed to open database.");
+
+        // No per-boot super key was ever loaded for user 99, so the user is locked.
+        let app_uid = 99 * rustutils::users::AID_USER_OFFSET + LegacyKeystore::AID_APP_START + 1;
+        db.put_encrypted(app_uid, "test1", TEST_BLOB1, TEST_BLOB2, TEST_BLOB3)
+            .expect("Failed to insert test1.");
+
+        let err = LegacyKeystore::get_from_db(&mut db, app_uid, "test1")
+            .expect_err("Reading an encrypted entry of a locked user must fail.");
+        assert_eq!(Some(&Error::locked()), err.root_cause().downcast_ref::<Error>());
+
+        let err = LegacyKeystore::put_into_db(&mut db, app_uid, "test2", TEST_BLOB4)
+            .expect_err("Storing an entry of a locked user must fail.");
+        assert_eq!(Some(&Error::locked()), err.root_cause().downcast_ref::<Error>());
+        assert!(db.get_entry(app_uid, "test2").expect("Failed to get entry.").is_none());
+
+        // Entries of system components are not encrypted and remain accessible.
+        let system_uid = 99 * rustutils::users::AID_USER_OFFSET + 1010;
+        LegacyKeystore::put_into_db(&mut db, system_uid, "test3", TEST_BLOB4)
+            .expect("Failed to insert test3.");
+        assert_eq!(
+            Some(TEST_BLOB4),
+            LegacyKeystore::get_from_db(&mut db, system_uid, "test3")
+                .expect("Failed to get test3.")
+                .as_deref()
+        );
+    }
+
     #[test]
     fn concurrent_legacy_keystore_entry_test() -> Result<()> {
         let temp_dir = Arc::new(
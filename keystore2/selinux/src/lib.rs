@@ -321,6 +321,36 @@ pub fn check_access(source: &CStr, target: &CStr, tclass: &str, perm: &str) -> R
     }
 }
 
+static SELINUX_STATUS_INIT: sync::Once = sync::Once::new();
+
+/// Safe wrapper around `selinux_status_updated`. Returns true if the SELinux policy was
+/// reloaded or the enforcing mode changed since the last call. This state is process global,
+/// so there should only be one caller that propagates updates to interested parties. If the
+/// SELinux status cannot be opened, this always returns false.
+pub fn policy_updated() -> bool {
+    init_logger_once();
+    // The selinux_status_* functions keep the mapped status page, the netlink fallback socket
+    // and the last observed sequence number in unsynchronized process globals. Calling them
+    // from several threads concurrently, including selinux_status_open, is not safe, so all
+    // calls are serialized by the libselinux lock.
+    let _lock = LIB_SELINUX_LOCK.lock().unwrap();
+
+    SELINUX_STATUS_INIT.call_once(|| {
+        // SAFETY: `selinux_status_open` takes no pointer arguments. It only initializes the
+        // status globals of libselinux, which are protected by `LIB_SELINUX_LOCK` held above,
+        // and `SELINUX_STATUS_INIT` ensures that it is called at most once, so the mapping is
+        // never leaked or replaced while in use. The fallback argument 1 lets libselinux use a
+        // netlink socket if the status page cannot be mapped.
+        if unsafe { selinux::selinux_status_open(1) } < 0 {
+            log::error!("policy_updated: selinux_status_open failed.");
+        }
+    });
+    // SAFETY: `selinux_status_updated` takes no arguments and only reads the status page and
+    // updates the last observed sequence number, both guarded by `LIB_SELINUX_LOCK`. If the
+    // status was not opened, it does not dereference the mapping and returns a negative value.
+    unsafe { selinux::selinux_status_updated() > 0 }
+}
+
 /// Safe wrapper around setcon.
 pub fn setcon(target: &CStr) -> std::io::Result<()> {
     // SAFETY: `setcon` takes a const char* and only performs read accesses on it
@@ -29,8 +29,8 @@ use android_security_apc::aidl::android::security::apc::{
     ResponseCode::ResponseCode,
 };
 use android_security_apc::binder::{
-    BinderFeatures, ExceptionCode, Interface, Result as BinderResult, SpIBinder,
-    Status as BinderStatus, Strong, ThreadState,
+    BinderFeatures, DeathRecipient, ExceptionCode, IBinder, Interface, Result as BinderResult,
+    SpIBinder, Status as BinderStatus, Strong, ThreadState,
 };
 use anyhow::{Context, Result};
 use keystore2_apc_compat::ApcHal;
@@ -181,6 +181,9 @@ struct ApcSessionState {
     /// This is used by the rate limiting logic to determine
     /// if the client needs to be penalized for this attempt.
     client_aborted: bool,
+    /// Aborts the session if the client dies while the prompt is pending.
+    /// It must be kept alive for as long as the session exists.
+    _death_recipient: DeathRecipient,
 }
 
 struct ApcState {
@@ -308,6 +311,16 @@ impl ApcManager {
 
         let ui_opts = ui_opts_2_compat(ui_option_flags);
 
+        let mut cb = listener.as_binder();
+        let mut death_recipient = {
+            let state = self.state.clone();
+            let cb = cb.clone();
+            DeathRecipient::new(move || Self::client_died(&state, &cb))
+        };
+        cb.link_to_death(&mut death_recipient)
+            .map_err(|_| Error::sys())
+            .context("In present_prompt: Failed to link to listener death.")?;
+
         let state_clone = self.state.clone();
         hal.prompt_user_confirmation(
             prompt_text,
@@ -322,10 +335,11 @@ impl ApcManager {
         .context("In present_prompt: Failed to present prompt.")?;
         state.session = Some(ApcSessionState {
             hal,
-            cb: listener.as_binder(),
+            cb,
             uid,
             start: Instant::now(),
             client_aborted: false,
+            _death_recipient: death_recipient,
         });
         Ok(())
     }
@@ -353,6 +367,25 @@ impl ApcManager {
         Ok(())
     }
 
+    /// Called when the listener of a pending session dies. The session is aborted so that
+    /// the prompt does not linger on screen without anyone to report the result to.
+    /// This counts as a client abort for the purpose of rate limiting, otherwise a client
+    /// could evade the back-off by terminating instead of canceling.
+    fn client_died(state: &Arc<Mutex<ApcState>>, cb: &SpIBinder) {
+        let mut state = state.lock().unwrap();
+        let hal = match &mut state.session {
+            Some(session) if &session.cb == cb => {
+                session.client_aborted = true;
+                session.hal.clone()
+            }
+            // The session has already completed or belongs to another client.
+            _ => return,
+        };
+        drop(state);
+        log::info!("In ApcManager::client_died: Listener died. Aborting pending prompt.");
+        hal.abort();
+    }
+
     fn is_supported() -> Result<bool> {
         Ok(ApcHal::try_get_service().is_some())
     }
@@ -0,0 +1,177 @@
+// Copyright 2021, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements the IKeystoreAsyncKeyGeneration AIDL interface, which generates keys
+//! in the background. The caller dependent parts of a generation, i.e., all permission checks,
+//! are performed on the binder thread of the caller. The KeyMint call, which includes the
+//! attestation, and the storing of the key are performed by a dedicated worker, so that slow
+//! generations neither block the caller nor the other background tasks of Keystore.
+
+use crate::audit_log::log_key_generated;
+use crate::config;
+use crate::error::{get_error_code, map_or_log_err, Error};
+use crate::metrics_store::log_key_creation_event_stats;
+use crate::security_level::KeystoreSecurityLevel;
+use crate::{async_task::AsyncTask, utils::watchdog as wd};
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
+    KeyParameter::KeyParameter, SecurityLevel::SecurityLevel,
+};
+use android_security_asynckeygen::aidl::android::security::asynckeygen::{
+    IKeyGenerationCallback::IKeyGenerationCallback,
+    IKeystoreAsyncKeyGeneration::{BnKeystoreAsyncKeyGeneration, IKeystoreAsyncKeyGeneration},
+};
+use android_security_asynckeygen::binder::{
+    BinderFeatures, Interface, Result as BinderResult, Strong, ThreadState,
+};
+use android_system_keystore2::aidl::android::system::keystore2::{
+    Domain::Domain, KeyDescriptor::KeyDescriptor, KeyMetadata::KeyMetadata,
+    ResponseCode::ResponseCode,
+};
+use anyhow::{Context, Result};
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+lazy_static! {
+    /// Performs the queued key generations one at a time.
+    static ref KEY_GENERATION_WORKER: AsyncTask = Default::default();
+    /// Maps the uid of a caller to the number of its queued or running key generations.
+    static ref PENDING_PER_UID: Mutex<HashMap<u32, usize>> = Default::default();
+}
+
+/// Counts a pending key generation of a uid for as long as it lives.
+struct PendingSlot(u32);
+
+impl PendingSlot {
+    /// Claims a slot for `uid`. Fails with `ResponseCode::BACKEND_BUSY` if the uid already has
+    /// `config::MAX_PENDING_ASYNC_KEYGENS_PER_UID` pending key generations, so that a single
+    /// app cannot grow the queue without bound.
+    fn acquire(uid: u32) -> Result<Self> {
+        let limit = config::MAX_PENDING_ASYNC_KEYGENS_PER_UID.get();
+        let mut pending = PENDING_PER_UID.lock().unwrap();
+        let count = pending.entry(uid).or_insert(0);
+        if limit != 0 && *count >= limit {
+            return Err(Error::Rc(ResponseCode::BACKEND_BUSY)).context(format!(
+                "In PendingSlot::acquire: Uid {} has {} pending key generations.",
+                uid, count
+            ));
+        }
+        *count += 1;
+        Ok(Self(uid))
+    }
+}
+
+impl Drop for PendingSlot {
+    fn drop(&mut self) {
+        let mut pending = PENDING_PER_UID.lock().unwrap();
+        if let Some(count) = pending.get_mut(&self.0) {
+            *count -= 1;
+            if *count == 0 {
+                pending.remove(&self.0);
+            }
+        }
+    }
+}
+
+/// Implementation of the IKeystoreAsyncKeyGeneration AIDL interface.
+pub struct AsyncKeyGeneration;
+
+impl AsyncKeyGeneration {
+    /// Creates a new instance of the asynchronous key generation service.
+    pub fn new_native_binder() -> Result<Strong<dyn IKeystoreAsyncKeyGeneration>> {
+        Ok(BnKeystoreAsyncKeyGeneration::new_binder(
+            Self,
+            BinderFeatures { set_requesting_sid: true, ..BinderFeatures::default() },
+        ))
+    }
+
+    fn generate_key(
+        security_level: SecurityLevel,
+        key: &KeyDescriptor,
+        attestation_key: Option<&KeyDescriptor>,
+        params: &[KeyParameter],
+        flags: i32,
+        callback: Option<&Strong<dyn IKeyGenerationCallback>>,
+    ) -> Result<()> {
+        if key.domain == Domain::BLOB && callback.is_none() {
+            return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+                .context("In generate_key: Blob keys can only be delivered to a callback.");
+        }
+        let sec_level = KeystoreSecurityLevel::get(security_level)
+            .ok_or(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+            .context("In generate_key: No such security level.")?;
+        sec_level.check_flags(flags).context("In generate_key.")?;
+        let caller_uid = ThreadState::get_calling_uid();
+        let slot = PendingSlot::acquire(caller_uid).context("In generate_key.")?;
+        let request = sec_level
+            .prepare_key_generation(key, attestation_key, params)
+            .context("In generate_key.")?;
+
+        let key = key.clone();
+        let params = params.to_vec();
+        let callback = callback.cloned();
+        KEY_GENERATION_WORKER.queue_hi(move |_| {
+            let _slot = slot;
+            let result = sec_level
+                .complete_key_generation(request, flags)
+                .context("In generate_key: Generating the key in the background.");
+            log_key_creation_event_stats(security_level, &params, &result);
+            log_key_generated(&key, caller_uid, result.is_ok());
+            Self::deliver_result(callback, result);
+        });
+        Ok(())
+    }
+
+    fn deliver_result(
+        callback: Option<Strong<dyn IKeyGenerationCallback>>,
+        result: Result<KeyMetadata>,
+    ) {
+        let delivered = match (callback, result) {
+            (Some(callback), Ok(metadata)) => callback.onKeyGenerated(&metadata),
+            (Some(callback), Err(e)) => {
+                log::error!("{:?}", e);
+                callback.onError(get_error_code(&e), &format!("{:?}", e))
+            }
+            (None, Ok(_)) => Ok(()),
+            (None, Err(e)) => {
+                log::error!("{:?}", e);
+                Ok(())
+            }
+        };
+        if let Err(e) = delivered {
+            log::warn!("In deliver_result: Failed to call the callback: {:?}", e);
+        }
+    }
+}
+
+impl Interface for AsyncKeyGeneration {}
+
+impl IKeystoreAsyncKeyGeneration for AsyncKeyGeneration {
+    fn generateKey(
+        &self,
+        security_level: SecurityLevel,
+        key: &KeyDescriptor,
+        attestation_key: Option<&KeyDescriptor>,
+        params: &[KeyParameter],
+        flags: i32,
+        _entropy: &[u8],
+        callback: Option<&Strong<dyn IKeyGenerationCallback>>,
+    ) -> BinderResult<()> {
+        let _wp = wd::watch_millis("IKeystoreAsyncKeyGeneration::generateKey", 500);
+        map_or_log_err(
+            Self::generate_key(security_level, key, attestation_key, params, flags, callback),
+            Ok,
+        )
+    }
+}
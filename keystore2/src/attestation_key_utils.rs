@@ -18,6 +18,7 @@
 use crate::database::{BlobMetaData, KeyEntryLoadBits, KeyType};
 use crate::database::{KeyIdGuard, KeystoreDB};
 use crate::error::{Error, ErrorCode};
+use crate::key_parameter::{KeyParameterValue, KeyPurpose};
 use crate::permission::KeyPerm;
 use crate::remote_provisioning::RemProvState;
 use crate::utils::check_key_permission;
@@ -44,6 +45,10 @@ pub enum AttestationKeyInfo {
         blob: Vec<u8>,
         blob_metadata: BlobMetaData,
         issuer_subject: Vec<u8>,
+        /// The certificate of the attestation key followed by its certificate chain, if any.
+        /// KeyMint only returns the leaf certificate of keys attested by a user generated
+        /// attestation key, so this is appended to complete the chain of the new key.
+        attestation_certs: Certificate,
     },
 }
 
@@ -90,7 +95,7 @@ fn get_user_generated_attestation_key(
     caller_uid: u32,
     db: &mut KeystoreDB,
 ) -> Result<AttestationKeyInfo> {
-    let (key_id_guard, blob, cert, blob_metadata) =
+    let (key_id_guard, blob, cert, cert_chain, blob_metadata) =
         load_attest_key_blob_and_cert(key, caller_uid, db)
             .context("In get_user_generated_attestation_key: Failed to load blob and cert")?;
 
@@ -98,14 +103,23 @@ fn get_user_generated_attestation_key(
         "In get_user_generated_attestation_key: Failed to parse subject from certificate.",
     )?;
 
-    Ok(AttestationKeyInfo::UserGenerated { key_id_guard, blob, issuer_subject, blob_metadata })
+    let mut encoded_certificate = cert;
+    encoded_certificate.extend(cert_chain.unwrap_or_default());
+
+    Ok(AttestationKeyInfo::UserGenerated {
+        key_id_guard,
+        blob,
+        issuer_subject,
+        blob_metadata,
+        attestation_certs: Certificate { encodedCertificate: encoded_certificate },
+    })
 }
 
 fn load_attest_key_blob_and_cert(
     key: &KeyDescriptor,
     caller_uid: u32,
     db: &mut KeystoreDB,
-) -> Result<(KeyIdGuard, Vec<u8>, Vec<u8>, BlobMetaData)> {
+) -> Result<(KeyIdGuard, Vec<u8>, Vec<u8>, Option<Vec<u8>>, BlobMetaData)> {
     match key.domain {
         Domain::BLOB => Err(Error::Km(ErrorCode::INVALID_ARGUMENT)).context(
             "In load_attest_key_blob_and_cert: Domain::BLOB attestation keys not supported",
@@ -121,6 +135,16 @@ fn load_attest_key_blob_and_cert(
                 )
                 .context("In load_attest_key_blob_and_cert: Failed to load key.")?;
 
+            // Fail early with the error KeyMint would report for a key that cannot attest.
+            if !key_entry.key_parameters().iter().any(|kp| {
+                *kp.key_parameter_value() == KeyParameterValue::KeyPurpose(KeyPurpose::ATTEST_KEY)
+            }) {
+                return Err(Error::Km(ErrorCode::INCOMPATIBLE_PURPOSE)).context(concat!(
+                    "In load_attest_key_blob_and_cert: ",
+                    "The attestation key does not have the ATTEST_KEY purpose."
+                ));
+            }
+
             let (blob, blob_metadata) =
                 key_entry.take_key_blob_info().ok_or_else(Error::sys).context(concat!(
                     "In load_attest_key_blob_and_cert: Successfully loaded key entry,",
@@ -130,7 +154,8 @@ fn load_attest_key_blob_and_cert(
                 "In load_attest_key_blob_and_cert: Successfully loaded key entry,",
                 " but cert was missing."
             ))?;
-            Ok((key_id_guard, blob, cert, blob_metadata))
+            let cert_chain = key_entry.take_cert_chain();
+            Ok((key_id_guard, blob, cert, cert_chain, blob_metadata))
         }
     }
 }
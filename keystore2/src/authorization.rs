@@ -14,14 +14,19 @@
 
 //! This module implements IKeystoreAuthorization AIDL interface.
 
+use crate::enforcements::UnlockMethod;
 use crate::error::Error as KeystoreError;
 use crate::error::anyhow_error_to_cstring;
+use crate::error::{map_binder_status, map_km_error};
 use crate::globals::{ENFORCEMENTS, SUPER_KEY, DB, LEGACY_IMPORTER};
+use crate::globals::{get_cached_keymint_devices, get_timestamp_service};
+use crate::legacy_importer::LegacyImporter;
 use crate::permission::KeystorePerm;
 use crate::super_key::UserState;
 use crate::utils::{check_keystore_permission, watchdog as wd};
 use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
-    HardwareAuthToken::HardwareAuthToken,
+    HardwareAuthToken::HardwareAuthToken, HardwareAuthenticatorType::HardwareAuthenticatorType,
+    SecurityLevel::SecurityLevel,
 };
 use android_security_authorization::binder::{BinderFeatures,ExceptionCode, Interface, Result as BinderResult,
      Strong, Status as BinderStatus};
@@ -115,6 +120,40 @@ where
 /// As of now, it is an empty struct.
 pub struct AuthorizationManager;
 
+/// Tells all connected KeyMint instances that the device was locked, so that keys with
+/// `Tag::UNLOCKED_DEVICE_REQUIRED` become unusable in hardware even if keystore's own
+/// enforcement is bypassed. If `password_only` is true, only a password auth token unlocks them
+/// again. StrongBox has no clock of its own and gets a timestamp token to date the lock.
+/// Failures are logged only, because the device is locked for keystore regardless.
+fn notify_keymint_device_locked(password_only: bool) {
+    for (sec_level, dev) in get_cached_keymint_devices() {
+        let timestamp_token = match sec_level {
+            SecurityLevel::STRONGBOX => match get_timestamp_service().and_then(|clock| {
+                map_binder_status(clock.generateTimeStamp(0)).context("Generating timestamp.")
+            }) {
+                Ok(token) => Some(token),
+                Err(e) => {
+                    log::warn!("In notify_keymint_device_locked: No timestamp token: {:?}", e);
+                    None
+                }
+            },
+            _ => None,
+        };
+        let result = {
+            let _wp =
+                wd::watch_millis("In notify_keymint_device_locked: calling deviceLocked", 500);
+            map_km_error(dev.deviceLocked(password_only, timestamp_token.as_ref()))
+        };
+        if let Err(e) = result {
+            log::error!(
+                "In notify_keymint_device_locked: deviceLocked failed for {:?}: {:?}",
+                sec_level,
+                e
+            );
+        }
+    }
+}
+
 impl AuthorizationManager {
     /// Create a new instance of Keystore Authorization service.
     pub fn new_native_binder() -> Result<Strong<dyn IKeystoreAuthorization>> {
@@ -146,19 +185,23 @@ impl AuthorizationManager {
             password.is_some(),
             unlocking_sids
         );
+        if user_id < 0 {
+            return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+                .context(format!("In on_lock_screen_event: Invalid user id {}.", user_id));
+        }
         match (lock_screen_event, password) {
             (LockScreenEvent::UNLOCK, Some(password)) => {
                 // This corresponds to the unlock() method in legacy keystore API.
                 // check permission
                 check_keystore_permission(KeystorePerm::Unlock)
                     .context("In on_lock_screen_event: Unlock with password.")?;
-                ENFORCEMENTS.set_device_locked(user_id, false);
+                ENFORCEMENTS.record_unlock(user_id, UnlockMethod::Password);
 
                 let mut skm = SUPER_KEY.write().unwrap();
 
                 DB.with(|db| {
                     skm.unlock_screen_lock_bound_key(
-                        &mut db.borrow_mut(),
+                        &mut db.borrow_mut()?,
                         user_id as u32,
                         &password,
                     )
@@ -166,10 +209,10 @@ impl AuthorizationManager {
                 .context("In on_lock_screen_event: unlock_screen_lock_bound_key failed")?;
 
                 // Unlock super key.
-                if let UserState::Uninitialized = DB
+                match DB
                     .with(|db| {
                         skm.unlock_and_get_user_state(
-                            &mut db.borrow_mut(),
+                            &mut db.borrow_mut()?,
                             &LEGACY_IMPORTER,
                             user_id as u32,
                             &password,
@@ -177,9 +220,21 @@ impl AuthorizationManager {
                     })
                     .context("In on_lock_screen_event: Unlock with password.")?
                 {
-                    log::info!(
-                        "In on_lock_screen_event. Trying to unlock when LSKF is uninitialized."
-                    );
+                    UserState::Uninitialized => {
+                        log::info!(
+                            "In on_lock_screen_event. Trying to unlock when LSKF is uninitialized."
+                        );
+                    }
+                    UserState::LskfUnlocked(super_key) => {
+                        // Now that the super key is available, import the user's remaining
+                        // legacy keys in the background.
+                        LegacyImporter::schedule_bulk_import_user(
+                            &LEGACY_IMPORTER,
+                            user_id as u32,
+                            Some(super_key),
+                        );
+                    }
+                    UserState::LskfLocked => {}
                 }
 
                 Ok(())
@@ -187,12 +242,23 @@ impl AuthorizationManager {
             (LockScreenEvent::UNLOCK, None) => {
                 check_keystore_permission(KeystorePerm::Unlock)
                     .context("In on_lock_screen_event: Unlock.")?;
-                ENFORCEMENTS.set_device_locked(user_id, false);
                 let mut skm = SUPER_KEY.write().unwrap();
-                DB.with(|db| {
-                    skm.try_unlock_user_with_biometric(&mut db.borrow_mut(), user_id as u32)
-                })
-                .context("In on_lock_screen_event: try_unlock_user_with_biometric failed")?;
+                let unlocked = DB
+                    .with(|db| {
+                        skm.try_unlock_user_with_biometric(&mut db.borrow_mut()?, user_id as u32)
+                    })
+                    .context("In on_lock_screen_event: try_unlock_user_with_biometric failed")?;
+                if unlocked {
+                    ENFORCEMENTS.record_unlock(user_id, UnlockMethod::Biometric);
+                } else {
+                    // A weak unlock must not make UNLOCKED_DEVICE_REQUIRED keys usable. KeyMint
+                    // does not see an auth token either, so it keeps them locked, too.
+                    log::info!(
+                        "In on_lock_screen_event: Weak unlock. User {} stays locked for keystore.",
+                        user_id
+                    );
+                    ENFORCEMENTS.record_unlock(user_id, UnlockMethod::Weak);
+                }
                 Ok(())
             }
             (LockScreenEvent::LOCK, None) => {
@@ -200,13 +266,21 @@ impl AuthorizationManager {
                     .context("In on_lock_screen_event: Lock")?;
                 ENFORCEMENTS.set_device_locked(user_id, true);
                 let mut skm = SUPER_KEY.write().unwrap();
-                DB.with(|db| {
+                DB.with::<_, Result<()>>(|db| {
                     skm.lock_screen_lock_bound_key(
-                        &mut db.borrow_mut(),
+                        &mut db.borrow_mut()?,
                         user_id as u32,
                         unlocking_sids.unwrap_or(&[]),
                     );
-                });
+                    Ok(())
+                })
+                .context("In on_lock_screen_event: lock_screen_lock_bound_key failed")?;
+                // KeyMint has a single lock state for all users, so it is only locked once no
+                // user is left unlocked. Keystore enforces the lock state of each user itself.
+                if !ENFORCEMENTS.is_device_unlocked_for_any_user() {
+                    let password_only = unlocking_sids.map_or(true, |sids| sids.is_empty());
+                    notify_keymint_device_locked(password_only);
+                }
                 Ok(())
             }
             _ => {
@@ -238,6 +312,25 @@ impl AuthorizationManager {
             ENFORCEMENTS.get_auth_tokens(challenge, secure_user_id, auth_token_max_age_millis)?;
         Ok(AuthorizationTokens { authToken: auth_token, timestampToken: ts_token })
     }
+
+    fn get_last_auth_time(
+        &self,
+        secure_user_id: i64,
+        auth_types: &[HardwareAuthenticatorType],
+    ) -> Result<i64> {
+        // Check permission. Function should return if this failed. Therefore having '?' at the end
+        // is very important.
+        check_keystore_permission(KeystorePerm::GetLastAuthTime)
+            .context("In get_last_auth_time.")?;
+
+        auth_types
+            .iter()
+            .filter_map(|auth_type| ENFORCEMENTS.get_last_auth_time(secure_user_id, *auth_type))
+            .max()
+            .map(|time| time.milliseconds())
+            .ok_or(Error::Rc(ResponseCode::NO_AUTH_TOKEN_FOUND))
+            .context("In get_last_auth_time: No auth token found.")
+    }
 }
 
 impl Interface for AuthorizationManager {}
@@ -286,4 +379,13 @@ impl IKeystoreAuthorization for AuthorizationManager {
             Ok,
         )
     }
+
+    fn getLastAuthTime(
+        &self,
+        secure_user_id: i64,
+        auth_types: &[HardwareAuthenticatorType],
+    ) -> binder::Result<i64> {
+        let _wp = wd::watch_millis("IKeystoreAuthorization::getLastAuthTime", 500);
+        map_or_log_err(self.get_last_auth_time(secure_user_id, auth_types), Ok)
+    }
 }
@@ -0,0 +1,112 @@
+// Copyright 2021, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements the optional envelope encryption of key blobs at rest. When enabled,
+//! the key blobs of client keys are additionally encrypted with AES-256-GCM under a database
+//! key before they are written to the database. The database key never touches the disk; it is
+//! derived with HMAC-SHA256 from a Keystore internal KeyMint key on each start. A copy of the
+//! database taken after the user unlocked the device is therefore useless without the KeyMint
+//! instance of this device. Existing key blobs are encrypted lazily by a background sweep.
+
+use crate::{
+    config, database::KeystoreDB, error::Error, globals::DB, hw_bound_kdf, idle_maintenance,
+};
+use anyhow::{Context, Result};
+use keystore2_crypto::{aes_gcm_decrypt, aes_gcm_encrypt, ZVec};
+use lazy_static::lazy_static;
+use std::sync::{Arc, RwLock};
+
+/// Number of key blobs encrypted by a single job of the background migration.
+const MIGRATION_BATCH_SIZE: usize = 20;
+
+/// Alias of the Keystore internal KeyMint key from which the database key is derived.
+const DATABASE_KEY_ALIAS: &str = "blob_envelope_key";
+
+/// Message signed with the internal KeyMint key to derive the database key.
+const DATABASE_KEY_INFO: &[u8] = b"Create Keystore blob envelope database key";
+
+lazy_static! {
+    static ref DATABASE_KEY: RwLock<Option<Arc<ZVec>>> = Default::default();
+}
+
+/// Derives the database key and makes it available to the database, if envelope encryption
+/// is enabled or if the database still holds envelope encrypted key blobs from a time when it
+/// was enabled. This must be called once at startup before any key is loaded.
+pub fn initialize(db: &mut KeystoreDB) -> Result<()> {
+    if !config::BLOB_ENVELOPE_ENCRYPTION.get()
+        && !db.has_enveloped_blobs().context("In initialize: Trying to query the database.")?
+    {
+        return Ok(());
+    }
+    let database_key = derive_database_key(db).context("In initialize.")?;
+    *DATABASE_KEY.write().unwrap() = Some(Arc::new(database_key));
+    Ok(())
+}
+
+fn derive_database_key(db: &mut KeystoreDB) -> Result<ZVec> {
+    hw_bound_kdf::derive_with_device_key(db, DATABASE_KEY_ALIAS, DATABASE_KEY_INFO)
+        .context("In derive_database_key.")
+}
+
+/// Encrypts the given key blob with the database key and returns the sealed blob, the
+/// initialization vector and the AEAD tag. Returns None if envelope encryption is disabled or
+/// the database key is not available, in which case the blob must be stored as is.
+pub(crate) fn seal(blob: &[u8]) -> Result<Option<(Vec<u8>, Vec<u8>, Vec<u8>)>> {
+    if !config::BLOB_ENVELOPE_ENCRYPTION.get() {
+        return Ok(None);
+    }
+    let database_key = match DATABASE_KEY.read().unwrap().clone() {
+        Some(database_key) => database_key,
+        None => return Ok(None),
+    };
+    aes_gcm_encrypt(blob, &database_key).map(Some).context("In seal: Failed to encrypt key blob.")
+}
+
+/// Decrypts a key blob sealed by `seal` in place. The plaintext is written into the buffer of
+/// the sealed blob, which AES-GCM leaves unchanged in length, and is otherwise only held in a
+/// ZVec, so that no copy of it is left behind in memory that is not zeroed.
+pub(crate) fn open(blob: &mut Vec<u8>, iv: &[u8], tag: &[u8]) -> Result<()> {
+    let database_key = DATABASE_KEY
+        .read()
+        .unwrap()
+        .clone()
+        .ok_or_else(Error::sys)
+        .context("In open: The database key is not available.")?;
+    let plaintext = aes_gcm_decrypt(blob, iv, tag, &database_key)
+        .context("In open: Failed to decrypt key blob.")?;
+    blob.clear();
+    blob.extend_from_slice(&plaintext);
+    Ok(())
+}
+
+/// Schedules the background migration that encrypts the key blobs stored before envelope
+/// encryption was enabled. The migration runs in small batches of low priority jobs and
+/// resumes on the next start if it is interrupted.
+pub fn schedule_migration_if_required() {
+    if config::BLOB_ENVELOPE_ENCRYPTION.get() && DATABASE_KEY.read().unwrap().is_some() {
+        queue_migration_batch();
+    }
+}
+
+fn queue_migration_batch() {
+    idle_maintenance::queue_lo("key blob envelope migration", || {
+        match DB.with(|db| db.borrow_mut()?.envelope_key_blobs(MIGRATION_BATCH_SIZE)) {
+            Ok(count) if count == MIGRATION_BATCH_SIZE => queue_migration_batch(),
+            Ok(_) => log::info!("Key blob envelope migration completed."),
+            Err(e) => {
+                log::error!("In queue_migration_batch: Failed to migrate key blobs: {:?}", e)
+            }
+        }
+    });
+}
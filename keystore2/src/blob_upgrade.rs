@@ -0,0 +1,243 @@
+// Copyright 2021, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements the background key blob upgrade sweep.
+//! Key blobs are usually upgraded lazily when a key is used after a KeyMint or OS update.
+//! Rarely used keys may therefore still be in an old blob format when a vendor drops support
+//! for it. When the version of a KeyMint instance or the OS patch level changes, the sweep
+//! iterates over all keys bound to that instance in small batches of low priority jobs and
+//! upgrades the blobs that require it.
+
+use crate::{
+    config,
+    database::{
+        BlobMetaData, BlobMetaEntry, KeyEntryLoadBits, KeyIdGuard, KeyType, SubComponentType, Uuid,
+    },
+    error::map_km_error,
+    globals::{get_keymint_device, DB},
+    idle_maintenance,
+    utils::{
+        key_characteristics_to_internal, upgrade_keyblob_if_required_with, watchdog as wd,
+        AID_KEYSTORE,
+    },
+};
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
+    IKeyMintDevice::IKeyMintDevice, KeyCharacteristics::KeyCharacteristics,
+    KeyParameter::KeyParameter, KeyParameterValue::KeyParameterValue, SecurityLevel::SecurityLevel,
+    Tag::Tag,
+};
+use android_hardware_security_keymint::binder::Strong;
+use android_system_keystore2::aidl::android::system::keystore2::{
+    Domain::Domain, KeyDescriptor::KeyDescriptor,
+};
+use anyhow::{Context, Result};
+
+const OS_PATCH_LEVEL_PROPERTY: &str = "ro.build.version.security_patch";
+
+/// Key parameters that a key blob upgrade may change.
+const VERSION_TAGS: &[Tag] =
+    &[Tag::OS_VERSION, Tag::OS_PATCHLEVEL, Tag::VENDOR_PATCHLEVEL, Tag::BOOT_PATCHLEVEL];
+
+/// Checks for each KeyMint instance whether its version or the OS patch level changed since
+/// the last time this was checked, and if so, schedules a background sweep that upgrades the
+/// key blobs bound to that instance. Instances that are not available are skipped.
+/// The new version is recorded right away, so a sweep interrupted by a reboot is not resumed.
+/// The remaining keys are still upgraded lazily when they are used.
+pub fn schedule_upgrade_sweeps_if_required() {
+    let os_patch_level = match rustutils::system_properties::read(OS_PATCH_LEVEL_PROPERTY) {
+        Ok(Some(patch_level)) => patch_level,
+        Ok(None) => String::new(),
+        Err(e) => {
+            log::error!(
+                "In schedule_upgrade_sweeps_if_required: Failed to read OS patch level: {:?}",
+                e
+            );
+            return;
+        }
+    };
+    for sec_level in [SecurityLevel::TRUSTED_ENVIRONMENT, SecurityLevel::STRONGBOX].iter() {
+        if let Err(e) = schedule_upgrade_sweep_if_required(*sec_level, &os_patch_level) {
+            log::info!(
+                "In schedule_upgrade_sweeps_if_required: Not sweeping {:?}: {:?}",
+                sec_level,
+                e
+            );
+        }
+    }
+}
+
+fn schedule_upgrade_sweep_if_required(
+    sec_level: SecurityLevel,
+    os_patch_level: &str,
+) -> Result<()> {
+    let (km_dev, hw_info, km_uuid) = get_keymint_device(&sec_level)
+        .context("In schedule_upgrade_sweep_if_required: Trying to get KeyMint device.")?;
+    let changed = DB
+        .with(|db| {
+            db.borrow_mut()?.update_keymint_version(&km_uuid, hw_info.versionNumber, os_patch_level)
+        })
+        .context("In schedule_upgrade_sweep_if_required: Trying to update KeyMint version.")?;
+    if changed {
+        log::info!("Scheduling key blob upgrade sweep for {:?}.", sec_level);
+        queue_sweep_batch(km_dev, km_uuid, -1);
+    }
+    Ok(())
+}
+
+/// Queues a low priority job that upgrades the key blobs of the next batch of keys with ids
+/// greater than `after_key_id`. Each job queues the next one, so that other work on the async
+/// task is not blocked for the whole duration of the sweep.
+fn queue_sweep_batch(km_dev: Strong<dyn IKeyMintDevice>, km_uuid: Uuid, after_key_id: i64) {
+    idle_maintenance::queue_lo("key blob upgrade sweep", move || {
+        let batch_size = config::UPGRADE_SWEEP_BATCH_SIZE.get().max(1);
+        let key_ids = match DB
+            .with(|db| db.borrow_mut()?.get_key_ids_for_km_uuid(&km_uuid, after_key_id, batch_size))
+        {
+            Ok(key_ids) => key_ids,
+            Err(e) => {
+                log::error!("In queue_sweep_batch: Failed to list keys. Giving up: {:?}", e);
+                return;
+            }
+        };
+        for key_id in &key_ids {
+            if let Err(e) = upgrade_key_blob_if_required(&*km_dev, &km_uuid, *key_id) {
+                log::warn!("In queue_sweep_batch: Failed to upgrade key {}: {:?}", key_id, e);
+            }
+        }
+        match key_ids.last() {
+            Some(last_key_id) if key_ids.len() == batch_size => {
+                queue_sweep_batch(km_dev, km_uuid, *last_key_id)
+            }
+            _ => log::info!("Key blob upgrade sweep completed."),
+        }
+    });
+}
+
+/// Upgrades the key blob of the given key if KeyMint requires it. Super encrypted blobs are
+/// skipped, because they cannot be decrypted without the user's super key. Keys that require
+/// application id or data cannot be upgraded here either. All of these are still upgraded
+/// lazily when they are used.
+/// Returns Ok(true) if the blob was upgraded.
+fn upgrade_key_blob_if_required(
+    km_dev: &dyn IKeyMintDevice,
+    km_uuid: &Uuid,
+    key_id: i64,
+) -> Result<bool> {
+    let (key_id_guard, key_entry) = DB
+        .with(|db| {
+            db.borrow_mut()?.load_key_entry(
+                &KeyDescriptor { domain: Domain::KEY_ID, nspace: key_id, alias: None, blob: None },
+                KeyType::Client,
+                KeyEntryLoadBits::KM,
+                AID_KEYSTORE,
+                |_, _| Ok(()),
+            )
+        })
+        .context("In upgrade_key_blob_if_required: Trying to load key entry.")?;
+    let (key_blob, blob_metadata) = match key_entry.key_blob_info() {
+        Some((key_blob, blob_metadata)) => (key_blob, blob_metadata),
+        None => return Ok(false),
+    };
+    if blob_metadata.encrypted_by().is_some() || blob_metadata.max_boot_level().is_some() {
+        return Ok(false);
+    }
+
+    let (key_characteristics, upgraded_blob) = upgrade_keyblob_if_required_with(
+        km_dev,
+        key_blob,
+        &[],
+        |blob| {
+            let _wp = wd::watch_millis(
+                "In upgrade_key_blob_if_required: calling getKeyCharacteristics.",
+                500,
+            );
+            map_km_error(km_dev.getKeyCharacteristics(blob, &[], &[]))
+        },
+        |upgraded_blob| {
+            let mut new_blob_metadata = BlobMetaData::new();
+            new_blob_metadata.add(BlobMetaEntry::KmUuid(*km_uuid));
+            DB.with(|db| {
+                let mut db = db.borrow_mut()?;
+                // The replacement is committed once the version parameters are stored below.
+                db.begin_blob_replacement(&key_id_guard, &[SubComponentType::KEY_BLOB])?;
+                db.set_blob(
+                    &key_id_guard,
+                    SubComponentType::KEY_BLOB,
+                    Some(upgraded_blob),
+                    Some(&new_blob_metadata),
+                )
+            })
+            .context("In upgrade_key_blob_if_required: Trying to store upgraded blob.")
+        },
+    )
+    .context("In upgrade_key_blob_if_required.")?;
+
+    if upgraded_blob.is_none() {
+        return Ok(false);
+    }
+    store_version_parameters(&key_id_guard, key_characteristics)
+        .context("In upgrade_key_blob_if_required.")?;
+    DB.with(|db| db.borrow_mut()?.commit_blob_replacement(&key_id_guard))
+        .context("In upgrade_key_blob_if_required: Trying to commit upgraded blob.")?;
+    Ok(true)
+}
+
+/// Updates the key parameters of the given key that an upgrade of its key blob may change.
+/// Must be called whenever a key blob is upgraded, because the upgrade binds the key to the
+/// current versions, and the cached key characteristics must follow.
+fn store_version_parameters(
+    key_id_guard: &KeyIdGuard,
+    key_characteristics: Vec<KeyCharacteristics>,
+) -> Result<()> {
+    DB.with(|db| {
+        db.borrow_mut()?.update_key_parameters(
+            key_id_guard,
+            VERSION_TAGS,
+            &key_characteristics_to_internal(key_characteristics),
+        )
+    })
+    .context("In store_version_parameters: Trying to update key parameters.")
+}
+
+/// Refreshes the key parameters of the given key that an upgrade of its key blob may change,
+/// after the key blob was upgraded on demand to `upgraded_blob`. `params` are the parameters
+/// the key was upgraded with, which hold the application id and data if the key requires them.
+pub fn refresh_version_parameters(
+    km_dev: &dyn IKeyMintDevice,
+    key_id_guard: &KeyIdGuard,
+    upgraded_blob: &[u8],
+    params: &[KeyParameter],
+) -> Result<()> {
+    let find_blob = |tag: Tag| {
+        params
+            .iter()
+            .find_map(|p| match (p.tag, &p.value) {
+                (t, KeyParameterValue::Blob(b)) if t == tag => Some(b.as_slice()),
+                _ => None,
+            })
+            .unwrap_or(&[])
+    };
+    let key_characteristics = map_km_error({
+        let _wp =
+            wd::watch_millis("In refresh_version_parameters: calling getKeyCharacteristics.", 500);
+        km_dev.getKeyCharacteristics(
+            upgraded_blob,
+            find_blob(Tag::APPLICATION_ID),
+            find_blob(Tag::APPLICATION_DATA),
+        )
+    })
+    .context("In refresh_version_parameters: Trying to get key characteristics.")?;
+    store_version_parameters(key_id_guard, key_characteristics)
+        .context("In refresh_version_parameters.")
+}
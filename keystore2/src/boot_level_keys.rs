@@ -26,7 +26,7 @@ use android_hardware_security_keymint::aidl::android::hardware::security::keymin
 };
 use anyhow::{Context, Result};
 use keystore2_crypto::{hkdf_expand, ZVec, AES_256_KEY_LENGTH};
-use std::{collections::VecDeque, convert::TryFrom};
+use std::collections::VecDeque;
 
 fn get_preferred_km_instance_for_level_zero_key() -> Result<KeyMintDevice> {
     let tee = KeyMintDevice::get(SecurityLevel::TRUSTED_ENVIRONMENT)
@@ -114,13 +114,13 @@ pub fn get_level_zero_key(db: &mut KeystoreDB) -> Result<ZVec> {
             b"Create boot level key",
         )
         .context("In get_level_zero_key: use_key_in_one_step failed")?;
-    // TODO: this is rather unsatisfactory, we need a better way to handle
-    // sensitive binder returns.
-    let level_zero_key = ZVec::try_from(level_zero_key)
-        .context("In get_level_zero_key: conversion to ZVec failed")?;
     Ok(level_zero_key)
 }
 
+/// Boot levels at or above this value are never accessible. Setting `keystore.boot_level` to
+/// this value or higher drops all boot level keys.
+pub const MAX_MAX_BOOT_LEVEL: usize = 1_000_000_000;
+
 /// Holds the key for the current boot level, and a cache of future keys generated as required.
 /// When the boot level advances, keys prior to the current boot level are securely dropped.
 pub struct BootLevelKeyCache {
@@ -149,8 +149,9 @@ impl BootLevelKeyCache {
     pub fn level_accessible(&self, boot_level: usize) -> bool {
         // If the requested boot level is lower than the current boot level
         // or if we have reached the end (`cache.empty()`) we can't retrieve
-        // the boot key.
-        boot_level >= self.current && !self.cache.is_empty()
+        // the boot key. Levels beyond the maximum would require deriving an
+        // unbounded number of keys, so they are never accessible either.
+        boot_level >= self.current && boot_level < MAX_MAX_BOOT_LEVEL && !self.cache.is_empty()
     }
 
     /// Get the HKDF key for boot level `boot_level`. The key for level *i*+1
@@ -238,6 +239,7 @@ impl BootLevelKeyCache {
 #[cfg(test)]
 mod test {
     use super::*;
+    use std::convert::TryFrom;
 
     #[test]
     fn test_output_is_consistent() -> Result<()> {
@@ -281,4 +283,18 @@ mod test {
         assert_eq!(None, blkc.aes_key(10)?);
         Ok(())
     }
+
+    #[test]
+    fn test_levels_beyond_maximum_are_inaccessible() -> Result<()> {
+        let initial_key = b"initial key";
+        let mut blkc = BootLevelKeyCache::new(ZVec::try_from(initial_key as &[u8])?);
+        assert!(blkc.level_accessible(MAX_MAX_BOOT_LEVEL - 1));
+        assert!(!blkc.level_accessible(MAX_MAX_BOOT_LEVEL));
+        assert!(!blkc.level_accessible(usize::MAX));
+        assert_eq!(None, blkc.aes_key(usize::MAX)?);
+        // Advancing beyond the maximum is ignored.
+        blkc.advance_boot_level(usize::MAX)?;
+        assert!(blkc.level_accessible(0));
+        Ok(())
+    }
 }
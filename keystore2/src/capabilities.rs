@@ -0,0 +1,427 @@
+// Copyright 2021, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements the IKeystoreCapabilities AIDL interface, which reports the
+//! capabilities of the KeyMint instances backing the security levels of this device.
+//!
+//! KeyMint has no way of querying the supported algorithms. So the supported algorithms, key
+//! sizes, digests, padding modes, and block modes are probed by generating a key for each
+//! candidate. Probing is started in the background at startup and the result is cached per
+//! KeyMint instance, so that probing happens at most once per instance and boot, and callers
+//! never wait for it.
+
+use crate::async_task::AsyncTask;
+use crate::database::Uuid;
+use crate::error::{map_km_error, map_or_log_err, Error, ResponseCode};
+use crate::globals::{get_keymint_device, get_remotely_provisioned_component};
+use crate::key_parameter::KeyParameterValue;
+use crate::permission::KeystorePerm;
+use crate::security_level::UNDEFINED_NOT_AFTER;
+use crate::utils::{check_keystore_permission, watchdog as wd};
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
+    Algorithm::Algorithm, BlockMode::BlockMode, Digest::Digest, EcCurve::EcCurve,
+    IKeyMintDevice::IKeyMintDevice, KeyMintHardwareInfo::KeyMintHardwareInfo,
+    KeyParameter::KeyParameter as KmKeyParameter, KeyPurpose::KeyPurpose, PaddingMode::PaddingMode,
+    SecurityLevel::SecurityLevel,
+};
+use android_security_capabilities::aidl::android::security::capabilities::{
+    AlgorithmCapabilities::AlgorithmCapabilities,
+    IKeystoreCapabilities::{BnKeystoreCapabilities, IKeystoreCapabilities},
+    SecurityLevelCapabilities::SecurityLevelCapabilities,
+};
+use android_security_capabilities::binder::{
+    BinderFeatures, Interface, Result as BinderResult, Strong,
+};
+use anyhow::{Context, Result};
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// The first KeyMint version that supports keys with purpose ATTEST_KEY.
+const KEY_MINT_V1: i32 = 100;
+/// The first KeyMint version that supports Ed25519 and X25519 keys.
+const KEY_MINT_V2: i32 = 200;
+
+/// RSA key sizes that are probed.
+const RSA_KEY_SIZES: &[i32] = &[2048, 3072, 4096];
+/// AES key sizes that are probed.
+const AES_KEY_SIZES: &[i32] = &[128, 192, 256];
+/// HMAC key sizes that are probed.
+const HMAC_KEY_SIZES: &[i32] = &[64, 128, 256, 512];
+/// EC curves that are probed along with their key sizes.
+const EC_CURVES: &[(EcCurve, i32)] = &[
+    (EcCurve::P_224, 224),
+    (EcCurve::P_256, 256),
+    (EcCurve::P_384, 384),
+    (EcCurve::P_521, 521),
+    (EcCurve::CURVE_25519, 256),
+];
+
+/// Digests that are probed.
+const DIGESTS: &[Digest] = &[
+    Digest::NONE,
+    Digest::MD5,
+    Digest::SHA1,
+    Digest::SHA_2_224,
+    Digest::SHA_2_256,
+    Digest::SHA_2_384,
+    Digest::SHA_2_512,
+];
+/// RSA padding modes that are probed.
+const RSA_PADDING_MODES: &[PaddingMode] = &[
+    PaddingMode::NONE,
+    PaddingMode::RSA_OAEP,
+    PaddingMode::RSA_PSS,
+    PaddingMode::RSA_PKCS1_1_5_ENCRYPT,
+    PaddingMode::RSA_PKCS1_1_5_SIGN,
+];
+/// Block cipher padding modes that are probed.
+const BLOCK_CIPHER_PADDING_MODES: &[PaddingMode] = &[PaddingMode::NONE, PaddingMode::PKCS7];
+/// AES block modes that are probed.
+const AES_BLOCK_MODES: &[BlockMode] =
+    &[BlockMode::ECB, BlockMode::CBC, BlockMode::CTR, BlockMode::GCM];
+/// Triple DES block modes that are probed.
+const TRIPLE_DES_BLOCK_MODES: &[BlockMode] = &[BlockMode::ECB, BlockMode::CBC];
+
+lazy_static! {
+    /// Probed algorithm capabilities by KeyMint instance. None while probing is in progress.
+    static ref ALGORITHM_CAPABILITIES: Mutex<HashMap<Uuid, Option<Vec<AlgorithmCapabilities>>>> =
+        Default::default();
+    /// Probes one KeyMint instance at a time, without holding up the other background tasks.
+    static ref PROBE_WORKER: AsyncTask = Default::default();
+}
+
+/// Implementation of the IKeystoreCapabilities AIDL interface.
+pub struct Capabilities;
+
+impl Capabilities {
+    /// Creates a new instance of the capabilities service.
+    pub fn new_native_binder() -> Result<Strong<dyn IKeystoreCapabilities>> {
+        Ok(BnKeystoreCapabilities::new_binder(
+            Self,
+            BinderFeatures { set_requesting_sid: true, ..BinderFeatures::default() },
+        ))
+    }
+
+    fn get_security_level_capabilities() -> Result<Vec<SecurityLevelCapabilities>> {
+        Ok([SecurityLevel::TRUSTED_ENVIRONMENT, SecurityLevel::STRONGBOX]
+            .iter()
+            .map(|sec_level| match get_keymint_device(sec_level) {
+                Ok((_, hw_info, _)) => Self::capabilities_from_hw_info(*sec_level, &hw_info),
+                Err(e) => {
+                    log::info!(
+                        "In get_security_level_capabilities: {:?} is not available: {:?}",
+                        sec_level,
+                        e
+                    );
+                    SecurityLevelCapabilities {
+                        securityLevel: *sec_level,
+                        available: false,
+                        ..Default::default()
+                    }
+                }
+            })
+            .collect())
+    }
+
+    fn capabilities_from_hw_info(
+        sec_level: SecurityLevel,
+        hw_info: &KeyMintHardwareInfo,
+    ) -> SecurityLevelCapabilities {
+        SecurityLevelCapabilities {
+            securityLevel: sec_level,
+            available: true,
+            keyMintVersion: hw_info.versionNumber,
+            keyMintName: hw_info.keyMintName.clone(),
+            keyMintAuthorName: hw_info.keyMintAuthorName.clone(),
+            timestampTokenRequired: hw_info.timestampTokenRequired,
+            supportsCurve25519: hw_info.versionNumber >= KEY_MINT_V2,
+            supportsAttestKey: hw_info.versionNumber >= KEY_MINT_V1,
+            supportsRemoteProvisioning: get_remotely_provisioned_component(&sec_level).is_ok(),
+        }
+    }
+
+    fn get_supported_algorithms(sec_level: SecurityLevel) -> Result<Vec<AlgorithmCapabilities>> {
+        check_keystore_permission(KeystorePerm::GetCapabilities)
+            .context("In get_supported_algorithms.")?;
+        let (_, _, km_uuid) =
+            get_keymint_device(&sec_level).context("In get_supported_algorithms.")?;
+
+        match ALGORITHM_CAPABILITIES.lock().unwrap().get(&km_uuid) {
+            Some(Some(capabilities)) => return Ok(capabilities.clone()),
+            Some(None) => {
+                return Err(Error::Rc(ResponseCode::BACKEND_BUSY))
+                    .context("In get_supported_algorithms: Probing is in progress.")
+            }
+            None => {}
+        }
+        // The instance was not available or was replaced since probing was started.
+        Self::start_probing(sec_level);
+        Err(Error::Rc(ResponseCode::BACKEND_BUSY))
+            .context("In get_supported_algorithms: Probing was started.")
+    }
+
+    /// Probes the algorithms supported by the KeyMint instances of all security levels in the
+    /// background. This is called once at startup, so that the capabilities are known by the
+    /// time they are queried.
+    pub fn probe_all_in_background() {
+        for sec_level in [SecurityLevel::TRUSTED_ENVIRONMENT, SecurityLevel::STRONGBOX] {
+            Self::start_probing(sec_level);
+        }
+    }
+
+    /// Queues the probing of the KeyMint instance backing `sec_level` unless it was probed
+    /// already or is being probed.
+    fn start_probing(sec_level: SecurityLevel) {
+        PROBE_WORKER.queue_lo(move |_| {
+            let (km_dev, _, km_uuid) = match get_keymint_device(&sec_level) {
+                Ok(dev) => dev,
+                Err(e) => {
+                    log::info!("In start_probing: {:?} is not available: {:?}", sec_level, e);
+                    return;
+                }
+            };
+            {
+                let mut cache = ALGORITHM_CAPABILITIES.lock().unwrap();
+                if cache.contains_key(&km_uuid) {
+                    return;
+                }
+                cache.insert(km_uuid, None);
+            }
+            // The lock is not held while probing, so that callers learn that probing is in
+            // progress instead of waiting for it.
+            let capabilities = Self::probe_algorithms(&km_dev);
+            ALGORITHM_CAPABILITIES.lock().unwrap().insert(km_uuid, Some(capabilities));
+        });
+    }
+
+    fn probe_algorithms(km_dev: &Strong<dyn IKeyMintDevice>) -> Vec<AlgorithmCapabilities> {
+        let probe_sizes =
+            |sizes: &[i32], params: &dyn Fn(i32) -> Vec<KeyParameterValue>| -> Vec<i32> {
+                sizes.iter().copied().filter(|size| Self::probe(km_dev, params(*size))).collect()
+            };
+
+        let rsa_base = |size| {
+            vec![
+                KeyParameterValue::Algorithm(Algorithm::RSA),
+                KeyParameterValue::KeySize(size),
+                KeyParameterValue::RSAPublicExponent(65537),
+                KeyParameterValue::KeyPurpose(KeyPurpose::SIGN),
+                KeyParameterValue::KeyPurpose(KeyPurpose::DECRYPT),
+            ]
+        };
+        let aes_base = |size| {
+            vec![
+                KeyParameterValue::Algorithm(Algorithm::AES),
+                KeyParameterValue::KeySize(size),
+                KeyParameterValue::KeyPurpose(KeyPurpose::ENCRYPT),
+                KeyParameterValue::KeyPurpose(KeyPurpose::DECRYPT),
+            ]
+        };
+        let triple_des_base = |size| {
+            vec![
+                KeyParameterValue::Algorithm(Algorithm::TRIPLE_DES),
+                KeyParameterValue::KeySize(size),
+                KeyParameterValue::KeyPurpose(KeyPurpose::ENCRYPT),
+                KeyParameterValue::KeyPurpose(KeyPurpose::DECRYPT),
+            ]
+        };
+        let hmac_base = |size| {
+            vec![
+                KeyParameterValue::Algorithm(Algorithm::HMAC),
+                KeyParameterValue::KeySize(size),
+                KeyParameterValue::KeyPurpose(KeyPurpose::SIGN),
+                KeyParameterValue::MinMacLength(128),
+            ]
+        };
+        let ec_base = |curve| {
+            vec![
+                KeyParameterValue::Algorithm(Algorithm::EC),
+                KeyParameterValue::EcCurve(curve),
+                KeyParameterValue::KeyPurpose(KeyPurpose::SIGN),
+            ]
+        };
+        // Block ciphers need a block mode, HMAC keys need a digest.
+        let ecb = || vec![KeyParameterValue::BlockMode(BlockMode::ECB)];
+        let sha256 = || vec![KeyParameterValue::Digest(Digest::SHA_2_256)];
+
+        let rsa_sizes = probe_sizes(RSA_KEY_SIZES, &rsa_base);
+        let aes_sizes = probe_sizes(AES_KEY_SIZES, &|size| [aes_base(size), ecb()].concat());
+        let triple_des_sizes = probe_sizes(&[168], &|size| [triple_des_base(size), ecb()].concat());
+        let hmac_sizes = probe_sizes(HMAC_KEY_SIZES, &|size| [hmac_base(size), sha256()].concat());
+        let (ec_curves, mut ec_sizes): (Vec<EcCurve>, Vec<i32>) = EC_CURVES
+            .iter()
+            .copied()
+            .filter(|(curve, _)| Self::probe(km_dev, ec_base(*curve)))
+            .unzip();
+        ec_sizes.sort_unstable();
+        ec_sizes.dedup();
+
+        // Digests, padding modes, and block modes are probed with the smallest supported key
+        // size. Ed25519 keys only support Digest::NONE, so a NIST curve is used for EC.
+        let mut capabilities = Vec::new();
+        if let Some(&size) = rsa_sizes.first() {
+            let base = rsa_base(size);
+            capabilities.push(AlgorithmCapabilities {
+                algorithm: Algorithm::RSA,
+                keySizes: rsa_sizes,
+                digests: Self::probe_each(km_dev, &base, DIGESTS, |d| {
+                    vec![KeyParameterValue::Digest(d)]
+                }),
+                paddingModes: Self::probe_each(km_dev, &base, RSA_PADDING_MODES, |p| {
+                    vec![KeyParameterValue::PaddingMode(p)]
+                }),
+                ..Default::default()
+            });
+        }
+        if !ec_curves.is_empty() {
+            let digests = match ec_curves.iter().find(|curve| **curve != EcCurve::CURVE_25519) {
+                Some(curve) => Self::probe_each(km_dev, &ec_base(*curve), DIGESTS, |d| {
+                    vec![KeyParameterValue::Digest(d)]
+                }),
+                None => vec![Digest::NONE],
+            };
+            capabilities.push(AlgorithmCapabilities {
+                algorithm: Algorithm::EC,
+                keySizes: ec_sizes,
+                ecCurves: ec_curves,
+                digests,
+                ..Default::default()
+            });
+        }
+        if let Some(&size) = aes_sizes.first() {
+            let base = aes_base(size);
+            capabilities.push(AlgorithmCapabilities {
+                algorithm: Algorithm::AES,
+                keySizes: aes_sizes,
+                paddingModes: Self::probe_each(
+                    km_dev,
+                    &[base.clone(), ecb()].concat(),
+                    BLOCK_CIPHER_PADDING_MODES,
+                    |p| vec![KeyParameterValue::PaddingMode(p)],
+                ),
+                blockModes: Self::probe_each(km_dev, &base, AES_BLOCK_MODES, |m| {
+                    let mut params = vec![KeyParameterValue::BlockMode(m)];
+                    // GCM keys must specify the minimal MAC length.
+                    if m == BlockMode::GCM {
+                        params.push(KeyParameterValue::MinMacLength(128));
+                    }
+                    params
+                }),
+                ..Default::default()
+            });
+        }
+        if let Some(&size) = triple_des_sizes.first() {
+            let base = triple_des_base(size);
+            capabilities.push(AlgorithmCapabilities {
+                algorithm: Algorithm::TRIPLE_DES,
+                keySizes: triple_des_sizes,
+                paddingModes: Self::probe_each(
+                    km_dev,
+                    &[base.clone(), ecb()].concat(),
+                    BLOCK_CIPHER_PADDING_MODES,
+                    |p| vec![KeyParameterValue::PaddingMode(p)],
+                ),
+                blockModes: Self::probe_each(km_dev, &base, TRIPLE_DES_BLOCK_MODES, |m| {
+                    vec![KeyParameterValue::BlockMode(m)]
+                }),
+                ..Default::default()
+            });
+        }
+        if let Some(&size) = hmac_sizes.first() {
+            let base = hmac_base(size);
+            capabilities.push(AlgorithmCapabilities {
+                algorithm: Algorithm::HMAC,
+                keySizes: hmac_sizes,
+                digests: Self::probe_each(km_dev, &base, DIGESTS, |d| {
+                    vec![KeyParameterValue::Digest(d)]
+                }),
+                ..Default::default()
+            });
+        }
+
+        capabilities
+            .into_iter()
+            .map(|c| AlgorithmCapabilities {
+                minKeySize: c.keySizes[0],
+                maxKeySize: c.keySizes[c.keySizes.len() - 1],
+                ..c
+            })
+            .collect()
+    }
+
+    /// Returns the candidates for which a key with the `base` parameters and the parameters
+    /// returned by `params` for the candidate can be generated.
+    fn probe_each<T: Copy>(
+        km_dev: &Strong<dyn IKeyMintDevice>,
+        base: &[KeyParameterValue],
+        candidates: &[T],
+        params: impl Fn(T) -> Vec<KeyParameterValue>,
+    ) -> Vec<T> {
+        candidates
+            .iter()
+            .copied()
+            .filter(|candidate| Self::probe(km_dev, [base.to_vec(), params(*candidate)].concat()))
+            .collect()
+    }
+
+    /// Generates a key with the given parameters and deletes it again. Returns true if the key
+    /// could be generated.
+    fn probe(km_dev: &Strong<dyn IKeyMintDevice>, params: Vec<KeyParameterValue>) -> bool {
+        let is_asymmetric = params
+            .iter()
+            .any(|p| matches!(p, KeyParameterValue::Algorithm(Algorithm::RSA | Algorithm::EC)));
+        let mut params: Vec<KmKeyParameter> = params.into_iter().map(|p| p.into()).collect();
+        params.push(KeyParameterValue::NoAuthRequired.into());
+        if is_asymmetric {
+            params.push(KeyParameterValue::CertificateNotBefore(0).into());
+            params.push(KeyParameterValue::CertificateNotAfter(UNDEFINED_NOT_AFTER).into());
+        }
+
+        let result = {
+            let _wp = wd::watch_millis("In Capabilities::probe: calling generateKey", 5000);
+            map_km_error(km_dev.generateKey(&params, None))
+        };
+        match result {
+            Ok(creation_result) => {
+                let _wp = wd::watch_millis("In Capabilities::probe: calling deleteKey", 500);
+                if let Err(e) = map_km_error(km_dev.deleteKey(&creation_result.keyBlob)) {
+                    log::debug!("In Capabilities::probe: Failed to delete probe key: {:?}", e);
+                }
+                true
+            }
+            Err(e) => {
+                log::debug!("In Capabilities::probe: {:?} not supported: {:?}", params, e);
+                false
+            }
+        }
+    }
+}
+
+impl Interface for Capabilities {}
+
+impl IKeystoreCapabilities for Capabilities {
+    fn getSecurityLevelCapabilities(&self) -> BinderResult<Vec<SecurityLevelCapabilities>> {
+        let _wp = wd::watch_millis("IKeystoreCapabilities::getSecurityLevelCapabilities", 500);
+        map_or_log_err(Self::get_security_level_capabilities(), Ok)
+    }
+
+    fn getSupportedAlgorithms(
+        &self,
+        security_level: SecurityLevel,
+    ) -> BinderResult<Vec<AlgorithmCapabilities>> {
+        let _wp = wd::watch_millis("IKeystoreCapabilities::getSupportedAlgorithms", 500);
+        map_or_log_err(Self::get_supported_algorithms(security_level), Ok)
+    }
+}
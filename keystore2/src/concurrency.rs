@@ -0,0 +1,97 @@
+// Copyright 2021, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements concurrency limits for expensive service calls. A burst of slow
+//! calls, e.g., RSA-4096 key generations, must not occupy every binder thread and starve quick
+//! calls like getKeyEntry. Callers exceeding a limit are not queued, because waiting would
+//! occupy a binder thread all the same. They get `ResponseCode::BACKEND_BUSY` instead and are
+//! expected to retry.
+
+use crate::config::Tunable;
+use crate::error::Error;
+use android_system_keystore2::aidl::android::system::keystore2::ResponseCode::ResponseCode;
+use anyhow::{Context, Result};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Limits the number of concurrent calls of a service method.
+pub struct ConcurrencyLimit {
+    name: &'static str,
+    limit: &'static Tunable<usize>,
+    active: AtomicUsize,
+}
+
+/// Holds one slot of a `ConcurrencyLimit`. The slot is released when the guard is dropped.
+pub struct ConcurrencyGuard<'a> {
+    limit: &'a ConcurrencyLimit,
+}
+
+impl ConcurrencyLimit {
+    /// Creates a new limit for the method `name`. The maximal number of concurrent calls is read
+    /// from the tunable `limit` on every call; 0 means unlimited.
+    pub const fn new(name: &'static str, limit: &'static Tunable<usize>) -> Self {
+        Self { name, limit, active: AtomicUsize::new(0) }
+    }
+
+    /// Takes a slot, or fails with `ResponseCode::BACKEND_BUSY` if all slots are taken.
+    pub fn try_acquire(&self) -> Result<ConcurrencyGuard> {
+        let limit = self.limit.get();
+        self.active
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |active| {
+                if limit == 0 || active < limit {
+                    Some(active + 1)
+                } else {
+                    None
+                }
+            })
+            .map(|_| ConcurrencyGuard { limit: self })
+            .map_err(|active| {
+                log::warn!("{}: {} of {} concurrent calls in flight.", self.name, active, limit);
+                Error::Rc(ResponseCode::BACKEND_BUSY)
+            })
+            .context(format!("In ConcurrencyLimit::try_acquire: {} is busy.", self.name))
+    }
+
+    /// Returns the number of calls currently holding a slot.
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for ConcurrencyGuard<'_> {
+    fn drop(&mut self) {
+        self.limit.active.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::get_error_code;
+
+    static TEST_LIMIT: Tunable<usize> = Tunable::new("keystore2.test.concurrency_limit", 2);
+
+    #[test]
+    fn test_try_acquire() {
+        let limit = ConcurrencyLimit::new("test", &TEST_LIMIT);
+        let first = limit.try_acquire().expect("First slot should be free.");
+        let second = limit.try_acquire().expect("Second slot should be free.");
+        let busy = limit.try_acquire().err().expect("All slots should be taken.");
+        assert_eq!(ResponseCode::BACKEND_BUSY.0, get_error_code(&busy));
+        assert_eq!(2, limit.active());
+        drop(first);
+        let _third = limit.try_acquire().expect("A slot should have been released.");
+        drop(second);
+        assert_eq!(1, limit.active());
+    }
+}
@@ -0,0 +1,302 @@
+// Copyright 2021, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module holds the tunables of Keystore 2.0. Each tunable is backed by a system property
+//! that is read every time the tunable is queried, so that tunables can be adjusted at runtime
+//! using `setprop`. Devices can ship their own defaults in a config file, which is read once
+//! at startup and holds one `<property>=<value>` line per tunable. Empty lines and lines
+//! starting with `#` are ignored. A value set as system property takes precedence over the
+//! config file, which takes precedence over the built-in default. Malformed values are
+//! ignored.
+
+use anyhow::{Context, Result};
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::RwLock;
+
+/// The location of the config file with device specific defaults.
+pub const CONFIG_FILE_PATH: &str = "/vendor/etc/keystore2.conf";
+
+lazy_static! {
+    /// Values read from the config file.
+    static ref CONFIG_FILE_VALUES: RwLock<HashMap<String, String>> = Default::default();
+}
+
+/// Values of tunables must be parsable from the string representation of a system property.
+pub trait TunableValue: Copy {
+    /// Parses the value, returning None if it is malformed.
+    fn parse(value: &str) -> Option<Self>;
+}
+
+impl TunableValue for u64 {
+    fn parse(value: &str) -> Option<Self> {
+        value.trim().parse().ok()
+    }
+}
+
+impl TunableValue for usize {
+    fn parse(value: &str) -> Option<Self> {
+        value.trim().parse().ok()
+    }
+}
+
+impl TunableValue for bool {
+    /// Accepts the same spellings as Android's property_get_bool.
+    fn parse(value: &str) -> Option<Self> {
+        match value.trim() {
+            "1" | "y" | "yes" | "on" | "true" => Some(true),
+            "0" | "n" | "no" | "off" | "false" => Some(false),
+            _ => None,
+        }
+    }
+}
+
+/// A tunable of Keystore backed by a system property.
+pub struct Tunable<T: TunableValue> {
+    property: &'static str,
+    default: T,
+}
+
+impl<T: TunableValue> Tunable<T> {
+    /// Creates a tunable backed by the given system property.
+    pub const fn new(property: &'static str, default: T) -> Self {
+        Self { property, default }
+    }
+
+    /// Returns the current value of the tunable.
+    pub fn get(&self) -> T {
+        if let Ok(Some(value)) = rustutils::system_properties::read(self.property) {
+            if let Some(value) = T::parse(&value) {
+                return value;
+            }
+        }
+        CONFIG_FILE_VALUES
+            .read()
+            .unwrap()
+            .get(self.property)
+            .and_then(|value| T::parse(value))
+            .unwrap_or(self.default)
+    }
+}
+
+/// Soft limit of concurrent regular operations per uid. Operations of uids exceeding the
+/// limit are pruned first when KeyMint runs out of operation slots.
+pub static OPERATION_QUOTA_PER_UID: Tunable<u64> =
+    Tunable::new("persist.keystore2.op_quota_per_uid", 4);
+
+/// Operations of owners within their quota are only pruned if they have not been used for at
+/// least this many seconds.
+pub static OPERATION_MIN_IDLE_SECS: Tunable<u64> =
+    Tunable::new("persist.keystore2.op_min_idle_secs", 5);
+
+/// Operations that were kept alive within this many seconds are exempt from pruning by other
+/// uids, unless their owner exceeds `OPERATION_QUOTA_PER_UID`.
+pub static OPERATION_KEEP_ALIVE_SECS: Tunable<u64> =
+    Tunable::new("persist.keystore2.op_keep_alive_secs", 30);
+
+/// Maximal input size of a single call into the TEE KeyMint instance.
+pub static MAX_CHUNK_SIZE_TEE: Tunable<usize> =
+    Tunable::new("persist.keystore2.max_chunk_size.tee", 0x8000);
+
+/// Maximal input size of a single call into the StrongBox KeyMint instance. StrongBox
+/// implementations typically have small I/O buffers.
+pub static MAX_CHUNK_SIZE_STRONGBOX: Tunable<usize> =
+    Tunable::new("persist.keystore2.max_chunk_size.strongbox", 0x1000);
+
+/// Time in milliseconds a StrongBox `begin` may wait for an operation slot to become available
+/// before `ResponseCode::BACKEND_BUSY` is surfaced to the caller. StrongBox implementations
+/// often support very few concurrent operations. 0 disables the queue.
+pub static STRONGBOX_BEGIN_QUEUE_MILLIS: Tunable<u64> =
+    Tunable::new("persist.keystore2.strongbox_begin_queue_millis", 250);
+
+/// Maximal number of StrongBox `begin` requests waiting for an operation slot at a time.
+/// Further requests fail immediately.
+pub static STRONGBOX_BEGIN_QUEUE_LENGTH: Tunable<usize> =
+    Tunable::new("persist.keystore2.strongbox_begin_queue_length", 4);
+
+/// Number of superseded key blobs the garbage collector loads from the database at a time.
+pub static GC_BATCH_SIZE: Tunable<usize> = Tunable::new("persist.keystore2.gc_batch_size", 20);
+
+/// Number of keys handled by a single job of the background key blob upgrade sweep.
+pub static UPGRADE_SWEEP_BATCH_SIZE: Tunable<usize> =
+    Tunable::new("persist.keystore2.upgrade_sweep_batch_size", 20);
+
+/// Whether keys are also written to the legacy keystore, so that a downgrade to Keystore 1.0
+/// does not lose them.
+pub static LEGACY_SHADOW_WRITE: Tunable<bool> =
+    Tunable::new("persist.keystore2.legacy_shadow_write", false);
+
+/// Whether callers of the legacy keystore blob store must hold the `use_legacy_blob_store`
+/// permission. If not set, denials are only logged, so that existing clients keep working
+/// until the device policy grants the permission.
+pub static LEGACY_BLOB_STORE_PERMISSION_ENFORCED: Tunable<bool> =
+    Tunable::new("persist.keystore2.legacy_blob_store_permission_enforced", false);
+
+/// Maximal number of keys per app, 0 means unlimited. Creating a key in a full namespace fails
+/// with `ResponseCode::INVALID_ARGUMENT`. See `KeystoreSecurityLevel::check_key_quota`.
+pub static MAX_KEYS_PER_UID: Tunable<usize> =
+    Tunable::new("persist.keystore2.max_keys_per_uid", 10000);
+
+/// Maximal number of keys per SELinux namespace, 0 means unlimited. Enforced like
+/// `MAX_KEYS_PER_UID`.
+pub static MAX_KEYS_PER_NAMESPACE: Tunable<usize> =
+    Tunable::new("persist.keystore2.max_keys_per_namespace", 10000);
+
+/// Whether Keystore falls back to the in-process software KeyMint implementation if neither a
+/// KeyMint nor a Keymaster HAL is available for the TEE. Meant for emulators and GSI targets
+/// only; keys of the software implementation have no hardware protection.
+pub static SOFTWARE_KEYMINT_FALLBACK: Tunable<bool> =
+    Tunable::new("ro.keystore2.software_keymint_fallback", false);
+
+/// Maximal number of binder threads serving Keystore, 0 leaves the libbinder default in place.
+/// Read once at startup; the `--binder-threads` command line option takes precedence.
+pub static BINDER_THREADS: Tunable<usize> = Tunable::new("ro.keystore2.binder_threads", 0);
+
+/// Maximal number of concurrent generateKey calls on the TEE security level, 0 means unlimited.
+pub static MAX_CONCURRENT_GENERATE_KEY_TEE: Tunable<usize> =
+    Tunable::new("persist.keystore2.max_concurrent_generate_key.tee", 8);
+
+/// Maximal number of concurrent generateKey calls on the StrongBox security level, 0 means
+/// unlimited. StrongBox generates one key at a time anyway, so waiting calls would only occupy
+/// binder threads.
+pub static MAX_CONCURRENT_GENERATE_KEY_STRONGBOX: Tunable<usize> =
+    Tunable::new("persist.keystore2.max_concurrent_generate_key.strongbox", 2);
+
+/// Maximal number of queued asynchronous key generations per uid, 0 means unlimited.
+pub static MAX_PENDING_ASYNC_KEYGENS_PER_UID: Tunable<usize> =
+    Tunable::new("persist.keystore2.max_pending_async_keygens_per_uid", 4);
+
+/// Whether the key blobs of client keys are envelope encrypted with a database key that is
+/// derived from a Keystore internal TEE key. Read at startup; existing key blobs are migrated
+/// in the background.
+pub static BLOB_ENVELOPE_ENCRYPTION: Tunable<bool> =
+    Tunable::new("ro.keystore2.blob_envelope_encryption", false);
+
+/// Interval between two health checks of the cached KeyMint and secure clock connections, 0
+/// disables the health checks.
+pub static HAL_HEALTH_CHECK_INTERVAL_SECS: Tunable<u64> =
+    Tunable::new("persist.keystore2.hal_health_check_interval_secs", 600);
+
+/// Time a HAL instance has to answer a health check ping before it is considered degraded.
+pub static HAL_HEALTH_CHECK_DEADLINE_MILLIS: Tunable<u64> =
+    Tunable::new("persist.keystore2.hal_health_check_deadline_millis", 2000);
+
+/// Whether heavy background work, such as key blob upgrade sweeps, is deferred until the device
+/// is idle and charging. See `idle_maintenance`.
+pub static DEFER_MAINTENANCE: Tunable<bool> =
+    Tunable::new("persist.keystore2.defer_maintenance", true);
+
+/// Deferred background work is started after at most this many seconds, even if the device was
+/// never idle and charging in the meantime.
+pub static MAX_MAINTENANCE_DEFERRAL_SECS: Tunable<u64> =
+    Tunable::new("persist.keystore2.max_maintenance_deferral_secs", 24 * 60 * 60);
+
+/// Maximal number of IKeystoreService calls per second of an app, 0 means unlimited. See
+/// `rate_limit`.
+pub static RATE_LIMIT_SERVICE: Tunable<u64> =
+    Tunable::new("persist.keystore2.rate_limit.service", 100);
+
+/// Number of IKeystoreService calls an app can make in a burst above its rate limit.
+pub static RATE_LIMIT_BURST_SERVICE: Tunable<u64> =
+    Tunable::new("persist.keystore2.rate_limit_burst.service", 200);
+
+/// Maximal number of key generations and imports per second of an app, 0 means unlimited.
+pub static RATE_LIMIT_KEY_CREATION: Tunable<u64> =
+    Tunable::new("persist.keystore2.rate_limit.key_creation", 5);
+
+/// Number of key generations and imports an app can make in a burst above its rate limit.
+pub static RATE_LIMIT_BURST_KEY_CREATION: Tunable<u64> =
+    Tunable::new("persist.keystore2.rate_limit_burst.key_creation", 20);
+
+/// Maximal number of operations an app can begin per second, 0 means unlimited.
+pub static RATE_LIMIT_OPERATION: Tunable<u64> =
+    Tunable::new("persist.keystore2.rate_limit.operation", 50);
+
+/// Number of operations an app can begin in a burst above its rate limit.
+pub static RATE_LIMIT_BURST_OPERATION: Tunable<u64> =
+    Tunable::new("persist.keystore2.rate_limit_burst.operation", 100);
+
+/// Number of failed startups in a row after which Keystore starts in safe mode, 0 disables safe
+/// mode. See `safe_mode`.
+pub static SAFE_MODE_CRASH_THRESHOLD: Tunable<u64> =
+    Tunable::new("persist.keystore2.safe_mode_crash_threshold", 3);
+
+/// Number of seconds Keystore must keep running after starting all of its subsystems before a
+/// startup counts as successful. See `safe_mode`.
+pub static SAFE_MODE_GRACE_PERIOD_SECS: Tunable<u64> =
+    Tunable::new("persist.keystore2.safe_mode_grace_period_secs", 60);
+
+/// Reads the device specific defaults from the given config file, replacing all values read
+/// before. A missing config file is not an error, it just leaves all defaults in place.
+pub fn load_config_file(path: &Path) -> Result<()> {
+    let values = match std::fs::read_to_string(path) {
+        Ok(contents) => parse_config(&contents),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+        Err(e) => {
+            return Err(e).context(format!("In load_config_file: Failed to read {:?}.", path))
+        }
+    };
+    *CONFIG_FILE_VALUES.write().unwrap() = values;
+    Ok(())
+}
+
+fn parse_config(contents: &str) -> HashMap<String, String> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| match line.split_once('=') {
+            Some((property, value)) => {
+                Some((property.trim().to_string(), value.trim().to_string()))
+            }
+            None => {
+                log::warn!("In parse_config: Ignoring malformed line {:?}.", line);
+                None
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_config() {
+        let config = parse_config(
+            "# Comment\n\
+             persist.keystore2.gc_batch_size = 10\n\
+             \n\
+             malformed line\n\
+             persist.keystore2.legacy_shadow_write=on\n",
+        );
+        assert_eq!(2, config.len());
+        assert_eq!(Some("10"), config.get("persist.keystore2.gc_batch_size").map(String::as_str));
+        assert_eq!(
+            Some("on"),
+            config.get("persist.keystore2.legacy_shadow_write").map(String::as_str)
+        );
+    }
+
+    #[test]
+    fn test_parse_values() {
+        assert_eq!(Some(20u64), u64::parse(" 20"));
+        assert_eq!(None, u64::parse("-1"));
+        assert_eq!(Some(0usize), usize::parse("0"));
+        assert_eq!(Some(true), bool::parse("yes"));
+        assert_eq!(Some(false), bool::parse("0"));
+        assert_eq!(None, bool::parse("maybe"));
+    }
+}
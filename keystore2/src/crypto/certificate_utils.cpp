@@ -403,7 +403,8 @@ CertUtilsError signCert(X509* certificate, EVP_PKEY* signing_key) {
         return CertUtilsError::UnexpectedNullPointer;
     }
 
-    if (!X509_sign(certificate, signing_key, EVP_sha256())) {
+    const EVP_MD* md = EVP_PKEY_id(signing_key) == EVP_PKEY_ED25519 ? nullptr : EVP_sha256();
+    if (!X509_sign(certificate, signing_key, md)) {
         return CertUtilsError::BoringSsl;
     }
 
@@ -583,6 +584,10 @@ std::variant<CertUtilsError, X509_ALGOR_Ptr> makeAlgo(Algo algo, Padding padding
             return CertUtilsError::InvalidArgument;
         }
         break;
+    case Algo::ED25519:
+        // RFC 8410: The parameters of the Ed25519 algorithm identifier must be absent.
+        nid = NID_ED25519;
+        break;
     default:
         return CertUtilsError::InvalidArgument;
     }
@@ -631,4 +636,36 @@ CertUtilsError signCertWith(X509* certificate,
     return CertUtilsError::Ok;
 }
 
+CertUtilsError
+signCertificateRequestWith(X509_REQ* request,
+                           std::function<std::vector<uint8_t>(const uint8_t*, size_t)> sign,
+                           Algo algo, Padding padding, Digest digest) {
+    auto algo_objV = makeAlgo(algo, padding, digest);
+    if (auto error = std::get_if<CertUtilsError>(&algo_objV)) {
+        return *error;
+    }
+    auto& algo_obj = std::get<X509_ALGOR_Ptr>(algo_objV);
+    if (!X509_REQ_set1_signature_algo(request, algo_obj.get())) {
+        return CertUtilsError::BoringSsl;
+    }
+
+    uint8_t* req_buf = nullptr;
+    int buf_len = i2d_re_X509_REQ_tbs(request, &req_buf);
+    if (buf_len < 0) {
+        return CertUtilsError::Encoding;
+    }
+
+    bssl::UniquePtr<uint8_t> free_req_buf(req_buf);
+    auto signature = sign(req_buf, buf_len);
+    if (signature.empty()) {
+        return CertUtilsError::SignatureFailed;
+    }
+
+    if (!X509_REQ_set1_signature_value(request, signature.data(), signature.size())) {
+        return CertUtilsError::BoringSsl;
+    }
+
+    return CertUtilsError::Ok;
+}
+
 }  // namespace keystore
@@ -17,18 +17,26 @@
 #define LOG_TAG "keystore2"
 
 #include "crypto.hpp"
+#include "certificate_utils.h"
 
 #include <log/log.h>
 #include <openssl/aes.h>
+#include <openssl/bytestring.h>
 #include <openssl/ec.h>
 #include <openssl/ec_key.h>
 #include <openssl/ecdh.h>
+#include <openssl/err.h>
 #include <openssl/evp.h>
 #include <openssl/hkdf.h>
 #include <openssl/hmac.h>
+#include <openssl/md5.h>
+#include <openssl/mem.h>
+#include <openssl/pkcs8.h>
 #include <openssl/rand.h>
 #include <openssl/x509.h>
 
+#include <climits>
+#include <cstring>
 #include <vector>
 
 // Copied from system/security/keystore/blob.h.
@@ -155,6 +163,58 @@ bool AES_gcm_decrypt(const uint8_t* in, uint8_t* out, size_t len, const uint8_t*
     return true;
 }
 
+/*
+ * Decrypt 'len' data at 'in' with AES-CBC without padding, using 128-bit or 256-bit key at 'key'
+ * and 128-bit IV at 'iv', writing plaintext to 'out' (which may be the same location as 'in').
+ * Keystore used this scheme to encrypt blobs before Android P.
+ */
+bool AES_cbc_decrypt(const uint8_t* in, uint8_t* out, size_t len, const uint8_t* key,
+                     size_t key_size, const uint8_t* iv) {
+    if (len % AES_BLOCK_SIZE != 0) {
+        ALOGE("Ciphertext length %zu is not a multiple of the AES block size", len);
+        return false;
+    }
+
+    const EVP_CIPHER* cipher = EVP_aes_256_cbc();
+    if (key_size == kAes128KeySizeBytes) {
+        cipher = EVP_aes_128_cbc();
+    }
+
+    bssl::UniquePtr<EVP_CIPHER_CTX> ctx(EVP_CIPHER_CTX_new());
+
+    if (!EVP_DecryptInit_ex(ctx.get(), cipher, nullptr /* engine */, key, iv)) {
+        return false;
+    }
+    EVP_CIPHER_CTX_set_padding(ctx.get(), 0 /* the plaintext was zero padded by keystore */);
+
+    std::vector<uint8_t> out_tmp(len);
+    ArrayEraser out_eraser(out_tmp.data(), len);
+    uint8_t* out_pos = out_tmp.data();
+    int out_len;
+
+    if (!EVP_DecryptUpdate(ctx.get(), out_pos, &out_len, in, len)) {
+        return false;
+    }
+    out_pos += out_len;
+    if (!EVP_DecryptFinal_ex(ctx.get(), out_pos, &out_len)) {
+        return false;
+    }
+    out_pos += out_len;
+    if (out_pos - out_tmp.data() != static_cast<ssize_t>(len)) {
+        ALOGE("Decrypted plaintext is the wrong size, expected %zu, got %zd", len,
+              out_pos - out_tmp.data());
+        return false;
+    }
+
+    std::copy(out_tmp.data(), out_pos, out);
+
+    return true;
+}
+
+bool MD5Digest(const uint8_t* in, size_t len, uint8_t* out) {
+    return MD5(in, len, out) != nullptr;
+}
+
 // Copied from system/security/keystore/keymaster_enforcement.cpp.
 
 class EvpMdCtx {
@@ -290,6 +350,18 @@ EC_POINT* ECPOINTOct2Point(const uint8_t* buf, size_t len) {
     return point;
 }
 
+static int copyToBuffer(const std::vector<uint8_t>& data, uint8_t* out_buf, size_t out_buf_len) {
+    if (data.size() > static_cast<size_t>(INT_MAX)) {
+        ALOGE("copyToBuffer: output too large");
+        return 0;
+    }
+    if (data.size() > out_buf_len) {
+        return -static_cast<int>(data.size());
+    }
+    memcpy(out_buf, data.data(), data.size());
+    return static_cast<int>(data.size());
+}
+
 int extractSubjectFromCertificate(const uint8_t* cert_buf, size_t cert_len, uint8_t* subject_buf,
                                   size_t subject_buf_len) {
     if (!cert_buf || !subject_buf) {
@@ -328,3 +400,346 @@ int extractSubjectFromCertificate(const uint8_t* cert_buf, size_t cert_len, uint
     uint8_t* tmp = subject_buf;
     return i2d_X509_NAME(subject, &tmp);
 }
+
+int extractPublicKeyFromCertificate(const uint8_t* cert_buf, size_t cert_len, uint8_t* out_buf,
+                                    size_t out_buf_len) {
+    if (!cert_buf || !out_buf) {
+        ALOGE("extractPublicKeyFromCertificate: received null pointer");
+        return 0;
+    }
+
+    const uint8_t* p = cert_buf;
+    bssl::UniquePtr<X509> cert(d2i_X509(nullptr /* Allocate X509 struct */, &p, cert_len));
+    if (!cert) {
+        ALOGE("extractPublicKeyFromCertificate: failed to parse certificate");
+        return 0;
+    }
+
+    uint8_t* spki = nullptr;
+    int spki_len = i2d_X509_PUBKEY(X509_get_X509_PUBKEY(cert.get()), &spki);
+    if (spki_len < 0) {
+        ALOGE("extractPublicKeyFromCertificate: failed to encode public key");
+        return 0;
+    }
+    bssl::UniquePtr<uint8_t> free_spki(spki);
+    return copyToBuffer(std::vector<uint8_t>(spki, spki + spki_len), out_buf, out_buf_len);
+}
+
+int extractPublicKeyFromPrivateKey(const uint8_t* private_key, size_t private_key_len,
+                                   uint8_t* out_buf, size_t out_buf_len) {
+    if (!private_key || !out_buf) {
+        ALOGE("extractPublicKeyFromPrivateKey: received null pointer");
+        return 0;
+    }
+
+    CBS cbs;
+    CBS_init(&cbs, private_key, private_key_len);
+    bssl::UniquePtr<EVP_PKEY> pkey(EVP_parse_private_key(&cbs));
+    if (!pkey) {
+        ALOGE("extractPublicKeyFromPrivateKey: failed to parse private key");
+        return 0;
+    }
+
+    bssl::ScopedCBB cbb;
+    uint8_t* spki = nullptr;
+    size_t spki_len = 0;
+    if (!CBB_init(cbb.get(), 0) || !EVP_marshal_public_key(cbb.get(), pkey.get()) ||
+        !CBB_finish(cbb.get(), &spki, &spki_len)) {
+        ALOGE("extractPublicKeyFromPrivateKey: failed to encode public key");
+        return 0;
+    }
+    bssl::UniquePtr<uint8_t> free_spki(spki);
+    return copyToBuffer(std::vector<uint8_t>(spki, spki + spki_len), out_buf, out_buf_len);
+}
+
+int makeSelfSignedCertificate(const uint8_t* private_key, size_t private_key_len,
+                              const uint8_t* subject, size_t subject_len, const uint8_t* serial,
+                              size_t serial_len, int64_t not_before, int64_t not_after, int algo,
+                              int padding, int digest, const uint8_t* signature,
+                              size_t signature_len, uint8_t* out_buf, size_t out_buf_len) {
+    if (!private_key || !out_buf) {
+        ALOGE("makeSelfSignedCertificate: received null pointer");
+        return 0;
+    }
+    if (algo < 0 || algo > static_cast<int>(keystore::Algo::ED25519) || padding < 0 ||
+        padding > static_cast<int>(keystore::Padding::PSS) || digest < 0 ||
+        digest > static_cast<int>(keystore::Digest::SHA512)) {
+        ALOGE("makeSelfSignedCertificate: invalid signature algorithm");
+        return 0;
+    }
+
+    CBS cbs;
+    CBS_init(&cbs, private_key, private_key_len);
+    bssl::UniquePtr<EVP_PKEY> pkey(EVP_parse_private_key(&cbs));
+    if (!pkey) {
+        ALOGE("makeSelfSignedCertificate: failed to parse private key");
+        return 0;
+    }
+
+    std::vector<uint8_t> serial_vec;
+    std::optional<std::reference_wrapper<const std::vector<uint8_t>>> serial_ref;
+    if (serial) {
+        serial_vec.assign(serial, serial + serial_len);
+        serial_ref = serial_vec;
+    }
+    std::vector<uint8_t> subject_vec;
+    std::optional<std::reference_wrapper<const std::vector<uint8_t>>> subject_ref;
+    if (subject) {
+        subject_vec.assign(subject, subject + subject_len);
+        subject_ref = subject_vec;
+    }
+
+    keystore::KeyUsageExtension key_usage{
+        .isSigningKey = true,
+        .isEncryptionKey = false,
+        .isCertificationKey = false,
+    };
+    auto cert_or_error =
+        keystore::makeCert(pkey.get(), serial_ref, subject_ref, not_before, not_after,
+                           false /* subject key id extension */, key_usage, std::nullopt);
+    if (std::holds_alternative<keystore::CertUtilsError>(cert_or_error)) {
+        ALOGE("makeSelfSignedCertificate: failed to make certificate");
+        return 0;
+    }
+    auto cert = std::move(std::get<keystore::X509_Ptr>(cert_or_error));
+    if (keystore::setIssuer(cert.get(), cert.get(), false)) {
+        ALOGE("makeSelfSignedCertificate: failed to set issuer");
+        return 0;
+    }
+
+    std::vector<uint8_t> tbs;
+    auto error = keystore::signCertWith(
+        cert.get(),
+        [&](const uint8_t* data, size_t len) {
+            if (signature) {
+                return std::vector<uint8_t>(signature, signature + signature_len);
+            }
+            // First pass: hand out the to-be-signed certificate and set a placeholder signature.
+            tbs.assign(data, data + len);
+            return std::vector<uint8_t>(1);
+        },
+        static_cast<keystore::Algo>(algo), static_cast<keystore::Padding>(padding),
+        static_cast<keystore::Digest>(digest));
+    if (error) {
+        ALOGE("makeSelfSignedCertificate: failed to sign certificate");
+        return 0;
+    }
+    if (!signature) {
+        return copyToBuffer(tbs, out_buf, out_buf_len);
+    }
+
+    auto encoded_or_error = keystore::encodeCert(cert.get());
+    if (std::holds_alternative<keystore::CertUtilsError>(encoded_or_error)) {
+        ALOGE("makeSelfSignedCertificate: failed to encode certificate");
+        return 0;
+    }
+    return copyToBuffer(std::get<std::vector<uint8_t>>(encoded_or_error), out_buf, out_buf_len);
+}
+
+int makeCertificateRequest(const uint8_t* cert, size_t cert_len, const uint8_t* subject,
+                           size_t subject_len, const uint8_t* extensions, size_t extensions_len,
+                           int algo, int padding, int digest, const uint8_t* signature,
+                           size_t signature_len, uint8_t* out_buf, size_t out_buf_len) {
+    if (!cert || !subject || !out_buf) {
+        ALOGE("makeCertificateRequest: received null pointer");
+        return 0;
+    }
+    if (algo < 0 || algo > static_cast<int>(keystore::Algo::ED25519) || padding < 0 ||
+        padding > static_cast<int>(keystore::Padding::PSS) || digest < 0 ||
+        digest > static_cast<int>(keystore::Digest::SHA512)) {
+        ALOGE("makeCertificateRequest: invalid signature algorithm");
+        return 0;
+    }
+
+    const uint8_t* p = cert;
+    bssl::UniquePtr<X509> x509(d2i_X509(nullptr, &p, cert_len));
+    if (!x509) {
+        ALOGE("makeCertificateRequest: failed to parse certificate");
+        return 0;
+    }
+    bssl::UniquePtr<EVP_PKEY> pkey(X509_get_pubkey(x509.get()));
+    if (!pkey) {
+        ALOGE("makeCertificateRequest: failed to get public key");
+        return 0;
+    }
+    int expected_type = EVP_PKEY_NONE;
+    switch (static_cast<keystore::Algo>(algo)) {
+    case keystore::Algo::ECDSA:
+        expected_type = EVP_PKEY_EC;
+        break;
+    case keystore::Algo::RSA:
+        expected_type = EVP_PKEY_RSA;
+        break;
+    case keystore::Algo::ED25519:
+        expected_type = EVP_PKEY_ED25519;
+        break;
+    }
+    if (EVP_PKEY_id(pkey.get()) != expected_type) {
+        ALOGE("makeCertificateRequest: signature algorithm does not match the public key");
+        return 0;
+    }
+
+    p = subject;
+    bssl::UniquePtr<X509_NAME> name(d2i_X509_NAME(nullptr, &p, subject_len));
+    if (!name) {
+        ALOGE("makeCertificateRequest: failed to parse subject");
+        return 0;
+    }
+
+    bssl::UniquePtr<X509_REQ> req(X509_REQ_new());
+    if (!req || !X509_REQ_set_version(req.get(), 0 /* version 1 */) ||
+        !X509_REQ_set_subject_name(req.get(), name.get()) ||
+        !X509_REQ_set_pubkey(req.get(), pkey.get())) {
+        ALOGE("makeCertificateRequest: failed to populate request");
+        return 0;
+    }
+
+    if (extensions) {
+        p = extensions;
+        bssl::UniquePtr<X509_EXTENSIONS> exts(d2i_X509_EXTENSIONS(nullptr, &p, extensions_len));
+        if (!exts) {
+            ALOGE("makeCertificateRequest: failed to parse extensions");
+            return 0;
+        }
+        if (!X509_REQ_add_extensions(req.get(), exts.get())) {
+            ALOGE("makeCertificateRequest: failed to add extensions");
+            return 0;
+        }
+    }
+
+    std::vector<uint8_t> tbs;
+    auto error = keystore::signCertificateRequestWith(
+        req.get(),
+        [&](const uint8_t* data, size_t len) {
+            if (signature) {
+                return std::vector<uint8_t>(signature, signature + signature_len);
+            }
+            // First pass: hand out the request info and set a placeholder signature.
+            tbs.assign(data, data + len);
+            return std::vector<uint8_t>(1);
+        },
+        static_cast<keystore::Algo>(algo), static_cast<keystore::Padding>(padding),
+        static_cast<keystore::Digest>(digest));
+    if (error) {
+        ALOGE("makeCertificateRequest: failed to sign request");
+        return 0;
+    }
+    if (!signature) {
+        return copyToBuffer(tbs, out_buf, out_buf_len);
+    }
+
+    uint8_t* encoded = nullptr;
+    int encoded_len = i2d_X509_REQ(req.get(), &encoded);
+    if (encoded_len < 0) {
+        ALOGE("makeCertificateRequest: failed to encode request");
+        return 0;
+    }
+    bssl::UniquePtr<uint8_t> free_encoded(encoded);
+    return copyToBuffer(std::vector<uint8_t>(encoded, encoded + encoded_len), out_buf,
+                        out_buf_len);
+}
+
+// Writes the PKCS#8 encoding of pkey to out_buf following the convention of
+// extractSubjectFromCertificate. The intermediate encoding is cleansed before it is freed.
+static int marshalPrivateKey(const EVP_PKEY* pkey, uint8_t* out_buf, size_t out_buf_len) {
+    bssl::ScopedCBB cbb;
+    uint8_t* der = nullptr;
+    size_t der_len = 0;
+    if (!CBB_init(cbb.get(), 0) || !EVP_marshal_private_key(cbb.get(), pkey) ||
+        !CBB_finish(cbb.get(), &der, &der_len)) {
+        ALOGE("marshalPrivateKey: failed to encode private key");
+        return 0;
+    }
+    int result;
+    if (der_len > static_cast<size_t>(INT_MAX)) {
+        ALOGE("marshalPrivateKey: output too large");
+        result = 0;
+    } else if (der_len > out_buf_len) {
+        result = -static_cast<int>(der_len);
+    } else {
+        memcpy(out_buf, der, der_len);
+        result = static_cast<int>(der_len);
+    }
+    OPENSSL_cleanse(der, der_len);
+    OPENSSL_free(der);
+    return result;
+}
+
+int decryptPkcs8(const uint8_t* data, size_t data_len, const uint8_t* password,
+                 size_t password_len, uint8_t* out_buf, size_t out_buf_len) {
+    if (!data || !password || !out_buf) {
+        ALOGE("decryptPkcs8: received null pointer");
+        return 0;
+    }
+    CBS cbs;
+    CBS_init(&cbs, data, data_len);
+    bssl::UniquePtr<EVP_PKEY> pkey(PKCS8_parse_encrypted_private_key(
+        &cbs, reinterpret_cast<const char*>(password), password_len));
+    if (!pkey || CBS_len(&cbs) != 0) {
+        ALOGE("decryptPkcs8: failed to decrypt private key");
+        return 0;
+    }
+    return marshalPrivateKey(pkey.get(), out_buf, out_buf_len);
+}
+
+int parsePkcs12(const uint8_t* data, size_t data_len, const char* password, uint8_t* key_buf,
+                size_t* key_len, uint8_t* cert_buf, size_t* cert_len, uint8_t* chain_buf,
+                size_t* chain_len) {
+    if (!data || !password || !key_buf || !key_len || !cert_buf || !cert_len || !chain_buf ||
+        !chain_len) {
+        ALOGE("parsePkcs12: received null pointer");
+        return 0;
+    }
+    CBS cbs;
+    CBS_init(&cbs, data, data_len);
+    EVP_PKEY* raw_key = nullptr;
+    bssl::UniquePtr<STACK_OF(X509)> certs(sk_X509_new_null());
+    if (!certs || !PKCS12_get_key_and_certs(&raw_key, certs.get(), &cbs, password)) {
+        ALOGE("parsePkcs12: failed to parse container");
+        return 0;
+    }
+    bssl::UniquePtr<EVP_PKEY> pkey(raw_key);
+    if (!pkey) {
+        ALOGE("parsePkcs12: container holds no private key");
+        return 0;
+    }
+
+    // The certificate of the private key goes first, all others form the chain.
+    std::vector<uint8_t> cert;
+    std::vector<uint8_t> chain;
+    for (size_t i = 0; i < sk_X509_num(certs.get()); ++i) {
+        X509* x509 = sk_X509_value(certs.get(), i);
+        uint8_t* der = nullptr;
+        int der_len = i2d_X509(x509, &der);
+        if (der_len < 0) {
+            ALOGE("parsePkcs12: failed to encode certificate");
+            return 0;
+        }
+        bssl::UniquePtr<uint8_t> free_der(der);
+        if (cert.empty() && X509_check_private_key(x509, pkey.get())) {
+            cert.assign(der, der + der_len);
+        } else {
+            chain.insert(chain.end(), der, der + der_len);
+        }
+    }
+    // X509_check_private_key leaves an error on the queue for every mismatch.
+    ERR_clear_error();
+
+    int key_result = marshalPrivateKey(pkey.get(), key_buf, *key_len);
+    if (key_result == 0) {
+        return 0;
+    }
+    bool too_small = key_result < 0 || cert.size() > *cert_len || chain.size() > *chain_len;
+    *key_len = static_cast<size_t>(key_result < 0 ? -key_result : key_result);
+    *cert_len = cert.size();
+    *chain_len = chain.size();
+    if (too_small) {
+        if (key_result > 0) {
+            OPENSSL_cleanse(key_buf, static_cast<size_t>(key_result));
+        }
+        return -1;
+    }
+    memcpy(cert_buf, cert.data(), cert.size());
+    memcpy(chain_buf, chain.data(), chain.size());
+    return 1;
+}
@@ -30,6 +30,9 @@ extern "C" {
   bool AES_gcm_decrypt(const uint8_t* in, uint8_t* out, size_t len,
                        const uint8_t* key, size_t key_size, const uint8_t* iv,
                        const uint8_t* tag);
+  bool AES_cbc_decrypt(const uint8_t* in, uint8_t* out, size_t len,
+                       const uint8_t* key, size_t key_size, const uint8_t* iv);
+  bool MD5Digest(const uint8_t* in, size_t len, uint8_t* out);
 
   // Copied from system/security/keystore/keymaster_enforcement.h.
   typedef uint64_t km_id_t;
@@ -89,4 +92,67 @@ extern "C" {
 int extractSubjectFromCertificate(const uint8_t* cert_buf, size_t cert_len,
                                   uint8_t* subject_buf, size_t subject_buf_len);
 
+// Copies the DER-encoded SubjectPublicKeyInfo of the DER-encoded X.509 certificate in cert_buf
+// into out_buf. The return value follows the convention of extractSubjectFromCertificate.
+int extractPublicKeyFromCertificate(const uint8_t* cert_buf, size_t cert_len, uint8_t* out_buf,
+                                    size_t out_buf_len);
+
+// Writes the DER-encoded SubjectPublicKeyInfo of the PKCS#8 encoded private key in private_key
+// into out_buf. The return value follows the convention of extractSubjectFromCertificate.
+int extractPublicKeyFromPrivateKey(const uint8_t* private_key, size_t private_key_len,
+                                   uint8_t* out_buf, size_t out_buf_len);
+
+// Builds an X.509 certificate for the public key of the PKCS#8 encoded private key in
+// private_key, with itself as issuer. subject is the DER-encoded subject name and serial the
+// big-endian serial number; both may be null, in which case defaults are used. not_before and
+// not_after are given in milliseconds since the epoch. algo, padding, and digest select the
+// signature algorithm; their values correspond to keystore::Algo, keystore::Padding, and
+// keystore::Digest respectively.
+//
+// The certificate is built in two passes. If signature is null, the DER-encoded
+// to-be-signed portion of the certificate is written to out_buf. The caller signs it and calls
+// this function again with identical parameters and the signature, which writes the complete
+// DER-encoded certificate to out_buf. This allows signing with a key that is not accessible
+// outside of KeyMint.
+//
+// The return value follows the convention of extractSubjectFromCertificate: > 0 is the number
+// of bytes written into out_buf, 0 indicates an unrecoverable failure, which is logged, and
+// < 0 is the negated size required for out_buf.
+int makeSelfSignedCertificate(const uint8_t* private_key, size_t private_key_len,
+                              const uint8_t* subject, size_t subject_len, const uint8_t* serial,
+                              size_t serial_len, int64_t not_before, int64_t not_after, int algo,
+                              int padding, int digest, const uint8_t* signature,
+                              size_t signature_len, uint8_t* out_buf, size_t out_buf_len);
+
+// Builds a PKCS#10 certification request for the public key of the DER-encoded X.509 certificate
+// in cert. subject is the DER-encoded subject name of the request. extensions, which may be null,
+// is a DER-encoded SEQUENCE OF Extension that is added as extensionRequest attribute. algo,
+// padding, and digest select the signature algorithm as in makeSelfSignedCertificate; algo must
+// match the type of the public key.
+//
+// Like makeSelfSignedCertificate, the request is built in two passes. If signature is null, the
+// DER-encoded CertificationRequestInfo is written to out_buf. Otherwise the complete DER-encoded
+// request carrying the given signature is written to out_buf. The return value follows the same
+// convention.
+int makeCertificateRequest(const uint8_t* cert, size_t cert_len, const uint8_t* subject,
+                           size_t subject_len, const uint8_t* extensions, size_t extensions_len,
+                           int algo, int padding, int digest, const uint8_t* signature,
+                           size_t signature_len, uint8_t* out_buf, size_t out_buf_len);
+
+// Decrypts the encrypted PKCS#8 structure (EncryptedPrivateKeyInfo) in data with password and
+// writes the PKCS#8 encoded private key to out_buf. The return value follows the convention of
+// extractSubjectFromCertificate. Intermediate copies of the private key are cleansed.
+int decryptPkcs8(const uint8_t* data, size_t data_len, const uint8_t* password,
+                 size_t password_len, uint8_t* out_buf, size_t out_buf_len);
+
+// Parses the PKCS#12 container in data using the NUL terminated password. The PKCS#8 encoded
+// private key is written to key_buf, the DER-encoded certificate of the private key to cert_buf,
+// and the remaining DER-encoded certificates, concatenated in container order, to chain_buf.
+// On input, key_len, cert_len, and chain_len hold the sizes of the buffers; on output, the number
+// of bytes written or required. Returns 1 on success, -1 if any of the buffers was too small, in
+// which case nothing was written, and 0 on failure, which is logged.
+int parsePkcs12(const uint8_t* data, size_t data_len, const char* password, uint8_t* key_buf,
+                size_t* key_len, uint8_t* cert_buf, size_t* cert_len, uint8_t* chain_buf,
+                size_t* chain_len);
+
 #endif  //  __CRYPTO_H__
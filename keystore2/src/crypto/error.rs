@@ -95,10 +95,34 @@ pub enum Error {
     #[error("Failed to extract certificate subject.")]
     ExtractSubjectFailed,
 
+    /// This is returned if the C implementation of extractPublicKeyFromCertificate failed.
+    #[error("Failed to extract certificate public key.")]
+    ExtractPublicKeyFailed,
+
+    /// This is returned if the C implementation of makeSelfSignedCertificate failed.
+    #[error("Failed to make certificate.")]
+    MakeCertificateFailed,
+
+    /// This is returned if the C implementation of makeCertificateRequest failed.
+    #[error("Failed to make certificate request.")]
+    MakeCertificateRequestFailed,
+
+    /// This is returned if the C implementation of decryptPkcs8 failed.
+    #[error("Failed to decrypt PKCS#8 private key.")]
+    DecryptPkcs8Failed,
+
+    /// This is returned if the C implementation of parsePkcs12 failed.
+    #[error("Failed to parse PKCS#12 container.")]
+    ParsePkcs12Failed,
+
     /// This is returned if the C implementation of hmacSha256 failed.
     #[error("Failed to calculate HMAC-SHA256.")]
     HmacSha256Failed,
 
+    /// This is returned if the C implementation of MD5Digest failed.
+    #[error("Failed to calculate MD5 digest.")]
+    Md5Failed,
+
     /// Zvec error.
     #[error(transparent)]
     ZVec(#[from] zvec::Error),
@@ -42,6 +42,7 @@ DEFINE_OPENSSL_OBJECT_POINTER(X509);
 DEFINE_OPENSSL_OBJECT_POINTER(X509_ALGOR);
 DEFINE_OPENSSL_OBJECT_POINTER(X509_EXTENSION);
 DEFINE_OPENSSL_OBJECT_POINTER(X509_NAME);
+DEFINE_OPENSSL_OBJECT_POINTER(X509_REQ);
 DEFINE_OPENSSL_OBJECT_POINTER(EVP_PKEY_CTX);
 
 class CertUtilsError {
@@ -115,7 +116,8 @@ CertUtilsError setIssuer(X509* cert, const X509* signingCert, bool addAuthKeyExt
 
 /**
  * Takes a certificate, and private signing_key.
- * Signs the certificate with the latter.
+ * Signs the certificate with the latter. Ed25519 keys sign without a message digest, all other
+ * keys use SHA-256.
  */
 CertUtilsError signCert(X509* certificate, EVP_PKEY* signing_key);
 
@@ -130,6 +132,7 @@ enum class Digest {
 enum class Algo {
     ECDSA,
     RSA,
+    ED25519,
 };
 
 enum class Padding {
@@ -155,6 +158,8 @@ std::optional<std::array<char, 16>> toTimeString(int64_t timeMillis);
  * algorithm. The caller is responsible to provide a callback that actually performs the signature
  * as described by this triplet.
  * The `padding` argument is ignored if `algo` is Algo::EC.
+ * Both `padding` and `digest` are ignored if `algo` is Algo::ED25519, because Ed25519 signs the
+ *              to-be-signed certificate without a separate message digest.
  * The `digest` field controls the message digest used, and, in case of RSA with PSS padding,
  *              also the MGF1 digest.
  *
@@ -169,6 +174,22 @@ CertUtilsError signCertWith(X509* certificate,
                             std::function<std::vector<uint8_t>(const uint8_t*, size_t)> sign,
                             Algo algo, Padding padding, Digest digest);
 
+/**
+ * Like `signCertWith`, but sets the signature specifier and signature of a PKCS#10 certification
+ * request. The `sign` callback receives the DER encoded CertificationRequestInfo.
+ *
+ * @param request X509_REQ structure to be signed.
+ * @param sign Callback function used to digest and sign the DER encoded request info.
+ * @param algo Algorithm specifier used to encode the signing algorithm id of the request.
+ * @param padding Padding specifier used to encode the signing algorithm id of the request.
+ * @param digest Digest specifier used to encode the signing algorithm id of the request.
+ * @return CertUtilsError::Ok on success.
+ */
+CertUtilsError
+signCertificateRequestWith(X509_REQ* request,
+                           std::function<std::vector<uint8_t>(const uint8_t*, size_t)> sign,
+                           Algo algo, Padding padding, Digest digest);
+
 /**
  * Generates the DER representation of the given signed X509 certificate structure.
  * @param certificate
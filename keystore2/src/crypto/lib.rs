@@ -19,10 +19,13 @@ mod error;
 pub mod zvec;
 pub use error::Error;
 use keystore2_crypto_bindgen::{
-    extractSubjectFromCertificate, generateKeyFromPassword, hmacSha256, randomBytes,
-    AES_gcm_decrypt, AES_gcm_encrypt, ECDHComputeKey, ECKEYGenerateKey, ECKEYMarshalPrivateKey,
+    decryptPkcs8, extractPublicKeyFromCertificate, extractPublicKeyFromPrivateKey,
+    extractSubjectFromCertificate, generateKeyFromPassword, hmacSha256, makeCertificateRequest,
+    makeSelfSignedCertificate, parsePkcs12, randomBytes, AES_cbc_decrypt, AES_gcm_decrypt,
+    AES_gcm_encrypt, ECDHComputeKey, ECKEYGenerateKey, ECKEYMarshalPrivateKey,
     ECKEYParsePrivateKey, ECPOINTOct2Point, ECPOINTPoint2Oct, EC_KEY_free, EC_KEY_get0_public_key,
-    EC_POINT_free, HKDFExpand, HKDFExtract, EC_KEY, EC_MAX_BYTES, EC_POINT, EVP_MAX_MD_SIZE,
+    EC_POINT_free, HKDFExpand, HKDFExtract, MD5Digest, EC_KEY, EC_MAX_BYTES, EC_POINT,
+    EVP_MAX_MD_SIZE,
 };
 use std::convert::TryFrom;
 use std::convert::TryInto;
@@ -41,6 +44,10 @@ pub const AES_128_KEY_LENGTH: usize = 16;
 pub const SALT_LENGTH: usize = 16;
 /// Length of an HMAC-SHA256 tag in bytes.
 pub const HMAC_SHA256_LEN: usize = 32;
+/// Length of an AES block, which is also the length of an AES-CBC initialization vector.
+pub const AES_BLOCK_SIZE: usize = 16;
+/// Length of an MD5 digest in bytes.
+pub const MD5_DIGEST_LENGTH: usize = 16;
 
 /// Older versions of keystore produced IVs with four extra
 /// ignored zero bytes at the end; recognise and trim those.
@@ -89,6 +96,55 @@ pub fn hmac_sha256(key: &[u8], msg: &[u8]) -> Result<Vec<u8>, Error> {
     }
 }
 
+/// Computes the MD5 digest of `data`. This must only be used to verify the integrity of
+/// blobs written by Keystore before Android P.
+pub fn md5(data: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut digest = vec![0; MD5_DIGEST_LENGTH];
+    // Safety: The first argument must point to a buffer with a size given by the second
+    // argument. The output buffer must be MD5_DIGEST_LENGTH bytes long.
+    match unsafe { MD5Digest(data.as_ptr(), data.len(), digest.as_mut_ptr()) } {
+        true => Ok(digest),
+        false => Err(Error::Md5Failed),
+    }
+}
+
+/// Uses AES CBC without padding to decipher a message given an initialization vector and key.
+/// This function accepts 128 and 256-bit keys and uses AES128 and AES256 respectively based
+/// on the key length. It must only be used to decrypt blobs written by Keystore before
+/// Android P, which were encrypted with the user's master key using AES CBC.
+/// The plaintext is returned in a ZVec.
+pub fn aes_cbc_decrypt(data: &[u8], iv: &[u8], key: &[u8]) -> Result<ZVec, Error> {
+    if iv.len() != AES_BLOCK_SIZE {
+        return Err(Error::InvalidIvLength);
+    }
+    if data.len() % AES_BLOCK_SIZE != 0 {
+        return Err(Error::InvalidDataLength);
+    }
+    match key.len() {
+        AES_128_KEY_LENGTH | AES_256_KEY_LENGTH => {}
+        _ => return Err(Error::InvalidKeyLength),
+    }
+
+    let mut result = ZVec::new(data.len())?;
+
+    // Safety: The first two arguments must point to buffers with a size given by the third
+    // argument. We pass the length of the key buffer along with the key.
+    // The `iv` buffer must be 16 bytes, which we check above.
+    match unsafe {
+        AES_cbc_decrypt(
+            data.as_ptr(),
+            result.as_mut_ptr(),
+            data.len(),
+            key.as_ptr(),
+            key.len(),
+            iv.as_ptr(),
+        )
+    } {
+        true => Ok(result),
+        false => Err(Error::DecryptionFailed),
+    }
+}
+
 /// Uses AES GCM to decipher a message given an initialization vector, aead tag, and key.
 /// This function accepts 128 and 256-bit keys and uses AES128 and AES256 respectively based
 /// on the key length.
@@ -474,6 +530,370 @@ pub fn parse_subject_from_certificate(cert_buf: &[u8]) -> Result<Vec<u8>, Error>
     Ok(retval)
 }
 
+/// Uses BoringSSL to extract the DER-encoded SubjectPublicKeyInfo from a DER-encoded X.509
+/// certificate.
+pub fn parse_public_key_from_certificate(cert_buf: &[u8]) -> Result<Vec<u8>, Error> {
+    let call = |out: &mut [u8]| {
+        // Safety: extractPublicKeyFromCertificate reads at most cert_buf.len() bytes from
+        // cert_buf and writes at most out.len() bytes to out.
+        unsafe {
+            extractPublicKeyFromCertificate(
+                cert_buf.as_ptr(),
+                cert_buf.len(),
+                out.as_mut_ptr(),
+                out.len(),
+            )
+        }
+    };
+    call_with_output_buffer(call).ok_or(Error::ExtractPublicKeyFailed)
+}
+
+/// Uses BoringSSL to derive the DER-encoded SubjectPublicKeyInfo from a PKCS#8 encoded private
+/// key.
+pub fn public_key_from_private_key(private_key: &[u8]) -> Result<Vec<u8>, Error> {
+    let call = |out: &mut [u8]| {
+        // Safety: extractPublicKeyFromPrivateKey reads at most private_key.len() bytes from
+        // private_key and writes at most out.len() bytes to out.
+        unsafe {
+            extractPublicKeyFromPrivateKey(
+                private_key.as_ptr(),
+                private_key.len(),
+                out.as_mut_ptr(),
+                out.len(),
+            )
+        }
+    };
+    call_with_output_buffer(call).ok_or(Error::ExtractPublicKeyFailed)
+}
+
+/// Encodes `der` as PEM with the given label, e.g., "PUBLIC KEY", as described in RFC 7468.
+pub fn der_to_pem(label: &str, der: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut base64 = String::with_capacity((der.len() + 2) / 3 * 4);
+    for chunk in der.chunks(3) {
+        let bytes = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = u32::from(bytes[0]) << 16 | u32::from(bytes[1]) << 8 | u32::from(bytes[2]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                base64.push(ALPHABET[((n >> (18 - 6 * i)) & 0x3f) as usize] as char);
+            } else {
+                base64.push('=');
+            }
+        }
+    }
+
+    let mut pem = format!("-----BEGIN {}-----\n", label);
+    for (i, c) in base64.chars().enumerate() {
+        if i > 0 && i % 64 == 0 {
+            pem.push('\n');
+        }
+        pem.push(c);
+    }
+    if !base64.is_empty() {
+        pem.push('\n');
+    }
+    pem.push_str(&format!("-----END {}-----\n", label));
+    pem
+}
+
+/// Message digest of a certificate signature.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CertDigest {
+    /// SHA-1
+    Sha1,
+    /// SHA-224
+    Sha224,
+    /// SHA-256
+    Sha256,
+    /// SHA-384
+    Sha384,
+    /// SHA-512
+    Sha512,
+}
+
+/// Signature algorithm of a certificate built by `make_tbs_certificate`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CertSignatureAlgorithm {
+    /// ECDSA with the given digest.
+    Ecdsa(CertDigest),
+    /// RSA with PKCS#1 v1.5 padding and the given digest.
+    RsaPkcs1(CertDigest),
+    /// RSA with PSS padding, using the given digest for the message and MGF1.
+    RsaPss(CertDigest),
+    /// Ed25519, which does not use a separate message digest.
+    Ed25519,
+}
+
+impl CertSignatureAlgorithm {
+    /// Returns the values of keystore::Algo, keystore::Padding, and keystore::Digest
+    /// corresponding to this signature algorithm.
+    fn to_native(self) -> (i32, i32, i32) {
+        const ALGO_ECDSA: i32 = 0;
+        const ALGO_RSA: i32 = 1;
+        const ALGO_ED25519: i32 = 2;
+        const PADDING_IGNORED: i32 = 0;
+        const PADDING_PKCS1_5: i32 = 1;
+        const PADDING_PSS: i32 = 2;
+        let digest = |d: CertDigest| d as i32;
+        match self {
+            Self::Ecdsa(d) => (ALGO_ECDSA, PADDING_IGNORED, digest(d)),
+            Self::RsaPkcs1(d) => (ALGO_RSA, PADDING_PKCS1_5, digest(d)),
+            Self::RsaPss(d) => (ALGO_RSA, PADDING_PSS, digest(d)),
+            Self::Ed25519 => (ALGO_ED25519, PADDING_IGNORED, digest(CertDigest::Sha256)),
+        }
+    }
+}
+
+/// Parameters of a self signed certificate.
+#[derive(Debug, Clone, Copy)]
+pub struct SelfSignedCertParams<'a> {
+    /// PKCS#8 encoded private key, whose public key the certificate is issued for.
+    pub private_key: &'a [u8],
+    /// DER-encoded subject name. A default subject is used if not given.
+    pub subject: Option<&'a [u8]>,
+    /// Big-endian serial number. A default serial number is used if not given.
+    pub serial: Option<&'a [u8]>,
+    /// Start of the validity period in milliseconds since the epoch.
+    pub not_before: i64,
+    /// End of the validity period in milliseconds since the epoch.
+    pub not_after: i64,
+    /// Algorithm with which the to-be-signed certificate is signed.
+    pub algorithm: CertSignatureAlgorithm,
+}
+
+/// Returns the DER-encoded to-be-signed portion of the self signed certificate described by
+/// `params`. The caller signs it with the private key and passes the signature to
+/// `make_signed_certificate` along with the same `params`.
+pub fn make_tbs_certificate(params: &SelfSignedCertParams) -> Result<Vec<u8>, Error> {
+    make_self_signed_certificate(params, None)
+}
+
+/// Returns the DER-encoded self signed certificate described by `params` with the given
+/// signature of the to-be-signed certificate returned by `make_tbs_certificate`.
+pub fn make_signed_certificate(
+    params: &SelfSignedCertParams,
+    signature: &[u8],
+) -> Result<Vec<u8>, Error> {
+    make_self_signed_certificate(params, Some(signature))
+}
+
+fn make_self_signed_certificate(
+    params: &SelfSignedCertParams,
+    signature: Option<&[u8]>,
+) -> Result<Vec<u8>, Error> {
+    let (algo, padding, digest) = params.algorithm.to_native();
+    let ptr_len = |v: Option<&[u8]>| v.map_or((std::ptr::null(), 0), |v| (v.as_ptr(), v.len()));
+    let (subject, subject_len) = ptr_len(params.subject);
+    let (serial, serial_len) = ptr_len(params.serial);
+    let (signature, signature_len) = ptr_len(signature);
+
+    let call = |out: &mut [u8]| {
+        // Safety: makeSelfSignedCertificate reads at most the given number of bytes from each
+        // input buffer and writes at most out.len() bytes to out. Null pointers are only passed
+        // for absent optional inputs.
+        unsafe {
+            makeSelfSignedCertificate(
+                params.private_key.as_ptr(),
+                params.private_key.len(),
+                subject,
+                subject_len,
+                serial,
+                serial_len,
+                params.not_before,
+                params.not_after,
+                algo,
+                padding,
+                digest,
+                signature,
+                signature_len,
+                out.as_mut_ptr(),
+                out.len(),
+            )
+        }
+    };
+
+    call_with_output_buffer(call).ok_or(Error::MakeCertificateFailed)
+}
+
+/// Parameters of a PKCS#10 certification request.
+#[derive(Debug, Clone, Copy)]
+pub struct CertificateRequestParams<'a> {
+    /// DER-encoded certificate of the public key the request is made for.
+    pub certificate: &'a [u8],
+    /// DER-encoded subject name of the request.
+    pub subject: &'a [u8],
+    /// DER-encoded `SEQUENCE OF Extension`, which is requested via the extensionRequest
+    /// attribute if given.
+    pub extensions: Option<&'a [u8]>,
+    /// Algorithm with which the request info is signed. It must match the type of the public
+    /// key.
+    pub algorithm: CertSignatureAlgorithm,
+}
+
+/// Returns the DER-encoded CertificationRequestInfo of the request described by `params`.
+/// The caller signs it with the private key and passes the signature to
+/// `make_signed_certificate_request` along with the same `params`.
+pub fn make_certificate_request_info(params: &CertificateRequestParams) -> Result<Vec<u8>, Error> {
+    make_certificate_request(params, None)
+}
+
+/// Returns the DER-encoded certification request described by `params` with the given
+/// signature of the request info returned by `make_certificate_request_info`.
+pub fn make_signed_certificate_request(
+    params: &CertificateRequestParams,
+    signature: &[u8],
+) -> Result<Vec<u8>, Error> {
+    make_certificate_request(params, Some(signature))
+}
+
+fn make_certificate_request(
+    params: &CertificateRequestParams,
+    signature: Option<&[u8]>,
+) -> Result<Vec<u8>, Error> {
+    let (algo, padding, digest) = params.algorithm.to_native();
+    let ptr_len = |v: Option<&[u8]>| v.map_or((std::ptr::null(), 0), |v| (v.as_ptr(), v.len()));
+    let (extensions, extensions_len) = ptr_len(params.extensions);
+    let (signature, signature_len) = ptr_len(signature);
+
+    let call = |out: &mut [u8]| {
+        // Safety: makeCertificateRequest reads at most the given number of bytes from each
+        // input buffer and writes at most out.len() bytes to out. Null pointers are only passed
+        // for absent optional inputs.
+        unsafe {
+            makeCertificateRequest(
+                params.certificate.as_ptr(),
+                params.certificate.len(),
+                params.subject.as_ptr(),
+                params.subject.len(),
+                extensions,
+                extensions_len,
+                algo,
+                padding,
+                digest,
+                signature,
+                signature_len,
+                out.as_mut_ptr(),
+                out.len(),
+            )
+        }
+    };
+    call_with_output_buffer(call).ok_or(Error::MakeCertificateRequestFailed)
+}
+
+/// Decrypts an encrypted PKCS#8 private key (EncryptedPrivateKeyInfo) with the given password
+/// and returns the PKCS#8 encoded private key.
+pub fn decrypt_pkcs8(data: &[u8], password: &[u8]) -> Result<ZVec, Error> {
+    let mut result = ZVec::new(2048)?;
+    let call = |out: &mut ZVec| {
+        // Safety: decryptPkcs8 reads at most the given number of bytes from data and password,
+        // and writes at most out.len() bytes to out.
+        unsafe {
+            decryptPkcs8(
+                data.as_ptr(),
+                data.len(),
+                password.as_ptr(),
+                password.len(),
+                out.as_mut_ptr(),
+                out.len(),
+            )
+        }
+    };
+
+    let mut size = call(&mut result);
+    if size < 0 {
+        // Our buffer wasn't big enough. Make one that is just the right size and try again.
+        result = ZVec::new(usize::try_from(-size).map_err(|_e| Error::DecryptPkcs8Failed)?)?;
+        size = call(&mut result);
+    }
+    if size <= 0 {
+        return Err(Error::DecryptPkcs8Failed);
+    }
+    result.reduce_len(usize::try_from(size).map_err(|_e| Error::DecryptPkcs8Failed)?);
+    Ok(result)
+}
+
+/// Contents of a PKCS#12 container.
+#[derive(Debug)]
+pub struct Pkcs12Contents {
+    /// The PKCS#8 encoded private key.
+    pub private_key: ZVec,
+    /// The DER-encoded certificate of the private key, if the container holds one.
+    pub certificate: Option<Vec<u8>>,
+    /// The remaining DER-encoded certificates of the container, concatenated.
+    pub cert_chain: Option<Vec<u8>>,
+}
+
+/// Parses a PKCS#12 container and decrypts its private key with the given password.
+pub fn parse_pkcs12(data: &[u8], password: &[u8]) -> Result<Pkcs12Contents, Error> {
+    // The password is passed as NUL terminated string.
+    if password.contains(&0) {
+        return Err(Error::ParsePkcs12Failed);
+    }
+    let mut pw = ZVec::new(password.len() + 1)?;
+    pw[..password.len()].copy_from_slice(password);
+
+    let mut sizes = (2048, 2048, 4096);
+    // The second attempt uses the sizes reported by the first one.
+    for _ in 0..2 {
+        let (mut key_len, mut cert_len, mut chain_len) = sizes;
+        let mut private_key = ZVec::new(key_len)?;
+        let mut certificate = vec![0; cert_len];
+        let mut cert_chain = vec![0; chain_len];
+        // Safety: parsePkcs12 reads at most data.len() bytes from data and the NUL terminated
+        // pw. It writes at most the given number of bytes to each of the output buffers and
+        // updates the lengths.
+        match unsafe {
+            parsePkcs12(
+                data.as_ptr(),
+                data.len(),
+                pw.as_ptr() as *const std::os::raw::c_char,
+                private_key.as_mut_ptr(),
+                &mut key_len,
+                certificate.as_mut_ptr(),
+                &mut cert_len,
+                cert_chain.as_mut_ptr(),
+                &mut chain_len,
+            )
+        } {
+            1 => {
+                private_key.reduce_len(key_len);
+                certificate.truncate(cert_len);
+                cert_chain.truncate(chain_len);
+                return Ok(Pkcs12Contents {
+                    private_key,
+                    certificate: Some(certificate).filter(|c| !c.is_empty()),
+                    cert_chain: Some(cert_chain).filter(|c| !c.is_empty()),
+                });
+            }
+            -1 => sizes = (key_len, cert_len, chain_len),
+            _ => break,
+        }
+    }
+    Err(Error::ParsePkcs12Failed)
+}
+
+/// Calls `call` with an output buffer, retrying once with a buffer of the required size if the
+/// first one was too small. `call` follows the convention of extractSubjectFromCertificate.
+/// Returns None if `call` failed.
+fn call_with_output_buffer<F>(call: F) -> Option<Vec<u8>>
+where
+    F: Fn(&mut [u8]) -> i32,
+{
+    let mut retval = vec![0; 1024];
+    let mut size = call(&mut retval);
+    if size < 0 {
+        // Our buffer wasn't big enough. Make one that is just the right size and try again.
+        retval = vec![0; usize::try_from(-size).ok()?];
+        size = call(&mut retval);
+    }
+    if size <= 0 {
+        return None;
+    }
+
+    retval.truncate(usize::try_from(size).ok()?);
+    Some(retval)
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -596,4 +1016,274 @@ mod tests {
         assert_eq!(tag2.len(), HMAC_SHA256_LEN);
         assert_ne!(tag1a, tag2);
     }
+
+    #[test]
+    fn test_aes_cbc_decrypt() {
+        // Test vector from NIST SP 800-38A, F.2.2 CBC-AES128.Decrypt.
+        let key = [
+            0x2b, 0x7e, 0x15, 0x16, 0x28, 0xae, 0xd2, 0xa6, 0xab, 0xf7, 0x15, 0x88, 0x09, 0xcf,
+            0x4f, 0x3c,
+        ];
+        let iv = [
+            0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d,
+            0x0e, 0x0f,
+        ];
+        let ciphertext = [
+            0x76, 0x49, 0xab, 0xac, 0x81, 0x19, 0xb2, 0x46, 0xce, 0xe9, 0x8e, 0x9b, 0x12, 0xe9,
+            0x19, 0x7d,
+        ];
+        let plaintext = [
+            0x6b, 0xc1, 0xbe, 0xe2, 0x2e, 0x40, 0x9f, 0x96, 0xe9, 0x3d, 0x7e, 0x11, 0x73, 0x93,
+            0x17, 0x2a,
+        ];
+        assert_eq!(aes_cbc_decrypt(&ciphertext, &iv, &key).unwrap()[..], plaintext[..]);
+        assert_eq!(
+            aes_cbc_decrypt(&ciphertext[..15], &iv, &key).unwrap_err(),
+            Error::InvalidDataLength
+        );
+        assert_eq!(
+            aes_cbc_decrypt(&ciphertext, &iv[..12], &key).unwrap_err(),
+            Error::InvalidIvLength
+        );
+    }
+
+    #[test]
+    fn test_md5() {
+        let digest = md5(b"").unwrap();
+        assert_eq!(
+            digest,
+            vec![
+                0xd4, 0x1d, 0x8c, 0xd9, 0x8f, 0x00, 0xb2, 0x04, 0xe9, 0x80, 0x09, 0x98, 0xec,
+                0xf8, 0x42, 0x7e
+            ]
+        );
+    }
+
+    // A PKCS#8 encoded NIST P-256 private key.
+    static EC_P256_PKCS8: &[u8] = &[
+        0x30, 0x81, 0x87, 0x02, 0x01, 0x00, 0x30, 0x13, 0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d,
+        0x02, 0x01, 0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07, 0x04, 0x6d, 0x30,
+        0x6b, 0x02, 0x01, 0x01, 0x04, 0x20, 0xf9, 0x45, 0xbc, 0x94, 0x81, 0x33, 0x9f, 0x7e, 0x6d,
+        0x06, 0x8d, 0x7d, 0x76, 0xf0, 0xc9, 0x23, 0xfe, 0xf5, 0x21, 0x8d, 0x99, 0xab, 0xb3, 0xa4,
+        0xbf, 0x04, 0xf1, 0x3b, 0xd0, 0x95, 0x33, 0x9f, 0xa1, 0x44, 0x03, 0x42, 0x00, 0x04, 0xa5,
+        0xba, 0xf5, 0xcd, 0x83, 0x49, 0x89, 0x0b, 0x4d, 0x9b, 0xc7, 0x15, 0x78, 0x28, 0xc3, 0x5f,
+        0x9d, 0x14, 0xc4, 0xca, 0x09, 0xfc, 0x44, 0xd5, 0x17, 0x72, 0x58, 0x1e, 0x29, 0x5d, 0xba,
+        0xcf, 0xcf, 0x43, 0x1e, 0xce, 0xc2, 0x2b, 0x17, 0xc6, 0x54, 0x5d, 0x8e, 0xd7, 0x93, 0xcc,
+        0xb8, 0x1b, 0x44, 0xd7, 0x54, 0x7e, 0xd1, 0xfd, 0xd8, 0x04, 0xe6, 0x03, 0xe3, 0xbd, 0x3e,
+        0x25, 0xba, 0x27,
+    ];
+
+    static EC_P256_PKCS8_ENCRYPTED: &[u8] = &[
+        0x30, 0x81, 0xf4, 0x30, 0x5f, 0x06, 0x09, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x05,
+        0x0d, 0x30, 0x52, 0x30, 0x31, 0x06, 0x09, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x05,
+        0x0c, 0x30, 0x24, 0x04, 0x10, 0x15, 0x0b, 0xd8, 0x8d, 0xd2, 0x15, 0x86, 0x4f, 0x9b, 0x43,
+        0xbc, 0xc8, 0x59, 0xee, 0xf0, 0xcc, 0x02, 0x02, 0x08, 0x00, 0x30, 0x0c, 0x06, 0x08, 0x2a,
+        0x86, 0x48, 0x86, 0xf7, 0x0d, 0x02, 0x09, 0x05, 0x00, 0x30, 0x1d, 0x06, 0x09, 0x60, 0x86,
+        0x48, 0x01, 0x65, 0x03, 0x04, 0x01, 0x2a, 0x04, 0x10, 0xd2, 0x8d, 0x50, 0x78, 0x54, 0x7f,
+        0x96, 0xa9, 0x4d, 0x66, 0x49, 0x15, 0x48, 0x01, 0xad, 0x6f, 0x04, 0x81, 0x90, 0xbc, 0x63,
+        0xb1, 0xfa, 0xd8, 0x2c, 0xd6, 0xfe, 0x6a, 0x37, 0x5f, 0x57, 0x58, 0xfc, 0xfa, 0x87, 0x47,
+        0x16, 0xef, 0xa7, 0xea, 0x4e, 0xf7, 0xbd, 0xe2, 0xb2, 0x77, 0x0c, 0xaa, 0x51, 0x9b, 0x6c,
+        0x10, 0x54, 0x50, 0x5e, 0xce, 0xbd, 0xaa, 0x8f, 0x57, 0x1a, 0xe3, 0xe6, 0x96, 0xc9, 0x10,
+        0x98, 0x8d, 0x59, 0x8e, 0x19, 0xc6, 0x8f, 0x7f, 0xf7, 0xd1, 0xe6, 0xc9, 0x01, 0xff, 0x39,
+        0xa1, 0xcc, 0x5e, 0x66, 0x83, 0x90, 0x38, 0x80, 0x2e, 0x90, 0xbb, 0x8e, 0x53, 0xa4, 0x41,
+        0xab, 0x68, 0x01, 0xcb, 0x17, 0xf9, 0x07, 0xef, 0xb0, 0x11, 0x3e, 0x61, 0x03, 0x35, 0x96,
+        0xf3, 0xee, 0x24, 0xcb, 0xb7, 0x6e, 0x87, 0x4e, 0xc0, 0x49, 0xd1, 0x98, 0x19, 0x21, 0x71,
+        0xac, 0x71, 0x1e, 0x5c, 0xa9, 0xed, 0x78, 0xb6, 0x01, 0x21, 0xa0, 0x70, 0x15, 0x85, 0x58,
+        0xee, 0x29, 0xc3, 0x04, 0xcc, 0xed, 0xf3, 0x56, 0x11, 0x1d, 0x0e, 0x9b, 0x7f, 0x67, 0xe9,
+        0xea, 0x03, 0x16, 0xbd, 0xe0, 0x7f, 0x04,
+    ];
+
+    static EC_P256_PKCS12: &[u8] = &[
+        0x30, 0x82, 0x03, 0x72, 0x02, 0x01, 0x03, 0x30, 0x82, 0x03, 0x38, 0x06, 0x09, 0x2a, 0x86,
+        0x48, 0x86, 0xf7, 0x0d, 0x01, 0x07, 0x01, 0xa0, 0x82, 0x03, 0x29, 0x04, 0x82, 0x03, 0x25,
+        0x30, 0x82, 0x03, 0x21, 0x30, 0x82, 0x02, 0x17, 0x06, 0x09, 0x2a, 0x86, 0x48, 0x86, 0xf7,
+        0x0d, 0x01, 0x07, 0x06, 0xa0, 0x82, 0x02, 0x08, 0x30, 0x82, 0x02, 0x04, 0x02, 0x01, 0x00,
+        0x30, 0x82, 0x01, 0xfd, 0x06, 0x09, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x07, 0x01,
+        0x30, 0x1c, 0x06, 0x0a, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x0c, 0x01, 0x03, 0x30,
+        0x0e, 0x04, 0x08, 0xa5, 0xf0, 0xa8, 0xf1, 0xbf, 0xaf, 0x4c, 0x3a, 0x02, 0x02, 0x08, 0x00,
+        0x80, 0x82, 0x01, 0xd0, 0x57, 0xf8, 0xbf, 0xd7, 0xca, 0x69, 0x81, 0x97, 0x57, 0xa2, 0x6f,
+        0x23, 0x6c, 0xf1, 0xc6, 0x4d, 0xf5, 0x64, 0x62, 0x10, 0xef, 0x69, 0xe2, 0x4c, 0x66, 0x85,
+        0x9f, 0xed, 0x64, 0xe2, 0x72, 0x9f, 0x66, 0x82, 0x02, 0xee, 0xae, 0x56, 0x6e, 0xf8, 0xa4,
+        0x9e, 0x87, 0xa5, 0x89, 0x81, 0xb9, 0x6e, 0x75, 0xd7, 0x65, 0x73, 0x79, 0x7a, 0xbb, 0xa7,
+        0xff, 0xd5, 0x3a, 0x80, 0xe4, 0x9f, 0x78, 0x73, 0xd7, 0xbb, 0x35, 0x94, 0x0f, 0xd8, 0x2a,
+        0xe8, 0xcc, 0xd4, 0x88, 0xf6, 0x9d, 0x0f, 0x52, 0x23, 0x7d, 0x6c, 0x82, 0x50, 0xf7, 0x31,
+        0x77, 0x8d, 0x3b, 0x60, 0xee, 0x7a, 0x51, 0x67, 0xd6, 0x10, 0xee, 0x00, 0x78, 0x7f, 0x85,
+        0x06, 0x23, 0x83, 0x10, 0x66, 0xe5, 0xe6, 0xbe, 0xb4, 0xfd, 0x56, 0x59, 0x81, 0xd0, 0x80,
+        0x9e, 0xa2, 0xd3, 0x3b, 0xfb, 0x27, 0xd6, 0x64, 0xae, 0xf3, 0x35, 0x5d, 0x31, 0xa1, 0xf2,
+        0x7c, 0x04, 0x32, 0x5c, 0xc1, 0x6c, 0x5d, 0x9a, 0xca, 0x77, 0x6e, 0x42, 0x4f, 0xf1, 0x9b,
+        0x53, 0xe7, 0xb9, 0xde, 0x0e, 0x87, 0x10, 0xd9, 0xbe, 0x07, 0x01, 0x9b, 0x36, 0xf8, 0x52,
+        0xfd, 0x6a, 0xb5, 0x32, 0xcb, 0x90, 0x7a, 0x29, 0x1b, 0xc9, 0x78, 0xf9, 0x37, 0x15, 0xec,
+        0xdd, 0x65, 0x41, 0x05, 0xfe, 0xde, 0x54, 0xd5, 0x13, 0x9e, 0xf9, 0x23, 0xfe, 0xf5, 0xdd,
+        0x63, 0xa4, 0xc8, 0x33, 0x14, 0xfa, 0x69, 0xc6, 0x12, 0x7f, 0x86, 0x98, 0x8a, 0x08, 0x61,
+        0x30, 0x92, 0x4a, 0x20, 0xfa, 0x91, 0xbf, 0x76, 0x77, 0x86, 0xdb, 0xb0, 0xfa, 0xf6, 0xd9,
+        0xaa, 0x2f, 0x71, 0x6e, 0x78, 0x94, 0x0b, 0x85, 0x3c, 0x60, 0x2b, 0x07, 0x4f, 0xb0, 0x52,
+        0x64, 0xdf, 0xf2, 0x6b, 0x76, 0x2a, 0x51, 0x10, 0xe0, 0xc2, 0xaf, 0xf0, 0xc0, 0x1e, 0x06,
+        0x60, 0x75, 0x07, 0x03, 0x83, 0xf8, 0x35, 0x2c, 0x98, 0xa2, 0x3b, 0x99, 0x59, 0x01, 0x62,
+        0x3f, 0x59, 0xd1, 0xe8, 0xcb, 0x88, 0x86, 0x1f, 0x1c, 0x9a, 0xeb, 0x4b, 0xb1, 0xa3, 0x65,
+        0x7f, 0x81, 0xeb, 0x9b, 0x6c, 0x19, 0x24, 0x38, 0x6d, 0x5f, 0x24, 0xe0, 0x42, 0x89, 0xb3,
+        0x29, 0xc1, 0x69, 0x02, 0xb4, 0x43, 0xbb, 0xd5, 0x1f, 0xd4, 0x02, 0x4b, 0x05, 0x8d, 0x77,
+        0x5b, 0x40, 0x03, 0x28, 0xcb, 0xde, 0xf4, 0xc0, 0xd0, 0xa1, 0x46, 0x3e, 0x7b, 0x1a, 0xc9,
+        0xd9, 0xcf, 0x53, 0xf0, 0x3d, 0xe7, 0xa1, 0xb8, 0x3f, 0x7f, 0x62, 0xd4, 0x1b, 0x6b, 0xc4,
+        0x68, 0x61, 0x53, 0x3e, 0xa7, 0x20, 0x39, 0x2d, 0x27, 0x70, 0xc2, 0xf1, 0xe8, 0x2b, 0x8f,
+        0x1c, 0xb1, 0xcb, 0x92, 0x04, 0x1b, 0x46, 0x09, 0xfa, 0x44, 0x42, 0xb5, 0xda, 0x04, 0x0a,
+        0x15, 0x5f, 0xde, 0x2c, 0x4e, 0x68, 0x2f, 0x1e, 0x80, 0x58, 0x00, 0x28, 0xc5, 0x97, 0x8b,
+        0x59, 0xd9, 0xca, 0x2b, 0x48, 0x93, 0x0e, 0x13, 0x30, 0x63, 0x60, 0x27, 0x70, 0x2a, 0xfa,
+        0xad, 0x4b, 0xd1, 0x81, 0x9f, 0xef, 0x8b, 0x1f, 0x60, 0xdf, 0xfd, 0xe8, 0xca, 0x5b, 0x17,
+        0xf5, 0x61, 0x4a, 0x41, 0x33, 0xf6, 0x1c, 0x28, 0x06, 0xe3, 0x49, 0xb5, 0xf1, 0x6a, 0x51,
+        0x8a, 0x17, 0x70, 0x4c, 0x04, 0xf1, 0xe3, 0xa5, 0x1d, 0x36, 0x6e, 0x58, 0xf6, 0xfa, 0x1d,
+        0x34, 0xb4, 0xc7, 0x84, 0xa6, 0xc5, 0x26, 0x5b, 0xdc, 0x8d, 0x25, 0x6d, 0x3e, 0x9b, 0xdd,
+        0x40, 0xd2, 0xb0, 0x30, 0x82, 0x01, 0x02, 0x06, 0x09, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d,
+        0x01, 0x07, 0x01, 0xa0, 0x81, 0xf4, 0x04, 0x81, 0xf1, 0x30, 0x81, 0xee, 0x30, 0x81, 0xeb,
+        0x06, 0x0b, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x0c, 0x0a, 0x01, 0x02, 0xa0, 0x81,
+        0xb4, 0x30, 0x81, 0xb1, 0x30, 0x1c, 0x06, 0x0a, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01,
+        0x0c, 0x01, 0x03, 0x30, 0x0e, 0x04, 0x08, 0xc5, 0xda, 0xed, 0xa1, 0x30, 0xa3, 0x5f, 0xa6,
+        0x02, 0x02, 0x08, 0x00, 0x04, 0x81, 0x90, 0xc8, 0x26, 0x6a, 0xaa, 0xef, 0x64, 0x65, 0xf2,
+        0x2f, 0x3f, 0x28, 0x4f, 0xd1, 0x8a, 0xd4, 0x19, 0x74, 0x9b, 0x3e, 0xf7, 0xbc, 0xfd, 0xb5,
+        0xd0, 0x2f, 0x4f, 0xe5, 0xca, 0x88, 0xb1, 0x08, 0xc0, 0xc7, 0xbd, 0x32, 0xde, 0xa9, 0x7a,
+        0x5f, 0xa6, 0x39, 0x67, 0x0c, 0xeb, 0x53, 0x5e, 0x29, 0x7c, 0xcf, 0x29, 0x97, 0x5d, 0x53,
+        0x02, 0xf6, 0xf4, 0xc9, 0xe1, 0xb0, 0xda, 0x12, 0xd6, 0xb0, 0x57, 0x7d, 0xfd, 0x30, 0xef,
+        0xe4, 0x23, 0xab, 0xcc, 0xa2, 0x62, 0x09, 0x95, 0x8c, 0xfd, 0x52, 0x86, 0x25, 0xda, 0x64,
+        0xbf, 0xfd, 0x22, 0x99, 0x96, 0x2f, 0xdc, 0x7b, 0xfb, 0x50, 0x4c, 0x89, 0x12, 0xbd, 0x9a,
+        0x64, 0xe5, 0xe1, 0xf3, 0xb0, 0x31, 0x29, 0xab, 0xb7, 0x73, 0xf6, 0xc2, 0xa8, 0x43, 0xf1,
+        0x1b, 0xec, 0x80, 0x6d, 0xc7, 0x76, 0xdd, 0xe1, 0x8f, 0x2d, 0x89, 0xc4, 0x32, 0x53, 0x03,
+        0xf8, 0x53, 0x77, 0x64, 0x08, 0x10, 0x76, 0xce, 0xe9, 0x25, 0xdd, 0x0b, 0x0e, 0x2a, 0xb5,
+        0x5e, 0x31, 0x25, 0x30, 0x23, 0x06, 0x09, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09,
+        0x15, 0x31, 0x16, 0x04, 0x14, 0x6b, 0x27, 0x8e, 0xb6, 0xde, 0x34, 0xa2, 0xd8, 0xc2, 0xe9,
+        0xf5, 0x3a, 0x91, 0x6c, 0x5e, 0xe2, 0x87, 0x26, 0x4f, 0x4a, 0x30, 0x31, 0x30, 0x21, 0x30,
+        0x09, 0x06, 0x05, 0x2b, 0x0e, 0x03, 0x02, 0x1a, 0x05, 0x00, 0x04, 0x14, 0x88, 0xce, 0x25,
+        0x92, 0x5e, 0x22, 0x67, 0x07, 0x7e, 0x44, 0x0d, 0xf7, 0x46, 0xa9, 0x46, 0x7e, 0x11, 0x73,
+        0x0a, 0xc2, 0x04, 0x08, 0x29, 0x75, 0xda, 0x08, 0x0e, 0xcc, 0x19, 0x4d, 0x02, 0x02, 0x08,
+        0x00,
+    ];
+
+    fn test_cert_params() -> SelfSignedCertParams<'static> {
+        SelfSignedCertParams {
+            private_key: EC_P256_PKCS8,
+            subject: None,
+            serial: Some(&[0x01, 0x02]),
+            not_before: 0,
+            not_after: 253402300799000,
+            algorithm: CertSignatureAlgorithm::Ecdsa(CertDigest::Sha256),
+        }
+    }
+
+    #[test]
+    fn test_make_self_signed_certificate() -> Result<(), Error> {
+        let params = test_cert_params();
+        let tbs = make_tbs_certificate(&params)?;
+        assert!(!tbs.is_empty());
+        // The signature is computed over the first pass, so both passes must agree.
+        assert_eq!(tbs, make_tbs_certificate(&params)?);
+
+        let cert = make_signed_certificate(&params, &[0x30, 0x00])?;
+        assert!(!parse_subject_from_certificate(&cert)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_public_key_from_certificate() -> Result<(), Error> {
+        let cert = make_signed_certificate(&test_cert_params(), &[0x30, 0x00])?;
+        let spki = parse_public_key_from_certificate(&cert)?;
+        // The SubjectPublicKeyInfo ends with the public point, which is also the tail of the
+        // PKCS#8 encoded private key.
+        assert!(EC_P256_PKCS8.ends_with(&spki[spki.len() - 65..]));
+        assert_eq!(
+            parse_public_key_from_certificate(&[0x30, 0x00]),
+            Err(Error::ExtractPublicKeyFailed)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_public_key_from_private_key() -> Result<(), Error> {
+        let cert = make_signed_certificate(&test_cert_params(), &[0x30, 0x00])?;
+        assert_eq!(
+            public_key_from_private_key(EC_P256_PKCS8)?,
+            parse_public_key_from_certificate(&cert)?
+        );
+        assert_eq!(public_key_from_private_key(&[0x30, 0x00]), Err(Error::ExtractPublicKeyFailed));
+        Ok(())
+    }
+
+    #[test]
+    fn test_der_to_pem() {
+        assert_eq!(der_to_pem("TEST", b""), "-----BEGIN TEST-----\n-----END TEST-----\n");
+        assert_eq!(
+            der_to_pem("TEST", b"hello world"),
+            "-----BEGIN TEST-----\naGVsbG8gd29ybGQ=\n-----END TEST-----\n"
+        );
+        let pem = der_to_pem("TEST", &[0u8; 48]);
+        let lines: Vec<&str> = pem.lines().collect();
+        assert_eq!(lines, vec!["-----BEGIN TEST-----", &"A".repeat(64), "-----END TEST-----"]);
+    }
+
+    #[test]
+    fn test_make_self_signed_certificate_invalid_key() {
+        let params = SelfSignedCertParams { private_key: &[0x30, 0x00], ..test_cert_params() };
+        assert_eq!(make_tbs_certificate(&params), Err(Error::MakeCertificateFailed));
+    }
+
+    #[test]
+    fn test_make_certificate_request() -> Result<(), Error> {
+        let cert_params = test_cert_params();
+        let cert = make_signed_certificate(&cert_params, &[0x30, 0x00])?;
+        let subject = parse_subject_from_certificate(&cert)?;
+        let params = CertificateRequestParams {
+            certificate: &cert,
+            subject: &subject,
+            extensions: None,
+            algorithm: CertSignatureAlgorithm::Ecdsa(CertDigest::Sha256),
+        };
+        let info = make_certificate_request_info(&params)?;
+        assert!(!info.is_empty());
+        assert_eq!(info, make_certificate_request_info(&params)?);
+
+        let request = make_signed_certificate_request(&params, &[0x30, 0x00])?;
+        assert!(request.len() > info.len());
+        Ok(())
+    }
+
+    #[test]
+    fn test_make_certificate_request_algorithm_mismatch() -> Result<(), Error> {
+        let cert = make_signed_certificate(&test_cert_params(), &[0x30, 0x00])?;
+        let subject = parse_subject_from_certificate(&cert)?;
+        let params = CertificateRequestParams {
+            certificate: &cert,
+            subject: &subject,
+            extensions: None,
+            algorithm: CertSignatureAlgorithm::RsaPkcs1(CertDigest::Sha256),
+        };
+        assert_eq!(
+            make_certificate_request_info(&params),
+            Err(Error::MakeCertificateRequestFailed)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_decrypt_pkcs8() -> Result<(), Error> {
+        let key = decrypt_pkcs8(EC_P256_PKCS8_ENCRYPTED, b"keystore")?;
+        assert_eq!(&key[..], EC_P256_PKCS8);
+        assert_eq!(
+            decrypt_pkcs8(EC_P256_PKCS8_ENCRYPTED, b"wrong").unwrap_err(),
+            Error::DecryptPkcs8Failed
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_pkcs12() -> Result<(), Error> {
+        let contents = parse_pkcs12(EC_P256_PKCS12, b"keystore")?;
+        assert_eq!(&contents.private_key[..], EC_P256_PKCS8);
+        let cert = contents.certificate.expect("Certificate missing.");
+        assert!(!parse_subject_from_certificate(&cert)?.is_empty());
+        assert_eq!(contents.cert_chain, None);
+
+        assert_eq!(parse_pkcs12(EC_P256_PKCS12, b"wrong").unwrap_err(), Error::ParsePkcs12Failed);
+        assert_eq!(
+            parse_pkcs12(EC_P256_PKCS12, b"key\0store").unwrap_err(),
+            Error::ParsePkcs12Failed
+        );
+        Ok(())
+    }
 }
@@ -18,6 +18,7 @@
 
 #include "certificate_utils.h"
 
+#include <openssl/curve25519.h>
 #include <openssl/err.h>
 #include <openssl/evp.h>
 #include <openssl/mem.h>
@@ -316,6 +317,85 @@ TEST_P(CertificateUtilsWithRsa, CertSigningWithCallbackRsa) {
     ASSERT_TRUE(X509_verify(decoded_cert.get(), decoded_pkey.get()));
 }
 
+static EVP_PKEY_Ptr generateEd25519Key() {
+    EVP_PKEY_CTX_Ptr pkey_ctx(EVP_PKEY_CTX_new_id(EVP_PKEY_ED25519, NULL));
+    if (!pkey_ctx || !EVP_PKEY_keygen_init(pkey_ctx.get())) return {};
+    EVP_PKEY* pkey_ptr = nullptr;
+    if (!EVP_PKEY_keygen(pkey_ctx.get(), &pkey_ptr)) return {};
+    return EVP_PKEY_Ptr(pkey_ptr);
+}
+
+static X509_Ptr makeSelfIssuedCert(EVP_PKEY* pkey) {
+    uint64_t now_ms = (uint64_t)time(nullptr) * 1000;
+
+    BasicConstraintsExtension bcons{
+        .isCa = true,
+        .pathLength = {},
+    };
+
+    KeyUsageExtension keyUsage{
+        .isSigningKey = true,
+        .isEncryptionKey = false,
+        .isCertificationKey = true,
+    };
+
+    auto certV = makeCert(pkey, std::nullopt, std::nullopt, now_ms - kValidity,
+                          now_ms + kValidity, true /* subject key id extension */, keyUsage, bcons);
+    if (!std::holds_alternative<X509_Ptr>(certV)) return {};
+    auto cert = std::move(std::get<X509_Ptr>(certV));
+    if (setIssuer(cert.get(), cert.get(), true)) return {};
+    return cert;
+}
+
+static void verifyEncodedCert(X509* cert) {
+    auto encCertV = encodeCert(cert);
+    ASSERT_TRUE(std::holds_alternative<std::vector<uint8_t>>(encCertV));
+
+    auto& encCert = std::get<1>(encCertV);
+    const uint8_t* p = encCert.data();
+    X509_Ptr decoded_cert(d2i_X509(nullptr, &p, (long)encCert.size()));
+    ASSERT_TRUE(decoded_cert);
+    ASSERT_EQ(X509_get_signature_nid(decoded_cert.get()), NID_ED25519);
+    EVP_PKEY_Ptr decoded_pkey(X509_get_pubkey(decoded_cert.get()));
+    ASSERT_TRUE(X509_verify(decoded_cert.get(), decoded_pkey.get()));
+}
+
+TEST(CertificateUtilsEd25519, CertSigningWithCallbackEd25519) {
+    EVP_PKEY_Ptr pkey = generateEd25519Key();
+    ASSERT_TRUE(pkey);
+    auto cert = makeSelfIssuedCert(pkey.get());
+    ASSERT_TRUE(cert);
+
+    // Ed25519 takes no digest, so the digest argument is ignored.
+    ASSERT_TRUE(!signCertWith(
+        cert.get(),
+        [&](const uint8_t* data, size_t len) {
+            bssl::ScopedEVP_MD_CTX sign_ctx;
+            EXPECT_TRUE(
+                EVP_DigestSignInit(sign_ctx.get(), nullptr, nullptr, nullptr, pkey.get()));
+
+            std::vector<uint8_t> sig_buf(ED25519_SIGNATURE_LEN);
+            size_t sig_len = sig_buf.size();
+            EVP_DigestSign(sign_ctx.get(), sig_buf.data(), &sig_len, data, len);
+            sig_buf.resize(sig_len);
+            return sig_buf;
+        },
+        Algo::ED25519, Padding::Ignored, Digest::SHA256));
+
+    verifyEncodedCert(cert.get());
+}
+
+TEST(CertificateUtilsEd25519, CertSigningWithKeyEd25519) {
+    EVP_PKEY_Ptr pkey = generateEd25519Key();
+    ASSERT_TRUE(pkey);
+    auto cert = makeSelfIssuedCert(pkey.get());
+    ASSERT_TRUE(cert);
+
+    ASSERT_TRUE(!signCert(cert.get(), pkey.get()));
+
+    verifyEncodedCert(cert.get());
+}
+
 TEST(TimeStringTests, toTimeStringTest) {
     // Two test vectors that need to result in UTCTime
     ASSERT_EQ(std::string(toTimeString(1622758591000)->data()), std::string("210603221631Z"));
@@ -45,13 +45,17 @@ mod perboot;
 pub(crate) mod utils;
 mod versioning;
 
+use crate::blob_envelope;
 use crate::gc::Gc;
 use crate::globals::get_keymint_dev_by_uuid;
 use crate::impl_metadata; // This is in db_utils.rs
 use crate::key_parameter::{KeyParameter, Tag};
 use crate::metrics_store::log_rkp_error_stats;
 use crate::permission::KeyPermSet;
-use crate::utils::{get_current_time_in_milliseconds, watchdog as wd, AID_USER_OFFSET};
+use crate::trace;
+use crate::utils::{
+    get_current_time_in_milliseconds, watchdog as wd, AID_KEYSTORE, AID_USER_OFFSET,
+};
 use crate::{
     error::{Error as KsError, ErrorCode, ResponseCode},
     super_key::SuperKeyType,
@@ -120,6 +124,19 @@ impl_metadata!(
         AttestationRawPubKey(Vec<u8>) with accessor attestation_raw_pub_key,
         /// SEC1 public key for ECDH encryption
         Sec1PublicKey(Vec<u8>) with accessor sec1_public_key,
+        /// DER-encoded SubjectPublicKeyInfo of an asymmetric key that has no certificate
+        SubjectPublicKeyInfo(Vec<u8>) with accessor subject_public_key_info,
+        /// Date of the last use of the key in an operation.
+        LastUsedDate(DateTime) with accessor last_used_date,
+        /// Number of operations that were started with the key.
+        UseCount(i64) with accessor use_count,
+        /// Opaque metadata attached to the key by its owner.
+        AppMetadata(Vec<u8>) with accessor app_metadata,
+        /// Boot id of the boot the key was created in, if the key lives for one boot only.
+        BootId(String) with accessor boot_id,
+        /// Security level the key was created in instead of the StrongBox security level the
+        /// caller requested, if the key was created in StrongBox fallback mode.
+        StrongBoxFallback(i32) with accessor strongbox_fallback,
         //  --- ADD NEW META DATA FIELDS HERE ---
         // For backwards compatibility add new entries only to
         // end of this list and above this comment.
@@ -155,7 +172,7 @@ impl KeyMetaData {
 
     fn store_in_db(&self, key_id: i64, tx: &Transaction) -> Result<()> {
         let mut stmt = tx
-            .prepare(
+            .prepare_cached(
                 "INSERT or REPLACE INTO persistent.keymetadata (keyentryid, tag, data)
                     VALUES (?, ?, ?);",
             )
@@ -195,6 +212,18 @@ impl_metadata!(
         /// If the key is encrypted with a MaxBootLevel key, this is the boot level
         /// of that key
         MaxBootLevel(i32) with accessor max_boot_level,
+        /// If the blob is password encrypted and the key derived from the password was bound
+        /// to the device with a Keystore internal KeyMint key, this is set to true.
+        HwBoundKdf(bool) with accessor hw_bound_kdf,
+        /// If the key blob is envelope encrypted with the database key, this is the
+        /// initialization vector of the envelope.
+        EnvelopeIv(Vec<u8>) with accessor envelope_iv,
+        /// If the key blob is envelope encrypted with the database key, this holds the AEAD
+        /// TAG of the envelope.
+        EnvelopeAeadTag(Vec<u8>) with accessor envelope_aead_tag,
+        /// If the key blob belongs to a key with ROLLBACK_RESISTANCE, this is set to true.
+        /// The garbage collector retries deleteKey for such blobs until KeyMint acknowledges it.
+        RollbackResistant(bool) with accessor rollback_resistant,
         //  --- ADD NEW META DATA FIELDS HERE ---
         // For backwards compatibility add new entries only to
         // end of this list and above this comment.
@@ -230,7 +259,7 @@ impl BlobMetaData {
 
     fn store_in_db(&self, blob_id: i64, tx: &Transaction) -> Result<()> {
         let mut stmt = tx
-            .prepare(
+            .prepare_cached(
                 "INSERT or REPLACE INTO persistent.blobmetadata (blobentryid, tag, data)
                     VALUES (?, ?, ?);",
             )
@@ -280,6 +309,93 @@ impl FromSql for KeyType {
     }
 }
 
+/// The state of a key in the legacy import journal.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum LegacyImportState {
+    /// The key is about to be stored in the database. If Keystore finds a key in this state
+    /// when it starts up, it may or may not have been stored.
+    Importing,
+    /// The key was stored in the database, but may still be present in the legacy database.
+    Imported,
+    /// The key was created in the database and mirrored to the legacy database by the legacy
+    /// shadow-write mode. The legacy copy must not be imported.
+    Shadowed,
+}
+
+impl ToSql for LegacyImportState {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput> {
+        Ok(ToSqlOutput::Owned(Value::Integer(match self {
+            LegacyImportState::Importing => 0,
+            LegacyImportState::Imported => 1,
+            LegacyImportState::Shadowed => 2,
+        })))
+    }
+}
+
+impl FromSql for LegacyImportState {
+    fn column_result(value: ValueRef) -> FromSqlResult<Self> {
+        match i64::column_result(value)? {
+            0 => Ok(LegacyImportState::Importing),
+            1 => Ok(LegacyImportState::Imported),
+            2 => Ok(LegacyImportState::Shadowed),
+            v => Err(FromSqlError::OutOfRange(v)),
+        }
+    }
+}
+
+/// An entry of the legacy import journal.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct LegacyImportJournalEntry {
+    /// The uid owning the legacy key.
+    pub uid: u32,
+    /// The alias of the legacy key.
+    pub alias: String,
+    /// The domain the key is imported into.
+    pub domain: Domain,
+    /// The namespace the key is imported into.
+    pub namespace: i64,
+    /// The import state of the key.
+    pub state: LegacyImportState,
+}
+
+/// The holder of a grant. Keys can be granted to an app uid or to an SELinux namespace.
+/// A grant to an SELinux namespace gives access to all callers that have the requested
+/// permission in that namespace, as long as the permission is also in the access vector of
+/// the grant.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub enum Grantee {
+    /// The grant is held by the given uid.
+    Uid(u32),
+    /// The grant is held by the given SELinux namespace.
+    Namespace(i64),
+}
+
+impl Grantee {
+    /// The domain stored in the grant table for this grantee.
+    fn domain(&self) -> Domain {
+        match self {
+            Grantee::Uid(_) => Domain::APP,
+            Grantee::Namespace(_) => Domain::SELINUX,
+        }
+    }
+
+    /// The value stored in the grantee column of the grant table for this grantee.
+    fn id(&self) -> i64 {
+        match *self {
+            Grantee::Uid(uid) => uid as i64,
+            Grantee::Namespace(namespace) => namespace,
+        }
+    }
+
+    fn from_row(domain: Domain, id: i64) -> Result<Self> {
+        match domain {
+            Domain::APP => Ok(Grantee::Uid(id as u32)),
+            Domain::SELINUX => Ok(Grantee::Namespace(id)),
+            _ => Err(KsError::sys()).context(format!("Invalid grantee domain {:?}.", domain)),
+        }
+    }
+}
+
 /// Uuid representation that can be stored in the database.
 /// Right now it can only be initialized from SecurityLevel.
 /// Once KeyMint provides a UUID type a corresponding From impl shall be added.
@@ -617,6 +733,16 @@ impl CertificateInfo {
         Self { cert, cert_chain }
     }
 
+    /// Returns the cert.
+    pub fn cert(&self) -> Option<&[u8]> {
+        self.cert.as_deref()
+    }
+
+    /// Returns the cert chain.
+    pub fn cert_chain(&self) -> Option<&[u8]> {
+        self.cert_chain.as_deref()
+    }
+
     /// Take the cert
     pub fn take_cert(&mut self) -> Option<Vec<u8>> {
         self.cert.take()
@@ -640,6 +766,35 @@ pub struct CertificateChain {
     pub cert_chain: Vec<u8>,
 }
 
+/// A key entry as listed by `KeystoreDB::list_with_dates`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ListedKeyEntry {
+    /// The id of the key entry.
+    pub id: i64,
+    /// The alias of the key entry.
+    pub alias: String,
+    /// The creation date of the key entry, if known.
+    pub creation_date: Option<DateTime>,
+    /// The date of the last recorded use of the key, if any.
+    pub last_used_date: Option<DateTime>,
+    /// The KeyOrigin of the key, if known. Certificate only entries have no origin.
+    pub origin: Option<i32>,
+}
+
+/// The public material of a key entry as exported for backup. It never contains key blobs.
+/// See `KeystoreDB::export_certificates`.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct CertificateBackup {
+    /// The alias of the key entry.
+    pub alias: String,
+    /// The public certificate of the key, if any.
+    pub cert: Option<Vec<u8>>,
+    /// The certificate chain, if any.
+    pub cert_chain: Option<Vec<u8>>,
+    /// The creation date of the key entry, if known.
+    pub creation_date: Option<DateTime>,
+}
+
 /// This type represents a Keystore 2.0 key entry.
 /// An entry has a unique `id` by which it can be found in the database.
 /// It has a security level field, key parameters, and three optional fields
@@ -772,23 +927,25 @@ pub struct KeystoreDB {
     perboot: Arc<perboot::PerbootDB>,
 }
 
-/// Database representation of the monotonic time retrieved from the system call clock_gettime with
-/// CLOCK_MONOTONIC_RAW. Stores monotonic time as i64 in milliseconds.
+/// Database representation of the time since boot retrieved from the system call clock_gettime
+/// with CLOCK_BOOTTIME. Unlike CLOCK_MONOTONIC_RAW, it keeps advancing while the device is
+/// suspended, so that auth token timeouts also expire during suspend. Stores the time as i64 in
+/// milliseconds.
 #[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Ord, PartialOrd)]
-pub struct MonotonicRawTime(i64);
+pub struct BootTime(i64);
 
-impl MonotonicRawTime {
-    /// Constructs a new MonotonicRawTime
+impl BootTime {
+    /// Constructs a new BootTime with the current time since boot.
     pub fn now() -> Self {
         Self(get_current_time_in_milliseconds())
     }
 
-    /// Returns the value of MonotonicRawTime in milliseconds as i64
+    /// Returns the value of BootTime in milliseconds as i64
     pub fn milliseconds(&self) -> i64 {
         self.0
     }
 
-    /// Returns the integer value of MonotonicRawTime as i64
+    /// Returns the integer value of BootTime as i64
     pub fn seconds(&self) -> i64 {
         self.0 / 1000
     }
@@ -799,13 +956,13 @@ impl MonotonicRawTime {
     }
 }
 
-impl ToSql for MonotonicRawTime {
+impl ToSql for BootTime {
     fn to_sql(&self) -> rusqlite::Result<ToSqlOutput> {
         Ok(ToSqlOutput::Owned(Value::Integer(self.0)))
     }
 }
 
-impl FromSql for MonotonicRawTime {
+impl FromSql for BootTime {
     fn column_result(value: ValueRef) -> FromSqlResult<Self> {
         Ok(Self(i64::column_result(value)?))
     }
@@ -817,12 +974,18 @@ impl FromSql for MonotonicRawTime {
 pub struct AuthTokenEntry {
     auth_token: HardwareAuthToken,
     // Time received in milliseconds
-    time_received: MonotonicRawTime,
+    time_received: BootTime,
+    // True if the token was consumed by the operation that requested its challenge.
+    bound_to_operation: bool,
 }
 
 impl AuthTokenEntry {
-    fn new(auth_token: HardwareAuthToken, time_received: MonotonicRawTime) -> Self {
-        AuthTokenEntry { auth_token, time_received }
+    fn new(
+        auth_token: HardwareAuthToken,
+        time_received: BootTime,
+        bound_to_operation: bool,
+    ) -> Self {
+        AuthTokenEntry { auth_token, time_received, bound_to_operation }
     }
 
     /// Checks if this auth token satisfies the given authentication information.
@@ -844,7 +1007,7 @@ impl AuthTokenEntry {
     }
 
     /// Returns the time that this auth token was received.
-    pub fn time_received(&self) -> MonotonicRawTime {
+    pub fn time_received(&self) -> BootTime {
         self.time_received
     }
 
@@ -852,6 +1015,13 @@ impl AuthTokenEntry {
     pub fn challenge(&self) -> i64 {
         self.auth_token.challenge
     }
+
+    /// Returns true if the auth token was consumed by the operation that requested its
+    /// challenge. Such a token authorizes that one operation only and must not be used to
+    /// authorize anything else.
+    pub fn is_bound_to_operation(&self) -> bool {
+        self.bound_to_operation
+    }
 }
 
 /// Shared in-memory databases get destroyed as soon as the last connection to them gets closed.
@@ -861,8 +1031,9 @@ pub struct PerBootDbKeepAlive(Connection);
 
 impl KeystoreDB {
     const UNASSIGNED_KEY_ID: i64 = -1i64;
-    const CURRENT_DB_VERSION: u32 = 1;
-    const UPGRADERS: &'static [fn(&Transaction) -> Result<u32>] = &[Self::from_0_to_1];
+    const CURRENT_DB_VERSION: u32 = 3;
+    const UPGRADERS: &'static [fn(&Transaction) -> Result<u32>] =
+        &[Self::from_0_to_1, Self::from_1_to_2, Self::from_2_to_3];
 
     /// Name of the file that holds the cross-boot persistent database.
     pub const PERSISTENT_DB_FILENAME: &'static str = "persistent.sqlite";
@@ -907,6 +1078,25 @@ impl KeystoreDB {
         Ok(1)
     }
 
+    // This upgrade function adds the expiry column to the grant table. Existing grants do not
+    // expire.
+    fn from_1_to_2(tx: &Transaction) -> Result<u32> {
+        tx.execute("ALTER TABLE persistent.grant ADD COLUMN expires_at INTEGER;", NO_PARAMS)
+            .context("In from_1_to_2: Failed to add grant expiry column.")?;
+        Ok(2)
+    }
+
+    // This upgrade function adds the grantee domain column to the grant table. All existing
+    // grants are held by uids, i.e., their grantee domain is Domain::APP (0).
+    fn from_2_to_3(tx: &Transaction) -> Result<u32> {
+        tx.execute(
+            "ALTER TABLE persistent.grant ADD COLUMN grantee_domain INTEGER NOT NULL DEFAULT 0;",
+            NO_PARAMS,
+        )
+        .context("In from_2_to_3: Failed to add grantee domain column.")?;
+        Ok(3)
+    }
+
     fn init_tables(tx: &Transaction) -> Result<()> {
         tx.execute(
             "CREATE TABLE IF NOT EXISTS persistent.keyentry (
@@ -1009,14 +1199,60 @@ impl KeystoreDB {
                     id INTEGER UNIQUE,
                     grantee INTEGER,
                     keyentryid INTEGER,
-                    access_vector INTEGER);",
+                    access_vector INTEGER,
+                    expires_at INTEGER,
+                    grantee_domain INTEGER NOT NULL DEFAULT 0);",
             NO_PARAMS,
         )
         .context("Failed to initialize \"grant\" table.")?;
 
+        tx.execute(
+            "CREATE TABLE IF NOT EXISTS persistent.blobjournal (
+                    keyentryid INTEGER,
+                    subcomponent_type INTEGER,
+                    old_blob_id INTEGER);",
+            NO_PARAMS,
+        )
+        .context("Failed to initialize \"blobjournal\" table.")?;
+
+        tx.execute(
+            "CREATE TABLE IF NOT EXISTS persistent.keymintversion (
+                    km_uuid BLOB PRIMARY KEY,
+                    version INTEGER,
+                    os_patch_level TEXT);",
+            NO_PARAMS,
+        )
+        .context("Failed to initialize \"keymintversion\" table.")?;
+
+        tx.execute(
+            "CREATE TABLE IF NOT EXISTS persistent.legacyimportjournal (
+                    uid INTEGER,
+                    alias TEXT,
+                    domain INTEGER,
+                    namespace INTEGER,
+                    state INTEGER,
+                    UNIQUE (uid, alias));",
+            NO_PARAMS,
+        )
+        .context("Failed to initialize \"legacyimportjournal\" table.")?;
+
+        tx.execute(
+            "CREATE TABLE IF NOT EXISTS persistent.pendingsecuredeletion (
+                    blobentryid INTEGER PRIMARY KEY,
+                    attempts INTEGER);",
+            NO_PARAMS,
+        )
+        .context("Failed to initialize \"pendingsecuredeletion\" table.")?;
+
         Ok(())
     }
 
+    #[cfg(all(test, feature = "keystore2_simulator"))]
+    fn make_persistent_path(_db_root: &Path) -> Result<String> {
+        Ok(crate::simulator::SIMULATOR_DB_URI.to_owned())
+    }
+
+    #[cfg(not(all(test, feature = "keystore2_simulator")))]
     fn make_persistent_path(db_root: &Path) -> Result<String> {
         // Build the path to the sqlite file.
         let mut persistent_path = db_root.to_path_buf();
@@ -1174,6 +1410,11 @@ impl KeystoreDB {
                 .context("Trying to delete blob metadata.")?;
                 tx.execute("DELETE FROM persistent.blobentry WHERE id = ?;", params![blob_id])
                     .context("Trying to blob.")?;
+                tx.execute(
+                    "DELETE FROM persistent.pendingsecuredeletion WHERE blobentryid = ?;",
+                    params![blob_id],
+                )
+                .context("Trying to delete pending secure deletion.")?;
             }
 
             Self::cleanup_unreferenced(tx).context("Trying to cleanup unreferenced.")?;
@@ -1184,6 +1425,8 @@ impl KeystoreDB {
                     .prepare(
                         "SELECT id, blob FROM persistent.blobentry
                         WHERE subcomponent_type = ?
+                        AND keyentryid NOT IN (SELECT keyentryid FROM persistent.blobjournal)
+                        AND id NOT IN (SELECT blobentryid FROM persistent.pendingsecuredeletion)
                         AND (
                             id NOT IN (
                                 SELECT MAX(id) FROM persistent.blobentry
@@ -1213,7 +1456,9 @@ impl KeystoreDB {
             let result = result
                 .into_iter()
                 .map(|(blob_id, blob)| {
-                    Ok((blob_id, blob, BlobMetaData::load_from_db(blob_id, tx)?))
+                    let (blob, blob_metadata) =
+                        Self::open_key_blob(blob, BlobMetaData::load_from_db(blob_id, tx)?)?;
+                    Ok((blob_id, blob, blob_metadata))
                 })
                 .collect::<Result<Vec<(i64, Vec<u8>, BlobMetaData)>>>()
                 .context("Trying to load blob metadata.")?;
@@ -1226,6 +1471,7 @@ impl KeystoreDB {
             tx.execute(
                 "DELETE FROM persistent.blobentry
                  WHERE NOT subcomponent_type = ?
+                 AND keyentryid NOT IN (SELECT keyentryid FROM persistent.blobjournal)
                  AND (
                      id NOT IN (
                         SELECT MAX(id) FROM persistent.blobentry
@@ -1250,10 +1496,16 @@ impl KeystoreDB {
     /// occurrences.
     /// Unlike with `mark_unreferenced`, we don't need to purge grants, because only keys that made
     /// it to `KeyLifeCycle::Live` may have grants.
+    /// Blob replacements that were interrupted are replayed from the blob journal first.
     pub fn cleanup_leftovers(&mut self) -> Result<usize> {
         let _wp = wd::watch_millis("KeystoreDB::cleanup_leftovers", 500);
 
         self.with_transaction(TransactionBehavior::Immediate, |tx| {
+            let replayed =
+                Self::replay_blob_journal(tx).context("Failed to replay blob journal.")?;
+            if replayed > 0 {
+                log::warn!("Replayed {} interrupted blob replacements.", replayed);
+            }
             tx.execute(
                 "UPDATE persistent.keyentry SET state = ? WHERE state = ?;",
                 params![KeyLifeCycle::Unreferenced, KeyLifeCycle::Existing],
@@ -1302,34 +1554,14 @@ impl KeystoreDB {
         let _wp = wd::watch_millis("KeystoreDB::store_super_key", 500);
 
         self.with_transaction(TransactionBehavior::Immediate, |tx| {
-            let key_id = Self::insert_with_retry(|id| {
-                tx.execute(
-                    "INSERT into persistent.keyentry
-                            (id, key_type, domain, namespace, alias, state, km_uuid)
-                            VALUES(?, ?, ?, ?, ?, ?, ?);",
-                    params![
-                        id,
-                        KeyType::Super,
-                        Domain::APP.0,
-                        user_id as i64,
-                        key_type.alias,
-                        KeyLifeCycle::Live,
-                        &KEYSTORE_UUID,
-                    ],
-                )
-            })
-            .context("Failed to insert into keyentry table.")?;
-
-            key_metadata.store_in_db(key_id, tx).context("KeyMetaData::store_in_db failed")?;
-
-            Self::set_blob_internal(
+            let key_id = Self::store_super_key_internal(
                 tx,
-                key_id,
-                SubComponentType::KEY_BLOB,
-                Some(blob),
-                Some(blob_metadata),
-            )
-            .context("Failed to store key blob.")?;
+                user_id,
+                key_type,
+                blob,
+                blob_metadata,
+                key_metadata,
+            )?;
 
             Self::load_key_components(tx, KeyEntryLoadBits::KM, key_id)
                 .context("Trying to load key components.")
@@ -1338,6 +1570,45 @@ impl KeystoreDB {
         .context("In store_super_key.")
     }
 
+    fn store_super_key_internal(
+        tx: &Transaction,
+        user_id: u32,
+        key_type: &SuperKeyType,
+        blob: &[u8],
+        blob_metadata: &BlobMetaData,
+        key_metadata: &KeyMetaData,
+    ) -> Result<i64> {
+        let key_id = Self::insert_with_retry(|id| {
+            tx.execute(
+                "INSERT into persistent.keyentry
+                        (id, key_type, domain, namespace, alias, state, km_uuid)
+                        VALUES(?, ?, ?, ?, ?, ?, ?);",
+                params![
+                    id,
+                    KeyType::Super,
+                    Domain::APP.0,
+                    user_id as i64,
+                    key_type.alias,
+                    KeyLifeCycle::Live,
+                    &KEYSTORE_UUID,
+                ],
+            )
+        })
+        .context("Failed to insert into keyentry table.")?;
+
+        key_metadata.store_in_db(key_id, tx).context("KeyMetaData::store_in_db failed")?;
+
+        Self::set_blob_internal(
+            tx,
+            key_id,
+            SubComponentType::KEY_BLOB,
+            Some(blob),
+            Some(blob_metadata),
+        )
+        .context("Failed to store key blob.")?;
+        Ok(key_id)
+    }
+
     /// Loads super key of a given user, if exists
     pub fn load_super_key(
         &mut self,
@@ -1370,6 +1641,174 @@ impl KeystoreDB {
         .context("In load_super_key.")
     }
 
+    /// Records the version reported by the KeyMint instance with the given uuid and the OS patch
+    /// level it is running with. Returns Ok(true) if either differs from what was recorded
+    /// before, or if nothing was recorded for this instance yet.
+    pub fn update_keymint_version(
+        &mut self,
+        km_uuid: &Uuid,
+        version: i32,
+        os_patch_level: &str,
+    ) -> Result<bool> {
+        let _wp = wd::watch_millis("KeystoreDB::update_keymint_version", 500);
+
+        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+            let recorded: Option<(i32, String)> = tx
+                .query_row(
+                    "SELECT version, os_patch_level FROM persistent.keymintversion
+                     WHERE km_uuid = ?;",
+                    params![km_uuid],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )
+                .optional()
+                .context("Trying to load recorded version.")?;
+            let changed = recorded.map_or(true, |(recorded_version, recorded_patch_level)| {
+                recorded_version != version || recorded_patch_level != os_patch_level
+            });
+            if changed {
+                tx.execute(
+                    "INSERT OR REPLACE INTO persistent.keymintversion
+                        (km_uuid, version, os_patch_level) VALUES (?, ?, ?);",
+                    params![km_uuid, version, os_patch_level],
+                )
+                .context("Trying to record version.")?;
+            }
+            Ok(changed).no_gc()
+        })
+        .context("In update_keymint_version.")
+    }
+
+    /// Returns the ids of up to `limit` live client keys bound to the KeyMint instance with the
+    /// given uuid, whose ids are greater than `after_key_id`, in ascending order. This allows
+    /// iterating over all keys of a KeyMint instance in batches.
+    pub fn get_key_ids_for_km_uuid(
+        &mut self,
+        km_uuid: &Uuid,
+        after_key_id: i64,
+        limit: usize,
+    ) -> Result<Vec<i64>> {
+        let _wp = wd::watch_millis("KeystoreDB::get_key_ids_for_km_uuid", 500);
+
+        self.with_transaction(TransactionBehavior::Deferred, |tx| {
+            let mut stmt = tx
+                .prepare(
+                    "SELECT id FROM persistent.keyentry
+                     WHERE km_uuid = ? AND key_type = ? AND state = ? AND id > ?
+                     ORDER BY id ASC LIMIT ?;",
+                )
+                .context("Trying to prepare query.")?;
+            let mut rows = stmt
+                .query(params![
+                    km_uuid,
+                    KeyType::Client,
+                    KeyLifeCycle::Live,
+                    after_key_id,
+                    limit as i64
+                ])
+                .context("Trying to query key ids.")?;
+            let mut key_ids: Vec<i64> = Vec::new();
+            db_utils::with_rows_extract_all(&mut rows, |row| {
+                key_ids.push(row.get(0).context("Trying to extract key id.")?);
+                Ok(())
+            })
+            .context("Trying to extract rows.")?;
+            Ok(key_ids).no_gc()
+        })
+        .context("In get_key_ids_for_km_uuid.")
+    }
+
+    /// Deletes the super key of the given type of a given user, if it exists.
+    /// Returns Ok(true) if a key was deleted.
+    pub fn delete_super_key(&mut self, key_type: &SuperKeyType, user_id: u32) -> Result<bool> {
+        let _wp = wd::watch_millis("KeystoreDB::delete_super_key", 500);
+
+        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+            Self::delete_super_key_internal(tx, key_type, user_id).map(|need_gc| (need_gc, need_gc))
+        })
+        .context("In delete_super_key.")
+    }
+
+    /// Marks the given super key of the given user unreferenced. Returns true if the key
+    /// existed, i.e., if the garbage collector needs to run.
+    fn delete_super_key_internal(
+        tx: &Transaction,
+        key_type: &SuperKeyType,
+        user_id: u32,
+    ) -> Result<bool> {
+        let key_descriptor = KeyDescriptor {
+            domain: Domain::APP,
+            nspace: user_id as i64,
+            alias: Some(key_type.alias.into()),
+            blob: None,
+        };
+        match Self::load_key_entry_id(tx, &key_descriptor, KeyType::Super) {
+            Ok(id) => {
+                Self::mark_unreferenced(tx, id).context("Trying to mark the key unreferenced.")
+            }
+            Err(error) => match error.root_cause().downcast_ref::<KsError>() {
+                Some(KsError::Rc(ResponseCode::KEY_NOT_FOUND)) => Ok(false),
+                _ => Err(error),
+            },
+        }
+    }
+
+    /// Replaces the given super keys of the given user in a single transaction. Each existing
+    /// key of the given types is deleted, and a new key is stored if a blob is given for it.
+    /// This is used to escrow and to consume the escrowed super keys atomically.
+    pub fn replace_super_keys(
+        &mut self,
+        user_id: u32,
+        keys: &[(&SuperKeyType, Option<(Vec<u8>, BlobMetaData)>)],
+    ) -> Result<()> {
+        let _wp = wd::watch_millis("KeystoreDB::replace_super_keys", 500);
+
+        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+            let mut need_gc = false;
+            for (key_type, blob) in keys {
+                need_gc |= Self::delete_super_key_internal(tx, key_type, user_id)
+                    .context("Trying to delete super key.")?;
+                if let Some((blob, blob_metadata)) = blob {
+                    Self::store_super_key_internal(
+                        tx,
+                        user_id,
+                        key_type,
+                        blob,
+                        blob_metadata,
+                        &KeyMetaData::new(),
+                    )
+                    .context("Trying to store super key.")?;
+                }
+            }
+            Ok(()).do_gc(need_gc)
+        })
+        .context("In replace_super_keys.")
+    }
+
+    /// Replaces the key blobs of the given super keys in a single transaction. This is used to
+    /// re-encrypt super keys when the user's password changes. The super keys keep their key
+    /// ids, so that the keys encrypted with them remain usable.
+    pub fn replace_super_key_blobs(
+        &mut self,
+        blobs: &[(KeyIdGuard, Vec<u8>, BlobMetaData)],
+    ) -> Result<()> {
+        let _wp = wd::watch_millis("KeystoreDB::replace_super_key_blobs", 500);
+
+        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+            for (key_id, blob, blob_metadata) in blobs {
+                Self::set_blob_internal(
+                    tx,
+                    key_id.id(),
+                    SubComponentType::KEY_BLOB,
+                    Some(blob),
+                    Some(blob_metadata),
+                )
+                .context("Trying to replace super key blob.")?;
+            }
+            Ok(()).need_gc()
+        })
+        .context("In replace_super_key_blobs.")
+    }
+
     /// Atomically loads a key entry and associated metadata or creates it using the
     /// callback create_new_key callback. The callback is called during a database
     /// transaction. This means that implementers should be mindful about using
@@ -1472,6 +1911,7 @@ impl KeystoreDB {
     where
         F: Fn(&Transaction) -> Result<(bool, T)>,
     {
+        let _trace = trace::begin("KeystoreDB::with_transaction");
         loop {
             match self
                 .conn
@@ -1630,53 +2070,395 @@ impl KeystoreDB {
         .context("In set_blob.")
     }
 
-    /// Why would we insert a deleted blob? This weird function is for the purpose of legacy
-    /// key migration in the case where we bulk delete all the keys of an app or even a user.
-    /// We use this to insert key blobs into the database which can then be garbage collected
-    /// lazily by the key garbage collector.
-    pub fn set_deleted_blob(&mut self, blob: &[u8], blob_metadata: &BlobMetaData) -> Result<()> {
-        let _wp = wd::watch_millis("KeystoreDB::set_deleted_blob", 500);
+    /// Records in the blob journal that the blobs of the given subcomponents of the given key are
+    /// about to be replaced, e.g., by storing an upgraded key blob and then updating the key
+    /// parameters that depend on it. The replacement must be completed with
+    /// `commit_blob_replacement`. Until then, the garbage collector keeps the superseded blobs
+    /// of the key. If Keystore dies in between, the replacement is replayed by
+    /// `cleanup_leftovers`, see `replay_blob_journal`.
+    pub fn begin_blob_replacement(
+        &mut self,
+        key_id: &KeyIdGuard,
+        sc_types: &[SubComponentType],
+    ) -> Result<()> {
+        let _wp = wd::watch_millis("KeystoreDB::begin_blob_replacement", 500);
 
         self.with_transaction(TransactionBehavior::Immediate, |tx| {
-            Self::set_blob_internal(
-                tx,
-                Self::UNASSIGNED_KEY_ID,
-                SubComponentType::KEY_BLOB,
-                Some(blob),
-                Some(blob_metadata),
+            tx.execute(
+                "DELETE FROM persistent.blobjournal WHERE keyentryid = ?;",
+                params![key_id.0],
             )
-            .need_gc()
+            .context("Trying to delete stale journal entries.")?;
+            for sc_type in sc_types {
+                tx.execute(
+                    "INSERT INTO persistent.blobjournal
+                        (keyentryid, subcomponent_type, old_blob_id)
+                     VALUES (?, ?, (
+                        SELECT MAX(id) FROM persistent.blobentry
+                        WHERE keyentryid = ? AND subcomponent_type = ?
+                     ));",
+                    params![key_id.0, sc_type, key_id.0, sc_type],
+                )
+                .context("Trying to insert journal entry.")?;
+            }
+            Ok(()).no_gc()
         })
-        .context("In set_deleted_blob.")
+        .context("In begin_blob_replacement.")
     }
 
-    fn set_blob_internal(
-        tx: &Transaction,
-        key_id: i64,
-        sc_type: SubComponentType,
-        blob: Option<&[u8]>,
-        blob_metadata: Option<&BlobMetaData>,
+    /// Completes a blob replacement started with `begin_blob_replacement`.
+    pub fn commit_blob_replacement(&mut self, key_id: &KeyIdGuard) -> Result<()> {
+        let _wp = wd::watch_millis("KeystoreDB::commit_blob_replacement", 500);
+
+        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+            tx.execute(
+                "DELETE FROM persistent.blobjournal WHERE keyentryid = ?;",
+                params![key_id.0],
+            )
+            .context("Trying to delete journal entries.")
+            .no_gc()
+        })
+        .context("In commit_blob_replacement.")?;
+        Ok(())
+    }
+
+    /// Attaches the given opaque metadata to the key entry, replacing any metadata attached
+    /// before. `None` removes the attached metadata. The metadata is deleted along with the
+    /// key entry.
+    pub fn set_app_metadata(
+        &mut self,
+        key_id: &KeyIdGuard,
+        app_metadata: Option<&[u8]>,
     ) -> Result<()> {
-        match (blob, sc_type) {
-            (Some(blob), _) => {
-                tx.execute(
-                    "INSERT INTO persistent.blobentry
-                     (subcomponent_type, keyentryid, blob) VALUES (?, ?, ?);",
-                    params![sc_type, key_id, blob],
-                )
-                .context("In set_blob_internal: Failed to insert blob.")?;
-                if let Some(blob_metadata) = blob_metadata {
-                    let blob_id = tx
-                        .query_row("SELECT MAX(id) FROM persistent.blobentry;", NO_PARAMS, |row| {
-                            row.get(0)
-                        })
-                        .context("In set_blob_internal: Failed to get new blob id.")?;
-                    blob_metadata
-                        .store_in_db(blob_id, tx)
-                        .context("In set_blob_internal: Trying to store blob metadata.")?;
+        let _wp = wd::watch_millis("KeystoreDB::set_app_metadata", 500);
+
+        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+            match app_metadata {
+                Some(app_metadata) => {
+                    let mut metadata = KeyMetaData::new();
+                    metadata.add(KeyMetaEntry::AppMetadata(app_metadata.to_vec()));
+                    metadata.store_in_db(key_id.0, tx).context("Trying to store app metadata.")?;
+                }
+                None => {
+                    tx.execute(
+                        "DELETE FROM persistent.keymetadata WHERE keyentryid = ? AND tag = ?;",
+                        params![key_id.0, KeyMetaData::AppMetadata],
+                    )
+                    .context("Trying to delete app metadata.")?;
                 }
             }
-            (None, SubComponentType::CERT) | (None, SubComponentType::CERT_CHAIN) => {
+            Ok(()).no_gc()
+        })
+        .context("In set_app_metadata.")
+    }
+
+    /// Adds the given usage records to the usage statistics of the keys. Each record consists
+    /// of a key id, the time of the last use and the number of uses since the last update.
+    /// Records of keys that are no longer live are dropped.
+    pub fn update_key_usage(&mut self, records: &[(i64, DateTime, i64)]) -> Result<()> {
+        let _wp = wd::watch_millis("KeystoreDB::update_key_usage", 500);
+
+        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+            for (key_id, last_used, uses) in records {
+                let live = tx
+                    .query_row(
+                        "SELECT id FROM persistent.keyentry WHERE id = ? AND state = ?;",
+                        params![key_id, KeyLifeCycle::Live],
+                        |_| Ok(()),
+                    )
+                    .optional()
+                    .context("Trying to check key entry state.")?
+                    .is_some();
+                if !live {
+                    continue;
+                }
+                let use_count = KeyMetaData::load_from_db(*key_id, tx)
+                    .context("Trying to load key metadata.")?
+                    .use_count()
+                    .copied()
+                    .unwrap_or(0);
+                let mut metadata = KeyMetaData::new();
+                metadata.add(KeyMetaEntry::LastUsedDate(*last_used));
+                metadata.add(KeyMetaEntry::UseCount(use_count + uses));
+                metadata.store_in_db(*key_id, tx).context("Trying to store key usage.")?;
+            }
+            Ok(()).no_gc()
+        })
+        .context("In update_key_usage.")
+    }
+
+    /// Replaces the stored key parameters of the given key that have one of the given `tags`
+    /// with the matching entries of `params`. Parameters with other tags are ignored on both
+    /// sides. This keeps the key characteristics cached in the database in sync with KeyMint
+    /// when a key blob upgrade changes, e.g., the patch levels bound to the key.
+    pub fn update_key_parameters(
+        &mut self,
+        key_id: &KeyIdGuard,
+        tags: &[Tag],
+        params: &[KeyParameter],
+    ) -> Result<()> {
+        let _wp = wd::watch_millis("KeystoreDB::update_key_parameters", 500);
+
+        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+            for tag in tags {
+                tx.execute(
+                    "DELETE FROM persistent.keyparameter WHERE keyentryid = ? AND tag = ?;",
+                    params![key_id.0, tag.0],
+                )
+                .context("Trying to delete key parameters.")?;
+            }
+            let params: Vec<KeyParameter> =
+                params.iter().filter(|p| tags.contains(&p.get_tag())).cloned().collect();
+            Self::insert_keyparameter_internal(tx, key_id, &params).no_gc()
+        })
+        .context("In update_key_parameters.")
+    }
+
+    /// Replaces the certificate and the certificate chain of the given key in a single
+    /// transaction. `check_cert` is called with the currently stored certificate and the stored
+    /// SubjectPublicKeyInfo of the key, if any, within the transaction before anything is
+    /// changed. If it fails, the key entry is left untouched and its error is returned.
+    pub fn replace_certificates<F>(
+        &mut self,
+        key_id: &KeyIdGuard,
+        cert: Option<&[u8]>,
+        cert_chain: Option<&[u8]>,
+        check_cert: F,
+    ) -> Result<()>
+    where
+        F: Fn(Option<&[u8]>, Option<&[u8]>) -> Result<()>,
+    {
+        let _wp = wd::watch_millis("KeystoreDB::replace_certificates", 500);
+
+        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+            let (_, _, current_cert, _) =
+                Self::load_blob_components(key_id.0, KeyEntryLoadBits::PUBLIC, tx)
+                    .context("Trying to load current certificate.")?;
+            let metadata =
+                KeyMetaData::load_from_db(key_id.0, tx).context("Trying to load key metadata.")?;
+            check_cert(
+                current_cert.as_deref(),
+                metadata.subject_public_key_info().map(|k| k.as_slice()),
+            )?;
+            Self::set_blob_internal(tx, key_id.0, SubComponentType::CERT, cert, None)
+                .context("Trying to replace certificate.")?;
+            Self::set_blob_internal(tx, key_id.0, SubComponentType::CERT_CHAIN, cert_chain, None)
+                .context("Trying to replace certificate chain.")
+                .need_gc()
+        })
+        .context("In replace_certificates.")
+    }
+
+    /// Records the import state of the legacy key with the given uid and alias in the legacy
+    /// import journal. The key is imported as the key with the given domain, namespace, and
+    /// alias.
+    pub fn set_legacy_import_state(
+        &mut self,
+        uid: u32,
+        alias: &str,
+        domain: Domain,
+        namespace: i64,
+        state: LegacyImportState,
+    ) -> Result<()> {
+        let _wp = wd::watch_millis("KeystoreDB::set_legacy_import_state", 500);
+
+        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+            tx.execute(
+                "INSERT OR REPLACE INTO persistent.legacyimportjournal
+                    (uid, alias, domain, namespace, state) VALUES (?, ?, ?, ?, ?);",
+                params![uid, alias, domain.0, namespace, state],
+            )
+            .context("Trying to insert journal entry.")
+            .no_gc()
+        })
+        .context("In set_legacy_import_state.")?;
+        Ok(())
+    }
+
+    /// Removes the legacy key with the given uid and alias from the legacy import journal.
+    pub fn clear_legacy_import_state(&mut self, uid: u32, alias: &str) -> Result<()> {
+        let _wp = wd::watch_millis("KeystoreDB::clear_legacy_import_state", 500);
+
+        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+            tx.execute(
+                "DELETE FROM persistent.legacyimportjournal WHERE uid = ? AND alias = ?;",
+                params![uid, alias],
+            )
+            .context("Trying to delete journal entry.")
+            .no_gc()
+        })
+        .context("In clear_legacy_import_state.")?;
+        Ok(())
+    }
+
+    /// Returns the import state of the legacy key with the given uid and alias or None if the
+    /// key is not in the legacy import journal.
+    pub fn get_legacy_import_state(
+        &mut self,
+        uid: u32,
+        alias: &str,
+    ) -> Result<Option<LegacyImportState>> {
+        let _wp = wd::watch_millis("KeystoreDB::get_legacy_import_state", 500);
+
+        self.with_transaction(TransactionBehavior::Deferred, |tx| {
+            tx.query_row(
+                "SELECT state FROM persistent.legacyimportjournal WHERE uid = ? AND alias = ?;",
+                params![uid, alias],
+                |row| row.get(0),
+            )
+            .optional()
+            .context("Trying to query journal entry.")
+            .no_gc()
+        })
+        .context("In get_legacy_import_state.")
+    }
+
+    /// Returns all entries of the legacy import journal.
+    pub fn get_legacy_import_journal(&mut self) -> Result<Vec<LegacyImportJournalEntry>> {
+        let _wp = wd::watch_millis("KeystoreDB::get_legacy_import_journal", 500);
+
+        self.with_transaction(TransactionBehavior::Deferred, |tx| {
+            let mut stmt = tx
+                .prepare(
+                    "SELECT uid, alias, domain, namespace, state
+                     FROM persistent.legacyimportjournal;",
+                )
+                .context("Trying to prepare query.")?;
+            let mut rows = stmt.query(NO_PARAMS).context("Trying to query journal.")?;
+            let mut journal: Vec<LegacyImportJournalEntry> = Vec::new();
+            db_utils::with_rows_extract_all(&mut rows, |row| {
+                journal.push(LegacyImportJournalEntry {
+                    uid: row.get(0).context("Trying to extract uid.")?,
+                    alias: row.get(1).context("Trying to extract alias.")?,
+                    domain: Domain(row.get(2).context("Trying to extract domain.")?),
+                    namespace: row.get(3).context("Trying to extract namespace.")?,
+                    state: row.get(4).context("Trying to extract state.")?,
+                });
+                Ok(())
+            })
+            .context("Trying to extract rows.")?;
+            Ok(journal).no_gc()
+        })
+        .context("In get_legacy_import_journal.")
+    }
+
+    /// Replays blob replacements that were interrupted, i.e., that are still in the blob
+    /// journal. A replacement is rolled back by deleting the blobs that were added after it
+    /// began, so that the key is left with the blobs that match the rest of its entry. An
+    /// upgraded key blob is dropped without calling deleteKey, because it holds the same key
+    /// material as the old blob; the key is simply upgraded again on its next use. If an old
+    /// blob was deleted, the replacement is rolled forward instead, because deleted blobs
+    /// cannot be restored. Returns the number of keys affected.
+    fn replay_blob_journal(tx: &Transaction) -> Result<usize> {
+        let journal: Vec<(i64, i64, Option<i64>)> = {
+            let mut stmt = tx
+                .prepare(
+                    "SELECT keyentryid, subcomponent_type, old_blob_id
+                     FROM persistent.blobjournal ORDER BY keyentryid;",
+                )
+                .context("Trying to prepare journal query.")?;
+            let rows = stmt
+                .query_map(NO_PARAMS, |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+                .context("Trying to query journal.")?;
+            rows.collect::<Result<Vec<(i64, i64, Option<i64>)>, rusqlite::Error>>()
+                .context("Trying to extract journal entries.")?
+        };
+
+        let mut by_key: HashMap<i64, Vec<(i64, Option<i64>)>> = HashMap::new();
+        for (key_id, sc_type, old_blob_id) in journal {
+            by_key.entry(key_id).or_default().push((sc_type, old_blob_id));
+        }
+
+        for (key_id, entries) in &by_key {
+            let mut replaced = Vec::new();
+            let mut old_blob_deleted = false;
+            for (sc_type, old_blob_id) in entries {
+                let current_blob_id: Option<i64> = tx
+                    .query_row(
+                        "SELECT MAX(id) FROM persistent.blobentry
+                         WHERE keyentryid = ? AND subcomponent_type = ?;",
+                        params![key_id, sc_type],
+                        |row| row.get(0),
+                    )
+                    .context("Trying to query current blob.")?;
+                if let Some(old_blob_id) = old_blob_id {
+                    let old_blob_exists = tx
+                        .query_row(
+                            "SELECT id FROM persistent.blobentry WHERE id = ?;",
+                            params![old_blob_id],
+                            |_| Ok(()),
+                        )
+                        .optional()
+                        .context("Trying to query old blob.")?
+                        .is_some();
+                    old_blob_deleted |= !old_blob_exists;
+                }
+                if current_blob_id != *old_blob_id {
+                    replaced.push((*sc_type, old_blob_id.unwrap_or(-1)));
+                }
+            }
+
+            if old_blob_deleted {
+                log::warn!("Rolling forward interrupted blob replacement of key {}.", key_id);
+                continue;
+            }
+            log::warn!("Rolling back interrupted blob replacement of key {}.", key_id);
+            for (sc_type, old_blob_id) in replaced {
+                tx.execute(
+                    "DELETE FROM persistent.blobmetadata WHERE blobentryid IN (
+                        SELECT id FROM persistent.blobentry
+                        WHERE keyentryid = ? AND subcomponent_type = ? AND id > ?
+                    );",
+                    params![key_id, sc_type, old_blob_id],
+                )
+                .context("Trying to delete blob metadata.")?;
+                tx.execute(
+                    "DELETE FROM persistent.blobentry
+                     WHERE keyentryid = ? AND subcomponent_type = ? AND id > ?;",
+                    params![key_id, sc_type, old_blob_id],
+                )
+                .context("Trying to delete blob.")?;
+            }
+        }
+
+        tx.execute("DELETE FROM persistent.blobjournal;", NO_PARAMS)
+            .context("Trying to clear journal.")?;
+        Ok(by_key.len())
+    }
+
+    /// Why would we insert a deleted blob? This weird function is for the purpose of legacy
+    /// key migration in the case where we bulk delete all the keys of an app or even a user.
+    /// We use this to insert key blobs into the database which can then be garbage collected
+    /// lazily by the key garbage collector.
+    pub fn set_deleted_blob(&mut self, blob: &[u8], blob_metadata: &BlobMetaData) -> Result<()> {
+        let _wp = wd::watch_millis("KeystoreDB::set_deleted_blob", 500);
+
+        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+            Self::set_blob_internal(
+                tx,
+                Self::UNASSIGNED_KEY_ID,
+                SubComponentType::KEY_BLOB,
+                Some(blob),
+                Some(blob_metadata),
+            )
+            .need_gc()
+        })
+        .context("In set_deleted_blob.")
+    }
+
+    fn set_blob_internal(
+        tx: &Transaction,
+        key_id: i64,
+        sc_type: SubComponentType,
+        blob: Option<&[u8]>,
+        blob_metadata: Option<&BlobMetaData>,
+    ) -> Result<()> {
+        match (blob, sc_type) {
+            (Some(blob), _) => {
+                Self::insert_blobs_internal(tx, key_id, &[(sc_type, blob, blob_metadata)])
+                    .context("In set_blob_internal.")?;
+            }
+            (None, SubComponentType::CERT) | (None, SubComponentType::CERT_CHAIN) => {
                 tx.execute(
                     "DELETE FROM persistent.blobentry
                     WHERE subcomponent_type = ? AND keyentryid = ?;",
@@ -1692,6 +2474,252 @@ impl KeystoreDB {
         Ok(())
     }
 
+    /// Inserts the given blobs and their metadata for the key entry `key_id`. All blobs share
+    /// one cached prepared statement per table, and the id of a new blob is taken from the
+    /// insert rather than queried separately.
+    fn insert_blobs_internal(
+        tx: &Transaction,
+        key_id: i64,
+        blobs: &[(SubComponentType, &[u8], Option<&BlobMetaData>)],
+    ) -> Result<()> {
+        let mut stmt = tx
+            .prepare_cached(
+                "INSERT INTO persistent.blobentry
+                 (subcomponent_type, keyentryid, blob) VALUES (?, ?, ?);",
+            )
+            .context("In insert_blobs_internal: Failed to prepare statement.")?;
+        for (sc_type, blob, blob_metadata) in blobs {
+            let envelope = if *sc_type == SubComponentType::KEY_BLOB
+                && Self::is_envelope_eligible(tx, key_id)
+                    .context("In insert_blobs_internal: Trying to check envelope eligibility.")?
+            {
+                blob_envelope::seal(blob)
+                    .context("In insert_blobs_internal: Trying to seal key blob.")?
+            } else {
+                None
+            };
+            let blob_id = match &envelope {
+                Some((sealed_blob, _, _)) => stmt.insert(params![sc_type, key_id, sealed_blob]),
+                None => stmt.insert(params![sc_type, key_id, blob]),
+            }
+            .context("In insert_blobs_internal: Failed to insert blob.")?;
+            if let Some(blob_metadata) = blob_metadata {
+                blob_metadata
+                    .store_in_db(blob_id, tx)
+                    .context("In insert_blobs_internal: Trying to store blob metadata.")?;
+            }
+            if let Some((_, iv, tag)) = envelope {
+                Self::envelope_metadata(iv, tag)
+                    .store_in_db(blob_id, tx)
+                    .context("In insert_blobs_internal: Trying to store envelope metadata.")?;
+            }
+            if *sc_type == SubComponentType::KEY_BLOB
+                && Self::is_rollback_resistant(tx, key_id)
+                    .context("In insert_blobs_internal: Trying to check rollback resistance.")?
+            {
+                let mut rollback_metadata = BlobMetaData::new();
+                rollback_metadata.add(BlobMetaEntry::RollbackResistant(true));
+                rollback_metadata
+                    .store_in_db(blob_id, tx)
+                    .context("In insert_blobs_internal: Trying to store rollback metadata.")?;
+            }
+        }
+        Ok(())
+    }
+
+    fn is_rollback_resistant(tx: &Transaction, key_id: i64) -> Result<bool> {
+        let mut stmt = tx
+            .prepare_cached(
+                "SELECT COUNT(keyentryid) FROM persistent.keyparameter
+                 WHERE keyentryid = ? AND tag = ?;",
+            )
+            .context("In is_rollback_resistant: Failed to prepare statement.")?;
+        let count: i64 = stmt
+            .query_row(params![key_id, Tag::ROLLBACK_RESISTANCE.0], |row| row.get(0))
+            .context("In is_rollback_resistant: Failed to query key parameters.")?;
+        Ok(count != 0)
+    }
+
+    /// Records that KeyMint did not acknowledge the deletion of the given rollback resistant
+    /// key blob. The blob is kept in the database and excluded from the regular garbage
+    /// collection until `get_pending_secure_deletions` hands it out for another attempt.
+    pub fn defer_secure_deletion(&mut self, blob_id: i64) -> Result<()> {
+        let _wp = wd::watch_millis("KeystoreDB::defer_secure_deletion", 500);
+
+        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+            tx.execute(
+                "INSERT OR IGNORE INTO persistent.pendingsecuredeletion (blobentryid, attempts)
+                 VALUES (?, 0);",
+                params![blob_id],
+            )
+            .context("Trying to insert pending secure deletion.")?;
+            tx.execute(
+                "UPDATE persistent.pendingsecuredeletion SET attempts = attempts + 1
+                 WHERE blobentryid = ?;",
+                params![blob_id],
+            )
+            .context("Trying to count secure deletion attempt.")?;
+            Ok(()).no_gc()
+        })
+        .context("In defer_secure_deletion.")
+    }
+
+    /// Returns up to `max_blobs` rollback resistant key blobs whose deletion KeyMint did not
+    /// acknowledge yet, the ones with the fewest attempts first. Once deleted successfully,
+    /// the blobs are passed back to `handle_next_superseded_blobs` like any other blob.
+    pub fn get_pending_secure_deletions(
+        &mut self,
+        max_blobs: usize,
+    ) -> Result<Vec<(i64, Vec<u8>, BlobMetaData)>> {
+        let _wp = wd::watch_millis("KeystoreDB::get_pending_secure_deletions", 500);
+
+        self.with_transaction(TransactionBehavior::Deferred, |tx| {
+            let blobs: Vec<(i64, Vec<u8>)> = {
+                let mut stmt = tx
+                    .prepare(
+                        "SELECT b.id, b.blob FROM persistent.pendingsecuredeletion AS p
+                         INNER JOIN persistent.blobentry AS b ON p.blobentryid = b.id
+                         ORDER BY p.attempts ASC LIMIT ?;",
+                    )
+                    .context("Trying to prepare query for pending secure deletions.")?;
+                let rows = stmt
+                    .query_map(params![max_blobs as i64], |row| Ok((row.get(0)?, row.get(1)?)))
+                    .context("Trying to query pending secure deletions.")?;
+                rows.collect::<Result<Vec<(i64, Vec<u8>)>, rusqlite::Error>>()
+                    .context("Trying to extract pending secure deletions.")?
+            };
+            blobs
+                .into_iter()
+                .map(|(blob_id, blob)| {
+                    let (blob, blob_metadata) =
+                        Self::open_key_blob(blob, BlobMetaData::load_from_db(blob_id, tx)?)?;
+                    Ok((blob_id, blob, blob_metadata))
+                })
+                .collect::<Result<Vec<(i64, Vec<u8>, BlobMetaData)>>>()
+                .context("Trying to load blob metadata.")
+                .no_gc()
+        })
+        .context("In get_pending_secure_deletions.")
+    }
+
+    /// Only the key blobs of client keys are envelope encrypted. Keystore's own internal keys
+    /// are excluded, because the database key is derived with one of them.
+    fn is_envelope_eligible(tx: &Transaction, key_id: i64) -> Result<bool> {
+        let mut stmt = tx
+            .prepare_cached(
+                "SELECT COUNT(id) FROM persistent.keyentry
+                 WHERE id = ? AND key_type = ? AND NOT (domain = ? AND namespace = ?);",
+            )
+            .context("In is_envelope_eligible: Failed to prepare statement.")?;
+        let count: i64 = stmt
+            .query_row(
+                params![key_id, KeyType::Client, Domain::APP.0 as u32, AID_KEYSTORE as i64],
+                |row| row.get(0),
+            )
+            .context("In is_envelope_eligible: Failed to query key entry.")?;
+        Ok(count != 0)
+    }
+
+    fn envelope_metadata(iv: Vec<u8>, tag: Vec<u8>) -> BlobMetaData {
+        let mut metadata = BlobMetaData::new();
+        metadata.add(BlobMetaEntry::EnvelopeIv(iv));
+        metadata.add(BlobMetaEntry::EnvelopeAeadTag(tag));
+        metadata
+    }
+
+    /// Removes the envelope of a key blob if it has one. The envelope metadata is stripped,
+    /// so that callers never see it and cannot carry it over to a new blob by accident.
+    fn open_key_blob(
+        mut blob: Vec<u8>,
+        mut metadata: BlobMetaData,
+    ) -> Result<(Vec<u8>, BlobMetaData)> {
+        let iv = metadata.data.remove(&BlobMetaData::EnvelopeIv);
+        let tag = metadata.data.remove(&BlobMetaData::EnvelopeAeadTag);
+        match (iv, tag) {
+            (None, None) => Ok((blob, metadata)),
+            (Some(BlobMetaEntry::EnvelopeIv(iv)), Some(BlobMetaEntry::EnvelopeAeadTag(tag))) => {
+                blob_envelope::open(&mut blob, &iv, &tag)
+                    .context("In open_key_blob: Trying to open key blob envelope.")?;
+                Ok((blob, metadata))
+            }
+            _ => Err(KsError::sys()).context("In open_key_blob: Incomplete envelope metadata."),
+        }
+    }
+
+    /// Returns true if at least one key blob in the database is envelope encrypted.
+    pub fn has_enveloped_blobs(&mut self) -> Result<bool> {
+        let _wp = wd::watch_millis("KeystoreDB::has_enveloped_blobs", 500);
+
+        self.with_transaction(TransactionBehavior::Deferred, |tx| {
+            tx.query_row(
+                "SELECT EXISTS (SELECT 1 FROM persistent.blobmetadata WHERE tag = ?);",
+                params![BlobMetaData::EnvelopeIv],
+                |row| row.get(0),
+            )
+            .context("Failed to query envelope metadata.")
+            .no_gc()
+        })
+        .context("In has_enveloped_blobs.")
+    }
+
+    /// Envelope encrypts up to `max_blobs` eligible key blobs that are still stored without an
+    /// envelope, in place. Returns the number of blobs that were encrypted. This is used to
+    /// migrate existing key blobs lazily after envelope encryption was enabled.
+    pub fn envelope_key_blobs(&mut self, max_blobs: usize) -> Result<usize> {
+        let _wp = wd::watch_millis("KeystoreDB::envelope_key_blobs", 500);
+
+        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+            let blobs: Vec<(i64, Vec<u8>)> = {
+                let mut stmt = tx
+                    .prepare(
+                        "SELECT b.id, b.blob FROM persistent.blobentry AS b
+                         INNER JOIN persistent.keyentry AS k ON b.keyentryid = k.id
+                         WHERE b.subcomponent_type = ?
+                         AND k.key_type = ?
+                         AND NOT (k.domain = ? AND k.namespace = ?)
+                         AND b.id NOT IN (
+                             SELECT blobentryid FROM persistent.blobmetadata WHERE tag = ?
+                         ) LIMIT ?;",
+                    )
+                    .context("Trying to prepare query for plain key blobs.")?;
+                let rows = stmt
+                    .query_map(
+                        params![
+                            SubComponentType::KEY_BLOB,
+                            KeyType::Client,
+                            Domain::APP.0 as u32,
+                            AID_KEYSTORE as i64,
+                            BlobMetaData::EnvelopeIv,
+                            max_blobs as i64,
+                        ],
+                        |row| Ok((row.get(0)?, row.get(1)?)),
+                    )
+                    .context("Trying to query plain key blobs.")?;
+                rows.collect::<Result<Vec<(i64, Vec<u8>)>, rusqlite::Error>>()
+                    .context("Trying to extract plain key blobs.")?
+            };
+
+            for (blob_id, blob) in &blobs {
+                let (sealed_blob, iv, tag) =
+                    match blob_envelope::seal(blob).context("Trying to seal key blob.")? {
+                        Some(envelope) => envelope,
+                        // Envelope encryption is not available, leave the remaining blobs alone.
+                        None => return Ok(0).no_gc(),
+                    };
+                tx.execute(
+                    "UPDATE persistent.blobentry SET blob = ? WHERE id = ?;",
+                    params![sealed_blob, blob_id],
+                )
+                .context("Trying to update key blob.")?;
+                Self::envelope_metadata(iv, tag)
+                    .store_in_db(*blob_id, tx)
+                    .context("Trying to store envelope metadata.")?;
+            }
+            Ok(blobs.len()).no_gc()
+        })
+        .context("In envelope_key_blobs.")
+    }
+
     /// Inserts a collection of key parameters into the `persistent.keyparameter` table
     /// and associates them with the given `key_id`.
     #[cfg(test)]
@@ -1708,7 +2736,7 @@ impl KeystoreDB {
         params: &[KeyParameter],
     ) -> Result<()> {
         let mut stmt = tx
-            .prepare(
+            .prepare_cached(
                 "INSERT into persistent.keyparameter (keyentryid, tag, data, security_level)
                 VALUES (?, ?, ?, ?);",
             )
@@ -2234,6 +3262,9 @@ impl KeystoreDB {
 
     /// Moves the key given by KeyIdGuard to the new location at `destination`. If the destination
     /// is already occupied by a key, this function fails with `ResponseCode::INVALID_ARGUMENT`.
+    /// The metadata, parameters, and blobs of the key are referenced by key id and move with the
+    /// key entry. The grants of the key were issued by the previous owner, so they are revoked
+    /// in the same transaction.
     pub fn migrate_key_namespace(
         &mut self,
         key_id_guard: KeyIdGuard,
@@ -2292,6 +3323,12 @@ impl KeystoreDB {
                 return Err(KsError::sys())
                     .context(format!("Update succeeded, but {} rows were updated.", updated));
             }
+
+            tx.execute(
+                "DELETE FROM persistent.grant WHERE keyentryid = ?;",
+                params![key_id_guard.id()],
+            )
+            .context("Failed to revoke grants.")?;
             Ok(()).no_gc()
         })
         .context("In migrate_key_namespace:")
@@ -2300,8 +3337,11 @@ impl KeystoreDB {
     /// Store a new key in a single transaction.
     /// The function creates a new key entry, populates the blob, key parameter, and metadata
     /// fields, and rebinds the given alias to the new key.
-    /// The boolean returned is a hint for the garbage collector. If true, a key was replaced,
-    /// is now unreferenced and needs to be collected.
+    /// A key previously bound to the alias is marked unreferenced in the same transaction, so
+    /// it is only subjected to garbage collection once the new key has been committed.
+    /// If `no_clobber` is set and the alias is already taken, the new key is discarded instead
+    /// and `ResponseCode::INVALID_ARGUMENT` is returned. The new key blob is still recorded,
+    /// so that the garbage collector deletes it from the KeyMint back end.
     #[allow(clippy::too_many_arguments)]
     pub fn store_new_key(
         &mut self,
@@ -2312,6 +3352,7 @@ impl KeystoreDB {
         cert_info: &CertificateInfo,
         metadata: &KeyMetaData,
         km_uuid: &Uuid,
+        no_clobber: bool,
     ) -> Result<KeyIdGuard> {
         let _wp = wd::watch_millis("KeystoreDB::store_new_key", 500);
 
@@ -2335,51 +3376,57 @@ impl KeystoreDB {
             // database here and then immediately replaced by the superseding blob.
             // The garbage collector will then subject the blob to deleteKey of the
             // KM back end to permanently invalidate the key.
-            let need_gc = if let Some((blob, blob_metadata)) = superseded_blob {
-                Self::set_blob_internal(
-                    tx,
-                    key_id.id(),
-                    SubComponentType::KEY_BLOB,
-                    Some(blob),
-                    Some(blob_metadata),
-                )
-                .context("Trying to insert superseded key blob.")?;
-                true
-            } else {
-                false
-            };
-
-            Self::set_blob_internal(
-                tx,
-                key_id.id(),
-                SubComponentType::KEY_BLOB,
-                Some(blob),
-                Some(blob_metadata),
-            )
-            .context("Trying to insert the key blob.")?;
-            if let Some(cert) = &cert_info.cert {
-                Self::set_blob_internal(tx, key_id.id(), SubComponentType::CERT, Some(cert), None)
-                    .context("Trying to insert the certificate.")?;
+            let mut blobs = Vec::with_capacity(4);
+            if let Some((blob, blob_metadata)) = superseded_blob {
+                blobs.push((SubComponentType::KEY_BLOB, blob, Some(blob_metadata)));
             }
-            if let Some(cert_chain) = &cert_info.cert_chain {
-                Self::set_blob_internal(
-                    tx,
-                    key_id.id(),
-                    SubComponentType::CERT_CHAIN,
-                    Some(cert_chain),
-                    None,
-                )
-                .context("Trying to insert the certificate chain.")?;
+            let need_gc = superseded_blob.is_some();
+            blobs.push((SubComponentType::KEY_BLOB, blob, Some(blob_metadata)));
+            if let Some(cert) = cert_info.cert.as_deref() {
+                blobs.push((SubComponentType::CERT, cert, None));
             }
+            if let Some(cert_chain) = cert_info.cert_chain.as_deref() {
+                blobs.push((SubComponentType::CERT_CHAIN, cert_chain, None));
+            }
+            // The key parameters go first, so that the key blobs of a rollback resistant key
+            // are marked as such.
             Self::insert_keyparameter_internal(tx, &key_id, params)
                 .context("Trying to insert key parameters.")?;
+            Self::insert_blobs_internal(tx, key_id.id(), &blobs)
+                .context("Trying to insert the key blob and certificates.")?;
             metadata.store_in_db(key_id.id(), tx).context("Trying to insert key metadata.")?;
-            let need_gc = Self::rebind_alias(tx, &key_id, alias, &domain, namespace, key_type)
+            if no_clobber
+                && Self::alias_taken_internal(tx, alias, &domain, namespace, key_type)
+                    .context("Trying to check if the alias is taken.")?
+            {
+                Self::mark_unreferenced(tx, key_id.id())
+                    .context("Trying to discard the new key entry.")?;
+                return Ok(None).need_gc();
+            }
+            let need_gc = Self::rebind_alias(tx, &key_id, alias, &domain, namespace, key_type)
                 .context("Trying to rebind alias.")?
                 || need_gc;
-            Ok(key_id).do_gc(need_gc)
+            Ok(Some(key_id)).do_gc(need_gc)
         })
-        .context("In store_new_key.")
+        .context("In store_new_key.")?
+        .ok_or(KsError::Rc(ResponseCode::INVALID_ARGUMENT))
+        .context("In store_new_key: The alias is already taken.")
+    }
+
+    fn alias_taken_internal(
+        tx: &Transaction,
+        alias: &str,
+        domain: &Domain,
+        namespace: &i64,
+        key_type: KeyType,
+    ) -> Result<bool> {
+        tx.query_row(
+            "SELECT EXISTS (SELECT 1 FROM persistent.keyentry
+                 WHERE alias = ? AND domain = ? AND namespace = ? AND key_type = ? AND state = ?);",
+            params![alias, domain.0 as u32, namespace, key_type, KeyLifeCycle::Live],
+            |row| row.get(0),
+        )
+        .context("In alias_taken_internal.")
     }
 
     /// Store a new certificate
@@ -2432,6 +3479,148 @@ impl KeystoreDB {
         .context("In store_new_certificate.")
     }
 
+    /// Returns the alias, certificates, and creation date of every live client key entry with
+    /// an alias in the given domain and namespace that has a certificate or a certificate
+    /// chain. Key blobs and key parameters are never loaded.
+    pub fn export_certificates(
+        &mut self,
+        domain: Domain,
+        namespace: i64,
+    ) -> Result<Vec<CertificateBackup>> {
+        let _wp = wd::watch_millis("KeystoreDB::export_certificates", 500);
+
+        self.with_transaction(TransactionBehavior::Deferred, |tx| {
+            let mut stmt = tx
+                .prepare(
+                    "SELECT id, alias FROM persistent.keyentry
+                     WHERE domain = ?
+                     AND namespace = ?
+                     AND alias IS NOT NULL
+                     AND state = ?
+                     AND key_type = ?;",
+                )
+                .context("Failed to prepare.")?;
+            let mut rows = stmt
+                .query(params![domain.0 as u32, namespace, KeyLifeCycle::Live, KeyType::Client])
+                .context("Failed to query.")?;
+            let mut entries: Vec<(i64, String)> = Vec::new();
+            db_utils::with_rows_extract_all(&mut rows, |row| {
+                entries.push((
+                    row.get(0).context("Trying to extract key id.")?,
+                    row.get(1).context("Trying to extract alias.")?,
+                ));
+                Ok(())
+            })
+            .context("Failed to extract rows.")?;
+
+            let mut backups = Vec::new();
+            for (key_id, alias) in entries {
+                let (_, _, cert, cert_chain) =
+                    Self::load_blob_components(key_id, KeyEntryLoadBits::PUBLIC, tx)
+                        .context("Trying to load certificates.")?;
+                if cert.is_none() && cert_chain.is_none() {
+                    continue;
+                }
+                let metadata =
+                    KeyMetaData::load_from_db(key_id, tx).context("Trying to load metadata.")?;
+                backups.push(CertificateBackup {
+                    alias,
+                    cert,
+                    cert_chain,
+                    creation_date: metadata.creation_date().copied(),
+                });
+            }
+            Ok(backups).no_gc()
+        })
+        .context("In export_certificates.")
+    }
+
+    /// Creates a certificate only entry in the given domain and namespace for each of the
+    /// given backups, like `store_new_certificate` but with both the certificate and the
+    /// certificate chain. Existing entries are never replaced. Returns for each backup whether
+    /// an entry was created, which is not the case if the alias was already taken.
+    pub fn import_certificates(
+        &mut self,
+        domain: Domain,
+        namespace: i64,
+        backups: &[CertificateBackup],
+    ) -> Result<Vec<bool>> {
+        let _wp = wd::watch_millis("KeystoreDB::import_certificates", 500);
+
+        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+            let now = DateTime::now().context("Trying to make creation time.")?;
+            backups
+                .iter()
+                .map(|backup| {
+                    let taken: bool = tx
+                        .query_row(
+                            "SELECT EXISTS (SELECT 1 FROM persistent.keyentry
+                                WHERE domain = ?
+                                AND namespace = ?
+                                AND alias = ?
+                                AND state = ?
+                                AND key_type = ?);",
+                            params![
+                                domain.0 as u32,
+                                namespace,
+                                backup.alias,
+                                KeyLifeCycle::Live,
+                                KeyType::Client
+                            ],
+                            |row| row.get(0),
+                        )
+                        .context("Trying to look up alias.")?;
+                    if taken {
+                        return Ok(false);
+                    }
+                    let key_id = Self::create_key_entry_internal(
+                        tx,
+                        &domain,
+                        &namespace,
+                        KeyType::Client,
+                        &KEYSTORE_UUID,
+                    )
+                    .context("Trying to create new key entry.")?;
+                    if let Some(cert) = &backup.cert {
+                        Self::set_blob_internal(
+                            tx,
+                            key_id.id(),
+                            SubComponentType::CERT,
+                            Some(cert),
+                            None,
+                        )
+                        .context("Trying to insert certificate.")?;
+                    }
+                    if let Some(cert_chain) = &backup.cert_chain {
+                        Self::set_blob_internal(
+                            tx,
+                            key_id.id(),
+                            SubComponentType::CERT_CHAIN,
+                            Some(cert_chain),
+                            None,
+                        )
+                        .context("Trying to insert certificate chain.")?;
+                    }
+                    let mut metadata = KeyMetaData::new();
+                    metadata.add(KeyMetaEntry::CreationDate(backup.creation_date.unwrap_or(now)));
+                    metadata.store_in_db(key_id.id(), tx).context("Trying to insert metadata.")?;
+                    Self::rebind_alias(
+                        tx,
+                        &key_id,
+                        &backup.alias,
+                        &domain,
+                        &namespace,
+                        KeyType::Client,
+                    )
+                    .context("Trying to rebind alias.")?;
+                    Ok(true)
+                })
+                .collect::<Result<Vec<bool>>>()
+                .no_gc()
+        })
+        .context("In import_certificates.")
+    }
+
     // Helper function loading the key_id given the key descriptor
     // tuple comprising domain, namespace, and alias.
     // Requires a valid transaction.
@@ -2476,12 +3665,15 @@ impl KeystoreDB {
     ///       `namespace`.
     /// In each case the information returned is sufficient to perform the access
     /// check and the key id can be used to load further key artifacts.
+    /// The last element of the tuple holds the grants of the key to SELinux namespaces
+    /// that may give the caller access to the key, see `check_access`. It is only
+    /// populated for Domain::GRANT and Domain::KEY_ID.
     fn load_access_tuple(
         tx: &Transaction,
         key: &KeyDescriptor,
         key_type: KeyType,
         caller_uid: u32,
-    ) -> Result<(i64, KeyDescriptor, Option<KeyPermSet>)> {
+    ) -> Result<(i64, KeyDescriptor, Option<KeyPermSet>, Vec<(i64, KeyPermSet)>)> {
         match key.domain {
             // Domain App or SELinux. In this case we load the key_id from
             // the keyentry database for further loading of key components.
@@ -2497,33 +3689,61 @@ impl KeystoreDB {
                 let key_id = Self::load_key_entry_id(tx, &access_key, key_type)
                     .with_context(|| format!("With key.domain = {:?}.", access_key.domain))?;
 
-                Ok((key_id, access_key, None))
+                Ok((key_id, access_key, None, Vec::new()))
             }
 
             // Domain::GRANT. In this case we load the key_id and the access_vector
-            // from the grant table.
+            // from the grant table. A grant to an SELinux namespace can be used by any
+            // caller, but it only gives access if the caller also has access to the
+            // namespace. So we return an empty access vector for the grant descriptor and
+            // the actual access vector with the grantee namespace.
             Domain::GRANT => {
+                let now = DateTime::now().context("Domain::GRANT: Failed to get current time.")?;
                 let mut stmt = tx
                     .prepare(
-                        "SELECT keyentryid, access_vector FROM persistent.grant
-                            WHERE grantee = ? AND id = ? AND
+                        "SELECT keyentryid, access_vector, grantee_domain, grantee
+                            FROM persistent.grant
+                            WHERE id = ? AND
+                            ((grantee_domain = ? AND grantee = ?) OR grantee_domain = ?) AND
+                            (expires_at IS NULL OR expires_at > ?) AND
                             (SELECT state FROM persistent.keyentry WHERE id = keyentryid) = ?;",
                     )
                     .context("Domain::GRANT prepare statement failed")?;
                 let mut rows = stmt
-                    .query(params![caller_uid as i64, key.nspace, KeyLifeCycle::Live])
+                    .query(params![
+                        key.nspace,
+                        Domain::APP.0,
+                        caller_uid as i64,
+                        Domain::SELINUX.0,
+                        now,
+                        KeyLifeCycle::Live
+                    ])
                     .context("Domain:Grant: query failed.")?;
-                let (key_id, access_vector): (i64, i32) =
+                let (key_id, access_vector, grantee_domain, grantee): (i64, i32, i32, i64) =
                     db_utils::with_rows_extract_one(&mut rows, |row| {
                         let r =
                             row.map_or_else(|| Err(KsError::Rc(ResponseCode::KEY_NOT_FOUND)), Ok)?;
                         Ok((
                             r.get(0).context("Failed to unpack key_id.")?,
                             r.get(1).context("Failed to unpack access_vector.")?,
+                            r.get(2).context("Failed to unpack grantee_domain.")?,
+                            r.get(3).context("Failed to unpack grantee.")?,
                         ))
                     })
                     .context("Domain::GRANT.")?;
-                Ok((key_id, key.clone(), Some(access_vector.into())))
+                match Grantee::from_row(Domain(grantee_domain), grantee)
+                    .context("Domain::GRANT.")?
+                {
+                    Grantee::Uid(_) => {
+                        Ok((key_id, key.clone(), Some(access_vector.into()), Vec::new()))
+                    }
+                    Grantee::Namespace(namespace) => Ok((
+                        key_id,
+                        key.clone(),
+                        Some(KeyPermSet::from(0)),
+                        vec![(namespace, access_vector.into())],
+                    )),
+                }
             }
 
             // Domain::KEY_ID. In this case we load the domain and namespace from the
@@ -2558,20 +3778,27 @@ impl KeystoreDB {
                 // But we cannot know this if domain is anything but App. E.g. in the case
                 // of Domain::SELINUX we have to speculatively check for grants because we have to
                 // consult the SEPolicy before we know if the caller is the owner.
-                let access_vector: Option<KeyPermSet> =
+                // The same holds for grants to SELinux namespaces, which we cannot match
+                // with the caller before consulting the SEPolicy.
+                let (access_vector, namespace_grants): (Option<KeyPermSet>, _) =
                     if domain != Domain::APP || namespace != caller_uid as i64 {
+                        let now =
+                            DateTime::now().context("Domain::KEY_ID: Failed to get current time.")?;
                         let access_vector: Option<i32> = tx
                             .query_row(
                                 "SELECT access_vector FROM persistent.grant
-                                WHERE grantee = ? AND keyentryid = ?;",
-                                params![caller_uid as i64, key.nspace],
+                                WHERE grantee_domain = ? AND grantee = ? AND keyentryid = ? AND
+                                (expires_at IS NULL OR expires_at > ?);",
+                                params![Domain::APP.0, caller_uid as i64, key.nspace, now],
                                 |row| row.get(0),
                             )
                             .optional()
                             .context("Domain::KEY_ID: query grant failed.")?;
-                        access_vector.map(|p| p.into())
+                        let namespace_grants = Self::load_namespace_grants(tx, key.nspace, now)
+                            .context("Domain::KEY_ID.")?;
+                        (access_vector.map(|p| p.into()), namespace_grants)
                     } else {
-                        None
+                        (None, Vec::new())
                     };
 
                 let key_id = key.nspace;
@@ -2579,12 +3806,70 @@ impl KeystoreDB {
                 access_key.domain = domain;
                 access_key.nspace = namespace;
 
-                Ok((key_id, access_key, access_vector))
+                Ok((key_id, access_key, access_vector, namespace_grants))
             }
             _ => Err(anyhow!(KsError::sys())),
         }
     }
 
+    /// Loads the unexpired grants of the given key to SELinux namespaces as tuples of
+    /// grantee namespace and access vector.
+    fn load_namespace_grants(
+        tx: &Transaction,
+        key_id: i64,
+        now: DateTime,
+    ) -> Result<Vec<(i64, KeyPermSet)>> {
+        let mut stmt = tx
+            .prepare(
+                "SELECT grantee, access_vector FROM persistent.grant
+                    WHERE grantee_domain = ? AND keyentryid = ? AND
+                    (expires_at IS NULL OR expires_at > ?);",
+            )
+            .context("In load_namespace_grants: Failed to prepare statement.")?;
+        let mut rows = stmt
+            .query(params![Domain::SELINUX.0, key_id, now])
+            .context("In load_namespace_grants: Query failed.")?;
+        let mut namespace_grants = Vec::new();
+        db_utils::with_rows_extract_all(&mut rows, |row| {
+            let namespace: i64 = row.get(0).context("Failed to read grantee.")?;
+            let access_vector: i32 = row.get(1).context("Failed to read access vector.")?;
+            namespace_grants.push((namespace, access_vector.into()));
+            Ok(())
+        })
+        .context("In load_namespace_grants: Failed to extract rows.")?;
+        Ok(namespace_grants)
+    }
+
+    /// Performs access control for an access tuple loaded by `load_access_tuple`.
+    /// If the caller has no access to the key by ownership or by a grant to its uid, the
+    /// caller may still have access by a grant to an SELinux namespace. For this, the caller
+    /// must have the requested permission in the grantee namespace, which is checked by
+    /// calling `check_permission` with a Domain::SELINUX descriptor of the namespace, and the
+    /// permission must be in the access vector of the grant, which is checked by calling
+    /// `check_permission` with a Domain::GRANT descriptor and the access vector.
+    fn check_access(
+        check_permission: &impl Fn(&KeyDescriptor, Option<KeyPermSet>) -> Result<()>,
+        access_key_descriptor: &KeyDescriptor,
+        access_vector: Option<KeyPermSet>,
+        namespace_grants: &[(i64, KeyPermSet)],
+    ) -> Result<()> {
+        let result = check_permission(access_key_descriptor, access_vector);
+        if result.is_ok() {
+            return result;
+        }
+        let grant_key = KeyDescriptor { domain: Domain::GRANT, ..Default::default() };
+        for (namespace, grant_access_vector) in namespace_grants {
+            let namespace_key =
+                KeyDescriptor { domain: Domain::SELINUX, nspace: *namespace, ..Default::default() };
+            if check_permission(&namespace_key, None).is_ok()
+                && check_permission(&grant_key, Some(*grant_access_vector)).is_ok()
+            {
+                return Ok(());
+            }
+        }
+        result
+    }
+
     fn load_blob_components(
         key_id: i64,
         load_bits: KeyEntryLoadBits,
@@ -2633,11 +3918,14 @@ impl KeystoreDB {
         .context("In load_blob_components.")?;
 
         let blob_info = key_blob.map_or::<Result<_>, _>(Ok(None), |(blob_id, blob)| {
-            Ok(Some((
-                blob,
-                BlobMetaData::load_from_db(blob_id, tx)
-                    .context("In load_blob_components: Trying to load blob_metadata.")?,
-            )))
+            Ok(Some(
+                Self::open_key_blob(
+                    blob,
+                    BlobMetaData::load_from_db(blob_id, tx)
+                        .context("In load_blob_components: Trying to load blob_metadata.")?,
+                )
+                .context("In load_blob_components.")?,
+            ))
         })?;
 
         Ok((has_km_blob, blob_info, cert_blob, cert_chain_blob))
@@ -2671,8 +3959,7 @@ impl KeystoreDB {
 
     /// Decrements the usage count of a limited use key. This function first checks whether the
     /// usage has been exhausted, if not, decreases the usage count. If the usage count reaches
-    /// zero, the key also gets marked unreferenced and scheduled for deletion.
-    /// Returns Ok(true) if the key was marked unreferenced as a hint to the garbage collector.
+    /// zero, the key also gets marked unreferenced and the garbage collector is notified.
     pub fn check_and_update_key_usage_count(&mut self, key_id: i64) -> Result<()> {
         let _wp = wd::watch_millis("KeystoreDB::check_and_update_key_usage_count", 500);
 
@@ -2767,13 +4054,19 @@ impl KeystoreDB {
             .context("In load_key_entry: Failed to initialize transaction.")?;
 
         // Load the key_id and complete the access control tuple.
-        let (key_id, access_key_descriptor, access_vector) =
+        let (key_id, access_key_descriptor, access_vector, namespace_grants) =
             Self::load_access_tuple(&tx, key, key_type, caller_uid)
                 .context("In load_key_entry.")?;
 
         // Perform access control. It is vital that we return here if the permission is denied.
         // So do not touch that '?' at the end.
-        check_permission(&access_key_descriptor, access_vector).context("In load_key_entry.")?;
+        Self::check_access(
+            check_permission,
+            &access_key_descriptor,
+            access_vector,
+            &namespace_grants,
+        )
+        .context("In load_key_entry.")?;
 
         // KEY ID LOCK 2/2
         // If we did not get a key id lock by now, it was because we got a key descriptor
@@ -2842,6 +4135,34 @@ impl KeystoreDB {
         Ok(updated != 0)
     }
 
+    /// Marks all per-boot keys that were created in a boot other than the one given by
+    /// `boot_id` as unreferenced, so that the garbage collector deletes them. Returns the
+    /// number of keys affected.
+    pub fn unbind_keys_of_previous_boots(&mut self, boot_id: &str) -> Result<usize> {
+        let _wp = wd::watch_millis("KeystoreDB::unbind_keys_of_previous_boots", 500);
+
+        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+            let key_ids: Vec<i64> = {
+                let mut stmt = tx
+                    .prepare(
+                        "SELECT keyentryid FROM persistent.keymetadata
+                         WHERE tag = ? AND data != ?;",
+                    )
+                    .context("Trying to prepare query for per-boot keys.")?;
+                let rows = stmt
+                    .query_map(params![KeyMetaData::BootId, boot_id], |row| row.get(0))
+                    .context("Trying to query per-boot keys.")?;
+                rows.collect::<Result<Vec<i64>, rusqlite::Error>>()
+                    .context("Trying to extract per-boot keys.")?
+            };
+            for key_id in &key_ids {
+                Self::mark_unreferenced(tx, *key_id).context("Trying to unbind per-boot key.")?;
+            }
+            Ok((!key_ids.is_empty(), key_ids.len()))
+        })
+        .context("In unbind_keys_of_previous_boots.")
+    }
+
     /// Marks the given key as unreferenced and removes all of the grants to this key.
     /// Returns Ok(true) if a key was marked unreferenced as a hint for the garbage collector.
     pub fn unbind_key(
@@ -2854,14 +4175,19 @@ impl KeystoreDB {
         let _wp = wd::watch_millis("KeystoreDB::unbind_key", 500);
 
         self.with_transaction(TransactionBehavior::Immediate, |tx| {
-            let (key_id, access_key_descriptor, access_vector) =
+            let (key_id, access_key_descriptor, access_vector, namespace_grants) =
                 Self::load_access_tuple(tx, key, key_type, caller_uid)
                     .context("Trying to get access tuple.")?;
 
             // Perform access control. It is vital that we return here if the permission is denied.
             // So do not touch that '?' at the end.
-            check_permission(&access_key_descriptor, access_vector)
-                .context("While checking permission.")?;
+            Self::check_access(
+                &check_permission,
+                &access_key_descriptor,
+                access_vector,
+                &namespace_grants,
+            )
+            .context("While checking permission.")?;
 
             Self::mark_unreferenced(tx, key_id)
                 .map(|need_gc| (need_gc, ()))
@@ -2870,6 +4196,53 @@ impl KeystoreDB {
         .context("In unbind_key.")
     }
 
+    /// Like `unbind_key` but unbinds all of the given keys in a single transaction. Failing to
+    /// resolve a key or a denied permission check only fails that key, and the returned vector
+    /// holds one result per key in the order of `keys`. On success, the result holds the access
+    /// descriptor of the unbound key. The garbage collector is notified at most once.
+    pub fn unbind_keys(
+        &mut self,
+        keys: &[KeyDescriptor],
+        key_type: KeyType,
+        caller_uid: u32,
+        check_permission: impl Fn(&KeyDescriptor, Option<KeyPermSet>) -> Result<()>,
+    ) -> Result<Vec<Result<KeyDescriptor>>> {
+        let _wp = wd::watch_millis("KeystoreDB::unbind_keys", 500);
+
+        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+            let mut need_gc = false;
+            let mut results = Vec::with_capacity(keys.len());
+            for key in keys {
+                let access = Self::load_access_tuple(tx, key, key_type, caller_uid)
+                    .context("Trying to get access tuple.")
+                    .and_then(|(key_id, access_key_descriptor, access_vector, grants)| {
+                        // Perform access control. It is vital that we skip the key if the
+                        // permission is denied.
+                        Self::check_access(
+                            &check_permission,
+                            &access_key_descriptor,
+                            access_vector,
+                            &grants,
+                        )
+                        .context("While checking permission.")?;
+                        Ok((key_id, access_key_descriptor))
+                    });
+                match access {
+                    Ok((key_id, access_key_descriptor)) => {
+                        need_gc |= Self::mark_unreferenced(tx, key_id)
+                            .context("Trying to mark the key unreferenced.")?;
+                        results.push(Ok(access_key_descriptor));
+                    }
+                    // A busy database must fail the transaction so that it gets retried.
+                    Err(e) if Self::is_locked_error(&e) => return Err(e),
+                    Err(e) => results.push(Err(e)),
+                }
+            }
+            Ok((need_gc, results))
+        })
+        .context("In unbind_keys.")
+    }
+
     fn get_key_km_uuid(tx: &Transaction, key_id: i64) -> Result<Uuid> {
         tx.query_row(
             "SELECT km_uuid FROM persistent.keyentry WHERE id = ?",
@@ -2927,6 +4300,57 @@ impl KeystoreDB {
         .context("In unbind_keys_for_namespace")
     }
 
+    /// Deletes all client keys and the super keys of all users, together with their
+    /// parameters, metadata, and grants. Keystore's own keys, i.e., the boot level key and the
+    /// remote provisioning key pool, are kept. This leaves the blob entries of the deleted keys
+    /// orphaned for subsequent garbage collection. This is used when all keys were deleted
+    /// from all KeyMint instances.
+    pub fn unbind_all_keys(&mut self) -> Result<()> {
+        let _wp = wd::watch_millis("KeystoreDB::unbind_all_keys", 500);
+
+        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+            tx.execute(
+                "DELETE FROM persistent.keymetadata
+                WHERE keyentryid IN (
+                    SELECT id FROM persistent.keyentry
+                    WHERE (key_type = ? AND NOT (domain = ? AND namespace = ?))
+                        OR key_type = ?
+                );",
+                params![KeyType::Client, Domain::APP.0 as u32, AID_KEYSTORE as i64, KeyType::Super],
+            )
+            .context("Trying to delete keymetadata.")?;
+            tx.execute(
+                "DELETE FROM persistent.keyparameter
+                WHERE keyentryid IN (
+                    SELECT id FROM persistent.keyentry
+                    WHERE (key_type = ? AND NOT (domain = ? AND namespace = ?))
+                        OR key_type = ?
+                );",
+                params![KeyType::Client, Domain::APP.0 as u32, AID_KEYSTORE as i64, KeyType::Super],
+            )
+            .context("Trying to delete keyparameters.")?;
+            tx.execute(
+                "DELETE FROM persistent.grant
+                WHERE keyentryid IN (
+                    SELECT id FROM persistent.keyentry
+                    WHERE (key_type = ? AND NOT (domain = ? AND namespace = ?))
+                        OR key_type = ?
+                );",
+                params![KeyType::Client, Domain::APP.0 as u32, AID_KEYSTORE as i64, KeyType::Super],
+            )
+            .context("Trying to delete grants.")?;
+            tx.execute(
+                "DELETE FROM persistent.keyentry
+                WHERE (key_type = ? AND NOT (domain = ? AND namespace = ?))
+                    OR key_type = ?;",
+                params![KeyType::Client, Domain::APP.0 as u32, AID_KEYSTORE as i64, KeyType::Super],
+            )
+            .context("Trying to delete keyentry.")?;
+            Ok(()).need_gc()
+        })
+        .context("In unbind_all_keys.")
+    }
+
     fn cleanup_unreferenced(tx: &Transaction) -> Result<()> {
         let _wp = wd::watch_millis("KeystoreDB::cleanup_unreferenced", 500);
         {
@@ -2969,7 +4393,9 @@ impl KeystoreDB {
     }
 
     /// Delete the keys created on behalf of the user, denoted by the user id.
-    /// Delete all the keys unless 'keep_non_super_encrypted_keys' set to true.
+    /// Delete all the keys unless 'keep_non_super_encrypted_keys' set to true. In that case,
+    /// all grants held by the apps of the user are deleted as well, and so are the auth tokens
+    /// of the secure user ids that the user's keys are bound to.
     /// Returned boolean is to hint the garbage collector to delete the unbound keys.
     /// The caller of this function should notify the gc if the returned value is true.
     pub fn unbind_keys_for_user(
@@ -3022,6 +4448,29 @@ impl KeystoreDB {
             })
             .context("In unbind_keys_for_user.")?;
 
+            // The auth tokens of a removed user must not outlive the user. They are identified
+            // by the secure user ids that the user's keys are bound to.
+            let mut secure_user_ids: HashSet<i64> = HashSet::new();
+            if !keep_non_super_encrypted_keys {
+                let mut stmt = tx
+                    .prepare(
+                        "SELECT data FROM persistent.keyparameter
+                         WHERE keyentryid = ? AND tag = ?;",
+                    )
+                    .context("In unbind_keys_for_user: Failed to prepare secure user id query.")?;
+                for key_id in &key_ids {
+                    let mut rows = stmt
+                        .query(params![key_id, Tag::USER_SECURE_ID.0])
+                        .context("In unbind_keys_for_user: Failed to query secure user ids.")?;
+                    db_utils::with_rows_extract_all(&mut rows, |row| {
+                        secure_user_ids
+                            .insert(row.get(0).context("Failed to read secure user id.")?);
+                        Ok(())
+                    })
+                    .context("In unbind_keys_for_user.")?;
+                }
+            }
+
             let mut notify_gc = false;
             for key_id in key_ids {
                 if keep_non_super_encrypted_keys {
@@ -3039,9 +4488,25 @@ impl KeystoreDB {
                     .context("In unbind_keys_for_user.")?
                     || notify_gc;
             }
-            Ok(()).do_gc(notify_gc)
+            if !keep_non_super_encrypted_keys {
+                // The user is being removed, so the grants held by the user's apps go as well.
+                tx.execute(
+                    &format!(
+                        "DELETE FROM persistent.grant
+                         WHERE grantee_domain = ? AND
+                         cast ( (grantee/{aid_user_offset}) as int) = ?;",
+                        aid_user_offset = AID_USER_OFFSET
+                    ),
+                    params![Domain::APP.0, user_id],
+                )
+                .context("In unbind_keys_for_user: Trying to delete grants held by the user.")?;
+            }
+            Ok(secure_user_ids).do_gc(notify_gc)
         })
         .context("In unbind_keys_for_user.")
+        .map(|secure_user_ids| {
+            self.perboot.remove_auth_token_entries_for_sids(&secure_user_ids);
+        })
     }
 
     fn load_key_components(
@@ -3072,6 +4537,33 @@ impl KeystoreDB {
         })
     }
 
+    /// Returns the number of live keys in the selected domain/namespace.
+    pub fn count_keys(
+        &mut self,
+        domain: Domain,
+        namespace: i64,
+        key_type: KeyType,
+    ) -> Result<usize> {
+        let _wp = wd::watch_millis("KeystoreDB::count_keys", 500);
+
+        self.with_transaction(TransactionBehavior::Deferred, |tx| {
+            tx.query_row(
+                "SELECT COUNT(id) FROM persistent.keyentry
+                     WHERE domain = ?
+                     AND namespace = ?
+                     AND alias IS NOT NULL
+                     AND state = ?
+                     AND key_type = ?;",
+                params![domain.0 as u32, namespace, KeyLifeCycle::Live, key_type],
+                |row| row.get::<_, i64>(0),
+            )
+            .context("Failed to count keys.")
+            .map(|count| count as usize)
+            .no_gc()
+        })
+        .context("In count_keys.")
+    }
+
     /// Returns a list of KeyDescriptors in the selected domain/namespace.
     /// The key descriptors will have the domain, nspace, and alias field set.
     /// Domain must be APP or SELINUX, the caller must make sure of that.
@@ -3114,6 +4606,64 @@ impl KeystoreDB {
         })
     }
 
+    /// Like `list`, but also returns the creation date, the date of the last recorded use,
+    /// and the origin of each key entry. All of them are retrieved with a single query, so
+    /// that callers can order large namespaces without loading the metadata of every entry.
+    pub fn list_with_dates(
+        &mut self,
+        domain: Domain,
+        namespace: i64,
+        key_type: KeyType,
+    ) -> Result<Vec<ListedKeyEntry>> {
+        let _wp = wd::watch_millis("KeystoreDB::list_with_dates", 500);
+
+        self.with_transaction(TransactionBehavior::Deferred, |tx| {
+            let mut stmt = tx
+                .prepare(
+                    "SELECT k.id, k.alias,
+                        (SELECT data FROM persistent.keymetadata
+                            WHERE keyentryid = k.id AND tag = ?),
+                        (SELECT data FROM persistent.keymetadata
+                            WHERE keyentryid = k.id AND tag = ?),
+                        (SELECT data FROM persistent.keyparameter
+                            WHERE keyentryid = k.id AND tag = ? LIMIT 1)
+                     FROM persistent.keyentry k
+                     WHERE k.domain = ?
+                     AND k.namespace = ?
+                     AND k.alias IS NOT NULL
+                     AND k.state = ?
+                     AND k.key_type = ?;",
+                )
+                .context("In list_with_dates: Failed to prepare.")?;
+
+            let mut rows = stmt
+                .query(params![
+                    KeyMetaData::CreationDate,
+                    KeyMetaData::LastUsedDate,
+                    Tag::ORIGIN.0,
+                    domain.0 as u32,
+                    namespace,
+                    KeyLifeCycle::Live,
+                    key_type
+                ])
+                .context("In list_with_dates: Failed to query.")?;
+
+            let mut entries: Vec<ListedKeyEntry> = Vec::new();
+            db_utils::with_rows_extract_all(&mut rows, |row| {
+                entries.push(ListedKeyEntry {
+                    id: row.get(0).context("Trying to extract key id.")?,
+                    alias: row.get(1).context("Trying to extract alias.")?,
+                    creation_date: row.get(2).context("Trying to extract creation date.")?,
+                    last_used_date: row.get(3).context("Trying to extract last use.")?,
+                    origin: row.get(4).context("Trying to extract origin.")?,
+                });
+                Ok(())
+            })
+            .context("In list_with_dates: Failed to extract rows.")?;
+            Ok(entries).no_gc()
+        })
+    }
+
     /// Adds a grant to the grant table.
     /// Like `load_key_entry` this function loads the access tuple before
     /// it uses the callback for a permission check. Upon success,
@@ -3127,6 +4677,29 @@ impl KeystoreDB {
         grantee_uid: u32,
         access_vector: KeyPermSet,
         check_permission: impl Fn(&KeyDescriptor, &KeyPermSet) -> Result<()>,
+    ) -> Result<KeyDescriptor> {
+        self.grant_with_expiry(
+            key,
+            caller_uid,
+            Grantee::Uid(grantee_uid),
+            access_vector,
+            None,
+            check_permission,
+        )
+    }
+
+    /// Like `grant` but the grant is held by `grantee`, which may also be an SELinux namespace,
+    /// and the grant expires at `expires_at` if given. Expired grants no longer give access to
+    /// the key, and they are purged by `delete_expired_grants`. Granting the key to the same
+    /// grantee again replaces the access vector and the expiry.
+    pub fn grant_with_expiry(
+        &mut self,
+        key: &KeyDescriptor,
+        caller_uid: u32,
+        grantee: Grantee,
+        access_vector: KeyPermSet,
+        expires_at: Option<DateTime>,
+        check_permission: impl Fn(&KeyDescriptor, &KeyPermSet) -> Result<()>,
     ) -> Result<KeyDescriptor> {
         let _wp = wd::watch_millis("KeystoreDB::grant", 500);
 
@@ -3140,7 +4713,7 @@ impl KeystoreDB {
             // We could check key.domain == Domain::GRANT and fail early.
             // But even if we load the access tuple by grant here, the permission
             // check denies the attempt to create a grant by grant descriptor.
-            let (key_id, access_key_descriptor, _) =
+            let (key_id, access_key_descriptor, _, _) =
                 Self::load_access_tuple(tx, key, KeyType::Client, caller_uid)
                     .context("In grant")?;
 
@@ -3155,8 +4728,8 @@ impl KeystoreDB {
             let grant_id = if let Some(grant_id) = tx
                 .query_row(
                     "SELECT id FROM persistent.grant
-                WHERE keyentryid = ? AND grantee = ?;",
-                    params![key_id, grantee_uid],
+                WHERE keyentryid = ? AND grantee_domain = ? AND grantee = ?;",
+                    params![key_id, grantee.domain().0, grantee.id()],
                     |row| row.get(0),
                 )
                 .optional()
@@ -3164,18 +4737,26 @@ impl KeystoreDB {
             {
                 tx.execute(
                     "UPDATE persistent.grant
-                    SET access_vector = ?
+                    SET access_vector = ?, expires_at = ?
                     WHERE id = ?;",
-                    params![i32::from(access_vector), grant_id],
+                    params![i32::from(access_vector), expires_at, grant_id],
                 )
                 .context("In grant: Failed to update existing grant.")?;
                 grant_id
             } else {
                 Self::insert_with_retry(|id| {
                     tx.execute(
-                        "INSERT INTO persistent.grant (id, grantee, keyentryid, access_vector)
-                        VALUES (?, ?, ?, ?);",
-                        params![id, grantee_uid, key_id, i32::from(access_vector)],
+                        "INSERT INTO persistent.grant
+                            (id, grantee_domain, grantee, keyentryid, access_vector, expires_at)
+                        VALUES (?, ?, ?, ?, ?, ?);",
+                        params![
+                            id,
+                            grantee.domain().0,
+                            grantee.id(),
+                            key_id,
+                            i32::from(access_vector),
+                            expires_at
+                        ],
                     )
                 })
                 .context("In grant")?
@@ -3194,13 +4775,25 @@ impl KeystoreDB {
         caller_uid: u32,
         grantee_uid: u32,
         check_permission: impl Fn(&KeyDescriptor) -> Result<()>,
+    ) -> Result<()> {
+        self.ungrant_grantee(key, caller_uid, Grantee::Uid(grantee_uid), check_permission)
+    }
+
+    /// Like `ungrant` but removes the grant held by `grantee`, which may also be an SELinux
+    /// namespace.
+    pub fn ungrant_grantee(
+        &mut self,
+        key: &KeyDescriptor,
+        caller_uid: u32,
+        grantee: Grantee,
+        check_permission: impl Fn(&KeyDescriptor) -> Result<()>,
     ) -> Result<()> {
         let _wp = wd::watch_millis("KeystoreDB::ungrant", 500);
 
         self.with_transaction(TransactionBehavior::Immediate, |tx| {
             // Load the key_id and complete the access control tuple.
             // We ignore the access vector here because grants cannot be granted.
-            let (key_id, access_key_descriptor, _) =
+            let (key_id, access_key_descriptor, _, _) =
                 Self::load_access_tuple(tx, key, KeyType::Client, caller_uid)
                     .context("In ungrant.")?;
 
@@ -3211,8 +4804,8 @@ impl KeystoreDB {
 
             tx.execute(
                 "DELETE FROM persistent.grant
-                WHERE keyentryid = ? AND grantee = ?;",
-                params![key_id, grantee_uid],
+                WHERE keyentryid = ? AND grantee_domain = ? AND grantee = ?;",
+                params![key_id, grantee.domain().0, grantee.id()],
             )
             .context("Failed to delete grant.")?;
 
@@ -3220,42 +4813,162 @@ impl KeystoreDB {
         })
     }
 
-    // Generates a random id and passes it to the given function, which will
-    // try to insert it into a database.  If that insertion fails, retry;
-    // otherwise return the id.
-    fn insert_with_retry(inserter: impl Fn(i64) -> rusqlite::Result<usize>) -> Result<i64> {
-        loop {
-            let newid: i64 = match random() {
-                Self::UNASSIGNED_KEY_ID => continue, // UNASSIGNED_KEY_ID cannot be assigned.
-                i => i,
-            };
-            match inserter(newid) {
-                // If the id already existed, try again.
-                Err(rusqlite::Error::SqliteFailure(
-                    libsqlite3_sys::Error {
-                        code: libsqlite3_sys::ErrorCode::ConstraintViolation,
-                        extended_code: libsqlite3_sys::SQLITE_CONSTRAINT_UNIQUE,
-                    },
-                    _,
-                )) => (),
-                Err(e) => {
-                    return Err(e).context("In insert_with_retry: failed to insert into database.")
-                }
-                _ => return Ok(newid),
-            }
-        }
-    }
+    /// Lists the grants of the given key as tuples of grantee, access vector, and expiry.
+    /// Expired grants that were not purged yet are not listed.
+    /// Like `ungrant` this function loads the access tuple before it uses the callback
+    /// for a permission check.
+    pub fn list_grants(
+        &mut self,
+        key: &KeyDescriptor,
+        caller_uid: u32,
+        check_permission: impl Fn(&KeyDescriptor) -> Result<()>,
+    ) -> Result<Vec<(Grantee, KeyPermSet, Option<DateTime>)>> {
+        let _wp = wd::watch_millis("KeystoreDB::list_grants", 500);
 
-    /// Insert or replace the auth token based on (user_id, auth_id, auth_type)
+        self.with_transaction(TransactionBehavior::Deferred, |tx| {
+            let (key_id, access_key_descriptor, _, _) =
+                Self::load_access_tuple(tx, key, KeyType::Client, caller_uid)
+                    .context("In list_grants.")?;
+
+            // Perform access control. We must return here if the permission
+            // was denied. So do not touch the '?' at the end of this line.
+            check_permission(&access_key_descriptor)
+                .context("In list_grants: check_permission failed.")?;
+
+            let now = DateTime::now().context("In list_grants: Failed to get current time.")?;
+            let mut stmt = tx
+                .prepare(
+                    "SELECT grantee_domain, grantee, access_vector, expires_at
+                     FROM persistent.grant
+                     WHERE keyentryid = ? AND (expires_at IS NULL OR expires_at > ?)
+                     ORDER BY grantee_domain, grantee;",
+                )
+                .context("In list_grants: Failed to prepare statement.")?;
+            let mut rows =
+                stmt.query(params![key_id, now]).context("In list_grants: Query failed.")?;
+            let mut grants = Vec::new();
+            db_utils::with_rows_extract_all(&mut rows, |row| {
+                let grantee_domain: i32 = row.get(0).context("Failed to read grantee domain.")?;
+                let grantee: i64 = row.get(1).context("Failed to read grantee.")?;
+                let access_vector: i32 = row.get(2).context("Failed to read access vector.")?;
+                let expires_at: Option<DateTime> = row.get(3).context("Failed to read expiry.")?;
+                grants.push((
+                    Grantee::from_row(Domain(grantee_domain), grantee)?,
+                    access_vector.into(),
+                    expires_at,
+                ));
+                Ok(())
+            })
+            .context("In list_grants: Failed to extract rows.")?;
+            Ok(grants).no_gc()
+        })
+    }
+
+    /// Deletes all grants that expired before `now`. Returns the number of deleted grants.
+    pub fn delete_expired_grants(&mut self, now: DateTime) -> Result<usize> {
+        let _wp = wd::watch_millis("KeystoreDB::delete_expired_grants", 500);
+
+        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+            tx.execute(
+                "DELETE FROM persistent.grant WHERE expires_at IS NOT NULL AND expires_at <= ?;",
+                params![now],
+            )
+            .context("Trying to delete expired grants.")
+            .no_gc()
+        })
+        .context("In delete_expired_grants.")
+    }
+
+    /// Revokes all grants held by the given uid and all grants of the keys owned by the given
+    /// uid in Domain::APP. Returns the number of revoked grants.
+    pub fn revoke_all_grants_for_uid(&mut self, uid: u32) -> Result<usize> {
+        let _wp = wd::watch_millis("KeystoreDB::revoke_all_grants_for_uid", 500);
+
+        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+            let held = tx
+                .execute(
+                    "DELETE FROM persistent.grant WHERE grantee_domain = ? AND grantee = ?;",
+                    params![Domain::APP.0, uid],
+                )
+                .context("Trying to delete grants held by uid.")?;
+            let issued = tx
+                .execute(
+                    "DELETE FROM persistent.grant
+                    WHERE keyentryid IN (
+                        SELECT id FROM persistent.keyentry
+                        WHERE domain = ? AND namespace = ?
+                    );",
+                    params![Domain::APP.0, uid],
+                )
+                .context("Trying to delete grants issued by uid.")?;
+            Ok(held + issued).no_gc()
+        })
+        .context("In revoke_all_grants_for_uid.")
+    }
+
+    /// Revokes all grants held by the given SELinux namespace. Returns the number of revoked
+    /// grants.
+    pub fn revoke_all_grants_for_namespace(&mut self, namespace: i64) -> Result<usize> {
+        let _wp = wd::watch_millis("KeystoreDB::revoke_all_grants_for_namespace", 500);
+
+        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+            tx.execute(
+                "DELETE FROM persistent.grant WHERE grantee_domain = ? AND grantee = ?;",
+                params![Domain::SELINUX.0, namespace],
+            )
+            .context("Trying to delete grants held by namespace.")
+            .no_gc()
+        })
+        .context("In revoke_all_grants_for_namespace.")
+    }
+
+    // Generates a random id and passes it to the given function, which will
+    // try to insert it into a database.  If that insertion fails, retry;
+    // otherwise return the id.
+    fn insert_with_retry(inserter: impl Fn(i64) -> rusqlite::Result<usize>) -> Result<i64> {
+        loop {
+            let newid: i64 = match random() {
+                Self::UNASSIGNED_KEY_ID => continue, // UNASSIGNED_KEY_ID cannot be assigned.
+                i => i,
+            };
+            match inserter(newid) {
+                // If the id already existed, try again.
+                Err(rusqlite::Error::SqliteFailure(
+                    libsqlite3_sys::Error {
+                        code: libsqlite3_sys::ErrorCode::ConstraintViolation,
+                        extended_code: libsqlite3_sys::SQLITE_CONSTRAINT_UNIQUE,
+                    },
+                    _,
+                )) => (),
+                Err(e) => {
+                    return Err(e).context("In insert_with_retry: failed to insert into database.")
+                }
+                _ => return Ok(newid),
+            }
+        }
+    }
+
+    /// Insert or replace the auth token based on (user_id, auth_id, auth_type)
     pub fn insert_auth_token(&mut self, auth_token: &HardwareAuthToken) {
         self.perboot.insert_auth_token_entry(AuthTokenEntry::new(
             auth_token.clone(),
-            MonotonicRawTime::now(),
+            BootTime::now(),
+            false,
+        ))
+    }
+
+    /// Like `insert_auth_token`, but records that the auth token was consumed by the operation
+    /// that requested its challenge.
+    pub fn insert_operation_auth_token(&mut self, auth_token: &HardwareAuthToken) {
+        self.perboot.insert_auth_token_entry(AuthTokenEntry::new(
+            auth_token.clone(),
+            BootTime::now(),
+            true,
         ))
     }
 
     /// Find the newest auth token matching the given predicate.
-    pub fn find_auth_token_entry<F>(&self, p: F) -> Option<(AuthTokenEntry, MonotonicRawTime)>
+    pub fn find_auth_token_entry<F>(&self, p: F) -> Option<(AuthTokenEntry, BootTime)>
     where
         F: Fn(&AuthTokenEntry) -> bool,
     {
@@ -3263,20 +4976,29 @@ impl KeystoreDB {
     }
 
     /// Insert last_off_body into the metadata table at the initialization of auth token table
-    pub fn insert_last_off_body(&self, last_off_body: MonotonicRawTime) {
+    pub fn insert_last_off_body(&self, last_off_body: BootTime) {
         self.perboot.set_last_off_body(last_off_body)
     }
 
     /// Update last_off_body when on_device_off_body is called
-    pub fn update_last_off_body(&self, last_off_body: MonotonicRawTime) {
+    pub fn update_last_off_body(&self, last_off_body: BootTime) {
         self.perboot.set_last_off_body(last_off_body)
     }
 
     /// Get last_off_body time when finding auth tokens
-    fn get_last_off_body(&self) -> MonotonicRawTime {
+    fn get_last_off_body(&self) -> BootTime {
         self.perboot.get_last_off_body()
     }
 
+    /// Rebuilds the persistent database file to reclaim the space of deleted entries. This
+    /// rewrites the whole file, so it should only be run while the device is idle.
+    pub fn vacuum(&mut self) -> Result<()> {
+        let _wp = wd::watch_millis("KeystoreDB::vacuum", 10000);
+
+        self.conn.execute("VACUUM persistent;", NO_PARAMS).context("In vacuum.")?;
+        Ok(())
+    }
+
     /// Load descriptor of a key by key id
     pub fn load_key_descriptor(&mut self, key_id: i64) -> Result<Option<KeyDescriptor>> {
         let _wp = wd::watch_millis("KeystoreDB::load_key_descriptor", 500);
@@ -3312,7 +5034,10 @@ pub mod tests {
     };
     use crate::key_perm_set;
     use crate::permission::{KeyPerm, KeyPermSet};
-    use crate::super_key::{SuperKeyManager, USER_SUPER_KEY, SuperEncryptionAlgorithm, SuperKeyType};
+    use crate::super_key::{
+        SuperEncryptionAlgorithm, SuperKeyManager, SuperKeyType, USER_SCREEN_LOCK_BOUND_KEY,
+        USER_SCREEN_LOCK_BOUND_P521_KEY, USER_SUPER_KEY,
+    };
     use keystore2_test_utils::TempDir;
     use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
         HardwareAuthToken::HardwareAuthToken,
@@ -3474,6 +5199,21 @@ pub mod tests {
         db.insert_auth_token(&auth_token3);
         let auth_tokens_returned = get_auth_tokens(&db);
         assert_eq!(auth_tokens_returned.len(), 2);
+        assert!(auth_tokens_returned.iter().all(|entry| !entry.is_bound_to_operation()));
+
+        // A token consumed by an operation is kept next to the unbound token of the same
+        // authenticator and stays marked as such.
+        db.insert_operation_auth_token(&auth_token3);
+        let auth_tokens_returned = get_auth_tokens(&db);
+        assert_eq!(auth_tokens_returned.len(), 3);
+        assert_eq!(
+            auth_tokens_returned.iter().filter(|entry| entry.is_bound_to_operation()).count(),
+            1
+        );
+
+        // The next bound token replaces the previous bound token only.
+        db.insert_operation_auth_token(&auth_token3);
+        assert_eq!(get_auth_tokens(&db).len(), 3);
 
         Ok(())
     }
@@ -4053,6 +5793,88 @@ pub mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_open_key_blob_without_envelope() -> Result<()> {
+        let mut blob_metadata = BlobMetaData::new();
+        blob_metadata.add(BlobMetaEntry::KmUuid(KEYSTORE_UUID));
+        let (blob, metadata) = KeystoreDB::open_key_blob(TEST_KEY_BLOB.to_vec(), blob_metadata)?;
+        assert_eq!(blob, TEST_KEY_BLOB.to_vec());
+        assert_eq!(metadata.km_uuid(), Some(&KEYSTORE_UUID));
+
+        // An envelope without its tag cannot be opened.
+        let mut incomplete = metadata;
+        incomplete.add(BlobMetaEntry::EnvelopeIv(vec![0; 12]));
+        assert!(KeystoreDB::open_key_blob(TEST_KEY_BLOB.to_vec(), incomplete).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_unbind_keys_of_previous_boots() -> Result<()> {
+        let mut db = new_test_db()?;
+        let old_key = make_test_key_entry(&mut db, Domain::APP, 1, "old", None)?;
+        let current_key = make_test_key_entry(&mut db, Domain::APP, 1, "current", None)?;
+        let persistent_key = make_test_key_entry(&mut db, Domain::APP, 1, "persistent", None)?;
+        let mut metadata = KeyMetaData::new();
+        metadata.add(KeyMetaEntry::BootId("old boot".to_string()));
+        db.insert_key_metadata(&old_key, &metadata)?;
+        metadata.add(KeyMetaEntry::BootId("current boot".to_string()));
+        db.insert_key_metadata(&current_key, &metadata)?;
+
+        assert_eq!(db.unbind_keys_of_previous_boots("current boot")?, 1);
+        let key_exists = |db: &mut KeystoreDB, key_id: &KeyIdGuard| -> Result<bool> {
+            Ok(db.conn.query_row(
+                "SELECT COUNT(id) FROM persistent.keyentry WHERE id = ?;",
+                params![key_id.id()],
+                |row| row.get::<_, i64>(0),
+            )? == 1)
+        };
+        assert!(!key_exists(&mut db, &old_key)?);
+        assert!(key_exists(&mut db, &current_key)?);
+        assert!(key_exists(&mut db, &persistent_key)?);
+
+        // A restart within the same boot keeps the remaining per-boot keys.
+        assert_eq!(db.unbind_keys_of_previous_boots("current boot")?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_unbind_keys() -> Result<()> {
+        let mut db = new_test_db()?;
+        make_test_key_entry(&mut db, Domain::APP, 1, "first", None)?;
+        make_test_key_entry(&mut db, Domain::APP, 1, "second", None)?;
+        make_test_key_entry(&mut db, Domain::APP, 2, "foreign", None)?;
+        let descriptor = |alias: &str| KeyDescriptor {
+            domain: Domain::APP,
+            nspace: 0,
+            alias: Some(alias.to_string()),
+            blob: None,
+        };
+
+        let results = db.unbind_keys(
+            &[descriptor("first"), descriptor("missing"), descriptor("second")],
+            KeyType::Client,
+            1,
+            |_, _| Ok(()),
+        )?;
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].as_ref().unwrap().alias.as_deref(), Some("first"));
+        assert_eq!(
+            Some(&KsError::Rc(ResponseCode::KEY_NOT_FOUND)),
+            results[1].as_ref().unwrap_err().root_cause().downcast_ref::<KsError>()
+        );
+        assert_eq!(results[2].as_ref().unwrap().alias.as_deref(), Some("second"));
+        assert!(!db.key_exists(Domain::APP, 1, "first", KeyType::Client)?);
+        assert!(!db.key_exists(Domain::APP, 1, "second", KeyType::Client)?);
+
+        // A denied permission check only fails the affected key.
+        let results = db.unbind_keys(&[descriptor("foreign")], KeyType::Client, 2, |_, _| {
+            Err(KsError::perm()).context("Denied.")
+        })?;
+        assert!(results[0].is_err());
+        assert!(db.key_exists(Domain::APP, 2, "foreign", KeyType::Client)?);
+        Ok(())
+    }
+
     static TEST_ALIAS: &str = "my super duper key";
 
     #[test]
@@ -4112,6 +5934,152 @@ pub mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_list_with_dates() -> Result<()> {
+        let mut db = new_test_db()?;
+        let key_id = make_app_test_key_entry(&mut db)?.id();
+        db.store_new_certificate(
+            &KeyDescriptor {
+                domain: Domain::APP,
+                nspace: 1,
+                alias: Some("cert_only".to_string()),
+                blob: None,
+            },
+            KeyType::Client,
+            TEST_CERT_CHAIN_BLOB,
+            &KEYSTORE_UUID,
+        )?;
+        make_test_key_entry(&mut db, Domain::APP, 2, "other_uid", None)?;
+        db.update_key_usage(&[(key_id, DateTime::from_millis_epoch(2000), 1)])?;
+
+        let mut entries = db.list_with_dates(Domain::APP, 1, KeyType::Client)?;
+        entries.sort_by(|a, b| a.alias.cmp(&b.alias));
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].alias, "cert_only");
+        assert!(entries[0].creation_date.is_some());
+        assert_eq!(entries[0].last_used_date, None);
+        assert_eq!(entries[0].origin, None);
+        assert_eq!(
+            entries[1],
+            ListedKeyEntry {
+                id: key_id,
+                alias: TEST_ALIAS.to_string(),
+                creation_date: Some(DateTime::from_millis_epoch(123456789)),
+                last_used_date: Some(DateTime::from_millis_epoch(2000)),
+                origin: Some(KeyOrigin::GENERATED.0),
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_export_and_import_certificates() -> Result<()> {
+        let mut db = new_test_db()?;
+        make_app_test_key_entry(&mut db)?;
+        db.store_new_certificate(
+            &KeyDescriptor {
+                domain: Domain::APP,
+                nspace: 1,
+                alias: Some("cert_only".to_string()),
+                blob: None,
+            },
+            KeyType::Client,
+            TEST_CERT_CHAIN_BLOB,
+            &KEYSTORE_UUID,
+        )?;
+        make_test_key_entry(&mut db, Domain::APP, 2, "other_uid", None)?;
+
+        let mut backups = db.export_certificates(Domain::APP, 1)?;
+        backups.sort_by(|a, b| a.alias.cmp(&b.alias));
+        assert_eq!(backups.len(), 2);
+        assert_eq!(backups[0].alias, "cert_only");
+        assert_eq!(backups[0].cert, None);
+        assert_eq!(backups[0].cert_chain, Some(TEST_CERT_CHAIN_BLOB.to_vec()));
+        assert_eq!(backups[1].alias, TEST_ALIAS);
+        assert_eq!(backups[1].cert, Some(TEST_CERT_BLOB.to_vec()));
+        assert_eq!(backups[1].cert_chain, Some(TEST_CERT_CHAIN_BLOB.to_vec()));
+        assert_eq!(backups[1].creation_date, Some(DateTime::from_millis_epoch(123456789)));
+
+        // In namespace 3 only the alias of the certificate only entry is free.
+        make_test_key_entry(&mut db, Domain::APP, 3, TEST_ALIAS, None)?;
+        assert_eq!(db.import_certificates(Domain::APP, 3, &backups)?, vec![true, false]);
+
+        let (_, mut key_entry) = db.load_key_entry(
+            &KeyDescriptor {
+                domain: Domain::APP,
+                nspace: 3,
+                alias: Some("cert_only".to_string()),
+                blob: None,
+            },
+            KeyType::Client,
+            KeyEntryLoadBits::BOTH,
+            3,
+            |_k, _av| Ok(()),
+        )?;
+        assert!(key_entry.pure_cert());
+        assert_eq!(key_entry.take_cert_chain(), Some(TEST_CERT_CHAIN_BLOB.to_vec()));
+
+        // The existing key entry was not replaced.
+        let (_, key_entry) = db.load_key_entry(
+            &KeyDescriptor {
+                domain: Domain::APP,
+                nspace: 3,
+                alias: Some(TEST_ALIAS.to_string()),
+                blob: None,
+            },
+            KeyType::Client,
+            KeyEntryLoadBits::BOTH,
+            3,
+            |_k, _av| Ok(()),
+        )?;
+        assert!(!key_entry.pure_cert());
+        Ok(())
+    }
+
+    #[test]
+    fn test_store_new_key_no_clobber() -> Result<()> {
+        let mut db = new_test_db()?;
+        let old_id = make_app_test_key_entry(&mut db)?.id();
+        let key = KeyDescriptor {
+            domain: Domain::APP,
+            nspace: 1,
+            alias: Some(TEST_ALIAS.to_string()),
+            blob: None,
+        };
+        let blob_metadata = BlobMetaData::new();
+        let store = |db: &mut KeystoreDB, no_clobber| {
+            db.store_new_key(
+                &key,
+                KeyType::Client,
+                &[],
+                &BlobInfo::new(TEST_KEY_BLOB, &blob_metadata),
+                &CertificateInfo::new(None, None),
+                &KeyMetaData::new(),
+                &KEYSTORE_UUID,
+                no_clobber,
+            )
+        };
+        let load_id = |db: &mut KeystoreDB| -> Result<i64> {
+            Ok(load_app_test_key_entry(db, KeyEntryLoadBits::NONE)?.0.id())
+        };
+
+        // The existing key is kept, and the new key entry is left for the garbage collector.
+        assert_eq!(
+            Some(&KsError::Rc(ResponseCode::INVALID_ARGUMENT)),
+            store(&mut db, true).unwrap_err().root_cause().downcast_ref::<KsError>()
+        );
+        assert_eq!(load_id(&mut db)?, old_id);
+        assert_eq!(get_keyentry(&db)?.len(), 1);
+
+        // Without the flag the alias is rebound, and the old entry is unreferenced.
+        let new_id = store(&mut db, false)?.id();
+        assert_eq!(load_id(&mut db)?, new_id);
+        let old_entry = get_keyentry(&db)?.into_iter().find(|row| row.id == old_id).unwrap();
+        assert_eq!(old_entry.state, KeyLifeCycle::Unreferenced);
+        assert_eq!(old_entry.alias, None);
+        Ok(())
+    }
+
     #[test]
     fn test_insert_and_load_certificate_entry_domain_app() -> Result<()> {
         let mut db = new_test_db()?;
@@ -4536,6 +6504,59 @@ pub mod tests {
         Ok(())
     }
 
+    // Grants issued by the previous owner must not give access to the migrated key.
+    #[test]
+    fn test_migrate_key_revokes_grants() -> Result<()> {
+        let mut db = new_test_db()?;
+        const SOURCE_UID: u32 = 1u32;
+        const DESTINATION_UID: u32 = 2u32;
+        const GRANTEE_UID: u32 = 3u32;
+        static SOURCE_ALIAS: &str = "SOURCE_ALIAS";
+        static DESTINATION_ALIAS: &str = "DESTINATION_ALIAS";
+        let key_id_guard =
+            make_test_key_entry(&mut db, Domain::APP, SOURCE_UID as i64, SOURCE_ALIAS, None)?;
+        let source_descriptor = KeyDescriptor {
+            domain: Domain::APP,
+            nspace: -1,
+            alias: Some(SOURCE_ALIAS.to_string()),
+            blob: None,
+        };
+        let destination_descriptor = KeyDescriptor {
+            domain: Domain::APP,
+            nspace: -1,
+            alias: Some(DESTINATION_ALIAS.to_string()),
+            blob: None,
+        };
+        let grant = db.grant(
+            &source_descriptor,
+            SOURCE_UID,
+            GRANTEE_UID,
+            key_perm_set![KeyPerm::Use],
+            |_, _| Ok(()),
+        )?;
+
+        db.migrate_key_namespace(key_id_guard, &destination_descriptor, DESTINATION_UID, |_k| {
+            Ok(())
+        })?;
+
+        assert!(db.list_grants(&destination_descriptor, DESTINATION_UID, |_| Ok(()))?.is_empty());
+        assert_eq!(
+            Some(&KsError::Rc(ResponseCode::KEY_NOT_FOUND)),
+            db.load_key_entry(
+                &grant,
+                KeyType::Client,
+                KeyEntryLoadBits::NONE,
+                GRANTEE_UID,
+                |_k, _av| Ok(()),
+            )
+            .unwrap_err()
+            .root_cause()
+            .downcast_ref::<KsError>()
+        );
+
+        Ok(())
+    }
+
     // Creates a key migrates it to a different location and then tries to access it by the old
     // and new location.
     #[test]
@@ -5066,6 +7087,7 @@ pub mod tests {
             let mut list_result = db.list(*domain, *namespace, KeyType::Client)?;
             list_result.sort();
             assert_eq!(list_o_descriptors, list_result);
+            assert_eq!(list_result.len(), db.count_keys(*domain, *namespace, KeyType::Client)?);
 
             let mut list_o_ids: Vec<i64> = list_o_descriptors
                 .into_iter()
@@ -5094,6 +7116,7 @@ pub mod tests {
             assert_eq!(list_o_ids, loaded_entries);
         }
         assert_eq!(Vec::<KeyDescriptor>::new(), db.list(Domain::SELINUX, 101, KeyType::Client)?);
+        assert_eq!(0, db.count_keys(Domain::SELINUX, 101, KeyType::Client)?);
 
         Ok(())
     }
@@ -5435,6 +7458,30 @@ pub mod tests {
         Ok(key_id)
     }
 
+    /// Creates the test key `TEST_ALIAS` in the namespace of app 1.
+    fn make_app_test_key_entry(db: &mut KeystoreDB) -> Result<KeyIdGuard> {
+        make_test_key_entry(db, Domain::APP, 1, TEST_ALIAS, None)
+    }
+
+    /// Loads the test key created by `make_app_test_key_entry`.
+    fn load_app_test_key_entry(
+        db: &mut KeystoreDB,
+        load_bits: KeyEntryLoadBits,
+    ) -> Result<(KeyIdGuard, KeyEntry)> {
+        db.load_key_entry(
+            &KeyDescriptor {
+                domain: Domain::APP,
+                nspace: 1,
+                alias: Some(TEST_ALIAS.to_string()),
+                blob: None,
+            },
+            KeyType::Client,
+            load_bits,
+            1,
+            |_k, _av| Ok(()),
+        )
+    }
+
     fn make_test_key_entry_test_vector(key_id: i64, max_usage_count: Option<i32>) -> KeyEntry {
         let params = make_test_params(max_usage_count);
 
@@ -5590,13 +7637,13 @@ pub mod tests {
     #[test]
     fn test_last_off_body() -> Result<()> {
         let mut db = new_test_db()?;
-        db.insert_last_off_body(MonotonicRawTime::now());
+        db.insert_last_off_body(BootTime::now());
         let tx = db.conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
         tx.commit()?;
         let last_off_body_1 = db.get_last_off_body();
         let one_second = Duration::from_secs(1);
         thread::sleep(one_second);
-        db.update_last_off_body(MonotonicRawTime::now());
+        db.update_last_off_body(BootTime::now());
         let tx2 = db.conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
         tx2.commit()?;
         let last_off_body_2 = db.get_last_off_body();
@@ -5623,17 +7670,316 @@ pub mod tests {
     }
 
     #[test]
-    fn test_unbind_keys_for_user_removes_superkeys() -> Result<()> {
+    fn test_unbind_keys_for_user_removes_grants_held_by_user() -> Result<()> {
         let mut db = new_test_db()?;
-        let super_key = keystore2_crypto::generate_aes256_key()?;
-        let pw: keystore2_crypto::Password = (&b"xyzabc"[..]).into();
-        let (encrypted_super_key, metadata) =
-            SuperKeyManager::encrypt_with_password(&super_key, &pw)?;
-
-        let key_name_enc = SuperKeyType {
-            alias: "test_super_key_1",
-            algorithm: SuperEncryptionAlgorithm::Aes256Gcm,
-        };
+        make_test_key_entry(&mut db, Domain::APP, 110000, TEST_ALIAS, None)?;
+        let key = KeyDescriptor {
+            domain: Domain::APP,
+            nspace: 0,
+            alias: Some(TEST_ALIAS.to_string()),
+            blob: None,
+        };
+        // Grant the key of user 1 to an app of user 2 and to another app of user 1.
+        db.grant(&key, 110000, 210000, key_perm_set![KeyPerm::Use], |_, _| Ok(()))?;
+        db.grant(&key, 110000, 110001, key_perm_set![KeyPerm::Use], |_, _| Ok(()))?;
+
+        let grant_count = |db: &KeystoreDB| -> Result<i64> {
+            Ok(db.conn.query_row("SELECT COUNT(*) FROM persistent.grant;", NO_PARAMS, |row| {
+                row.get(0)
+            })?)
+        };
+        assert_eq!(2, grant_count(&db)?);
+
+        // Keeping non super encrypted keys does not touch grants.
+        db.unbind_keys_for_user(2, true)?;
+        assert_eq!(2, grant_count(&db)?);
+
+        // Removing user 2 removes the grant held by user 2 only.
+        db.unbind_keys_for_user(2, false)?;
+        assert_eq!(1, grant_count(&db)?);
+        assert_eq!(1, db.list(Domain::APP, 110000, KeyType::Client)?.len());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_unbind_keys_for_user_removes_auth_tokens() -> Result<()> {
+        let mut db = new_test_db()?;
+        // The test key is bound to the secure user id 42.
+        make_test_key_entry(&mut db, Domain::APP, 210000, TEST_ALIAS, None)?;
+        let auth_token = |sid: i64| HardwareAuthToken {
+            challenge: 0,
+            userId: sid,
+            authenticatorId: 0,
+            authenticatorType: kmhw_authenticator_type::PASSWORD,
+            timestamp: Timestamp { milliSeconds: 500 },
+            mac: String::from("mac").into_bytes(),
+        };
+        db.insert_auth_token(&auth_token(42));
+        db.insert_auth_token(&auth_token(43));
+
+        // Removing user 2 removes the auth tokens of the secure user ids of its keys only.
+        db.unbind_keys_for_user(2, false)?;
+        let auth_tokens = get_auth_tokens(&db);
+        assert_eq!(1, auth_tokens.len());
+        assert_eq!(43, auth_tokens[0].auth_token.userId);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_list_grants_and_revoke_all_grants_for_uid() -> Result<()> {
+        let mut db = new_test_db()?;
+        make_test_key_entry(&mut db, Domain::APP, 10001, TEST_ALIAS, None)?;
+        make_test_key_entry(&mut db, Domain::APP, 10002, TEST_ALIAS, None)?;
+        let key = KeyDescriptor {
+            domain: Domain::APP,
+            nspace: 0,
+            alias: Some(TEST_ALIAS.to_string()),
+            blob: None,
+        };
+        const PVEC1: KeyPermSet = key_perm_set![KeyPerm::Use, KeyPerm::GetInfo];
+        const PVEC2: KeyPermSet = key_perm_set![KeyPerm::Use];
+
+        db.grant(&key, 10001, 10003, PVEC1, |_, _| Ok(()))?;
+        db.grant(&key, 10001, 10002, PVEC2, |_, _| Ok(()))?;
+        db.grant(&key, 10002, 10001, PVEC2, |_, _| Ok(()))?;
+        db.grant(&key, 10002, 10003, PVEC2, |_, _| Ok(()))?;
+
+        assert_eq!(
+            db.list_grants(&key, 10001, |k| {
+                assert_eq!(k.nspace, 10001);
+                Ok(())
+            })?,
+            vec![(Grantee::Uid(10002), PVEC2, None), (Grantee::Uid(10003), PVEC1, None)]
+        );
+        assert!(db
+            .list_grants(&key, 10001, |_| Err(KsError::perm()).context("Denied."))
+            .is_err());
+
+        // Revokes the grants issued by 10001 and the grant held by 10001.
+        assert_eq!(db.revoke_all_grants_for_uid(10001)?, 3);
+        assert!(db.list_grants(&key, 10001, |_| Ok(()))?.is_empty());
+        assert_eq!(
+            db.list_grants(&key, 10002, |_| Ok(()))?,
+            vec![(Grantee::Uid(10003), PVEC2, None)]
+        );
+
+        // The keys themselves are not affected.
+        assert!(db.key_exists(Domain::APP, 10001, TEST_ALIAS, KeyType::Client)?);
+        assert!(db.key_exists(Domain::APP, 10002, TEST_ALIAS, KeyType::Client)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_grant_expiry() -> Result<()> {
+        let mut db = new_test_db()?;
+        make_test_key_entry(&mut db, Domain::APP, 10001, TEST_ALIAS, None)?;
+        let key = KeyDescriptor {
+            domain: Domain::APP,
+            nspace: 0,
+            alias: Some(TEST_ALIAS.to_string()),
+            blob: None,
+        };
+        const PVEC: KeyPermSet = key_perm_set![KeyPerm::Use];
+        let now = DateTime::now()?.to_millis_epoch();
+        let expired = Some(DateTime::from_millis_epoch(now - 1000));
+        let valid = Some(DateTime::from_millis_epoch(now + 3_600_000));
+
+        let expired_grant =
+            db.grant_with_expiry(&key, 10001, Grantee::Uid(10002), PVEC, expired, |_, _| Ok(()))?;
+        let valid_grant =
+            db.grant_with_expiry(&key, 10001, Grantee::Uid(10003), PVEC, valid, |_, _| Ok(()))?;
+
+        let load = |db: &mut KeystoreDB, grant: &KeyDescriptor, grantee: u32| {
+            db.load_key_entry(grant, KeyType::Client, KeyEntryLoadBits::NONE, grantee, |_, _| {
+                Ok(())
+            })
+        };
+        assert_eq!(
+            Some(&KsError::Rc(ResponseCode::KEY_NOT_FOUND)),
+            load(&mut db, &expired_grant, 10002)
+                .unwrap_err()
+                .root_cause()
+                .downcast_ref::<KsError>()
+        );
+        load(&mut db, &valid_grant, 10003)?;
+        assert_eq!(
+            db.list_grants(&key, 10001, |_| Ok(()))?,
+            vec![(Grantee::Uid(10003), PVEC, valid)]
+        );
+
+        assert_eq!(db.delete_expired_grants(DateTime::now()?)?, 1);
+        assert_eq!(db.delete_expired_grants(DateTime::now()?)?, 0);
+
+        // Granting again without expiry makes the grant permanent.
+        db.grant(&key, 10001, 10003, PVEC, |_, _| Ok(()))?;
+        assert_eq!(db.delete_expired_grants(DateTime::from_millis_epoch(now + 7_200_000))?, 0);
+        load(&mut db, &valid_grant, 10003)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_upgrade_1_to_2() -> Result<()> {
+        let mut db = new_test_db()?;
+        db.conn.execute("DROP TABLE persistent.grant;", NO_PARAMS)?;
+        db.conn.execute(
+            "CREATE TABLE persistent.grant (
+                    id INTEGER UNIQUE,
+                    grantee INTEGER,
+                    keyentryid INTEGER,
+                    access_vector INTEGER);",
+            NO_PARAMS,
+        )?;
+        make_test_key_entry(&mut db, Domain::APP, 10001, TEST_ALIAS, None)?;
+        db.conn.execute(
+            "INSERT INTO persistent.grant (id, grantee, keyentryid, access_vector)
+                VALUES (1, 10002, (SELECT id FROM persistent.keyentry), ?);",
+            params![i32::from(key_perm_set![KeyPerm::Use])],
+        )?;
+
+        db.with_transaction(TransactionBehavior::Immediate, |tx| {
+            KeystoreDB::from_1_to_2(tx).no_gc()
+        })?;
+        db.with_transaction(TransactionBehavior::Immediate, |tx| {
+            KeystoreDB::from_2_to_3(tx).no_gc()
+        })?;
+
+        // Grants that predate the upgrades do not expire and are held by uids.
+        let key = KeyDescriptor {
+            domain: Domain::APP,
+            nspace: 0,
+            alias: Some(TEST_ALIAS.to_string()),
+            blob: None,
+        };
+        assert_eq!(
+            db.list_grants(&key, 10001, |_| Ok(()))?,
+            vec![(Grantee::Uid(10002), key_perm_set![KeyPerm::Use], None)]
+        );
+        assert_eq!(db.delete_expired_grants(DateTime::from_millis_epoch(i64::MAX))?, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_namespace_grant() -> Result<()> {
+        const GRANTEE_NAMESPACE: i64 = 102;
+        const GRANTEE_UID: u32 = 1010;
+        let mut db = new_test_db()?;
+        let key_id = make_test_key_entry(&mut db, Domain::APP, 10001, TEST_ALIAS, None)?.id();
+        let key = KeyDescriptor {
+            domain: Domain::APP,
+            nspace: 0,
+            alias: Some(TEST_ALIAS.to_string()),
+            blob: None,
+        };
+        const PVEC: KeyPermSet = key_perm_set![KeyPerm::Use, KeyPerm::GetInfo];
+
+        let grant = db.grant_with_expiry(
+            &key,
+            10001,
+            Grantee::Namespace(GRANTEE_NAMESPACE),
+            PVEC,
+            None,
+            |k, a| {
+                assert_eq!(k.domain, Domain::APP);
+                assert_eq!(k.nspace, 10001);
+                assert_eq!(*a, PVEC);
+                Ok(())
+            },
+        )?;
+        assert_eq!(
+            db.list_grants(&key, 10001, |_| Ok(()))?,
+            vec![(Grantee::Namespace(GRANTEE_NAMESPACE), PVEC, None)]
+        );
+
+        // Simulates check_key_permission for a caller that has the requested permission in the
+        // grantee namespace if `in_namespace` is true, but no access to the key otherwise.
+        let check = |perm: KeyPerm, in_namespace: bool| {
+            move |k: &KeyDescriptor, av: Option<KeyPermSet>| match (k.domain, av) {
+                (Domain::GRANT, Some(av)) if av.includes(perm) => Ok(()),
+                (Domain::SELINUX, None) if k.nspace == GRANTEE_NAMESPACE && in_namespace => Ok(()),
+                _ => Err(KsError::perm()).context("Denied."),
+            }
+        };
+        let key_id_descriptor =
+            KeyDescriptor { domain: Domain::KEY_ID, nspace: key_id, alias: None, blob: None };
+
+        for descriptor in [&grant, &key_id_descriptor].iter() {
+            // Access requires both the namespace membership and the granted permission.
+            db.load_key_entry(
+                descriptor,
+                KeyType::Client,
+                KeyEntryLoadBits::NONE,
+                GRANTEE_UID,
+                check(KeyPerm::Use, true),
+            )?;
+            assert!(db
+                .load_key_entry(
+                    descriptor,
+                    KeyType::Client,
+                    KeyEntryLoadBits::NONE,
+                    GRANTEE_UID,
+                    check(KeyPerm::Use, false),
+                )
+                .is_err());
+            assert!(db
+                .load_key_entry(
+                    descriptor,
+                    KeyType::Client,
+                    KeyEntryLoadBits::NONE,
+                    GRANTEE_UID,
+                    check(KeyPerm::Delete, true),
+                )
+                .is_err());
+        }
+
+        // A uid grant to the same value as the namespace is a different grant.
+        db.ungrant(&key, 10001, GRANTEE_NAMESPACE as u32, |_| Ok(()))?;
+        assert_eq!(db.list_grants(&key, 10001, |_| Ok(()))?.len(), 1);
+
+        db.ungrant_grantee(&key, 10001, Grantee::Namespace(GRANTEE_NAMESPACE), |_| Ok(()))?;
+        assert!(db.list_grants(&key, 10001, |_| Ok(()))?.is_empty());
+
+        // Clearing the namespace revokes the grants held by the namespace only.
+        db.grant_with_expiry(
+            &key,
+            10001,
+            Grantee::Namespace(GRANTEE_NAMESPACE),
+            PVEC,
+            None,
+            |_, _| Ok(()),
+        )?;
+        assert_eq!(db.revoke_all_grants_for_namespace(GRANTEE_NAMESPACE + 1)?, 0);
+        assert_eq!(db.revoke_all_grants_for_namespace(GRANTEE_NAMESPACE)?, 1);
+        assert!(db.list_grants(&key, 10001, |_| Ok(()))?.is_empty());
+        assert!(db
+            .load_key_entry(
+                &grant,
+                KeyType::Client,
+                KeyEntryLoadBits::NONE,
+                GRANTEE_UID,
+                check(KeyPerm::Use, true),
+            )
+            .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_unbind_keys_for_user_removes_superkeys() -> Result<()> {
+        let mut db = new_test_db()?;
+        let super_key = keystore2_crypto::generate_aes256_key()?;
+        let pw: keystore2_crypto::Password = (&b"xyzabc"[..]).into();
+        let (encrypted_super_key, metadata) =
+            SuperKeyManager::encrypt_with_password(&super_key, &pw)?;
+
+        let key_name_enc = SuperKeyType {
+            alias: "test_super_key_1",
+            algorithm: SuperEncryptionAlgorithm::Aes256Gcm,
+        };
 
         let key_name_nonenc = SuperKeyType {
             alias: "test_super_key_2",
@@ -5720,6 +8066,7 @@ pub mod tests {
 
         let (_, key_entry) = db.load_super_key(&USER_SUPER_KEY, 1)?.unwrap();
         let loaded_super_key = SuperKeyManager::extract_super_key_from_key_entry(
+            &mut db,
             USER_SUPER_KEY.algorithm,
             key_entry,
             &pw,
@@ -5732,6 +8079,488 @@ pub mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_replace_super_key_blobs() -> Result<()> {
+        let mut db = new_test_db()?;
+        let old_pw: keystore2_crypto::Password = (&b"xyzabc"[..]).into();
+        let new_pw: keystore2_crypto::Password = (&b"abcxyz"[..]).into();
+        let super_key = keystore2_crypto::generate_aes256_key()?;
+
+        let (encrypted_super_key, metadata) =
+            SuperKeyManager::encrypt_with_password(&super_key, &old_pw)?;
+        let key_entry = db.store_super_key(
+            1,
+            &USER_SUPER_KEY,
+            &encrypted_super_key,
+            &metadata,
+            &KeyMetaData::new(),
+        )?;
+
+        let (key_id_guard, _) = db.load_super_key(&USER_SUPER_KEY, 1)?.unwrap();
+        let (encrypted_super_key, metadata) =
+            SuperKeyManager::encrypt_with_password(&super_key, &new_pw)?;
+        db.replace_super_key_blobs(&[(key_id_guard, encrypted_super_key, metadata)])?;
+
+        // The super key keeps its id but can only be decrypted with the new password.
+        let (_, reloaded_entry) = db.load_super_key(&USER_SUPER_KEY, 1)?.unwrap();
+        assert_eq!(key_entry.id(), reloaded_entry.id());
+        assert!(SuperKeyManager::extract_super_key_from_key_entry(
+            &mut db,
+            USER_SUPER_KEY.algorithm,
+            reloaded_entry,
+            &old_pw,
+            None,
+        )
+        .is_err());
+        let (_, reloaded_entry) = db.load_super_key(&USER_SUPER_KEY, 1)?.unwrap();
+        let loaded_super_key = SuperKeyManager::extract_super_key_from_key_entry(
+            &mut db,
+            USER_SUPER_KEY.algorithm,
+            reloaded_entry,
+            &new_pw,
+            None,
+        )?;
+        let (encrypted_secret, iv, tag) = keystore2_crypto::aes_gcm_encrypt(b"secret", &super_key)?;
+        assert_eq!(b"secret", &*loaded_super_key.decrypt(&encrypted_secret, &iv, &tag)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_replay_blob_journal() -> Result<()> {
+        let mut db = new_test_db()?;
+        let key_id = make_app_test_key_entry(&mut db)?;
+        let load_key_blob = |db: &mut KeystoreDB| -> Result<Vec<u8>> {
+            let (_, mut key_entry) = load_app_test_key_entry(db, KeyEntryLoadBits::KM)?;
+            Ok(key_entry.take_key_blob_info().unwrap().0)
+        };
+        let mut blob_metadata = BlobMetaData::new();
+        blob_metadata.add(BlobMetaEntry::KmUuid(KEYSTORE_UUID));
+
+        // Keystore died after storing the upgraded key blob, but before the key parameters
+        // were updated. The superseded blob is kept until the replacement is replayed, which
+        // rolls it back.
+        db.begin_blob_replacement(&key_id, &[SubComponentType::KEY_BLOB])?;
+        db.set_blob(&key_id, SubComponentType::KEY_BLOB, Some(b"upgraded"), Some(&blob_metadata))?;
+        assert!(db.handle_next_superseded_blobs(&[], 20)?.is_empty());
+        let replayed = db.with_transaction(TransactionBehavior::Immediate, |tx| {
+            KeystoreDB::replay_blob_journal(tx).no_gc()
+        })?;
+        assert_eq!(1, replayed);
+        assert_eq!(TEST_KEY_BLOB, load_key_blob(&mut db)?.as_slice());
+        assert!(db.handle_next_superseded_blobs(&[], 20)?.is_empty());
+
+        // A replacement of which an old blob was deleted is rolled forward.
+        let cert_and_chain = [SubComponentType::CERT, SubComponentType::CERT_CHAIN];
+        db.begin_blob_replacement(&key_id, &cert_and_chain)?;
+        db.set_blob(&key_id, SubComponentType::CERT, None, None)?;
+        db.set_blob(&key_id, SubComponentType::CERT_CHAIN, Some(b"new chain"), None)?;
+        db.cleanup_leftovers()?;
+        let (_, key_entry) = load_app_test_key_entry(&mut db, KeyEntryLoadBits::PUBLIC)?;
+        assert_eq!(
+            (&None, &Some(b"new chain".to_vec())),
+            (key_entry.cert(), key_entry.cert_chain())
+        );
+
+        // A committed replacement leaves nothing to replay, and the superseded blob is handed
+        // to the garbage collector.
+        db.begin_blob_replacement(&key_id, &[SubComponentType::KEY_BLOB])?;
+        db.set_blob(&key_id, SubComponentType::KEY_BLOB, Some(b"upgraded"), Some(&blob_metadata))?;
+        db.commit_blob_replacement(&key_id)?;
+        db.with_transaction(TransactionBehavior::Immediate, |tx| {
+            assert_eq!(0, KeystoreDB::replay_blob_journal(tx)?);
+            Ok(()).no_gc()
+        })?;
+        assert_eq!(b"upgraded", load_key_blob(&mut db)?.as_slice());
+        let superseded = db.handle_next_superseded_blobs(&[], 20)?;
+        assert_eq!(1, superseded.len());
+        assert_eq!(TEST_KEY_BLOB, superseded[0].1.as_slice());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_update_keymint_version() -> Result<()> {
+        let mut db = new_test_db()?;
+        let other_uuid: Uuid = SecurityLevel::STRONGBOX.into();
+
+        // Nothing was recorded yet.
+        assert!(db.update_keymint_version(&KEYSTORE_UUID, 100, "2021-05-05")?);
+        assert!(!db.update_keymint_version(&KEYSTORE_UUID, 100, "2021-05-05")?);
+        assert!(db.update_keymint_version(&other_uuid, 100, "2021-05-05")?);
+        // KeyMint was updated.
+        assert!(db.update_keymint_version(&KEYSTORE_UUID, 200, "2021-05-05")?);
+        // OS patch level changed.
+        assert!(db.update_keymint_version(&KEYSTORE_UUID, 200, "2021-06-05")?);
+        assert!(!db.update_keymint_version(&KEYSTORE_UUID, 200, "2021-06-05")?);
+        assert!(!db.update_keymint_version(&other_uuid, 100, "2021-05-05")?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_key_ids_for_km_uuid() -> Result<()> {
+        let mut db = new_test_db()?;
+        let key_ids: Vec<i64> = (0..5)
+            .map(|i| {
+                make_test_key_entry(&mut db, Domain::APP, 1, &format!("key{}", i), None)
+                    .map(|guard| guard.id())
+            })
+            .collect::<Result<_>>()?;
+        let other_uuid: Uuid = SecurityLevel::STRONGBOX.into();
+
+        assert_eq!(key_ids[..2], db.get_key_ids_for_km_uuid(&KEYSTORE_UUID, -1, 2)?[..]);
+        assert_eq!(key_ids[2..4], db.get_key_ids_for_km_uuid(&KEYSTORE_UUID, key_ids[1], 2)?[..]);
+        assert_eq!(key_ids[4..], db.get_key_ids_for_km_uuid(&KEYSTORE_UUID, key_ids[3], 2)?[..]);
+        assert!(db.get_key_ids_for_km_uuid(&KEYSTORE_UUID, key_ids[4], 2)?.is_empty());
+        assert!(db.get_key_ids_for_km_uuid(&other_uuid, -1, 2)?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_app_metadata() -> Result<()> {
+        let mut db = new_test_db()?;
+        let key_id = make_app_test_key_entry(&mut db)?;
+        let load_app_metadata = |db: &mut KeystoreDB| -> Result<Option<Vec<u8>>> {
+            let (_, key_entry) = load_app_test_key_entry(db, KeyEntryLoadBits::NONE)?;
+            Ok(key_entry.metadata().app_metadata().cloned())
+        };
+
+        assert_eq!(None, load_app_metadata(&mut db)?);
+        db.set_app_metadata(&key_id, Some(b"first"))?;
+        assert_eq!(Some(b"first".to_vec()), load_app_metadata(&mut db)?);
+        db.set_app_metadata(&key_id, Some(b"second"))?;
+        assert_eq!(Some(b"second".to_vec()), load_app_metadata(&mut db)?);
+        db.set_app_metadata(&key_id, None)?;
+        assert_eq!(None, load_app_metadata(&mut db)?);
+
+        // The metadata goes away with the key.
+        db.set_app_metadata(&key_id, Some(b"third"))?;
+        let key_id = key_id.id();
+        db.unbind_key(
+            &KeyDescriptor {
+                domain: Domain::APP,
+                nspace: 1,
+                alias: Some(TEST_ALIAS.to_string()),
+                blob: None,
+            },
+            KeyType::Client,
+            1,
+            |_, _| Ok(()),
+        )?;
+        db.handle_next_superseded_blobs(&[], 20)?;
+        let remaining: i64 = db.conn.query_row(
+            "SELECT COUNT(*) FROM persistent.keymetadata WHERE keyentryid = ?;",
+            params![key_id],
+            |row| row.get(0),
+        )?;
+        assert_eq!(0, remaining);
+        Ok(())
+    }
+
+    #[test]
+    fn test_pending_secure_deletion() -> Result<()> {
+        let mut db = new_test_db()?;
+        // The test parameters include ROLLBACK_RESISTANCE.
+        let key_id = make_app_test_key_entry(&mut db)?;
+        let mut blob_metadata = BlobMetaData::new();
+        blob_metadata.add(BlobMetaEntry::KmUuid(KEYSTORE_UUID));
+        db.set_blob(&key_id, SubComponentType::KEY_BLOB, Some(b"upgraded"), Some(&blob_metadata))?;
+
+        // The new key blob of the rollback resistant key is marked as such.
+        let marked: i64 = db.conn.query_row(
+            "SELECT COUNT(*) FROM persistent.blobmetadata WHERE tag = ?;",
+            params![BlobMetaData::RollbackResistant],
+            |row| row.get(0),
+        )?;
+        assert_eq!(1, marked);
+
+        let superseded = db.handle_next_superseded_blobs(&[], 20)?;
+        assert_eq!(1, superseded.len());
+        let blob_id = superseded[0].0;
+        db.defer_secure_deletion(blob_id)?;
+        db.defer_secure_deletion(blob_id)?;
+
+        // Pending blobs are only handed out for another deletion attempt.
+        assert!(db.handle_next_superseded_blobs(&[], 20)?.is_empty());
+        let pending = db.get_pending_secure_deletions(20)?;
+        assert_eq!(1, pending.len());
+        assert_eq!(blob_id, pending[0].0);
+
+        // A blob that was finally deleted is no longer pending.
+        db.handle_next_superseded_blobs(&[blob_id], 20)?;
+        assert!(db.get_pending_secure_deletions(20)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_update_key_usage() -> Result<()> {
+        let mut db = new_test_db()?;
+        let key_id = make_app_test_key_entry(&mut db)?;
+        let load_metadata = |db: &mut KeystoreDB| -> Result<KeyMetaData> {
+            let (_, key_entry) = load_app_test_key_entry(db, KeyEntryLoadBits::NONE)?;
+            Ok(key_entry.into_key_parameters_and_metadata().1)
+        };
+
+        let metadata = load_metadata(&mut db)?;
+        assert_eq!(None, metadata.last_used_date());
+        assert_eq!(None, metadata.use_count());
+
+        db.update_key_usage(&[(key_id.id(), DateTime::from_millis_epoch(1000), 3)])?;
+        // Records of unknown keys are dropped.
+        db.update_key_usage(&[
+            (key_id.id() + 1, DateTime::from_millis_epoch(3000), 1),
+            (key_id.id(), DateTime::from_millis_epoch(2000), 2),
+        ])?;
+
+        let metadata = load_metadata(&mut db)?;
+        assert_eq!(Some(&DateTime::from_millis_epoch(2000)), metadata.last_used_date());
+        assert_eq!(Some(&5), metadata.use_count());
+        // Other metadata is left untouched.
+        assert_eq!(Some(&DateTime::from_millis_epoch(123456789)), metadata.creation_date());
+        Ok(())
+    }
+
+    #[test]
+    fn test_update_key_parameters() -> Result<()> {
+        let mut db = new_test_db()?;
+        let key_id = make_app_test_key_entry(&mut db)?;
+        let load_params = |db: &mut KeystoreDB| -> Result<Vec<KeyParameter>> {
+            let (_, key_entry) = load_app_test_key_entry(db, KeyEntryLoadBits::NONE)?;
+            Ok(key_entry.into_key_parameters())
+        };
+        let original = load_params(&mut db)?;
+
+        db.update_key_parameters(
+            &key_id,
+            &[Tag::OS_PATCHLEVEL, Tag::BOOT_PATCHLEVEL],
+            &[
+                KeyParameter::new(
+                    KeyParameterValue::OSPatchLevel(202201),
+                    SecurityLevel::TRUSTED_ENVIRONMENT,
+                ),
+                KeyParameter::new(KeyParameterValue::OSVersion(13), SecurityLevel::SOFTWARE),
+            ],
+        )?;
+
+        let mut updated = load_params(&mut db)?;
+        // The boot patch level was dropped, the new OS version was ignored.
+        let mut expected: Vec<KeyParameter> = original
+            .into_iter()
+            .filter(|p| p.get_tag() != Tag::OS_PATCHLEVEL && p.get_tag() != Tag::BOOT_PATCHLEVEL)
+            .collect();
+        expected.push(KeyParameter::new(
+            KeyParameterValue::OSPatchLevel(202201),
+            SecurityLevel::TRUSTED_ENVIRONMENT,
+        ));
+        expected.sort();
+        updated.sort();
+        assert_eq!(expected, updated);
+        Ok(())
+    }
+
+    #[test]
+    fn test_replace_certificates() -> Result<()> {
+        let mut db = new_test_db()?;
+        let key_id = make_app_test_key_entry(&mut db)?;
+        let load_certs = |db: &mut KeystoreDB| -> Result<(Option<Vec<u8>>, Option<Vec<u8>>)> {
+            let (_, key_entry) = load_app_test_key_entry(db, KeyEntryLoadBits::PUBLIC)?;
+            Ok((key_entry.cert().clone(), key_entry.cert_chain().clone()))
+        };
+        let original = load_certs(&mut db)?;
+
+        // A failing check leaves both subcomponents untouched.
+        let result = db.replace_certificates(
+            &key_id,
+            Some(b"new cert"),
+            Some(b"new chain"),
+            |current, _| {
+                assert_eq!(current, original.0.as_deref());
+                Err(KsError::Rc(ResponseCode::INVALID_ARGUMENT).into())
+            },
+        );
+        assert_eq!(
+            Some(&KsError::Rc(ResponseCode::INVALID_ARGUMENT)),
+            result.unwrap_err().root_cause().downcast_ref::<KsError>()
+        );
+        assert_eq!(original, load_certs(&mut db)?);
+
+        db.replace_certificates(&key_id, Some(b"new cert"), None, |_, _| Ok(()))?;
+        assert_eq!((Some(b"new cert".to_vec()), None), load_certs(&mut db)?);
+
+        // The stored public key of a key without certificate is passed to the check.
+        db.with_transaction(TransactionBehavior::Immediate, |tx| {
+            let mut metadata = KeyMetaData::new();
+            metadata.add(KeyMetaEntry::SubjectPublicKeyInfo(b"public key".to_vec()));
+            metadata.store_in_db(key_id.0, tx).no_gc()
+        })?;
+        db.replace_certificates(&key_id, None, None, |_, _| Ok(()))?;
+        db.replace_certificates(&key_id, Some(b"new cert"), None, |current, public_key| {
+            assert_eq!(None, current);
+            assert_eq!(Some(&b"public key"[..]), public_key);
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_legacy_import_journal() -> Result<()> {
+        let mut db = new_test_db()?;
+        assert!(db.get_legacy_import_journal()?.is_empty());
+
+        let importing = LegacyImportState::Importing;
+        db.set_legacy_import_state(10001, "foo", Domain::APP, 10001, importing)?;
+        db.set_legacy_import_state(1010, "bar", Domain::SELINUX, 102, importing)?;
+        db.set_legacy_import_state(10001, "foo", Domain::APP, 10001, LegacyImportState::Imported)?;
+
+        let mut journal = db.get_legacy_import_journal()?;
+        journal.sort_by_key(|entry| entry.uid);
+        assert_eq!(
+            journal,
+            vec![
+                LegacyImportJournalEntry {
+                    uid: 1010,
+                    alias: "bar".to_string(),
+                    domain: Domain::SELINUX,
+                    namespace: 102,
+                    state: LegacyImportState::Importing,
+                },
+                LegacyImportJournalEntry {
+                    uid: 10001,
+                    alias: "foo".to_string(),
+                    domain: Domain::APP,
+                    namespace: 10001,
+                    state: LegacyImportState::Imported,
+                },
+            ]
+        );
+
+        assert_eq!(db.get_legacy_import_state(1010, "bar")?, Some(importing));
+        assert_eq!(db.get_legacy_import_state(1010, "foo")?, None);
+
+        let shadowed = LegacyImportState::Shadowed;
+        db.set_legacy_import_state(10001, "baz", Domain::APP, 10001, shadowed)?;
+        assert_eq!(db.get_legacy_import_state(10001, "baz")?, Some(shadowed));
+        db.clear_legacy_import_state(10001, "baz")?;
+
+        db.clear_legacy_import_state(10001, "foo")?;
+        db.clear_legacy_import_state(10001, "bar")?;
+        assert_eq!(db.get_legacy_import_journal()?.len(), 1);
+        db.clear_legacy_import_state(1010, "bar")?;
+        assert!(db.get_legacy_import_journal()?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_unbind_all_keys() -> Result<()> {
+        let mut db = new_test_db()?;
+        make_test_key_entry(&mut db, Domain::APP, 110000, TEST_ALIAS, None)?;
+        make_test_key_entry(&mut db, Domain::SELINUX, 101, TEST_ALIAS, None)?;
+        let pw: keystore2_crypto::Password = (&b"xyzabc"[..]).into();
+        let super_key = keystore2_crypto::generate_aes256_key()?;
+        let (encrypted_super_key, metadata) =
+            SuperKeyManager::encrypt_with_password(&super_key, &pw)?;
+        db.store_super_key(
+            1,
+            &USER_SUPER_KEY,
+            &encrypted_super_key,
+            &metadata,
+            &KeyMetaData::new(),
+        )?;
+        // Keystore's own keys.
+        let boot_level_key_id =
+            make_test_key_entry(&mut db, Domain::APP, AID_KEYSTORE as i64, TEST_ALIAS, None)?.id();
+        load_attestation_key_pool(&mut db, 100 /* expiration */, 110000, 0x01)?;
+        assert_eq!(5, get_keyentry(&db)?.len());
+
+        db.unbind_all_keys()?;
+
+        let remaining = get_keyentry(&db)?;
+        assert_eq!(2, remaining.len());
+        assert!(remaining.iter().any(|row| row.id == boot_level_key_id));
+        assert!(remaining.iter().any(|row| row.key_type == KeyType::Attestation));
+        assert!(db.load_super_key(&USER_SUPER_KEY, 1)?.is_none());
+        let param_count: i64 = db.conn.query_row(
+            "SELECT COUNT(*) FROM persistent.keyparameter WHERE keyentryid != ?;",
+            params![boot_level_key_id],
+            |row| row.get(0),
+        )?;
+        assert_eq!(0, param_count);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_delete_super_key() -> Result<()> {
+        let mut db = new_test_db()?;
+        let pw: keystore2_crypto::Password = (&b"xyzabc"[..]).into();
+        let super_key = keystore2_crypto::generate_aes256_key()?;
+        let (encrypted_super_key, metadata) =
+            SuperKeyManager::encrypt_with_password(&super_key, &pw)?;
+        db.store_super_key(
+            1,
+            &USER_SUPER_KEY,
+            &encrypted_super_key,
+            &metadata,
+            &KeyMetaData::new(),
+        )?;
+        db.store_super_key(
+            2,
+            &USER_SUPER_KEY,
+            &encrypted_super_key,
+            &metadata,
+            &KeyMetaData::new(),
+        )?;
+
+        assert!(db.delete_super_key(&USER_SUPER_KEY, 1)?);
+        assert!(db.load_super_key(&USER_SUPER_KEY, 1)?.is_none());
+        // Deleting a key that does not exist is not an error.
+        assert!(!db.delete_super_key(&USER_SUPER_KEY, 1)?);
+        // The super keys of other users are not affected.
+        assert!(db.load_super_key(&USER_SUPER_KEY, 2)?.is_some());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_replace_super_keys() -> Result<()> {
+        let mut db = new_test_db()?;
+        let pw: keystore2_crypto::Password = (&b"xyzabc"[..]).into();
+        let super_key = keystore2_crypto::generate_aes256_key()?;
+        let (encrypted_super_key, metadata) =
+            SuperKeyManager::encrypt_with_password(&super_key, &pw)?;
+        db.store_super_key(
+            1,
+            &USER_SUPER_KEY,
+            &encrypted_super_key,
+            &metadata,
+            &KeyMetaData::new(),
+        )?;
+        let old_id = db.load_super_key(&USER_SUPER_KEY, 1)?.unwrap().0.id();
+
+        // Keys are replaced, created, and deleted at once.
+        db.replace_super_keys(
+            1,
+            &[
+                (&USER_SUPER_KEY, Some(SuperKeyManager::encrypt_with_password(&super_key, &pw)?)),
+                (&USER_SCREEN_LOCK_BOUND_KEY, Some((encrypted_super_key, metadata))),
+                (&USER_SCREEN_LOCK_BOUND_P521_KEY, None),
+            ],
+        )?;
+        let new_id = db.load_super_key(&USER_SUPER_KEY, 1)?.unwrap().0.id();
+        assert_ne!(old_id, new_id);
+        assert!(db.load_super_key(&USER_SCREEN_LOCK_BOUND_KEY, 1)?.is_some());
+        assert!(db.load_super_key(&USER_SCREEN_LOCK_BOUND_P521_KEY, 1)?.is_none());
+
+        db.replace_super_keys(1, &[(&USER_SUPER_KEY, None), (&USER_SCREEN_LOCK_BOUND_KEY, None)])?;
+        assert!(db.load_super_key(&USER_SUPER_KEY, 1)?.is_none());
+        assert!(db.load_super_key(&USER_SCREEN_LOCK_BOUND_KEY, 1)?.is_none());
+
+        Ok(())
+    }
+
     fn get_valid_statsd_storage_types() -> Vec<MetricsStorage> {
         vec![
             MetricsStorage::KEY_ENTRY,
@@ -5961,6 +8790,35 @@ pub mod tests {
         Ok(())
     }
 
+    #[test]
+    fn auth_token_table_is_bounded() -> Result<()> {
+        let mut db = new_test_db()?;
+        let make_token = |user_id: i64, auth_id: i64| HardwareAuthToken {
+            challenge: 0,
+            userId: user_id,
+            authenticatorId: auth_id,
+            authenticatorType: kmhw_authenticator_type::ANY,
+            timestamp: Timestamp { milliSeconds: 0 },
+            mac: b"mac".to_vec(),
+        };
+        // One token of user 1 followed by enough tokens of user 2 to fill the table.
+        db.insert_auth_token(&make_token(1, 0));
+        for auth_id in 0..perboot::PerbootDB::MAX_AUTH_TOKENS as i64 {
+            std::thread::sleep(std::time::Duration::from_millis(1));
+            db.insert_auth_token(&make_token(2, auth_id));
+        }
+        assert_eq!(db.perboot.auth_tokens_len(), perboot::PerbootDB::MAX_AUTH_TOKENS);
+        // The token of user 1 survives even though it is the oldest.
+        assert!(db.find_auth_token_entry(|e| e.auth_token.userId == 1).is_some());
+        // The oldest token of user 2 was evicted instead.
+        assert!(db
+            .find_auth_token_entry(|e| {
+                e.auth_token.userId == 2 && e.auth_token.authenticatorId == 0
+            })
+            .is_none());
+        Ok(())
+    }
+
     #[test]
     fn test_load_key_descriptor() -> Result<()> {
         let mut db = new_test_db()?;
@@ -15,7 +15,7 @@
 //! This module implements a per-boot, shared, in-memory storage of auth tokens
 //! and last-time-on-body for the main Keystore 2.0 database module.
 
-use super::{AuthTokenEntry, MonotonicRawTime};
+use super::{AuthTokenEntry, BootTime};
 use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
     HardwareAuthToken::HardwareAuthToken, HardwareAuthenticatorType::HardwareAuthenticatorType,
 };
@@ -30,14 +30,18 @@ struct AuthTokenId {
     user_id: i64,
     auth_id: i64,
     authenticator_type: HardwareAuthenticatorType,
+    // Tokens bound to an operation are kept apart from the others, so that a token consumed by
+    // an operation does not replace the token that authorizes timeout bound keys.
+    bound_to_operation: bool,
 }
 
 impl AuthTokenId {
-    fn from_auth_token(tok: &HardwareAuthToken) -> Self {
+    fn from_entry(entry: &AuthTokenEntry) -> Self {
         AuthTokenId {
-            user_id: tok.userId,
-            auth_id: tok.authenticatorId,
-            authenticator_type: tok.authenticatorType,
+            user_id: entry.auth_token.userId,
+            auth_id: entry.auth_token.authenticatorId,
+            authenticator_type: entry.auth_token.authenticatorType,
+            bound_to_operation: entry.bound_to_operation,
         }
     }
 }
@@ -49,14 +53,13 @@ struct AuthTokenEntryWrap(AuthTokenEntry);
 
 impl std::hash::Hash for AuthTokenEntryWrap {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
-        AuthTokenId::from_auth_token(&self.0.auth_token).hash(state)
+        AuthTokenId::from_entry(&self.0).hash(state)
     }
 }
 
 impl PartialEq<AuthTokenEntryWrap> for AuthTokenEntryWrap {
     fn eq(&self, other: &AuthTokenEntryWrap) -> bool {
-        AuthTokenId::from_auth_token(&self.0.auth_token)
-            == AuthTokenId::from_auth_token(&other.0.auth_token)
+        AuthTokenId::from_entry(&self.0) == AuthTokenId::from_entry(&other.0)
     }
 }
 
@@ -82,14 +85,41 @@ lazy_static! {
 }
 
 impl PerbootDB {
+    /// Upper bound for the number of auth tokens tracked at any given time. Tokens are
+    /// replaced per (user_id, auth_id, auth_type, bound_to_operation), so this limit is only
+    /// reached if a large number of distinct authenticators hands tokens to Keystore.
+    pub const MAX_AUTH_TOKENS: usize = 64;
+
     /// Construct a new perboot database. Currently just uses default values.
     pub fn new() -> Self {
         Default::default()
     }
     /// Add a new auth token + timestamp to the database, replacing any which
-    /// match all of user_id, auth_id, and auth_type.
+    /// match all of user_id, auth_id, auth_type, and whether they are bound to an operation.
+    /// If this exceeds `MAX_AUTH_TOKENS`, the oldest token of the same user is evicted.
+    /// If the new token is the only one of its user, the oldest token overall is evicted
+    /// instead. This way a single user cannot push the tokens of other users out of the table.
     pub fn insert_auth_token_entry(&self, entry: AuthTokenEntry) {
-        self.auth_tokens.write().unwrap().replace(AuthTokenEntryWrap(entry));
+        let user_id = entry.auth_token.userId;
+        let mut auth_tokens = self.auth_tokens.write().unwrap();
+        auth_tokens.replace(AuthTokenEntryWrap(entry));
+        if auth_tokens.len() <= Self::MAX_AUTH_TOKENS {
+            return;
+        }
+        let oldest_of_user = auth_tokens
+            .iter()
+            .filter(|x| x.0.auth_token.userId == user_id)
+            .min_by_key(|x| x.0.time_received);
+        let user_count = auth_tokens.iter().filter(|x| x.0.auth_token.userId == user_id).count();
+        let victim = if user_count > 1 {
+            oldest_of_user
+        } else {
+            auth_tokens.iter().min_by_key(|x| x.0.time_received)
+        }
+        .cloned();
+        if let Some(victim) = victim {
+            auth_tokens.remove(&victim);
+        }
     }
     /// Locate an auth token entry which matches the predicate with the most
     /// recent update time.
@@ -102,12 +132,19 @@ impl PerbootDB {
         matches.sort_by_key(|x| x.0.time_received);
         matches.last().map(|x| x.0.clone())
     }
+    /// Remove all auth tokens issued for any of the given secure user ids.
+    pub fn remove_auth_token_entries_for_sids(&self, sids: &HashSet<i64>) {
+        self.auth_tokens.write().unwrap().retain(|x| {
+            !sids.contains(&x.0.auth_token.userId)
+                && !sids.contains(&x.0.auth_token.authenticatorId)
+        });
+    }
     /// Get the last time the device was off the user's body
-    pub fn get_last_off_body(&self) -> MonotonicRawTime {
-        MonotonicRawTime(self.last_off_body.load(Ordering::Relaxed))
+    pub fn get_last_off_body(&self) -> BootTime {
+        BootTime(self.last_off_body.load(Ordering::Relaxed))
     }
     /// Set the last time the device was off the user's body
-    pub fn set_last_off_body(&self, last_off_body: MonotonicRawTime) {
+    pub fn set_last_off_body(&self, last_off_body: BootTime) {
         self.last_off_body.store(last_off_body.0, Ordering::Relaxed)
     }
     /// Return how many auth tokens are currently tracked.
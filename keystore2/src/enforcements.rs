@@ -17,9 +17,11 @@
 use crate::error::{map_binder_status, Error, ErrorCode};
 use crate::globals::{get_timestamp_service, ASYNC_TASK, DB, ENFORCEMENTS};
 use crate::key_parameter::{KeyParameter, KeyParameterValue};
+use crate::metrics_store::log_timestamp_token_cache_stats;
+use crate::trace;
 use crate::{authorization::Error as AuthzError, super_key::SuperEncryptionType};
 use crate::{
-    database::{AuthTokenEntry, MonotonicRawTime},
+    database::{AuthTokenEntry, BootTime},
     globals::SUPER_KEY,
 };
 use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
@@ -38,6 +40,7 @@ use android_system_keystore2::aidl::android::system::keystore2::{
 use anyhow::{Context, Result};
 use std::{
     collections::{HashMap, HashSet},
+    io::Write,
     sync::{
         mpsc::{channel, Receiver, Sender, TryRecvError},
         Arc, Mutex, Weak,
@@ -174,7 +177,9 @@ impl TokenReceiverMap {
     /// This is the number of calls to add_receiver between cleanups.
     const CLEANUP_PERIOD: u8 = 25;
 
-    pub fn add_auth_token(&self, hat: HardwareAuthToken) {
+    /// Passes the auth token to the operation waiting for its challenge, if any. Returns true
+    /// if an operation consumed the token.
+    pub fn add_auth_token(&self, hat: HardwareAuthToken) -> bool {
         let recv = {
             // Limit the scope of the mutex guard, so that it is not held while the auth token is
             // added.
@@ -183,8 +188,9 @@ impl TokenReceiverMap {
             map.remove_entry(&hat.challenge)
         };
 
-        if let Some((_, recv)) = recv {
-            recv.add_auth_token(hat);
+        match recv {
+            Some((_, recv)) => recv.add_auth_token(hat),
+            None => false,
         }
     }
 
@@ -210,14 +216,73 @@ impl TokenReceiver {
         self.0.upgrade().is_none()
     }
 
-    fn add_auth_token(&self, hat: HardwareAuthToken) {
+    fn add_auth_token(&self, hat: HardwareAuthToken) -> bool {
         if let Some(state_arc) = self.0.upgrade() {
             state_arc.add_auth_token(hat);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Timestamp tokens are served from the cache for at most this many milliseconds. A cached
+/// token carries the secure clock time of its generation, so serving it later makes auth tokens
+/// appear younger than they are by up to this amount. Hence, this must be kept short.
+const TIMESTAMP_TOKEN_CACHE_TTL_MS: i64 = 500;
+
+/// Caches recently generated timestamp tokens by challenge. A timestamp token is bound to its
+/// challenge, and the challenges of operations are random and never repeat, so operations always
+/// request a fresh token. Only `get_auth_tokens` goes through the cache, because credstore
+/// requests tokens for the same challenge repeatedly during a presentation, and each of these
+/// requests would otherwise require a round trip to the secure clock.
+#[derive(Default)]
+struct TimeStampTokenCache {
+    /// Maps a challenge to its token and the boot time in milliseconds when it was received.
+    tokens: Mutex<HashMap<i64, (TimeStampToken, i64)>>,
+}
+
+impl TimeStampTokenCache {
+    /// Returns a fresh cached token for the given challenge or calls `fetch` to get a new one.
+    fn get_or_fetch<F>(&self, challenge: i64, fetch: F) -> Result<TimeStampToken, Error>
+    where
+        F: FnOnce(i64) -> Result<TimeStampToken, Error>,
+    {
+        self.get_or_fetch_at(challenge, BootTime::now().milliseconds(), fetch)
+    }
+
+    /// Like `get_or_fetch` with `now` being the current boot time in milliseconds.
+    /// Stale entries are purged on every call, which keeps the cache small. Cache hits are
+    /// logged; misses are not, because every token that is not cached must be fetched anyway.
+    fn get_or_fetch_at<F>(
+        &self,
+        challenge: i64,
+        now: i64,
+        fetch: F,
+    ) -> Result<TimeStampToken, Error>
+    where
+        F: FnOnce(i64) -> Result<TimeStampToken, Error>,
+    {
+        {
+            let mut tokens = self.tokens.lock().unwrap();
+            tokens.retain(|_, (_, received)| now - *received < TIMESTAMP_TOKEN_CACHE_TTL_MS);
+            if let Some((token, _)) = tokens.get(&challenge) {
+                log_timestamp_token_cache_stats(true);
+                return Ok(token.clone());
+            }
         }
+        // Do not hold the lock while talking to the secure clock.
+        let token = fetch(challenge)?;
+        self.tokens.lock().unwrap().insert(challenge, (token.clone(), now));
+        Ok(token)
     }
 }
 
-fn get_timestamp_token(challenge: i64) -> Result<TimeStampToken, Error> {
+fn get_cached_timestamp_token(challenge: i64) -> Result<TimeStampToken, Error> {
+    ENFORCEMENTS.timestamp_token_cache.get_or_fetch(challenge, fetch_timestamp_token)
+}
+
+fn fetch_timestamp_token(challenge: i64) -> Result<TimeStampToken, Error> {
     let dev = get_timestamp_service().expect(concat!(
         "Secure Clock service must be present ",
         "if TimeStampTokens are required."
@@ -226,7 +291,7 @@ fn get_timestamp_token(challenge: i64) -> Result<TimeStampToken, Error> {
 }
 
 fn timestamp_token_request(challenge: i64, sender: Sender<Result<TimeStampToken, Error>>) {
-    if let Err(e) = sender.send(get_timestamp_token(challenge)) {
+    if let Err(e) = sender.send(fetch_timestamp_token(challenge)) {
         log::info!(
             concat!(
                 "In timestamp_token_request: Receiver hung up ",
@@ -318,7 +383,7 @@ impl AuthInfo {
             // On the last successful use, the key gets deleted. In this case we
             // have to notify the garbage collector.
             DB.with(|db| {
-                db.borrow_mut()
+                db.borrow_mut()?
                     .check_and_update_key_usage_count(key_id)
                     .context("Trying to update key usage count.")
             })
@@ -333,6 +398,7 @@ impl AuthInfo {
     /// function may block on the generation of a time stamp token. It then moves the
     /// tokens into the DeferredAuthState::Token state for future use.
     fn get_auth_tokens(&mut self) -> Result<(Option<HardwareAuthToken>, Option<TimeStampToken>)> {
+        let _trace = trace::begin("AuthInfo::get_auth_tokens");
         let deferred_tokens = if let DeferredAuthState::Waiting(ref auth_request) = self.state {
             Some(auth_request.get_auth_tokens().context("In AuthInfo::get_auth_tokens.")?)
         } else {
@@ -362,12 +428,26 @@ impl AuthInfo {
     }
 }
 
+/// The way a user unlocked the device, as reported by the lock screen.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UnlockMethod {
+    /// The user entered the LSKF.
+    Password,
+    /// The user authenticated with a strong biometric.
+    Biometric,
+    /// The device was unlocked without strong authentication, e.g., by a trust agent or a weak
+    /// biometric. This does not unlock the device for keystore.
+    Weak,
+}
+
 /// Enforcements data structure
 #[derive(Default)]
 pub struct Enforcements {
     /// This hash set contains the user ids for whom the device is currently unlocked. If a user id
     /// is not in the set, it implies that the device is locked for the user.
     device_unlocked_set: Mutex<HashSet<i32>>,
+    /// The method of the most recent unlock of each user since the user last locked the device.
+    unlock_methods: Mutex<HashMap<i32, UnlockMethod>>,
     /// This field maps outstanding auth challenges to their operations. When an auth token
     /// with the right challenge is received it is passed to the map using
     /// TokenReceiverMap::add_auth_token() which removes the entry from the map. If an entry goes
@@ -377,6 +457,10 @@ pub struct Enforcements {
     /// The enforcement module will try to get a confirmation token from this channel whenever
     /// an operation that requires confirmation finishes.
     confirmation_token_receiver: Arc<Mutex<Option<Receiver<Vec<u8>>>>>,
+    /// Counts the uses of keys with Tag::MAX_USES_PER_BOOT since boot, indexed by key id.
+    key_uses_per_boot: Mutex<HashMap<i64, i32>>,
+    /// Recently generated timestamp tokens. See `TimeStampTokenCache`.
+    timestamp_token_cache: TimeStampTokenCache,
 }
 
 impl Enforcements {
@@ -408,6 +492,7 @@ impl Enforcements {
         op_params: &[KmKeyParameter],
         requires_timestamp: bool,
     ) -> Result<(Option<HardwareAuthToken>, AuthInfo)> {
+        let _trace = trace::begin("Enforcements::authorize_create");
         let (key_id, key_params) = match key_properties {
             Some((key_id, key_params)) => (*key_id, key_params),
             None => {
@@ -430,6 +515,12 @@ impl Enforcements {
                 return Err(Error::Km(Ec::INCOMPATIBLE_PURPOSE))
                     .context("In authorize_create: WRAP_KEY purpose is not allowed here.");
             }
+            // Rule out ATTEST_KEY purpose. Attestation keys are only ever used by KeyMint while
+            // generating or importing another key, never through an operation.
+            KeyPurpose::ATTEST_KEY => {
+                return Err(Error::Km(Ec::INCOMPATIBLE_PURPOSE))
+                    .context("In authorize_create: ATTEST_KEY purpose is not allowed here.");
+            }
             // Allow AGREE_KEY for EC keys only.
             KeyPurpose::AGREE_KEY => {
                 for kp in key_params.iter() {
@@ -479,6 +570,7 @@ impl Enforcements {
         let mut key_usage_limited: Option<i64> = None;
         let mut confirmation_token_receiver: Option<Arc<Mutex<Option<Receiver<Vec<u8>>>>>> = None;
         let mut max_boot_level: Option<i32> = None;
+        let mut max_uses_per_boot: Option<i32> = None;
 
         // iterate through key parameters, recording information we need for authorization
         // enforcements later, or enforcing authorizations in place, where applicable
@@ -503,7 +595,9 @@ impl Enforcements {
                     caller_nonce_allowed = true;
                 }
                 KeyParameterValue::ActiveDateTime(a) => {
-                    if !Enforcements::is_given_time_passed(*a, true) {
+                    if !Enforcements::is_given_time_passed(*a, true)
+                        .context("In authorize_create.")?
+                    {
                         return Err(Error::Km(Ec::KEY_NOT_YET_VALID))
                             .context("In authorize_create: key is not yet active.");
                     }
@@ -511,6 +605,7 @@ impl Enforcements {
                 KeyParameterValue::OriginationExpireDateTime(o) => {
                     if (purpose == KeyPurpose::ENCRYPT || purpose == KeyPurpose::SIGN)
                         && Enforcements::is_given_time_passed(*o, false)
+                            .context("In authorize_create.")?
                     {
                         return Err(Error::Km(Ec::KEY_EXPIRED))
                             .context("In authorize_create: key is expired.");
@@ -519,6 +614,7 @@ impl Enforcements {
                 KeyParameterValue::UsageExpireDateTime(u) => {
                     if (purpose == KeyPurpose::DECRYPT || purpose == KeyPurpose::VERIFY)
                         && Enforcements::is_given_time_passed(*u, false)
+                            .context("In authorize_create.")?
                     {
                         return Err(Error::Km(Ec::KEY_EXPIRED))
                             .context("In authorize_create: key is expired.");
@@ -536,10 +632,15 @@ impl Enforcements {
                 KeyParameterValue::AllowWhileOnBody => {
                     allow_while_on_body = true;
                 }
-                KeyParameterValue::UsageCountLimit(_) => {
-                    // We don't examine the limit here because this is enforced on finish.
-                    // Instead, we store the key_id so that finish can look up the key
-                    // in the database again and check and update the counter.
+                KeyParameterValue::UsageCountLimit(remaining) => {
+                    // The limit is enforced on finish, where the counter is checked and updated
+                    // atomically. So we store the key_id so that finish can look up the key
+                    // in the database again. But there is no point in starting an operation
+                    // if the key is already exhausted.
+                    if *remaining <= 0 {
+                        return Err(Error::Km(Ec::KEY_MAX_OPS_EXCEEDED))
+                            .context("In authorize_create: limited use key is exhausted.");
+                    }
                     key_usage_limited = Some(key_id);
                 }
                 KeyParameterValue::TrustedConfirmationRequired => {
@@ -548,6 +649,9 @@ impl Enforcements {
                 KeyParameterValue::MaxBootLevel(level) => {
                     max_boot_level = Some(*level);
                 }
+                KeyParameterValue::MaxUsesPerBoot(max) => {
+                    max_uses_per_boot = Some(*max);
+                }
                 // NOTE: as per offline discussion, sanitizing key parameters and rejecting
                 // create operation if any non-allowed tags are present, is not done in
                 // authorize_create (unlike in legacy keystore where AuthorizeBegin is rejected if
@@ -606,7 +710,17 @@ impl Enforcements {
             }
         }
 
+        // Uses per boot are only counted once the use is authorized, so that failed attempts
+        // cannot exhaust the key.
+        let count_use_per_boot = || match max_uses_per_boot {
+            Some(max) => self
+                .check_and_count_use_per_boot(key_id, max)
+                .context("In authorize_create: Trying to count use of the key."),
+            None => Ok(()),
+        };
+
         if !unlocked_device_required && no_auth_required {
+            count_use_per_boot()?;
             return Ok((
                 None,
                 AuthInfo {
@@ -627,7 +741,9 @@ impl Enforcements {
 
         let hat_and_last_off_body = if need_auth_token {
             let hat_and_last_off_body = Self::find_auth_token(|hat: &AuthTokenEntry| {
-                if let (Some(auth_type), true) = (user_auth_type, timeout_bound) {
+                if hat.is_bound_to_operation() {
+                    false
+                } else if let (Some(auth_type), true) = (user_auth_type, timeout_bound) {
                     hat.satisfies(&user_secure_ids, auth_type)
                 } else {
                     unlocked_device_required
@@ -645,7 +761,7 @@ impl Enforcements {
         // Now check the validity of the auth token if the key is timeout bound.
         let hat = match (hat_and_last_off_body, key_time_out) {
             (Some((hat, last_off_body)), Some(key_time_out)) => {
-                let now = MonotonicRawTime::now();
+                let now = BootTime::now();
                 let token_age = now
                     .checked_sub(&hat.time_received())
                     .ok_or_else(Error::sys)
@@ -669,7 +785,7 @@ impl Enforcements {
             _ => None,
         };
 
-        Ok(match (hat, requires_timestamp, per_op_bound) {
+        let (hat, state) = match (hat, requires_timestamp, per_op_bound) {
             // Per-op-bound and Some(hat) can only happen if we are both per-op bound and unlocked
             // device required. In addition, this KM instance needs a timestamp token.
             // So the HAT cannot be presented on create. So on update/finish we present both
@@ -687,33 +803,66 @@ impl Enforcements {
             }
             (None, _, true) => (None, DeferredAuthState::OpAuthRequired),
             (None, _, false) => (None, DeferredAuthState::NoAuthRequired),
-        })
-        .map(|(hat, state)| {
-            (hat, AuthInfo { state, key_usage_limited, confirmation_token_receiver })
-        })
+        };
+
+        count_use_per_boot()?;
+        Ok((hat, AuthInfo { state, key_usage_limited, confirmation_token_receiver }))
     }
 
-    fn find_auth_token<F>(p: F) -> Option<(AuthTokenEntry, MonotonicRawTime)>
+    /// Counts a use of a key with Tag::MAX_USES_PER_BOOT unless the key has already been
+    /// used `max` times since boot, in which case the use is rejected.
+    /// KeyMint back ends are expected to enforce this tag as well, but compat and software
+    /// back ends do not do so consistently.
+    fn check_and_count_use_per_boot(&self, key_id: i64, max: i32) -> Result<()> {
+        let mut uses_per_boot = self.key_uses_per_boot.lock().unwrap();
+        let uses = uses_per_boot.entry(key_id).or_insert(0);
+        if *uses >= max {
+            return Err(Error::Km(Ec::KEY_MAX_OPS_EXCEEDED)).context(format!(
+                "In check_and_count_use_per_boot: Key was already used {} times since boot.",
+                uses
+            ));
+        }
+        *uses += 1;
+        Ok(())
+    }
+
+    /// Returns the time at which the most recent auth token for the given user secure id and
+    /// authenticator type was received, if there is one.
+    pub fn get_last_auth_time(
+        &self,
+        user_secure_id: i64,
+        auth_type: HardwareAuthenticatorType,
+    ) -> Option<BootTime> {
+        let sids = [user_secure_id];
+        Self::find_auth_token(|entry: &AuthTokenEntry| entry.satisfies(&sids, auth_type))
+            .map(|(entry, _)| entry.time_received())
+    }
+
+    fn find_auth_token<F>(p: F) -> Option<(AuthTokenEntry, BootTime)>
     where
         F: Fn(&AuthTokenEntry) -> bool,
     {
-        DB.with(|db| db.borrow().find_auth_token_entry(p))
+        DB.with(|db| db.borrow_mut().map(|db| db.find_auth_token_entry(p))).unwrap_or_else(|e| {
+            log::error!("In find_auth_token: Failed to access the database: {:?}", e);
+            None
+        })
     }
 
     /// Checks if the time now since epoch is greater than (or equal, if is_given_time_inclusive is
-    /// set) the given time (in milliseconds)
-    fn is_given_time_passed(given_time: i64, is_given_time_inclusive: bool) -> bool {
-        let duration_since_epoch = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH);
-
-        let time_since_epoch = match duration_since_epoch {
-            Ok(duration) => duration.as_millis(),
-            Err(_) => return false,
-        };
+    /// set) the given time (in milliseconds).
+    /// Fails if the wall clock cannot be read, so that neither validity window check can be
+    /// bypassed by setting the clock back before the epoch.
+    fn is_given_time_passed(given_time: i64, is_given_time_inclusive: bool) -> Result<bool> {
+        let time_since_epoch = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_err(|_| Error::sys())
+            .context("In is_given_time_passed: System time is before the epoch.")?
+            .as_millis() as i128;
 
         if is_given_time_inclusive {
-            time_since_epoch >= (given_time as u128)
+            Ok(time_since_epoch >= given_time as i128)
         } else {
-            time_since_epoch > (given_time as u128)
+            Ok(time_since_epoch > given_time as i128)
         }
     }
 
@@ -733,17 +882,56 @@ impl Enforcements {
         let mut set = self.device_unlocked_set.lock().unwrap();
         if device_locked_status {
             set.remove(&user_id);
+            drop(set);
+            self.unlock_methods.lock().unwrap().remove(&user_id);
         } else {
             set.insert(user_id);
         }
     }
 
+    /// Records that the user unlocked the device with the given method. Unless the unlock was
+    /// weak, the device is unlocked for the user.
+    pub fn record_unlock(&self, user_id: i32, method: UnlockMethod) {
+        if method != UnlockMethod::Weak {
+            self.set_device_locked(user_id, false);
+        }
+        self.unlock_methods.lock().unwrap().insert(user_id, method);
+    }
+
+    /// Returns true if the device is unlocked for at least one user.
+    pub fn is_device_unlocked_for_any_user(&self) -> bool {
+        !self.device_unlocked_set.lock().unwrap().is_empty()
+    }
+
+    /// Writes the unlock method of every user that unlocked the device since they last locked
+    /// it to the given writer.
+    pub fn dump_unlock_methods(&self, w: &mut dyn Write) -> std::io::Result<()> {
+        let mut methods: Vec<(i32, UnlockMethod)> =
+            self.unlock_methods.lock().unwrap().iter().map(|(u, m)| (*u, *m)).collect();
+        methods.sort_by_key(|(user_id, _)| *user_id);
+        writeln!(w, "Device unlocks:")?;
+        for (user_id, method) in methods.iter() {
+            let state = if self.is_device_locked(*user_id) { "locked" } else { "unlocked" };
+            writeln!(w, "  user {}: {:?}, {}", user_id, method, state)?;
+        }
+        Ok(())
+    }
+
     /// Add this auth token to the database.
     /// Then present the auth token to the op auth map. If an operation is waiting for this
     /// auth token this fulfills the request and removes the receiver from the map.
     pub fn add_auth_token(&self, hat: HardwareAuthToken) {
-        DB.with(|db| db.borrow_mut().insert_auth_token(&hat));
-        self.op_auth_map.add_auth_token(hat);
+        // A token that carries the challenge of an operation authorizes that operation only.
+        // It is still cached, so that getLastAuthTime reflects it, but it is marked, so that it
+        // cannot be replayed to authorize other operations.
+        let result = if self.op_auth_map.add_auth_token(hat.clone()) {
+            DB.with(|db| db.borrow_mut().map(|mut db| db.insert_operation_auth_token(&hat)))
+        } else {
+            DB.with(|db| db.borrow_mut().map(|mut db| db.insert_auth_token(&hat)))
+        };
+        if let Err(e) = result {
+            log::error!("In add_auth_token: Failed to store auth token: {:?}", e);
+        }
     }
 
     /// This allows adding an entry to the op_auth_map, indexed by the operation challenge.
@@ -818,14 +1006,16 @@ impl Enforcements {
         } else {
             // Filter the matching auth tokens by age.
             if auth_token_max_age_millis != 0 {
-                let now_in_millis = MonotonicRawTime::now();
+                let now_in_millis = BootTime::now();
                 let result = Self::find_auth_token(|auth_token_entry: &AuthTokenEntry| {
                     let token_valid = now_in_millis
                         .checked_sub(&auth_token_entry.time_received())
                         .map_or(false, |token_age_in_millis| {
                             auth_token_max_age_millis > token_age_in_millis.milliseconds()
                         });
-                    token_valid && auth_token_entry.satisfies(&sids, auth_type)
+                    token_valid
+                        && !auth_token_entry.is_bound_to_operation()
+                        && auth_token_entry.satisfies(&sids, auth_type)
                 });
 
                 if let Some((auth_token_entry, _)) = result {
@@ -844,10 +1034,184 @@ impl Enforcements {
             }
         };
         // Wait and obtain the timestamp token from secure clock service.
-        let tst = get_timestamp_token(challenge)
+        let tst = get_cached_timestamp_token(challenge)
             .context("In get_auth_tokens. Error in getting timestamp token.")?;
         Ok((auth_token, tst))
     }
 }
 
-// TODO: Add tests to enforcement module (b/175578618).
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use android_hardware_security_secureclock::aidl::android::hardware::security::secureclock::{
+        Timestamp::Timestamp,
+    };
+    use android_hardware_security_keymint::aidl::android::hardware::security::keymint::SecurityLevel::SecurityLevel;
+    use std::cell::Cell;
+
+    fn token_fetcher(
+        fetches: &Cell<u32>,
+    ) -> impl FnOnce(i64) -> Result<TimeStampToken, Error> + '_ {
+        move |challenge| {
+            fetches.set(fetches.get() + 1);
+            Ok(TimeStampToken {
+                challenge,
+                timestamp: Timestamp { milliSeconds: fetches.get() as i64 },
+                mac: vec![],
+            })
+        }
+    }
+
+    #[test]
+    fn test_timestamp_token_cache_hit_and_miss() {
+        let cache = TimeStampTokenCache::default();
+        let fetches = Cell::new(0);
+
+        let first = cache.get_or_fetch_at(1, 1000, token_fetcher(&fetches)).unwrap();
+        assert_eq!(1, fetches.get());
+        assert_eq!(1, first.challenge);
+
+        // The same challenge within the TTL is served from the cache.
+        let second = cache.get_or_fetch_at(1, 1100, token_fetcher(&fetches)).unwrap();
+        assert_eq!(1, fetches.get());
+        assert_eq!(first.timestamp.milliSeconds, second.timestamp.milliSeconds);
+
+        // A different challenge is a miss.
+        let other = cache.get_or_fetch_at(2, 1100, token_fetcher(&fetches)).unwrap();
+        assert_eq!(2, fetches.get());
+        assert_eq!(2, other.challenge);
+    }
+
+    #[test]
+    fn test_timestamp_token_cache_ttl_expiry() {
+        let cache = TimeStampTokenCache::default();
+        let fetches = Cell::new(0);
+
+        cache.get_or_fetch_at(1, 1000, token_fetcher(&fetches)).unwrap();
+        cache
+            .get_or_fetch_at(1, 1000 + TIMESTAMP_TOKEN_CACHE_TTL_MS - 1, token_fetcher(&fetches))
+            .unwrap();
+        assert_eq!(1, fetches.get());
+
+        // Once the TTL has elapsed, the stale token is purged and a new one is fetched.
+        let token = cache
+            .get_or_fetch_at(1, 1000 + TIMESTAMP_TOKEN_CACHE_TTL_MS, token_fetcher(&fetches))
+            .unwrap();
+        assert_eq!(2, fetches.get());
+        assert_eq!(2, token.timestamp.milliSeconds);
+        assert_eq!(1, cache.tokens.lock().unwrap().len());
+    }
+
+    #[test]
+    fn test_timestamp_token_cache_does_not_cache_errors() {
+        let cache = TimeStampTokenCache::default();
+        let fetches = Cell::new(0);
+
+        let result =
+            cache.get_or_fetch_at(1, 1000, |_| Err(Error::Km(Ec::HARDWARE_TYPE_UNAVAILABLE)));
+        assert_eq!(Some(Error::Km(Ec::HARDWARE_TYPE_UNAVAILABLE)), result.err());
+        assert!(cache.tokens.lock().unwrap().is_empty());
+
+        cache.get_or_fetch_at(1, 1000, token_fetcher(&fetches)).unwrap();
+        assert_eq!(1, fetches.get());
+    }
+
+    fn now_ms() -> i64 {
+        SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis() as i64
+    }
+
+    /// Returns the KeyMint error code with which `authorize_create` rejects an operation with
+    /// the given purpose on an AES key with the given validity parameter, or None if the
+    /// operation is authorized.
+    fn authorize_with_validity(purpose: KeyPurpose, validity: KeyParameterValue) -> Option<Ec> {
+        let key_params = vec![
+            KeyParameter::new(
+                KeyParameterValue::Algorithm(Algorithm::AES),
+                SecurityLevel::TRUSTED_ENVIRONMENT,
+            ),
+            KeyParameter::new(
+                KeyParameterValue::KeyPurpose(KeyPurpose::ENCRYPT),
+                SecurityLevel::TRUSTED_ENVIRONMENT,
+            ),
+            KeyParameter::new(
+                KeyParameterValue::KeyPurpose(KeyPurpose::DECRYPT),
+                SecurityLevel::TRUSTED_ENVIRONMENT,
+            ),
+            KeyParameter::new(
+                KeyParameterValue::NoAuthRequired,
+                SecurityLevel::TRUSTED_ENVIRONMENT,
+            ),
+            KeyParameter::new(validity, SecurityLevel::KEYSTORE),
+        ];
+        match Enforcements::default().authorize_create(purpose, Some(&(1, key_params)), &[], false)
+        {
+            Ok(_) => None,
+            Err(e) => match e.root_cause().downcast_ref::<Error>() {
+                Some(Error::Km(ec)) => Some(*ec),
+                _ => panic!("Unexpected error: {:?}", e),
+            },
+        }
+    }
+
+    #[test]
+    fn test_active_date_time() {
+        let hour = 3600 * 1000;
+
+        // A key that becomes active in the future cannot be used for any purpose yet.
+        for purpose in [KeyPurpose::ENCRYPT, KeyPurpose::DECRYPT] {
+            assert_eq!(
+                Some(Ec::KEY_NOT_YET_VALID),
+                authorize_with_validity(
+                    purpose,
+                    KeyParameterValue::ActiveDateTime(now_ms() + hour)
+                )
+            );
+            assert_eq!(
+                None,
+                authorize_with_validity(
+                    purpose,
+                    KeyParameterValue::ActiveDateTime(now_ms() - hour)
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn test_usage_expire_date_time() {
+        let hour = 3600 * 1000;
+        let expired = KeyParameterValue::UsageExpireDateTime(now_ms() - hour);
+        let valid = KeyParameterValue::UsageExpireDateTime(now_ms() + hour);
+
+        // The usage expiry only applies to decryption and verification.
+        assert_eq!(Some(Ec::KEY_EXPIRED), authorize_with_validity(KeyPurpose::DECRYPT, expired));
+        assert_eq!(None, authorize_with_validity(KeyPurpose::DECRYPT, valid));
+        assert_eq!(
+            None,
+            authorize_with_validity(
+                KeyPurpose::ENCRYPT,
+                KeyParameterValue::UsageExpireDateTime(now_ms() - hour)
+            )
+        );
+    }
+
+    #[test]
+    fn test_origination_expire_date_time() {
+        let hour = 3600 * 1000;
+
+        // The origination expiry only applies to encryption and signing.
+        assert_eq!(
+            Some(Ec::KEY_EXPIRED),
+            authorize_with_validity(
+                KeyPurpose::ENCRYPT,
+                KeyParameterValue::OriginationExpireDateTime(now_ms() - hour)
+            )
+        );
+        assert_eq!(
+            None,
+            authorize_with_validity(
+                KeyPurpose::DECRYPT,
+                KeyParameterValue::OriginationExpireDateTime(now_ms() - hour)
+            )
+        );
+    }
+}
@@ -29,11 +29,16 @@
 //!
 //! Keystore functions should use `anyhow::Result` to return error conditions, and
 //! context should be added every time an error is forwarded.
+//!
+//! Errors are either transient or permanent, see `ErrorClass`. Transient errors that have no
+//! dedicated error code of their own are reported to clients as `ResponseCode::BACKEND_BUSY`,
+//! so that clients can tell them apart from failures that a retry would not mask.
 
+use crate::globals::{ERROR_STATS, LOG_THROTTLE};
 pub use android_hardware_security_keymint::aidl::android::hardware::security::keymint::ErrorCode::ErrorCode;
 pub use android_system_keystore2::aidl::android::system::keystore2::ResponseCode::ResponseCode;
 use android_system_keystore2::binder::{
-    ExceptionCode, Result as BinderResult, Status as BinderStatus, StatusCode,
+    ExceptionCode, Result as BinderResult, Status as BinderStatus, StatusCode, ThreadState,
 };
 use keystore2_selinux as selinux;
 use std::cmp::PartialEq;
@@ -100,6 +105,13 @@ pub fn map_km_error<T>(r: BinderResult<T>) -> Result<T, Error> {
                     Error::Binder(ExceptionCode::SERVICE_SPECIFIC, se)
                 }
             }
+            // A dead KeyMint instance gets reconnected, so the status code is preserved to
+            // tell this apart from other transaction failures.
+            ExceptionCode::TRANSACTION_FAILED
+                if s.transaction_error() == StatusCode::DEAD_OBJECT =>
+            {
+                Error::BinderTransaction(StatusCode::DEAD_OBJECT)
+            }
             // We create `Error::Binder` to preserve the exception code
             // for logging.
             // `map_or_log_err` will map this on a system error.
@@ -108,6 +120,45 @@ pub fn map_km_error<T>(r: BinderResult<T>) -> Result<T, Error> {
     })
 }
 
+/// Classifies errors by whether the failed request may succeed if it is retried unchanged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorClass {
+    /// The error is caused by a condition that is expected to clear up by itself, such as a
+    /// busy database, running out of operation slots, or a KeyMint instance that died and is
+    /// being reconnected.
+    Transient,
+    /// The request fails the same way if it is retried.
+    Permanent,
+}
+
+/// Returns the class of the given error, see `ErrorClass`. Using an operation that was pruned
+/// is a permanent error, because the operation is gone for good. Beginning a new operation
+/// fails with a transient error if no operation could be pruned.
+pub fn classify_error(e: &anyhow::Error) -> ErrorClass {
+    let root_cause = e.root_cause();
+    let transient = match root_cause.downcast_ref::<Error>() {
+        Some(Error::Rc(ResponseCode::BACKEND_BUSY))
+        | Some(Error::Rc(ResponseCode::OPERATION_BUSY))
+        | Some(Error::Km(ErrorCode::TOO_MANY_OPERATIONS))
+        | Some(Error::BinderTransaction(StatusCode::DEAD_OBJECT)) => true,
+        Some(_) => false,
+        None => is_database_busy(root_cause),
+    };
+    if transient {
+        ErrorClass::Transient
+    } else {
+        ErrorClass::Permanent
+    }
+}
+
+fn is_database_busy(root_cause: &(dyn std::error::Error + 'static)) -> bool {
+    matches!(
+        root_cause.downcast_ref::<rusqlite::ffi::Error>(),
+        Some(rusqlite::ffi::Error { code: rusqlite::ErrorCode::DatabaseBusy, .. })
+            | Some(rusqlite::ffi::Error { code: rusqlite::ErrorCode::DatabaseLocked, .. })
+    )
+}
+
 /// Helper function to map the binder status we get from calls into a RemotelyProvisionedComponent
 /// to a Keystore Error. We don't create an anyhow error here to make
 /// it easier to evaluate service specific errors.
@@ -144,13 +195,18 @@ pub fn map_binder_status_code<T>(r: Result<T, StatusCode>) -> Result<T, Error> {
 /// into service specific exceptions.
 ///
 /// All error conditions get logged by this function, except for KEY_NOT_FOUND error.
+/// Identical errors are logged at most once per throttling window, see `LOG_THROTTLE`.
+/// All error conditions are counted in `ERROR_STATS`, and all but KEY_NOT_FOUND errors are
+/// retained among its recent errors for dumpsys.
 ///
 /// All `Error::Rc(x)` and `Error::Km(x)` variants get mapped onto a service specific error
 /// code of x. This is possible because KeyMint `ErrorCode` errors are always negative and
 /// `ResponseCode` codes are always positive.
 /// `selinux::Error::PermissionDenied` is mapped on `ResponseCode::PERMISSION_DENIED`.
 ///
-/// All non `Error` error conditions and the Error::Binder variant get mapped onto
+/// Transient error conditions without an error code of their own, i.e., a busy database and
+/// a dead KeyMint instance, get mapped onto `ResponseCode::BACKEND_BUSY`, see `classify_error`.
+/// All other non `Error` error conditions and the Error::Binder variant get mapped onto
 /// ResponseCode::SYSTEM_ERROR`.
 ///
 /// `handle_ok` will be called if `result` is `Ok(value)` where `value` will be passed
@@ -178,11 +234,18 @@ where
         result,
         |e| {
             // Make the key not found errors silent.
-            if !matches!(
+            let key_not_found = matches!(
                 e.root_cause().downcast_ref::<Error>(),
                 Some(Error::Rc(ResponseCode::KEY_NOT_FOUND))
-            ) {
-                log::error!("{:?}", e);
+            );
+            ERROR_STATS.record(
+                ThreadState::get_calling_uid(),
+                get_error_code(&e),
+                &e,
+                key_not_found,
+            );
+            if !key_not_found {
+                LOG_THROTTLE.error(&format!("{:?}", e));
             }
             e
         },
@@ -235,6 +298,8 @@ pub fn get_error_code(e: &anyhow::Error) -> i32 {
     match root_cause.downcast_ref::<Error>() {
         Some(Error::Rc(rcode)) => rcode.0,
         Some(Error::Km(ec)) => ec.0,
+        // Transient errors without an error code of their own tell the client to retry.
+        _ if classify_error(e) == ErrorClass::Transient => ResponseCode::BACKEND_BUSY.0,
         Some(Error::Rp(_)) => ResponseCode::SYSTEM_ERROR.0,
         // If an Error::Binder reaches this stage we report a system error.
         // The exception code and possible service specific error will be
@@ -403,6 +468,27 @@ pub mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_classify_error() {
+        let busy = anyhow!(Error::Rc(ResponseCode::BACKEND_BUSY)).context("busy");
+        assert_eq!(ErrorClass::Transient, classify_error(&busy));
+
+        let not_found = anyhow!(Error::Rc(ResponseCode::KEY_NOT_FOUND)).context("not found");
+        assert_eq!(ErrorClass::Permanent, classify_error(&not_found));
+        assert_eq!(ErrorClass::Permanent, classify_error(&anyhow!(TestError::Fail)));
+
+        let db_busy =
+            anyhow!(rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_BUSY)).context("database busy");
+        assert_eq!(ErrorClass::Transient, classify_error(&db_busy));
+        assert_eq!(ResponseCode::BACKEND_BUSY.0, get_error_code(&db_busy));
+
+        let dead = map_km_error::<()>(Err(BinderStatus::from(StatusCode::DEAD_OBJECT)));
+        assert_eq!(Err(Error::BinderTransaction(StatusCode::DEAD_OBJECT)), dead);
+        let dead = dead.context("KeyMint died.").unwrap_err();
+        assert_eq!(ErrorClass::Transient, classify_error(&dead));
+        assert_eq!(ResponseCode::BACKEND_BUSY.0, get_error_code(&dead));
+    }
+
     //Helper function to test whether error cases are handled as expected.
     pub fn check_result_contains_error_string<T>(
         result: anyhow::Result<T>,
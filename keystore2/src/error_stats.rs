@@ -0,0 +1,169 @@
+// Copyright 2021, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module keeps statistics about the errors returned to Keystore clients, i.e., a counter
+//! per error code and the most recent failures along with their context. Both are reported by
+//! dumpsys, so that bug reports contain the failures even after the corresponding log lines
+//! rotated away.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Number of recent failures that are retained.
+const MAX_RECENT_ERRORS: usize = 64;
+
+/// Maximal length of the recorded context chain of a failure.
+const MAX_CONTEXT_LEN: usize = 512;
+
+/// A failed call into Keystore.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ErrorRecord {
+    /// Time of the failure in milliseconds since the epoch.
+    pub time_ms: u128,
+    /// The uid of the caller.
+    pub uid: u32,
+    /// The function that failed, as given by the outermost context of the error.
+    pub api: String,
+    /// The error code returned to the caller.
+    pub error_code: i32,
+    /// The context chain of the error, truncated to `MAX_CONTEXT_LEN` bytes.
+    pub context: String,
+}
+
+#[derive(Default)]
+struct ErrorStatsState {
+    counts: BTreeMap<i32, u64>,
+    recent: VecDeque<ErrorRecord>,
+}
+
+/// Counts errors by error code and retains the most recent failures.
+#[derive(Default)]
+pub struct ErrorStats {
+    state: Mutex<ErrorStatsState>,
+}
+
+impl ErrorStats {
+    /// Counts the given error. Unless `count_only` is set, the failure is also added to the
+    /// recent failures, evicting the oldest failure if necessary.
+    pub fn record(&self, uid: u32, error_code: i32, e: &anyhow::Error, count_only: bool) {
+        let mut state = self.state.lock().unwrap();
+        *state.counts.entry(error_code).or_default() += 1;
+        if count_only {
+            return;
+        }
+        if state.recent.len() >= MAX_RECENT_ERRORS {
+            state.recent.pop_front();
+        }
+        state.recent.push_back(ErrorRecord {
+            time_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis())
+                .unwrap_or(0),
+            uid,
+            api: api_name(e),
+            error_code,
+            context: truncate(format!("{:#}", e), MAX_CONTEXT_LEN),
+        });
+    }
+
+    /// Returns the number of errors by error code.
+    pub fn counts(&self) -> BTreeMap<i32, u64> {
+        self.state.lock().unwrap().counts.clone()
+    }
+
+    /// Returns the recent failures, oldest first.
+    pub fn recent(&self) -> Vec<ErrorRecord> {
+        self.state.lock().unwrap().recent.iter().cloned().collect()
+    }
+
+    /// Writes the error counts and the recent failures to the given writer.
+    pub fn dump(&self, w: &mut dyn Write) -> std::io::Result<()> {
+        let state = self.state.lock().unwrap();
+        writeln!(w, "Error counts:")?;
+        for (error_code, count) in state.counts.iter() {
+            writeln!(w, "  {}: {}", error_code, count)?;
+        }
+        writeln!(w, "Recent errors:")?;
+        for r in state.recent.iter() {
+            writeln!(
+                w,
+                "  {} uid {} {} error {}: {}",
+                r.time_ms, r.uid, r.api, r.error_code, r.context
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Keystore's context messages start with "In <function>", so the outermost context names the
+/// function that failed.
+fn api_name(e: &anyhow::Error) -> String {
+    let outermost = e.to_string();
+    outermost
+        .strip_prefix("In ")
+        .and_then(|rest| rest.split(|c: char| c == ':' || c == '.' || c == ' ').next())
+        .filter(|name| !name.is_empty())
+        .unwrap_or("unknown")
+        .to_string()
+}
+
+fn truncate(mut s: String, max_len: usize) -> String {
+    if s.len() > max_len {
+        let mut end = max_len;
+        while !s.is_char_boundary(end) {
+            end -= 1;
+        }
+        s.truncate(end);
+    }
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::{anyhow, Context};
+
+    fn failure(i: usize) -> anyhow::Error {
+        Err::<(), _>(anyhow!("root cause {}", i)).context("In generate_key: Failed.").unwrap_err()
+    }
+
+    #[test]
+    fn test_counts_and_recent_errors() {
+        let stats = ErrorStats::default();
+        for i in 0..MAX_RECENT_ERRORS + 3 {
+            stats.record(10001, if i % 2 == 0 { 7 } else { -28 }, &failure(i), false);
+        }
+        stats.record(10001, 7, &failure(0), true);
+
+        let counts = stats.counts();
+        assert_eq!(counts.get(&7), Some(&35));
+        assert_eq!(counts.get(&-28), Some(&33));
+
+        let recent = stats.recent();
+        assert_eq!(recent.len(), MAX_RECENT_ERRORS);
+        assert_eq!(recent[0].context, "In generate_key: Failed.: root cause 3");
+        assert_eq!(recent[0].api, "generate_key");
+        assert_eq!(recent[0].uid, 10001);
+    }
+
+    #[test]
+    fn test_api_name_and_truncation() {
+        assert_eq!(api_name(&anyhow!("In get_key_entry.")), "get_key_entry");
+        assert_eq!(api_name(&anyhow!("Something else")), "unknown");
+        assert_eq!(truncate("äää".to_string(), 3), "ä");
+        assert_eq!(truncate("abc".to_string(), 3), "abc");
+    }
+}
@@ -19,9 +19,10 @@
 //! the key entry from the database.
 
 use crate::{
-    async_task,
-    database::{BlobMetaData, KeystoreDB, Uuid},
+    async_task, config,
+    database::{BlobMetaData, DateTime, KeystoreDB, Uuid},
     super_key::SuperKeyManager,
+    trace,
 };
 use anyhow::{Context, Result};
 use async_task::AsyncTask;
@@ -61,6 +62,7 @@ impl Gc {
             shelf.get_or_put_with(|| GcInternal {
                 deleted_blob_ids: vec![],
                 superseded_blobs: vec![],
+                pending_secure_deletions_retried: false,
                 invalidate_key,
                 db,
                 async_task: weak_at,
@@ -84,6 +86,9 @@ impl Gc {
 struct GcInternal {
     deleted_blob_ids: Vec<i64>,
     superseded_blobs: Vec<(i64, Vec<u8>, BlobMetaData)>,
+    /// Set once the rollback resistant key blobs with pending deletions were handed out for
+    /// another attempt during the current run of the garbage collector.
+    pending_secure_deletions_retried: bool,
     invalidate_key: Box<dyn Fn(&Uuid, &[u8]) -> Result<()> + Send + 'static>,
     db: KeystoreDB,
     async_task: std::sync::Weak<AsyncTask>,
@@ -100,46 +105,73 @@ impl GcInternal {
     /// with threads on the critical path, deleted blobs are loaded in batches.
     fn process_one_key(&mut self) -> Result<()> {
         if self.superseded_blobs.is_empty() {
+            // Expired grants no longer give access to their keys. They are purged alongside
+            // each batch of blobs.
+            if let Err(e) = DateTime::now()
+                .context("Trying to get current time.")
+                .and_then(|now| self.db.delete_expired_grants(now))
+            {
+                log::error!("In process_one_key: Failed to delete expired grants: {:?}", e);
+            }
             let blobs = self
                 .db
-                .handle_next_superseded_blobs(&self.deleted_blob_ids, 20)
+                .handle_next_superseded_blobs(&self.deleted_blob_ids, config::GC_BATCH_SIZE.get())
                 .context("In process_one_key: Trying to handle superseded blob.")?;
             self.deleted_blob_ids = vec![];
             self.superseded_blobs = blobs;
+            if self.superseded_blobs.is_empty() && !self.pending_secure_deletions_retried {
+                self.pending_secure_deletions_retried = true;
+                self.superseded_blobs = self
+                    .db
+                    .get_pending_secure_deletions(config::GC_BATCH_SIZE.get())
+                    .context("In process_one_key: Trying to get pending secure deletions.")?;
+            }
         }
 
         if let Some((blob_id, blob, blob_metadata)) = self.superseded_blobs.pop() {
-            // Add the next blob_id to the deleted blob ids list. So it will be
-            // removed from the database regardless of whether the following
-            // succeeds or not.
-            self.deleted_blob_ids.push(blob_id);
-
-            // If the key has a km_uuid we try to get the corresponding device
-            // and delete the key, unwrapping if necessary and possible.
-            // (At this time keys may get deleted without having the super encryption
-            // key in this case we can only delete the key from the database.)
-            if let Some(uuid) = blob_metadata.km_uuid() {
-                let blob = self
-                    .super_key
-                    .read()
-                    .unwrap()
-                    .unwrap_key_if_required(&blob_metadata, &blob)
-                    .context("In process_one_key: Trying to unwrap to-be-deleted blob.")?;
-                (self.invalidate_key)(uuid, &*blob)
-                    .context("In process_one_key: Trying to invalidate key.")?;
+            let result = self.invalidate_blob(&blob, &blob_metadata);
+            // Rollback resistant key blobs are kept until KeyMint acknowledges their deletion,
+            // because rollback protection only holds if the key is securely deleted. All other
+            // blobs are removed from the database regardless of whether the deletion succeeded.
+            if result.is_err() && blob_metadata.rollback_resistant() == Some(&true) {
+                self.db
+                    .defer_secure_deletion(blob_id)
+                    .context("In process_one_key: Trying to defer secure deletion.")?;
+            } else {
+                self.deleted_blob_ids.push(blob_id);
             }
+            result?;
+        }
+        Ok(())
+    }
+
+    /// If the key has a km_uuid we try to get the corresponding device
+    /// and delete the key, unwrapping if necessary and possible.
+    /// (At this time keys may get deleted without having the super encryption
+    /// key in this case we can only delete the key from the database.)
+    fn invalidate_blob(&self, blob: &[u8], blob_metadata: &BlobMetaData) -> Result<()> {
+        if let Some(uuid) = blob_metadata.km_uuid() {
+            let blob = self
+                .super_key
+                .read()
+                .unwrap()
+                .unwrap_key_if_required(blob_metadata, blob)
+                .context("In invalidate_blob: Trying to unwrap to-be-deleted blob.")?;
+            (self.invalidate_key)(uuid, &*blob)
+                .context("In invalidate_blob: Trying to invalidate key.")?;
         }
         Ok(())
     }
 
     /// Processes one key and then schedules another attempt until it runs out of blobs to delete.
     fn step(&mut self) {
+        let _trace = trace::begin("Gc::step");
         self.notified.store(0, Ordering::Relaxed);
         if let Err(e) = self.process_one_key() {
             log::error!("Error trying to delete blob entry. {:?}", e);
         }
         // Schedule the next step. This gives high priority requests a chance to interleave.
-        if !self.deleted_blob_ids.is_empty() {
+        if !self.deleted_blob_ids.is_empty() || !self.superseded_blobs.is_empty() {
             if let Some(at) = self.async_task.upgrade() {
                 if let Ok(0) =
                     self.notified.compare_exchange(0, 1, Ordering::Relaxed, Ordering::Relaxed)
@@ -149,6 +181,9 @@ impl GcInternal {
                     });
                 }
             }
+        } else {
+            // The run is complete. Pending secure deletions are retried on the next run.
+            self.pending_secure_deletions_retried = false;
         }
     }
 }
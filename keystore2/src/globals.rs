@@ -16,16 +16,27 @@
 //! database connections and connections to services that Keystore needs
 //! to talk to.
 
+use crate::config;
+use crate::error_stats::ErrorStats;
 use crate::gc::Gc;
+use crate::key_usage::KeyUsageTracker;
 use crate::legacy_blob::LegacyBlobLoader;
 use crate::legacy_importer::LegacyImporter;
+use crate::log_throttle::LogThrottle;
+use crate::operation::{OperationBinderRegistry, OperationDb};
+use crate::pruning_stats::PruningStats;
+use crate::rate_limit::RateLimit;
+use crate::security_level::KeystoreSecurityLevel;
 use crate::super_key::SuperKeyManager;
-use crate::utils::watchdog as wd;
-use crate::{async_task::AsyncTask, database::MonotonicRawTime};
+use crate::utils::{get_boot_id, watchdog as wd};
+use crate::{async_task::AsyncTask, database::BootTime};
 use crate::{
     database::KeystoreDB,
     database::Uuid,
-    error::{map_binder_status, map_binder_status_code, Error, ErrorCode},
+    error::{
+        classify_error, map_binder_status, map_binder_status_code, Error, ErrorClass, ErrorCode,
+        ResponseCode,
+    },
 };
 use crate::km_compat::{KeyMintV1, BacklevelKeyMintWrapper};
 use crate::{enforcements::Enforcements, error::map_km_error};
@@ -39,33 +50,96 @@ use android_hardware_security_secureclock::aidl::android::hardware::security::se
 use android_hardware_security_keymint::binder::{StatusCode, Strong};
 use android_security_compat::aidl::android::security::compat::IKeystoreCompatService::IKeystoreCompatService;
 use anyhow::{Context, Result};
-use binder::FromIBinder;
+use binder::{DeathRecipient, FromIBinder, IBinder, Interface, SpIBinder};
 use keystore2_vintf::get_aidl_instances;
 use lazy_static::lazy_static;
-use std::sync::{Arc, Mutex, RwLock};
-use std::{cell::RefCell, sync::Once};
-use std::{collections::HashMap, path::Path, path::PathBuf};
-
-static DB_INIT: Once = Once::new();
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::cell::{RefCell, RefMut};
+use std::time::{Duration, Instant};
+use std::{collections::HashMap, collections::HashSet, path::Path, path::PathBuf};
+
+/// Number of times opening the database is retried after a failure, before the request that
+/// triggered the attempt fails.
+const DB_OPEN_RETRIES: u32 = 3;
+
+/// Delay before the first retry. Each subsequent retry waits one such delay longer.
+const DB_OPEN_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Delay before a failed earlyBootEnded notification is retried by `get_keymint_device`. The
+/// delay doubles with every failed attempt up to `EARLY_BOOT_RETRY_MAX_DELAY`.
+const EARLY_BOOT_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Upper bound of the delay between retries of a failed earlyBootEnded notification.
+const EARLY_BOOT_RETRY_MAX_DELAY: Duration = Duration::from_secs(300);
+
+/// Number of failed earlyBootEnded notifications after which lookups of the instance stop
+/// retrying. With the delays above, the last attempt happens about four minutes after the first.
+const EARLY_BOOT_MAX_ATTEMPTS: u32 = 8;
+
+/// Outcome of telling a KeyMint instance that early boot has ended.
+#[derive(Debug, PartialEq)]
+enum EarlyBootNotification {
+    Acknowledged,
+    /// The notification failed `attempts` times in a row. Lookups of the instance do not retry
+    /// before `retry_after`.
+    Failed {
+        attempts: u32,
+        retry_after: Instant,
+    },
+    /// The notification failed with a permanent error, e.g., because the instance does not
+    /// implement earlyBootEnded, or it failed `EARLY_BOOT_MAX_ATTEMPTS` times. Lookups of the
+    /// instance no longer retry. Only the next earlyBootEnded event does.
+    Abandoned,
+}
 
-/// Open a connection to the Keystore 2.0 database. This is called during the initialization of
-/// the thread local DB field. It should never be called directly. The first time this is called
-/// we also call KeystoreDB::cleanup_leftovers to restore the key lifecycle invariant. See the
-/// documentation of cleanup_leftovers for more details. The function also constructs a blob
-/// garbage collector. The initializing closure constructs another database connection without
-/// a gc. Although one GC is created for each thread local database connection, this closure
-/// is run only once, as long as the ASYNC_TASK instance is the same. So only one additional
-/// database connection is created for the garbage collector worker.
-pub fn create_thread_local_db() -> KeystoreDB {
-    let db_path = DB_PATH.read().expect("Could not get the database directory.");
+impl EarlyBootNotification {
+    /// Returns the state after a notification failed with `error`, given that it had failed
+    /// `attempts` times in a row before.
+    fn after_failure(attempts: u32, error: &anyhow::Error, now: Instant) -> Self {
+        let attempts = attempts + 1;
+        if classify_error(error) == ErrorClass::Permanent || attempts >= EARLY_BOOT_MAX_ATTEMPTS {
+            return Self::Abandoned;
+        }
+        let delay = EARLY_BOOT_RETRY_DELAY
+            .checked_mul(1 << (attempts - 1).min(16))
+            .map_or(EARLY_BOOT_RETRY_MAX_DELAY, |d| d.min(EARLY_BOOT_RETRY_MAX_DELAY));
+        Self::Failed { attempts, retry_after: now + delay }
+    }
+}
 
-    let mut db = KeystoreDB::new(&db_path, Some(GC.clone())).expect("Failed to open database.");
+lazy_static! {
+    /// Set once the database was initialized for this boot, see `create_thread_local_db`.
+    static ref DB_INIT: Mutex<bool> = Mutex::new(false);
+    /// Held by the thread that retries to open the database after a failure.
+    static ref DB_RECOVERY: Mutex<()> = Mutex::new(());
+}
 
-    DB_INIT.call_once(|| {
+/// Open a connection to the Keystore 2.0 database. This is called when the thread local DB
+/// field is first accessed. It should never be called directly. The first time this succeeds
+/// we also call KeystoreDB::cleanup_leftovers to restore the key lifecycle invariant. See the
+/// documentation of cleanup_leftovers for more details. If the cleanup fails, it is repeated
+/// by the next call. The function also constructs a blob garbage collector. The initializing
+/// closure constructs another database connection without a gc. Although one GC is created for
+/// each thread local database connection, this closure is run only once, as long as the
+/// ASYNC_TASK instance is the same. So only one additional database connection is created for
+/// the garbage collector worker.
+pub fn create_thread_local_db() -> Result<KeystoreDB> {
+    let db_path = DB_PATH
+        .read()
+        .map_err(|_| Error::sys())
+        .context("In create_thread_local_db: Could not get the database directory.")?;
+
+    let mut db = KeystoreDB::new(&db_path, Some(GC.clone()))
+        .context("In create_thread_local_db: Failed to open database.")?;
+
+    let mut initialized = DB_INIT.lock().unwrap();
+    if !*initialized {
         log::info!("Touching Keystore 2.0 database for this first time since boot.");
-        db.insert_last_off_body(MonotonicRawTime::now());
+        db.insert_last_off_body(BootTime::now());
         log::info!("Calling cleanup leftovers.");
-        let n = db.cleanup_leftovers().expect("Failed to cleanup database on startup.");
+        let n = db
+            .cleanup_leftovers()
+            .context("In create_thread_local_db: Failed to cleanup database on startup.")?;
         if n != 0 {
             log::info!(
                 concat!(
@@ -75,8 +149,67 @@ pub fn create_thread_local_db() -> KeystoreDB {
                 n
             );
         }
-    });
-    db
+        // Per-boot keys of earlier boots are deleted by the garbage collector. A restart of
+        // Keystore within the same boot keeps them, because the boot id does not change.
+        match get_boot_id().and_then(|boot_id| db.unbind_keys_of_previous_boots(&boot_id)) {
+            Ok(0) => {}
+            Ok(n) => log::info!("Deleting {} per-boot keys of previous boots.", n),
+            Err(e) => log::error!("Failed to delete per-boot keys of previous boots: {:?}", e),
+        }
+        *initialized = true;
+    }
+    Ok(db)
+}
+
+/// Opens a connection to the database like `create_thread_local_db`, but retries with
+/// increasing delays if that fails. Only one thread retries at a time. Requests arriving on
+/// other threads in the meantime fail right away with `ResponseCode::SYSTEM_ERROR` instead of
+/// piling up behind the recovery.
+pub fn open_thread_local_db() -> Result<KeystoreDB> {
+    let e = match create_thread_local_db() {
+        Ok(db) => return Ok(db),
+        Err(e) => e,
+    };
+    let _recovery = match DB_RECOVERY.try_lock() {
+        Ok(recovery) => recovery,
+        Err(_) => {
+            return Err(Error::Rc(ResponseCode::SYSTEM_ERROR))
+                .context("In open_thread_local_db: Database recovery in progress.");
+        }
+    };
+    log::error!("In open_thread_local_db: Failed to open database. Retrying: {:?}", e);
+    for attempt in 1..=DB_OPEN_RETRIES {
+        std::thread::sleep(DB_OPEN_RETRY_DELAY * attempt);
+        match create_thread_local_db() {
+            Ok(db) => {
+                log::info!("In open_thread_local_db: Opened database after {} retries.", attempt);
+                return Ok(db);
+            }
+            Err(e) => log::error!("In open_thread_local_db: Retry {} failed: {:?}", attempt, e),
+        }
+    }
+    Err(Error::Rc(ResponseCode::SYSTEM_ERROR))
+        .context("In open_thread_local_db: Giving up on opening the database.")
+}
+
+/// A database connection that is opened on first use. If opening the connection fails, every
+/// access fails until a later attempt succeeds, instead of taking down the whole process.
+#[derive(Default)]
+pub struct ThreadLocalDb(RefCell<Option<KeystoreDB>>);
+
+impl ThreadLocalDb {
+    /// Mutably borrows the connection, opening it first if needed. Fails with
+    /// `ResponseCode::SYSTEM_ERROR` if the database cannot be opened. Like
+    /// `RefCell::borrow_mut`, this panics if the connection is already borrowed.
+    pub fn borrow_mut(&self) -> Result<RefMut<'_, KeystoreDB>> {
+        let mut db = self.0.borrow_mut();
+        if db.is_none() {
+            *db = Some(open_thread_local_db().context("In ThreadLocalDb::borrow_mut.")?);
+        }
+        RefMut::filter_map(db, Option::as_mut)
+            .map_err(|_| Error::sys())
+            .context("In ThreadLocalDb::borrow_mut: Connection vanished.")
+    }
 }
 
 thread_local! {
@@ -84,13 +217,18 @@ thread_local! {
     /// same database multiple times is safe as long as each connection is
     /// used by only one thread. So we store one database connection per
     /// thread in this thread local key.
-    pub static DB: RefCell<KeystoreDB> =
-            RefCell::new(create_thread_local_db());
+    pub static DB: ThreadLocalDb = Default::default();
 }
 
 struct DevicesMap<T: FromIBinder + ?Sized> {
     devices_by_uuid: HashMap<Uuid, (Strong<T>, KeyMintHardwareInfo)>,
     uuid_by_sec_level: HashMap<SecurityLevel, Uuid>,
+    /// None during early boot. Once early boot has ended, this records for each instance
+    /// whether it acknowledged the end of early boot.
+    early_boot_ended: Option<HashMap<Uuid, EarlyBootNotification>>,
+    /// Death recipients of remote instances. They are only replaced when the instance is
+    /// reconnected, because they must not be dropped from within their own callback.
+    death_recipients: HashMap<Uuid, DeathRecipient>,
 }
 
 impl<T: FromIBinder + ?Sized> DevicesMap<T> {
@@ -117,10 +255,36 @@ impl<T: FromIBinder + ?Sized> DevicesMap<T> {
     fn insert(&mut self, sec_level: SecurityLevel, dev: Strong<T>, hw_info: KeyMintHardwareInfo) {
         // For now we use the reported security level of the KM instance as UUID.
         // TODO update this section once UUID was added to the KM hardware info.
-        let uuid: Uuid = sec_level.into();
+        self.insert_with_uuid(sec_level, sec_level.into(), dev, hw_info);
+    }
+
+    /// Like `insert`, but binds the requested security level to the given uuid. This is used
+    /// when a different instance stands in for the one requested.
+    fn insert_with_uuid(
+        &mut self,
+        sec_level: SecurityLevel,
+        uuid: Uuid,
+        dev: Strong<T>,
+        hw_info: KeyMintHardwareInfo,
+    ) {
         self.devices_by_uuid.insert(uuid, (dev, hw_info));
         self.uuid_by_sec_level.insert(sec_level, uuid);
     }
+
+    /// Drops the instance with the given uuid, unless it was already replaced by a different
+    /// instance than the given dead one.
+    fn remove_dead(&mut self, uuid: &Uuid, dead: &SpIBinder) {
+        match self.devices_by_uuid.get(uuid) {
+            Some((dev, _)) if dev.as_binder() == *dead => {}
+            _ => return,
+        }
+        self.devices_by_uuid.remove(uuid);
+        self.uuid_by_sec_level.retain(|_, u| u != uuid);
+        // A reconnected instance must be told about the end of early boot again.
+        if let Some(status) = self.early_boot_ended.as_mut() {
+            status.remove(uuid);
+        }
+    }
 }
 
 impl<T: FromIBinder + ?Sized> Default for DevicesMap<T> {
@@ -128,10 +292,92 @@ impl<T: FromIBinder + ?Sized> Default for DevicesMap<T> {
         Self {
             devices_by_uuid: HashMap::<Uuid, (Strong<T>, KeyMintHardwareInfo)>::new(),
             uuid_by_sec_level: Default::default(),
+            early_boot_ended: None,
+            death_recipients: Default::default(),
         }
     }
 }
 
+impl DevicesMap<dyn IKeyMintDevice> {
+    /// Evicts the instance with the given uuid from the cache when its service dies, so that
+    /// the next request reconnects. This way Keystore recovers from a restart of the compat
+    /// service or of a KeyMint HAL. Instances living in this process cannot be linked to.
+    fn link_to_death(&mut self, uuid: Uuid) {
+        let mut binder = match self.devices_by_uuid.get(&uuid) {
+            Some((dev, _)) => dev.as_binder(),
+            None => return,
+        };
+        let mut death_recipient = {
+            let dead = binder.clone();
+            DeathRecipient::new(move || {
+                log::warn!("KeyMint instance {:?} died. Reconnecting on next use.", uuid);
+                KEY_MINT_DEVICES.lock().unwrap().remove_dead(&uuid, &dead);
+            })
+        };
+        match binder.link_to_death(&mut death_recipient) {
+            Ok(()) => {
+                self.death_recipients.insert(uuid, death_recipient);
+            }
+            Err(e) => log::info!(
+                "In link_to_death: Not watching KeyMint instance {:?} for death: {:?}",
+                uuid,
+                e
+            ),
+        }
+    }
+
+    /// Tells the instance with the given uuid that early boot has ended, unless early boot is
+    /// still ongoing or the instance already acknowledged it. The outcome is recorded. After a
+    /// transient failure, the notification is retried with exponential backoff when the instance
+    /// is requested, up to `EARLY_BOOT_MAX_ATTEMPTS` times. Regardless of earlier failures, it is
+    /// retried right away if `retry_now` is set, i.e., on the next earlyBootEnded event.
+    fn notify_early_boot_ended(&mut self, uuid: &Uuid, retry_now: bool) -> Result<()> {
+        let status = match self.early_boot_ended.as_mut() {
+            Some(status) => status,
+            None => return Ok(()),
+        };
+        let attempts = match status.get(uuid) {
+            Some(EarlyBootNotification::Acknowledged) => return Ok(()),
+            Some(EarlyBootNotification::Failed { attempts, retry_after }) => {
+                if !retry_now && Instant::now() < *retry_after {
+                    return Ok(());
+                }
+                *attempts
+            }
+            Some(EarlyBootNotification::Abandoned) => {
+                if !retry_now {
+                    return Ok(());
+                }
+                0
+            }
+            None => 0,
+        };
+        let (dev, _) = self
+            .devices_by_uuid
+            .get(uuid)
+            .ok_or_else(Error::sys)
+            .context("In notify_early_boot_ended: Unknown KeyMint instance.")?;
+        let result = {
+            let _wp = wd::watch_millis("In notify_early_boot_ended: calling earlyBootEnded", 500);
+            map_km_error(dev.earlyBootEnded())
+                .context("In notify_early_boot_ended: calling earlyBootEnded.")
+        };
+        let notification = match &result {
+            Ok(()) => EarlyBootNotification::Acknowledged,
+            Err(e) => EarlyBootNotification::after_failure(attempts, e, Instant::now()),
+        };
+        if notification == EarlyBootNotification::Abandoned {
+            log::error!(
+                "In notify_early_boot_ended: Giving up on KeyMint instance {:?} after {} attempts.",
+                uuid,
+                attempts + 1
+            );
+        }
+        status.insert(*uuid, notification);
+        result
+    }
+}
+
 struct RemotelyProvisionedDevicesMap<T: FromIBinder + ?Sized> {
     devices_by_sec_level: HashMap<SecurityLevel, Strong<T>>,
 }
@@ -152,6 +398,15 @@ impl<T: FromIBinder + ?Sized> RemotelyProvisionedDevicesMap<T> {
     }
 }
 
+/// Sets the directory in which Keystore stores its database and in which it looks for legacy
+/// key blobs. This must be called before any database connection is opened, because the
+/// connections and the legacy blob loader capture the path when they are created. The legacy
+/// blob loader is created right away, so that it is bound to the new path.
+pub fn set_db_path(path: &Path) {
+    *DB_PATH.write().expect("Could not lock DB_PATH.") = path.to_path_buf();
+    lazy_static::initialize(&LEGACY_BLOB_LOADER);
+}
+
 lazy_static! {
     /// The path where keystore stores all its keys.
     pub static ref DB_PATH: RwLock<PathBuf> = RwLock::new(
@@ -160,8 +415,13 @@ lazy_static! {
     pub static ref SUPER_KEY: Arc<RwLock<SuperKeyManager>> = Default::default();
     /// Map of KeyMint devices.
     static ref KEY_MINT_DEVICES: Mutex<DevicesMap<dyn IKeyMintDevice>> = Default::default();
+    /// Replacement of `connect_keymint`, see `set_keymint_connector`.
+    static ref KEY_MINT_CONNECTOR: RwLock<Option<Box<KeyMintConnector>>> = Default::default();
     /// Timestamp service.
     static ref TIME_STAMP_DEVICE: Mutex<Option<Strong<dyn ISecureClock>>> = Default::default();
+    /// Evicts the timestamp service from the cache when it dies. It is only replaced when the
+    /// service is reconnected, because it must not be dropped from within its own callback.
+    static ref TIME_STAMP_DEATH_RECIPIENT: Mutex<Option<DeathRecipient>> = Default::default();
     /// RemotelyProvisionedComponent HAL devices.
     static ref REMOTELY_PROVISIONED_COMPONENT_DEVICES:
             Mutex<RemotelyProvisionedDevicesMap<dyn IRemotelyProvisionedComponent>> =
@@ -178,8 +438,44 @@ lazy_static! {
     /// Legacy migrator. Atomically migrates legacy blobs to the database.
     pub static ref LEGACY_IMPORTER: Arc<LegacyImporter> =
         Arc::new(LegacyImporter::new(Arc::new(Default::default())));
+    /// The operation databases of all security levels.
+    pub static ref OPERATION_DBS: Mutex<Vec<Weak<OperationDb>>> = Default::default();
+    /// The security levels by their SecurityLevel.
+    pub static ref SECURITY_LEVELS: Mutex<HashMap<SecurityLevel, Weak<KeystoreSecurityLevel>>> =
+        Default::default();
+    /// The binders of all live operations.
+    pub static ref OPERATION_BINDERS: OperationBinderRegistry = Default::default();
+    /// Uids of the apps in the foreground as reported by ActivityManager. Their operations are
+    /// pruned last, see `OperationDb::prune`.
+    pub static ref FOREGROUND_UIDS: Mutex<HashSet<u32>> = Default::default();
     /// Background thread which handles logging via statsd and logd
     pub static ref LOGS_HANDLER: Arc<AsyncTask> = Default::default();
+    /// Accumulates key usage records until they are written to the database.
+    pub static ref KEY_USAGE: KeyUsageTracker = Default::default();
+    /// Counts the errors returned to clients and retains the most recent ones.
+    pub static ref ERROR_STATS: ErrorStats = Default::default();
+    /// Counts pruned operations by cause and owner.
+    pub static ref PRUNING_STATS: PruningStats = Default::default();
+    /// Deduplicates error messages logged on behalf of clients.
+    pub static ref LOG_THROTTLE: LogThrottle = Default::default();
+    /// Limits the rate of IKeystoreService calls per app.
+    pub static ref SERVICE_RATE_LIMIT: RateLimit = RateLimit::new(
+        "IKeystoreService",
+        &config::RATE_LIMIT_SERVICE,
+        &config::RATE_LIMIT_BURST_SERVICE,
+    );
+    /// Limits the rate of key generations and imports per app.
+    pub static ref KEY_CREATION_RATE_LIMIT: RateLimit = RateLimit::new(
+        "Key creation",
+        &config::RATE_LIMIT_KEY_CREATION,
+        &config::RATE_LIMIT_BURST_KEY_CREATION,
+    );
+    /// Limits the rate at which apps begin operations.
+    pub static ref OPERATION_RATE_LIMIT: RateLimit = RateLimit::new(
+        "createOperation",
+        &config::RATE_LIMIT_OPERATION,
+        &config::RATE_LIMIT_BURST_OPERATION,
+    );
 
     static ref GC: Arc<Gc> = Arc::new(Gc::new_init_with(ASYNC_TASK.clone(), || {
         (
@@ -196,6 +492,11 @@ lazy_static! {
     }));
 }
 
+/// Notifies the key garbage collector to look for unreferenced key blobs.
+pub fn notify_gc() {
+    GC.notify_gc();
+}
+
 static KEYMINT_SERVICE_NAME: &str = "android.hardware.security.keymint.IKeyMintDevice";
 
 /// Determine the service name for a KeyMint device of the given security level
@@ -346,23 +647,122 @@ fn connect_keymint(
     Ok((keymint, hw_info))
 }
 
+/// Connects to the software KeyMint implementation of the compat service. This runs in
+/// process and does not need any Keymaster or KeyMint HAL.
+pub(crate) fn connect_software_keymint() -> Result<KeyMintConnection> {
+    // This is a no-op if it was called before.
+    keystore2_km_compat::add_keymint_device_service();
+
+    let keystore_compat_service: Strong<dyn IKeystoreCompatService> =
+        map_binder_status_code(binder::get_interface("android.security.compat"))
+            .context("In connect_software_keymint: Trying to connect to compat service.")?;
+    let keymint =
+        map_binder_status(keystore_compat_service.getKeyMintDevice(SecurityLevel::SOFTWARE))
+            .context("In connect_software_keymint: Trying to get software KeyMint.")?;
+
+    let wp = wd::watch_millis("In connect_software_keymint: calling getHardwareInfo()", 500);
+    let mut hw_info = map_km_error(keymint.getHardwareInfo())
+        .context("In connect_software_keymint: Failed to get hardware info.")?;
+    drop(wp);
+
+    // The software implementation is the one backing the emulation wrapper of back-level
+    // devices, so it always implements the current KeyMint version.
+    hw_info.versionNumber = 200;
+    Ok((keymint, hw_info))
+}
+
+/// A KeyMint device along with its hardware info.
+pub type KeyMintConnection = (Strong<dyn IKeyMintDevice>, KeyMintHardwareInfo);
+
+/// A function that connects to the KeyMint instance of the given security level.
+pub type KeyMintConnector = dyn Fn(&SecurityLevel) -> Result<KeyMintConnection> + Send + Sync;
+
+/// Replaces the way Keystore connects to KeyMint instances, and drops all connections made so
+/// far. This allows tests to run the services against fake KeyMint devices. It must be called
+/// before any security level is instantiated, because security levels are bound to the uuid of
+/// the instance they were created with.
+pub fn set_keymint_connector(connector: Box<KeyMintConnector>) {
+    *KEY_MINT_CONNECTOR.write().unwrap() = Some(connector);
+    *KEY_MINT_DEVICES.lock().unwrap() = Default::default();
+}
+
 /// Get a keymint device for the given security level either from our cache or
 /// by making a new connection. Returns the device, the hardware info and the uuid.
 /// TODO the latter can be removed when the uuid is part of the hardware info.
+/// Once early boot has ended, the device is told so before it is handed out.
 pub fn get_keymint_device(
     security_level: &SecurityLevel,
 ) -> Result<(Strong<dyn IKeyMintDevice>, KeyMintHardwareInfo, Uuid)> {
     let mut devices_map = KEY_MINT_DEVICES.lock().unwrap();
+    let (dev, hw_info, uuid) = get_or_connect_keymint_device(&mut devices_map, security_level)
+        .context("In get_keymint_device.")?;
+    if let Err(e) = devices_map.notify_early_boot_ended(&uuid, false) {
+        log::error!(
+            "In get_keymint_device: Failed to notify {:?} of the end of early boot: {:?}",
+            security_level,
+            e
+        );
+    }
+    Ok((dev, hw_info, uuid))
+}
+
+fn get_or_connect_keymint_device(
+    devices_map: &mut DevicesMap<dyn IKeyMintDevice>,
+    security_level: &SecurityLevel,
+) -> Result<(Strong<dyn IKeyMintDevice>, KeyMintHardwareInfo, Uuid)> {
     if let Some((dev, hw_info, uuid)) = devices_map.dev_by_sec_level(security_level) {
         Ok((dev, hw_info, uuid))
     } else {
-        let (dev, hw_info) = connect_keymint(security_level).context("In get_keymint_device.")?;
-        devices_map.insert(*security_level, dev, hw_info);
+        let connection = match KEY_MINT_CONNECTOR.read().unwrap().as_ref() {
+            Some(connect) => connect(security_level),
+            None => connect_keymint(security_level),
+        };
+        match connection {
+            Ok((dev, hw_info)) => devices_map.insert(*security_level, dev, hw_info),
+            // Devices without any TEE backed implementation, such as emulators and some GSI
+            // targets, may opt into the software implementation. It is bound to the uuid of
+            // the software security level, so that its keys are never mistaken for keys of a
+            // real TEE that may show up later.
+            Err(e)
+                if *security_level == SecurityLevel::TRUSTED_ENVIRONMENT
+                    && config::SOFTWARE_KEYMINT_FALLBACK.get() =>
+            {
+                log::warn!(
+                    concat!(
+                        "In get_or_connect_keymint_device: No TEE KeyMint available ({:?}). ",
+                        "Falling back to software KeyMint."
+                    ),
+                    e
+                );
+                let (dev, hw_info) = connect_software_keymint()
+                    .context("In get_or_connect_keymint_device: Software fallback failed.")?;
+                devices_map.insert_with_uuid(
+                    *security_level,
+                    SecurityLevel::SOFTWARE.into(),
+                    dev,
+                    hw_info,
+                );
+            }
+            Err(e) => return Err(e).context("In get_or_connect_keymint_device."),
+        }
         // Unwrap must succeed because we just inserted it.
-        Ok(devices_map.dev_by_sec_level(security_level).unwrap())
+        let (dev, hw_info, uuid) = devices_map.dev_by_sec_level(security_level).unwrap();
+        devices_map.link_to_death(uuid);
+        Ok((dev, hw_info, uuid))
     }
 }
 
+/// Records that early boot has ended and tells the KeyMint instance of the given security
+/// level, connecting to it if required. Instances that are connected later on, e.g. because
+/// they were not available yet, are told by `get_keymint_device` when they are connected.
+pub fn notify_early_boot_ended(security_level: &SecurityLevel) -> Result<()> {
+    let mut devices_map = KEY_MINT_DEVICES.lock().unwrap();
+    devices_map.early_boot_ended.get_or_insert_with(Default::default);
+    let (_, _, uuid) = get_or_connect_keymint_device(&mut devices_map, security_level)
+        .context("In notify_early_boot_ended.")?;
+    devices_map.notify_early_boot_ended(&uuid, true).context("In notify_early_boot_ended.")
+}
+
 /// Get a keymint device for the given uuid. This will only access the cache, but will not
 /// attempt to establish a new connection. It is assumed that the cache is already populated
 /// when this is called. This is a fair assumption, because service.rs iterates through all
@@ -383,6 +783,29 @@ pub fn get_keymint_devices() -> Vec<Strong<dyn IKeyMintDevice>> {
     KEY_MINT_DEVICES.lock().unwrap().devices()
 }
 
+/// Returns the cached KeyMint instances along with the security level they serve. Unlike
+/// `get_keymint_device`, this never connects to an instance.
+pub fn get_cached_keymint_devices() -> Vec<(SecurityLevel, Strong<dyn IKeyMintDevice>)> {
+    let devices_map = KEY_MINT_DEVICES.lock().unwrap();
+    devices_map
+        .uuid_by_sec_level
+        .keys()
+        .filter_map(|sec_level| {
+            devices_map.dev_by_sec_level(sec_level).map(|(dev, _, _)| (*sec_level, dev))
+        })
+        .collect()
+}
+
+/// Evicts the given KeyMint instance serving the given security level from the cache, unless
+/// it was replaced already, so that the next request reconnects. This is used for instances
+/// that stopped responding without dying.
+pub fn evict_keymint_device(security_level: &SecurityLevel, dev: &Strong<dyn IKeyMintDevice>) {
+    let mut devices_map = KEY_MINT_DEVICES.lock().unwrap();
+    if let Some(uuid) = devices_map.uuid_by_sec_level.get(security_level).copied() {
+        devices_map.remove_dead(&uuid, &dev.as_binder());
+    }
+}
+
 static TIME_STAMP_SERVICE_NAME: &str = "android.hardware.security.secureclock.ISecureClock";
 
 /// Make a new connection to a secure clock service.
@@ -430,11 +853,45 @@ pub fn get_timestamp_service() -> Result<Strong<dyn ISecureClock>> {
         Ok(dev.clone())
     } else {
         let dev = connect_secureclock().context("In get_timestamp_service.")?;
+        // Reconnect on next use if the service dies. Services living in this process cannot
+        // be linked to, but they cannot die either.
+        let mut binder = dev.as_binder();
+        let mut death_recipient = {
+            let dead = binder.clone();
+            DeathRecipient::new(move || {
+                log::warn!("Secure clock service died. Reconnecting on next use.");
+                let mut ts_device = TIME_STAMP_DEVICE.lock().unwrap();
+                if matches!(&*ts_device, Some(dev) if dev.as_binder() == dead) {
+                    *ts_device = None;
+                }
+            })
+        };
+        match binder.link_to_death(&mut death_recipient) {
+            Ok(()) => *TIME_STAMP_DEATH_RECIPIENT.lock().unwrap() = Some(death_recipient),
+            Err(e) => {
+                log::info!("In get_timestamp_service: Not watching secure clock for death: {:?}", e)
+            }
+        }
         *ts_device = Some(dev.clone());
         Ok(dev)
     }
 }
 
+/// Returns the cached timestamp service, if any. Unlike `get_timestamp_service`, this never
+/// connects to the service.
+pub fn get_cached_timestamp_service() -> Option<Strong<dyn ISecureClock>> {
+    TIME_STAMP_DEVICE.lock().unwrap().clone()
+}
+
+/// Evicts the given timestamp service from the cache, unless it was replaced already, so that
+/// the next request reconnects.
+pub fn evict_timestamp_service(dev: &Strong<dyn ISecureClock>) {
+    let mut ts_device = TIME_STAMP_DEVICE.lock().unwrap();
+    if matches!(&*ts_device, Some(cached) if cached.as_binder() == dev.as_binder()) {
+        *ts_device = None;
+    }
+}
+
 static REMOTE_PROVISIONING_HAL_SERVICE_NAME: &str =
     "android.hardware.security.keymint.IRemotelyProvisionedComponent";
 
@@ -490,3 +947,44 @@ pub fn get_remotely_provisioned_component(
         Ok(devices_map.dev_by_sec_level(security_level).unwrap())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+
+    #[test]
+    fn test_early_boot_notification_after_failure() {
+        let now = Instant::now();
+        let busy = anyhow!(Error::Rc(ResponseCode::BACKEND_BUSY));
+
+        // Transient failures are retried with exponential backoff.
+        assert_eq!(
+            EarlyBootNotification::Failed {
+                attempts: 1,
+                retry_after: now + EARLY_BOOT_RETRY_DELAY
+            },
+            EarlyBootNotification::after_failure(0, &busy, now)
+        );
+        assert_eq!(
+            EarlyBootNotification::Failed {
+                attempts: 3,
+                retry_after: now + EARLY_BOOT_RETRY_DELAY * 4
+            },
+            EarlyBootNotification::after_failure(2, &busy, now)
+        );
+
+        // Retries stop after EARLY_BOOT_MAX_ATTEMPTS failures.
+        assert_eq!(
+            EarlyBootNotification::Abandoned,
+            EarlyBootNotification::after_failure(EARLY_BOOT_MAX_ATTEMPTS - 1, &busy, now)
+        );
+
+        // Permanent failures are not retried at all.
+        let unimplemented = anyhow!(Error::Km(ErrorCode::UNIMPLEMENTED));
+        assert_eq!(
+            EarlyBootNotification::Abandoned,
+            EarlyBootNotification::after_failure(0, &unimplemented, now)
+        );
+    }
+}
@@ -0,0 +1,193 @@
+// Copyright 2021, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module checks the health of the cached KeyMint and secure clock connections in regular
+//! intervals. Each instance is pinged with a cheap call that has to complete within a short
+//! deadline. An instance that fails the ping is marked as degraded, evicted from the cache and
+//! reconnected right away, so that a dead or hung HAL is discovered before a user operation
+//! fails on it. The health state is reported by the dump of the maintenance service.
+
+use crate::config;
+use crate::error::{map_binder_status, map_km_error};
+use crate::globals::{
+    evict_keymint_device, evict_timestamp_service, get_cached_keymint_devices,
+    get_cached_timestamp_service, get_keymint_device, get_timestamp_service, ASYNC_TASK,
+};
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
+    IKeyMintDevice::IKeyMintDevice,
+};
+use android_hardware_security_secureclock::aidl::android::hardware::security::secureclock::{
+    ISecureClock::ISecureClock,
+};
+use anyhow::{anyhow, Context, Result};
+use lazy_static::lazy_static;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::sync::{mpsc, Mutex};
+use std::time::Duration;
+
+/// Health checks are disabled while the interval is 0. The tunable is then polled at this
+/// interval, so that health checks can be enabled at runtime.
+const DISABLED_POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Health of a single HAL instance.
+#[derive(Debug, Default)]
+struct HalHealth {
+    /// Whether the last ping failed or timed out.
+    degraded: bool,
+    /// Number of pings.
+    pings: u64,
+    /// Number of consecutive failed pings.
+    consecutive_failures: u32,
+    /// Error of the last failed ping.
+    last_error: Option<String>,
+    /// Whether a ping did not return yet. A hung instance is not pinged again until it does,
+    /// so that hung pings cannot pile up.
+    in_flight: bool,
+}
+
+lazy_static! {
+    /// Health of the HAL instances by name.
+    static ref HEALTH: Mutex<BTreeMap<String, HalHealth>> = Default::default();
+}
+
+/// Starts the thread that schedules the health checks on the low priority queue of the async
+/// task.
+pub fn start() {
+    let spawned = std::thread::Builder::new().name("keystore2_hal_health".into()).spawn(|| loop {
+        let interval = config::HAL_HEALTH_CHECK_INTERVAL_SECS.get();
+        if interval == 0 {
+            std::thread::sleep(DISABLED_POLL_INTERVAL);
+            continue;
+        }
+        std::thread::sleep(Duration::from_secs(interval));
+        ASYNC_TASK.queue_lo(|_| check_all());
+    });
+    if let Err(e) = spawned {
+        log::error!("In hal_health::start: Failed to start health checks: {:?}", e);
+    }
+}
+
+/// Pings all cached HAL instances. Degraded instances are evicted and reconnected.
+fn check_all() {
+    for (sec_level, dev) in get_cached_keymint_devices() {
+        let name = format!("KeyMint {:?}", sec_level);
+        let ping_dev = dev.clone();
+        let ping = move || {
+            map_km_error(ping_dev.getHardwareInfo()).map(|_| ()).context("Calling getHardwareInfo.")
+        };
+        if !check(&name, ping) {
+            evict_keymint_device(&sec_level, &dev);
+            if let Err(e) = get_keymint_device(&sec_level) {
+                log::error!("In check_all: Failed to reconnect {}: {:?}", name, e);
+            }
+        }
+    }
+    if let Some(dev) = get_cached_timestamp_service() {
+        let name = "SecureClock".to_string();
+        let ping_dev = dev.clone();
+        let ping = move || {
+            map_binder_status(ping_dev.generateTimeStamp(0))
+                .map(|_| ())
+                .context("Calling generateTimeStamp.")
+        };
+        if !check(&name, ping) {
+            evict_timestamp_service(&dev);
+            if let Err(e) = get_timestamp_service() {
+                log::error!("In check_all: Failed to reconnect {}: {:?}", name, e);
+            }
+        }
+    }
+}
+
+/// Runs `ping` on a helper thread and waits for it at most for the configured deadline.
+/// Records the outcome and returns true if the instance is healthy. An instance whose previous
+/// ping did not return yet is reported as degraded, but it is not evicted again.
+fn check<F>(name: &str, ping: F) -> bool
+where
+    F: FnOnce() -> Result<()> + Send + 'static,
+{
+    {
+        let mut health = HEALTH.lock().unwrap();
+        let entry = health.entry(name.to_string()).or_default();
+        if entry.in_flight {
+            entry.degraded = true;
+            entry.last_error = Some("Previous ping did not return.".to_string());
+            return true;
+        }
+        entry.in_flight = true;
+    }
+
+    let deadline = Duration::from_millis(config::HAL_HEALTH_CHECK_DEADLINE_MILLIS.get());
+    let (sender, receiver) = mpsc::channel();
+    let thread_name = name.to_string();
+    let spawned = std::thread::Builder::new().name("keystore2_hal_ping".into()).spawn(move || {
+        let result = ping();
+        if let Some(entry) = HEALTH.lock().unwrap().get_mut(&thread_name) {
+            entry.in_flight = false;
+        }
+        // The receiver is gone if the deadline has passed.
+        let _ = sender.send(result);
+    });
+    let result = match spawned {
+        Ok(_) => receiver
+            .recv_timeout(deadline)
+            .unwrap_or_else(|_| Err(anyhow!("No response within {:?}.", deadline))),
+        Err(e) => {
+            // Without a ping thread, nobody else clears the flag.
+            if let Some(entry) = HEALTH.lock().unwrap().get_mut(name) {
+                entry.in_flight = false;
+            }
+            Err(anyhow!(e)).context("Failed to spawn ping thread.")
+        }
+    };
+
+    let mut health = HEALTH.lock().unwrap();
+    let entry = health.entry(name.to_string()).or_default();
+    entry.pings += 1;
+    match result {
+        Ok(()) => {
+            entry.degraded = false;
+            entry.consecutive_failures = 0;
+            true
+        }
+        Err(e) => {
+            log::warn!("In check: {} is degraded: {:?}", name, e);
+            entry.degraded = true;
+            entry.consecutive_failures += 1;
+            entry.last_error = Some(format!("{:?}", e));
+            false
+        }
+    }
+}
+
+/// Writes the health of all HAL instances that were checked so far.
+pub fn dump(w: &mut dyn Write) -> std::io::Result<()> {
+    let health = HEALTH.lock().unwrap();
+    writeln!(w, "HAL health:")?;
+    for (name, h) in health.iter() {
+        writeln!(
+            w,
+            "  {}: {}, pings {}, consecutive failures {}",
+            name,
+            if h.degraded { "degraded" } else { "healthy" },
+            h.pings,
+            h.consecutive_failures
+        )?;
+        if let Some(e) = &h.last_error {
+            writeln!(w, "    last error: {}", e)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,86 @@
+// Copyright 2021, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Binds the keys derived from the user's LSKF to this device. The key derived from the LSKF
+//! is run through HMAC-SHA256 with a Keystore internal KeyMint key, and the result wraps the
+//! super keys. Without the KeyMint instance of this device, the LSKF cannot be brute forced
+//! offline against an extracted database.
+
+use crate::{
+    database::{KeyType, KeystoreDB},
+    error::Error,
+    key_parameter::KeyParameterValue,
+    raw_device::KeyMintDevice,
+};
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
+    Algorithm::Algorithm, Digest::Digest, KeyPurpose::KeyPurpose, SecurityLevel::SecurityLevel,
+};
+use anyhow::{Context, Result};
+use keystore2_crypto::{ZVec, AES_256_KEY_LENGTH};
+
+/// Alias of the Keystore internal KeyMint key that binds the LSKF derived keys to the device.
+const LSKF_BINDING_KEY_ALIAS: &str = "lskf_binding_key";
+
+/// Binds the key derived from the user's LSKF to this device and returns the key that wraps
+/// the user's super keys. The binding key is an HMAC-SHA256 key of the TEE KeyMint instance,
+/// stored as the Keystore internal key "lskf_binding_key". It is generated by the first call
+/// and loaded from the database by all later ones; the derived key is then run through
+/// HMAC-SHA256 with it, see `derive_with_device_key`.
+///
+/// Loading the binding key takes the KeystoreDB key id lock on the binding key's own entry.
+/// Callers must not hold the `KeyIdGuard` of any other key, such as a super key, while calling
+/// this function, which is why the callers in super_key.rs drop their key_id_guard first.
+pub fn bind_to_device(db: &mut KeystoreDB, derived_key: &[u8]) -> Result<ZVec> {
+    derive_with_device_key(db, LSKF_BINDING_KEY_ALIAS, derived_key).context("In bind_to_device.")
+}
+
+/// Computes HMAC-SHA256 over `message` with the Keystore internal KeyMint key of the given
+/// alias and returns the MAC, which has the length of an AES-256 key. The internal key is an
+/// HMAC key of the TEE KeyMint instance that never leaves KeyMint, so the result can only be
+/// computed on this device. The key is generated when it is used for the first time and is
+/// loaded from the database afterwards; if it cannot be used anymore, e.g., because its
+/// characteristics do not match the instance, it is replaced with a new one. Loading the key
+/// takes the key id lock on its entry, see `bind_to_device`.
+pub fn derive_with_device_key(db: &mut KeystoreDB, alias: &str, message: &[u8]) -> Result<ZVec> {
+    let km_dev = KeyMintDevice::get(SecurityLevel::TRUSTED_ENVIRONMENT)
+        .context("In derive_with_device_key: Get TEE instance failed.")?;
+
+    let key_desc = KeyMintDevice::internal_descriptor(alias.to_string());
+    let params = [
+        KeyParameterValue::Algorithm(Algorithm::HMAC).into(),
+        KeyParameterValue::Digest(Digest::SHA_2_256).into(),
+        KeyParameterValue::KeySize(256).into(),
+        KeyParameterValue::MinMacLength(256).into(),
+        KeyParameterValue::KeyPurpose(KeyPurpose::SIGN).into(),
+        KeyParameterValue::NoAuthRequired.into(),
+    ];
+    let (key_id_guard, key_blob) = km_dev
+        .lookup_or_generate_key(db, &key_desc, KeyType::Client, &params, |key_characteristics| {
+            key_characteristics.iter().any(|kc| kc.securityLevel == km_dev.security_level())
+        })
+        .context("In derive_with_device_key: lookup_or_generate_key failed.")?;
+
+    let params = [
+        KeyParameterValue::MacLength(256).into(),
+        KeyParameterValue::Digest(Digest::SHA_2_256).into(),
+    ];
+    let mac = km_dev
+        .use_key_in_one_step(db, &key_id_guard, &key_blob, KeyPurpose::SIGN, &params, None, message)
+        .context("In derive_with_device_key: use_key_in_one_step failed.")?;
+    if mac.len() != AES_256_KEY_LENGTH {
+        return Err(Error::sys())
+            .context(format!("In derive_with_device_key: Unexpected MAC length {}.", mac.len()));
+    }
+    Ok(mac)
+}
@@ -0,0 +1,114 @@
+// Copyright 2021, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module defers heavy background work, such as the key blob upgrade sweep, until the
+//! device is idle and charging, so that Keystore maintenance neither drains the battery nor
+//! competes with the user for CPU and storage bandwidth. The device state is reported through
+//! IKeystoreMaintenance::onDeviceStateChanged. Deferred work can be forced to run right away
+//! through IKeystoreMaintenance::runDeferredMaintenance, and deferral can be switched off with
+//! the tunable `config::DEFER_MAINTENANCE`. Devices that are rarely idle and charging, e.g.,
+//! devices that are never charged overnight, must not postpone the work forever, so deferred
+//! work is started anyway once it was deferred for `config::MAX_MAINTENANCE_DEFERRAL_SECS`.
+//!
+//! All work deferred this way must be safe to postpone for that long, e.g., because the same
+//! work is also done lazily when a key is used.
+
+use crate::config;
+use crate::globals::ASYNC_TASK;
+use lazy_static::lazy_static;
+use std::sync::Mutex;
+use std::time::Duration;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+#[derive(Default)]
+struct State {
+    idle: bool,
+    charging: bool,
+    /// Deferred jobs along with their names.
+    pending: Vec<(&'static str, Job)>,
+    /// Whether the thread that bounds the deferral is running.
+    deadline_armed: bool,
+}
+
+lazy_static! {
+    static ref STATE: Mutex<State> = Default::default();
+}
+
+/// Adds `job` to the low priority queue of the async task if the device is idle and charging,
+/// and defers it until it is otherwise. `name` identifies the job in logs and dumps.
+pub fn queue_lo<F>(name: &'static str, job: F)
+where
+    F: FnOnce() + Send + 'static,
+{
+    let mut state = STATE.lock().unwrap();
+    if !config::DEFER_MAINTENANCE.get() || (state.idle && state.charging) {
+        drop(state);
+        ASYNC_TASK.queue_lo(move |_shelf| job());
+    } else {
+        log::info!("Deferring {} until the device is idle and charging.", name);
+        state.pending.push((name, Box::new(job)));
+        if !state.deadline_armed {
+            state.deadline_armed = arm_deadline();
+        }
+    }
+}
+
+/// Starts a thread that starts all deferred work once the maximal deferral has elapsed.
+/// Returns false if the thread could not be started.
+fn arm_deadline() -> bool {
+    let deferral = Duration::from_secs(config::MAX_MAINTENANCE_DEFERRAL_SECS.get());
+    let builder = std::thread::Builder::new().name("keystore2_maintenance_deadline".into());
+    let spawned = builder.spawn(move || {
+        std::thread::sleep(deferral);
+        STATE.lock().unwrap().deadline_armed = false;
+        log::info!("Maximal deferral elapsed, starting deferred work.");
+        run_deferred();
+    });
+    match spawned {
+        Ok(_) => true,
+        Err(e) => {
+            log::error!("In arm_deadline: Failed to bound the deferral: {:?}", e);
+            false
+        }
+    }
+}
+
+/// Records the state of the device. Deferred work is started once the device is idle and
+/// charging.
+pub fn on_device_state_changed(idle: bool, charging: bool) {
+    let ready = {
+        let mut state = STATE.lock().unwrap();
+        state.idle = idle;
+        state.charging = charging;
+        idle && charging
+    };
+    if ready {
+        run_deferred();
+    }
+}
+
+/// Starts all deferred work regardless of the state of the device.
+pub fn run_deferred() {
+    let pending = std::mem::take(&mut STATE.lock().unwrap().pending);
+    for (name, job) in pending {
+        log::info!("Starting deferred {}.", name);
+        ASYNC_TASK.queue_lo(move |_shelf| job());
+    }
+}
+
+/// Returns the names of the jobs that are currently deferred.
+pub fn deferred_work() -> Vec<&'static str> {
+    STATE.lock().unwrap().pending.iter().map(|(name, _)| *name).collect()
+}
@@ -0,0 +1,154 @@
+// Copyright 2021, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements the IKeystoreKeyImport AIDL interface, which imports private keys
+//! from password protected containers. The containers are decrypted in process into zeroizing
+//! buffers, and the plain key material is handed to the regular import path of the security
+//! level, so that all permission checks and enforcements apply.
+
+use crate::error::{map_or_log_err, Error};
+use crate::security_level::KeystoreSecurityLevel;
+use crate::utils::watchdog as wd;
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
+    Certificate::Certificate, KeyParameter::KeyParameter, SecurityLevel::SecurityLevel,
+};
+use android_security_keyimport::aidl::android::security::keyimport::{
+    IKeystoreKeyImport::{BnKeystoreKeyImport, IKeystoreKeyImport},
+    KeyContainerFormat::KeyContainerFormat,
+};
+use android_security_keyimport::binder::{
+    BinderFeatures, Interface, Result as BinderResult, Strong,
+};
+use android_system_keystore2::aidl::android::system::keystore2::{
+    KeyDescriptor::KeyDescriptor, KeyMetadata::KeyMetadata, ResponseCode::ResponseCode,
+};
+use anyhow::{Context, Result};
+use keystore2_crypto::{decrypt_pkcs8, parse_pkcs12, ZVec};
+
+/// Implementation of the IKeystoreKeyImport AIDL interface.
+pub struct KeyImport;
+
+impl KeyImport {
+    /// Creates a new instance of the key import service.
+    pub fn new_native_binder() -> Result<Strong<dyn IKeystoreKeyImport>> {
+        Ok(BnKeystoreKeyImport::new_binder(
+            Self,
+            BinderFeatures { set_requesting_sid: true, ..BinderFeatures::default() },
+        ))
+    }
+
+    /// Decrypts the container and returns the PKCS#8 encoded private key along with the
+    /// certificates to store for the key, if any.
+    fn unpack_container(
+        format: KeyContainerFormat,
+        container: &[u8],
+        password: &[u8],
+    ) -> Result<(ZVec, Option<Vec<Certificate>>)> {
+        match format {
+            KeyContainerFormat::ENCRYPTED_PKCS8 => {
+                let private_key = decrypt_pkcs8(container, password)
+                    .map_err(|_| Error::Rc(ResponseCode::INVALID_ARGUMENT))
+                    .context("In unpack_container: Failed to decrypt PKCS#8 private key.")?;
+                Ok((private_key, None))
+            }
+            KeyContainerFormat::PKCS12 => {
+                let contents = parse_pkcs12(container, password)
+                    .map_err(|_| Error::Rc(ResponseCode::INVALID_ARGUMENT))
+                    .context("In unpack_container: Failed to parse PKCS#12 container.")?;
+                let certificates = match (contents.certificate, contents.cert_chain) {
+                    // The chain is stored as concatenation of its certificates anyway, so it
+                    // is passed on as a single entry.
+                    (Some(cert), chain) => Some(
+                        std::iter::once(cert)
+                            .chain(chain)
+                            .map(|encoded_certificate| Certificate {
+                                encodedCertificate: encoded_certificate,
+                            })
+                            .collect(),
+                    ),
+                    (None, Some(_)) => {
+                        log::warn!(concat!(
+                            "In unpack_container: No certificate of the PKCS#12 container ",
+                            "matches its private key. Ignoring the certificates."
+                        ));
+                        None
+                    }
+                    (None, None) => None,
+                };
+                Ok((contents.private_key, certificates))
+            }
+            _ => Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+                .context(format!("In unpack_container: Unknown container format {:?}.", format)),
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn import_key_container(
+        security_level: SecurityLevel,
+        key: &KeyDescriptor,
+        attestation_key: Option<&KeyDescriptor>,
+        params: &[KeyParameter],
+        flags: i32,
+        format: KeyContainerFormat,
+        container: &[u8],
+        password: &[u8],
+    ) -> Result<KeyMetadata> {
+        let sec_level = KeystoreSecurityLevel::get(security_level)
+            .ok_or(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+            .context("In import_key_container: No such security level.")?;
+        let (private_key, certificates) = Self::unpack_container(format, container, password)
+            .context("In import_key_container.")?;
+        sec_level
+            .import_key_with_certificates(
+                key,
+                attestation_key,
+                params,
+                flags,
+                &private_key,
+                certificates,
+            )
+            .context("In import_key_container.")
+    }
+}
+
+impl Interface for KeyImport {}
+
+impl IKeystoreKeyImport for KeyImport {
+    fn importKeyContainer(
+        &self,
+        security_level: SecurityLevel,
+        key: &KeyDescriptor,
+        attestation_key: Option<&KeyDescriptor>,
+        params: &[KeyParameter],
+        flags: i32,
+        format: KeyContainerFormat,
+        container: &[u8],
+        password: &[u8],
+    ) -> BinderResult<KeyMetadata> {
+        let _wp = wd::watch_millis("IKeystoreKeyImport::importKeyContainer", 500);
+        map_or_log_err(
+            Self::import_key_container(
+                security_level,
+                key,
+                attestation_key,
+                params,
+                flags,
+                format,
+                container,
+                password,
+            ),
+            Ok,
+        )
+    }
+}
@@ -0,0 +1,283 @@
+// Copyright 2021, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements the IKeystoreKeyInfo AIDL interface, which exposes information about
+//! keys that does not fit into the KeyMetadata of the public Keystore API. All information is
+//! served from the database, i.e., no KeyMint instance is involved.
+
+use crate::database::{KeyEntry, KeyEntryLoadBits, KeyIdGuard, KeyType, ListedKeyEntry};
+use crate::error::{map_or_log_err, Error, ResponseCode};
+use crate::globals::{get_keymint_dev_by_uuid, DB, KEY_USAGE, LEGACY_IMPORTER, SUPER_KEY};
+use crate::permission::{KeyPerm, KeyPermSet};
+use crate::utils::{
+    check_key_permission, check_list_permission, uid_to_android_user, watchdog as wd,
+};
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::SecurityLevel::SecurityLevel;
+use android_security_keyinfo::aidl::android::security::keyinfo::{
+    IKeystoreKeyInfo::{BnKeystoreKeyInfo, IKeystoreKeyInfo},
+    KeyCharacteristicsInfo::KeyCharacteristicsInfo,
+    KeyEntryOrder::KeyEntryOrder,
+    KeyEntrySummary::KeyEntrySummary,
+    PublicKeyFormat::PublicKeyFormat,
+};
+use android_security_keyinfo::binder::{
+    BinderFeatures, Interface, Result as BinderResult, Strong, ThreadState,
+};
+use android_system_keystore2::aidl::android::system::keystore2::{
+    Domain::Domain, KeyDescriptor::KeyDescriptor,
+};
+use anyhow::{Context, Result};
+use keystore2_crypto::{der_to_pem, parse_public_key_from_certificate};
+
+/// Maximal size of the opaque metadata that a key owner can attach to a key.
+const MAX_APP_METADATA_SIZE: usize = 4096;
+
+/// Implementation of the IKeystoreKeyInfo AIDL interface.
+pub struct KeyInfo;
+
+impl KeyInfo {
+    /// Creates a new instance of the key info service.
+    pub fn new_native_binder() -> Result<Strong<dyn IKeystoreKeyInfo>> {
+        Ok(BnKeystoreKeyInfo::new_binder(
+            Self,
+            BinderFeatures { set_requesting_sid: true, ..BinderFeatures::default() },
+        ))
+    }
+
+    /// Loads the given components of the given key on behalf of the caller, checking that the
+    /// caller has the given permission for the key.
+    fn load_key_entry(
+        key: &KeyDescriptor,
+        load_bits: KeyEntryLoadBits,
+        key_perm: KeyPerm,
+    ) -> Result<(KeyIdGuard, KeyEntry)> {
+        Self::load_key_entry_with(key, load_bits, |k, av| check_key_permission(key_perm, k, &av))
+    }
+
+    /// Like `load_key_entry`, but the permission check is performed by `check_permission`.
+    fn load_key_entry_with(
+        key: &KeyDescriptor,
+        load_bits: KeyEntryLoadBits,
+        check_permission: impl Fn(&KeyDescriptor, Option<KeyPermSet>) -> Result<()>,
+    ) -> Result<(KeyIdGuard, KeyEntry)> {
+        let caller_uid = ThreadState::get_calling_uid();
+
+        let super_key =
+            SUPER_KEY.read().unwrap().get_per_boot_key_by_user_id(uid_to_android_user(caller_uid));
+
+        DB.with(|db| {
+            LEGACY_IMPORTER.with_try_import(key, caller_uid, super_key, || {
+                db.borrow_mut()?.load_key_entry(
+                    key,
+                    KeyType::Client,
+                    load_bits,
+                    caller_uid,
+                    &check_permission,
+                )
+            })
+        })
+        .context("In load_key_entry.")
+    }
+
+    fn get_public_key(key: &KeyDescriptor, format: PublicKeyFormat) -> Result<Vec<u8>> {
+        let (_, key_entry) = Self::load_key_entry(key, KeyEntryLoadBits::PUBLIC, KeyPerm::GetInfo)
+            .context("In get_public_key.")?;
+
+        // Keys with a certificate always carry their public key in the certificate. Keys that
+        // were imported without certificate have their public key recorded in the metadata.
+        let spki = match key_entry.cert() {
+            Some(cert) => parse_public_key_from_certificate(cert)
+                .context("In get_public_key: Failed to parse certificate.")?,
+            None => key_entry
+                .metadata()
+                .subject_public_key_info()
+                .cloned()
+                .ok_or(Error::Rc(ResponseCode::KEY_NOT_FOUND))
+                .context("In get_public_key: Public key is not known.")?,
+        };
+
+        match format {
+            PublicKeyFormat::DER => Ok(spki),
+            PublicKeyFormat::PEM => Ok(der_to_pem("PUBLIC KEY", &spki).into_bytes()),
+            _ => Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+                .context(format!("In get_public_key: Unknown public key format {:?}.", format)),
+        }
+    }
+
+    fn get_key_characteristics(key: &KeyDescriptor) -> Result<KeyCharacteristicsInfo> {
+        let (key_id_guard, key_entry) =
+            Self::load_key_entry(key, KeyEntryLoadBits::NONE, KeyPerm::GetInfo)
+                .context("In get_key_characteristics.")?;
+
+        let km_uuid = *key_entry.km_uuid();
+        let key_security_level = get_keymint_dev_by_uuid(&km_uuid)
+            .map(|(_, hw_info)| hw_info.securityLevel)
+            .unwrap_or(SecurityLevel::SOFTWARE);
+        let modification_time_ms = key_entry
+            .metadata()
+            .creation_date()
+            .map(|d| d.to_millis_epoch())
+            .ok_or(Error::Rc(ResponseCode::VALUE_CORRUPTED))
+            .context("In get_key_characteristics: Trying to get creation date.")?;
+
+        let mut last_used = key_entry.metadata().last_used_date().copied();
+        let mut use_count = key_entry.metadata().use_count().copied().unwrap_or(0);
+        if let Some(pending) = KEY_USAGE.pending_usage(key_id_guard.id()) {
+            last_used = Some(pending.last_used);
+            use_count += pending.use_count;
+        }
+
+        let app_metadata = key_entry.metadata().app_metadata().cloned();
+
+        let (hardware_enforced, keystore_enforced): (Vec<_>, Vec<_>) =
+            key_entry.into_key_parameters().into_iter().partition(|p| {
+                matches!(
+                    *p.security_level(),
+                    SecurityLevel::TRUSTED_ENVIRONMENT | SecurityLevel::STRONGBOX
+                )
+            });
+
+        Ok(KeyCharacteristicsInfo {
+            key: KeyDescriptor {
+                domain: Domain::KEY_ID,
+                nspace: key_id_guard.id(),
+                ..Default::default()
+            },
+            keySecurityLevel: key_security_level,
+            securityLevelUuid: km_uuid.to_vec(),
+            hardwareEnforced: hardware_enforced
+                .into_iter()
+                .map(|p| p.into_authorization().keyParameter)
+                .collect(),
+            keystoreEnforced: keystore_enforced
+                .into_iter()
+                .map(|p| p.into_authorization().keyParameter)
+                .collect(),
+            modificationTimeMs: modification_time_ms,
+            lastUsedTimeMs: last_used.map(|d| d.to_millis_epoch()).unwrap_or(0),
+            useCount: use_count,
+            appMetadata: app_metadata,
+        })
+    }
+
+    fn set_app_metadata(key: &KeyDescriptor, app_metadata: Option<&[u8]>) -> Result<()> {
+        if app_metadata.map_or(false, |m| m.len() > MAX_APP_METADATA_SIZE) {
+            return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+                .context("In set_app_metadata: App metadata too large.");
+        }
+        // App metadata belongs to the owner of the key, so grants do not give access to it,
+        // regardless of the permissions granted.
+        if key.domain == Domain::GRANT {
+            return Err(Error::Rc(ResponseCode::PERMISSION_DENIED))
+                .context("In set_app_metadata: Only the owner may set app metadata.");
+        }
+        let (key_id_guard, _) = Self::load_key_entry_with(key, KeyEntryLoadBits::NONE, |k, _| {
+            check_key_permission(KeyPerm::Update, k, &None)
+        })
+        .context("In set_app_metadata.")?;
+        DB.with(|db| db.borrow_mut()?.set_app_metadata(&key_id_guard, app_metadata))
+            .context("In set_app_metadata.")
+    }
+
+    fn list_entries_ordered(
+        domain: Domain,
+        namespace: i64,
+        order: KeyEntryOrder,
+    ) -> Result<Vec<KeyEntrySummary>> {
+        let k = check_list_permission(domain, namespace).context("In list_entries_ordered.")?;
+
+        let entries = DB
+            .with(|db| db.borrow_mut()?.list_with_dates(k.domain, k.nspace, KeyType::Client))
+            .context("In list_entries_ordered: Trying to list keystore database.")?;
+        let mut summaries: Vec<KeyEntrySummary> = entries
+            .into_iter()
+            .map(|entry| {
+                let ListedKeyEntry { id, alias, creation_date, mut last_used_date, origin } = entry;
+                if let Some(pending) = KEY_USAGE.pending_usage(id) {
+                    last_used_date = Some(pending.last_used);
+                }
+                KeyEntrySummary {
+                    key: KeyDescriptor {
+                        domain: k.domain,
+                        nspace: k.nspace,
+                        alias: Some(alias),
+                        blob: None,
+                    },
+                    creationTimeMs: creation_date.map_or(0, |d| d.to_millis_epoch()),
+                    lastUsedTimeMs: last_used_date.map_or(0, |d| d.to_millis_epoch()),
+                    origin: origin.unwrap_or(-1),
+                }
+            })
+            .collect();
+
+        // Legacy keys that were not imported into the database yet have no recorded dates.
+        let legacy = LEGACY_IMPORTER
+            .list_uid(k.domain, k.nspace)
+            .context("In list_entries_ordered: Trying to list legacy keys.")?;
+        for key in legacy {
+            if !summaries.iter().any(|s| s.key.alias == key.alias) {
+                summaries.push(KeyEntrySummary {
+                    key,
+                    creationTimeMs: 0,
+                    lastUsedTimeMs: 0,
+                    origin: -1,
+                });
+            }
+        }
+
+        match order {
+            KeyEntryOrder::ALIAS => summaries.sort_by(|a, b| a.key.alias.cmp(&b.key.alias)),
+            KeyEntryOrder::CREATION_TIME => summaries.sort_by(|a, b| {
+                a.creationTimeMs.cmp(&b.creationTimeMs).then_with(|| a.key.alias.cmp(&b.key.alias))
+            }),
+            KeyEntryOrder::LAST_USED_TIME => summaries.sort_by(|a, b| {
+                a.lastUsedTimeMs.cmp(&b.lastUsedTimeMs).then_with(|| a.key.alias.cmp(&b.key.alias))
+            }),
+            _ => {
+                return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+                    .context(format!("In list_entries_ordered: Unknown order {:?}.", order))
+            }
+        }
+        Ok(summaries)
+    }
+}
+
+impl Interface for KeyInfo {}
+
+impl IKeystoreKeyInfo for KeyInfo {
+    fn getPublicKey(&self, key: &KeyDescriptor, format: PublicKeyFormat) -> BinderResult<Vec<u8>> {
+        let _wp = wd::watch_millis("IKeystoreKeyInfo::getPublicKey", 500);
+        map_or_log_err(Self::get_public_key(key, format), Ok)
+    }
+
+    fn getKeyCharacteristics(&self, key: &KeyDescriptor) -> BinderResult<KeyCharacteristicsInfo> {
+        let _wp = wd::watch_millis("IKeystoreKeyInfo::getKeyCharacteristics", 500);
+        map_or_log_err(Self::get_key_characteristics(key), Ok)
+    }
+
+    fn setAppMetadata(&self, key: &KeyDescriptor, app_metadata: Option<&[u8]>) -> BinderResult<()> {
+        let _wp = wd::watch_millis("IKeystoreKeyInfo::setAppMetadata", 500);
+        map_or_log_err(Self::set_app_metadata(key, app_metadata), Ok)
+    }
+
+    fn listEntriesOrdered(
+        &self,
+        domain: Domain,
+        namespace: i64,
+        order: KeyEntryOrder,
+    ) -> BinderResult<Vec<KeyEntrySummary>> {
+        let _wp = wd::watch_millis("IKeystoreKeyInfo::listEntriesOrdered", 500);
+        map_or_log_err(Self::list_entries_ordered(domain, namespace, order), Ok)
+    }
+}
@@ -1314,6 +1314,22 @@ mod storage_tests {
         Ok(())
     }
 
+    /// Test storing a KeyParameter with the Curve 25519 EC curve in the database and reading it
+    /// back. Ed25519 and X25519 keys share this curve.
+    #[test]
+    fn test_to_sql_ec_curve_25519() -> Result<()> {
+        let db = init_db()?;
+        let kp = KeyParameter::new(
+            KeyParameterValue::EcCurve(EcCurve::CURVE_25519),
+            SecurityLevel::TRUSTED_ENVIRONMENT,
+        );
+        store_keyparameter(&db, 1, &kp)?;
+        let key_param = query_from_keyparameter(&db)?;
+        assert_eq!(Tag::EC_CURVE, key_param.get_tag());
+        assert_eq!(kp.key_parameter_value(), key_param.key_parameter_value());
+        Ok(())
+    }
+
     /// Test storing a KeyParameter (with key parameter value which is of i32) in the database
     #[test]
     fn test_to_sql_i32() -> Result<()> {
@@ -0,0 +1,123 @@
+// Copyright 2021, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module records the usage of keys, i.e., the time of the last use and the number of
+//! operations started with a key. Recording a use happens on the hot path of every operation,
+//! so uses are accumulated in memory and written to the database in batches by a low priority
+//! job on the async task. A batch is written once enough keys were used, or by a timer at most
+//! `FLUSH_INTERVAL` after its first use, even if no further key is used. Uses that were not
+//! written yet are lost when Keystore restarts, which is acceptable for statistics.
+
+use crate::database::DateTime;
+use crate::globals::{ASYNC_TASK, DB};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Number of keys with pending usage records at which the records are written to the database.
+const FLUSH_THRESHOLD: usize = 32;
+
+/// Maximal time for which usage records are held back before they are written to the database.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// The usage of a key since its usage was last written to the database.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyUsage {
+    /// Time of the last use.
+    pub last_used: DateTime,
+    /// Number of uses.
+    pub use_count: i64,
+}
+
+#[derive(Default)]
+struct KeyUsageTrackerState {
+    pending: HashMap<i64, KeyUsage>,
+    /// True while a timer is running that schedules the pending records to be written.
+    timer_armed: bool,
+}
+
+/// Accumulates key usage records and writes them to the database in batches.
+#[derive(Default)]
+pub struct KeyUsageTracker {
+    state: Mutex<KeyUsageTrackerState>,
+}
+
+impl KeyUsageTracker {
+    /// Records a use of the given key. If enough records accumulated, a job writing all pending
+    /// records to the database is scheduled right away. Otherwise it is scheduled by a timer
+    /// that is armed with the first pending record.
+    pub fn record_use(&'static self, key_id: i64) {
+        let now = match DateTime::now() {
+            Ok(now) => now,
+            Err(e) => {
+                log::error!("In record_use: Failed to get current time: {:?}", e);
+                return;
+            }
+        };
+        let mut state = self.state.lock().unwrap();
+        state
+            .pending
+            .entry(key_id)
+            .and_modify(|usage| {
+                usage.last_used = now;
+                usage.use_count += 1;
+            })
+            .or_insert(KeyUsage { last_used: now, use_count: 1 });
+        if state.pending.len() >= FLUSH_THRESHOLD {
+            ASYNC_TASK.queue_lo(move |_| self.flush());
+        } else if !state.timer_armed {
+            state.timer_armed = self.arm_timer();
+        }
+    }
+
+    /// Starts a timer that schedules the pending records to be written after `FLUSH_INTERVAL`.
+    /// Returns false if the timer could not be started, in which case the next use retries.
+    fn arm_timer(&'static self) -> bool {
+        let builder = std::thread::Builder::new().name("keystore2_key_usage_flush".into());
+        let spawned = builder.spawn(move || {
+            std::thread::sleep(FLUSH_INTERVAL);
+            self.state.lock().unwrap().timer_armed = false;
+            ASYNC_TASK.queue_lo(move |_| self.flush());
+        });
+        match spawned {
+            Ok(_) => true,
+            Err(e) => {
+                log::error!("In arm_timer: Failed to start flush timer: {:?}", e);
+                false
+            }
+        }
+    }
+
+    /// Returns the usage of the given key that was not written to the database yet.
+    pub fn pending_usage(&self, key_id: i64) -> Option<KeyUsage> {
+        self.state.lock().unwrap().pending.get(&key_id).copied()
+    }
+
+    fn flush(&self) {
+        let records: Vec<(i64, DateTime, i64)> = {
+            let mut state = self.state.lock().unwrap();
+            state
+                .pending
+                .drain()
+                .map(|(key_id, usage)| (key_id, usage.last_used, usage.use_count))
+                .collect()
+        };
+        if records.is_empty() {
+            return;
+        }
+        if let Err(e) = DB.with(|db| db.borrow_mut()?.update_key_usage(&records)) {
+            log::error!("In flush: Failed to store key usage: {:?}", e);
+        }
+    }
+}
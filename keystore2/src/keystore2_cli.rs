@@ -0,0 +1,204 @@
+// Copyright 2021, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This crate implements `keystore2_cli`, a command line tool for inspecting and maintaining
+//! Keystore 2.0 during bring-up and debugging. It talks to Keystore over binder, so it is
+//! subject to the same permission checks as any other client. Most commands need to run as
+//! root. The tool refuses to run on non-debuggable builds.
+
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
+    SecurityLevel::SecurityLevel,
+};
+use android_security_maintenance::aidl::android::security::maintenance::{
+    IKeystoreMaintenance::IKeystoreMaintenance,
+};
+use android_security_remoteprovisioning::aidl::android::security::remoteprovisioning::{
+    IRemoteProvisioning::IRemoteProvisioning,
+};
+use android_system_keystore2::aidl::android::system::keystore2::{
+    Domain::Domain, IKeystoreService::IKeystoreService, KeyDescriptor::KeyDescriptor,
+};
+use anyhow::{anyhow, bail, Context, Result};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+static KS2_SERVICE_NAME: &str = "android.system.keystore2.IKeystoreService/default";
+static MAINTENANCE_SERVICE_NAME: &str = "android.security.maintenance";
+static REMOTE_PROVISIONING_SERVICE_NAME: &str = "android.security.remoteprovisioning";
+
+static USAGE: &str = "\
+Usage: keystore2_cli <command> [<args>]
+
+Commands:
+  list <domain> <namespace>             Lists the keys of a namespace with their metadata.
+  delete <domain> <namespace> <alias>   Deletes a key.
+  grants <domain> <namespace> <alias>   Lists the grants of a key.
+  rkp-status <tee|strongbox> [<days>]   Shows the state of the remotely provisioned key pool,
+                                        counting keys expiring within <days> (default 3).
+  run-maintenance                       Starts all deferred background work and the key
+                                        garbage collector right away.
+
+<domain> is `app` or `selinux`. For `app`, Keystore always uses the uid of the caller, so
+<namespace> is ignored.";
+
+fn parse_domain(domain: &str) -> Result<Domain> {
+    match domain {
+        "app" => Ok(Domain::APP),
+        "selinux" => Ok(Domain::SELINUX),
+        _ => Err(anyhow!("Unknown domain {:?}.", domain)),
+    }
+}
+
+fn parse_key_descriptor(args: &[String]) -> Result<KeyDescriptor> {
+    match args {
+        [domain, nspace, alias] => Ok(KeyDescriptor {
+            domain: parse_domain(domain)?,
+            nspace: nspace.parse().context("Invalid namespace.")?,
+            alias: Some(alias.clone()),
+            blob: None,
+        }),
+        _ => bail!("Expected <domain> <namespace> <alias>."),
+    }
+}
+
+fn get_keystore_service() -> Result<binder::Strong<dyn IKeystoreService>> {
+    binder::get_interface(KS2_SERVICE_NAME).context("Failed to connect to Keystore.")
+}
+
+fn get_maintenance_service() -> Result<binder::Strong<dyn IKeystoreMaintenance>> {
+    binder::get_interface(MAINTENANCE_SERVICE_NAME)
+        .context("Failed to connect to the Keystore maintenance service.")
+}
+
+fn list(args: &[String]) -> Result<()> {
+    let (domain, nspace) = match args {
+        [domain, nspace] => (parse_domain(domain)?, nspace.parse().context("Invalid namespace.")?),
+        _ => bail!("Expected <domain> <namespace>."),
+    };
+    let service = get_keystore_service()?;
+    let entries = service.listEntries(domain, nspace).context("Failed to list entries.")?;
+    for entry in &entries {
+        let alias = entry.alias.as_deref().unwrap_or("<no alias>");
+        match service.getKeyEntry(entry) {
+            Ok(response) => {
+                let metadata = &response.metadata;
+                println!(
+                    "{}: {:?}, modified at {} ms, certificate: {} bytes, chain: {} bytes",
+                    alias,
+                    metadata.keySecurityLevel,
+                    metadata.modificationTimeMs,
+                    metadata.certificate.as_ref().map_or(0, Vec::len),
+                    metadata.certificateChain.as_ref().map_or(0, Vec::len),
+                );
+                for authorization in &metadata.authorizations {
+                    println!(
+                        "    {:?} {:?}: {:?}",
+                        authorization.securityLevel,
+                        authorization.keyParameter.tag,
+                        authorization.keyParameter.value
+                    );
+                }
+            }
+            Err(e) => println!("{}: failed to load entry: {:?}", alias, e),
+        }
+    }
+    println!("{} entries.", entries.len());
+    Ok(())
+}
+
+fn delete(args: &[String]) -> Result<()> {
+    let key = parse_key_descriptor(args)?;
+    get_keystore_service()?.deleteKey(&key).context("Failed to delete key.")?;
+    println!("Deleted {:?}.", key.alias.unwrap_or_default());
+    Ok(())
+}
+
+fn grants(args: &[String]) -> Result<()> {
+    let key = parse_key_descriptor(args)?;
+    let grants = get_maintenance_service()?.listGrants(&key).context("Failed to list grants.")?;
+    for grant in &grants {
+        let grantee = if grant.granteeUid >= 0 {
+            format!("uid {}", grant.granteeUid)
+        } else {
+            format!("namespace {}", grant.granteeNamespace)
+        };
+        let expiry = match grant.expiresAtMillis {
+            0 => "never expires".to_string(),
+            millis => format!("expires at {} ms", millis),
+        };
+        println!("{}: access vector {:#x}, {}", grantee, grant.accessVector, expiry);
+    }
+    println!("{} grants.", grants.len());
+    Ok(())
+}
+
+fn rkp_status(args: &[String]) -> Result<()> {
+    let (sec_level, days) = match args {
+        [sec_level] => (sec_level, 3),
+        [sec_level, days] => (sec_level, days.parse().context("Invalid number of days.")?),
+        _ => bail!("Expected <tee|strongbox> [<days>]."),
+    };
+    let sec_level = match sec_level.as_str() {
+        "tee" => SecurityLevel::TRUSTED_ENVIRONMENT,
+        "strongbox" => SecurityLevel::STRONGBOX,
+        _ => bail!("Unknown security level {:?}.", sec_level),
+    };
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).context("Invalid system time.")?;
+    let expired_by = now.as_millis() as i64 + days * 24 * 60 * 60 * 1000;
+    let service: binder::Strong<dyn IRemoteProvisioning> =
+        binder::get_interface(REMOTE_PROVISIONING_SERVICE_NAME)
+            .context("Failed to connect to the remote provisioning service.")?;
+    let status =
+        service.getPoolStatus(expired_by, sec_level).context("Failed to get pool status.")?;
+    println!("Total keys:      {}", status.total);
+    println!("Attested keys:   {}", status.attested);
+    println!("Unassigned keys: {}", status.unassigned);
+    println!("Expiring within {} days: {}", days, status.expiring);
+    Ok(())
+}
+
+fn run_maintenance() -> Result<()> {
+    get_maintenance_service()?
+        .runDeferredMaintenance()
+        .context("Failed to run deferred maintenance.")?;
+    println!("Started deferred maintenance and the key garbage collector.");
+    Ok(())
+}
+
+fn main() {
+    let debuggable = rustutils::system_properties::read("ro.debuggable")
+        .ok()
+        .flatten()
+        .map_or(false, |value| value == "1");
+    if !debuggable {
+        eprintln!("keystore2_cli is only available on debuggable builds.");
+        std::process::exit(1);
+    }
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let result = match args.split_first() {
+        Some((command, args)) => match command.as_str() {
+            "list" => list(args),
+            "delete" => delete(args),
+            "grants" => grants(args),
+            "rkp-status" => rkp_status(args),
+            "run-maintenance" if args.is_empty() => run_maintenance(),
+            _ => Err(anyhow!("Unknown command {:?}.\n\n{}", command, USAGE)),
+        },
+        None => Err(anyhow!("{}", USAGE)),
+    };
+    if let Err(e) = result {
+        eprintln!("{:?}", e);
+        std::process::exit(1);
+    }
+}
@@ -14,21 +14,40 @@
 
 //! This crate implements the Keystore 2.0 service entry point.
 
+use keystore2::async_keygen::AsyncKeyGeneration;
+use keystore2::blob_envelope;
+use keystore2::blob_upgrade;
+use keystore2::capabilities::Capabilities;
+use keystore2::config;
 use keystore2::entropy;
-use keystore2::globals::ENFORCEMENTS;
+use keystore2::globals::{DB, ENFORCEMENTS};
+use keystore2::hal_health;
+use keystore2::idle_maintenance;
+use keystore2::key_import::KeyImport;
+use keystore2::key_info::KeyInfo;
+use keystore2::legacy_shadow;
 use keystore2::maintenance::Maintenance;
 use keystore2::metrics::Metrics;
 use keystore2::metrics_store;
+use keystore2::namespaces;
+use keystore2::one_shot_operations::OneShotOperations;
 use keystore2::remote_provisioning::{
     RemoteProvisioningService, RemotelyProvisionedKeyPoolService,
 };
+use keystore2::safe_mode;
 use keystore2::service::KeystoreService;
+use keystore2::shared_memory_operations::SharedMemoryOperations;
 use keystore2::{apc::ApcManager, shared_secret_negotiation};
 use keystore2::{authorization::AuthorizationManager, id_rotation::IdRotationState};
 use legacykeystore::LegacyKeystore;
-use log::{error, info};
+use log::{error, info, warn};
 use rusqlite::trace as sqlite_trace;
-use std::{os::raw::c_int, panic, path::Path, sync::mpsc::channel};
+use std::{
+    os::raw::c_int,
+    panic,
+    path::{Path, PathBuf},
+    sync::mpsc::channel,
+};
 
 static KS2_SERVICE_NAME: &str = "android.system.keystore2.IKeystoreService/default";
 static APC_SERVICE_NAME: &str = "android.security.apc";
@@ -39,8 +58,23 @@ static REMOTELY_PROVISIONED_KEY_POOL_SERVICE_NAME: &str =
     "android.security.remoteprovisioning.IRemotelyProvisionedKeyPool";
 static USER_MANAGER_SERVICE_NAME: &str = "android.security.maintenance";
 static LEGACY_KEYSTORE_SERVICE_NAME: &str = "android.security.legacykeystore";
+static SHARED_MEMORY_OPERATIONS_SERVICE_NAME: &str = "android.security.operation";
+static ONE_SHOT_OPERATIONS_SERVICE_NAME: &str = "android.security.operation.oneshot";
+static KEY_IMPORT_SERVICE_NAME: &str = "android.security.keyimport";
+static KEY_INFO_SERVICE_NAME: &str = "android.security.keyinfo";
+static CAPABILITIES_SERVICE_NAME: &str = "android.security.capabilities";
+static ASYNC_KEY_GENERATION_SERVICE_NAME: &str = "android.security.asynckeygen";
+
+/// Command line option that overrides the database directory.
+static DB_DIR_FLAG: &str = "--db-dir";
+/// Environment variable that overrides the database directory.
+static DB_DIR_ENV_VAR: &str = "KEYSTORE2_DB_DIR";
+/// Command line option that sets the maximal number of binder threads.
+static BINDER_THREADS_FLAG: &str = "--binder-threads";
 
 /// Keystore 2.0 takes one argument which is a path indicating its designated working directory.
+/// It can be overridden with `--db-dir <dir>` or the environment variable `KEYSTORE2_DB_DIR`.
+/// The maximal number of binder threads can be set with `--binder-threads <n>`.
 fn main() {
     // Initialize android logging.
     android_logger::init_once(
@@ -71,18 +105,51 @@ fn main() {
     // Write/update keystore.crash_count system property.
     metrics_store::update_keystore_crash_sysprop();
 
+    // Device specific defaults of the tunables. The system properties are read on demand.
+    if let Err(e) = config::load_config_file(Path::new(config::CONFIG_FILE_PATH)) {
+        log::error!("Failed to load config file: {:?}", e);
+    }
+
     // Keystore 2.0 cannot change to the database directory (typically /data/misc/keystore) on
     // startup as Keystore 1.0 did because Keystore 2.0 is intended to run much earlier than
     // Keystore 1.0. Instead we set a global variable to the database path.
     // For the ground truth check the service startup rule for init (typically in keystore2.rc).
-    let id_rotation_state = if let Some(dir) = args.next() {
-        let db_path = Path::new(&dir);
-        *keystore2::globals::DB_PATH.write().expect("Could not lock DB_PATH.") =
-            db_path.to_path_buf();
-        IdRotationState::new(db_path)
-    } else {
-        panic!("Must specify a database directory.");
+    // The directory can be overridden with the DB_DIR_FLAG option or the DB_DIR_ENV_VAR
+    // environment variable, e.g., to run integration tests or to bring Keystore up against a
+    // scratch directory when the regular database is unusable.
+    let mut db_dir_override = std::env::var_os(DB_DIR_ENV_VAR).map(PathBuf::from);
+    let mut db_dir = None;
+    let mut binder_threads = config::BINDER_THREADS.get();
+    while let Some(arg) = args.next() {
+        if arg == DB_DIR_FLAG {
+            let dir = args.next().unwrap_or_else(|| panic!("{} requires a value.", DB_DIR_FLAG));
+            db_dir_override = Some(PathBuf::from(dir));
+        } else if arg == BINDER_THREADS_FLAG {
+            binder_threads = args
+                .next()
+                .and_then(|n| n.parse().ok())
+                .unwrap_or_else(|| panic!("{} requires a number.", BINDER_THREADS_FLAG));
+        } else if db_dir.is_none() {
+            db_dir = Some(PathBuf::from(arg));
+        } else {
+            panic!("Unexpected argument {:?}.", arg);
+        }
+    }
+    let db_path = match (db_dir_override, db_dir) {
+        (Some(dir_override), dir) => {
+            warn!("Using database directory {:?} instead of {:?}.", dir_override, dir);
+            std::fs::create_dir_all(&dir_override).unwrap_or_else(|e| {
+                panic!("Failed to create database directory {:?}: {:?}", dir_override, e)
+            });
+            dir_override
+        }
+        (None, Some(dir)) => dir,
+        (None, None) => panic!("Must specify a database directory."),
     };
+    // Must run before anything that may crash Keystore during startup.
+    safe_mode::on_startup(&db_path);
+    keystore2::globals::set_db_path(&db_path);
+    let id_rotation_state = IdRotationState::new(&db_path);
 
     let (confirmation_token_sender, confirmation_token_receiver) = channel();
 
@@ -91,6 +158,17 @@ fn main() {
     entropy::register_feeder();
     shared_secret_negotiation::perform_shared_secret_negotiation();
 
+    if let Err(e) = DB.with(|db| blob_envelope::initialize(&mut db.borrow_mut()?)) {
+        error!("Failed to initialize key blob envelope encryption: {:?}", e);
+    }
+
+    // Well-known namespaces are resolved before any client can connect.
+    namespaces::bootstrap();
+
+    if binder_threads > 0 {
+        info!("Limiting the thread pool to {} binder threads.", binder_threads);
+        binder::ProcessState::set_thread_pool_max_thread_count(binder_threads as u32);
+    }
     info!("Starting thread pool now.");
     binder::ProcessState::start_thread_pool();
 
@@ -137,51 +215,116 @@ fn main() {
         panic!("Failed to register service {} because of {:?}.", METRICS_SERVICE_NAME, e);
     });
 
-    // Devices with KS2 and KM 1.0 may not have any IRemotelyProvisionedComponent HALs at all. Do
-    // not panic if new_native_binder returns failure because it could not find the TEE HAL.
-    if let Ok(remote_provisioning_service) = RemoteProvisioningService::new_native_binder() {
-        binder::add_service(
-            REMOTE_PROVISIONING_SERVICE_NAME,
-            remote_provisioning_service.as_binder(),
-        )
-        .unwrap_or_else(|e| {
-            panic!(
-                "Failed to register service {} because of {:?}.",
-                REMOTE_PROVISIONING_SERVICE_NAME, e
-            );
-        });
-    }
+    // The following services extend the platform interfaces. Keystore works without them, e.g.,
+    // if the device policy does not allow registering them.
+    add_optional_service(SHARED_MEMORY_OPERATIONS_SERVICE_NAME, || {
+        Ok(SharedMemoryOperations::new_native_binder()?.as_binder())
+    });
+    add_optional_service(ONE_SHOT_OPERATIONS_SERVICE_NAME, || {
+        Ok(OneShotOperations::new_native_binder()?.as_binder())
+    });
+    add_optional_service(KEY_IMPORT_SERVICE_NAME, || {
+        Ok(KeyImport::new_native_binder()?.as_binder())
+    });
+    add_optional_service(KEY_INFO_SERVICE_NAME, || Ok(KeyInfo::new_native_binder()?.as_binder()));
+    add_optional_service(CAPABILITIES_SERVICE_NAME, || {
+        let binder = Capabilities::new_native_binder()?.as_binder();
+        Capabilities::probe_all_in_background();
+        Ok(binder)
+    });
+    add_optional_service(ASYNC_KEY_GENERATION_SERVICE_NAME, || {
+        Ok(AsyncKeyGeneration::new_native_binder()?.as_binder())
+    });
 
-    // Even if the IRemotelyProvisionedComponent HAL is implemented, it doesn't mean that the keys
-    // may be fetched via the key pool. The HAL must be a new version that exports a unique id. If
-    // none of the HALs support this, then the key pool service is not published.
-    match RemotelyProvisionedKeyPoolService::new_native_binder() {
-        Ok(key_pool_service) => {
+    if !safe_mode::skip("remote key provisioning") {
+        // Devices with KS2 and KM 1.0 may not have any IRemotelyProvisionedComponent HALs at
+        // all. Do not panic if new_native_binder returns failure because it could not find the
+        // TEE HAL.
+        if let Ok(remote_provisioning_service) = RemoteProvisioningService::new_native_binder() {
             binder::add_service(
-                REMOTELY_PROVISIONED_KEY_POOL_SERVICE_NAME,
-                key_pool_service.as_binder(),
+                REMOTE_PROVISIONING_SERVICE_NAME,
+                remote_provisioning_service.as_binder(),
             )
             .unwrap_or_else(|e| {
                 panic!(
                     "Failed to register service {} because of {:?}.",
-                    REMOTELY_PROVISIONED_KEY_POOL_SERVICE_NAME, e
+                    REMOTE_PROVISIONING_SERVICE_NAME, e
                 );
             });
         }
-        Err(e) => log::info!("Not publishing IRemotelyProvisionedKeyPool service: {:?}", e),
+
+        // Even if the IRemotelyProvisionedComponent HAL is implemented, it doesn't mean that the
+        // keys may be fetched via the key pool. The HAL must be a new version that exports a
+        // unique id. If none of the HALs support this, then the key pool service is not
+        // published.
+        match RemotelyProvisionedKeyPoolService::new_native_binder() {
+            Ok(key_pool_service) => {
+                binder::add_service(
+                    REMOTELY_PROVISIONED_KEY_POOL_SERVICE_NAME,
+                    key_pool_service.as_binder(),
+                )
+                .unwrap_or_else(|e| {
+                    panic!(
+                        "Failed to register service {} because of {:?}.",
+                        REMOTELY_PROVISIONED_KEY_POOL_SERVICE_NAME, e
+                    );
+                });
+            }
+            Err(e) => log::info!("Not publishing IRemotelyProvisionedKeyPool service: {:?}", e),
+        }
     }
 
-    binder::add_service(LEGACY_KEYSTORE_SERVICE_NAME, legacykeystore.as_binder()).unwrap_or_else(
-        |e| {
-            panic!(
-                "Failed to register service {} because of {:?}.",
-                LEGACY_KEYSTORE_SERVICE_NAME, e
-            );
-        },
-    );
+    if !safe_mode::skip("legacy keystore blob store") {
+        binder::add_service(LEGACY_KEYSTORE_SERVICE_NAME, legacykeystore.as_binder())
+            .unwrap_or_else(|e| {
+                panic!(
+                    "Failed to register service {} because of {:?}.",
+                    LEGACY_KEYSTORE_SERVICE_NAME, e
+                );
+            });
+    }
 
     info!("Successfully registered Keystore 2.0 service.");
 
+    if !safe_mode::skip("key blob upgrade sweeps") {
+        blob_upgrade::schedule_upgrade_sweeps_if_required();
+    }
+    if !safe_mode::skip("key blob envelope migration") {
+        blob_envelope::schedule_migration_if_required();
+    }
+    if !safe_mode::skip("legacy shadow purge") {
+        legacy_shadow::schedule_purge_if_disabled();
+    }
+    if !safe_mode::skip("HAL health checks") {
+        hal_health::start();
+    }
+    if !safe_mode::skip("database vacuum") {
+        idle_maintenance::queue_lo("database vacuum", || {
+            if let Err(e) = DB.with(|db| db.borrow_mut()?.vacuum()) {
+                error!("Failed to vacuum the database: {:?}", e);
+            }
+        });
+    }
+
+    // Only count the startup as successful if Keystore survives starting all subsystems.
+    safe_mode::schedule_startup_completed(&db_path);
+
     info!("Joining thread pool now.");
     binder::ProcessState::join_thread_pool();
 }
+
+/// Creates and registers a service that Keystore can do without. Failures are logged instead of
+/// aborting the start of Keystore.
+fn add_optional_service<F>(name: &str, new_native_binder: F)
+where
+    F: FnOnce() -> anyhow::Result<binder::SpIBinder>,
+{
+    match new_native_binder() {
+        Ok(service) => {
+            if let Err(e) = binder::add_service(name, service) {
+                error!("Failed to register optional service {} because of {:?}.", name, e);
+            }
+        }
+        Err(e) => error!("Failed to create optional service {} because of {:?}.", name, e),
+    }
+}
@@ -35,6 +35,7 @@
 #include <chrono>
 
 #include "certificate_utils.h"
+#include "vendor_tag_policy.h"
 
 using ::aidl::android::hardware::security::keymint::Algorithm;
 using ::aidl::android::hardware::security::keymint::CreateKeyMintDevice;
@@ -59,7 +60,9 @@ namespace KMV1 = ::aidl::android::hardware::security::keymint;
 using namespace std::chrono_literals;
 using std::chrono::duration_cast;
 
-static const KMV1::Tag KM_TAG_FBE_ICE = static_cast<KMV1::Tag>((7 << 28) | 16201);
+// Wrapped operations that have not been used for this long are considered leaked and are
+// aborted when the device runs out of operation slots.
+static constexpr auto kMaxOperationIdleTime = 10min;
 
 // Utility functions
 
@@ -134,7 +137,8 @@ bool isKeyCreationParameter(const KMV1::KeyParameter& param) {
     case Tag::MAC_LENGTH:
         return true;
     default:
-        return false;
+        // Vendor tags are subject to the vendor tag policy during conversion.
+        return VendorTagPolicy::isVendorTag(static_cast<int32_t>(param.tag));
     }
 }
 
@@ -287,6 +291,12 @@ convertKeyParametersToLegacy(const std::vector<KeyParameter>& kps) {
     std::vector<V4_0::KeyParameter> legacyKps;
     legacyKps.reserve(kps.size());
     for (const auto& kp : kps) {
+        if (VendorTagPolicy::isVendorTag(static_cast<int32_t>(kp.tag))) {
+            if (auto p = VendorTagPolicy::get().toLegacy(kp)) {
+                legacyKps.push_back(std::move(*p));
+            }
+            continue;
+        }
         auto p = convertKeyParameterToLegacy(kp);
         if (p.tag != V4_0::Tag::INVALID) {
             legacyKps.push_back(std::move(p));
@@ -297,8 +307,17 @@ convertKeyParametersToLegacy(const std::vector<KeyParameter>& kps) {
 
 static std::vector<KeyParameter>
 convertKeyParametersFromLegacy(const std::vector<V4_0_KeyParameter>& legacyKps) {
-    std::vector<KeyParameter> kps(legacyKps.size());
-    std::transform(legacyKps.begin(), legacyKps.end(), kps.begin(), convertKeyParameterFromLegacy);
+    std::vector<KeyParameter> kps;
+    kps.reserve(legacyKps.size());
+    for (const auto& legacyKp : legacyKps) {
+        if (VendorTagPolicy::isVendorTag(static_cast<int32_t>(legacyKp.tag))) {
+            if (auto kp = VendorTagPolicy::get().fromLegacy(legacyKp)) {
+                kps.push_back(std::move(*kp));
+            }
+            continue;
+        }
+        kps.push_back(convertKeyParameterFromLegacy(legacyKp));
+    }
     return kps;
 }
 
@@ -421,6 +440,38 @@ OperationSlot::~OperationSlot() {
     }
 }
 
+void OperationSlotManager::registerOperation(const std::shared_ptr<KeyMintOperation>& operation) {
+    std::lock_guard<std::mutex> lock(mLiveOperationsMutex);
+    mLiveOperations.erase(std::remove_if(mLiveOperations.begin(), mLiveOperations.end(),
+                                         [](const auto& op) { return op.expired(); }),
+                          mLiveOperations.end());
+    mLiveOperations.push_back(operation);
+}
+
+size_t
+OperationSlotManager::reclaimStaleOperations(std::chrono::steady_clock::duration maxIdleTime) {
+    std::vector<std::shared_ptr<KeyMintOperation>> operations;
+    {
+        std::lock_guard<std::mutex> lock(mLiveOperationsMutex);
+        for (const auto& weakOp : mLiveOperations) {
+            if (auto op = weakOp.lock()) {
+                operations.push_back(std::move(op));
+            }
+        }
+    }
+    auto cutoff = std::chrono::steady_clock::now() - maxIdleTime;
+    size_t reclaimed = 0;
+    for (const auto& op : operations) {
+        if (op->abortIfIdleSince(cutoff)) {
+            reclaimed++;
+        }
+    }
+    if (reclaimed > 0) {
+        LOG(WARNING) << __func__ << ": Aborted " << reclaimed << " leaked operation(s).";
+    }
+    return reclaimed;
+}
+
 // KeyMintDevice implementation
 
 ScopedAStatus KeyMintDevice::getHardwareInfo(KeyMintHardwareInfo* _aidl_return) {
@@ -443,6 +494,16 @@ ScopedAStatus KeyMintDevice::addRngEntropy(const std::vector<uint8_t>& in_data)
     return convertErrorCode(result);
 }
 
+// KeyMaster 4.x has no notion of Curve 25519. Without an explicit check the EC_CURVE tag would be
+// dropped during parameter conversion and the legacy device would silently create a key on its
+// default curve instead.
+static bool requestsCurve25519(const std::vector<KeyParameter>& keyParams) {
+    return std::any_of(keyParams.begin(), keyParams.end(), [](const KeyParameter& kp) {
+        return kp.tag == Tag::EC_CURVE &&
+               kp.value.get<KeyParameterValue::Tag::ecCurve>() == KMV1::EcCurve::CURVE_25519;
+    });
+}
+
 ScopedAStatus KeyMintDevice::generateKey(const std::vector<KeyParameter>& inKeyParams,
                                          const std::optional<AttestationKey>& in_attestationKey,
                                          KeyCreationResult* out_creationResult) {
@@ -469,20 +530,18 @@ ScopedAStatus KeyMintDevice::generateKey(const std::vector<KeyParameter>& inKeyP
         }
     }
 
-    auto legacyKeyGenParams = convertKeyParametersToLegacy(extractGenerationParams(inKeyParams));
-    KMV1::ErrorCode errorCode;
+    if (requestsCurve25519(inKeyParams)) {
+        LOG(ERROR) << __func__ << ": Curve 25519 signing keys are not supported by KeyMaster 4.x.";
+        return convertErrorCode(KMV1::ErrorCode::UNSUPPORTED_EC_CURVE);
+    }
 
-    for (const auto& keyParam : inKeyParams) {
-        if((int32_t)keyParam.tag==(int32_t)KM_TAG_FBE_ICE) {
-            android::hardware::keymaster::V4_0::KeyParameter param1;
-            param1.tag = static_cast<::android::hardware::keymaster::V4_0::Tag>
-			(android::hardware::keymaster::V4_0::KM_TAG_FBE_ICE);
-            param1.f.boolValue = true;
-            legacyKeyGenParams.push_back(param1);
-            break;
-        }
+    if (auto error = checkKeymaster41Params(inKeyParams)) {
+        return convertErrorCode(*error);
     }
 
+    auto legacyKeyGenParams = convertKeyParametersToLegacy(extractGenerationParams(inKeyParams));
+    KMV1::ErrorCode errorCode;
+
     auto result = mDevice->generateKey(
         legacyKeyGenParams, [&](V4_0_ErrorCode error, const hidl_vec<uint8_t>& keyBlob,
                                 const V4_0_KeyCharacteristics& keyCharacteristics) {
@@ -516,6 +575,13 @@ ScopedAStatus KeyMintDevice::importKey(const std::vector<KeyParameter>& inKeyPar
                                        const std::vector<uint8_t>& in_inKeyData,
                                        const std::optional<AttestationKey>& /* in_attestationKey */,
                                        KeyCreationResult* out_creationResult) {
+    if (requestsCurve25519(inKeyParams)) {
+        LOG(ERROR) << __func__ << ": Curve 25519 keys cannot be imported into KeyMaster 4.x.";
+        return convertErrorCode(KMV1::ErrorCode::UNSUPPORTED_EC_CURVE);
+    }
+    if (auto error = checkKeymaster41Params(inKeyParams)) {
+        return convertErrorCode(*error);
+    }
     auto legacyKeyGENParams = convertKeyParametersToLegacy(extractGenerationParams(inKeyParams));
     auto legacyKeyFormat = convertKeyFormatToLegacy(in_inKeyFormat);
     KMV1::ErrorCode errorCode;
@@ -558,9 +624,14 @@ KeyMintDevice::importWrappedKey(const std::vector<uint8_t>& in_inWrappedKeyData,
     const std::vector<uint8_t>& wrappingKeyBlob =
         prefixedKeyBlobRemovePrefix(in_inPrefixedWrappingKeyBlob);
     if (prefixedKeyBlobIsSoftKeyMint(in_inPrefixedWrappingKeyBlob)) {
-        return softKeyMintDevice_->importWrappedKey(
+        auto ret = softKeyMintDevice_->importWrappedKey(
             in_inWrappedKeyData, wrappingKeyBlob, in_inMaskingKey, in_inUnwrappingParams,
             in_inPasswordSid, in_inBiometricSid, out_creationResult);
+        if (ret.isOk()) {
+            // The wrapped key lives where the wrapping key lives.
+            out_creationResult->keyBlob = keyBlobPrefix(out_creationResult->keyBlob, true);
+        }
+        return ret;
     }
 
     auto legacyUnwrappingParams = convertKeyParametersToLegacy(in_inUnwrappingParams);
@@ -579,6 +650,26 @@ KeyMintDevice::importWrappedKey(const std::vector<uint8_t>& in_inWrappedKeyData,
         LOG(ERROR) << __func__ << " transaction failed. " << result.description();
         return convertErrorCode(KMV1::ErrorCode::UNKNOWN_ERROR);
     }
+    if (errorCode == KMV1::ErrorCode::OK) {
+        // KeyMint returns a certificate for asymmetric wrapped keys, KeyMaster does not. The
+        // parameters of the wrapped key are only known from its characteristics.
+        std::vector<KeyParameter> keyParams;
+        for (const auto& characteristics : out_creationResult->keyCharacteristics) {
+            keyParams.insert(keyParams.end(), characteristics.authorizations.begin(),
+                             characteristics.authorizations.end());
+        }
+        auto cert = getCertificate(keyParams, out_creationResult->keyBlob);
+        if (std::holds_alternative<KMV1::ErrorCode>(cert)) {
+            auto code = std::get<KMV1::ErrorCode>(cert);
+            // We return OK in successful cases that do not generate a certificate.
+            if (code != KMV1::ErrorCode::OK) {
+                errorCode = code;
+                deleteKey(out_creationResult->keyBlob);
+            }
+        } else {
+            out_creationResult->certificateChain = std::get<std::vector<Certificate>>(cert);
+        }
+    }
     return convertErrorCode(errorCode);
 }
 
@@ -669,7 +760,14 @@ ScopedAStatus KeyMintDevice::beginInternal(KeyPurpose in_inPurpose,
         // the reserved slot becomes available.
         slot = OperationSlotManager::claimReservedSlot(mOperationSlots);
     } else {
-        if (auto opt_slot = OperationSlotManager::claimSlot(mOperationSlots)) {
+        auto opt_slot = OperationSlotManager::claimSlot(mOperationSlots);
+        // Operations whose owner went away without aborting them are only released once their
+        // last reference is dropped. Reclaim the ones that have been idle for too long before
+        // giving up.
+        if (!opt_slot && mOperationSlots->reclaimStaleOperations(kMaxOperationIdleTime) > 0) {
+            opt_slot = OperationSlotManager::claimSlot(mOperationSlots);
+        }
+        if (opt_slot) {
             slot = std::move(*opt_slot);
         } else {
             return convertErrorCode(V4_0_ErrorCode::TOO_MANY_OPERATIONS);
@@ -689,8 +787,10 @@ ScopedAStatus KeyMintDevice::beginInternal(KeyPurpose in_inPurpose,
                            if (error == V4_0_ErrorCode::OK) {
                                _aidl_return->challenge = operationHandle;
                                _aidl_return->params = convertKeyParametersFromLegacy(outParams);
-                               _aidl_return->operation = ndk::SharedRefBase::make<KeyMintOperation>(
+                               auto operation = ndk::SharedRefBase::make<KeyMintOperation>(
                                    mDevice, operationHandle, std::move(slot));
+                               mOperationSlots->registerOperation(operation);
+                               _aidl_return->operation = std::move(operation);
                            }
                        });
     if (!result.isOk()) {
@@ -706,21 +806,31 @@ ScopedAStatus KeyMintDevice::deviceLocked(bool passwordOnly,
     if (timestampToken.has_value()) {
         token = convertTimestampTokenToLegacy(timestampToken.value());
     }
+    if (!isKeymaster41_) {
+        // Keymaster 4.0 cannot be told. Keystore still enforces UNLOCKED_DEVICE_REQUIRED by
+        // means of its super keys.
+        return convertErrorCode(KMV1::ErrorCode::OK);
+    }
     auto ret = mDevice->deviceLocked(passwordOnly, token);
     if (!ret.isOk()) {
+        LOG(ERROR) << __func__ << " transaction failed. " << ret.description();
         return convertErrorCode(KMV1::ErrorCode::UNKNOWN_ERROR);
-    } else {
-        return convertErrorCode(KMV1::ErrorCode::OK);
     }
+    return convertErrorCode(static_cast<V4_0_ErrorCode>(ret));
 }
 
 ScopedAStatus KeyMintDevice::earlyBootEnded() {
+    if (!isKeymaster41_) {
+        // Keymaster 4.0 has no notion of early boot, and no early boot only keys can exist on
+        // it, see checkKeymaster41Params.
+        return convertErrorCode(KMV1::ErrorCode::OK);
+    }
     auto ret = mDevice->earlyBootEnded();
     if (!ret.isOk()) {
+        LOG(ERROR) << __func__ << " transaction failed. " << ret.description();
         return convertErrorCode(KMV1::ErrorCode::UNKNOWN_ERROR);
-    } else {
-        return convertErrorCode(KMV1::ErrorCode::OK);
     }
+    return convertErrorCode(static_cast<V4_0_ErrorCode>(ret));
 }
 
 ScopedAStatus
@@ -810,6 +920,8 @@ ScopedAStatus KeyMintDevice::sendRootOfTrust(const std::vector<uint8_t>& /* root
 ScopedAStatus KeyMintOperation::updateAad(const std::vector<uint8_t>& input,
                                           const std::optional<HardwareAuthToken>& optAuthToken,
                                           const std::optional<TimeStampToken>& optTimeStampToken) {
+    std::lock_guard<std::mutex> lock(mMutex);
+    mLastUsed = std::chrono::steady_clock::now();
     V4_0_HardwareAuthToken authToken = convertAuthTokenToLegacy(optAuthToken);
     V4_0_VerificationToken verificationToken = convertTimestampTokenToLegacy(optTimeStampToken);
 
@@ -850,6 +962,8 @@ ScopedAStatus KeyMintOperation::update(const std::vector<uint8_t>& input_raw,
                                        const std::optional<HardwareAuthToken>& optAuthToken,
                                        const std::optional<TimeStampToken>& optTimeStampToken,
                                        std::vector<uint8_t>* out_output) {
+    std::lock_guard<std::mutex> lock(mMutex);
+    mLastUsed = std::chrono::steady_clock::now();
     V4_0_HardwareAuthToken authToken = convertAuthTokenToLegacy(optAuthToken);
     V4_0_VerificationToken verificationToken = convertTimestampTokenToLegacy(optTimeStampToken);
 
@@ -900,6 +1014,8 @@ KeyMintOperation::finish(const std::optional<std::vector<uint8_t>>& in_input,
                          const std::optional<TimeStampToken>& in_timeStampToken,
                          const std::optional<std::vector<uint8_t>>& in_confirmationToken,
                          std::vector<uint8_t>* out_output) {
+    std::lock_guard<std::mutex> lock(mMutex);
+    mLastUsed = std::chrono::steady_clock::now();
     auto input_raw = in_input.value_or(std::vector<uint8_t>());
     auto input = getExtendedUpdateBuffer(input_raw);
     auto signature = in_signature.value_or(std::vector<uint8_t>());
@@ -930,6 +1046,24 @@ KeyMintOperation::finish(const std::optional<std::vector<uint8_t>>& in_input,
 }
 
 ScopedAStatus KeyMintOperation::abort() {
+    std::lock_guard<std::mutex> lock(mMutex);
+    return abortLocked();
+}
+
+bool KeyMintOperation::abortIfIdleSince(std::chrono::steady_clock::time_point cutoff) {
+    // An operation that is busy is in use, so it has not leaked.
+    std::unique_lock<std::mutex> lock(mMutex, std::try_to_lock);
+    if (!lock.owns_lock() || !mOperationSlot || mLastUsed >= cutoff) {
+        return false;
+    }
+    auto error = abortLocked();
+    if (!error.isOk()) {
+        LOG(WARNING) << __func__ << ": Failed to abort leaked operation: " << error.getMessage();
+    }
+    return true;
+}
+
+ScopedAStatus KeyMintOperation::abortLocked() {
     auto result = mDevice->abort(mOperationHandle);
     mOperationSlot = std::nullopt;
     if (!result.isOk()) {
@@ -1021,6 +1155,25 @@ static bool containsParam(const std::vector<KeyParameter>& keyParams, T ttag) {
     return static_cast<bool>(getParam(keyParams, ttag));
 }
 
+// Keymaster 4.0 does not know the tags introduced with Keymaster 4.1. Sending them anyway would
+// either fail with an obscure error or, worse, create a key that silently lacks the restriction.
+std::optional<KMV1::ErrorCode>
+KeyMintDevice::checkKeymaster41Params(const std::vector<KeyParameter>& keyParams) const {
+    if (isKeymaster41_) {
+        return std::nullopt;
+    }
+    if (containsParam(keyParams, KMV1::TAG_DEVICE_UNIQUE_ATTESTATION)) {
+        LOG(ERROR) << __func__ << ": Device unique attestation requires Keymaster 4.1.";
+        return KMV1::ErrorCode::CANNOT_ATTEST_IDS;
+    }
+    if (containsParam(keyParams, KMV1::TAG_EARLY_BOOT_ONLY) ||
+        containsParam(keyParams, KMV1::TAG_STORAGE_KEY)) {
+        LOG(ERROR) << __func__ << ": Early boot only and storage keys require Keymaster 4.1.";
+        return KMV1::ErrorCode::UNSUPPORTED_TAG;
+    }
+    return std::nullopt;
+}
+
 // Prefer the smallest.
 // If no options are found, return the first.
 template <typename T>
@@ -1433,11 +1586,21 @@ void KeyMintDevice::setNumFreeSlots(uint8_t numFreeSlots) {
     mOperationSlots->setNumFreeSlots(numFreeSlots);
 }
 
+size_t KeyMintDevice::reclaimStaleOperations(std::chrono::steady_clock::duration maxIdleTime) {
+    return mOperationSlots->reclaimStaleOperations(maxIdleTime);
+}
+
 // Constructors and helpers.
 
 KeyMintDevice::KeyMintDevice(sp<Keymaster> device, KeyMintSecurityLevel securityLevel)
     : mDevice(device), mOperationSlots(std::make_shared<OperationSlotManager>()),
       securityLevel_(securityLevel) {
+    auto halVersion = device->halVersion();
+    isKeymaster41_ = halVersion.majorVersion > 4 ||
+                     (halVersion.majorVersion == 4 && halVersion.minorVersion >= 1);
+    LOG(INFO) << "Wrapping Keymaster " << halVersion.majorVersion << "."
+              << halVersion.minorVersion << " for security level " << toString(securityLevel);
+
     if (securityLevel == KeyMintSecurityLevel::STRONGBOX) {
         setNumFreeSlots(3);
     } else {
@@ -23,6 +23,7 @@
 #include <aidl/android/hardware/security/sharedsecret/BnSharedSecret.h>
 #include <aidl/android/security/compat/BnKeystoreCompatService.h>
 #include <keymasterV4_1/Keymaster4.h>
+#include <chrono>
 #include <unordered_map>
 #include <variant>
 
@@ -50,6 +51,7 @@ using ::aidl::android::security::compat::BnKeystoreCompatService;
 using ::android::hardware::keymaster::V4_1::support::Keymaster;
 using ::ndk::ScopedAStatus;
 
+class KeyMintOperation;
 class OperationSlot;
 class OperationSlotManager;
 // An abstraction for a single operation slot.
@@ -80,6 +82,8 @@ class OperationSlotManager {
     uint8_t mNumFreeSlots;
     std::mutex mNumFreeSlotsMutex;
     std::mutex mReservedSlotMutex;
+    std::mutex mLiveOperationsMutex;
+    std::vector<std::weak_ptr<KeyMintOperation>> mLiveOperations;
 
   public:
     void setNumFreeSlots(uint8_t numFreeSlots);
@@ -87,6 +91,12 @@ class OperationSlotManager {
     claimSlot(std::shared_ptr<OperationSlotManager> operationSlots);
     static OperationSlot claimReservedSlot(std::shared_ptr<OperationSlotManager> operationSlots);
     void freeSlot();
+
+    // Keeps track of the given operation, so that it can be reclaimed if it leaks.
+    void registerOperation(const std::shared_ptr<KeyMintOperation>& operation);
+    // Aborts all live operations that have not been used for the given amount of time and
+    // returns how many were aborted.
+    size_t reclaimStaleOperations(std::chrono::steady_clock::duration maxIdleTime);
 };
 
 class KeyMintDevice : public aidl::android::hardware::security::keymint::BnKeyMintDevice {
@@ -153,12 +163,18 @@ class KeyMintDevice : public aidl::android::hardware::security::keymint::BnKeyMi
     getCertificate(const std::vector<KeyParameter>& keyParams, const std::vector<uint8_t>& keyBlob);
 
     void setNumFreeSlots(uint8_t numFreeSlots);
+    size_t reclaimStaleOperations(std::chrono::steady_clock::duration maxIdleTime);
 
   private:
     std::optional<KMV1_ErrorCode> signCertificate(const std::vector<KeyParameter>& keyParams,
                                                   const std::vector<uint8_t>& keyBlob, X509* cert);
+    std::optional<KMV1_ErrorCode>
+    checkKeymaster41Params(const std::vector<KeyParameter>& keyParams) const;
     KeyMintSecurityLevel securityLevel_;
 
+    // True if the wrapped device implements Keymaster 4.1 or newer.
+    bool isKeymaster41_;
+
     // Software-based KeyMint device used to implement ECDH.
     std::shared_ptr<IKeyMintDevice> softKeyMintDevice_;
 };
@@ -166,7 +182,8 @@ class KeyMintDevice : public aidl::android::hardware::security::keymint::BnKeyMi
 class KeyMintOperation : public aidl::android::hardware::security::keymint::BnKeyMintOperation {
   public:
     KeyMintOperation(::android::sp<Keymaster> device, uint64_t operationHandle, OperationSlot slot)
-        : mDevice(device), mOperationHandle(operationHandle), mOperationSlot(std::move(slot)) {}
+        : mDevice(device), mOperationHandle(operationHandle), mOperationSlot(std::move(slot)),
+          mLastUsed(std::chrono::steady_clock::now()) {}
     ~KeyMintOperation();
 
     ScopedAStatus updateAad(const std::vector<uint8_t>& input,
@@ -187,7 +204,13 @@ class KeyMintOperation : public aidl::android::hardware::security::keymint::BnKe
 
     ScopedAStatus abort();
 
+    // Aborts the operation if it still occupies a slot and was last used before the given
+    // point in time. Returns true if the operation was aborted.
+    bool abortIfIdleSince(std::chrono::steady_clock::time_point cutoff);
+
   private:
+    ScopedAStatus abortLocked();
+
     /**
      * Sets mUpdateBuffer to the given value.
      * @param data
@@ -205,6 +228,9 @@ class KeyMintOperation : public aidl::android::hardware::security::keymint::BnKe
     ::android::sp<Keymaster> mDevice;
     uint64_t mOperationHandle;
     std::optional<OperationSlot> mOperationSlot;
+    // Guards against concurrent reclamation while the operation is in use.
+    std::mutex mMutex;
+    std::chrono::steady_clock::time_point mLastUsed;
 };
 
 class SharedSecret : public aidl::android::hardware::security::sharedsecret::BnSharedSecret {
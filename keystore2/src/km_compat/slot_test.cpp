@@ -233,3 +233,34 @@ TEST(SlotTest, TestSlots) {
     result = begin(device, true);
     ASSERT_TRUE(std::holds_alternative<BeginResult>(result));
 }
+
+TEST(SlotTest, TestReclaimStaleOperations) {
+    static std::shared_ptr<KeyMintDevice> device =
+        KeyMintDevice::getWrappedKeymasterDevice(SecurityLevel::TRUSTED_ENVIRONMENT);
+    ASSERT_NE(device.get(), nullptr);
+
+    device->setNumFreeSlots(NUM_SLOTS);
+
+    // Fill up all the slots with operations that are never used again.
+    std::vector<std::shared_ptr<IKeyMintOperation>> operations;
+    for (int i = 0; i < NUM_SLOTS; i++) {
+        auto result = begin(device, true);
+        ASSERT_TRUE(std::holds_alternative<BeginResult>(result));
+        operations.push_back(std::get<BeginResult>(result).operation);
+    }
+
+    // Operations that were used recently are not reclaimed.
+    ASSERT_EQ(device->reclaimStaleOperations(std::chrono::minutes(1)), 0u);
+    auto result = begin(device, true);
+    ASSERT_TRUE(std::holds_alternative<ScopedAStatus>(result));
+
+    // Idle operations are aborted and release their slots.
+    ASSERT_EQ(device->reclaimStaleOperations(std::chrono::seconds(0)), size_t(NUM_SLOTS));
+    result = begin(device, true);
+    ASSERT_TRUE(std::holds_alternative<BeginResult>(result));
+
+    // Reclaimed operations cannot be used anymore and do not free up another slot.
+    auto status = operations.front()->abort();
+    ASSERT_TRUE(!status.isOk());
+    ASSERT_EQ(device->reclaimStaleOperations(std::chrono::seconds(0)), 1u);
+}
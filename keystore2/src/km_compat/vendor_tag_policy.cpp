@@ -0,0 +1,186 @@
+/*
+ * Copyright (C) 2022 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#include "vendor_tag_policy.h"
+
+#include <android-base/file.h>
+#include <android-base/logging.h>
+#include <android-base/parseint.h>
+#include <android-base/strings.h>
+
+using ::aidl::android::hardware::security::keymint::KeyParameterValue;
+namespace V4_0 = ::android::hardware::keymaster::V4_0;
+namespace KMV1 = ::aidl::android::hardware::security::keymint;
+
+// The AOSP tags all have ids below this bound. Vendors allocate their proprietary tags above.
+static constexpr uint32_t kMinVendorTagId = 10000;
+static constexpr uint32_t kTagTypeMask = 0xf0000000;
+
+// Qualcomm's inline crypto engine tag for file based encryption keys. Its Keymaster 4.0 value
+// is the same.
+static constexpr int32_t kTagFbeIce = (7 << 28) | 16201;
+
+VendorTagPolicy::VendorTagPolicy() {
+    toLegacyTags_[kTagFbeIce] = kTagFbeIce;
+    fromLegacyTags_[kTagFbeIce] = kTagFbeIce;
+}
+
+const VendorTagPolicy& VendorTagPolicy::get() {
+    static const VendorTagPolicy policy = [] {
+        std::string config;
+        if (!android::base::ReadFileToString(kConfigPath, &config)) {
+            return VendorTagPolicy();
+        }
+        return parse(config);
+    }();
+    return policy;
+}
+
+// Accepts unsigned decimal and hexadecimal tag values as well as the negative decimal values
+// that tags with the high type bits set have as int32_t.
+static std::optional<int32_t> parseTag(const std::string& s) {
+    uint32_t tag;
+    if (android::base::ParseUint(s, &tag)) {
+        return static_cast<int32_t>(tag);
+    }
+    int32_t signedTag;
+    if (android::base::ParseInt(s, &signedTag)) {
+        return signedTag;
+    }
+    return std::nullopt;
+}
+
+VendorTagPolicy VendorTagPolicy::parse(const std::string& config) {
+    VendorTagPolicy policy;
+    for (const auto& rawLine : android::base::Split(config, "\n")) {
+        auto line = android::base::Trim(rawLine);
+        if (line.empty() || line[0] == '#') {
+            continue;
+        }
+        auto words = android::base::Tokenize(line, " \t");
+        if (words.size() == 3 && words[0] == "map") {
+            auto tag = parseTag(words[1]);
+            auto legacyTag = parseTag(words[2]);
+            if (tag && legacyTag && isVendorTag(*tag)) {
+                policy.toLegacyTags_[*tag] = *legacyTag;
+                policy.fromLegacyTags_[*legacyTag] = *tag;
+                continue;
+            }
+        } else if (words.size() == 2 && words[0] == "deny") {
+            auto tag = parseTag(words[1]);
+            if (tag && isVendorTag(*tag)) {
+                policy.denylist_.insert(*tag);
+                continue;
+            }
+        }
+        LOG(WARNING) << __func__ << ": Ignoring malformed line \"" << line << "\".";
+    }
+    return policy;
+}
+
+bool VendorTagPolicy::isVendorTag(int32_t tag) {
+    return (static_cast<uint32_t>(tag) & ~kTagTypeMask) >= kMinVendorTagId;
+}
+
+std::optional<V4_0::KeyParameter> VendorTagPolicy::toLegacy(const KMV1::KeyParameter& kp) const {
+    int32_t tag = static_cast<int32_t>(kp.tag);
+    if (denylist_.count(tag)) {
+        LOG(WARNING) << __func__ << ": Stripping denylisted vendor tag " << tag << ".";
+        return std::nullopt;
+    }
+    auto mapped = toLegacyTags_.find(tag);
+    V4_0::KeyParameter result;
+    result.tag = static_cast<V4_0::Tag>(mapped == toLegacyTags_.end() ? tag : mapped->second);
+
+    switch (static_cast<V4_0::TagType>(static_cast<uint32_t>(result.tag) & kTagTypeMask)) {
+    case V4_0::TagType::BOOL:
+        // Boolean tags are true by their mere presence.
+        result.f.boolValue = true;
+        return result;
+    case V4_0::TagType::ENUM:
+    case V4_0::TagType::ENUM_REP:
+    case V4_0::TagType::UINT:
+    case V4_0::TagType::UINT_REP:
+        if (kp.value.getTag() == KeyParameterValue::Tag::integer) {
+            result.f.integer = kp.value.get<KeyParameterValue::Tag::integer>();
+            return result;
+        }
+        break;
+    case V4_0::TagType::ULONG:
+    case V4_0::TagType::ULONG_REP:
+        if (kp.value.getTag() == KeyParameterValue::Tag::longInteger) {
+            result.f.longInteger = kp.value.get<KeyParameterValue::Tag::longInteger>();
+            return result;
+        }
+        break;
+    case V4_0::TagType::DATE:
+        if (kp.value.getTag() == KeyParameterValue::Tag::dateTime) {
+            result.f.dateTime = kp.value.get<KeyParameterValue::Tag::dateTime>();
+            return result;
+        }
+        break;
+    case V4_0::TagType::BIGNUM:
+    case V4_0::TagType::BYTES:
+        if (kp.value.getTag() == KeyParameterValue::Tag::blob) {
+            result.blob = kp.value.get<KeyParameterValue::Tag::blob>();
+            return result;
+        }
+        break;
+    default:
+        break;
+    }
+    LOG(WARNING) << __func__ << ": Stripping vendor tag " << tag << " with mismatching value.";
+    return std::nullopt;
+}
+
+std::optional<KMV1::KeyParameter> VendorTagPolicy::fromLegacy(const V4_0::KeyParameter& kp) const {
+    int32_t legacyTag = static_cast<int32_t>(kp.tag);
+    auto mapped = fromLegacyTags_.find(legacyTag);
+    int32_t tag = mapped == fromLegacyTags_.end() ? legacyTag : mapped->second;
+    if (denylist_.count(tag)) {
+        LOG(WARNING) << __func__ << ": Stripping denylisted vendor tag " << tag << ".";
+        return std::nullopt;
+    }
+    KMV1::KeyParameter result{.tag = static_cast<KMV1::Tag>(tag)};
+
+    switch (static_cast<V4_0::TagType>(static_cast<uint32_t>(kp.tag) & kTagTypeMask)) {
+    case V4_0::TagType::BOOL:
+        result.value = KeyParameterValue::make<KeyParameterValue::Tag::boolValue>(true);
+        return result;
+    case V4_0::TagType::ENUM:
+    case V4_0::TagType::ENUM_REP:
+    case V4_0::TagType::UINT:
+    case V4_0::TagType::UINT_REP:
+        result.value = KeyParameterValue::make<KeyParameterValue::Tag::integer>(kp.f.integer);
+        return result;
+    case V4_0::TagType::ULONG:
+    case V4_0::TagType::ULONG_REP:
+        result.value =
+            KeyParameterValue::make<KeyParameterValue::Tag::longInteger>(kp.f.longInteger);
+        return result;
+    case V4_0::TagType::DATE:
+        result.value = KeyParameterValue::make<KeyParameterValue::Tag::dateTime>(kp.f.dateTime);
+        return result;
+    case V4_0::TagType::BIGNUM:
+    case V4_0::TagType::BYTES:
+        result.value = KeyParameterValue::make<KeyParameterValue::Tag::blob>(kp.blob);
+        return result;
+    default:
+        break;
+    }
+    LOG(WARNING) << __func__ << ": Stripping vendor tag " << tag << " of unknown type.";
+    return std::nullopt;
+}
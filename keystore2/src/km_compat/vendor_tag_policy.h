@@ -0,0 +1,77 @@
+/*
+ * Copyright (C) 2022 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#pragma once
+
+#include <cstdint>
+#include <map>
+#include <optional>
+#include <set>
+#include <string>
+
+#include <aidl/android/hardware/security/keymint/KeyParameter.h>
+#include <android/hardware/keymaster/4.0/types.h>
+
+/**
+ * Decides what happens to vendor defined tags on their way between Keystore and a Keymaster 4.x
+ * device. By default vendor tags are passed through unchanged. Tags listed in the mapping table
+ * are translated to their Keymaster 4.0 equivalent and back, and denylisted tags are stripped.
+ *
+ * Devices can extend the built-in table with a config file holding one directive per line:
+ *
+ *     # Comment
+ *     map <KeyMint tag> <Keymaster 4.0 tag>
+ *     deny <KeyMint tag>
+ *
+ * Tags are given as full 32 bit tag values including the tag type, in decimal or hexadecimal
+ * notation. Decimal values may also be given as signed 32 bit integers. Malformed lines are
+ * logged and ignored.
+ */
+class VendorTagPolicy {
+  public:
+    /** Location of the device specific config file. */
+    static constexpr const char* kConfigPath = "/vendor/etc/keystore2_km_compat_vendor_tags.conf";
+
+    /** Returns the policy of this device, loading the config file on first use. */
+    static const VendorTagPolicy& get();
+
+    /** Returns the built-in policy extended by the directives of the given config. */
+    static VendorTagPolicy parse(const std::string& config);
+
+    /** Returns true if the given tag lies in the range reserved for vendor tags. */
+    static bool isVendorTag(int32_t tag);
+
+    /**
+     * Converts a vendor parameter to Keymaster 4.0. Returns std::nullopt if the parameter
+     * must be stripped.
+     */
+    std::optional<::android::hardware::keymaster::V4_0::KeyParameter>
+    toLegacy(const ::aidl::android::hardware::security::keymint::KeyParameter& kp) const;
+
+    /**
+     * Converts a vendor parameter reported by Keymaster 4.0 back to KeyMint. Returns
+     * std::nullopt if the parameter must be stripped.
+     */
+    std::optional<::aidl::android::hardware::security::keymint::KeyParameter>
+    fromLegacy(const ::android::hardware::keymaster::V4_0::KeyParameter& kp) const;
+
+  private:
+    VendorTagPolicy();
+
+    std::map<int32_t, int32_t> toLegacyTags_;
+    std::map<int32_t, int32_t> fromLegacyTags_;
+    std::set<int32_t> denylist_;
+};
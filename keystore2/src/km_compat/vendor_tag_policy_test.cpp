@@ -0,0 +1,96 @@
+/*
+ * Copyright 2022, The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#include <gtest/gtest.h>
+
+#include "vendor_tag_policy.h"
+
+using ::aidl::android::hardware::security::keymint::KeyParameterValue;
+namespace V4_0 = ::android::hardware::keymaster::V4_0;
+namespace KMV1 = ::aidl::android::hardware::security::keymint;
+
+static constexpr int32_t kVendorBoolTag = (7 << 28) | 20000;
+static constexpr int32_t kVendorUintTag = (3 << 28) | 20001;
+static constexpr int32_t kLegacyUintTag = (3 << 28) | 20101;
+static constexpr uint32_t kDeniedTag = (9u << 28) | 20002;
+
+static KMV1::KeyParameter makeParam(int32_t tag, KeyParameterValue value) {
+    return KMV1::KeyParameter{.tag = static_cast<KMV1::Tag>(tag), .value = std::move(value)};
+}
+
+TEST(VendorTagPolicyTest, testIsVendorTag) {
+    ASSERT_TRUE(VendorTagPolicy::isVendorTag(kVendorBoolTag));
+    ASSERT_TRUE(VendorTagPolicy::isVendorTag((7 << 28) | 16201));
+    ASSERT_FALSE(VendorTagPolicy::isVendorTag(static_cast<int32_t>(KMV1::Tag::PURPOSE)));
+    ASSERT_FALSE(VendorTagPolicy::isVendorTag(static_cast<int32_t>(KMV1::Tag::STORAGE_KEY)));
+}
+
+TEST(VendorTagPolicyTest, testPassThrough) {
+    auto policy = VendorTagPolicy::parse("");
+    auto legacy = policy.toLegacy(makeParam(
+        kVendorBoolTag, KeyParameterValue::make<KeyParameterValue::Tag::boolValue>(true)));
+    ASSERT_TRUE(legacy);
+    ASSERT_EQ(kVendorBoolTag, static_cast<int32_t>(legacy->tag));
+    ASSERT_TRUE(legacy->f.boolValue);
+
+    auto kp = policy.fromLegacy(*legacy);
+    ASSERT_TRUE(kp);
+    ASSERT_EQ(kVendorBoolTag, static_cast<int32_t>(kp->tag));
+    ASSERT_EQ(KeyParameterValue::Tag::boolValue, kp->value.getTag());
+}
+
+TEST(VendorTagPolicyTest, testMapAndDeny) {
+    auto policy = VendorTagPolicy::parse(
+        "# Comment\n"
+        "map " + std::to_string(kVendorUintTag) + " " + std::to_string(kLegacyUintTag) + "\n"
+        "\n"
+        "deny " + std::to_string(kDeniedTag) + "\n"
+        "malformed line\n");
+
+    auto legacy = policy.toLegacy(
+        makeParam(kVendorUintTag, KeyParameterValue::make<KeyParameterValue::Tag::integer>(42)));
+    ASSERT_TRUE(legacy);
+    ASSERT_EQ(kLegacyUintTag, static_cast<int32_t>(legacy->tag));
+    ASSERT_EQ(42u, legacy->f.integer);
+
+    auto kp = policy.fromLegacy(*legacy);
+    ASSERT_TRUE(kp);
+    ASSERT_EQ(kVendorUintTag, static_cast<int32_t>(kp->tag));
+    ASSERT_EQ(42, kp->value.get<KeyParameterValue::Tag::integer>());
+
+    std::vector<uint8_t> blob{1, 2, 3};
+    ASSERT_FALSE(policy.toLegacy(makeParam(
+        static_cast<int32_t>(kDeniedTag),
+        KeyParameterValue::make<KeyParameterValue::Tag::blob>(blob))));
+}
+
+TEST(VendorTagPolicyTest, testTagNotations) {
+    std::vector<uint8_t> blob{1, 2, 3};
+    auto param = makeParam(static_cast<int32_t>(kDeniedTag),
+                           KeyParameterValue::make<KeyParameterValue::Tag::blob>(blob));
+
+    ASSERT_FALSE(VendorTagPolicy::parse("deny 0x90004e22\n").toLegacy(param));
+    ASSERT_FALSE(
+        VendorTagPolicy::parse("deny " + std::to_string(static_cast<int32_t>(kDeniedTag)) + "\n")
+            .toLegacy(param));
+    ASSERT_TRUE(VendorTagPolicy::parse("deny 0x1x\n").toLegacy(param));
+}
+
+TEST(VendorTagPolicyTest, testMismatchingValueIsStripped) {
+    auto policy = VendorTagPolicy::parse("");
+    ASSERT_FALSE(policy.toLegacy(makeParam(
+        kVendorUintTag, KeyParameterValue::make<KeyParameterValue::Tag::longInteger>(42))));
+}
@@ -18,19 +18,21 @@ use crate::{
     error::{Error as KsError, ResponseCode},
     key_parameter::{KeyParameter, KeyParameterValue},
     utils::uid_to_android_user,
-    utils::AesGcm,
+    utils::{AesGcm, AesGcmKey},
 };
 use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
     SecurityLevel::SecurityLevel, Tag::Tag, TagType::TagType,
 };
 use anyhow::{Context, Result};
-use keystore2_crypto::{aes_gcm_decrypt, Password, ZVec};
+use keystore2_crypto::{
+    aes_cbc_decrypt, aes_gcm_decrypt, md5, Password, ZVec, AES_128_KEY_LENGTH, MD5_DIGEST_LENGTH,
+};
 use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 use std::{convert::TryInto, fs::File, path::Path, path::PathBuf};
 use std::{
     fs,
-    io::{ErrorKind, Read, Result as IoResult},
+    io::{ErrorKind, Read, Result as IoResult, Write},
 };
 
 const SUPPORTED_LEGACY_BLOB_VERSION: u8 = 3;
@@ -160,6 +162,27 @@ pub enum BlobValue {
     /// blob in this Variant is decrypted only with respect to any extra layer of encryption
     /// that Keystore added.
     Decrypted(ZVec),
+    /// A blob written before Android P, i.e., a blob of version 2 or older, encrypted with
+    /// the user's master key using AES-CBC. The ciphertext holds an MD5 digest of the remaining
+    /// plaintext, the length of the payload, and the zero padded payload.
+    CbcEncrypted {
+        /// The type of the payload, e.g., `blob_types::KM_BLOB`.
+        blob_type: u8,
+        /// Initialization vector.
+        iv: Vec<u8>,
+        /// Ciphertext.
+        data: Vec<u8>,
+    },
+    /// A master key blob written before Android P. It is encrypted like `CbcEncrypted` with an
+    /// AES128 key derived from the user's password.
+    PwCbcEncrypted {
+        /// Initialization vector.
+        iv: Vec<u8>,
+        /// Ciphertext.
+        data: Vec<u8>,
+        /// Salt for key derivation. Master keys created before Android 2.3 have no salt.
+        salt: Option<Vec<u8>>,
+    },
 }
 
 /// Keystore used two different key characteristics file formats in the past.
@@ -282,6 +305,8 @@ impl LegacyBlobLoader {
     const LENGTH_OFFSET: usize = 4 + Self::IV_SIZE + Self::GCM_TAG_LENGTH;
     const IV_OFFSET: usize = 4;
     const AEAD_TAG_OFFSET: usize = Self::IV_OFFSET + Self::IV_SIZE;
+    // Blobs before version 3 are encrypted starting with the digest.
+    const CBC_CIPHERTEXT_OFFSET: usize = Self::IV_OFFSET + Self::IV_SIZE;
     const _DIGEST_OFFSET: usize = Self::IV_OFFSET + Self::IV_SIZE;
 
     /// Construct a new LegacyBlobLoader with a root path of `path` relative to which it will
@@ -378,11 +403,19 @@ impl LegacyBlobLoader {
             _ => None,
         };
 
-        if version != SUPPORTED_LEGACY_BLOB_VERSION {
+        if version > SUPPORTED_LEGACY_BLOB_VERSION {
             return Err(KsError::Rc(ResponseCode::VALUE_CORRUPTED))
                 .context(format!("In new_from_stream: Unknown blob version: {}.", version));
         }
 
+        if version < SUPPORTED_LEGACY_BLOB_VERSION {
+            if let Some(blob) =
+                Self::new_from_pre_p_buffer(version, &buffer).context("In new_from_stream.")?
+            {
+                return Ok(blob);
+            }
+        }
+
         let length = u32::from_be_bytes(
             buffer[Self::LENGTH_OFFSET..Self::LENGTH_OFFSET + 4].try_into().unwrap(),
         ) as usize;
@@ -466,6 +499,74 @@ impl LegacyBlobLoader {
         }
     }
 
+    /// Parses encrypted blobs written before Android P, i.e., blobs of version 0 to 2.
+    /// Following the upgrade path of Keystore at the time, all version 0 blobs are generic
+    /// blobs, and all blobs older than version 2 are encrypted.
+    /// Returns None if the blob is not encrypted. These blobs have the same layout as
+    /// blobs of the current version.
+    fn new_from_pre_p_buffer(version: u8, buffer: &[u8]) -> Result<Option<Blob>> {
+        let blob_type = match version {
+            0 => blob_types::GENERIC,
+            _ => buffer[Self::TYPE_OFFSET],
+        };
+        let flags = match version {
+            0 | 1 => buffer[Self::FLAGS_OFFSET] | flags::ENCRYPTED,
+            _ => buffer[Self::FLAGS_OFFSET],
+        };
+        if flags & flags::ENCRYPTED == 0 {
+            return Ok(None);
+        }
+
+        let info_size = buffer[Self::SALT_SIZE_OFFSET] as usize;
+        if buffer.len() < Self::CBC_CIPHERTEXT_OFFSET + info_size {
+            return Err(Error::BadLen).context("In new_from_pre_p_buffer.");
+        }
+        let iv = buffer[Self::IV_OFFSET..Self::IV_OFFSET + Self::IV_SIZE].to_vec();
+        let data = buffer[Self::CBC_CIPHERTEXT_OFFSET..buffer.len() - info_size].to_vec();
+
+        let value = match blob_type {
+            blob_types::SUPER_KEY => BlobValue::PwCbcEncrypted {
+                iv,
+                data,
+                salt: match info_size {
+                    Self::SALT_SIZE => Some(buffer[buffer.len() - info_size..].to_vec()),
+                    _ => None,
+                },
+            },
+            _ => BlobValue::CbcEncrypted { blob_type, iv, data },
+        };
+        Ok(Some(Blob { flags, value }))
+    }
+
+    /// Deciphers `data` using the initialization vector `iv` and AES-CBC with the raw super key
+    /// `key`. Keystore encrypted blobs with this scheme before Android P.
+    fn decrypt_cbc(key: &[u8], data: &[u8], iv: &[u8]) -> Result<ZVec> {
+        aes_cbc_decrypt(data, iv, key).context("In decrypt_cbc: Decryption failed.")
+    }
+
+    /// Extracts the payload from the plaintext of an encrypted blob written before Android P.
+    /// The plaintext consists of an MD5 digest of the remaining plaintext, the length of the
+    /// payload in network byte order, the payload, and zero padding.
+    fn open_pre_p_plaintext(plaintext: &[u8]) -> Result<ZVec> {
+        const LENGTH_SIZE: usize = 4;
+        if plaintext.len() < MD5_DIGEST_LENGTH + LENGTH_SIZE {
+            return Err(Error::BadLen).context("In open_pre_p_plaintext.");
+        }
+        let (digest, digested) = plaintext.split_at(MD5_DIGEST_LENGTH);
+        if md5(digested).context("In open_pre_p_plaintext: Trying to compute digest.")? != digest {
+            return Err(KsError::Rc(ResponseCode::VALUE_CORRUPTED))
+                .context("In open_pre_p_plaintext: Digest mismatch.");
+        }
+        let length = u32::from_be_bytes(digested[..LENGTH_SIZE].try_into().unwrap()) as usize;
+        if length > digested.len() - LENGTH_SIZE {
+            return Err(KsError::Rc(ResponseCode::VALUE_CORRUPTED))
+                .context("In open_pre_p_plaintext: Invalid payload length.");
+        }
+        digested[LENGTH_SIZE..LENGTH_SIZE + length]
+            .try_into()
+            .context("In open_pre_p_plaintext: Trying to convert payload into ZVec.")
+    }
+
     /// Parses a legacy key blob file read from `stream`. A `decrypt` closure
     /// must be supplied, that is primed with the appropriate key.
     /// The callback takes the following arguments:
@@ -628,7 +729,7 @@ impl LegacyBlobLoader {
         Ok(params)
     }
 
-    /// This function takes a Blob and an optional AesGcm. Plain text blob variants are
+    /// This function takes a Blob and an optional AesGcmKey. Plain text blob variants are
     /// passed through as is. If a super key is given an attempt is made to decrypt the
     /// blob thereby mapping BlobValue variants as follows:
     /// BlobValue::Encrypted => BlobValue::Decrypted
@@ -636,7 +737,7 @@ impl LegacyBlobLoader {
     /// BlobValue::EncryptedCharacteristics => BlobValue::Characteristics
     /// If now super key is given or BlobValue::PwEncrypted is encountered,
     /// Err(Error::LockedComponent) is returned.
-    fn decrypt_if_required(super_key: &Option<Arc<dyn AesGcm>>, blob: Blob) -> Result<Blob> {
+    fn decrypt_if_required(super_key: &Option<Arc<dyn AesGcmKey>>, blob: Blob) -> Result<Blob> {
         match blob {
             Blob { value: BlobValue::Generic(_), .. }
             | Blob { value: BlobValue::Characteristics(_), .. }
@@ -684,6 +785,32 @@ impl LegacyBlobLoader {
                     flags,
                 })
             }
+            Blob { value: BlobValue::CbcEncrypted { blob_type, iv, data }, flags }
+                if super_key.is_some() =>
+            {
+                let payload = Self::open_pre_p_plaintext(
+                    &Self::decrypt_cbc(super_key.as_ref().unwrap().key(), &data, &iv)
+                        .context("In decrypt_if_required: Failed to decrypt CbcEncrypted")?,
+                )
+                .context("In decrypt_if_required.")?;
+                let value = match blob_type {
+                    blob_types::GENERIC => BlobValue::Generic(payload[..].to_vec()),
+                    blob_types::KEY_CHARACTERISTICS => {
+                        BlobValue::Characteristics(payload[..].to_vec())
+                    }
+                    blob_types::KEY_CHARACTERISTICS_CACHE => {
+                        BlobValue::CharacteristicsCache(payload[..].to_vec())
+                    }
+                    blob_types::KM_BLOB => BlobValue::Decrypted(payload),
+                    _ => {
+                        return Err(KsError::Rc(ResponseCode::VALUE_CORRUPTED)).context(format!(
+                            "In decrypt_if_required: Unknown blob type {}.",
+                            blob_type
+                        ))
+                    }
+                };
+                Ok(Blob { value, flags })
+            }
             // This arm catches all encrypted cases where super key is not present or cannot
             // decrypt the blob, the latter being BlobValue::PwEncrypted.
             _ => Err(Error::LockedComponent)
@@ -697,9 +824,10 @@ impl LegacyBlobLoader {
         prefix: &str,
         alias: &str,
         hw_sec_level: SecurityLevel,
-        super_key: &Option<Arc<dyn AesGcm>>,
+        super_key: &Option<Arc<dyn AesGcmKey>>,
     ) -> Result<LegacyKeyCharacteristics> {
-        let blob = Self::read_generic_blob(&self.make_chr_filename(uid, alias, prefix))
+        let blob = self
+            .read_generic_blob_or_quarantine(&self.make_chr_filename(uid, alias, prefix))
             .context("In read_characteristics_file")?;
 
         let blob = match blob {
@@ -756,6 +884,10 @@ impl LegacyBlobLoader {
     const KNOWN_KEYSTORE_PREFIXES: &'static [&'static str] =
         &["USRPKEY_", "USRSKEY_", "USRCERT_", "CACERT_"];
 
+    /// Corrupted legacy blob files are moved into this directory. It does not match "user_*",
+    /// so it is not considered when checking if the legacy blob database is empty.
+    const QUARANTINE_DIR: &'static str = "quarantine";
+
     fn is_keystore_alias(encoded_alias: &str) -> bool {
         // We can check the encoded alias because the prefixes we are interested
         // in are all in the printable range that don't get mangled.
@@ -767,9 +899,9 @@ impl LegacyBlobLoader {
 
         let (blob, prefix) = loop {
             if let Some(prefix) = iter.next() {
-                if let Some(blob) =
-                    Self::read_generic_blob(&self.make_blob_filename(uid, alias, prefix))
-                        .context("In read_km_blob_file.")?
+                if let Some(blob) = self
+                    .read_generic_blob_or_quarantine(&self.make_blob_filename(uid, alias, prefix))
+                    .context("In read_km_blob_file.")?
                 {
                     break (blob, prefix);
                 }
@@ -781,6 +913,51 @@ impl LegacyBlobLoader {
         Ok(Some((blob, prefix.to_string())))
     }
 
+    /// Like `read_generic_blob`, but a file that cannot be parsed is moved into the quarantine
+    /// directory and treated as absent. This way a single truncated or otherwise corrupted file
+    /// does not prevent access to the remaining entries. The file is kept for later analysis.
+    fn read_generic_blob_or_quarantine(&self, path: &Path) -> Result<Option<Blob>> {
+        match Self::read_generic_blob(path) {
+            Err(e) if Self::is_corrupted(&e) => {
+                log::error!("Quarantining corrupted legacy blob file {:?}: {:?}", path, e);
+                self.quarantine_file(path)
+                    .context("In read_generic_blob_or_quarantine: Trying to quarantine file.")?;
+                Ok(None)
+            }
+            result => result.context("In read_generic_blob_or_quarantine."),
+        }
+    }
+
+    fn is_corrupted(e: &anyhow::Error) -> bool {
+        let root_cause = e.root_cause();
+        root_cause.downcast_ref::<Error>() == Some(&Error::BadLen)
+            || matches!(
+                root_cause.downcast_ref::<KsError>(),
+                Some(&KsError::Rc(ResponseCode::VALUE_CORRUPTED))
+            )
+    }
+
+    /// Moves the given file into the quarantine directory, preserving the name of the user
+    /// directory it was found in.
+    fn quarantine_file(&self, path: &Path) -> Result<()> {
+        let user_dir = path.parent().and_then(Path::file_name);
+        let (user_dir, file_name) = match (user_dir, path.file_name()) {
+            (Some(user_dir), Some(file_name)) => (user_dir, file_name),
+            _ => {
+                return Err(KsError::sys())
+                    .context(format!("In quarantine_file: Invalid path {:?}.", path))
+            }
+        };
+        let mut quarantine_path = self.path.clone();
+        quarantine_path.push(Self::QUARANTINE_DIR);
+        quarantine_path.push(user_dir);
+        Self::with_retry_interrupted(|| fs::create_dir_all(&quarantine_path))
+            .context("In quarantine_file: Trying to create quarantine directory.")?;
+        quarantine_path.push(file_name);
+        Self::with_retry_interrupted(|| fs::rename(path, &quarantine_path))
+            .context("In quarantine_file: Trying to move file.")
+    }
+
     fn read_generic_blob(path: &Path) -> Result<Option<Blob>> {
         let mut file = match Self::with_retry_interrupted(|| File::open(path)) {
             Ok(file) => file,
@@ -998,6 +1175,75 @@ impl LegacyBlobLoader {
         Ok(true)
     }
 
+    /// Deletes all entries matching "user_*" in the database dir, i.e., all legacy key blobs,
+    /// super keys, and legacy keystore entries of all users, as well as quarantined files.
+    pub fn delete_all(&self) -> Result<()> {
+        let dir = Self::with_retry_interrupted(|| fs::read_dir(self.path.as_path()))
+            .context("In delete_all: Failed to open legacy blob database.")?;
+        for entry in dir {
+            let entry = entry.context("In delete_all: Trying to access dir entry")?;
+            if (*entry.file_name())
+                .to_str()
+                .map_or(false, |f| f.starts_with("user_") || f == Self::QUARANTINE_DIR)
+            {
+                let path = entry.path();
+                Self::with_retry_interrupted(|| fs::remove_dir_all(&path))
+                    .with_context(|| format!("In delete_all: Failed to remove {:?}.", path))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn make_quarantine_user_path_name(&self, user_id: u32) -> PathBuf {
+        let mut path = self.path.clone();
+        path.push(Self::QUARANTINE_DIR);
+        path.push(format!("user_{}", user_id));
+        path
+    }
+
+    /// Deletes the quarantined key blob and characteristics files of the given uid.
+    pub fn remove_quarantined_uid(&self, uid: u32) -> Result<()> {
+        let path = self.make_quarantine_user_path_name(uid_to_android_user(uid));
+        let dir = match Self::with_retry_interrupted(|| fs::read_dir(path.as_path())) {
+            Ok(dir) => dir,
+            Err(e) => match e.kind() {
+                ErrorKind::NotFound => return Ok(()),
+                _ => {
+                    return Err(e).context(format!(
+                        "In remove_quarantined_uid: Failed to open quarantine directory. {:?}",
+                        path
+                    ))
+                }
+            },
+        };
+        let blob_prefix = format!("{}_", uid);
+        let chr_prefix = format!(".{}_", uid);
+        for entry in dir {
+            let entry = entry.context("In remove_quarantined_uid: Trying to access dir entry")?;
+            if (*entry.file_name())
+                .to_str()
+                .map_or(false, |f| f.starts_with(&blob_prefix) || f.starts_with(&chr_prefix))
+            {
+                let path = entry.path();
+                Self::with_retry_interrupted(|| fs::remove_file(&path)).with_context(|| {
+                    format!("In remove_quarantined_uid: Failed to remove {:?}.", path)
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Deletes all quarantined files of the given user.
+    pub fn remove_quarantined_user(&self, user_id: u32) -> Result<()> {
+        let path = self.make_quarantine_user_path_name(user_id);
+        match Self::with_retry_interrupted(|| fs::remove_dir_all(&path)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                Err(e).context(format!("In remove_quarantined_user: Failed to remove {:?}.", path))
+            }
+            _ => Ok(()),
+        }
+    }
+
     /// Returns if the legacy blob database is empty for a given user, i.e., there are no entries
     /// matching "user_*" in the database dir.
     pub fn is_empty_user(&self, user_id: u32) -> Result<bool> {
@@ -1169,6 +1415,69 @@ impl LegacyBlobLoader {
         Ok(something_was_deleted)
     }
 
+    /// Writes a key blob and its certificates as legacy keystore entry, replacing any existing
+    /// entry with the same uid and alias. All blobs are written unencrypted, so this must not
+    /// be used for super encrypted key blobs. No characteristics file is written, because
+    /// Keystore 1.0 gets the key characteristics from Keymaster if the file is missing.
+    pub fn write_keystore_entry(
+        &self,
+        uid: u32,
+        alias: &str,
+        km_blob: &[u8],
+        is_strongbox: bool,
+        user_cert: Option<&[u8]>,
+        ca_cert: Option<&[u8]>,
+    ) -> Result<()> {
+        self.remove_keystore_entry(uid, alias)
+            .context("In write_keystore_entry: Trying to remove existing entry.")?;
+
+        let user_path = self.make_user_path_name(uid_to_android_user(uid));
+        Self::with_retry_interrupted(|| fs::create_dir_all(&user_path))
+            .context("In write_keystore_entry: Trying to create user directory.")?;
+
+        let flags = if is_strongbox { flags::STRONGBOX } else { 0 };
+        Self::write_blob_file(
+            &self.make_blob_filename(uid, alias, "USRPKEY"),
+            blob_types::KM_BLOB,
+            flags,
+            km_blob,
+        )
+        .context("In write_keystore_entry: Trying to write key blob.")?;
+        if let Some(user_cert) = user_cert {
+            Self::write_blob_file(
+                &self.make_blob_filename(uid, alias, "USRCERT"),
+                blob_types::GENERIC,
+                0,
+                user_cert,
+            )
+            .context("In write_keystore_entry: Trying to write user certificate.")?;
+        }
+        if let Some(ca_cert) = ca_cert {
+            Self::write_blob_file(
+                &self.make_blob_filename(uid, alias, "CACERT"),
+                blob_types::GENERIC,
+                0,
+                ca_cert,
+            )
+            .context("In write_keystore_entry: Trying to write CA certificates.")?;
+        }
+        Ok(())
+    }
+
+    /// Writes an unencrypted blob of the current version to the given path.
+    fn write_blob_file(path: &Path, blob_type: u8, flags: u8, data: &[u8]) -> Result<()> {
+        let mut buffer = Vec::with_capacity(Self::COMMON_HEADER_SIZE + data.len());
+        buffer.extend_from_slice(&[SUPPORTED_LEGACY_BLOB_VERSION, blob_type, flags, 0]);
+        // Unencrypted blobs have neither an initialization vector nor an AEAD tag.
+        buffer.resize(Self::LENGTH_OFFSET, 0);
+        buffer.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        buffer.extend_from_slice(data);
+
+        let mut file = File::create(path).context("In write_blob_file: Trying to create file.")?;
+        file.write_all(&buffer).context("In write_blob_file: Trying to write blob.")?;
+        file.sync_all().context("In write_blob_file: Trying to sync file.")
+    }
+
     /// This function moves a keystore file if it exists. It constructs the source and destination
     /// file name using the make_filename function with the arguments uid, alias, and prefix.
     /// The function overwrites existing destination files silently. If the source does not exist,
@@ -1267,7 +1576,7 @@ impl LegacyBlobLoader {
         &self,
         uid: u32,
         alias: &str,
-        super_key: &Option<Arc<dyn AesGcm>>,
+        super_key: &Option<Arc<dyn AesGcmKey>>,
     ) -> Result<(Option<(Blob, LegacyKeyCharacteristics)>, Option<Vec<u8>>, Option<Vec<u8>>)> {
         let km_blob = self.read_km_blob_file(uid, alias).context("In load_by_uid_alias.")?;
 
@@ -1277,6 +1586,11 @@ impl LegacyBlobLoader {
                     match km_blob {
                         Blob { flags: _, value: BlobValue::Decrypted(_) }
                         | Blob { flags: _, value: BlobValue::Encrypted { .. } } => km_blob,
+                        Blob {
+                            flags: _,
+                            value: BlobValue::CbcEncrypted { blob_type: blob_types::KM_BLOB, .. },
+                        } => Self::reencrypt_pre_p_km_blob(super_key, km_blob)
+                            .context("In load_by_uid_alias.")?,
                         _ => return Err(KsError::Rc(ResponseCode::VALUE_CORRUPTED)).context(
                             "In load_by_uid_alias: Found wrong blob type in legacy key blob file.",
                         ),
@@ -1294,9 +1608,9 @@ impl LegacyBlobLoader {
             None => None,
         };
 
-        let user_cert_blob =
-            Self::read_generic_blob(&self.make_blob_filename(uid, alias, "USRCERT"))
-                .context("In load_by_uid_alias: While loading user cert.")?;
+        let user_cert_blob = self
+            .read_generic_blob_or_quarantine(&self.make_blob_filename(uid, alias, "USRCERT"))
+            .context("In load_by_uid_alias: While loading user cert.")?;
 
         let user_cert = if let Some(blob) = user_cert_blob {
             let blob = Self::decrypt_if_required(super_key, blob)
@@ -1312,7 +1626,8 @@ impl LegacyBlobLoader {
             None
         };
 
-        let ca_cert_blob = Self::read_generic_blob(&self.make_blob_filename(uid, alias, "CACERT"))
+        let ca_cert_blob = self
+            .read_generic_blob_or_quarantine(&self.make_blob_filename(uid, alias, "CACERT"))
             .context("In load_by_uid_alias: While loading ca cert.")?;
 
         let ca_cert = if let Some(blob) = ca_cert_blob {
@@ -1332,6 +1647,24 @@ impl LegacyBlobLoader {
         Ok((km_blob, user_cert, ca_cert))
     }
 
+    /// Key blobs encrypted before Android P are decrypted and encrypted again with AES-GCM,
+    /// so that they stay bound to the user's super key after they were imported.
+    fn reencrypt_pre_p_km_blob(super_key: &Option<Arc<dyn AesGcmKey>>, blob: Blob) -> Result<Blob> {
+        let flags = blob.flags;
+        let blob = Self::decrypt_if_required(super_key, blob)
+            .context("In reencrypt_pre_p_km_blob: Trying to decrypt blob.")?;
+        match (blob.value, super_key) {
+            (BlobValue::Decrypted(data), Some(super_key)) => {
+                let (data, iv, tag) = super_key
+                    .encrypt(&data)
+                    .context("In reencrypt_pre_p_km_blob: Trying to encrypt blob.")?;
+                Ok(Blob { flags, value: BlobValue::Encrypted { iv, tag, data } })
+            }
+            _ => Err(KsError::sys())
+                .context("In reencrypt_pre_p_km_blob: Unexpected blob after decryption."),
+        }
+    }
+
     /// Returns true if the given user has a super key.
     pub fn has_super_key(&self, user_id: u32) -> bool {
         self.make_super_key_filename(user_id).is_file()
@@ -1362,6 +1695,19 @@ impl LegacyBlobLoader {
                         )
                     }
                 }
+                Blob { flags: _, value: BlobValue::PwCbcEncrypted { iv, data, salt } } => {
+                    // Master keys written before Android P are AES128 keys.
+                    let key = pw
+                        .derive_key(salt.as_deref(), AES_128_KEY_LENGTH)
+                        .context("In load_super_key: Failed to derive key from password.")?;
+                    let plaintext = aes_cbc_decrypt(&data, &iv, &key).context(
+                        "In load_super_key: while trying to decrypt pre P master key blob.",
+                    )?;
+                    Some(
+                        Self::open_pre_p_plaintext(&plaintext)
+                            .context("In load_super_key: Trying to open pre P master key blob.")?,
+                    )
+                }
                 _ => {
                     return Err(KsError::Rc(ResponseCode::VALUE_CORRUPTED)).context(
                         "In load_super_key: Found wrong blob type in legacy super key blob file.",
@@ -1577,6 +1923,10 @@ pub mod test_utils {
                 data[..].to_vec(),
                 None,
             ),
+            Blob { value: BlobValue::CbcEncrypted { .. }, .. }
+            | Blob { value: BlobValue::PwCbcEncrypted { .. }, .. } => {
+                return Err(anyhow::anyhow!("Cannot write pre Android P blobs as version 3."))
+            }
         };
         write_legacy_blob_helper(out, &header, &data, salt.as_deref())
     }
@@ -1852,6 +2202,185 @@ mod test {
         assert!(legacy_blob_loader.is_empty().expect("Should succeed and be empty again."));
     }
 
+    #[test]
+    fn test_delete_all() -> anyhow::Result<()> {
+        let temp_dir = TempDir::new("test_delete_all").unwrap();
+        let legacy_blob_loader = LegacyBlobLoader::new(temp_dir.path());
+
+        let _db = crate::database::KeystoreDB::new(temp_dir.path(), None)?;
+        std::fs::create_dir(&*temp_dir.build().push("user_0"))?;
+        std::fs::create_dir(&*temp_dir.build().push("user_10"))?;
+        std::fs::write(&*temp_dir.build().push("user_10").push(".masterkey"), SUPERKEY)?;
+        assert!(!legacy_blob_loader.is_empty()?);
+
+        legacy_blob_loader.delete_all()?;
+
+        assert!(legacy_blob_loader.is_empty()?);
+        // The Keystore 2.0 database is not touched.
+        assert!(temp_dir.build().push("persistent.sqlite").is_file());
+        Ok(())
+    }
+
+    #[test]
+    fn test_pre_p_blobs() -> anyhow::Result<()> {
+        let temp_dir = TempDir::new("test_pre_p_blobs").unwrap();
+        std::fs::create_dir(&*temp_dir.build().push("user_0"))?;
+        std::fs::write(&*temp_dir.build().push("user_0").push(".masterkey"), PRE_P_MASTERKEY)?;
+        std::fs::write(
+            &*temp_dir.build().push("user_0").push("10223_USRPKEY_prep"),
+            PRE_P_USRPKEY,
+        )?;
+
+        let legacy_blob_loader = LegacyBlobLoader::new(temp_dir.path());
+
+        let pw: Password = PRE_P_PASSWORD.into();
+        let master_key = legacy_blob_loader.load_super_key(0, &pw)?.unwrap();
+        assert_eq!(&master_key[..], PRE_P_MASTERKEY_PAYLOAD);
+
+        let wrong_pw: Password = PASSWORD.into();
+        assert!(legacy_blob_loader.load_super_key(0, &wrong_pw).is_err());
+
+        // The key blob cannot be loaded without the master key.
+        assert_eq!(
+            Some(&Error::LockedComponent),
+            legacy_blob_loader
+                .load_by_uid_alias(10223, "prep", &None)
+                .unwrap_err()
+                .root_cause()
+                .downcast_ref::<Error>()
+        );
+
+        // With the master key, the key blob is encrypted again using AES-GCM.
+        let super_key: Option<Arc<dyn AesGcmKey>> = Some(Arc::new(TestKey(master_key)));
+        if let (Some((Blob { flags, value }, _params)), None, None) =
+            legacy_blob_loader.load_by_uid_alias(10223, "prep", &super_key)?
+        {
+            assert_eq!(flags, flags::ENCRYPTED);
+            if let BlobValue::Encrypted { iv, tag, data } = value {
+                let payload = super_key.as_ref().unwrap().decrypt(&data, &iv, &tag)?;
+                assert_eq!(&payload[..], PRE_P_USRPKEY_PAYLOAD);
+            } else {
+                panic!("Key blob should be encrypted.");
+            }
+        } else {
+            panic!("Key blob should have been loaded.");
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_keystore_entry() -> anyhow::Result<()> {
+        const KM_BLOB: &[u8] = b"shadowed km blob";
+        let temp_dir = TempDir::new("test_write_keystore_entry").unwrap();
+        let legacy_blob_loader = LegacyBlobLoader::new(temp_dir.path());
+
+        legacy_blob_loader.write_keystore_entry(
+            10223,
+            "shadow",
+            KM_BLOB,
+            true,
+            Some(LOADED_CERT_AUTHBOUND),
+            Some(LOADED_CACERT_AUTHBOUND),
+        )?;
+        assert_eq!(
+            legacy_blob_loader.list_keystore_entries_for_uid(10223)?,
+            vec!["shadow".to_string()]
+        );
+
+        if let (Some((Blob { flags, value }, _params)), Some(cert), Some(chain)) =
+            legacy_blob_loader.load_by_uid_alias(10223, "shadow", &None)?
+        {
+            assert_eq!(flags, flags::STRONGBOX);
+            if let BlobValue::Decrypted(data) = value {
+                assert_eq!(&data[..], KM_BLOB);
+            } else {
+                panic!("Key blob should be unencrypted.");
+            }
+            assert_eq!(&cert[..], LOADED_CERT_AUTHBOUND);
+            assert_eq!(&chain[..], LOADED_CACERT_AUTHBOUND);
+        } else {
+            panic!("Key blob and certificates should have been loaded.");
+        }
+
+        // Replacing the entry removes the certificates of the old entry.
+        legacy_blob_loader.write_keystore_entry(10223, "shadow", KM_BLOB, false, None, None)?;
+        if let (Some((Blob { flags, value: BlobValue::Decrypted(data) }, _params)), None, None) =
+            legacy_blob_loader.load_by_uid_alias(10223, "shadow", &None)?
+        {
+            assert_eq!(flags, 0);
+            assert_eq!(&data[..], KM_BLOB);
+        } else {
+            panic!("Only the unencrypted key blob should have been loaded.");
+        }
+
+        assert!(legacy_blob_loader.remove_keystore_entry(10223, "shadow")?);
+        assert!(legacy_blob_loader.is_empty()?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_quarantine_corrupted_blobs() -> anyhow::Result<()> {
+        let temp_dir = TempDir::new("test_quarantine_corrupted_blobs").unwrap();
+        std::fs::create_dir(&*temp_dir.build().push("user_0"))?;
+        std::fs::write(
+            &*temp_dir.build().push("user_0").push("10223_USRPKEY_non_authbound"),
+            &USRPKEY_NON_AUTHBOUND[..20],
+        )?;
+        std::fs::write(
+            &*temp_dir.build().push("user_0").push("10223_USRCERT_non_authbound"),
+            USRCERT_NON_AUTHBOUND,
+        )?;
+        std::fs::write(
+            &*temp_dir.build().push("user_0").push("10223_CACERT_non_authbound"),
+            CACERT_NON_AUTHBOUND,
+        )?;
+
+        let legacy_blob_loader = LegacyBlobLoader::new(temp_dir.path());
+
+        // The truncated key blob is skipped, but the certificates can still be loaded.
+        let (km_blob, cert, chain) =
+            legacy_blob_loader.load_by_uid_alias(10223, "non_authbound", &None)?;
+        assert!(km_blob.is_none());
+        assert_eq!(cert.as_deref(), Some(LOADED_CERT_NON_AUTHBOUND));
+        assert_eq!(chain.as_deref(), Some(LOADED_CACERT_NON_AUTHBOUND));
+
+        // The truncated key blob was moved to the quarantine directory.
+        assert!(!temp_dir.build().push("user_0").push("10223_USRPKEY_non_authbound").is_file());
+        assert_eq!(
+            std::fs::read(
+                &*temp_dir
+                    .build()
+                    .push(LegacyBlobLoader::QUARANTINE_DIR)
+                    .push("user_0")
+                    .push("10223_USRPKEY_non_authbound")
+            )?,
+            &USRPKEY_NON_AUTHBOUND[..20]
+        );
+
+        // The quarantine directory does not count as legacy user data.
+        std::fs::remove_dir_all(&*temp_dir.build().push("user_0"))?;
+        assert!(legacy_blob_loader.is_empty()?);
+
+        // Quarantined files go away with their uid or user.
+        let quarantined =
+            |name: &str| temp_dir.build().push(LegacyBlobLoader::QUARANTINE_DIR).push(name);
+        std::fs::write(&*quarantined("user_0").push(".10224_chr_USRPKEY_other"), b"corrupted")?;
+        legacy_blob_loader.remove_quarantined_uid(10223)?;
+        assert!(!quarantined("user_0").push("10223_USRPKEY_non_authbound").is_file());
+        assert!(quarantined("user_0").push(".10224_chr_USRPKEY_other").is_file());
+        legacy_blob_loader.remove_quarantined_user(0)?;
+        assert!(!quarantined("user_0").exists());
+        // Removing the quarantined files of a user without any is not an error.
+        legacy_blob_loader.remove_quarantined_user(0)?;
+
+        legacy_blob_loader.delete_all()?;
+        assert!(!temp_dir.build().push(LegacyBlobLoader::QUARANTINE_DIR).exists());
+
+        Ok(())
+    }
+
     #[test]
     fn test_legacy_blobs() -> anyhow::Result<()> {
         let temp_dir = TempDir::new("legacy_blob_test").unwrap();
@@ -2208,7 +2737,7 @@ mod test {
             Some(&Error::LockedComponent)
         );
 
-        let super_key: Option<Arc<dyn AesGcm>> = Some(super_key);
+        let super_key: Option<Arc<dyn AesGcmKey>> = Some(super_key);
 
         assert_eq!(
             legacy_blob_loader.load_by_uid_alias(10223, "authbound", &super_key).unwrap(),
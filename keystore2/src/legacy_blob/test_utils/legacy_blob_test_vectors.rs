@@ -1482,3 +1482,39 @@ pub static LOADED_CACERT_NON_AUTHBOUND: &[u8] = &[
     0x40, 0xe1, 0x44, 0x52, 0x87, 0xbe, 0xd8, 0x77, 0xab, 0xae, 0x24, 0xe2, 0x44, 0x35, 0x16, 0x8d,
     0x55, 0x3c, 0xe4,
 ];
+
+/// Password protecting the pre Android P master key.
+pub static PRE_P_PASSWORD: &[u8] =
+    &[0x70, 0x72, 0x65, 0x2d, 0x70, 0x20, 0x70, 0x61, 0x73, 0x73, 0x77, 0x6f, 0x72, 0x64];
+
+/// Master key blob written before Android P (version 2, AES-128-CBC).
+pub static PRE_P_MASTERKEY: &[u8] = &[
+    0x02, 0x02, 0x01, 0x10, 0xef, 0x5c, 0x01, 0x29, 0xe2, 0xf9, 0x64, 0xf9, 0xf5, 0x10, 0x4b, 0xf8,
+    0xa3, 0xd4, 0x80, 0x7e, 0xe5, 0xb9, 0x7b, 0x5a, 0xb4, 0xfd, 0xce, 0x8d, 0x5e, 0xf4, 0x06, 0x65,
+    0x97, 0xa5, 0x77, 0x5e, 0x00, 0x6f, 0x52, 0x87, 0xd0, 0xb6, 0x04, 0xaf, 0xd6, 0x09, 0x33, 0xa2,
+    0x6c, 0xe9, 0x20, 0xa4, 0x75, 0x92, 0x7b, 0xbb, 0x31, 0x34, 0x30, 0xcf, 0xd4, 0xce, 0x10, 0x8a,
+    0x5b, 0x64, 0xd0, 0x13, 0x11, 0x11, 0x4b, 0x28, 0xc9, 0x55, 0xfb, 0x8a, 0x24, 0x2c, 0x5e, 0xdc,
+    0x22, 0xae, 0x05, 0x04,
+];
+
+/// Plaintext of the pre Android P master key.
+pub static PRE_P_MASTERKEY_PAYLOAD: &[u8] = &[
+    0xc9, 0xc5, 0x6c, 0xec, 0xd9, 0x1c, 0x3b, 0xc1, 0x93, 0xad, 0x83, 0xfd, 0xd0, 0xd3, 0x17, 0x76,
+];
+
+/// User key blob written before Android P, encrypted with the master key.
+pub static PRE_P_USRPKEY: &[u8] = &[
+    0x02, 0x04, 0x01, 0x00, 0xa5, 0x86, 0xea, 0x5c, 0x45, 0xce, 0x16, 0x68, 0x46, 0xf7, 0x58, 0x97,
+    0x9e, 0xf3, 0xeb, 0x5a, 0x53, 0x61, 0xd2, 0xa3, 0x2b, 0x0e, 0x03, 0x25, 0x21, 0xc3, 0x58, 0xc5,
+    0x31, 0x8a, 0x65, 0x9f, 0xe4, 0xa6, 0x98, 0x6b, 0xef, 0x81, 0xd5, 0xa7, 0xec, 0xac, 0xe7, 0x71,
+    0x48, 0x7c, 0x22, 0x42, 0xbd, 0x11, 0x69, 0x95, 0xb6, 0x62, 0x8a, 0xad, 0xf9, 0xf6, 0x98, 0x86,
+    0xba, 0x0f, 0xad, 0x36, 0x1a, 0x5b, 0x81, 0xc2, 0xb0, 0xb0, 0x8b, 0x9d, 0xb7, 0xec, 0x79, 0xa3,
+    0x05, 0xca, 0xb0, 0x48,
+];
+
+/// Plaintext of the pre Android P user key blob.
+pub static PRE_P_USRPKEY_PAYLOAD: &[u8] = &[
+    0x22, 0xd0, 0x58, 0xbe, 0x61, 0xb1, 0xcf, 0x4d, 0x2c, 0xaf, 0x64, 0xc0, 0x3f, 0xdd, 0xe6, 0xb9,
+    0xc9, 0xf4, 0x4d, 0x9f, 0xe6, 0x87, 0xb9, 0xbf, 0x0d, 0xdc, 0x51, 0x62, 0xdd, 0x56, 0xb2, 0x9b,
+    0xdc, 0xcb, 0x03, 0x5b, 0xe4, 0xaf, 0x69, 0xf7,
+];
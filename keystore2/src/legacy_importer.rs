@@ -16,15 +16,19 @@
 
 use crate::database::{
     BlobInfo, BlobMetaData, BlobMetaEntry, CertificateInfo, DateTime, EncryptedBy, KeyMetaData,
-    KeyMetaEntry, KeyType, KeystoreDB, Uuid, KEYSTORE_UUID,
+    KeyMetaEntry, KeyType, KeystoreDB, LegacyImportState, Uuid, KEYSTORE_UUID,
 };
 use crate::error::{map_km_error, Error};
+use crate::globals::ASYNC_TASK;
+use crate::idle_maintenance;
 use crate::key_parameter::{KeyParameter, KeyParameterValue};
 use crate::legacy_blob::{self, Blob, BlobValue, LegacyKeyCharacteristics};
+use crate::metrics_store::log_legacy_key_migration_stats;
+use crate::safe_mode;
 use crate::super_key::USER_SUPER_KEY;
 use crate::utils::{
     key_characteristics_to_internal, uid_to_android_user, upgrade_keyblob_if_required_with,
-    watchdog as wd, AesGcm,
+    watchdog as wd, AesGcm, AesGcmKey,
 };
 use crate::{async_task::AsyncTask, legacy_blob::LegacyBlobLoader};
 use android_hardware_security_keymint::aidl::android::hardware::security::keymint::SecurityLevel::SecurityLevel;
@@ -45,7 +49,8 @@ pub struct LegacyImporter {
     initializer: Mutex<
         Option<
             Box<
-                dyn FnOnce() -> (KeystoreDB, HashMap<SecurityLevel, Uuid>, Arc<LegacyBlobLoader>)
+                dyn Fn()
+                        -> Result<(KeystoreDB, HashMap<SecurityLevel, Uuid>, Arc<LegacyBlobLoader>)>
                     + Send
                     + 'static,
             >,
@@ -56,6 +61,19 @@ pub struct LegacyImporter {
     /// When transitioning from READY to EMPTY, spurious calls may occur for a brief period
     /// of time. This is tolerable in favor of the common case.
     state: AtomicU8,
+    /// Progress of the bulk imports scheduled since boot, indexed by Android user id.
+    bulk_imports: Mutex<HashMap<u32, BulkImportProgress>>,
+}
+
+/// Progress of the bulk import of the legacy keys of a single Android user.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct BulkImportProgress {
+    /// Number of keys imported into the database so far.
+    pub imported: usize,
+    /// Number of keys that could not be imported and remain in the legacy database.
+    pub failed: usize,
+    /// Number of keys that have not been processed yet.
+    pub remaining: usize,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
@@ -84,8 +102,8 @@ struct LegacyImporterState {
 }
 
 impl LegacyImporter {
-    const WIFI_NAMESPACE: i64 = 102;
-    const AID_WIFI: u32 = 1010;
+    pub(crate) const WIFI_NAMESPACE: i64 = 102;
+    pub(crate) const AID_WIFI: u32 = 1010;
 
     const STATE_UNINITIALIZED: u8 = 0;
     const STATE_READY: u8 = 1;
@@ -98,16 +116,18 @@ impl LegacyImporter {
             async_task,
             initializer: Default::default(),
             state: AtomicU8::new(Self::STATE_UNINITIALIZED),
+            bulk_imports: Default::default(),
         }
     }
 
     /// The legacy importer must be initialized deferred, because keystore starts very early.
     /// At this time the data partition may not be mounted. So we cannot open database connections
     /// until we get actual key load requests. This sets the function that the legacy loader
-    /// uses to connect to the database.
+    /// uses to connect to the database. If the initializer fails, it is called again on the
+    /// next import request.
     pub fn set_init<F>(&self, f_init: F) -> Result<()>
     where
-        F: FnOnce() -> (KeystoreDB, HashMap<SecurityLevel, Uuid>, Arc<LegacyBlobLoader>)
+        F: Fn() -> Result<(KeystoreDB, HashMap<SecurityLevel, Uuid>, Arc<LegacyBlobLoader>)>
             + Send
             + 'static,
     {
@@ -147,8 +167,10 @@ impl LegacyImporter {
                     // READY.
                     let mut initializer = self.initializer.lock().unwrap();
 
-                    if let Some(initializer) = initializer.take() {
-                        let (db, sec_level_to_km_uuid, legacy_loader) = (initializer)();
+                    if let Some(init) = initializer.as_ref() {
+                        let (db, sec_level_to_km_uuid, legacy_loader) =
+                            (init)().context("In check_state: Trying to initialize.")?;
+                        *initializer = None;
 
                         if legacy_loader.is_empty().context(
                             "In check_state: Trying to check if the legacy database is empty.",
@@ -158,12 +180,21 @@ impl LegacyImporter {
                         }
 
                         self.async_task.queue_hi(move |shelf| {
-                            shelf.get_or_put_with(|| LegacyImporterState {
-                                recently_imported: Default::default(),
-                                recently_imported_super_key: Default::default(),
-                                legacy_loader,
-                                sec_level_to_km_uuid,
-                                db,
+                            shelf.get_or_put_with(|| {
+                                let mut state = LegacyImporterState {
+                                    recently_imported: Default::default(),
+                                    recently_imported_super_key: Default::default(),
+                                    legacy_loader,
+                                    sec_level_to_km_uuid,
+                                    db,
+                                };
+                                if let Err(e) = state.replay_import_journal() {
+                                    log::error!(
+                                        "In check_state: Failed to replay import journal: {:?}",
+                                        e
+                                    );
+                                }
+                                state
                             });
                         });
 
@@ -288,7 +319,7 @@ impl LegacyImporter {
         &self,
         key: &KeyDescriptor,
         caller_uid: u32,
-        super_key: Option<Arc<dyn AesGcm + Send + Sync>>,
+        super_key: Option<Arc<dyn AesGcmKey + Send + Sync>>,
         key_accessor: F,
     ) -> Result<T>
     where
@@ -326,7 +357,7 @@ impl LegacyImporter {
 
         let key_clone = key.clone();
         let result = self.do_serialized(move |importer_state| {
-            let super_key = super_key.map(|sk| -> Arc<dyn AesGcm> { sk });
+            let super_key = super_key.map(|sk| -> Arc<dyn AesGcmKey> { sk });
             importer_state.check_and_import(uid, key_clone, super_key)
         });
 
@@ -407,6 +438,192 @@ impl LegacyImporter {
         result.unwrap_or(Ok(()))
     }
 
+    /// Deletes all legacy keys, super keys, and legacy keystore entries of all users without
+    /// importing them. This is only used when all keys were deleted from all KeyMint instances,
+    /// so there is nothing left for the garbage collector to delete.
+    pub fn delete_all(&self) -> Result<()> {
+        let _wp = wd::watch_millis("LegacyImporter::delete_all", 500);
+
+        let result = self.do_serialized(move |importer_state| {
+            importer_state.recently_imported.clear();
+            importer_state.recently_imported_super_key.clear();
+            importer_state.legacy_loader.delete_all()
+        });
+
+        result.unwrap_or(Ok(()))
+    }
+
+    /// Schedules the import of all legacy keys of the given Android user into the database.
+    /// This is called when the user unlocks the device with the LSKF, so that super encrypted
+    /// keys can be imported as well. The import runs on the async task as a sequence of low
+    /// priority jobs, each issuing the import request of a single key, so that neither lazy
+    /// import requests nor other jobs of the async task are blocked for its whole duration.
+    /// Each key is removed from the legacy database right after it was stored in the database.
+    /// A bulk import is scheduled at most once per user and boot, and it may be deferred until
+    /// the device is idle and charging, see `idle_maintenance`.
+    pub fn schedule_bulk_import_user(
+        importer: &Arc<LegacyImporter>,
+        user_id: u32,
+        super_key: Option<Arc<dyn AesGcmKey + Send + Sync>>,
+    ) {
+        if importer.state.load(Ordering::Relaxed) == Self::STATE_EMPTY {
+            return;
+        }
+
+        // Keys that were not imported yet are still imported lazily on use.
+        if safe_mode::skip("legacy key import") {
+            return;
+        }
+
+        {
+            let mut bulk_imports = importer.bulk_imports.lock().unwrap();
+            if bulk_imports.contains_key(&user_id) {
+                return;
+            }
+            bulk_imports.insert(user_id, Default::default());
+        }
+
+        // The import is heavy and may be deferred until the device is idle and charging,
+        // because keys that were not imported yet are still imported lazily on use.
+        let importer = importer.clone();
+        idle_maintenance::queue_lo("legacy key import", move || {
+            match importer.list_bulk_import_entries(user_id) {
+                Ok(entries) => Self::queue_bulk_import_step(importer, user_id, entries, super_key),
+                Err(e) => {
+                    log::error!(
+                        "In schedule_bulk_import_user: Bulk import for user {} failed: {:?}",
+                        user_id,
+                        e
+                    );
+                    // Allow the bulk import to be scheduled again on the next unlock.
+                    importer.bulk_imports.lock().unwrap().remove(&user_id);
+                }
+            }
+        });
+    }
+
+    /// Queues a low priority job that imports the last of the given entries and then queues
+    /// the import of the remaining entries.
+    fn queue_bulk_import_step(
+        importer: Arc<LegacyImporter>,
+        user_id: u32,
+        mut entries: Vec<(u32, String)>,
+        super_key: Option<Arc<dyn AesGcmKey + Send + Sync>>,
+    ) {
+        ASYNC_TASK.queue_lo(move |_| {
+            let (uid, alias) = match entries.pop() {
+                Some(entry) => entry,
+                None => {
+                    log::info!(
+                        "Finished importing legacy keys of user {}: {:?}",
+                        user_id,
+                        importer.bulk_imports.lock().unwrap().get(&user_id)
+                    );
+                    return;
+                }
+            };
+            if importer.bulk_import_key(user_id, uid, alias, super_key.clone()) {
+                Self::queue_bulk_import_step(importer, user_id, entries, super_key);
+            }
+        });
+    }
+
+    /// Lists the legacy keys of the given user for a bulk import.
+    fn list_bulk_import_entries(&self, user_id: u32) -> Result<Vec<(u32, String)>> {
+        let entries = match self.do_serialized(move |importer_state| {
+            importer_state
+                .legacy_loader
+                .list_keystore_entries_for_user(user_id)
+                .context("In list_bulk_import_entries: Trying to list legacy entries.")
+        }) {
+            Some(entries) => entries?,
+            None => HashMap::new(),
+        };
+
+        let entries: Vec<(u32, String)> = entries
+            .into_iter()
+            .flat_map(|(uid, aliases)| aliases.into_iter().map(move |alias| (uid, alias)))
+            .collect();
+        self.update_bulk_import_progress(user_id, |progress| progress.remaining = entries.len());
+        log::info!("Importing {} legacy keys of user {}.", entries.len(), user_id);
+        Ok(entries)
+    }
+
+    /// Imports a single key as part of the bulk import of the given user. Returns false if the
+    /// legacy database turned out to be empty, i.e., if there is nothing left to import.
+    fn bulk_import_key(
+        &self,
+        user_id: u32,
+        uid: u32,
+        alias: String,
+        super_key: Option<Arc<dyn AesGcmKey + Send + Sync>>,
+    ) -> bool {
+        let key = match uid {
+            Self::AID_WIFI => KeyDescriptor {
+                domain: Domain::SELINUX,
+                nspace: Self::WIFI_NAMESPACE,
+                alias: Some(alias),
+                blob: None,
+            },
+            _ => KeyDescriptor {
+                domain: Domain::APP,
+                nspace: uid as i64,
+                alias: Some(alias),
+                blob: None,
+            },
+        };
+        let result = self.do_serialized(move |importer_state| {
+            let super_key = super_key.map(|sk| -> Arc<dyn AesGcmKey> { sk });
+            importer_state.check_and_import(uid, key, super_key)
+        });
+
+        let imported = match result {
+            // The legacy database is empty, so all remaining keys are gone.
+            None => {
+                self.update_bulk_import_progress(user_id, |progress| progress.remaining = 0);
+                return false;
+            }
+            Some(Ok(())) => Some(true),
+            Some(Err(e)) => match e.root_cause().downcast_ref::<Error>() {
+                // The key was deleted in the meantime or could never be unlocked.
+                Some(&Error::Rc(ResponseCode::KEY_NOT_FOUND)) => None,
+                _ => {
+                    log::warn!("In bulk_import_key: Failed to import key: {:?}", e);
+                    Some(false)
+                }
+            },
+        };
+        if let Some(imported) = imported {
+            log_legacy_key_migration_stats(imported);
+        }
+        self.update_bulk_import_progress(user_id, |progress| {
+            progress.remaining -= 1;
+            match imported {
+                Some(true) => progress.imported += 1,
+                Some(false) => progress.failed += 1,
+                None => {}
+            }
+        });
+        true
+    }
+
+    fn update_bulk_import_progress<F>(&self, user_id: u32, f: F)
+    where
+        F: FnOnce(&mut BulkImportProgress),
+    {
+        if let Some(progress) = self.bulk_imports.lock().unwrap().get_mut(&user_id) {
+            f(progress)
+        }
+    }
+
+    /// Returns the progress of the bulk imports scheduled since boot ordered by user id.
+    pub fn get_bulk_import_progress(&self) -> Vec<(u32, BulkImportProgress)> {
+        let mut result: Vec<(u32, BulkImportProgress)> =
+            self.bulk_imports.lock().unwrap().iter().map(|(k, v)| (*k, *v)).collect();
+        result.sort_unstable_by_key(|(user_id, _)| *user_id);
+        result
+    }
+
     /// Queries the legacy database for the presence of a super key for the given user.
     pub fn has_super_key(&self, user_id: u32) -> Result<bool> {
         let result =
@@ -429,9 +646,65 @@ impl LegacyImporterState {
     }
 
     fn list_uid(&mut self, uid: u32) -> Result<Vec<String>> {
-        self.legacy_loader
+        // Keys that were imported but not yet removed from the legacy database, and keys that
+        // were mirrored by the shadow-write mode are already listed from the database.
+        let imported: HashSet<String> = self
+            .db
+            .get_legacy_import_journal()
+            .context("In list_uid: Trying to get import journal.")?
+            .into_iter()
+            .filter(|entry| entry.uid == uid && entry.state != LegacyImportState::Importing)
+            .map(|entry| entry.alias)
+            .collect();
+        let mut entries = self
+            .legacy_loader
             .list_keystore_entries_for_uid(uid)
-            .context("In list_uid: Trying to list legacy entries.")
+            .context("In list_uid: Trying to list legacy entries.")?;
+        entries.retain(|alias| !imported.contains(alias));
+        Ok(entries)
+    }
+
+    /// Completes the imports found in the import journal. These were interrupted, e.g., by a
+    /// reboot, and may have left a key in both the database and the legacy database.
+    /// Imports that had not stored the key in the database are discarded. These keys are
+    /// still in the legacy database and will be imported again on demand.
+    fn replay_import_journal(&mut self) -> Result<()> {
+        let journal = self
+            .db
+            .get_legacy_import_journal()
+            .context("In replay_import_journal: Trying to get import journal.")?;
+        for entry in journal {
+            let imported = match entry.state {
+                // Mirrored keys are not imported.
+                LegacyImportState::Shadowed => continue,
+                LegacyImportState::Imported => true,
+                LegacyImportState::Importing => self
+                    .db
+                    .key_exists(entry.domain, entry.namespace, &entry.alias, KeyType::Client)
+                    .context("In replay_import_journal: Trying to check for imported key.")?,
+            };
+            if imported {
+                self.finish_import(entry.uid, &entry.alias)
+                    .context("In replay_import_journal: Trying to finish import.")?;
+            } else {
+                self.db
+                    .clear_legacy_import_state(entry.uid, &entry.alias)
+                    .context("In replay_import_journal: Trying to discard import.")?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes a key that was stored in the database from the legacy database and then from
+    /// the import journal.
+    fn finish_import(&mut self, uid: u32, alias: &str) -> Result<()> {
+        self.recently_imported.insert(RecentImport::new(uid, alias.to_string()));
+        self.legacy_loader
+            .remove_keystore_entry(uid, alias)
+            .context("In finish_import: Trying to remove imported key.")?;
+        self.db
+            .clear_legacy_import_state(uid, alias)
+            .context("In finish_import: Trying to clear import journal.")
     }
 
     /// Checks if the key can potentially be unlocked. And deletes the key entry otherwise.
@@ -479,7 +752,7 @@ impl LegacyImporterState {
     fn characteristics_file_to_cache(
         &mut self,
         km_blob_params: Option<(Blob, LegacyKeyCharacteristics)>,
-        super_key: &Option<Arc<dyn AesGcm>>,
+        super_key: &Option<Arc<dyn AesGcmKey>>,
         uid: u32,
         alias: &str,
     ) -> Result<(Option<(Blob, Vec<KeyParameter>)>, Option<(LegacyBlob<'static>, BlobMetaData)>)>
@@ -592,7 +865,7 @@ impl LegacyImporterState {
         &mut self,
         uid: u32,
         mut key: KeyDescriptor,
-        super_key: Option<Arc<dyn AesGcm>>,
+        super_key: Option<Arc<dyn AesGcmKey>>,
     ) -> Result<()> {
         let alias = key.alias.clone().ok_or_else(|| {
             anyhow::anyhow!(Error::sys()).context(
@@ -609,6 +882,17 @@ impl LegacyImporterState {
             key.nspace = uid as i64;
         }
 
+        // Legacy entries written by the shadow-write mode are copies of database keys.
+        if self
+            .db
+            .get_legacy_import_state(uid, &alias)
+            .context("In check_and_import: Trying to get import state.")?
+            == Some(LegacyImportState::Shadowed)
+        {
+            return Err(Error::Rc(ResponseCode::KEY_NOT_FOUND))
+                .context("In check_and_import: Legacy entry is a shadow copy.");
+        }
+
         // If the key is not found in the cache, try to load from the legacy database.
         let (km_blob_params, user_cert, ca_cert) = self
             .legacy_loader
@@ -679,8 +963,8 @@ impl LegacyImporterState {
                     superseded_blob.as_ref().map(|(b, m)| (&**b, m)),
                 );
                 // Store legacy key in the database.
-                self.db
-                    .store_new_key(
+                self.store_journaled(uid, &alias, &key, |db| {
+                    db.store_new_key(
                         &key,
                         KeyType::Client,
                         &params,
@@ -688,15 +972,20 @@ impl LegacyImporterState {
                         &CertificateInfo::new(user_cert, ca_cert),
                         &metadata,
                         &km_uuid,
+                        false,
                     )
-                    .context("In check_and_import.")?;
+                    .map(|_| ())
+                })
+                .context("In check_and_import.")?;
                 Ok(())
             }
             None => {
                 if let Some(ca_cert) = ca_cert {
-                    self.db
-                        .store_new_certificate(&key, KeyType::Client, &ca_cert, &KEYSTORE_UUID)
-                        .context("In check_and_import: Failed to insert new certificate.")?;
+                    self.store_journaled(uid, &alias, &key, |db| {
+                        db.store_new_certificate(&key, KeyType::Client, &ca_cert, &KEYSTORE_UUID)
+                            .map(|_| ())
+                    })
+                    .context("In check_and_import: Failed to insert new certificate.")?;
                     Ok(())
                 } else {
                     Err(Error::Rc(ResponseCode::KEY_NOT_FOUND))
@@ -706,19 +995,53 @@ impl LegacyImporterState {
         };
 
         match result {
-            Ok(()) => {
-                // Add the key to the imported_keys list.
-                self.recently_imported.insert(RecentImport::new(uid, alias.clone()));
-                // Delete legacy key from the file system
-                self.legacy_loader
-                    .remove_keystore_entry(uid, &alias)
-                    .context("In check_and_import: Trying to remove imported key.")?;
-                Ok(())
-            }
+            // Delete legacy key from the file system
+            Ok(()) => self.finish_import(uid, &alias).context("In check_and_import."),
             Err(e) => Err(e),
         }
     }
 
+    /// Stores an imported key in the database using `store`. The import is journaled, so that
+    /// the key is removed from the legacy database even if Keystore is interrupted before that.
+    fn store_journaled<F>(
+        &mut self,
+        uid: u32,
+        alias: &str,
+        key: &KeyDescriptor,
+        store: F,
+    ) -> Result<()>
+    where
+        F: FnOnce(&mut KeystoreDB) -> Result<()>,
+    {
+        self.db
+            .set_legacy_import_state(
+                uid,
+                alias,
+                key.domain,
+                key.nspace,
+                LegacyImportState::Importing,
+            )
+            .context("In store_journaled: Trying to journal import.")?;
+        if let Err(e) = store(&mut self.db) {
+            if let Err(journal_error) = self.db.clear_legacy_import_state(uid, alias) {
+                log::error!(
+                    "In store_journaled: Failed to clear import journal: {:?}",
+                    journal_error
+                );
+            }
+            return Err(e).context("In store_journaled: Trying to store key.");
+        }
+        self.db
+            .set_legacy_import_state(
+                uid,
+                alias,
+                key.domain,
+                key.nspace,
+                LegacyImportState::Imported,
+            )
+            .context("In store_journaled: Trying to update import journal.")
+    }
+
     fn check_and_import_super_key(&mut self, user_id: u32, pw: &Password) -> Result<()> {
         if self.recently_imported_super_key.contains(&user_id) {
             return Ok(());
@@ -859,7 +1182,20 @@ impl LegacyImporterState {
                 .remove_keystore_entry(uid, &alias)
                 .context("In bulk_delete: Trying to remove imported key.")?;
         }
-        Ok(())
+
+        // Quarantined files cannot be parsed, so it is unknown whether they are super encrypted.
+        // They are kept along with the keys that are not super encrypted.
+        match bulk_delete_request {
+            BulkDeleteRequest::Uid(uid) => self
+                .legacy_loader
+                .remove_quarantined_uid(uid)
+                .context("In bulk_delete: Trying to remove quarantined files of uid."),
+            BulkDeleteRequest::User(user_id) if !keep_non_super_encrypted_keys => self
+                .legacy_loader
+                .remove_quarantined_user(user_id)
+                .context("In bulk_delete: Trying to remove quarantined files of user."),
+            BulkDeleteRequest::User(_) => Ok(()),
+        }
     }
 
     fn has_super_key(&mut self, user_id: u32) -> Result<bool> {
@@ -0,0 +1,250 @@
+// Copyright 2021, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements the legacy shadow-write mode. A device that was upgraded to
+//! Keystore 2.0 may be rolled back to a build with the Keymaster based Keystore 1.0, which
+//! only knows the legacy blob database. Keys created under Keystore 2.0 would be lost silently
+//! in that case. If the system property `persist.keystore2.legacy_shadow_write` is set, keys
+//! stored in the database are therefore mirrored to the legacy blob database, and the mirrored
+//! entries are removed when the keys are deleted, one by one or in bulk. Mirrored entries are
+//! recorded in the legacy import journal, so that the legacy importer does not import them
+//! again. If the shadow-write mode is disabled, nothing is mirrored or removed, and the mirrored
+//! entries left behind are purged in the background after the next start.
+//!
+//! Only keys in Domain::APP and the Wi-Fi namespace have a legacy representation. Only key
+//! blobs that km_compat created with a Keymaster device are mirrored, because Keystore 1.0
+//! could not use blobs of native KeyMint devices or the software KeyMint, nor super encrypted
+//! keys.
+
+use crate::config;
+use crate::database::{
+    BlobMetaData, CertificateInfo, KeyEntry, KeystoreDB, LegacyImportState, Uuid,
+};
+use crate::globals::{DB, LEGACY_BLOB_LOADER};
+use crate::idle_maintenance;
+use crate::legacy_importer::LegacyImporter;
+use crate::utils::uid_to_android_user;
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::SecurityLevel::SecurityLevel;
+use android_system_keystore2::aidl::android::system::keystore2::{
+    Domain::Domain, KeyDescriptor::KeyDescriptor,
+};
+use anyhow::{Context, Result};
+
+/// Magic that km_compat prepends to the key blobs it returns, see keyBlobPrefix() in
+/// km_compat.cpp. It is followed by a single byte that is 1 if the blob was generated by the
+/// software KeyMint and 0 otherwise.
+const KM_COMPAT_BLOB_MAGIC: &[u8] = b"pKMblob";
+
+/// Returns true if the legacy shadow-write mode is enabled.
+pub fn is_enabled() -> bool {
+    config::LEGACY_SHADOW_WRITE.get()
+}
+
+/// Returns the legacy uid of the given namespace, or None if the keys of the namespace cannot
+/// be represented in the legacy blob database.
+fn legacy_uid(domain: Domain, nspace: i64) -> Option<u32> {
+    match (domain, nspace) {
+        (Domain::APP, nspace) => Some(nspace as u32),
+        (Domain::SELINUX, LegacyImporter::WIFI_NAMESPACE) => Some(LegacyImporter::AID_WIFI),
+        _ => None,
+    }
+}
+
+/// Returns the legacy uid and alias of the given key, or None if the key cannot be represented
+/// in the legacy blob database. `key` must have the namespace of the owner, i.e., the caller
+/// supplied namespace must have been replaced for Domain::APP.
+fn legacy_uid_alias(key: &KeyDescriptor) -> Option<(u32, &str)> {
+    let uid = legacy_uid(key.domain, key.nspace)?;
+    key.alias.as_deref().map(|alias| (uid, alias))
+}
+
+/// Returns the Keymaster key blob wrapped by km_compat, or None if the blob was not created by
+/// km_compat with a Keymaster device, i.e., if it is a native KeyMint blob or was generated by
+/// the software KeyMint.
+fn strip_km_compat_prefix(key_blob: &[u8]) -> Option<&[u8]> {
+    let prefix_len = KM_COMPAT_BLOB_MAGIC.len() + 1;
+    if key_blob.len() < prefix_len || !key_blob.starts_with(KM_COMPAT_BLOB_MAGIC) {
+        return None;
+    }
+    match key_blob[KM_COMPAT_BLOB_MAGIC.len()] {
+        0 => Some(&key_blob[prefix_len..]),
+        _ => None,
+    }
+}
+
+/// Mirrors a key that was just stored in the database to the legacy blob database.
+/// A previously mirrored key with the same alias is replaced, or removed if the new key
+/// cannot be mirrored. Does nothing if the shadow-write mode is disabled.
+pub fn shadow_store_key(
+    db: &mut KeystoreDB,
+    key: &KeyDescriptor,
+    security_level: SecurityLevel,
+    key_blob: &[u8],
+    blob_metadata: &BlobMetaData,
+    cert_info: &CertificateInfo,
+) -> Result<()> {
+    if !is_enabled() {
+        return Ok(());
+    }
+    let (uid, alias) = match legacy_uid_alias(key) {
+        Some(uid_alias) => uid_alias,
+        None => return Ok(()),
+    };
+
+    let km_blob = match strip_km_compat_prefix(key_blob) {
+        Some(km_blob)
+            if blob_metadata.encrypted_by().is_none()
+                && blob_metadata.max_boot_level().is_none() =>
+        {
+            km_blob
+        }
+        _ => {
+            log::info!("In shadow_store_key: Key cannot be used by Keystore 1.0. Not mirroring.");
+            return shadow_delete_key(db, key).context("In shadow_store_key.");
+        }
+    };
+
+    LEGACY_BLOB_LOADER
+        .write_keystore_entry(
+            uid,
+            alias,
+            km_blob,
+            security_level == SecurityLevel::STRONGBOX,
+            cert_info.cert(),
+            cert_info.cert_chain(),
+        )
+        .context("In shadow_store_key: Trying to write legacy entry.")?;
+    db.set_legacy_import_state(uid, alias, key.domain, key.nspace, LegacyImportState::Shadowed)
+        .context("In shadow_store_key: Trying to journal legacy entry.")
+}
+
+/// Updates the legacy copies of a key that was moved from `source` to `destination` in the
+/// database. The legacy copy at the source location is removed, and the key is mirrored to the
+/// destination location. `key_entry` must have been loaded with the key blob and the
+/// certificates. Does nothing if the shadow-write mode is disabled.
+pub fn shadow_migrate_key(
+    db: &mut KeystoreDB,
+    source: &KeyDescriptor,
+    destination: &KeyDescriptor,
+    key_entry: &KeyEntry,
+) -> Result<()> {
+    if !is_enabled() {
+        return Ok(());
+    }
+    shadow_delete_key(db, source).context("In shadow_migrate_key.")?;
+    let (key_blob, blob_metadata) = match key_entry.key_blob_info() {
+        Some((key_blob, blob_metadata)) => (key_blob, blob_metadata),
+        None => return Ok(()),
+    };
+    let security_level = if *key_entry.km_uuid() == Uuid::from(SecurityLevel::STRONGBOX) {
+        SecurityLevel::STRONGBOX
+    } else {
+        SecurityLevel::TRUSTED_ENVIRONMENT
+    };
+    let cert_info = CertificateInfo::new(key_entry.cert().clone(), key_entry.cert_chain().clone());
+    shadow_store_key(db, destination, security_level, key_blob, blob_metadata, &cert_info)
+        .context("In shadow_migrate_key.")
+}
+
+/// Removes the legacy copy of a key that was deleted from the database. Legacy entries that
+/// were not written by the shadow-write mode are left alone. Does nothing if the shadow-write
+/// mode is disabled.
+pub fn shadow_delete_key(db: &mut KeystoreDB, key: &KeyDescriptor) -> Result<()> {
+    if !is_enabled() {
+        return Ok(());
+    }
+    let (uid, alias) = match legacy_uid_alias(key) {
+        Some(uid_alias) => uid_alias,
+        None => return Ok(()),
+    };
+    if db
+        .get_legacy_import_state(uid, alias)
+        .context("In shadow_delete_key: Trying to get journal entry.")?
+        != Some(LegacyImportState::Shadowed)
+    {
+        return Ok(());
+    }
+    remove_shadowed_entry(db, uid, alias).context("In shadow_delete_key.")
+}
+
+/// Removes the legacy copies of all keys in the given namespace, which are about to be deleted
+/// from the database in bulk. Does nothing if the shadow-write mode is disabled.
+pub fn shadow_delete_namespace(db: &mut KeystoreDB, domain: Domain, nspace: i64) -> Result<()> {
+    if !is_enabled() {
+        return Ok(());
+    }
+    let uid = match legacy_uid(domain, nspace) {
+        Some(uid) => uid,
+        None => return Ok(()),
+    };
+    remove_shadowed_entries(db, |entry_uid| entry_uid == uid)
+        .context("In shadow_delete_namespace.")?;
+    Ok(())
+}
+
+/// Removes the legacy copies of all keys of the given Android user, which are about to be
+/// deleted from the database in bulk. Does nothing if the shadow-write mode is disabled.
+pub fn shadow_delete_user(db: &mut KeystoreDB, user_id: u32) -> Result<()> {
+    if !is_enabled() {
+        return Ok(());
+    }
+    remove_shadowed_entries(db, |uid| uid_to_android_user(uid) == user_id)
+        .context("In shadow_delete_user.")?;
+    Ok(())
+}
+
+/// Schedules the removal of all mirrored legacy entries if the shadow-write mode is disabled.
+/// Otherwise Keystore 1.0 would find keys after a rollback that were deleted while the
+/// shadow-write mode was disabled.
+pub fn schedule_purge_if_disabled() {
+    if is_enabled() {
+        return;
+    }
+    idle_maintenance::queue_lo("legacy shadow purge", || {
+        match DB.with(|db| remove_shadowed_entries(&mut db.borrow_mut()?, |_| true)) {
+            Ok(0) => {}
+            Ok(count) => log::info!("Purged {} mirrored legacy entries.", count),
+            Err(e) => {
+                log::error!("In schedule_purge_if_disabled: Failed to purge entries: {:?}", e)
+            }
+        }
+    });
+}
+
+/// Removes the mirrored legacy entries of all uids for which `matches` returns true. Returns
+/// the number of removed entries.
+fn remove_shadowed_entries<F>(db: &mut KeystoreDB, matches: F) -> Result<usize>
+where
+    F: Fn(u32) -> bool,
+{
+    let entries: Vec<_> = db
+        .get_legacy_import_journal()
+        .context("In remove_shadowed_entries: Trying to get journal.")?
+        .into_iter()
+        .filter(|entry| entry.state == LegacyImportState::Shadowed && matches(entry.uid))
+        .collect();
+    for entry in &entries {
+        remove_shadowed_entry(db, entry.uid, &entry.alias)
+            .context("In remove_shadowed_entries.")?;
+    }
+    Ok(entries.len())
+}
+
+fn remove_shadowed_entry(db: &mut KeystoreDB, uid: u32, alias: &str) -> Result<()> {
+    LEGACY_BLOB_LOADER
+        .remove_keystore_entry(uid, alias)
+        .context("In remove_shadowed_entry: Trying to remove legacy entry.")?;
+    db.clear_legacy_import_state(uid, alias)
+        .context("In remove_shadowed_entry: Trying to clear journal entry.")
+}
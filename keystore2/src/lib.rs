@@ -16,35 +16,59 @@
 #![recursion_limit = "256"]
 
 pub mod apc;
+pub mod async_keygen;
 pub mod async_task;
 pub mod authorization;
+pub mod blob_envelope;
+pub mod blob_upgrade;
 pub mod boot_level_keys;
+pub mod capabilities;
+pub mod concurrency;
+pub mod config;
 pub mod database;
 pub mod ec_crypto;
 pub mod enforcements;
 pub mod entropy;
 pub mod error;
+pub mod error_stats;
 pub mod globals;
+pub mod hal_health;
 pub mod id_rotation;
+pub mod idle_maintenance;
+pub mod key_import;
+pub mod key_info;
 /// Internal Representation of Key Parameter and convenience functions.
 pub mod key_parameter;
+pub mod key_usage;
 pub mod legacy_blob;
 pub mod legacy_importer;
+pub mod legacy_shadow;
+pub mod log_throttle;
 pub mod maintenance;
 pub mod metrics;
 pub mod metrics_store;
+pub mod namespaces;
+pub mod one_shot_operations;
 pub mod operation;
 pub mod permission;
+pub mod pruning_stats;
+pub mod rate_limit;
 pub mod raw_device;
 pub mod remote_provisioning;
+pub mod safe_mode;
 pub mod security_level;
 pub mod service;
+pub mod shared_memory_operations;
 pub mod shared_secret_negotiation;
+#[cfg(all(test, feature = "keystore2_simulator"))]
+pub mod simulator;
+pub mod trace;
 pub mod utils;
 
 mod attestation_key_utils;
 mod audit_log;
 mod gc;
+mod hw_bound_kdf;
 mod km_compat;
 mod super_key;
 
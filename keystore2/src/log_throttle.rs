@@ -0,0 +1,139 @@
+// Copyright 2021, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module throttles repetitive log messages. A client that retries a failing call in a
+//! tight loop would otherwise flood the log with identical error messages. Identical messages
+//! are logged at most once per window. The number of messages suppressed in a window is
+//! reported along with the next message that is logged.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Time within which identical messages are logged only once.
+const THROTTLE_WINDOW: Duration = Duration::from_secs(10);
+
+/// Maximal number of distinct messages that are tracked.
+const MAX_TRACKED_MESSAGES: usize = 128;
+
+struct Entry {
+    window_start: Instant,
+    suppressed: u32,
+}
+
+/// Deduplicates identical log messages within a time window.
+pub struct LogThrottle {
+    window: Duration,
+    entries: Mutex<HashMap<u64, Entry>>,
+}
+
+impl Default for LogThrottle {
+    fn default() -> Self {
+        Self::new(THROTTLE_WINDOW)
+    }
+}
+
+impl LogThrottle {
+    /// Creates a throttle that logs identical messages at most once per `window`.
+    pub fn new(window: Duration) -> Self {
+        Self { window, entries: Default::default() }
+    }
+
+    /// Logs `msg` at the given level unless an identical message was logged within the
+    /// current window.
+    pub fn log(&self, level: log::Level, msg: &str) {
+        match self.admit(msg, Instant::now()) {
+            Some(0) => log::log!(level, "{}", msg),
+            Some(suppressed) => {
+                log::log!(level, "{} (suppressed {} similar messages)", msg, suppressed)
+            }
+            None => {}
+        }
+    }
+
+    /// Logs `msg` as error unless an identical message was logged within the current window.
+    pub fn error(&self, msg: &str) {
+        self.log(log::Level::Error, msg)
+    }
+
+    /// Returns the number of messages suppressed since `msg` was last logged if it is to be
+    /// logged now, and None if it is suppressed.
+    fn admit(&self, msg: &str, now: Instant) -> Option<u32> {
+        let mut hasher = DefaultHasher::new();
+        msg.hash(&mut hasher);
+        let key = hasher.finish();
+
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(entry) = entries.get_mut(&key) {
+            if now.duration_since(entry.window_start) < self.window {
+                entry.suppressed += 1;
+                return None;
+            }
+            let suppressed = entry.suppressed;
+            *entry = Entry { window_start: now, suppressed: 0 };
+            return Some(suppressed);
+        }
+
+        if entries.len() >= MAX_TRACKED_MESSAGES {
+            let window = self.window;
+            entries.retain(|_, e| now.duration_since(e.window_start) < window);
+        }
+        if entries.len() >= MAX_TRACKED_MESSAGES {
+            if let Some(oldest) =
+                entries.iter().min_by_key(|(_, e)| e.window_start).map(|(key, _)| *key)
+            {
+                entries.remove(&oldest);
+            }
+        }
+        entries.insert(key, Entry { window_start: now, suppressed: 0 });
+        Some(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_identical_messages_are_suppressed_within_window() {
+        let throttle = LogThrottle::new(Duration::from_secs(10));
+        let start = Instant::now();
+
+        assert_eq!(throttle.admit("failure", start), Some(0));
+        assert_eq!(throttle.admit("failure", start + Duration::from_secs(1)), None);
+        assert_eq!(throttle.admit("failure", start + Duration::from_secs(2)), None);
+        assert_eq!(throttle.admit("other failure", start + Duration::from_secs(2)), Some(0));
+
+        // The next window reports the suppressed messages of the previous one.
+        assert_eq!(throttle.admit("failure", start + Duration::from_secs(10)), Some(2));
+        assert_eq!(throttle.admit("failure", start + Duration::from_secs(21)), Some(0));
+    }
+
+    #[test]
+    fn test_number_of_tracked_messages_is_bounded() {
+        let throttle = LogThrottle::new(Duration::from_secs(10));
+        let start = Instant::now();
+
+        for i in 0..MAX_TRACKED_MESSAGES + 1 {
+            let now = start + Duration::from_millis(i as u64);
+            assert_eq!(throttle.admit(&format!("failure {}", i), now), Some(0));
+        }
+        assert_eq!(throttle.entries.lock().unwrap().len(), MAX_TRACKED_MESSAGES);
+        // The oldest message was evicted and is logged again.
+        assert_eq!(throttle.admit("failure 0", start + Duration::from_secs(1)), Some(0));
+        assert_eq!(throttle.admit("failure 2", start + Duration::from_secs(1)), None);
+    }
+}
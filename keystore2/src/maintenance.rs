@@ -14,31 +14,45 @@
 
 //! This module implements IKeystoreMaintenance AIDL interface.
 
-use crate::database::{KeyEntryLoadBits, KeyType, MonotonicRawTime};
+use crate::audit_log::log_key_deleted;
+use crate::database::{BootTime, CertificateBackup, DateTime, Grantee, KeyEntryLoadBits, KeyType};
+use crate::error::get_error_code;
 use crate::error::map_km_error;
 use crate::error::map_or_log_err;
 use crate::error::Error;
-use crate::globals::get_keymint_device;
-use crate::globals::{DB, LEGACY_IMPORTER, SUPER_KEY};
-use crate::permission::{KeyPerm, KeystorePerm};
+use crate::globals::{get_keymint_device, notify_early_boot_ended, notify_gc};
+use crate::globals::{
+    DB, ENFORCEMENTS, ERROR_STATS, FOREGROUND_UIDS, LEGACY_IMPORTER, OPERATION_DBS, PRUNING_STATS,
+    SUPER_KEY,
+};
+use crate::hal_health;
+use crate::idle_maintenance;
+use crate::legacy_shadow;
+use crate::permission::{permission_cache_stats, KeyPerm, KeyPermSet, KeystorePerm};
+use crate::safe_mode;
+use crate::shared_secret_negotiation;
 use crate::super_key::{SuperKeyManager, UserState};
 use crate::utils::{
-    check_key_permission, check_keystore_permission, uid_to_android_user, watchdog as wd,
+    check_dump_permission, check_grant_permission, check_key_permission, check_keystore_permission,
+    uid_to_android_user, watchdog as wd,
 };
 use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
     IKeyMintDevice::IKeyMintDevice, SecurityLevel::SecurityLevel,
 };
 use android_security_maintenance::aidl::android::security::maintenance::{
+    CertificateBackupEntry::CertificateBackupEntry,
+    GrantInfo::GrantInfo,
     IKeystoreMaintenance::{BnKeystoreMaintenance, IKeystoreMaintenance},
     UserState::UserState as AidlUserState,
 };
 use android_security_maintenance::binder::{
-    BinderFeatures, Interface, Result as BinderResult, Strong, ThreadState,
+    BinderFeatures, Interface, Result as BinderResult, StatusCode, Strong, ThreadState,
 };
 use android_system_keystore2::aidl::android::system::keystore2::KeyDescriptor::KeyDescriptor;
 use android_system_keystore2::aidl::android::system::keystore2::ResponseCode::ResponseCode;
 use anyhow::{Context, Result};
 use keystore2_crypto::Password;
+use std::cell::RefCell;
 
 /// Reexport Domain for the benefit of DeleteListener
 pub use android_system_keystore2::aidl::android::system::keystore2::Domain::Domain;
@@ -78,7 +92,7 @@ impl Maintenance {
 
         if let Some(pw) = password.as_ref() {
             DB.with(|db| {
-                skm.unlock_screen_lock_bound_key(&mut db.borrow_mut(), user_id as u32, pw)
+                skm.unlock_screen_lock_bound_key(&mut db.borrow_mut()?, user_id as u32, pw)
             })
             .context("In on_user_password_changed: unlock_screen_lock_bound_key failed")?;
         }
@@ -86,7 +100,7 @@ impl Maintenance {
         match DB
             .with(|db| {
                 skm.reset_or_init_user_and_get_user_state(
-                    &mut db.borrow_mut(),
+                    &mut db.borrow_mut()?,
                     &LEGACY_IMPORTER,
                     user_id as u32,
                     password.as_ref(),
@@ -112,33 +126,260 @@ impl Maintenance {
         check_keystore_permission(KeystorePerm::ChangeUser).context("In add_or_remove_user.")?;
 
         DB.with(|db| {
-            SUPER_KEY.write().unwrap().reset_user(
-                &mut db.borrow_mut(),
-                &LEGACY_IMPORTER,
-                user_id as u32,
-                false,
-            )
+            let mut db = db.borrow_mut()?;
+            legacy_shadow::shadow_delete_user(&mut db, user_id as u32)
+                .context("Trying to delete legacy copies.")?;
+            SUPER_KEY.write().unwrap().reset_user(&mut db, &LEGACY_IMPORTER, user_id as u32, false)
         })
         .context("In add_or_remove_user: Trying to delete keys from db.")?;
+        // Forget any lock screen state of a previous incarnation of this user id.
+        ENFORCEMENTS.set_device_locked(user_id, true);
         self.delete_listener
             .delete_user(user_id as u32)
             .context("In add_or_remove_user: While invoking the delete listener.")
     }
 
+    fn escrow_user_super_keys(user_id: i32, escrow_secret: Password) -> Result<()> {
+        // Check permission. Function should return if this failed. Therefore having '?' at the end
+        // is very important.
+        check_keystore_permission(KeystorePerm::ChangePassword)
+            .context("In escrow_user_super_keys.")?;
+
+        if user_id < 0 {
+            return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+                .context("In escrow_user_super_keys: Negative user id.");
+        }
+        DB.with(|db| {
+            SUPER_KEY.read().unwrap().escrow_user_super_keys(
+                &mut db.borrow_mut()?,
+                user_id as u32,
+                &escrow_secret,
+            )
+        })
+        .context("In escrow_user_super_keys.")
+    }
+
+    fn unlock_user_with_escrow(user_id: i32, escrow_secret: Password) -> Result<()> {
+        // Check permission. Function should return if this failed. Therefore having '?' at the end
+        // is very important.
+        check_keystore_permission(KeystorePerm::Unlock).context("In unlock_user_with_escrow.")?;
+
+        if user_id < 0 {
+            return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+                .context("In unlock_user_with_escrow: Negative user id.");
+        }
+        let screen_lock_bound_restored = DB
+            .with(|db| {
+                SUPER_KEY.write().unwrap().unlock_user_with_escrow(
+                    &mut db.borrow_mut()?,
+                    user_id as u32,
+                    &escrow_secret,
+                )
+            })
+            .context("In unlock_user_with_escrow.")?;
+        if screen_lock_bound_restored {
+            ENFORCEMENTS.set_device_locked(user_id, false);
+        }
+        Ok(())
+    }
+
+    fn evict_profile_key(user_id: i32) -> Result<()> {
+        // Check permission. Function should return if this failed. Therefore having '?' at the end
+        // is very important.
+        check_keystore_permission(KeystorePerm::Lock).context("In evict_profile_key.")?;
+
+        if user_id < 0 {
+            return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+                .context("In evict_profile_key: Negative user id.");
+        }
+        // Profiles are Android users of their own, so this leaves the parent user untouched.
+        // The super keys remain on flash and are unlocked again with the profile's LSKF.
+        SUPER_KEY.write().unwrap().forget_all_keys_for_user(user_id as u32);
+        // Auth bound keys of the profile must not be usable until it is unlocked again.
+        ENFORCEMENTS.set_device_locked(user_id, true);
+        Ok(())
+    }
+
     fn clear_namespace(&self, domain: Domain, nspace: i64) -> Result<()> {
-        // Permission check. Must return on error. Do not touch the '?'.
-        check_keystore_permission(KeystorePerm::ClearUID).context("In clear_namespace.")?;
+        // System components may clear their own SELinux namespace, e.g., when they reset their
+        // configuration, if they are allowed to delete keys in that namespace. Everything else
+        // requires the 'ClearUID' permission.
+        let may_delete_in_namespace = domain == Domain::SELINUX
+            && check_key_permission(
+                KeyPerm::Delete,
+                &KeyDescriptor { domain, nspace, alias: None, blob: None },
+                &None,
+            )
+            .is_ok();
+        if !may_delete_in_namespace {
+            // Permission check. Must return on error. Do not touch the '?'.
+            check_keystore_permission(KeystorePerm::ClearUID).context("In clear_namespace.")?;
+        }
 
+        // Legacy copies of the keys must be removed before the legacy keys are deleted, lest
+        // they are taken for legacy keys that need to be garbage collected.
+        DB.with(|db| legacy_shadow::shadow_delete_namespace(&mut db.borrow_mut()?, domain, nspace))
+            .context("In clear_namespace: Trying to delete legacy copies.")?;
         LEGACY_IMPORTER
             .bulk_delete_uid(domain, nspace)
             .context("In clear_namespace: Trying to delete legacy keys.")?;
-        DB.with(|db| db.borrow_mut().unbind_keys_for_namespace(domain, nspace))
-            .context("In clear_namespace: Trying to delete keys from db.")?;
+        DB.with(|db| {
+            let mut db = db.borrow_mut()?;
+            // The grants of the namespace's keys go with the keys, but the grants held by the
+            // app or the SELinux namespace would be left behind.
+            match domain {
+                Domain::APP => db
+                    .revoke_all_grants_for_uid(nspace as u32)
+                    .context("In clear_namespace: Trying to revoke grants.")?,
+                Domain::SELINUX => db
+                    .revoke_all_grants_for_namespace(nspace)
+                    .context("In clear_namespace: Trying to revoke grants.")?,
+                _ => 0,
+            };
+            db.unbind_keys_for_namespace(domain, nspace)
+                .context("In clear_namespace: Trying to delete keys from db.")
+        })?;
         self.delete_listener
             .delete_namespace(domain, nspace)
             .context("In clear_namespace: While invoking the delete listener.")
     }
 
+    fn list_grants(key: &KeyDescriptor) -> Result<Vec<GrantInfo>> {
+        let calling_uid = ThreadState::get_calling_uid();
+        let super_key = SUPER_KEY
+            .read()
+            .unwrap()
+            .get_per_boot_key_by_user_id(uid_to_android_user(calling_uid));
+
+        let grants = DB
+            .with(|db| {
+                LEGACY_IMPORTER.with_try_import(key, calling_uid, super_key, || {
+                    db.borrow_mut()?.list_grants(key, calling_uid, |k| {
+                        check_key_permission(KeyPerm::Grant, k, &None)
+                    })
+                })
+            })
+            .context("In list_grants.")?;
+        Ok(grants
+            .into_iter()
+            .map(|(grantee, access_vector, expires_at)| {
+                let (grantee_uid, grantee_namespace) = match grantee {
+                    Grantee::Uid(uid) => (uid as i32, -1),
+                    Grantee::Namespace(namespace) => (-1, namespace),
+                };
+                GrantInfo {
+                    granteeUid: grantee_uid,
+                    granteeNamespace: grantee_namespace,
+                    accessVector: access_vector.into(),
+                    expiresAtMillis: expires_at.map(|t| t.to_millis_epoch()).unwrap_or(0),
+                }
+            })
+            .collect())
+    }
+
+    fn grant_with_expiry(
+        key: &KeyDescriptor,
+        grantee_uid: i32,
+        access_vector: KeyPermSet,
+        duration_millis: i64,
+    ) -> Result<KeyDescriptor> {
+        if duration_millis <= 0 {
+            return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+                .context("In grant_with_expiry: Duration must be positive.");
+        }
+        let now = DateTime::now().context("In grant_with_expiry: Trying to get current time.")?;
+        let expires_at =
+            DateTime::from_millis_epoch(now.to_millis_epoch().saturating_add(duration_millis));
+
+        let calling_uid = ThreadState::get_calling_uid();
+        let super_key = SUPER_KEY
+            .read()
+            .unwrap()
+            .get_per_boot_key_by_user_id(uid_to_android_user(calling_uid));
+
+        DB.with(|db| {
+            LEGACY_IMPORTER.with_try_import(key, calling_uid, super_key, || {
+                db.borrow_mut()?.grant_with_expiry(
+                    key,
+                    calling_uid,
+                    Grantee::Uid(grantee_uid as u32),
+                    access_vector,
+                    Some(expires_at),
+                    |k, av| check_grant_permission(*av, k).context("During grant_with_expiry."),
+                )
+            })
+        })
+        .context("In grant_with_expiry.")
+    }
+
+    fn grant_to_namespace(
+        key: &KeyDescriptor,
+        namespace: i64,
+        access_vector: KeyPermSet,
+    ) -> Result<KeyDescriptor> {
+        let calling_uid = ThreadState::get_calling_uid();
+        let super_key = SUPER_KEY
+            .read()
+            .unwrap()
+            .get_per_boot_key_by_user_id(uid_to_android_user(calling_uid));
+
+        DB.with(|db| {
+            LEGACY_IMPORTER.with_try_import(key, calling_uid, super_key, || {
+                db.borrow_mut()?.grant_with_expiry(
+                    key,
+                    calling_uid,
+                    Grantee::Namespace(namespace),
+                    access_vector,
+                    None,
+                    |k, av| check_grant_permission(*av, k).context("During grant_to_namespace."),
+                )
+            })
+        })
+        .context("In grant_to_namespace.")
+    }
+
+    fn ungrant_from_namespace(key: &KeyDescriptor, namespace: i64) -> Result<()> {
+        DB.with(|db| {
+            db.borrow_mut()?.ungrant_grantee(
+                key,
+                ThreadState::get_calling_uid(),
+                Grantee::Namespace(namespace),
+                |k| check_key_permission(KeyPerm::Grant, k, &None),
+            )
+        })
+        .context("In ungrant_from_namespace.")
+    }
+
+    fn revoke_all_grants(uid: i32) -> Result<()> {
+        // Permission check. Must return on error. Do not touch the '?'.
+        check_keystore_permission(KeystorePerm::ClearUID).context("In revoke_all_grants.")?;
+
+        let revoked = DB
+            .with(|db| db.borrow_mut()?.revoke_all_grants_for_uid(uid as u32))
+            .context("In revoke_all_grants.")?;
+        log::info!("Revoked {} grants of uid {}.", revoked, uid);
+        Ok(())
+    }
+
+    fn abort_operations_for_uid(uid: i32) -> Result<()> {
+        // Permission check. Must return on error. Do not touch the '?'.
+        check_keystore_permission(KeystorePerm::ClearUID)
+            .context("In abort_operations_for_uid.")?;
+
+        let operation_dbs: Vec<_> = OPERATION_DBS
+            .lock()
+            .expect("In abort_operations_for_uid: Trying to lock OPERATION_DBS.")
+            .iter()
+            .filter_map(|operation_db| operation_db.upgrade())
+            .collect();
+        let aborted: usize = operation_dbs
+            .iter()
+            .map(|operation_db| operation_db.abort_operations_for_uid(uid as u32))
+            .sum();
+        log::info!("Aborted {} operations of uid {}.", aborted, uid);
+        Ok(())
+    }
+
     fn get_state(user_id: i32) -> Result<AidlUserState> {
         // Check permission. Function should return if this failed. Therefore having '?' at the end
         // is very important.
@@ -146,7 +387,7 @@ impl Maintenance {
         let state = DB
             .with(|db| {
                 SUPER_KEY.read().unwrap().get_user_state(
-                    &mut db.borrow_mut(),
+                    &mut db.borrow_mut()?,
                     &LEGACY_IMPORTER,
                     user_id as u32,
                 )
@@ -177,13 +418,22 @@ impl Maintenance {
     fn call_on_all_security_levels<F>(name: &'static str, op: F) -> Result<()>
     where
         F: Fn(Strong<dyn IKeyMintDevice>) -> binder::Result<()>,
+    {
+        Maintenance::for_all_security_levels(name, |sec_level| {
+            Maintenance::call_with_watchdog(sec_level, name, &op)
+        })
+    }
+
+    fn for_all_security_levels<F>(name: &'static str, op: F) -> Result<()>
+    where
+        F: Fn(SecurityLevel) -> Result<()>,
     {
         let sec_levels = [
             (SecurityLevel::TRUSTED_ENVIRONMENT, "TRUSTED_ENVIRONMENT"),
             (SecurityLevel::STRONGBOX, "STRONGBOX"),
         ];
         sec_levels.iter().fold(Ok(()), move |result, (sec_level, sec_level_string)| {
-            let curr_result = Maintenance::call_with_watchdog(*sec_level, name, &op);
+            let curr_result = op(*sec_level);
             match curr_result {
                 Ok(()) => log::info!(
                     "Call to {} succeeded for security level {}.",
@@ -206,19 +456,55 @@ impl Maintenance {
             .context("In early_boot_ended. Checking permission")?;
         log::info!("In early_boot_ended.");
 
-        if let Err(e) =
-            DB.with(|db| SuperKeyManager::set_up_boot_level_cache(&SUPER_KEY, &mut db.borrow_mut()))
+        if let Err(e) = DB
+            .with(|db| SuperKeyManager::set_up_boot_level_cache(&SUPER_KEY, &mut db.borrow_mut()?))
         {
             log::error!("SUPER_KEY.set_up_boot_level_cache failed:\n{:?}\n:(", e);
         }
-        Maintenance::call_on_all_security_levels("earlyBootEnded", |dev| dev.earlyBootEnded())
+        // Instances that cannot be reached now are told when they get connected lazily.
+        Maintenance::for_all_security_levels("earlyBootEnded", |sec_level| {
+            notify_early_boot_ended(&sec_level)
+        })
     }
 
     fn on_device_off_body() -> Result<()> {
         // Security critical permission check. This statement must return on fail.
         check_keystore_permission(KeystorePerm::ReportOffBody).context("In on_device_off_body.")?;
 
-        DB.with(|db| db.borrow_mut().update_last_off_body(MonotonicRawTime::now()));
+        DB.with(|db| db.borrow_mut().map(|db| db.update_last_off_body(BootTime::now())))
+            .context("In on_device_off_body.")
+    }
+
+    fn on_uid_foreground_changed(uid: i32, foreground: bool) -> Result<()> {
+        // Security critical permission check. This statement must return on fail.
+        check_keystore_permission(KeystorePerm::ReportUidState)
+            .context("In on_uid_foreground_changed.")?;
+
+        let mut foreground_uids = FOREGROUND_UIDS.lock().unwrap();
+        if foreground {
+            foreground_uids.insert(uid as u32);
+        } else {
+            foreground_uids.remove(&(uid as u32));
+        }
+        Ok(())
+    }
+
+    fn on_device_state_changed(idle: bool, charging: bool) -> Result<()> {
+        // Security critical permission check. This statement must return on fail.
+        check_keystore_permission(KeystorePerm::ReportDeviceState)
+            .context("In on_device_state_changed.")?;
+
+        idle_maintenance::on_device_state_changed(idle, charging);
+        Ok(())
+    }
+
+    fn run_deferred_maintenance() -> Result<()> {
+        // Security critical permission check. This statement must return on fail.
+        check_keystore_permission(KeystorePerm::ReportDeviceState)
+            .context("In run_deferred_maintenance.")?;
+
+        idle_maintenance::run_deferred();
+        notify_gc();
         Ok(())
     }
 
@@ -249,28 +535,150 @@ impl Maintenance {
 
         let super_key = SUPER_KEY.read().unwrap().get_per_boot_key_by_user_id(user_id);
 
-        DB.with(|db| {
-            let (key_id_guard, _) = LEGACY_IMPORTER
+        // The access descriptor of the source key is needed to update its legacy copy.
+        let source_key = RefCell::new(None);
+        let mut resolved_destination = destination.clone();
+        if resolved_destination.domain == Domain::APP {
+            resolved_destination.nspace = calling_uid as i64;
+        }
+        let load_bits = if legacy_shadow::is_enabled() {
+            KeyEntryLoadBits::BOTH
+        } else {
+            KeyEntryLoadBits::NONE
+        };
+
+        let key_entry = DB.with(|db| {
+            // A key at the destination that was not imported from the legacy database yet must
+            // be imported first, or the migration would silently shadow it.
+            if let Some(alias) = &resolved_destination.alias {
+                let destination_exists = || match db.borrow_mut()?.key_exists(
+                    resolved_destination.domain,
+                    resolved_destination.nspace,
+                    alias,
+                    KeyType::Client,
+                )? {
+                    true => Ok(()),
+                    false => {
+                        Err(Error::Rc(ResponseCode::KEY_NOT_FOUND)).context("Destination is free.")
+                    }
+                };
+                if let Err(e) = LEGACY_IMPORTER.with_try_import(
+                    destination,
+                    calling_uid,
+                    super_key.clone(),
+                    destination_exists,
+                ) {
+                    match e.root_cause().downcast_ref::<Error>() {
+                        Some(Error::Rc(ResponseCode::KEY_NOT_FOUND)) => {}
+                        _ => {
+                            return Err(e)
+                                .context("In migrate_key_namespace: Failed to import destination.")
+                        }
+                    }
+                }
+            }
+
+            let (key_id_guard, key_entry) = LEGACY_IMPORTER
                 .with_try_import(source, calling_uid, super_key, || {
-                    db.borrow_mut().load_key_entry(
+                    db.borrow_mut()?.load_key_entry(
                         source,
                         KeyType::Client,
-                        KeyEntryLoadBits::NONE,
+                        load_bits,
                         calling_uid,
                         |k, av| {
                             check_key_permission(KeyPerm::Use, k, &av)?;
                             check_key_permission(KeyPerm::Delete, k, &av)?;
-                            check_key_permission(KeyPerm::Grant, k, &av)
+                            check_key_permission(KeyPerm::Grant, k, &av)?;
+                            *source_key.borrow_mut() = Some(k.clone());
+                            Ok(())
                         },
                     )
                 })
                 .context("In migrate_key_namespace: Failed to load key blob.")?;
-            {
-                db.borrow_mut().migrate_key_namespace(key_id_guard, destination, calling_uid, |k| {
+            db.borrow_mut()?
+                .migrate_key_namespace(key_id_guard, destination, calling_uid, |k| {
                     check_key_permission(KeyPerm::Rebind, k, &None)
                 })
+                .context("In migrate_key_namespace.")?;
+            Ok(key_entry)
+        })?;
+
+        if let Some(source_key) = source_key.into_inner() {
+            if let Err(e) = DB.with(|db| {
+                legacy_shadow::shadow_migrate_key(
+                    &mut db.borrow_mut()?,
+                    &source_key,
+                    &resolved_destination,
+                    &key_entry,
+                )
+            }) {
+                log::error!("In migrate_key_namespace: Failed to update legacy copy: {:?}", e);
             }
-        })
+        }
+        Ok(())
+    }
+
+    fn delete_keys(keys: &[KeyDescriptor]) -> Result<Vec<i32>> {
+        let calling_uid = ThreadState::get_calling_uid();
+
+        let results = DB
+            .with(|db| {
+                db.borrow_mut()?.unbind_keys(keys, KeyType::Client, calling_uid, |k, av| {
+                    check_key_permission(KeyPerm::Delete, k, &av)
+                })
+            })
+            .context("In delete_keys: Trying to unbind the keys.")?;
+
+        let super_key =
+            SUPER_KEY.read().unwrap().get_per_boot_key_by_user_id(uid_to_android_user(calling_uid));
+        // Keys that were not found may not have been imported from the legacy database yet.
+        // Those are deleted one by one, the same way as IKeystoreService::deleteKey does.
+        let delete_legacy_key = |key: &KeyDescriptor| -> Result<KeyDescriptor> {
+            let deleted_key = RefCell::new(None);
+            DB.with(|db| {
+                LEGACY_IMPORTER.with_try_import(key, calling_uid, super_key.clone(), || {
+                    db.borrow_mut()?.unbind_key(key, KeyType::Client, calling_uid, |k, av| {
+                        check_key_permission(KeyPerm::Delete, k, &av)?;
+                        *deleted_key.borrow_mut() = Some(k.clone());
+                        Ok(())
+                    })
+                })
+            })?;
+            deleted_key
+                .into_inner()
+                .ok_or_else(Error::sys)
+                .context("Access descriptor of the deleted key is missing.")
+        };
+
+        Ok(keys
+            .iter()
+            .zip(results)
+            .map(|(key, result)| {
+                let result = match result {
+                    Err(e) if Self::is_key_not_found(&e) => delete_legacy_key(key),
+                    result => result,
+                };
+                log_key_deleted(key, calling_uid, result.is_ok());
+                match result {
+                    Ok(deleted_key) => {
+                        if let Err(e) = DB.with(|db| {
+                            legacy_shadow::shadow_delete_key(&mut db.borrow_mut()?, &deleted_key)
+                        }) {
+                            log::error!("In delete_keys: Failed to remove legacy copy: {:?}", e);
+                        }
+                        0
+                    }
+                    Err(e) => get_error_code(&e),
+                }
+            })
+            .collect())
+    }
+
+    fn is_key_not_found(e: &anyhow::Error) -> bool {
+        matches!(
+            e.root_cause().downcast_ref::<Error>(),
+            Some(Error::Rc(ResponseCode::KEY_NOT_FOUND))
+        )
     }
 
     fn delete_all_keys() -> Result<()> {
@@ -279,11 +687,140 @@ impl Maintenance {
             .context("In delete_all_keys. Checking permission")?;
         log::info!("In delete_all_keys.");
 
-        Maintenance::call_on_all_security_levels("deleteAllKeys", |dev| dev.deleteAllKeys())
+        // This is best effort. Every step is attempted even if a previous step failed, and the
+        // first error is reported.
+        let result =
+            Maintenance::call_on_all_security_levels("deleteAllKeys", |dev| dev.deleteAllKeys());
+        let result = result.and(
+            DB.with(|db| db.borrow_mut()?.unbind_all_keys())
+                .context("In delete_all_keys: Trying to delete keys from db."),
+        );
+        let result = result.and(
+            LEGACY_IMPORTER
+                .delete_all()
+                .context("In delete_all_keys: Trying to delete legacy keys."),
+        );
+        SUPER_KEY.write().unwrap().forget_all_keys();
+        result
+    }
+
+    fn export_certificates(uid: i32) -> Result<Vec<CertificateBackupEntry>> {
+        // Security critical permission check. This statement must return on fail.
+        check_keystore_permission(KeystorePerm::BackupCertificates)
+            .context("In export_certificates.")?;
+
+        let backups = DB
+            .with(|db| db.borrow_mut()?.export_certificates(Domain::APP, uid as i64))
+            .context("In export_certificates.")?;
+        log::info!("Exported {} certificate entries of uid {}.", backups.len(), uid);
+        Ok(backups
+            .into_iter()
+            .map(|backup| CertificateBackupEntry {
+                alias: backup.alias,
+                certificate: backup.cert,
+                certificateChain: backup.cert_chain,
+                creationDateMillis: backup.creation_date.map_or(0, |d| d.to_millis_epoch()),
+            })
+            .collect())
+    }
+
+    fn import_certificates(uid: i32, entries: &[CertificateBackupEntry]) -> Result<Vec<bool>> {
+        // Security critical permission check. This statement must return on fail.
+        check_keystore_permission(KeystorePerm::BackupCertificates)
+            .context("In import_certificates.")?;
+
+        let backups = entries
+            .iter()
+            .map(|entry| {
+                if entry.certificate.is_none() && entry.certificateChain.is_none() {
+                    return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT)).context(format!(
+                        "In import_certificates: Entry {:?} has no certificates.",
+                        entry.alias
+                    ));
+                }
+                Ok(CertificateBackup {
+                    alias: entry.alias.clone(),
+                    cert: entry.certificate.clone(),
+                    cert_chain: entry.certificateChain.clone(),
+                    creation_date: Some(entry.creationDateMillis)
+                        .filter(|millis| *millis > 0)
+                        .map(DateTime::from_millis_epoch),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let imported = DB
+            .with(|db| db.borrow_mut()?.import_certificates(Domain::APP, uid as i64, &backups))
+            .context("In import_certificates.")?;
+        log::info!(
+            "Imported {} of {} certificate entries of uid {}.",
+            imported.iter().filter(|imported| **imported).count(),
+            imported.len(),
+            uid
+        );
+        Ok(imported)
     }
 }
 
-impl Interface for Maintenance {}
+impl Interface for Maintenance {
+    fn dump(
+        &self,
+        mut file: &std::fs::File,
+        _args: &[&std::ffi::CStr],
+    ) -> std::result::Result<(), StatusCode> {
+        use std::io::Write;
+
+        // The dump includes per uid information, e.g., error statistics and pruning offenders.
+        if let Err(e) = check_dump_permission() {
+            log::error!("In Maintenance::dump: {:?}", e);
+            return Err(StatusCode::PERMISSION_DENIED);
+        }
+
+        let progress = LEGACY_IMPORTER.get_bulk_import_progress();
+        let result = writeln!(file, "Legacy key import:").and_then(|_| {
+            progress.iter().try_for_each(|(user_id, p)| {
+                writeln!(
+                    file,
+                    "  user {}: imported {}, failed {}, remaining {}",
+                    user_id, p.imported, p.failed, p.remaining
+                )
+            })
+        });
+        let negotiation = shared_secret_negotiation::negotiation_status();
+        let result = result.and_then(|_| {
+            writeln!(file, "Shared secret negotiation: {:?}", negotiation.state)?;
+            writeln!(file, "  participants: {}", negotiation.participants.join(", "))?;
+            writeln!(file, "  pending: {}", negotiation.pending.join(", "))?;
+            writeln!(file, "  retries: {}", negotiation.retries)?;
+            if let Some(e) = &negotiation.last_error {
+                writeln!(file, "  last error: {}", e)?;
+            }
+            Ok(())
+        });
+        let permission_cache = permission_cache_stats();
+        let result = result.and_then(|_| {
+            writeln!(
+                file,
+                "Permission cache: hits {}, misses {}, flushes {}, entries {}",
+                permission_cache.hits,
+                permission_cache.misses,
+                permission_cache.flushes,
+                permission_cache.entries
+            )
+        });
+        let result = result.and_then(|_| ERROR_STATS.dump(&mut file));
+        let result = result.and_then(|_| PRUNING_STATS.dump(&mut file));
+        let result = result.and_then(|_| ENFORCEMENTS.dump_unlock_methods(&mut file));
+        let result = result.and_then(|_| hal_health::dump(&mut file));
+        let deferred = idle_maintenance::deferred_work();
+        let result =
+            result.and_then(|_| writeln!(file, "Deferred maintenance: {}", deferred.join(", ")));
+        let result = result.and_then(|_| writeln!(file, "Safe mode: {}", safe_mode::is_enabled()));
+        result.map_err(|e| {
+            log::error!("In Maintenance::dump: Failed to write dump: {:?}", e);
+            StatusCode::UNKNOWN_ERROR
+        })
+    }
+}
 
 impl IKeystoreMaintenance for Maintenance {
     fn onUserPasswordChanged(&self, user_id: i32, password: Option<&[u8]>) -> BinderResult<()> {
@@ -301,11 +838,70 @@ impl IKeystoreMaintenance for Maintenance {
         map_or_log_err(self.add_or_remove_user(user_id), Ok)
     }
 
+    fn escrowUserSuperKeys(&self, user_id: i32, escrow_secret: &[u8]) -> BinderResult<()> {
+        let _wp = wd::watch_millis("IKeystoreMaintenance::escrowUserSuperKeys", 500);
+        map_or_log_err(Self::escrow_user_super_keys(user_id, escrow_secret.into()), Ok)
+    }
+
+    fn unlockUserWithEscrow(&self, user_id: i32, escrow_secret: &[u8]) -> BinderResult<()> {
+        let _wp = wd::watch_millis("IKeystoreMaintenance::unlockUserWithEscrow", 500);
+        map_or_log_err(Self::unlock_user_with_escrow(user_id, escrow_secret.into()), Ok)
+    }
+
+    fn evictProfileKey(&self, user_id: i32) -> BinderResult<()> {
+        let _wp = wd::watch_millis("IKeystoreMaintenance::evictProfileKey", 500);
+        map_or_log_err(Self::evict_profile_key(user_id), Ok)
+    }
+
     fn clearNamespace(&self, domain: Domain, nspace: i64) -> BinderResult<()> {
         let _wp = wd::watch_millis("IKeystoreMaintenance::clearNamespace", 500);
         map_or_log_err(self.clear_namespace(domain, nspace), Ok)
     }
 
+    fn listGrants(&self, key: &KeyDescriptor) -> BinderResult<Vec<GrantInfo>> {
+        let _wp = wd::watch_millis("IKeystoreMaintenance::listGrants", 500);
+        map_or_log_err(Self::list_grants(key), Ok)
+    }
+
+    fn grantWithExpiry(
+        &self,
+        key: &KeyDescriptor,
+        grantee_uid: i32,
+        access_vector: i32,
+        duration_millis: i64,
+    ) -> BinderResult<KeyDescriptor> {
+        let _wp = wd::watch_millis("IKeystoreMaintenance::grantWithExpiry", 500);
+        map_or_log_err(
+            Self::grant_with_expiry(key, grantee_uid, access_vector.into(), duration_millis),
+            Ok,
+        )
+    }
+
+    fn grantToNamespace(
+        &self,
+        key: &KeyDescriptor,
+        namespace: i64,
+        access_vector: i32,
+    ) -> BinderResult<KeyDescriptor> {
+        let _wp = wd::watch_millis("IKeystoreMaintenance::grantToNamespace", 500);
+        map_or_log_err(Self::grant_to_namespace(key, namespace, access_vector.into()), Ok)
+    }
+
+    fn ungrantFromNamespace(&self, key: &KeyDescriptor, namespace: i64) -> BinderResult<()> {
+        let _wp = wd::watch_millis("IKeystoreMaintenance::ungrantFromNamespace", 500);
+        map_or_log_err(Self::ungrant_from_namespace(key, namespace), Ok)
+    }
+
+    fn revokeAllGrants(&self, uid: i32) -> BinderResult<()> {
+        let _wp = wd::watch_millis("IKeystoreMaintenance::revokeAllGrants", 500);
+        map_or_log_err(Self::revoke_all_grants(uid), Ok)
+    }
+
+    fn abortOperationsForUid(&self, uid: i32) -> BinderResult<()> {
+        let _wp = wd::watch_millis("IKeystoreMaintenance::abortOperationsForUid", 500);
+        map_or_log_err(Self::abort_operations_for_uid(uid), Ok)
+    }
+
     fn getState(&self, user_id: i32) -> BinderResult<AidlUserState> {
         let _wp = wd::watch_millis("IKeystoreMaintenance::getState", 500);
         map_or_log_err(Self::get_state(user_id), Ok)
@@ -321,6 +917,21 @@ impl IKeystoreMaintenance for Maintenance {
         map_or_log_err(Self::on_device_off_body(), Ok)
     }
 
+    fn onUidForegroundChanged(&self, uid: i32, foreground: bool) -> BinderResult<()> {
+        let _wp = wd::watch_millis("IKeystoreMaintenance::onUidForegroundChanged", 500);
+        map_or_log_err(Self::on_uid_foreground_changed(uid, foreground), Ok)
+    }
+
+    fn onDeviceStateChanged(&self, idle: bool, charging: bool) -> BinderResult<()> {
+        let _wp = wd::watch_millis("IKeystoreMaintenance::onDeviceStateChanged", 500);
+        map_or_log_err(Self::on_device_state_changed(idle, charging), Ok)
+    }
+
+    fn runDeferredMaintenance(&self) -> BinderResult<()> {
+        let _wp = wd::watch_millis("IKeystoreMaintenance::runDeferredMaintenance", 500);
+        map_or_log_err(Self::run_deferred_maintenance(), Ok)
+    }
+
     fn migrateKeyNamespace(
         &self,
         source: &KeyDescriptor,
@@ -330,8 +941,27 @@ impl IKeystoreMaintenance for Maintenance {
         map_or_log_err(Self::migrate_key_namespace(source, destination), Ok)
     }
 
+    fn deleteKeys(&self, keys: &[KeyDescriptor]) -> BinderResult<Vec<i32>> {
+        let _wp = wd::watch_millis("IKeystoreMaintenance::deleteKeys", 500);
+        map_or_log_err(Self::delete_keys(keys), Ok)
+    }
+
     fn deleteAllKeys(&self) -> BinderResult<()> {
         let _wp = wd::watch_millis("IKeystoreMaintenance::deleteAllKeys", 500);
         map_or_log_err(Self::delete_all_keys(), Ok)
     }
+
+    fn exportCertificates(&self, uid: i32) -> BinderResult<Vec<CertificateBackupEntry>> {
+        let _wp = wd::watch_millis("IKeystoreMaintenance::exportCertificates", 500);
+        map_or_log_err(Self::export_certificates(uid), Ok)
+    }
+
+    fn importCertificates(
+        &self,
+        uid: i32,
+        entries: &[CertificateBackupEntry],
+    ) -> BinderResult<Vec<bool>> {
+        let _wp = wd::watch_millis("IKeystoreMaintenance::importCertificates", 500);
+        map_or_log_err(Self::import_certificates(uid, entries), Ok)
+    }
 }
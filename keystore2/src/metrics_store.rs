@@ -21,6 +21,7 @@ use crate::error::{get_error_code, Error};
 use crate::globals::DB;
 use crate::key_parameter::KeyParameterValue as KsKeyParamValue;
 use crate::operation::Outcome;
+use crate::pruning_stats::PruningCause;
 use crate::remote_provisioning::get_pool_status;
 use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
     Algorithm::Algorithm, BlockMode::BlockMode, Digest::Digest, EcCurve::EcCurve,
@@ -39,10 +40,12 @@ use android_security_metrics::aidl::android::security::metrics::{
     KeyOperationWithPurposeAndModesInfo::KeyOperationWithPurposeAndModesInfo,
     KeyOrigin::KeyOrigin as MetricsKeyOrigin, Keystore2AtomWithOverflow::Keystore2AtomWithOverflow,
     KeystoreAtom::KeystoreAtom, KeystoreAtomPayload::KeystoreAtomPayload,
-    Outcome::Outcome as MetricsOutcome, Purpose::Purpose as MetricsPurpose,
-    RkpError::RkpError as MetricsRkpError, RkpErrorStats::RkpErrorStats,
-    RkpPoolStats::RkpPoolStats, SecurityLevel::SecurityLevel as MetricsSecurityLevel,
-    Storage::Storage as MetricsStorage,
+    LegacyKeyMigrationStats::LegacyKeyMigrationStats, OperationPruningStats::OperationPruningStats,
+    Outcome::Outcome as MetricsOutcome, PruningCause::PruningCause as MetricsPruningCause,
+    Purpose::Purpose as MetricsPurpose, RkpError::RkpError as MetricsRkpError,
+    RkpErrorStats::RkpErrorStats, RkpPoolStats::RkpPoolStats,
+    SecurityLevel::SecurityLevel as MetricsSecurityLevel, Storage::Storage as MetricsStorage,
+    TimestampTokenCacheStats::TimestampTokenCacheStats,
 };
 use android_system_keystore2::aidl::android::system::keystore2::ResponseCode::ResponseCode;
 use anyhow::{Context, Result};
@@ -539,8 +542,8 @@ fn pull_storage_stats() -> Result<Vec<KeystoreAtom>> {
             }
         };
     };
-    DB.with(|db| {
-        let mut db = db.borrow_mut();
+    DB.with::<_, Result<()>>(|db| {
+        let mut db = db.borrow_mut()?;
         append(db.get_storage_stat(MetricsStorage::DATABASE));
         append(db.get_storage_stat(MetricsStorage::KEY_ENTRY));
         append(db.get_storage_stat(MetricsStorage::KEY_ENTRY_ID_INDEX));
@@ -555,7 +558,9 @@ fn pull_storage_stats() -> Result<Vec<KeystoreAtom>> {
         append(db.get_storage_stat(MetricsStorage::AUTH_TOKEN));
         append(db.get_storage_stat(MetricsStorage::BLOB_METADATA));
         append(db.get_storage_stat(MetricsStorage::BLOB_METADATA_BLOB_ENTRY_ID_INDEX));
-    });
+        Ok(())
+    })
+    .context("In pull_storage_stats.")?;
     Ok(atom_vec)
 }
 
@@ -605,6 +610,35 @@ pub fn log_rkp_error_stats(rkp_error: MetricsRkpError, sec_level: &SecurityLevel
     METRICS_STORE.insert_atom(AtomID::RKP_ERROR_STATS, rkp_error_stats);
 }
 
+/// Log a timestamp token request that was served from the enforcement module's cache.
+pub fn log_timestamp_token_cache_stats(cache_hit: bool) {
+    let cache_stats =
+        KeystoreAtomPayload::TimestampTokenCacheStats(TimestampTokenCacheStats { cache_hit });
+    METRICS_STORE.insert_atom(AtomID::TIMESTAMP_TOKEN_CACHE_STATS, cache_stats);
+}
+
+/// Log the outcome of importing a single key during the bulk import of legacy keys.
+pub fn log_legacy_key_migration_stats(imported: bool) {
+    let migration_stats =
+        KeystoreAtomPayload::LegacyKeyMigrationStats(LegacyKeyMigrationStats { imported });
+    METRICS_STORE.insert_atom(AtomID::LEGACY_KEY_MIGRATION_STATS, migration_stats);
+}
+
+/// Log the pruning of an operation along with the reason.
+pub fn log_operation_pruning_stats(cause: PruningCause, sec_level: SecurityLevel) {
+    let pruning_stats = KeystoreAtomPayload::OperationPruningStats(OperationPruningStats {
+        cause: match cause {
+            PruningCause::Lru => MetricsPruningCause::LRU,
+            PruningCause::Quota => MetricsPruningCause::QUOTA,
+            PruningCause::Forced => MetricsPruningCause::FORCED,
+            PruningCause::BinderDeath => MetricsPruningCause::BINDER_DEATH,
+            PruningCause::ForceStop => MetricsPruningCause::FORCE_STOP,
+        },
+        security_level: process_security_level(sec_level),
+    });
+    METRICS_STORE.insert_atom(AtomID::OPERATION_PRUNING_STATS, pruning_stats);
+}
+
 /// This function tries to read and update the system property: keystore.crash_count.
 /// If the property is absent, it sets the property with value 0. If the property is present, it
 /// increments the value. This helps tracking keystore crashes internally.
@@ -0,0 +1,48 @@
+// Copyright 2021, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module defines the well-known SELinux namespaces of system components that use Keystore
+//! early during boot. Their contexts are resolved from `keystore2_key_contexts` before any
+//! client can connect, so that a missing label is reported at startup and the first access
+//! checks of these components do not wait for the label backend. Which permissions the owners
+//! hold is left to the SELinux policy.
+
+use crate::permission;
+
+/// A well-known SELinux namespace as defined by `keystore2_key_contexts`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WellKnownNamespace {
+    /// The name of the component that owns the namespace.
+    pub name: &'static str,
+    /// The namespace number as used in KeyDescriptor::nspace with Domain::SELINUX.
+    pub namespace: i64,
+}
+
+/// The well-known namespaces that are resolved on startup.
+pub const WELL_KNOWN_NAMESPACES: &[WellKnownNamespace] = &[
+    WellKnownNamespace { name: "vold", namespace: 100 },
+    WellKnownNamespace { name: "odsign", namespace: 101 },
+    WellKnownNamespace { name: "wifi", namespace: 102 },
+    WellKnownNamespace { name: "resume_on_reboot", namespace: 120 },
+];
+
+/// Resolves the contexts of all well-known namespaces. Namespaces without a context are
+/// logged, because their owners will be denied access to their keys.
+pub fn bootstrap() {
+    for ns in WELL_KNOWN_NAMESPACES {
+        if let Err(e) = permission::preload_key_context(ns.namespace) {
+            log::error!("Failed to resolve the namespace of {}: {:?}", ns.name, e);
+        }
+    }
+}
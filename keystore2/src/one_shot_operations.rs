@@ -0,0 +1,167 @@
+// Copyright 2021, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements the IKeystoreOneShotOperations AIDL interface, which performs an
+//! operation with a single call. It creates the operation through the caller supplied security
+//! level and finishes it through the regular operation code path, so that all permission checks,
+//! enforcements, and chunking apply exactly as for streaming operations. It also implements
+//! batch signing, which signs many payloads with a single key lookup and permission check, and
+//! the creation of certification requests signed by keystore-held keys.
+
+use crate::error::{map_or_log_err, Error, ErrorCode};
+use crate::operation::KeystoreOperation;
+use crate::security_level::KeystoreSecurityLevel;
+use crate::utils::watchdog as wd;
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
+    KeyParameter::KeyParameter, SecurityLevel::SecurityLevel,
+};
+use android_security_operation::aidl::android::security::operation::{
+    ByteArray::ByteArray,
+    IKeystoreOneShotOperations::{BnKeystoreOneShotOperations, IKeystoreOneShotOperations},
+};
+use android_security_operation::binder::{
+    BinderFeatures, ExceptionCode, Interface, Result as BinderResult, Strong,
+};
+use android_system_keystore2::aidl::android::system::keystore2::{
+    IKeystoreSecurityLevel::IKeystoreSecurityLevel, KeyDescriptor::KeyDescriptor,
+    ResponseCode::ResponseCode,
+};
+use anyhow::{Context, Result};
+
+/// Implementation of the IKeystoreOneShotOperations AIDL interface.
+pub struct OneShotOperations;
+
+impl OneShotOperations {
+    /// Creates a new instance of the one-shot operations service.
+    pub fn new_native_binder() -> Result<Strong<dyn IKeystoreOneShotOperations>> {
+        Ok(BnKeystoreOneShotOperations::new_binder(
+            Self,
+            BinderFeatures { set_requesting_sid: true, ..BinderFeatures::default() },
+        ))
+    }
+
+    /// Maps the error returned by a local Keystore binder back onto the Keystore error,
+    /// so that it can be returned to the client unchanged.
+    fn map_local_status<T>(r: BinderResult<T>) -> Result<T, Error> {
+        r.map_err(|s| match s.exception_code() {
+            ExceptionCode::SERVICE_SPECIFIC if s.service_specific_error() < 0 => {
+                Error::Km(ErrorCode(s.service_specific_error()))
+            }
+            ExceptionCode::SERVICE_SPECIFIC => Error::Rc(ResponseCode(s.service_specific_error())),
+            e_code => Error::Binder(e_code, 0),
+        })
+    }
+
+    fn perform(
+        security_level: &Strong<dyn IKeystoreSecurityLevel>,
+        key: &KeyDescriptor,
+        operation_parameters: &[KeyParameter],
+        input: Option<&[u8]>,
+        signature: Option<&[u8]>,
+    ) -> Result<Option<Vec<u8>>> {
+        // Only call into security levels of this process. The calling identity is retained
+        // by local binder calls, so that the permission checks apply to the caller.
+        if security_level.as_binder().is_remote() {
+            return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+                .context("In perform: Not a Keystore security level.");
+        }
+        let response = Self::map_local_status(security_level.createOperation(
+            key,
+            operation_parameters,
+            false,
+        ))
+        .context("In perform: Trying to create operation.")?;
+        let operation = response
+            .iOperation
+            .ok_or_else(Error::sys)
+            .context("In perform: Operation created without operation binder.")?;
+        KeystoreOperation::from_binder(&operation)
+            .context("In perform.")?
+            .finish_one_shot(input, signature)
+            .context("In perform: Trying to finish operation.")
+    }
+
+    fn batch_sign(
+        security_level: SecurityLevel,
+        key: &KeyDescriptor,
+        operation_parameters: &[KeyParameter],
+        payloads: &[ByteArray],
+    ) -> Result<Vec<ByteArray>> {
+        let sec_level = KeystoreSecurityLevel::get(security_level)
+            .ok_or(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+            .context("In batch_sign: No such security level.")?;
+        let payloads: Vec<&[u8]> = payloads.iter().map(|payload| payload.data.as_slice()).collect();
+        Ok(sec_level
+            .batch_sign(key, operation_parameters, &payloads)
+            .context("In batch_sign.")?
+            .into_iter()
+            .map(|data| ByteArray { data })
+            .collect())
+    }
+
+    fn get_certificate_request(
+        security_level: SecurityLevel,
+        key: &KeyDescriptor,
+        subject: &[u8],
+        extensions: Option<&[u8]>,
+    ) -> Result<Vec<u8>> {
+        let sec_level = KeystoreSecurityLevel::get(security_level)
+            .ok_or(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+            .context("In get_certificate_request: No such security level.")?;
+        sec_level
+            .get_certificate_request(key, subject, extensions)
+            .context("In get_certificate_request.")
+    }
+}
+
+impl Interface for OneShotOperations {}
+
+impl IKeystoreOneShotOperations for OneShotOperations {
+    fn perform(
+        &self,
+        security_level: &Strong<dyn IKeystoreSecurityLevel>,
+        key: &KeyDescriptor,
+        operation_parameters: &[KeyParameter],
+        input: Option<&[u8]>,
+        signature: Option<&[u8]>,
+    ) -> BinderResult<Option<Vec<u8>>> {
+        let _wp = wd::watch_millis("IKeystoreOneShotOperations::perform", 500);
+        map_or_log_err(
+            Self::perform(security_level, key, operation_parameters, input, signature),
+            Ok,
+        )
+    }
+
+    fn batchSign(
+        &self,
+        security_level: SecurityLevel,
+        key: &KeyDescriptor,
+        operation_parameters: &[KeyParameter],
+        payloads: &[ByteArray],
+    ) -> BinderResult<Vec<ByteArray>> {
+        let _wp = wd::watch_millis("IKeystoreOneShotOperations::batchSign", 500);
+        map_or_log_err(Self::batch_sign(security_level, key, operation_parameters, payloads), Ok)
+    }
+
+    fn getCertificateRequest(
+        &self,
+        security_level: SecurityLevel,
+        key: &KeyDescriptor,
+        subject: &[u8],
+        extensions: Option<&[u8]>,
+    ) -> BinderResult<Vec<u8>> {
+        let _wp = wd::watch_millis("IKeystoreOneShotOperations::getCertificateRequest", 500);
+        map_or_log_err(Self::get_certificate_request(security_level, key, subject, extensions), Ok)
+    }
+}
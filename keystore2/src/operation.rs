@@ -125,9 +125,13 @@
 //! or it transitions to its end-of-life, which means we may get a free slot.
 //! Either way, we have to revaluate the pruning scores.
 
+use crate::config;
 use crate::enforcements::AuthInfo;
 use crate::error::{map_err_with, map_km_error, map_or_log_err, Error, ErrorCode, ResponseCode};
+use crate::globals::{FOREGROUND_UIDS, LOG_THROTTLE, OPERATION_BINDERS, PRUNING_STATS};
 use crate::metrics_store::log_key_operation_event_stats;
+use crate::pruning_stats::PruningCause;
+use crate::trace;
 use crate::utils::watchdog as wd;
 use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
     IKeyMintOperation::IKeyMintOperation, KeyParameter::KeyParameter, KeyPurpose::KeyPurpose,
@@ -138,8 +142,12 @@ use android_system_keystore2::aidl::android::system::keystore2::{
     IKeystoreOperation::BnKeystoreOperation, IKeystoreOperation::IKeystoreOperation,
 };
 use anyhow::{anyhow, Context, Result};
+use binder::{SpIBinder, WpIBinder};
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
+    fs::File,
+    os::unix::fs::FileExt,
+    sync::atomic::{AtomicUsize, Ordering},
     sync::{Arc, Mutex, MutexGuard, Weak},
     time::Duration,
     time::Instant,
@@ -172,11 +180,15 @@ pub struct Operation {
     index: usize,
     km_op: Strong<dyn IKeyMintOperation>,
     last_usage: Mutex<Instant>,
+    // The last time the client explicitly kept this operation alive.
+    last_keep_alive: Mutex<Option<Instant>>,
     outcome: Mutex<Outcome>,
     owner: u32, // Uid of the operation's owner.
     auth_info: Mutex<AuthInfo>,
     forced: bool,
     logging_info: LoggingInfo,
+    // The maximum input size of a single call into the KeyMint operation.
+    max_chunk_size: usize,
 }
 
 /// Keeps track of the information required for logging operations.
@@ -202,6 +214,7 @@ impl LoggingInfo {
 
 struct PruningInfo {
     last_usage: Instant,
+    last_keep_alive: Option<Instant>,
     owner: u32,
     index: usize,
     forced: bool,
@@ -210,6 +223,48 @@ struct PruningInfo {
 // We don't except more than 32KiB of data in `update`, `updateAad`, and `finish`.
 const MAX_RECEIVE_DATA: usize = 0x8000;
 
+/// Returns the maximum input size of a single `update`, `updateAad`, or `finish` call into
+/// the KeyMint instance of the given security level. Larger client inputs are split up into
+/// multiple calls. The limit can be tuned, see `config::MAX_CHUNK_SIZE_TEE` and
+/// `config::MAX_CHUNK_SIZE_STRONGBOX`, for devices that reject large inputs with
+/// `ErrorCode::INVALID_INPUT_LENGTH`.
+pub fn max_chunk_size(sec_level: SecurityLevel) -> usize {
+    match sec_level {
+        SecurityLevel::STRONGBOX => config::MAX_CHUNK_SIZE_STRONGBOX.get(),
+        _ => config::MAX_CHUNK_SIZE_TEE.get(),
+    }
+    .max(1)
+}
+
+/// Splits `input` into chunks that the KeyMint operation accepts in a single call. Empty input
+/// yields a single empty chunk.
+fn split_input(input: &[u8], max_chunk_size: usize) -> Vec<&[u8]> {
+    if input.is_empty() {
+        vec![input]
+    } else {
+        input.chunks(max_chunk_size).collect()
+    }
+}
+
+/// Splits the input of a finish call into the inputs of the updates that precede the KeyMint
+/// finish call and the input of the finish call itself, so that only the last chunk is passed
+/// to finish. Key agreement is the exception, because the peer's public key must be passed to
+/// finish as a whole.
+fn split_finish_input(
+    input: Option<&[u8]>,
+    max_chunk_size: usize,
+    purpose: KeyPurpose,
+) -> (Vec<&[u8]>, Option<&[u8]>) {
+    match input {
+        Some(input) if purpose != KeyPurpose::AGREE_KEY => {
+            let mut chunks = split_input(input, max_chunk_size);
+            let last_chunk = chunks.pop();
+            (chunks, last_chunk)
+        }
+        input => (Vec::new(), input),
+    }
+}
+
 impl Operation {
     /// Constructor
     pub fn new(
@@ -219,16 +274,19 @@ impl Operation {
         auth_info: AuthInfo,
         forced: bool,
         logging_info: LoggingInfo,
+        max_chunk_size: usize,
     ) -> Self {
         Self {
             index,
             km_op,
             last_usage: Mutex::new(Instant::now()),
+            last_keep_alive: Mutex::new(None),
             outcome: Mutex::new(Outcome::Unknown),
             owner,
             auth_info: Mutex::new(auth_info),
             forced,
             logging_info,
+            max_chunk_size: max_chunk_size.max(1),
         }
     }
 
@@ -252,6 +310,7 @@ impl Operation {
             // `last_usage` is locked only for primitive single line statements.
             // There is no chance to panic and poison the mutex.
             last_usage: *self.last_usage.lock().expect("In get_pruning_info."),
+            last_keep_alive: *self.last_keep_alive.lock().expect("In get_pruning_info."),
             owner: self.owner,
             index: self.index,
             forced: self.forced,
@@ -338,6 +397,10 @@ impl Operation {
         Ok(())
     }
 
+    fn split_input<'a>(&self, input: &'a [u8]) -> Vec<&'a [u8]> {
+        split_input(input, self.max_chunk_size)
+    }
+
     // Update the last usage to now.
     fn touch(&self) {
         // Expect safety:
@@ -360,20 +423,40 @@ impl Operation {
             .before_update()
             .context("In update_aad: Trying to get auth tokens.")?;
 
-        self.update_outcome(&mut *outcome, {
-            let _wp = wd::watch_millis("Operation::update_aad: calling updateAad", 500);
-            map_km_error(self.km_op.updateAad(aad_input, hat.as_ref(), tst.as_ref()))
-        })
-        .context("In update_aad: KeyMint::update failed.")?;
+        for chunk in self.split_input(aad_input) {
+            self.update_outcome(&mut *outcome, {
+                let _wp = wd::watch_millis("Operation::update_aad: calling updateAad", 500);
+                let _trace = trace::begin("KeyMint::updateAad");
+                map_km_error(self.km_op.updateAad(chunk, hat.as_ref(), tst.as_ref()))
+            })
+            .context("In update_aad: KeyMint::update failed.")?;
+        }
 
         Ok(())
     }
 
+    // Update the last usage and the last keep-alive to now.
+    fn keep_alive(&self) {
+        let now = Instant::now();
+        // Expect safety:
+        // `last_usage` and `last_keep_alive` are locked only for primitive single line
+        // statements. There is no chance to panic and poison the mutex.
+        *self.last_usage.lock().expect("In keep_alive.") = now;
+        *self.last_keep_alive.lock().expect("In keep_alive.") = Some(now);
+    }
+
     /// Implementation of `IKeystoreOperation::update`.
     /// Refer to the AIDL spec at system/hardware/interfaces/keystore2 for details.
+    /// An update with empty input does not call into KeyMint. It serves as keep-alive
+    /// which protects the operation from being pruned by other uids for a while. This
+    /// allows long-lived streaming operations to survive pauses. See `OperationDb::prune`.
     fn update(&self, input: &[u8]) -> Result<Option<Vec<u8>>> {
         let mut outcome = self.check_active().context("In update")?;
         Self::check_input_length(input).context("In update")?;
+        if input.is_empty() {
+            self.keep_alive();
+            return Ok(None);
+        }
         self.touch();
 
         let (hat, tst) = self
@@ -383,12 +466,17 @@ impl Operation {
             .before_update()
             .context("In update: Trying to get auth tokens.")?;
 
-        let output = self
-            .update_outcome(&mut *outcome, {
-                let _wp = wd::watch_millis("Operation::update: calling update", 500);
-                map_km_error(self.km_op.update(input, hat.as_ref(), tst.as_ref()))
-            })
-            .context("In update: KeyMint::update failed.")?;
+        let mut output = Vec::new();
+        for chunk in self.split_input(input) {
+            output.extend(
+                self.update_outcome(&mut *outcome, {
+                    let _wp = wd::watch_millis("Operation::update: calling update", 500);
+                    let _trace = trace::begin("KeyMint::update");
+                    map_km_error(self.km_op.update(chunk, hat.as_ref(), tst.as_ref()))
+                })
+                .context("In update: KeyMint::update failed.")?,
+            );
+        }
 
         if output.is_empty() {
             Ok(None)
@@ -413,9 +501,24 @@ impl Operation {
             .before_finish()
             .context("In finish: Trying to get auth tokens.")?;
 
-        let output = self
-            .update_outcome(&mut *outcome, {
+        let mut output = Vec::new();
+        let (update_chunks, input) =
+            split_finish_input(input, self.max_chunk_size, self.logging_info.purpose);
+        for chunk in update_chunks {
+            output.extend(
+                self.update_outcome(&mut *outcome, {
+                    let _wp = wd::watch_millis("Operation::finish: calling update", 500);
+                    let _trace = trace::begin("KeyMint::update");
+                    map_km_error(self.km_op.update(chunk, hat.as_ref(), tst.as_ref()))
+                })
+                .context("In finish: KeyMint::update failed.")?,
+            );
+        }
+
+        output.extend(
+            self.update_outcome(&mut *outcome, {
                 let _wp = wd::watch_millis("Operation::finish: calling finish", 500);
+                let _trace = trace::begin("KeyMint::finish");
                 map_km_error(self.km_op.finish(
                     input,
                     signature,
@@ -424,7 +527,8 @@ impl Operation {
                     confirmation_token.as_deref(),
                 ))
             })
-            .context("In finish: KeyMint::finish failed.")?;
+            .context("In finish: KeyMint::finish failed.")?,
+        );
 
         self.auth_info.lock().unwrap().after_finish().context("In finish.")?;
 
@@ -438,6 +542,74 @@ impl Operation {
         }
     }
 
+    // Feeds the first `input_length` bytes of `input` into `update` in pieces of at most
+    // MAX_RECEIVE_DATA bytes and writes the output to the beginning of `output`. If
+    // `hold_back_last` is true, the last piece is not fed into `update` but returned, so
+    // that it can be passed to `finish`. Returns the number of bytes written and the last
+    // piece, which is empty unless held back.
+    fn update_from_file(
+        &self,
+        input: &File,
+        input_length: u64,
+        output: &File,
+        hold_back_last: bool,
+    ) -> Result<(u64, Vec<u8>)> {
+        let mut input_offset: u64 = 0;
+        let mut output_offset: u64 = 0;
+        while input_offset < input_length {
+            let piece_length = (input_length - input_offset).min(MAX_RECEIVE_DATA as u64);
+            let mut piece = vec![0u8; piece_length as usize];
+            input.read_exact_at(&mut piece, input_offset).map_err(|e| {
+                anyhow!(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+                    .context(format!("In update_from_file: Failed to read input: {:?}", e))
+            })?;
+            input_offset += piece_length;
+            if hold_back_last && input_offset == input_length {
+                return Ok((output_offset, piece));
+            }
+            if let Some(piece_output) = self.update(&piece).context("In update_from_file.")? {
+                output_offset = Self::write_output(output, output_offset, &piece_output)
+                    .context("In update_from_file.")?;
+            }
+        }
+        Ok((output_offset, Vec::new()))
+    }
+
+    // Writes `data` to `output` at `offset` and returns the offset after the written data.
+    fn write_output(output: &File, offset: u64, data: &[u8]) -> Result<u64> {
+        output.write_all_at(data, offset).map_err(|e| {
+            anyhow!(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+                .context(format!("In write_output: Failed to write output: {:?}", e))
+        })?;
+        Ok(offset + data.len() as u64)
+    }
+
+    /// Shared memory variant of `update`. See `IKeystoreSharedMemoryOperations::update`.
+    fn update_shared_memory(&self, input: &File, input_length: u64, output: &File) -> Result<u64> {
+        let (output_length, _) = self
+            .update_from_file(input, input_length, output, false)
+            .context("In update_shared_memory.")?;
+        Ok(output_length)
+    }
+
+    /// Shared memory variant of `finish`. See `IKeystoreSharedMemoryOperations::finish`.
+    fn finish_shared_memory(
+        &self,
+        input: &File,
+        input_length: u64,
+        signature: Option<&[u8]>,
+        output: &File,
+    ) -> Result<u64> {
+        let (output_length, last_piece) = self
+            .update_from_file(input, input_length, output, true)
+            .context("In finish_shared_memory.")?;
+        match self.finish(Some(&last_piece), signature).context("In finish_shared_memory.")? {
+            Some(finish_output) => Self::write_output(output, output_length, &finish_output)
+                .context("In finish_shared_memory."),
+            None => Ok(output_length),
+        }
+    }
+
     /// Aborts the operation if it is active. IFF the operation is aborted the outcome is
     /// set to `outcome`. `outcome` must reflect the reason for the abort. Since the operation
     /// gets aborted `outcome` must not be `Operation::Success` or `Operation::Unknown`.
@@ -447,6 +619,7 @@ impl Operation {
 
         {
             let _wp = wd::watch_millis("Operation::abort: calling abort", 500);
+            let _trace = trace::begin("KeyMint::abort");
             map_km_error(self.km_op.abort()).context("In abort: KeyMint::abort failed.")
         }
     }
@@ -466,8 +639,15 @@ impl Drop for Operation {
             drop(guard);
             // If the operation was still active we call abort, setting
             // the outcome to `Outcome::Dropped`
-            if let Err(e) = self.abort(Outcome::Dropped) {
-                log::error!("While dropping Operation: abort failed:\n    {:?}", e);
+            match self.abort(Outcome::Dropped) {
+                // The owner released the operation while it was active, typically because
+                // it died.
+                Ok(()) => PRUNING_STATS.record(
+                    PruningCause::BinderDeath,
+                    self.owner,
+                    self.logging_info.sec_level,
+                ),
+                Err(e) => log::error!("While dropping Operation: abort failed:\n    {:?}", e),
             }
         }
     }
@@ -475,17 +655,98 @@ impl Drop for Operation {
 
 /// The OperationDb holds weak references to all ongoing operations.
 /// Its main purpose is to facilitate operation pruning.
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub struct OperationDb {
     // TODO replace Vec with WeakTable when the weak_table crate becomes
     // available.
     operations: Mutex<Vec<Weak<Operation>>>,
+    // The number of forced operations that are currently being created.
+    forced_pending: AtomicUsize,
+    // The maximum input size of a single call into the KeyMint operations.
+    max_chunk_size: usize,
+    // The number of begin requests that are currently waiting for an operation slot.
+    queued_begins: AtomicUsize,
+}
+
+/// Marks a forced operation as pending as long as it is alive. See `OperationDb::begin_forced`.
+pub struct ForcedOperationGuard<'a> {
+    db: &'a OperationDb,
+}
+
+impl Drop for ForcedOperationGuard<'_> {
+    fn drop(&mut self) {
+        self.db.forced_pending.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// The interval at which a queued begin request retries to get an operation slot.
+const BEGIN_QUEUE_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Holds a place in the queue of begin requests waiting for an operation slot as long as
+/// it is alive. See `OperationDb::queue_begin`.
+pub struct QueuedBeginGuard<'a> {
+    db: &'a OperationDb,
+    deadline: Instant,
+}
+
+impl QueuedBeginGuard<'_> {
+    /// Sleeps until the next attempt to begin the operation is due. Returns false if the
+    /// deadline has passed, in which case the caller must give up.
+    pub fn wait(&self) -> bool {
+        let now = Instant::now();
+        if now >= self.deadline {
+            return false;
+        }
+        std::thread::sleep(BEGIN_QUEUE_POLL_INTERVAL.min(self.deadline - now));
+        true
+    }
+}
+
+impl Drop for QueuedBeginGuard<'_> {
+    fn drop(&mut self) {
+        self.db.queued_begins.fetch_sub(1, Ordering::Relaxed);
+    }
 }
 
 impl OperationDb {
-    /// Creates a new OperationDb.
-    pub fn new() -> Self {
-        Self { operations: Mutex::new(Vec::new()) }
+    /// Creates a new OperationDb. The input passed to the operations is split into
+    /// chunks of at most `max_chunk_size` bytes. See `max_chunk_size`.
+    pub fn new(max_chunk_size: usize) -> Self {
+        Self {
+            operations: Mutex::new(Vec::new()),
+            forced_pending: AtomicUsize::new(0),
+            max_chunk_size,
+            queued_begins: AtomicUsize::new(0),
+        }
+    }
+
+    /// Marks a forced operation as pending until the returned guard is dropped.
+    /// While a forced operation is pending, callers of regular operations cannot prune
+    /// other operations. Otherwise they could snatch up the slots that were freed up for
+    /// the forced operation.
+    pub fn begin_forced(&self) -> ForcedOperationGuard {
+        self.forced_pending.fetch_add(1, Ordering::Relaxed);
+        ForcedOperationGuard { db: self }
+    }
+
+    /// Enqueues a begin request that failed because all operation slots are taken and none
+    /// could be pruned. The caller may retry for up to `timeout` calling `QueuedBeginGuard::wait`
+    /// between attempts. Returns None if `timeout` is zero or if `max_len` requests are queued
+    /// already, in which case the caller must surface the error right away.
+    pub fn queue_begin(&self, timeout: Duration, max_len: usize) -> Option<QueuedBeginGuard> {
+        if timeout.is_zero() {
+            return None;
+        }
+        self.queued_begins
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| {
+                if n < max_len {
+                    Some(n + 1)
+                } else {
+                    None
+                }
+            })
+            .ok()?;
+        Some(QueuedBeginGuard { db: self, deadline: Instant::now() + timeout })
     }
 
     /// Creates a new operation.
@@ -505,7 +766,7 @@ impl OperationDb {
         let mut index: usize = 0;
         // First we iterate through the operation slots to try and find an unused
         // slot. If we don't find one, we append the new entry instead.
-        match (*operations).iter_mut().find(|s| {
+        let new_op = match (*operations).iter_mut().find(|s| {
             index += 1;
             s.upgrade().is_none()
         }) {
@@ -517,6 +778,7 @@ impl OperationDb {
                     auth_info,
                     forced,
                     logging_info,
+                    self.max_chunk_size,
                 ));
                 *free_slot = Arc::downgrade(&new_op);
                 new_op
@@ -529,11 +791,54 @@ impl OperationDb {
                     auth_info,
                     forced,
                     logging_info,
+                    self.max_chunk_size,
                 ));
                 operations.push(Arc::downgrade(&new_op));
                 new_op
             }
+        };
+
+        if trace::is_enabled() {
+            let live = operations.iter().filter(|op| op.strong_count() > 0).count();
+            trace::counter("keystore2.live_operations", live as i32);
         }
+        new_op
+    }
+
+    /// Aborts all active operations owned by `uid`, freeing up their KeyMint operation
+    /// slots right away. The clients get `ErrorCode::INVALID_OPERATION_HANDLE` on
+    /// subsequent use, like for pruned operations. Returns the number of aborted operations.
+    pub fn abort_operations_for_uid(&self, uid: u32) -> usize {
+        // Collect the operations first, so that the lock is not held while calling into
+        // KeyMint or while dropping the last reference to an operation.
+        let ops: Vec<Arc<Operation>> = self
+            .operations
+            .lock()
+            .expect("In OperationDb::abort_operations_for_uid.")
+            .iter()
+            .filter_map(|op| op.upgrade())
+            .filter(|op| op.owner == uid)
+            .collect();
+        ops.iter()
+            .filter(|op| match op.abort(Outcome::Pruned) {
+                Ok(()) => {
+                    PRUNING_STATS.record(
+                        PruningCause::ForceStop,
+                        op.owner,
+                        op.logging_info.sec_level,
+                    );
+                    true
+                }
+                Err(e) => {
+                    match e.root_cause().downcast_ref::<Error>() {
+                        // The operation was finalized in the meantime.
+                        Some(Error::Km(ErrorCode::INVALID_OPERATION_HANDLE)) => {}
+                        _ => log::error!("In abort_operations_for_uid: {:?}", e),
+                    }
+                    false
+                }
+            })
+            .count()
     }
 
     fn get(&self, index: usize) -> Option<Arc<Operation>> {
@@ -549,75 +854,63 @@ impl OperationDb {
     /// returned for various reasons. E.g., another thread may have snatched up the newly
     /// available slot. Callers may have to call prune multiple times before they get a
     /// free operation slot. Prune may also return `Err(Error::Rc(ResponseCode::BACKEND_BUSY))`
-    /// which indicates that no prunable operation was found.
-    ///
-    /// To find a suitable candidate we compute the malus for the caller and each existing
-    /// operation. The malus is the inverse of the pruning power (caller) or pruning
-    /// resistance (existing operation).
-    ///
-    /// The malus is based on the number of sibling operations and age. Sibling
-    /// operations are operations that have the same owner (UID).
-    ///
-    /// Every operation, existing or new, starts with a malus of 1. Every sibling
-    /// increases the malus by one. The age is the time since an operation was last touched.
-    /// It increases the malus by log6(<age in seconds> + 1) rounded down to the next
-    /// integer. So the malus increases stepwise after 5s, 35s, 215s, ...
-    /// Of two operations with the same malus the least recently used one is considered
-    /// weaker.
+    /// which indicates that no prunable operation was found. Pruned operations are counted
+    /// by cause in `PRUNING_STATS`.
     ///
-    /// For the caller to be able to prune an operation it must find an operation
-    /// with a malus higher than its own.
+    /// Every uid has a soft quota of concurrent operations. To find a suitable candidate
+    /// for a regular operation we proceed as follows.
+    ///  1. If the caller has already reached its quota, it can only prune its own
+    ///     operations. The least recently used sibling is pruned.
+    ///  2. Otherwise, if any owner exceeds its quota, the least recently used operation
+    ///     of the owner with the most operations is pruned.
+    ///  3. Otherwise, the least recently used operation of any owner is pruned, provided
+    ///     it has not been used for a minimum idle time.
+    /// If none of the above yields a candidate, no operation is pruned.
     ///
-    /// The malus can be expressed as
-    /// ```
-    /// malus = 1 + no_of_siblings + floor(log6(age_in_seconds + 1))
-    /// ```
-    /// where the constant `1` accounts for the operation under consideration.
-    /// In reality we compute it as
-    /// ```
-    /// caller_malus = 1 + running_siblings
-    /// ```
-    /// because the new operation has no age and is not included in the `running_siblings`,
-    /// and
-    /// ```
-    /// running_malus = running_siblings + floor(log6(age_in_seconds + 1))
-    /// ```
-    /// because a running operation is included in the `running_siblings` and it has
-    /// an age.
+    /// Operations that were kept alive by their owner with an empty `update` within the
+    /// keep-alive time are exempt from step 3, i.e., they can only be pruned by their owner,
+    /// by forced operations, or in step 2 if their owner exceeds its quota. Otherwise, an
+    /// owner could shield an unbounded number of operations by keeping them all alive.
     ///
-    /// ## Example
-    /// A caller with no running operations has a malus of 1. Young (age < 5s) operations
-    /// also with no siblings have a malus of one and cannot be pruned by the caller.
-    /// We have to find an operation that has at least one sibling or is older than 5s.
+    /// Steps 2 and 3 are first attempted with the operations of background apps only. The
+    /// operations of apps that ActivityManager reported to be in the foreground are only
+    /// considered if this yields no candidate. This way a background app churning through
+    /// operations does not evict the operations of the app the user is interacting with.
     ///
-    /// A caller with one running operation has a malus of 2. Now even young siblings
-    /// or single child aging (5s <= age < 35s) operations are off limit. An aging
-    /// sibling of two, however, would have a malus of 3 and would be fair game.
+    /// The quota, the minimum idle time, and the keep-alive time can be tuned with the
+    /// system properties `persist.keystore2.op_quota_per_uid`,
+    /// `persist.keystore2.op_min_idle_secs`, and `persist.keystore2.op_keep_alive_secs`.
+    /// See `PruningPolicy` for the defaults.
     ///
     /// ## Rationale
     /// Due to the limitation of KeyMint operation slots, we cannot get around pruning or
     /// a single app could easily DoS KeyMint.
     /// Keystore 1.0 used to always prune the least recently used operation. This at least
-    /// guaranteed that new operations can always be started. With the increased usage
-    /// of Keystore we saw increased pruning activity which can lead to a livelock
-    /// situation in the worst case.
+    /// guaranteed that new operations can always be started. But it also allowed a single
+    /// misbehaving app to evict the long-lived operations of every other app, and with the
+    /// increased usage of Keystore it could lead to a livelock situation in the worst case.
     ///
-    /// With the new pruning strategy we want to provide well behaved clients with
-    /// progress assurances while punishing DoS attempts. As a result of this
-    /// strategy we can be in the situation where no operation can be pruned and the
-    /// creation of a new operation fails. This allows single child operations which
-    /// are frequently updated to complete, thereby breaking up livelock situations
-    /// and facilitating system wide progress.
+    /// With the quota, the cost of excessive operation creation is borne by the offending
+    /// uid first, while well behaved clients keep their operations. The minimum idle time
+    /// of the global fallback allows frequently updated operations to complete, thereby
+    /// breaking up livelock situations and facilitating system wide progress. As a result
+    /// we can be in the situation where no operation can be pruned and the creation of
+    /// a new operation fails.
     ///
-    /// ## Update
-    /// We also allow callers to cannibalize their own sibling operations if no other
-    /// slot can be found. In this case the least recently used sibling is pruned.
+    /// ## Forced operations
+    /// Forced operations cannot be pruned. A caller of a forced operation is not subject
+    /// to the quota. Instead it preempts the least recently used operation of the owner with the
+    /// most running regular operations, i.e., the owner that is furthest over its fair share.
+    /// While a forced operation is being created, callers of regular operations cannot
+    /// prune at all and get `ResponseCode::BACKEND_BUSY`, so that the churn of regular
+    /// operations cannot take the freed up slots away from the forced operation.
     pub fn prune(&self, caller: u32, forced: bool) -> Result<(), Error> {
+        if !forced && self.forced_pending.load(Ordering::Relaxed) != 0 {
+            return Err(Error::Rc(ResponseCode::BACKEND_BUSY));
+        }
         loop {
-            // Maps the uid of the owner to the number of operations that owner has
-            // (running_siblings). More operations per owner lowers the pruning
-            // resistance of the operations of that owner. Whereas the number of
-            // ongoing operations of the caller lowers the pruning power of the caller.
+            // Maps the uid of the owner to the number of regular operations that owner has.
+            // Forced operations do not count against the quota, because they cannot be pruned.
             let mut owners: HashMap<u32, u64> = HashMap::new();
             let mut pruning_info: Vec<PruningInfo> = Vec::new();
 
@@ -629,95 +922,42 @@ impl OperationDb {
                 .for_each(|op| {
                     if let Some(op) = op.upgrade() {
                         if let Some(p_info) = op.get_pruning_info() {
-                            let owner = p_info.owner;
+                            if !p_info.forced {
+                                // Count operations per owner.
+                                *owners.entry(p_info.owner).or_insert(0) += 1;
+                            }
                             pruning_info.push(p_info);
-                            // Count operations per owner.
-                            *owners.entry(owner).or_insert(0) += 1;
                         }
                     }
                 });
 
-            // If the operation is forced, the caller has a malus of 0.
-            let caller_malus = if forced { 0 } else { 1u64 + *owners.entry(caller).or_default() };
-
-            // We iterate through all operations computing the malus and finding
-            // the candidate with the highest malus which must also be higher
-            // than the caller_malus.
-            struct CandidateInfo {
-                index: usize,
-                malus: u64,
-                last_usage: Instant,
-                age: Duration,
-            }
-            let mut oldest_caller_op: Option<CandidateInfo> = None;
-            let candidate = pruning_info.iter().fold(
-                None,
-                |acc: Option<CandidateInfo>, &PruningInfo { last_usage, owner, index, forced }| {
-                    // Compute the age of the current operation.
-                    let age = now
-                        .checked_duration_since(last_usage)
-                        .unwrap_or_else(|| Duration::new(0, 0));
-
-                    // Find the least recently used sibling as an alternative pruning candidate.
-                    if owner == caller {
-                        if let Some(CandidateInfo { age: a, .. }) = oldest_caller_op {
-                            if age > a {
-                                oldest_caller_op =
-                                    Some(CandidateInfo { index, malus: 0, last_usage, age });
-                            }
-                        } else {
-                            oldest_caller_op =
-                                Some(CandidateInfo { index, malus: 0, last_usage, age });
-                        }
-                    }
-
-                    // Compute the malus of the current operation.
-                    let malus = if forced {
-                        // Forced operations have a malus of 0. And cannot even be pruned
-                        // by other forced operations.
-                        0
-                    } else {
-                        // Expect safety: Every owner in pruning_info was counted in
-                        // the owners map. So this unwrap cannot panic.
-                        *owners.get(&owner).expect(
-                            "This is odd. We should have counted every owner in pruning_info.",
-                        ) + ((age.as_secs() + 1) as f64).log(6.0).floor() as u64
-                    };
-
-                    // Now check if the current operation is a viable/better candidate
-                    // the one currently stored in the accumulator.
-                    match acc {
-                        // First we have to find any operation that is prunable by the caller.
-                        None => {
-                            if caller_malus < malus {
-                                Some(CandidateInfo { index, malus, last_usage, age })
-                            } else {
-                                None
-                            }
-                        }
-                        // If we have found one we look for the operation with the worst score.
-                        // If there is a tie, the older operation is considered weaker.
-                        Some(CandidateInfo { index: i, malus: m, last_usage: l, age: a }) => {
-                            if malus > m || (malus == m && age > a) {
-                                Some(CandidateInfo { index, malus, last_usage, age })
-                            } else {
-                                Some(CandidateInfo { index: i, malus: m, last_usage: l, age: a })
-                            }
-                        }
-                    }
-                },
-            );
-
-            // If we did not find a suitable candidate we may cannibalize our oldest sibling.
-            let candidate = candidate.or(oldest_caller_op);
+            let candidate = if forced {
+                Self::find_forced_pruning_candidate(&pruning_info, &owners)
+            } else {
+                Self::find_pruning_candidate(
+                    caller,
+                    &pruning_info,
+                    &owners,
+                    &FOREGROUND_UIDS.lock().unwrap(),
+                    now,
+                    &PruningPolicy::get(),
+                )
+            };
 
             match candidate {
-                Some(CandidateInfo { index, malus: _, last_usage, age: _ }) => {
+                Some((index, last_usage, cause)) => {
                     match self.get(index) {
                         Some(op) => {
                             match op.prune(last_usage) {
                                 // We successfully freed up a slot.
-                                Ok(()) => break Ok(()),
+                                Ok(()) => {
+                                    PRUNING_STATS.record(
+                                        cause,
+                                        op.owner,
+                                        op.logging_info.sec_level,
+                                    );
+                                    break Ok(());
+                                }
                                 // This means the operation we tried to prune was on its way
                                 // out. It also means that the slot it had occupied was freed up.
                                 Err(Error::Km(ErrorCode::INVALID_OPERATION_HANDLE)) => break Ok(()),
@@ -759,11 +999,144 @@ impl OperationDb {
             }
         }
     }
+
+    /// Selects the operation to prune for a new regular operation of `caller` as described
+    /// in `prune`. `foreground` holds the uids of the foreground apps. Returns the index and
+    /// last usage of the candidate along with the pruning cause, or None if no operation may
+    /// be pruned.
+    fn find_pruning_candidate(
+        caller: u32,
+        pruning_info: &[PruningInfo],
+        owners: &HashMap<u32, u64>,
+        foreground: &HashSet<u32>,
+        now: Instant,
+        policy: &PruningPolicy,
+    ) -> Option<(usize, Instant, PruningCause)> {
+        let elapsed_since = |instant: Instant| {
+            now.checked_duration_since(instant).unwrap_or_else(|| Duration::new(0, 0))
+        };
+        let is_exempt_foreground = |owner: u32, include_foreground: bool| -> bool {
+            !include_foreground && owner != caller && foreground.contains(&owner)
+        };
+        // Returns the least recently used regular operation that was idle for at least
+        // `min_idle_time` and is owned by `owner` if given. Operations that were kept alive
+        // by another uid within its quota are skipped, and so are those of foreground apps
+        // unless `include_foreground` is set.
+        let find_lru = |owner: Option<u32>, min_idle_time: Duration, include_foreground: bool| {
+            pruning_info
+                .iter()
+                .filter(|p_info| !p_info.forced && owner.map_or(true, |o| o == p_info.owner))
+                .filter(|p_info| !is_exempt_foreground(p_info.owner, include_foreground))
+                .filter(|p_info| elapsed_since(p_info.last_usage) >= min_idle_time)
+                .filter(|p_info| {
+                    p_info.owner == caller
+                        || owners.get(&p_info.owner).copied().unwrap_or(0) > policy.quota_per_uid
+                        || p_info.last_keep_alive.map_or(true, |last_keep_alive| {
+                            elapsed_since(last_keep_alive) >= policy.keep_alive_time
+                        })
+                })
+                .min_by_key(|p_info| p_info.last_usage)
+                .map(|p_info| (p_info.index, p_info.last_usage))
+        };
+
+        if owners.get(&caller).copied().unwrap_or(0) >= policy.quota_per_uid {
+            return find_lru(Some(caller), Duration::new(0, 0), true)
+                .map(|(index, last_usage)| (index, last_usage, PruningCause::Quota));
+        }
+
+        for include_foreground in [false, true].iter().copied() {
+            let worst_offender = owners
+                .iter()
+                .filter(|(_, running)| **running > policy.quota_per_uid)
+                .filter(|(owner, _)| !is_exempt_foreground(**owner, include_foreground))
+                .max_by_key(|(_, running)| **running)
+                .map(|(owner, _)| *owner);
+            if let Some(candidate) = worst_offender
+                .and_then(|owner| find_lru(Some(owner), Duration::new(0, 0), include_foreground))
+            {
+                return Some((candidate.0, candidate.1, PruningCause::Quota));
+            }
+            if let Some(candidate) = find_lru(None, policy.min_idle_time, include_foreground) {
+                return Some((candidate.0, candidate.1, PruningCause::Lru));
+            }
+        }
+        None
+    }
+
+    /// Returns the index and last usage of the least recently used regular operation of
+    /// the owner with the most regular operations, or None if there are no regular operations.
+    fn find_forced_pruning_candidate(
+        pruning_info: &[PruningInfo],
+        owners: &HashMap<u32, u64>,
+    ) -> Option<(usize, Instant, PruningCause)> {
+        pruning_info
+            .iter()
+            .filter(|p_info| !p_info.forced)
+            .max_by(|a, b| {
+                // The owner with more operations is weaker, and of the operations of the same
+                // owner the one that was used less recently is weaker.
+                // Expect safety: Every owner of a regular operation was counted in owners.
+                owners[&a.owner]
+                    .cmp(&owners[&b.owner])
+                    .then_with(|| b.last_usage.cmp(&a.last_usage))
+            })
+            .map(|p_info| (p_info.index, p_info.last_usage, PruningCause::Forced))
+    }
+}
+
+/// Tunable thresholds of the operation pruning policy. See `OperationDb::prune`.
+struct PruningPolicy {
+    /// The soft limit of concurrent regular operations per uid.
+    quota_per_uid: u64,
+    /// Operations of owners within their quota are only pruned if they have not been used
+    /// for at least this long.
+    min_idle_time: Duration,
+    /// Operations that were kept alive within this time are exempt from pruning by
+    /// other uids, as long as their owner is within its quota.
+    keep_alive_time: Duration,
+}
+
+impl PruningPolicy {
+    /// Reads the current policy from the tunables in `config`. The quota is at least 1.
+    fn get() -> Self {
+        Self {
+            quota_per_uid: config::OPERATION_QUOTA_PER_UID.get().max(1),
+            min_idle_time: Duration::from_secs(config::OPERATION_MIN_IDLE_SECS.get()),
+            keep_alive_time: Duration::from_secs(config::OPERATION_KEEP_ALIVE_SECS.get()),
+        }
+    }
 }
 
 /// Implementation of IKeystoreOperation.
 pub struct KeystoreOperation {
-    operation: Mutex<Option<Arc<Operation>>>,
+    operation: Arc<Mutex<Option<Arc<Operation>>>>,
+}
+
+/// Keeps track of the binders of all live operations, so that an operation can be looked up
+/// when a client passes its binder back to Keystore, e.g., for the shared memory data path.
+/// Only weak references are held, so that the operations are still dropped when the clients
+/// drop their binders.
+#[derive(Default)]
+pub struct OperationBinderRegistry {
+    entries: Mutex<Vec<(WpIBinder, Weak<Mutex<Option<Arc<Operation>>>>)>>,
+}
+
+impl OperationBinderRegistry {
+    fn register(&self, mut binder: SpIBinder, operation: &Arc<Mutex<Option<Arc<Operation>>>>) {
+        let mut entries = self.entries.lock().expect("In OperationBinderRegistry::register.");
+        // Drop the entries of operations that are gone.
+        entries.retain(|(_, operation)| operation.strong_count() != 0);
+        entries.push((binder.downgrade(), Arc::downgrade(operation)));
+    }
+
+    fn find(&self, binder: &SpIBinder) -> Option<Arc<Mutex<Option<Arc<Operation>>>>> {
+        self.entries
+            .lock()
+            .expect("In OperationBinderRegistry::find.")
+            .iter()
+            .find(|(entry, _)| entry.promote().as_ref() == Some(binder))
+            .and_then(|(_, operation)| operation.upgrade())
+    }
 }
 
 impl KeystoreOperation {
@@ -771,10 +1144,70 @@ impl KeystoreOperation {
     /// BnKeystoreOperation proxy object. It also enables
     /// `BinderFeatures::set_requesting_sid` on the new interface, because
     /// we need it for checking Keystore permissions.
+    /// The binder is registered with `OPERATION_BINDERS`.
     pub fn new_native_binder(operation: Arc<Operation>) -> binder::Strong<dyn IKeystoreOperation> {
-        BnKeystoreOperation::new_binder(
-            Self { operation: Mutex::new(Some(operation)) },
+        let operation = Arc::new(Mutex::new(Some(operation)));
+        let result = BnKeystoreOperation::new_binder(
+            Self { operation: operation.clone() },
             BinderFeatures { set_requesting_sid: true, ..BinderFeatures::default() },
+        );
+        OPERATION_BINDERS.register(result.as_binder(), &operation);
+        result
+    }
+
+    /// Looks up the operation of a binder that was created by `new_native_binder`.
+    /// Returns `ResponseCode::INVALID_ARGUMENT` if the binder is not a live operation.
+    pub fn from_binder(operation: &Strong<dyn IKeystoreOperation>) -> Result<Self> {
+        match OPERATION_BINDERS.find(&operation.as_binder()) {
+            Some(operation) => Ok(Self { operation }),
+            None => Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+                .context("In KeystoreOperation::from_binder: Unknown operation."),
+        }
+    }
+
+    /// Finishes the operation with all of its input.
+    /// Implementation of the second half of `IKeystoreOneShotOperations::perform`.
+    pub fn finish_one_shot(
+        &self,
+        input: Option<&[u8]>,
+        signature: Option<&[u8]>,
+    ) -> Result<Option<Vec<u8>>> {
+        self.with_locked_operation(
+            |op| op.finish(input, signature).context("In KeystoreOperation::finish_one_shot"),
+            true,
+        )
+    }
+
+    /// Implementation of `IKeystoreSharedMemoryOperations::update`.
+    pub fn update_shared_memory(
+        &self,
+        input: &File,
+        input_length: u64,
+        output: &File,
+    ) -> Result<u64> {
+        self.with_locked_operation(
+            |op| {
+                op.update_shared_memory(input, input_length, output)
+                    .context("In KeystoreOperation::update_shared_memory")
+            },
+            false,
+        )
+    }
+
+    /// Implementation of `IKeystoreSharedMemoryOperations::finish`.
+    pub fn finish_shared_memory(
+        &self,
+        input: &File,
+        input_length: u64,
+        signature: Option<&[u8]>,
+        output: &File,
+    ) -> Result<u64> {
+        self.with_locked_operation(
+            |op| {
+                op.finish_shared_memory(input, input_length, signature, output)
+                    .context("In KeystoreOperation::finish_shared_memory")
+            },
+            true,
         )
     }
 
@@ -868,7 +1301,7 @@ impl IKeystoreOperation for KeystoreOperation {
                     // There is no reason to clutter the log with it. It is never the cause
                     // for a true problem.
                     Some(Error::Km(ErrorCode::INVALID_OPERATION_HANDLE)) => {}
-                    _ => log::error!("{:?}", e),
+                    _ => LOG_THROTTLE.error(&format!("{:?}", e)),
                 };
                 e
             },
@@ -876,3 +1309,192 @@ impl IKeystoreOperation for KeystoreOperation {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // All instants are relative to a point in the future, so that subtracting idle times
+    // cannot underflow.
+    fn test_now() -> Instant {
+        Instant::now() + Duration::from_secs(1000)
+    }
+
+    fn test_policy() -> PruningPolicy {
+        PruningPolicy {
+            quota_per_uid: 2,
+            min_idle_time: Duration::from_secs(5),
+            keep_alive_time: Duration::from_secs(30),
+        }
+    }
+
+    fn p_info(now: Instant, index: usize, owner: u32, idle_secs: u64) -> PruningInfo {
+        PruningInfo {
+            last_usage: now - Duration::from_secs(idle_secs),
+            last_keep_alive: None,
+            owner,
+            index,
+            forced: false,
+        }
+    }
+
+    fn count_owners(pruning_info: &[PruningInfo]) -> HashMap<u32, u64> {
+        let mut owners = HashMap::new();
+        for p_info in pruning_info.iter().filter(|p_info| !p_info.forced) {
+            *owners.entry(p_info.owner).or_insert(0) += 1;
+        }
+        owners
+    }
+
+    // Returns the index and the cause of the pruning candidate for a new operation of `caller`.
+    fn candidate(
+        caller: u32,
+        pruning_info: &[PruningInfo],
+        foreground: &[u32],
+        now: Instant,
+    ) -> Option<(usize, PruningCause)> {
+        OperationDb::find_pruning_candidate(
+            caller,
+            pruning_info,
+            &count_owners(pruning_info),
+            &foreground.iter().copied().collect(),
+            now,
+            &test_policy(),
+        )
+        .map(|(index, _, cause)| (index, cause))
+    }
+
+    #[test]
+    fn test_prune_own_operation_at_quota() {
+        let now = test_now();
+        // The caller reached its quota, so it prunes its own least recently used operation,
+        // although the operation of uid 2 has been idle for longer.
+        let ops = [p_info(now, 0, 1, 1), p_info(now, 1, 1, 2), p_info(now, 2, 2, 100)];
+        assert_eq!(Some((1, PruningCause::Quota)), candidate(1, &ops, &[], now));
+    }
+
+    #[test]
+    fn test_prune_worst_offender() {
+        let now = test_now();
+        // Uid 1 exceeds its quota, so its least recently used operation is pruned even though
+        // it was used recently, and the older operation of uid 2 is spared.
+        let ops = [
+            p_info(now, 0, 1, 1),
+            p_info(now, 1, 1, 2),
+            p_info(now, 2, 1, 3),
+            p_info(now, 3, 2, 100),
+        ];
+        assert_eq!(Some((2, PruningCause::Quota)), candidate(3, &ops, &[], now));
+    }
+
+    #[test]
+    fn test_prune_lru_after_min_idle_time() {
+        let now = test_now();
+        // Nobody exceeds the quota and no operation was idle for the minimum idle time.
+        let mut ops = vec![p_info(now, 0, 1, 2), p_info(now, 1, 2, 3)];
+        assert_eq!(None, candidate(3, &ops, &[], now));
+
+        // The least recently used of the idle operations is pruned.
+        ops.push(p_info(now, 2, 1, 10));
+        ops.push(p_info(now, 3, 4, 20));
+        assert_eq!(Some((3, PruningCause::Lru)), candidate(3, &ops, &[], now));
+    }
+
+    #[test]
+    fn test_kept_alive_operations_are_exempt_within_quota() {
+        let now = test_now();
+        let kept_alive = |index, idle_secs| {
+            let mut info = p_info(now, index, 1, idle_secs);
+            info.last_keep_alive = Some(now - Duration::from_secs(10));
+            info
+        };
+        let ops = [kept_alive(0, 100), p_info(now, 1, 2, 10)];
+        assert_eq!(Some((1, PruningCause::Lru)), candidate(3, &ops, &[], now));
+
+        // The owner may still prune its own kept alive operation.
+        let ops = [kept_alive(0, 100), p_info(now, 1, 1, 1)];
+        assert_eq!(Some((0, PruningCause::Quota)), candidate(1, &ops, &[], now));
+
+        // An owner exceeding its quota cannot shield its operations by keeping them alive.
+        let ops = [kept_alive(0, 1), kept_alive(1, 2), kept_alive(2, 3)];
+        assert_eq!(Some((2, PruningCause::Quota)), candidate(3, &ops, &[], now));
+
+        // The exemption expires after the keep-alive time.
+        let mut expired = p_info(now, 0, 1, 100);
+        expired.last_keep_alive = Some(now - Duration::from_secs(50));
+        assert_eq!(Some((0, PruningCause::Lru)), candidate(3, &[expired], &[], now));
+    }
+
+    #[test]
+    fn test_background_operations_are_pruned_first() {
+        let now = test_now();
+        // The operation of the foreground app 1 is older, but the background app 2 pays first.
+        let ops = [p_info(now, 0, 1, 100), p_info(now, 1, 2, 10)];
+        assert_eq!(Some((1, PruningCause::Lru)), candidate(3, &ops, &[1], now));
+
+        // The same holds for owners over their quota.
+        let ops = [
+            p_info(now, 0, 1, 1),
+            p_info(now, 1, 1, 2),
+            p_info(now, 2, 1, 3),
+            p_info(now, 3, 2, 10),
+        ];
+        assert_eq!(Some((3, PruningCause::Lru)), candidate(3, &ops, &[1], now));
+
+        // Foreground operations are pruned if there is no other candidate.
+        let ops = [p_info(now, 0, 1, 100), p_info(now, 1, 2, 1)];
+        assert_eq!(Some((0, PruningCause::Lru)), candidate(3, &ops, &[1], now));
+    }
+
+    #[test]
+    fn test_forced_operation_preempts_worst_offender() {
+        let now = test_now();
+        let mut forced = p_info(now, 0, 3, 1000);
+        forced.forced = true;
+        let ops = [forced, p_info(now, 1, 1, 100), p_info(now, 2, 2, 1), p_info(now, 3, 2, 2)];
+        // Forced operations cannot be pruned, and uid 2 has the most regular operations.
+        let candidate = OperationDb::find_forced_pruning_candidate(&ops, &count_owners(&ops))
+            .map(|(index, _, cause)| (index, cause));
+        assert_eq!(Some((3, PruningCause::Forced)), candidate);
+    }
+
+    #[test]
+    fn test_pending_forced_operation_blocks_regular_pruning() {
+        let db = OperationDb::new(1);
+        {
+            let _guard = db.begin_forced();
+            assert_eq!(Err(Error::Rc(ResponseCode::BACKEND_BUSY)), db.prune(1, false));
+        }
+        assert_eq!(0, db.forced_pending.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn test_split_finish_input() {
+        let input = [0u8; 10];
+
+        let (updates, finish) = split_finish_input(Some(&input), 4, KeyPurpose::ENCRYPT);
+        assert_eq!(vec![&input[0..4], &input[4..8]], updates);
+        assert_eq!(Some(&input[8..10]), finish);
+
+        let (updates, finish) = split_finish_input(Some(&input), 10, KeyPurpose::SIGN);
+        assert!(updates.is_empty());
+        assert_eq!(Some(&input[..]), finish);
+
+        let (updates, finish) = split_finish_input(Some(&[]), 4, KeyPurpose::ENCRYPT);
+        assert!(updates.is_empty());
+        assert_eq!(Some(&[][..]), finish);
+
+        let (updates, finish) = split_finish_input(None, 4, KeyPurpose::ENCRYPT);
+        assert!(updates.is_empty());
+        assert_eq!(None, finish);
+    }
+
+    #[test]
+    fn test_split_finish_input_agree_key() {
+        // The peer's public key is never split up, regardless of its size.
+        let peer_key = [0u8; 91];
+        let (updates, finish) = split_finish_input(Some(&peer_key), 16, KeyPurpose::AGREE_KEY);
+        assert!(updates.is_empty());
+        assert_eq!(Some(&peer_key[..]), finish);
+    }
+}
@@ -27,8 +27,11 @@ use keystore2_selinux as selinux;
 use lazy_static::lazy_static;
 use selinux::{implement_class, Backend, ClassPermission};
 use std::cmp::PartialEq;
+use std::collections::{HashMap, HashSet};
 use std::convert::From;
-use std::ffi::CStr;
+use std::ffi::{CStr, CString};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
 
 // Replace getcon with a mock in the test situation
 #[cfg(not(test))]
@@ -39,12 +42,139 @@ use tests::test_getcon as getcon;
 lazy_static! {
     // Panicking here is allowed because keystore cannot function without this backend
     // and it would happen early and indicate a gross misconfiguration of the device.
-    static ref KEYSTORE2_KEY_LABEL_BACKEND: selinux::KeystoreKeyBackend =
-            selinux::KeystoreKeyBackend::new().unwrap();
+    // The backend is reopened when the policy is reloaded, so that it picks up new
+    // keystore2_key_contexts.
+    static ref KEYSTORE2_KEY_LABEL_BACKEND: RwLock<selinux::KeystoreKeyBackend> =
+            RwLock::new(selinux::KeystoreKeyBackend::new().unwrap());
+    /// Contexts of the namespaces looked up since the last policy reload.
+    static ref NAMESPACE_CONTEXTS: RwLock<HashMap<i64, CString>> = Default::default();
+    static ref PERMISSION_CACHE: PermissionCache = Default::default();
+}
+
+/// Checks whether the SELinux policy was reloaded, e.g., because an OTA or mainline update was
+/// applied. If so, the label backend is reopened and all cached contexts and permissions are
+/// dropped, so that namespaces are resolved again lazily under the new policy. libselinux
+/// learns about reloads through the kernel's SELinux status page, so this check is cheap.
+fn handle_policy_reload() {
+    if !selinux::policy_updated() {
+        return;
+    }
+    log::info!("SELinux policy was reloaded. Dropping cached contexts and permissions.");
+    match selinux::KeystoreKeyBackend::new() {
+        Ok(backend) => *KEYSTORE2_KEY_LABEL_BACKEND.write().unwrap() = backend,
+        Err(e) => log::error!("In handle_policy_reload: Failed to reopen backend: {:?}", e),
+    }
+    NAMESPACE_CONTEXTS.write().unwrap().clear();
+    PERMISSION_CACHE.flush();
+}
+
+/// Maximal number of granted permissions held by the permission cache. The cache is cleared
+/// when it is full.
+const MAX_CACHED_PERMISSIONS: usize = 1024;
+
+/// Identifies an access check by source context, target context, class and permission.
+type PermissionCacheKey = (Vec<u8>, Vec<u8>, &'static str, &'static str);
+
+/// Statistics of the permission cache as reported by dumpsys.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PermissionCacheStats {
+    /// Number of checks answered from the cache.
+    pub hits: u64,
+    /// Number of checks that were passed to libselinux.
+    pub misses: u64,
+    /// Number of times the cache was cleared.
+    pub flushes: u64,
+    /// Number of granted permissions currently held.
+    pub entries: usize,
+}
+
+/// Caches granted permissions. Access checks are pure functions of their inputs as long as
+/// the policy does not change, so the cache is flushed whenever libselinux reports a policy
+/// reload. Denials are not cached, so that they are audited every time.
+#[derive(Default)]
+struct PermissionCache {
+    granted: RwLock<HashSet<PermissionCacheKey>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    flushes: AtomicU64,
+}
+
+impl PermissionCache {
+    fn check_permission<T: ClassPermission>(
+        &self,
+        source: &CStr,
+        target: &CStr,
+        perm: T,
+    ) -> anyhow::Result<()> {
+        let key = (
+            source.to_bytes().to_vec(),
+            target.to_bytes().to_vec(),
+            perm.class_name(),
+            perm.name(),
+        );
+        if self.granted.read().unwrap().contains(&key) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(());
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        selinux::check_permission(source, target, perm)?;
+
+        let mut granted = self.granted.write().unwrap();
+        if granted.len() >= MAX_CACHED_PERMISSIONS {
+            granted.clear();
+            self.flushes.fetch_add(1, Ordering::Relaxed);
+        }
+        granted.insert(key);
+        Ok(())
+    }
+
+    fn flush(&self) {
+        self.granted.write().unwrap().clear();
+        self.flushes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn stats(&self) -> PermissionCacheStats {
+        PermissionCacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            flushes: self.flushes.load(Ordering::Relaxed),
+            entries: self.granted.read().unwrap().len(),
+        }
+    }
+}
+
+/// Like `selinux::check_permission` but consults the permission cache first.
+fn check_permission<T: ClassPermission>(
+    source: &CStr,
+    target: &CStr,
+    perm: T,
+) -> anyhow::Result<()> {
+    PERMISSION_CACHE.check_permission(source, target, perm)
+}
+
+/// Returns the statistics of the permission cache.
+pub fn permission_cache_stats() -> PermissionCacheStats {
+    PERMISSION_CACHE.stats()
 }
 
 fn lookup_keystore2_key_context(namespace: i64) -> anyhow::Result<selinux::Context> {
-    KEYSTORE2_KEY_LABEL_BACKEND.lookup(&namespace.to_string())
+    if let Some(context) = NAMESPACE_CONTEXTS.read().unwrap().get(&namespace) {
+        return Ok(selinux::Context::CString(context.clone()));
+    }
+    let context = KEYSTORE2_KEY_LABEL_BACKEND.read().unwrap().lookup(&namespace.to_string())?;
+    // Failed lookups are not cached, so that newly introduced namespaces resolve as soon as
+    // their contexts are available.
+    NAMESPACE_CONTEXTS.write().unwrap().insert(namespace, CString::from(&*context));
+    Ok(context)
+}
+
+/// Resolves the SELinux context of the given namespace ahead of the first access check, so
+/// that later checks are answered from the context cache.
+pub fn preload_key_context(namespace: i64) -> anyhow::Result<()> {
+    handle_policy_reload();
+    lookup_keystore2_key_context(namespace)
+        .with_context(|| format!("In preload_key_context: Namespace {} has no context.", namespace))
+        .map(|_| ())
 }
 
 implement_class!(
@@ -148,6 +278,36 @@ implement_class!(
         /// Checked on calls to IRemotelyProvisionedKeyPool::getAttestationKey
         #[selinux(name = get_attestation_key)]
         GetAttestationKey,
+        /// Checked when a key is generated or imported into a namespace that exceeds its key
+        /// quota.
+        #[selinux(name = exempt_from_key_quota)]
+        ExemptFromKeyQuota,
+        /// Checked when entries of the legacy keystore blob store (ILegacyKeystore) are read,
+        /// written, listed, or removed. Unlike the keystore2_key permissions, this does not
+        /// grant any rights over keys. See `config::LEGACY_BLOB_STORE_PERMISSION_ENFORCED`.
+        #[selinux(name = use_legacy_blob_store)]
+        UseLegacyBlobStore,
+        /// Checked on calls to IKeystoreAuthorization::getLastAuthTime.
+        #[selinux(name = get_last_auth_time)]
+        GetLastAuthTime,
+        /// Checked when a key is created in the TEE on behalf of a caller that requested
+        /// StrongBox on a device without StrongBox. See `KEY_FLAG_STRONGBOX_FALLBACK`.
+        #[selinux(name = strongbox_fallback)]
+        StrongBoxFallback,
+        /// Checked when IKeystoreMaintenance::onUidForegroundChanged is called.
+        #[selinux(name = report_uid_state)]
+        ReportUidState,
+        /// Checked when IKeystoreMaintenance::onDeviceStateChanged or
+        /// IKeystoreMaintenance::runDeferredMaintenance is called.
+        #[selinux(name = report_device_state)]
+        ReportDeviceState,
+        /// Checked when IKeystoreMaintenance::exportCertificates or
+        /// IKeystoreMaintenance::importCertificates is called.
+        #[selinux(name = backup_certificates)]
+        BackupCertificates,
+        /// Checked when IKeystoreCapabilities::getSupportedAlgorithms is called.
+        #[selinux(name = get_capabilities)]
+        GetCapabilities,
     }
 );
 
@@ -262,8 +422,9 @@ impl IntoIterator for KeyPermSet {
 /// Uses `selinux::check_permission` to check if the given caller context `caller_cxt` may access
 /// the given permision `perm` of the `keystore2` security class.
 pub fn check_keystore_permission(caller_ctx: &CStr, perm: KeystorePerm) -> anyhow::Result<()> {
+    handle_policy_reload();
     let target_context = getcon().context("check_keystore_permission: getcon failed.")?;
-    selinux::check_permission(caller_ctx, &target_context, perm)
+    check_permission(caller_ctx, &target_context, perm)
 }
 
 /// Uses `selinux::check_permission` to check if the given caller context `caller_cxt` has
@@ -284,6 +445,7 @@ pub fn check_grant_permission(
     access_vec: KeyPermSet,
     key: &KeyDescriptor,
 ) -> anyhow::Result<()> {
+    handle_policy_reload();
     let target_context = match key.domain {
         Domain::APP => getcon().context("check_grant_permission: getcon failed.")?,
         Domain::SELINUX => lookup_keystore2_key_context(key.nspace)
@@ -291,7 +453,7 @@ pub fn check_grant_permission(
         _ => return Err(KsError::sys()).context(format!("Cannot grant {:?}.", key.domain)),
     };
 
-    selinux::check_permission(caller_ctx, &target_context, KeyPerm::Grant)
+    check_permission(caller_ctx, &target_context, KeyPerm::Grant)
         .context("Grant permission is required when granting.")?;
 
     if access_vec.includes(KeyPerm::Grant) {
@@ -299,7 +461,7 @@ pub fn check_grant_permission(
     }
 
     for p in access_vec.into_iter() {
-        selinux::check_permission(caller_ctx, &target_context, p).context(format!(
+        check_permission(caller_ctx, &target_context, p).context(format!(
             "check_grant_permission: check_permission failed. \
             The caller may have tried to grant a permission that they don't possess. {:?}",
             p
@@ -334,6 +496,7 @@ pub fn check_key_permission(
     key: &KeyDescriptor,
     access_vector: &Option<KeyPermSet>,
 ) -> anyhow::Result<()> {
+    handle_policy_reload();
     // If an access vector was supplied, the key is either accessed by GRANT or by KEY_ID.
     // In the former case, key.domain was set to GRANT and we check the failure cases
     // further below. If the access is requested by KEY_ID, key.domain would have been
@@ -383,7 +546,7 @@ pub fn check_key_permission(
                 .context("Domain::BLOB: Failed to lookup namespace.")?;
             // If DOMAIN_KEY_BLOB was specified, we check for the "manage_blob"
             // permission in addition to the requested permission.
-            selinux::check_permission(caller_ctx, &tctx, KeyPerm::ManageBlob)?;
+            check_permission(caller_ctx, &tctx, KeyPerm::ManageBlob)?;
 
             tctx
         }
@@ -393,7 +556,7 @@ pub fn check_key_permission(
         }
     };
 
-    selinux::check_permission(caller_ctx, &target_context, perm)
+    check_permission(caller_ctx, &target_context, perm)
 }
 
 #[cfg(test)]
@@ -516,6 +679,29 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn permission_cache_test() -> Result<()> {
+        let cache = PermissionCache::default();
+        let system_server_ctx = Context::new("u:r:system_server:s0")?;
+        let shell_ctx = Context::new("u:r:shell:s0")?;
+        let target = getcon()?;
+
+        cache.check_permission(&system_server_ctx, &target, KeystorePerm::AddAuth)?;
+        cache.check_permission(&system_server_ctx, &target, KeystorePerm::AddAuth)?;
+        // Denials are not cached.
+        assert_perm_failed!(cache.check_permission(&shell_ctx, &target, KeystorePerm::AddAuth));
+        assert_perm_failed!(cache.check_permission(&shell_ctx, &target, KeystorePerm::AddAuth));
+
+        let stats = cache.stats();
+        assert_eq!(stats.hits, 1);
+        assert_eq!(stats.misses, 3);
+        assert_eq!(stats.entries, 1);
+
+        cache.flush();
+        assert_eq!(cache.stats().entries, 0);
+        Ok(())
+    }
+
     #[test]
     fn check_grant_permission_app() -> Result<()> {
         let system_server_ctx = Context::new("u:r:system_server:s0")?;
@@ -0,0 +1,139 @@
+// Copyright 2021, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module keeps statistics about operation pruning, i.e., a counter per pruning cause and
+//! the uids that lost the most operations. Both are reported by dumpsys, and each pruned
+//! operation is also logged to statsd, so that the pruning policy can be tuned based on field
+//! data.
+
+use crate::metrics_store::log_operation_pruning_stats;
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::SecurityLevel::SecurityLevel;
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+use std::sync::Mutex;
+
+/// Number of uids for which pruned operations are counted.
+const MAX_TRACKED_UIDS: usize = 256;
+
+/// Number of uids reported by `PruningStats::dump`.
+const TOP_UIDS: usize = 10;
+
+/// The reason an operation was pruned. See `OperationDb::prune`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum PruningCause {
+    /// The least recently used idle operation was pruned.
+    Lru,
+    /// The operation of an owner that reached or exceeded its quota was pruned.
+    Quota,
+    /// The operation was preempted by a forced operation.
+    Forced,
+    /// The owner released the operation while it was still active, e.g., because it died.
+    BinderDeath,
+    /// The owner was force stopped and its operations were aborted.
+    ForceStop,
+}
+
+#[derive(Default)]
+struct PruningStatsState {
+    counts: BTreeMap<PruningCause, u64>,
+    by_uid: HashMap<u32, u64>,
+}
+
+/// Counts pruned operations by cause and by owner.
+#[derive(Default)]
+pub struct PruningStats {
+    state: Mutex<PruningStatsState>,
+}
+
+impl PruningStats {
+    /// Records that an operation of `owner` at the given security level was pruned.
+    pub fn record(&self, cause: PruningCause, owner: u32, sec_level: SecurityLevel) {
+        self.count(cause, owner);
+        log_operation_pruning_stats(cause, sec_level);
+    }
+
+    fn count(&self, cause: PruningCause, owner: u32) {
+        let mut state = self.state.lock().unwrap();
+        *state.counts.entry(cause).or_default() += 1;
+        if !state.by_uid.contains_key(&owner) && state.by_uid.len() >= MAX_TRACKED_UIDS {
+            // Make room by forgetting the uid with the fewest pruned operations.
+            if let Some(uid) = state.by_uid.iter().min_by_key(|(_, n)| **n).map(|(uid, _)| *uid) {
+                state.by_uid.remove(&uid);
+            }
+        }
+        *state.by_uid.entry(owner).or_default() += 1;
+    }
+
+    /// Returns the number of pruned operations by cause.
+    pub fn counts(&self) -> BTreeMap<PruningCause, u64> {
+        self.state.lock().unwrap().counts.clone()
+    }
+
+    /// Returns up to `n` uids with the most pruned operations, most pruned first.
+    pub fn top_uids(&self, n: usize) -> Vec<(u32, u64)> {
+        let mut by_uid: Vec<(u32, u64)> =
+            self.state.lock().unwrap().by_uid.iter().map(|(uid, n)| (*uid, *n)).collect();
+        by_uid.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        by_uid.truncate(n);
+        by_uid
+    }
+
+    /// Writes the pruning counts and the top offending uids to the given writer.
+    pub fn dump(&self, w: &mut dyn Write) -> std::io::Result<()> {
+        writeln!(w, "Pruned operations:")?;
+        for (cause, count) in self.counts().iter() {
+            writeln!(w, "  {:?}: {}", cause, count)?;
+        }
+        writeln!(w, "Most pruned uids:")?;
+        for (uid, count) in self.top_uids(TOP_UIDS).iter() {
+            writeln!(w, "  uid {}: {}", uid, count)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counts_and_top_uids() {
+        let stats = PruningStats::default();
+        for _ in 0..3 {
+            stats.count(PruningCause::Quota, 10002);
+        }
+        stats.count(PruningCause::Lru, 10001);
+        stats.count(PruningCause::Forced, 10003);
+        stats.count(PruningCause::Lru, 10003);
+
+        let counts = stats.counts();
+        assert_eq!(counts.get(&PruningCause::Quota), Some(&3));
+        assert_eq!(counts.get(&PruningCause::Lru), Some(&2));
+        assert_eq!(counts.get(&PruningCause::BinderDeath), None);
+        assert_eq!(stats.top_uids(2), vec![(10002, 3), (10003, 2)]);
+    }
+
+    #[test]
+    fn test_tracked_uids_are_bounded() {
+        let stats = PruningStats::default();
+        stats.count(PruningCause::Quota, 1);
+        stats.count(PruningCause::Quota, 1);
+        for uid in 2..(MAX_TRACKED_UIDS as u32 + 10) {
+            stats.count(PruningCause::Lru, uid);
+        }
+        let top = stats.top_uids(MAX_TRACKED_UIDS + 10);
+        assert_eq!(top.len(), MAX_TRACKED_UIDS);
+        assert_eq!(top[0], (1, 2));
+    }
+}
@@ -0,0 +1,151 @@
+// Copyright 2021, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements per caller rate limits for service calls. A single misbehaving app
+//! must not be able to saturate the binder threads of Keystore or the KeyMint TA. Each app uid
+//! gets a token bucket per limit. Every call takes a token, and the tokens are replenished at
+//! a fixed rate up to the bucket size, which allows short bursts. Callers exceeding the rate get
+//! `ResponseCode::BACKEND_BUSY` and are expected to retry later. System uids are exempt.
+
+use crate::config::Tunable;
+use crate::error::Error;
+use crate::utils::AID_USER_OFFSET;
+use android_system_keystore2::aidl::android::system::keystore2::ResponseCode::ResponseCode;
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// The first app id of regular apps. Callers with lower app ids are system components, which
+/// are not rate limited.
+const AID_APP_START: u32 = 10000;
+
+/// Number of buckets at which full buckets are dropped. A full bucket is equivalent to no
+/// bucket, so this only bounds the memory use.
+const MAX_BUCKETS: usize = 1024;
+
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl Bucket {
+    /// Returns the number of tokens in the bucket at `now`.
+    fn refilled(&self, now: Instant, rate: u64, burst: f64) -> f64 {
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        (self.tokens + elapsed * rate as f64).min(burst)
+    }
+}
+
+/// Limits the rate of calls per caller uid.
+pub struct RateLimit {
+    name: &'static str,
+    rate: &'static Tunable<u64>,
+    burst: &'static Tunable<u64>,
+    buckets: Mutex<HashMap<u32, Bucket>>,
+}
+
+impl RateLimit {
+    /// Creates a new rate limit for the calls summarized as `name`. The number of calls per
+    /// second and the size of the buckets are read from the tunables `rate` and `burst` on
+    /// every call. A rate of 0 means unlimited.
+    pub fn new(
+        name: &'static str,
+        rate: &'static Tunable<u64>,
+        burst: &'static Tunable<u64>,
+    ) -> Self {
+        Self { name, rate, burst, buckets: Default::default() }
+    }
+
+    /// Takes a token from the bucket of `uid`, or fails with `ResponseCode::BACKEND_BUSY` if the
+    /// bucket is empty.
+    pub fn check(&self, uid: u32) -> Result<()> {
+        self.check_n(uid, 1)
+    }
+
+    /// Takes `n` tokens from the bucket of `uid` at once, for a call that does the work of `n`
+    /// calls. Fails with `ResponseCode::BACKEND_BUSY` and takes no token if the bucket holds
+    /// fewer than `n` tokens.
+    pub fn check_n(&self, uid: u32, n: u32) -> Result<()> {
+        self.check_at(uid, n, Instant::now())
+    }
+
+    fn check_at(&self, uid: u32, n: u32, now: Instant) -> Result<()> {
+        let rate = self.rate.get();
+        if rate == 0 || uid % AID_USER_OFFSET < AID_APP_START {
+            return Ok(());
+        }
+        let burst = self.burst.get().max(1) as f64;
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_BUCKETS {
+            buckets.retain(|_, bucket| bucket.refilled(now, rate, burst) < burst);
+        }
+        let bucket = buckets.entry(uid).or_insert(Bucket { tokens: burst, last_refill: now });
+        bucket.tokens = bucket.refilled(now, rate, burst);
+        bucket.last_refill = now;
+        if bucket.tokens < n as f64 {
+            log::warn!("{}: uid {} exceeded {} calls per second.", self.name, uid, rate);
+            return Err(Error::Rc(ResponseCode::BACKEND_BUSY))
+                .context(format!("In RateLimit::check: {} rate exceeded.", self.name));
+        }
+        bucket.tokens -= n as f64;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::get_error_code;
+    use std::time::Duration;
+
+    static TEST_RATE: Tunable<u64> = Tunable::new("keystore2.test.rate_limit", 10);
+    static TEST_BURST: Tunable<u64> = Tunable::new("keystore2.test.rate_limit_burst", 2);
+
+    #[test]
+    fn test_check() {
+        let limit = RateLimit::new("test", &TEST_RATE, &TEST_BURST);
+        let app_uid = 10100;
+        let now = Instant::now();
+        limit.check_at(app_uid, 1, now).expect("First call should pass.");
+        limit.check_at(app_uid, 1, now).expect("Second call should pass within the burst.");
+        let busy = limit.check_at(app_uid, 1, now).err().expect("Third call should be limited.");
+        assert_eq!(ResponseCode::BACKEND_BUSY.0, get_error_code(&busy));
+
+        // Other apps have their own bucket, and system uids are exempt.
+        limit.check_at(app_uid + 1, 1, now).expect("Other apps should not be limited.");
+        for _ in 0..10 {
+            limit.check_at(1000, 1, now).expect("System uids should not be limited.");
+        }
+
+        // One token is replenished every 100ms.
+        let later = now + Duration::from_millis(100);
+        limit.check_at(app_uid, 1, later).expect("A token should have been replenished.");
+        assert!(limit.check_at(app_uid, 1, later).is_err());
+    }
+
+    #[test]
+    fn test_check_n() {
+        let limit = RateLimit::new("test", &TEST_RATE, &TEST_BURST);
+        let app_uid = 10100;
+        let now = Instant::now();
+        // A call that needs more tokens than the bucket holds takes none of them.
+        assert!(limit.check_at(app_uid, 3, now).is_err());
+        limit.check_at(app_uid, 2, now).expect("Two tokens should be available.");
+        assert!(limit.check_at(app_uid, 1, now).is_err());
+
+        let later = now + Duration::from_millis(200);
+        limit.check_at(app_uid, 2, later).expect("Two tokens should have been replenished.");
+    }
+}
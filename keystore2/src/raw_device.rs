@@ -36,6 +36,8 @@ use android_system_keystore2::aidl::android::system::keystore2::{
 };
 use anyhow::{Context, Result};
 use binder::Strong;
+use keystore2_crypto::ZVec;
+use std::convert::TryFrom;
 
 /// Wrapper for operating directly on a KeyMint device.
 /// These methods often mirror methods in [`crate::security_level`]. However
@@ -130,6 +132,7 @@ impl KeyMintDevice {
             &CertificateInfo::new(None, None),
             &key_metadata,
             &self.km_uuid,
+            false,
         )
         .context("In create_and_store_key: store_new_key failed")?;
         Ok(())
@@ -301,7 +304,8 @@ impl KeyMintDevice {
     }
 
     /// Use the created key in an operation that can be done with
-    /// a call to begin followed by a call to finish.
+    /// a call to begin followed by a call to finish. The output is returned in a ZVec, because
+    /// the callers use it as key material.
     #[allow(clippy::too_many_arguments)]
     pub fn use_key_in_one_step(
         &self,
@@ -312,7 +316,7 @@ impl KeyMintDevice {
         operation_parameters: &[KeyParameter],
         auth_token: Option<&HardwareAuthToken>,
         input: &[u8],
-    ) -> Result<Vec<u8>> {
+    ) -> Result<ZVec> {
         let key_blob = KeyBlob::Ref(key_blob);
 
         let (begin_result, _) = self
@@ -327,10 +331,12 @@ impl KeyMintDevice {
             .operation
             .ok_or_else(Error::sys)
             .context("In use_key_in_one_step: Operation missing")?;
-        map_km_error({
+        let output = map_km_error({
             let _wp = wd::watch_millis("In use_key_in_one_step: calling: finish", 500);
             operation.finish(Some(input), None, None, None, None)
         })
-        .context("In use_key_in_one_step: Failed to finish operation.")
+        .context("In use_key_in_one_step: Failed to finish operation.")?;
+        // Converting the Vec takes over its buffer, so that the output is not copied.
+        ZVec::try_from(output).context("In use_key_in_one_step: Failed to convert output to ZVec.")
     }
 }
@@ -274,7 +274,7 @@ impl RemoteProvisioningService {
         let dev = self.get_dev_by_sec_level(&sec_level)?;
         let (_, _, uuid) = get_keymint_device(&sec_level)?;
         let keys_to_sign = DB.with::<_, Result<Vec<MacedPublicKey>>>(|db| {
-            let mut db = db.borrow_mut();
+            let mut db = db.borrow_mut()?;
             Ok(db
                 .fetch_unsigned_attestation_keys(num_csr, &uuid)?
                 .iter()
@@ -425,7 +425,7 @@ impl RemoteProvisioningService {
     /// regardless of what state of the attestation key lifecycle they were in.
     pub fn delete_all_keys(&self) -> Result<i64> {
         DB.with::<_, Result<i64>>(|db| {
-            let mut db = db.borrow_mut();
+            let mut db = db.borrow_mut()?;
             db.delete_all_attestation_keys()
         })
     }
@@ -437,7 +437,7 @@ impl RemoteProvisioningService {
 pub fn get_pool_status(expired_by: i64, sec_level: SecurityLevel) -> Result<AttestationPoolStatus> {
     let (_, _, uuid) = get_keymint_device(&sec_level)?;
     DB.with::<_, Result<AttestationPoolStatus>>(|db| {
-        let mut db = db.borrow_mut();
+        let mut db = db.borrow_mut()?;
         // delete_expired_attestation_keys is always safe to call, and will remove anything
         // older than the date at the time of calling. No work should be done on the
         // attestation keys unless the pool status is checked first, so this call should be
@@ -559,29 +559,27 @@ impl IRemoteProvisioning for RemoteProvisioningService {
         sec_level: SecurityLevel,
     ) -> binder::Result<()> {
         let _wp = wd::watch_millis("IRemoteProvisioning::provisionCertChain", 500);
-        DB.with::<_, binder::Result<()>>(|db| {
-            map_or_log_err(
+        map_or_log_err(
+            DB.with(|db| {
                 self.provision_cert_chain(
-                    &mut db.borrow_mut(),
+                    &mut db.borrow_mut()?,
                     public_key,
                     batch_cert,
                     certs,
                     expiration_date,
                     sec_level,
-                ),
-                Ok,
-            )
-        })
+                )
+            }),
+            Ok,
+        )
     }
 
     fn generateKeyPair(&self, is_test_mode: bool, sec_level: SecurityLevel) -> binder::Result<()> {
         let _wp = wd::watch_millis("IRemoteProvisioning::generateKeyPair", 500);
-        DB.with::<_, binder::Result<()>>(|db| {
-            map_or_log_err(
-                self.generate_key_pair(&mut db.borrow_mut(), is_test_mode, sec_level),
-                Ok,
-            )
-        })
+        map_or_log_err(
+            DB.with(|db| self.generate_key_pair(&mut db.borrow_mut()?, is_test_mode, sec_level)),
+            Ok,
+        )
     }
 
     fn getImplementationInfo(&self) -> binder::Result<Vec<ImplInfo>> {
@@ -683,9 +681,10 @@ impl IRemotelyProvisionedKeyPool for RemotelyProvisionedKeyPoolService {
     ) -> binder::Result<RemotelyProvisionedKey> {
         let _wp = wd::watch_millis("IRemotelyProvisionedKeyPool::getAttestationKey", 500);
         map_or_log_err(check_keystore_permission(KeystorePerm::GetAttestationKey), Ok)?;
-        DB.with::<_, binder::Result<RemotelyProvisionedKey>>(|db| {
-            map_or_log_err(self.get_attestation_key(&mut db.borrow_mut(), caller_uid, irpc_id), Ok)
-        })
+        map_or_log_err(
+            DB.with(|db| self.get_attestation_key(&mut db.borrow_mut()?, caller_uid, irpc_id)),
+            Ok,
+        )
     }
 }
 
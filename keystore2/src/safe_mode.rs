@@ -0,0 +1,132 @@
+// Copyright 2021, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module detects crash loops of Keystore during startup and puts Keystore into safe mode
+//! if it finds one. In safe mode, optional subsystems such as remote key provisioning, the
+//! legacy blob store, and background sweeps are skipped, so that Keystore can still serve the
+//! core key operations if one of those subsystems is what brings it down.
+//!
+//! Startup attempts are counted in a file next to the database rather than in the database
+//! itself, because a broken database is one of the causes of crash loops. The counter is reset
+//! once Keystore has started all of its subsystems and kept running for a grace period, so that
+//! a subsystem that crashes Keystore shortly after startup is also caught.
+
+use crate::config;
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+/// Name of the file that counts the startup attempts since the last successful startup.
+const STARTUP_ATTEMPTS_FILE_NAME: &str = "startup_attempts";
+
+static SAFE_MODE: AtomicBool = AtomicBool::new(false);
+
+fn startup_attempts_path(db_path: &Path) -> PathBuf {
+    db_path.join(STARTUP_ATTEMPTS_FILE_NAME)
+}
+
+fn read_startup_attempts(path: &Path) -> Result<u64> {
+    match std::fs::read_to_string(path) {
+        Ok(contents) => Ok(contents.trim().parse().unwrap_or(0)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(e).context("In read_startup_attempts."),
+    }
+}
+
+/// Records a startup attempt. If the previous startup attempts failed at least as many times
+/// in a row as configured by `config::SAFE_MODE_CRASH_THRESHOLD`, safe mode is entered.
+/// Must be called before any optional subsystem is started.
+pub fn on_startup(db_path: &Path) {
+    let path = startup_attempts_path(db_path);
+    let failed_attempts = match read_startup_attempts(&path) {
+        Ok(attempts) => attempts,
+        Err(e) => {
+            log::error!("In on_startup: Failed to read startup attempts: {:?}", e);
+            0
+        }
+    };
+    if let Err(e) = std::fs::write(&path, (failed_attempts + 1).to_string()) {
+        log::error!("In on_startup: Failed to record startup attempt: {:?}", e);
+    }
+    let threshold = config::SAFE_MODE_CRASH_THRESHOLD.get();
+    if threshold != 0 && failed_attempts >= threshold {
+        log::error!(
+            "SAFE MODE: Keystore failed to start up {} times in a row. Optional subsystems are \
+             disabled until Keystore starts up successfully.",
+            failed_attempts
+        );
+        SAFE_MODE.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Records that Keystore started up successfully, which resets the count of startup attempts.
+/// Safe mode stays in effect until the next restart.
+pub fn on_startup_completed(db_path: &Path) {
+    if let Err(e) = std::fs::remove_file(startup_attempts_path(db_path)) {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            log::error!("In on_startup_completed: Failed to reset startup attempts: {:?}", e);
+        }
+    }
+}
+
+/// Calls `on_startup_completed` once Keystore has kept running for the grace period configured
+/// by `config::SAFE_MODE_GRACE_PERIOD_SECS`. Must be called after all subsystems were started.
+pub fn schedule_startup_completed(db_path: &Path) {
+    let db_path = db_path.to_path_buf();
+    let grace_period = Duration::from_secs(config::SAFE_MODE_GRACE_PERIOD_SECS.get());
+    let builder = std::thread::Builder::new().name("keystore2_startup_grace_period".into());
+    let spawned = builder.spawn(move || {
+        std::thread::sleep(grace_period);
+        log::info!("Keystore survived the startup grace period.");
+        on_startup_completed(&db_path);
+    });
+    if let Err(e) = spawned {
+        log::error!("In schedule_startup_completed: Failed to spawn thread: {:?}", e);
+    }
+}
+
+/// Returns true if Keystore runs in safe mode.
+pub fn is_enabled() -> bool {
+    SAFE_MODE.load(Ordering::Relaxed)
+}
+
+/// Returns true and logs that `component` is skipped if Keystore runs in safe mode.
+pub fn skip(component: &str) -> bool {
+    if is_enabled() {
+        log::error!("SAFE MODE: Skipping {}.", component);
+        true
+    } else {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use keystore2_test_utils::TempDir;
+
+    #[test]
+    fn test_startup_attempts() -> Result<()> {
+        let temp_dir = TempDir::new("safe_mode_test")?;
+        let path = startup_attempts_path(temp_dir.path());
+        assert_eq!(read_startup_attempts(&path)?, 0);
+        on_startup(temp_dir.path());
+        on_startup(temp_dir.path());
+        assert_eq!(read_startup_attempts(&path)?, 2);
+        on_startup_completed(temp_dir.path());
+        assert_eq!(read_startup_attempts(&path)?, 0);
+        Ok(())
+    }
+}
@@ -18,18 +18,28 @@ use crate::attestation_key_utils::{get_attest_key_info, AttestationKeyInfo};
 use crate::audit_log::{
     log_key_deleted, log_key_generated, log_key_imported, log_key_integrity_violation,
 };
+use crate::blob_upgrade;
+use crate::concurrency::ConcurrencyLimit;
+use crate::config;
 use crate::database::{BlobInfo, CertificateInfo, KeyIdGuard};
 use crate::error::{self, map_km_error, map_or_log_err, Error, ErrorCode};
-use crate::globals::{DB, ENFORCEMENTS, LEGACY_IMPORTER, SUPER_KEY};
+use crate::globals::{
+    DB, ENFORCEMENTS, KEY_CREATION_RATE_LIMIT, KEY_USAGE, LEGACY_IMPORTER, OPERATION_DBS,
+    OPERATION_RATE_LIMIT, SECURITY_LEVELS, SUPER_KEY,
+};
 use crate::key_parameter::KeyParameter as KsKeyParam;
 use crate::key_parameter::KeyParameterValue as KsKeyParamValue;
+use crate::legacy_shadow;
 use crate::metrics_store::log_key_creation_event_stats;
 use crate::remote_provisioning::RemProvState;
 use crate::super_key::{KeyBlob, SuperKeyManager};
+use crate::trace;
 use crate::utils::{
-    check_device_attestation_permissions, check_key_permission,
-    check_unique_id_attestation_permissions, is_device_id_attestation_tag,
-    key_characteristics_to_internal, uid_to_android_user, watchdog as wd,
+    check_curve_25519_purposes, check_device_attestation_permissions,
+    check_device_id_attestation_params, check_key_permission, check_keystore_permission,
+    check_unique_id_attestation_permissions, get_boot_id, is_device_id_attestation_tag,
+    key_characteristics_to_internal, map_device_id_attestation_error, uid_to_android_user,
+    watchdog as wd,
 };
 use crate::{
     database::{
@@ -38,18 +48,20 @@ use crate::{
     },
     operation::KeystoreOperation,
     operation::LoggingInfo,
-    operation::OperationDb,
-    permission::KeyPerm,
+    operation::{max_chunk_size, OperationDb},
+    permission::{KeyPerm, KeystorePerm},
 };
 use crate::{globals::get_keymint_device, id_rotation::IdRotationState};
 use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
-    Algorithm::Algorithm, AttestationKey::AttestationKey,
-    HardwareAuthenticatorType::HardwareAuthenticatorType, IKeyMintDevice::IKeyMintDevice,
-    KeyCreationResult::KeyCreationResult, KeyFormat::KeyFormat,
+    Algorithm::Algorithm, AttestationKey::AttestationKey, Certificate::Certificate, Digest::Digest,
+    EcCurve::EcCurve, HardwareAuthenticatorType::HardwareAuthenticatorType,
+    IKeyMintDevice::IKeyMintDevice, KeyCreationResult::KeyCreationResult, KeyFormat::KeyFormat,
     KeyMintHardwareInfo::KeyMintHardwareInfo, KeyParameter::KeyParameter,
-    KeyParameterValue::KeyParameterValue, SecurityLevel::SecurityLevel, Tag::Tag,
+    KeyParameterValue::KeyParameterValue, KeyPurpose::KeyPurpose, PaddingMode::PaddingMode,
+    SecurityLevel::SecurityLevel, Tag::Tag,
 };
 use android_hardware_security_keymint::binder::{BinderFeatures, Strong, ThreadState};
+use android_security_keyflags::aidl::android::security::keyflags::KeystorePrivateKeyFlag::KeystorePrivateKeyFlag;
 use android_system_keystore2::aidl::android::system::keystore2::{
     AuthenticatorSpec::AuthenticatorSpec, CreateOperationResponse::CreateOperationResponse,
     Domain::Domain, EphemeralStorageKeyResponse::EphemeralStorageKeyResponse,
@@ -58,18 +70,67 @@ use android_system_keystore2::aidl::android::system::keystore2::{
     KeyMetadata::KeyMetadata, KeyParameters::KeyParameters, ResponseCode::ResponseCode,
 };
 use anyhow::{anyhow, Context, Result};
+use keystore2_crypto::{
+    make_certificate_request_info, make_signed_certificate, make_signed_certificate_request,
+    make_tbs_certificate, public_key_from_private_key, CertDigest, CertSignatureAlgorithm,
+    CertificateRequestParams, SelfSignedCertParams,
+};
+use std::borrow::Cow;
 use std::convert::TryInto;
-use std::time::SystemTime;
+use std::ops::Deref;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+/// Keystore private key flag that may be passed to generateKey and importKey in addition to
+/// the flags defined by `KeyFlag`. Keys created with this flag live for the current boot only.
+/// They are deleted from the database and from KeyMint after the next reboot. The private flags
+/// are defined in `KeystorePrivateKeyFlag`, which documents the bits reserved for them.
+pub const KEY_FLAG_PER_BOOT: i32 = KeystorePrivateKeyFlag::PER_BOOT.0;
+
+/// Keystore private key flag that may be passed to generateKey and importKey in addition to
+/// the flags defined by `KeyFlag`. It must be passed to the StrongBox security level that
+/// callers with the `strongbox_fallback` permission obtain on devices without StrongBox. The
+/// key is then created in the TEE, and the TEE security level is recorded in the key metadata.
+pub const KEY_FLAG_STRONGBOX_FALLBACK: i32 = KeystorePrivateKeyFlag::STRONGBOX_FALLBACK.0;
+
+/// Keystore private key flag that may be passed to generateKey and importKey in addition to
+/// the flags defined by `KeyFlag`. Without it, an existing key with the same alias is replaced.
+/// With it, the call fails with `ResponseCode::INVALID_ARGUMENT` and the existing key is kept.
+/// The frozen `ResponseCode` has no distinct code for an existing alias, and adding one would
+/// require a new version of the keystore2 interface, so INVALID_ARGUMENT is used instead.
+pub const KEY_FLAG_NO_CLOBBER: i32 = KeystorePrivateKeyFlag::NO_CLOBBER.0;
+
+/// Maximal number of payloads signed by one call to `batch_sign`. All signatures are created
+/// serially on the binder thread of the caller, so this bounds the time the call takes.
+pub const MAX_BATCH_SIGN_PAYLOADS: usize = 64;
 
 /// Implementation of the IKeystoreSecurityLevel Interface.
 pub struct KeystoreSecurityLevel {
     security_level: SecurityLevel,
-    keymint: Strong<dyn IKeyMintDevice>,
     hw_info: KeyMintHardwareInfo,
     km_uuid: Uuid,
-    operation_db: OperationDb,
+    operation_db: Arc<OperationDb>,
     rem_prov_state: RemProvState,
     id_rotation_state: IdRotationState,
+    generate_key_limit: ConcurrencyLimit,
+}
+
+/// A key loaded for the creation of operations. See `KeystoreSecurityLevel::load_operation_key`.
+struct OperationKey<'a> {
+    blob: Cow<'a, [u8]>,
+    key_properties: Option<(i64, Vec<KsKeyParam>)>,
+    key_id_guard: Option<KeyIdGuard>,
+    blob_metadata: BlobMetaData,
+}
+
+/// A key generation request prepared on the binder thread of the caller. See
+/// `KeystoreSecurityLevel::prepare_key_generation`.
+pub(crate) struct KeyGenerationRequest {
+    key: KeyDescriptor,
+    caller_uid: u32,
+    attestation_key_info: Option<AttestationKeyInfo>,
+    params: Vec<KeyParameter>,
+    quota_exempt: bool,
 }
 
 // Blob of 32 zeroes used as empty masking key.
@@ -77,7 +138,7 @@ static ZERO_BLOB_32: &[u8] = &[0; 32];
 
 // Per RFC 5280 4.1.2.5, an undefined expiration (not-after) field should be set to GeneralizedTime
 // 999912312359559, which is 253402300799000 ms from Jan 1, 1970.
-const UNDEFINED_NOT_AFTER: i64 = 253402300799000i64;
+pub(crate) const UNDEFINED_NOT_AFTER: i64 = 253402300799000i64;
 
 impl KeystoreSecurityLevel {
     /// Creates a new security level instance wrapped in a
@@ -88,34 +149,106 @@ impl KeystoreSecurityLevel {
         security_level: SecurityLevel,
         id_rotation_state: IdRotationState,
     ) -> Result<(Strong<dyn IKeystoreSecurityLevel>, Uuid)> {
-        let (dev, hw_info, km_uuid) = get_keymint_device(&security_level)
+        let (_, hw_info, km_uuid) = get_keymint_device(&security_level)
             .context("In KeystoreSecurityLevel::new_native_binder.")?;
+        let operation_db = Arc::new(OperationDb::new(max_chunk_size(security_level)));
+        OPERATION_DBS
+            .lock()
+            .expect("In KeystoreSecurityLevel::new_native_binder: Trying to lock OPERATION_DBS.")
+            .push(Arc::downgrade(&operation_db));
+        let sec_level = Arc::new(Self {
+            security_level,
+            hw_info,
+            km_uuid,
+            operation_db,
+            rem_prov_state: RemProvState::new(security_level, km_uuid),
+            id_rotation_state,
+            generate_key_limit: ConcurrencyLimit::new(
+                "IKeystoreSecurityLevel::generateKey",
+                match security_level {
+                    SecurityLevel::STRONGBOX => &config::MAX_CONCURRENT_GENERATE_KEY_STRONGBOX,
+                    _ => &config::MAX_CONCURRENT_GENERATE_KEY_TEE,
+                },
+            ),
+        });
+        SECURITY_LEVELS
+            .lock()
+            .expect("In KeystoreSecurityLevel::new_native_binder: Trying to lock SECURITY_LEVELS.")
+            .insert(security_level, Arc::downgrade(&sec_level));
         let result = BnKeystoreSecurityLevel::new_binder(
-            Self {
-                security_level,
-                keymint: dev,
-                hw_info,
-                km_uuid,
-                operation_db: OperationDb::new(),
-                rem_prov_state: RemProvState::new(security_level, km_uuid),
-                id_rotation_state,
-            },
+            KeystoreSecurityLevelBinder(sec_level),
             BinderFeatures { set_requesting_sid: true, ..BinderFeatures::default() },
         );
         Ok((result, km_uuid))
     }
 
+    /// Returns the security level instance of the given security level, if it exists.
+    pub fn get(security_level: SecurityLevel) -> Option<Arc<Self>> {
+        SECURITY_LEVELS
+            .lock()
+            .expect("In KeystoreSecurityLevel::get: Trying to lock SECURITY_LEVELS.")
+            .get(&security_level)
+            .and_then(|sec_level| sec_level.upgrade())
+    }
+
+    /// Creates the binder object that stands in for the StrongBox security level on devices
+    /// without StrongBox. See `KEY_FLAG_STRONGBOX_FALLBACK`. The TEE security level must have
+    /// been created before.
+    pub fn new_strongbox_fallback_binder() -> Result<Strong<dyn IKeystoreSecurityLevel>> {
+        let sec_level = Self::get(SecurityLevel::TRUSTED_ENVIRONMENT)
+            .ok_or_else(Error::sys)
+            .context("In new_strongbox_fallback_binder: No TEE security level.")?;
+        Ok(BnKeystoreSecurityLevel::new_binder(
+            StrongBoxFallbackBinder(sec_level),
+            BinderFeatures { set_requesting_sid: true, ..BinderFeatures::default() },
+        ))
+    }
+
+    /// Returns the KeyMint instance of this security level. It is looked up on every call, so
+    /// that a connection that was re-established after the service died is picked up.
+    fn keymint(&self) -> Result<Strong<dyn IKeyMintDevice>> {
+        let (dev, _, km_uuid) = get_keymint_device(&self.security_level)
+            .context("In KeystoreSecurityLevel::keymint.")?;
+        if km_uuid != self.km_uuid {
+            return Err(Error::sys()).context(format!(
+                "In KeystoreSecurityLevel::keymint: Expected KeyMint instance {:?} but got {:?}.",
+                self.km_uuid, km_uuid
+            ));
+        }
+        Ok(dev)
+    }
+
+    /// Checks that the caller may use the Keystore private flags in `flags` with this security
+    /// level. Must be called on the binder thread of the caller.
+    pub(crate) fn check_flags(&self, flags: i32) -> Result<()> {
+        if flags & KEY_FLAG_STRONGBOX_FALLBACK != 0 {
+            if self.security_level != SecurityLevel::TRUSTED_ENVIRONMENT
+                || Self::get(SecurityLevel::STRONGBOX).is_some()
+            {
+                return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT)).context(
+                    "In check_flags: StrongBox fallback requires a device without StrongBox.",
+                );
+            }
+            check_keystore_permission(KeystorePerm::StrongBoxFallback)
+                .context("In check_flags: Checking StrongBox fallback permission.")?;
+        }
+        Ok(())
+    }
+
     fn watch_millis(&self, id: &'static str, millis: u64) -> Option<wd::WatchPoint> {
         let sec_level = self.security_level;
         wd::watch_millis_with(id, millis, move || format!("SecurityLevel {:?}", sec_level))
     }
 
+    /// Stores a newly created key. `public_key` is the DER-encoded SubjectPublicKeyInfo of the
+    /// key, if known. It is remembered if KeyMint returned no certificate to read it from.
     fn store_new_key(
         &self,
         key: KeyDescriptor,
         creation_result: KeyCreationResult,
         user_id: u32,
         flags: Option<i32>,
+        public_key: Option<Vec<u8>>,
     ) -> Result<KeyMetadata> {
         let KeyCreationResult {
             keyBlob: key_blob,
@@ -149,6 +282,20 @@ impl KeystoreSecurityLevel {
 
         let creation_date = DateTime::now().context("Trying to make creation time.")?;
 
+        let per_boot = flags.map_or(false, |flags| flags & KEY_FLAG_PER_BOOT != 0);
+        if per_boot && key.domain == Domain::BLOB {
+            return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+                .context("In store_new_key: Per-boot keys must be stored in the database.");
+        }
+        let strongbox_fallback =
+            flags.map_or(false, |flags| flags & KEY_FLAG_STRONGBOX_FALLBACK != 0);
+        let no_clobber = flags.map_or(false, |flags| flags & KEY_FLAG_NO_CLOBBER != 0);
+        let boot_id = if per_boot {
+            Some(get_boot_id().context("In store_new_key: Trying to get boot id.")?)
+        } else {
+            None
+        };
+
         let key = match key.domain {
             Domain::BLOB => KeyDescriptor {
                 domain: Domain::BLOB,
@@ -157,7 +304,7 @@ impl KeystoreSecurityLevel {
             },
             _ => DB
                 .with::<_, Result<KeyDescriptor>>(|db| {
-                    let mut db = db.borrow_mut();
+                    let mut db = db.borrow_mut()?;
 
                     let (key_blob, mut blob_metadata) = SUPER_KEY
                         .read()
@@ -175,6 +322,15 @@ impl KeystoreSecurityLevel {
 
                     let mut key_metadata = KeyMetaData::new();
                     key_metadata.add(KeyMetaEntry::CreationDate(creation_date));
+                    if let Some(boot_id) = boot_id {
+                        key_metadata.add(KeyMetaEntry::BootId(boot_id));
+                    }
+                    if strongbox_fallback {
+                        key_metadata.add(KeyMetaEntry::StrongBoxFallback(self.security_level.0));
+                    }
+                    if let (None, Some(public_key)) = (cert_info.cert(), &public_key) {
+                        key_metadata.add(KeyMetaEntry::SubjectPublicKeyInfo(public_key.clone()));
+                    }
                     blob_metadata.add(BlobMetaEntry::KmUuid(self.km_uuid));
 
                     let key_id = db
@@ -186,8 +342,21 @@ impl KeystoreSecurityLevel {
                             &cert_info,
                             &key_metadata,
                             &self.km_uuid,
+                            no_clobber,
                         )
                         .context("In store_new_key.")?;
+
+                    if let Err(e) = legacy_shadow::shadow_store_key(
+                        &mut db,
+                        &key,
+                        self.security_level,
+                        &key_blob,
+                        &blob_metadata,
+                        &cert_info,
+                    ) {
+                        log::error!("In store_new_key: Failed to update legacy copy: {:?}", e);
+                    }
+
                     Ok(KeyDescriptor {
                         domain: Domain::KEY_ID,
                         nspace: key_id.id(),
@@ -214,33 +383,183 @@ impl KeystoreSecurityLevel {
         forced: bool,
     ) -> Result<CreateOperationResponse> {
         let caller_uid = ThreadState::get_calling_uid();
-        // We use `scoping_blob` to extend the life cycle of the blob loaded from the database,
-        // so that we can use it by reference like the blob provided by the key descriptor.
-        // Otherwise, we would have to clone the blob from the key descriptor.
-        let scoping_blob: Vec<u8>;
-        let (km_blob, key_properties, key_id_guard, blob_metadata) = match key.domain {
+        OPERATION_RATE_LIMIT.check(caller_uid).context("In create_operation.")?;
+        let mut operation_key =
+            self.load_operation_key(key, forced, caller_uid).context("In create_operation.")?;
+        self.begin_operation(key, &mut operation_key, operation_parameters, forced, caller_uid)
+            .context("In create_operation.")
+    }
+
+    /// Signs each of the payloads with a separate operation. The key is loaded and the
+    /// permissions are checked only once. The authorizations are still enforced for each
+    /// operation, so that, e.g., usage limits are accounted for correctly.
+    pub fn batch_sign(
+        &self,
+        key: &KeyDescriptor,
+        operation_parameters: &[KeyParameter],
+        payloads: &[&[u8]],
+    ) -> Result<Vec<Vec<u8>>> {
+        let mut purposes = operation_parameters.iter().filter(|p| p.tag == Tag::PURPOSE);
+        let is_sign = matches!(
+            (purposes.next(), purposes.next()),
+            (
+                Some(KeyParameter { value: KeyParameterValue::KeyPurpose(KeyPurpose::SIGN), .. }),
+                None
+            )
+        );
+        if !is_sign {
+            return Err(Error::Km(ErrorCode::INVALID_ARGUMENT))
+                .context("In batch_sign: The only purpose must be SIGN.");
+        }
+
+        if payloads.len() > MAX_BATCH_SIGN_PAYLOADS {
+            return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT)).context(format!(
+                "In batch_sign: {} payloads exceed the maximum of {}.",
+                payloads.len(),
+                MAX_BATCH_SIGN_PAYLOADS
+            ));
+        }
+
+        let caller_uid = ThreadState::get_calling_uid();
+        // Every payload begins an operation, so the batch is charged like as many calls to
+        // createOperation.
+        OPERATION_RATE_LIMIT
+            .check_n(caller_uid, payloads.len() as u32)
+            .context("In batch_sign.")?;
+        let mut operation_key =
+            self.load_operation_key(key, false, caller_uid).context("In batch_sign.")?;
+        payloads
+            .iter()
+            .map(|&payload| {
+                let response = self
+                    .begin_operation(
+                        key,
+                        &mut operation_key,
+                        operation_parameters,
+                        false,
+                        caller_uid,
+                    )
+                    .context("In batch_sign.")?;
+                let operation = response
+                    .iOperation
+                    .ok_or_else(Error::sys)
+                    .context("In batch_sign: Operation created without operation binder.")?;
+                Ok(KeystoreOperation::from_binder(&operation)
+                    .context("In batch_sign.")?
+                    .finish_one_shot(Some(payload), None)
+                    .context("In batch_sign: Trying to finish operation.")?
+                    .unwrap_or_default())
+            })
+            .collect()
+    }
+
+    /// Builds a PKCS#10 certification request for the public key of `key` and signs it with the
+    /// key in a keystore initiated operation, so that the private key never leaves KeyMint.
+    /// The signature algorithm is chosen based on the authorizations of the key. `subject` is
+    /// the DER-encoded subject name and `extensions` an optional DER-encoded
+    /// `SEQUENCE OF Extension` to be requested. The caller needs the use and the get_info
+    /// permission for the key.
+    pub fn get_certificate_request(
+        &self,
+        key: &KeyDescriptor,
+        subject: &[u8],
+        extensions: Option<&[u8]>,
+    ) -> Result<Vec<u8>> {
+        if key.domain == Domain::BLOB {
+            return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+                .context("In get_certificate_request: Domain::BLOB keys are not supported.");
+        }
+        let caller_uid = ThreadState::get_calling_uid();
+        // The key id guard of the certificate entry must be dropped before the operation key is
+        // loaded, because the latter locks the same key id again.
+        let cert = {
+            let (_key_id_guard, mut key_entry) = DB
+                .with(|db| {
+                    db.borrow_mut()?.load_key_entry(
+                        key,
+                        KeyType::Client,
+                        KeyEntryLoadBits::PUBLIC,
+                        caller_uid,
+                        |k, av| check_key_permission(KeyPerm::GetInfo, k, &av),
+                    )
+                })
+                .context("In get_certificate_request: Failed to load certificate.")?;
+            key_entry
+                .take_cert()
+                .ok_or(Error::Rc(ResponseCode::KEY_NOT_FOUND))
+                .context("In get_certificate_request: Key has no certificate.")?
+        };
+
+        let mut operation_key = self
+            .load_operation_key(key, false, caller_uid)
+            .context("In get_certificate_request.")?;
+
+        let key_params: Vec<KeyParameter> = operation_key
+            .key_properties
+            .as_ref()
+            .map(|(_, params)| {
+                params.iter().map(|p| p.key_parameter_value().clone().into()).collect()
+            })
+            .unwrap_or_default();
+        let (algorithm, op_params) = Self::cert_signing_algorithm(&key_params)
+            .ok_or(Error::Km(ErrorCode::INCOMPATIBLE_PURPOSE))
+            .context("In get_certificate_request: Key cannot sign a certification request.")?;
+
+        let request_params =
+            CertificateRequestParams { certificate: &cert, subject, extensions, algorithm };
+        let request_info = make_certificate_request_info(&request_params)
+            .map_err(|_| Error::Rc(ResponseCode::INVALID_ARGUMENT))
+            .context("In get_certificate_request: Failed to make request info.")?;
+
+        let response = self
+            .begin_operation(key, &mut operation_key, &op_params, false, caller_uid)
+            .context("In get_certificate_request.")?;
+        let operation = response
+            .iOperation
+            .ok_or_else(Error::sys)
+            .context("In get_certificate_request: Operation created without operation binder.")?;
+        let signature = KeystoreOperation::from_binder(&operation)
+            .context("In get_certificate_request.")?
+            .finish_one_shot(Some(&request_info), None)
+            .context("In get_certificate_request: Failed to sign request info.")?
+            .ok_or_else(Error::sys)
+            .context("In get_certificate_request: Signing operation returned no signature.")?;
+
+        make_signed_certificate_request(&request_params, &signature)
+            .context("In get_certificate_request: Failed to assemble certification request.")
+    }
+
+    /// Loads the key blob and the key properties for a new operation and checks that the
+    /// caller may use the key.
+    fn load_operation_key<'a>(
+        &self,
+        key: &'a KeyDescriptor,
+        forced: bool,
+        caller_uid: u32,
+    ) -> Result<OperationKey<'a>> {
+        match key.domain {
             Domain::BLOB => {
                 check_key_permission(KeyPerm::Use, key, &None)
-                    .context("In create_operation: checking use permission for Domain::BLOB.")?;
+                    .context("In load_operation_key: checking use permission for Domain::BLOB.")?;
                 if forced {
                     check_key_permission(KeyPerm::ReqForcedOp, key, &None).context(
-                        "In create_operation: checking forced permission for Domain::BLOB.",
+                        "In load_operation_key: checking forced permission for Domain::BLOB.",
                     )?;
                 }
-                (
-                    match &key.blob {
-                        Some(blob) => blob,
+                Ok(OperationKey {
+                    blob: match &key.blob {
+                        Some(blob) => Cow::Borrowed(blob),
                         None => {
                             return Err(Error::sys()).context(concat!(
-                                "In create_operation: Key blob must be specified when",
+                                "In load_operation_key: Key blob must be specified when",
                                 " using Domain::BLOB."
                             ))
                         }
                     },
-                    None,
-                    None,
-                    BlobMetaData::new(),
-                )
+                    key_properties: None,
+                    key_id_guard: None,
+                    blob_metadata: BlobMetaData::new(),
+                })
             }
             _ => {
                 let super_key = SUPER_KEY
@@ -250,7 +569,7 @@ impl KeystoreSecurityLevel {
                 let (key_id_guard, mut key_entry) = DB
                     .with::<_, Result<(KeyIdGuard, KeyEntry)>>(|db| {
                         LEGACY_IMPORTER.with_try_import(key, caller_uid, super_key, || {
-                            db.borrow_mut().load_key_entry(
+                            db.borrow_mut()?.load_key_entry(
                                 key,
                                 KeyType::Client,
                                 KeyEntryLoadBits::KM,
@@ -265,31 +584,43 @@ impl KeystoreSecurityLevel {
                             )
                         })
                     })
-                    .context("In create_operation: Failed to load key blob.")?;
+                    .context("In load_operation_key: Failed to load key blob.")?;
 
                 let (blob, blob_metadata) =
                     key_entry.take_key_blob_info().ok_or_else(Error::sys).context(concat!(
-                        "In create_operation: Successfully loaded key entry, ",
+                        "In load_operation_key: Successfully loaded key entry, ",
                         "but KM blob was missing."
                     ))?;
-                scoping_blob = blob;
 
-                (
-                    &scoping_blob,
-                    Some((key_id_guard.id(), key_entry.into_key_parameters())),
-                    Some(key_id_guard),
+                Ok(OperationKey {
+                    blob: Cow::Owned(blob),
+                    key_properties: Some((key_id_guard.id(), key_entry.into_key_parameters())),
+                    key_id_guard: Some(key_id_guard),
                     blob_metadata,
-                )
+                })
             }
-        };
+        }
+    }
 
+    /// Begins a new operation with a key loaded by `load_operation_key`. The key id guard of
+    /// `operation_key` is consumed by the first operation, which upgrades the key blob if
+    /// required.
+    fn begin_operation(
+        &self,
+        key: &KeyDescriptor,
+        operation_key: &mut OperationKey,
+        operation_parameters: &[KeyParameter],
+        forced: bool,
+        caller_uid: u32,
+    ) -> Result<CreateOperationResponse> {
+        let key_properties = operation_key.key_properties.as_ref();
         let purpose = operation_parameters.iter().find(|p| p.tag == Tag::PURPOSE).map_or(
             Err(Error::Km(ErrorCode::INVALID_ARGUMENT))
-                .context("In create_operation: No operation purpose specified."),
+                .context("In begin_operation: No operation purpose specified."),
             |kp| match kp.value {
                 KeyParameterValue::KeyPurpose(p) => Ok(p),
                 _ => Err(Error::Km(ErrorCode::INVALID_ARGUMENT))
-                    .context("In create_operation: Malformed KeyParameter."),
+                    .context("In begin_operation: Malformed KeyParameter."),
             },
         )?;
 
@@ -302,59 +633,103 @@ impl KeystoreSecurityLevel {
         let (immediate_hat, mut auth_info) = ENFORCEMENTS
             .authorize_create(
                 purpose,
-                key_properties.as_ref(),
+                key_properties,
                 operation_parameters.as_ref(),
                 self.hw_info.timestampTokenRequired,
             )
-            .context("In create_operation.")?;
+            .context("In begin_operation.")?;
 
         let km_blob = SUPER_KEY
             .read()
             .unwrap()
-            .unwrap_key_if_required(&blob_metadata, km_blob)
-            .context("In create_operation. Failed to handle super encryption.")?;
+            .unwrap_key_if_required(&operation_key.blob_metadata, &operation_key.blob)
+            .context("In begin_operation. Failed to handle super encryption.")?;
 
+        // Keep regular operations from taking the slots freed up for a forced operation
+        // until it is registered with the operation database.
+        let _forced_guard = if forced { Some(self.operation_db.begin_forced()) } else { None };
+
+        let km_dev = self.keymint().context("In begin_operation.")?;
         let (begin_result, upgraded_blob) = self
             .upgrade_keyblob_if_required_with(
-                &*self.keymint,
-                key_id_guard,
+                &*km_dev,
+                operation_key.key_id_guard.take(),
                 &km_blob,
-                blob_metadata.km_uuid().copied(),
+                operation_key.blob_metadata.km_uuid().copied(),
                 operation_parameters,
-                |blob| loop {
-                    match map_km_error({
-                        let _wp = self.watch_millis(
-                            "In KeystoreSecurityLevel::create_operation: calling begin",
-                            500,
-                        );
-                        self.keymint.begin(
-                            purpose,
-                            blob,
-                            operation_parameters,
-                            immediate_hat.as_ref(),
-                        )
-                    }) {
-                        Err(Error::Km(ErrorCode::TOO_MANY_OPERATIONS)) => {
-                            self.operation_db.prune(caller_uid, forced)?;
-                            continue;
-                        }
-                        v @ Err(Error::Km(ErrorCode::INVALID_KEY_BLOB)) => {
-                            if let Some((key_id, _)) = key_properties {
-                                if let Ok(Some(key)) =
-                                    DB.with(|db| db.borrow_mut().load_key_descriptor(key_id))
-                                {
-                                    log_key_integrity_violation(&key);
-                                } else {
-                                    log::error!("Failed to load key descriptor for audit log");
+                |blob| {
+                    let mut queued = None;
+                    loop {
+                        match map_km_error({
+                            let _wp = self.watch_millis(
+                                "In KeystoreSecurityLevel::begin_operation: calling begin",
+                                500,
+                            );
+                            let _trace = trace::begin("KeyMint::begin");
+                            km_dev.begin(
+                                purpose,
+                                blob,
+                                operation_parameters,
+                                immediate_hat.as_ref(),
+                            )
+                        }) {
+                            Err(Error::Km(ErrorCode::TOO_MANY_OPERATIONS)) => {
+                                match self.operation_db.prune(caller_uid, forced) {
+                                    // StrongBox supports very few concurrent operations. Rather
+                                    // than failing right away, wait briefly for a slot to free up.
+                                    Err(e @ Error::Rc(ResponseCode::BACKEND_BUSY))
+                                        if self.security_level == SecurityLevel::STRONGBOX =>
+                                    {
+                                        let queued = queued.get_or_insert_with(|| {
+                                            self.operation_db.queue_begin(
+                                                Duration::from_millis(
+                                                    config::STRONGBOX_BEGIN_QUEUE_MILLIS.get(),
+                                                ),
+                                                config::STRONGBOX_BEGIN_QUEUE_LENGTH.get(),
+                                            )
+                                        });
+                                        match queued {
+                                            Some(queued) if queued.wait() => continue,
+                                            _ => return Err(e),
+                                        }
+                                    }
+                                    r => r?,
+                                }
+                                continue;
+                            }
+                            v @ Err(Error::Km(ErrorCode::INVALID_KEY_BLOB)) => {
+                                if let Some((key_id, _)) = key_properties {
+                                    if let Ok(Some(key)) =
+                                        DB.with(|db| db.borrow_mut()?.load_key_descriptor(*key_id))
+                                    {
+                                        log_key_integrity_violation(&key);
+                                    } else {
+                                        log::error!("Failed to load key descriptor for audit log");
+                                    }
                                 }
+                                return v;
                             }
-                            return v;
+                            v => return v,
                         }
-                        v => return v,
                     }
                 },
             )
-            .context("In create_operation: Failed to begin operation.")?;
+            .context("In begin_operation: Failed to begin operation.")?;
+
+        // Further operations with the same operation key, e.g., in batch_sign, must not begin
+        // with the superseded blob. The upgraded blob is not super encrypted.
+        if let Some(upgraded_blob) = &upgraded_blob {
+            let mut blob_metadata = BlobMetaData::new();
+            if let Some(km_uuid) = operation_key.blob_metadata.km_uuid() {
+                blob_metadata.add(BlobMetaEntry::KmUuid(*km_uuid));
+            }
+            operation_key.blob = Cow::Owned(upgraded_blob.clone());
+            operation_key.blob_metadata = blob_metadata;
+        }
+
+        if let Some((key_id, _)) = key_properties {
+            KEY_USAGE.record_use(*key_id);
+        }
 
         let operation_challenge = auth_info.finalize_create_authorization(begin_result.challenge);
 
@@ -370,7 +745,7 @@ impl KeystoreSecurityLevel {
             ),
             None => {
                 return Err(Error::sys()).context(concat!(
-                    "In create_operation: Begin operation returned successfully, ",
+                    "In begin_operation: Begin operation returned successfully, ",
                     "but did not return a valid operation."
                 ))
             }
@@ -380,7 +755,7 @@ impl KeystoreSecurityLevel {
             KeystoreOperation::new_native_binder(operation)
                 .as_binder()
                 .into_interface()
-                .context("In create_operation: Failed to create IKeystoreOperation.")?;
+                .context("In begin_operation: Failed to create IKeystoreOperation.")?;
 
         Ok(CreateOperationResponse {
             iOperation: Some(op_binder),
@@ -413,6 +788,15 @@ impl KeystoreSecurityLevel {
             );
         }
 
+        // RESET_SINCE_ID_ROTATION is derived from the id rotation state below. Callers must not
+        // be able to force a unique id rotation by specifying it themselves.
+        if params.iter().any(|kp| kp.tag == Tag::RESET_SINCE_ID_ROTATION) {
+            return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT)).context(
+                "In KeystoreSecurityLevel::add_required_parameters: \
+                Specifying Tag::RESET_SINCE_ID_ROTATION is not allowed.",
+            );
+        }
+
         // Add CREATION_DATETIME only if the backend version Keymint V1 (100) or newer.
         if self.hw_info.versionNumber >= 100 {
             result.push(KeyParameter {
@@ -479,6 +863,11 @@ impl KeystoreSecurityLevel {
                 "Caller does not have the permission to attest device identifiers."
             ))?;
         }
+        check_device_id_attestation_params(params)
+            .context("In add_required_parameters: Cannot attest device identifiers.")?;
+
+        // Curve 25519 keys are either Ed25519 signing keys or X25519 key agreement keys.
+        check_curve_25519_purposes(params).context("In add_required_parameters.")?;
 
         // If we are generating/importing an asymmetric key, we need to make sure
         // that NOT_BEFORE and NOT_AFTER are present.
@@ -503,6 +892,76 @@ impl KeystoreSecurityLevel {
         Ok(result)
     }
 
+    /// Calls `create` with the attestation key described by `attestation_key_info`, if any,
+    /// upgrading the attestation key blob first if necessary. Certificates of the attestation
+    /// key are appended to the returned certificate chain, so that the stored chain of the new
+    /// key leads up to the root of the attestation key's chain.
+    fn create_key_with_attestation<F>(
+        &self,
+        attestation_key_info: Option<AttestationKeyInfo>,
+        params: &[KeyParameter],
+        create: F,
+    ) -> Result<KeyCreationResult>
+    where
+        F: Fn(Option<&AttestationKey>) -> Result<KeyCreationResult, Error>,
+    {
+        let km_dev = self.keymint().context("In create_key_with_attestation.")?;
+        match attestation_key_info {
+            Some(AttestationKeyInfo::UserGenerated {
+                key_id_guard,
+                blob,
+                blob_metadata,
+                issuer_subject,
+                attestation_certs,
+            }) => self
+                .upgrade_keyblob_if_required_with(
+                    &*km_dev,
+                    Some(key_id_guard),
+                    &KeyBlob::Ref(&blob),
+                    blob_metadata.km_uuid().copied(),
+                    params,
+                    |blob| {
+                        create(Some(&AttestationKey {
+                            keyBlob: blob.to_vec(),
+                            attestKeyParams: vec![],
+                            issuerSubjectName: issuer_subject.clone(),
+                        }))
+                    },
+                )
+                .context("Using user generated attestation key.")
+                .map(|(mut result, _)| {
+                    result.certificateChain.push(attestation_certs);
+                    result
+                }),
+            Some(AttestationKeyInfo::RemoteProvisioned {
+                key_id_guard,
+                attestation_key,
+                attestation_certs,
+            }) => self
+                .upgrade_keyblob_if_required_with(
+                    &*km_dev,
+                    Some(key_id_guard),
+                    &KeyBlob::Ref(&attestation_key.keyBlob),
+                    Some(self.rem_prov_state.get_uuid()),
+                    &[],
+                    |blob| {
+                        create(Some(&AttestationKey {
+                            keyBlob: blob.to_vec(),
+                            attestKeyParams: vec![],
+                            issuerSubjectName: attestation_key.issuerSubjectName.clone(),
+                        }))
+                    },
+                )
+                .context("Using remote provisioned attestation key.")
+                .map(|(mut result, _)| {
+                    result.certificateChain.push(attestation_certs);
+                    result
+                }),
+            None => create(None).context("Without explicit attestation key."),
+        }
+        .context("In create_key_with_attestation.")
+    }
+
     fn generate_key(
         &self,
         key: &KeyDescriptor,
@@ -511,9 +970,31 @@ impl KeystoreSecurityLevel {
         flags: i32,
         _entropy: &[u8],
     ) -> Result<KeyMetadata> {
+        KEY_CREATION_RATE_LIMIT
+            .check(ThreadState::get_calling_uid())
+            .context("In generate_key.")?;
+        self.check_flags(flags).context("In generate_key.")?;
+        let request = self
+            .prepare_key_generation(key, attest_key_descriptor, params)
+            .context("In generate_key.")?;
+        // Slow key generations must not occupy all binder threads.
+        let _slot = self.generate_key_limit.try_acquire().context("In generate_key.")?;
+        self.complete_key_generation(request, flags).context("In generate_key.")
+    }
+
+    /// Performs the parts of a key generation that depend on the calling identity, i.e., the
+    /// permission checks, the key quota, the selection of the attestation key, and the
+    /// parameters added on behalf of the caller. Must be called on the binder thread of the
+    /// caller. The returned request can be completed on any thread.
+    pub(crate) fn prepare_key_generation(
+        &self,
+        key: &KeyDescriptor,
+        attest_key_descriptor: Option<&KeyDescriptor>,
+        params: &[KeyParameter],
+    ) -> Result<KeyGenerationRequest> {
         if key.domain != Domain::BLOB && key.alias.is_none() {
             return Err(error::Error::Km(ErrorCode::INVALID_ARGUMENT))
-                .context("In generate_key: Alias must be specified");
+                .context("In prepare_key_generation: Alias must be specified");
         }
         let caller_uid = ThreadState::get_calling_uid();
 
@@ -529,7 +1010,9 @@ impl KeystoreSecurityLevel {
 
         // generate_key requires the rebind permission.
         // Must return on error for security reasons.
-        check_key_permission(KeyPerm::Rebind, &key, &None).context("In generate_key.")?;
+        check_key_permission(KeyPerm::Rebind, &key, &None).context("In prepare_key_generation.")?;
+        let quota_exempt = Self::check_key_quota(&key, Self::is_exempt_from_key_quota)
+            .context("In prepare_key_generation.")?;
 
         let attestation_key_info = match (key.domain, attest_key_descriptor) {
             (Domain::BLOB, _) => None,
@@ -541,112 +1024,308 @@ impl KeystoreSecurityLevel {
                         attest_key_descriptor,
                         params,
                         &self.rem_prov_state,
-                        &mut db.borrow_mut(),
+                        &mut db.borrow_mut()?,
                     )
                 })
-                .context("In generate_key: Trying to get an attestation key")?,
+                .context("In prepare_key_generation: Trying to get an attestation key")?,
         };
         let params = self
             .add_required_parameters(caller_uid, params, &key)
-            .context("In generate_key: Trying to get aaid.")?;
+            .context("In prepare_key_generation: Trying to get aaid.")?;
+        Ok(KeyGenerationRequest { key, caller_uid, attestation_key_info, params, quota_exempt })
+    }
 
-        let creation_result = match attestation_key_info {
-            Some(AttestationKeyInfo::UserGenerated {
-                key_id_guard,
-                blob,
-                blob_metadata,
-                issuer_subject,
-            }) => self
-                .upgrade_keyblob_if_required_with(
-                    &*self.keymint,
-                    Some(key_id_guard),
-                    &KeyBlob::Ref(&blob),
-                    blob_metadata.km_uuid().copied(),
-                    &params,
-                    |blob| {
-                        let attest_key = Some(AttestationKey {
-                            keyBlob: blob.to_vec(),
-                            attestKeyParams: vec![],
-                            issuerSubjectName: issuer_subject.clone(),
-                        });
-                        map_km_error({
-                            let _wp = self.watch_millis(
-                                concat!(
-                                    "In KeystoreSecurityLevel::generate_key (UserGenerated): ",
-                                    "calling generate_key."
-                                ),
-                                5000, // Generate can take a little longer.
-                            );
-                            self.keymint.generateKey(&params, attest_key.as_ref())
-                        })
-                    },
-                )
-                .context("In generate_key: Using user generated attestation key.")
-                .map(|(result, _)| result),
-            Some(AttestationKeyInfo::RemoteProvisioned {
-                key_id_guard,
-                attestation_key,
-                attestation_certs,
-            }) => self
-                .upgrade_keyblob_if_required_with(
-                    &*self.keymint,
-                    Some(key_id_guard),
-                    &KeyBlob::Ref(&attestation_key.keyBlob),
-                    Some(self.rem_prov_state.get_uuid()),
-                    &[],
-                    |blob| {
-                        map_km_error({
-                            let _wp = self.watch_millis(
-                                concat!(
-                                    "In KeystoreSecurityLevel::generate_key (RemoteProvisioned): ",
-                                    "calling generate_key.",
-                                ),
-                                5000, // Generate can take a little longer.
-                            );
-                            let dynamic_attest_key = Some(AttestationKey {
-                                keyBlob: blob.to_vec(),
-                                attestKeyParams: vec![],
-                                issuerSubjectName: attestation_key.issuerSubjectName.clone(),
-                            });
-                            self.keymint.generateKey(&params, dynamic_attest_key.as_ref())
-                        })
-                    },
-                )
-                .context("While generating Key with remote provisioned attestation key.")
-                .map(|(mut result, _)| {
-                    result.certificateChain.push(attestation_certs);
-                    result
-                }),
-            None => map_km_error({
-                let _wp = self.watch_millis(
-                    concat!(
-                        "In KeystoreSecurityLevel::generate_key (No attestation): ",
-                        "calling generate_key.",
-                    ),
-                    5000, // Generate can take a little longer.
-                );
-                self.keymint.generateKey(&params, None)
+    /// Generates the key of a prepared request in KeyMint, including its attestation, and
+    /// stores it. The key quota is checked again, because other keys may have been stored
+    /// since the request was prepared. The exemption from the quota cannot be checked off the
+    /// binder thread of the caller, so only a caller found exempt while preparing is exempt.
+    pub(crate) fn complete_key_generation(
+        &self,
+        request: KeyGenerationRequest,
+        flags: i32,
+    ) -> Result<KeyMetadata> {
+        let KeyGenerationRequest { key, caller_uid, attestation_key_info, params, quota_exempt } =
+            request;
+        Self::check_no_clobber(&key, flags).context("In complete_key_generation.")?;
+        Self::check_key_quota(&key, || quota_exempt).context("In complete_key_generation.")?;
+        let km_dev = self.keymint().context("In complete_key_generation.")?;
+        let creation_result = self
+            .create_key_with_attestation(attestation_key_info, &params, |attest_key| {
+                map_km_error({
+                    let _wp = self.watch_millis(
+                        "In KeystoreSecurityLevel::generate_key: calling generate_key.",
+                        5000, // Generate can take a little longer.
+                    );
+                    let _trace = trace::begin("KeyMint::generateKey");
+                    km_dev.generateKey(&params, attest_key)
+                })
             })
-            .context("While generating Key without explicit attestation key."),
-        }
-        .context("In generate_key.")?;
+            .map_err(|e| map_device_id_attestation_error(&params, e))
+            .context("In complete_key_generation.")?;
 
         let user_id = uid_to_android_user(caller_uid);
-        self.store_new_key(key, creation_result, user_id, Some(flags)).context("In generate_key.")
+        self.store_new_key(key, creation_result, user_id, Some(flags), None)
+            .context("In complete_key_generation.")
+    }
+
+    /// Fails with `ResponseCode::INVALID_ARGUMENT` if `flags` contain `KEY_FLAG_NO_CLOBBER` and
+    /// the alias of `key` is already taken. See `KEY_FLAG_NO_CLOBBER` for why no distinct code
+    /// is used. This spares KeyMint the work of creating a key that would be discarded.
+    /// `KeystoreDB::store_new_key` repeats the check atomically.
+    fn check_no_clobber(key: &KeyDescriptor, flags: i32) -> Result<()> {
+        if flags & KEY_FLAG_NO_CLOBBER == 0 {
+            return Ok(());
+        }
+        let alias = match (key.domain, &key.alias) {
+            (Domain::APP, Some(alias)) | (Domain::SELINUX, Some(alias)) => alias,
+            _ => return Ok(()),
+        };
+        let exists = DB
+            .with(|db| db.borrow_mut()?.key_exists(key.domain, key.nspace, alias, KeyType::Client))
+            .context("In check_no_clobber: Trying to look up the alias.")?;
+        if exists {
+            return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+                .context(format!("In check_no_clobber: Alias {:?} is already taken.", alias));
+        }
+        Ok(())
+    }
+
+    /// Checks that the namespace of `key` can hold another key. The maximal number of keys per
+    /// app and per SELinux namespace can be tuned with `config::MAX_KEYS_PER_UID` and
+    /// `config::MAX_KEYS_PER_NAMESPACE` respectively, where 0 means unlimited. Replacing an
+    /// existing key is always allowed, and callers for which `is_exempt` returns true are not
+    /// limited at all. `is_exempt` is only called if the namespace is full. Returns true if the
+    /// caller was found to be exempt.
+    /// An exceeded quota is reported as `ResponseCode::INVALID_ARGUMENT`. The frozen
+    /// `ResponseCode` has no code for it, and the closest existing code, `OUT_OF_KEYS`, means that
+    /// no remotely provisioned attestation keys are left, which would mislead callers.
+    fn check_key_quota<F>(key: &KeyDescriptor, is_exempt: F) -> Result<bool>
+    where
+        F: FnOnce() -> bool,
+    {
+        let max_keys = match key.domain {
+            Domain::APP => config::MAX_KEYS_PER_UID.get(),
+            Domain::SELINUX => config::MAX_KEYS_PER_NAMESPACE.get(),
+            _ => return Ok(false),
+        };
+        if max_keys == 0 {
+            return Ok(false);
+        }
+
+        let over_quota = DB
+            .with::<_, Result<bool>>(|db| {
+                let mut db = db.borrow_mut()?;
+                let count = db.count_keys(key.domain, key.nspace, KeyType::Client)?;
+                if count < max_keys {
+                    return Ok(false);
+                }
+                match &key.alias {
+                    Some(alias) => {
+                        Ok(!db.key_exists(key.domain, key.nspace, alias, KeyType::Client)?)
+                    }
+                    None => Ok(true),
+                }
+            })
+            .context("In check_key_quota: Trying to count keys.")?;
+        if !over_quota {
+            return Ok(false);
+        }
+        if !is_exempt() {
+            return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT)).context(format!(
+                "In check_key_quota: {:?} {} already holds {} keys.",
+                key.domain, key.nspace, max_keys
+            ));
+        }
+        Ok(true)
+    }
+
+    /// Returns true if the calling identity has the `exempt_from_key_quota` permission. Must
+    /// be called on the binder thread of the caller.
+    fn is_exempt_from_key_quota() -> bool {
+        check_keystore_permission(KeystorePerm::ExemptFromKeyQuota).is_ok()
+    }
+
+    /// Selects the algorithm with which a key described by `params` can sign a certificate or
+    /// certification request, along with the parameters of the signing operation. Returns None
+    /// if the key cannot sign with any algorithm suitable for certificates.
+    fn cert_signing_algorithm(
+        params: &[KeyParameter],
+    ) -> Option<(CertSignatureAlgorithm, Vec<KeyParameter>)> {
+        let has_param = |tag: Tag, value: KeyParameterValue| {
+            params.iter().any(|kp| kp.tag == tag && kp.value == value)
+        };
+        if !has_param(Tag::PURPOSE, KeyParameterValue::KeyPurpose(KeyPurpose::SIGN)) {
+            return None;
+        }
+
+        let mut op_params = vec![KeyParameter {
+            tag: Tag::PURPOSE,
+            value: KeyParameterValue::KeyPurpose(KeyPurpose::SIGN),
+        }];
+        let mut add_param = |tag: Tag, value: KeyParameterValue| {
+            op_params.push(KeyParameter { tag, value });
+        };
+
+        if has_param(Tag::EC_CURVE, KeyParameterValue::EcCurve(EcCurve::CURVE_25519)) {
+            add_param(Tag::DIGEST, KeyParameterValue::Digest(Digest::NONE));
+            return Some((CertSignatureAlgorithm::Ed25519, op_params));
+        }
+
+        // Prefer the digests that are most commonly supported by certificate consumers.
+        let (digest, cert_digest) = [
+            (Digest::SHA_2_256, CertDigest::Sha256),
+            (Digest::SHA_2_512, CertDigest::Sha512),
+            (Digest::SHA_2_384, CertDigest::Sha384),
+            (Digest::SHA_2_224, CertDigest::Sha224),
+            (Digest::SHA1, CertDigest::Sha1),
+        ]
+        .iter()
+        .copied()
+        .find(|(digest, _)| has_param(Tag::DIGEST, KeyParameterValue::Digest(*digest)))?;
+        add_param(Tag::DIGEST, KeyParameterValue::Digest(digest));
+
+        let is_rsa = has_param(Tag::ALGORITHM, KeyParameterValue::Algorithm(Algorithm::RSA));
+        let pss = KeyParameterValue::PaddingMode(PaddingMode::RSA_PSS);
+        let pkcs1 = KeyParameterValue::PaddingMode(PaddingMode::RSA_PKCS1_1_5_SIGN);
+        let algorithm = if has_param(Tag::ALGORITHM, KeyParameterValue::Algorithm(Algorithm::EC)) {
+            CertSignatureAlgorithm::Ecdsa(cert_digest)
+        } else if is_rsa && has_param(Tag::PADDING, pss.clone()) {
+            add_param(Tag::PADDING, pss);
+            CertSignatureAlgorithm::RsaPss(cert_digest)
+        } else if is_rsa && has_param(Tag::PADDING, pkcs1.clone()) {
+            add_param(Tag::PADDING, pkcs1);
+            CertSignatureAlgorithm::RsaPkcs1(cert_digest)
+        } else {
+            return None;
+        };
+        Some((algorithm, op_params))
+    }
+
+    /// Makes a self signed certificate for the freshly imported key `key_blob`, whose PKCS#8
+    /// encoded private key is `key_data`. The certificate is signed by the imported key in a
+    /// keystore initiated operation and honors the certificate subject, serial, and validity
+    /// given in `params`. Returns None if the key cannot sign its own certificate.
+    /// The operation bypasses the enforcements and the operation database, because the key is
+    /// not stored yet. So keys with usage limits or validity periods, which the signature would
+    /// count against or which may not be valid yet, do not get a certificate. If KeyMint has no
+    /// operation slot left, an error is returned, and the key is stored without certificate.
+    fn make_self_signed_cert(
+        &self,
+        params: &[KeyParameter],
+        key_blob: &[u8],
+        key_data: &[u8],
+    ) -> Result<Option<Vec<u8>>> {
+        // The key must be able to sign without user interaction.
+        if !params.iter().any(|kp| kp.tag == Tag::NO_AUTH_REQUIRED) {
+            return Ok(None);
+        }
+        let restricted = params.iter().any(|kp| {
+            matches!(
+                kp.tag,
+                Tag::USAGE_COUNT_LIMIT
+                    | Tag::MAX_USES_PER_BOOT
+                    | Tag::MIN_SECONDS_BETWEEN_OPS
+                    | Tag::ACTIVE_DATETIME
+                    | Tag::ORIGINATION_EXPIRE_DATETIME
+                    | Tag::USAGE_EXPIRE_DATETIME
+                    | Tag::UNLOCKED_DEVICE_REQUIRED
+                    | Tag::TRUSTED_CONFIRMATION_REQUIRED
+                    | Tag::TRUSTED_USER_PRESENCE_REQUIRED
+            )
+        });
+        if restricted {
+            return Ok(None);
+        }
+        let (algorithm, op_params) = match Self::cert_signing_algorithm(params) {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+        let blob_param = |tag: Tag| {
+            params.iter().find_map(|kp| match (&kp.value, kp.tag == tag) {
+                (KeyParameterValue::Blob(b), true) => Some(b.as_slice()),
+                _ => None,
+            })
+        };
+        let date_param = |tag: Tag| {
+            params.iter().find_map(|kp| match (&kp.value, kp.tag == tag) {
+                (KeyParameterValue::DateTime(d), true) => Some(*d),
+                _ => None,
+            })
+        };
+        let cert_params = SelfSignedCertParams {
+            private_key: key_data,
+            subject: blob_param(Tag::CERTIFICATE_SUBJECT),
+            serial: blob_param(Tag::CERTIFICATE_SERIAL),
+            not_before: date_param(Tag::CERTIFICATE_NOT_BEFORE).unwrap_or(0),
+            not_after: date_param(Tag::CERTIFICATE_NOT_AFTER).unwrap_or(UNDEFINED_NOT_AFTER),
+            algorithm,
+        };
+
+        let tbs = make_tbs_certificate(&cert_params)
+            .context("In make_self_signed_cert: Failed to make to-be-signed certificate.")?;
+
+        let km_dev = self.keymint().context("In make_self_signed_cert.")?;
+        let begin_result = map_km_error({
+            let _wp = self.watch_millis("In make_self_signed_cert: calling begin.", 500);
+            km_dev.begin(KeyPurpose::SIGN, key_blob, &op_params, None)
+        })
+        .context("In make_self_signed_cert: Failed to begin signing operation.")?;
+        let operation = begin_result
+            .operation
+            .ok_or_else(Error::sys)
+            .context("In make_self_signed_cert: Operation missing.")?;
+        let signature = map_km_error({
+            let _wp = self.watch_millis("In make_self_signed_cert: calling finish.", 500);
+            operation.finish(Some(&tbs), None, None, None, None)
+        })
+        .context("In make_self_signed_cert: Failed to sign certificate.")?;
+
+        make_signed_certificate(&cert_params, &signature)
+            .context("In make_self_signed_cert: Failed to assemble certificate.")
+            .map(Some)
     }
 
     fn import_key(
         &self,
         key: &KeyDescriptor,
-        _attestation_key: Option<&KeyDescriptor>,
+        attest_key_descriptor: Option<&KeyDescriptor>,
+        params: &[KeyParameter],
+        flags: i32,
+        key_data: &[u8],
+    ) -> Result<KeyMetadata> {
+        self.import_key_with_certificates(key, attest_key_descriptor, params, flags, key_data, None)
+    }
+
+    /// Imports a key like `IKeystoreSecurityLevel::importKey`. If `certificates` is given, its
+    /// first certificate is stored as the certificate of the new key and the remaining ones as
+    /// its certificate chain instead of the certificates created by KeyMint. Supplied
+    /// certificates cannot be combined with attestation, because the attestation chain would be
+    /// lost, so such requests fail with `INVALID_ARGUMENT`.
+    pub fn import_key_with_certificates(
+        &self,
+        key: &KeyDescriptor,
+        attest_key_descriptor: Option<&KeyDescriptor>,
         params: &[KeyParameter],
         flags: i32,
         key_data: &[u8],
+        certificates: Option<Vec<Certificate>>,
     ) -> Result<KeyMetadata> {
+        KEY_CREATION_RATE_LIMIT.check(ThreadState::get_calling_uid()).context("In import_key.")?;
+        self.check_flags(flags).context("In import_key.")?;
         if key.domain != Domain::BLOB && key.alias.is_none() {
             return Err(error::Error::Km(ErrorCode::INVALID_ARGUMENT))
                 .context("In import_key: Alias must be specified");
         }
+        let certificates = certificates.filter(|c| !c.is_empty());
+        if certificates.is_some()
+            && (attest_key_descriptor.is_some()
+                || params.iter().any(|p| p.tag == Tag::ATTESTATION_CHALLENGE))
+        {
+            return Err(error::Error::Km(ErrorCode::INVALID_ARGUMENT)).context(concat!(
+                "In import_key: Supplied certificates cannot be combined with an attestation ",
+                "key or an attestation challenge."
+            ));
+        }
         let caller_uid = ThreadState::get_calling_uid();
 
         let key = match key.domain {
@@ -661,6 +1340,24 @@ impl KeystoreSecurityLevel {
 
         // import_key requires the rebind permission.
         check_key_permission(KeyPerm::Rebind, &key, &None).context("In import_key.")?;
+        Self::check_key_quota(&key, Self::is_exempt_from_key_quota).context("In import_key.")?;
+        Self::check_no_clobber(&key, flags).context("In import_key.")?;
+
+        let attestation_key_info = match (key.domain, attest_key_descriptor) {
+            (Domain::BLOB, _) => None,
+            _ => DB
+                .with(|db| {
+                    get_attest_key_info(
+                        &key,
+                        caller_uid,
+                        attest_key_descriptor,
+                        params,
+                        &self.rem_prov_state,
+                        &mut db.borrow_mut()?,
+                    )
+                })
+                .context("In import_key: Trying to get an attestation key")?,
+        };
 
         let params = self
             .add_required_parameters(caller_uid, params, &key)
@@ -682,16 +1379,50 @@ impl KeystoreSecurityLevel {
             })
             .context("In import_key.")?;
 
-        let km_dev = &self.keymint;
-        let creation_result = map_km_error({
-            let _wp =
-                self.watch_millis("In KeystoreSecurityLevel::import_key: calling importKey.", 500);
-            km_dev.importKey(&params, format, key_data, None /* attestKey */)
-        })
-        .context("In import_key: Trying to call importKey")?;
+        let km_dev = self.keymint().context("In import_key.")?;
+        let mut creation_result = self
+            .create_key_with_attestation(attestation_key_info, &params, |attest_key| {
+                map_km_error({
+                    let _wp = self.watch_millis(
+                        "In KeystoreSecurityLevel::import_key: calling importKey.",
+                        500,
+                    );
+                    let _trace = trace::begin("KeyMint::importKey");
+                    km_dev.importKey(&params, format, key_data, attest_key)
+                })
+            })
+            .map_err(|e| map_device_id_attestation_error(&params, e))
+            .context("In import_key: Trying to call importKey")?;
+
+        if let Some(certificates) = certificates {
+            creation_result.certificateChain = certificates;
+        }
+
+        // Without a certificate the public key of an imported key cannot be retrieved, so try
+        // to make one if KeyMint did not.
+        if format == KeyFormat::PKCS8 && creation_result.certificateChain.is_empty() {
+            match self.make_self_signed_cert(&params, &creation_result.keyBlob, key_data) {
+                Ok(Some(cert)) => {
+                    creation_result.certificateChain.push(Certificate { encodedCertificate: cert })
+                }
+                Ok(None) => log::info!(
+                    "In import_key: Imported key cannot sign its own certificate, storing none."
+                ),
+                Err(e) => log::warn!("In import_key: Failed to make certificate: {:?}", e),
+            }
+        }
+
+        // Remember the public key of asymmetric keys in case there is no certificate.
+        let public_key = match format {
+            KeyFormat::PKCS8 => public_key_from_private_key(key_data)
+                .map_err(|e| log::warn!("In import_key: Failed to derive public key: {:?}", e))
+                .ok(),
+            _ => None,
+        };
 
         let user_id = uid_to_android_user(caller_uid);
-        self.store_new_key(key, creation_result, user_id, Some(flags)).context("In import_key.")
+        self.store_new_key(key, creation_result, user_id, Some(flags), public_key)
+            .context("In import_key.")
     }
 
     fn import_wrapped_key(
@@ -702,6 +1433,9 @@ impl KeystoreSecurityLevel {
         params: &[KeyParameter],
         authenticators: &[AuthenticatorSpec],
     ) -> Result<KeyMetadata> {
+        KEY_CREATION_RATE_LIMIT
+            .check(ThreadState::get_calling_uid())
+            .context("In import_wrapped_key.")?;
         let wrapped_data: &[u8] = match key {
             KeyDescriptor { domain: Domain::APP, blob: Some(ref blob), alias: Some(_), .. }
             | KeyDescriptor {
@@ -745,13 +1479,15 @@ impl KeystoreSecurityLevel {
 
         // Import_wrapped_key requires the rebind permission for the new key.
         check_key_permission(KeyPerm::Rebind, &key, &None).context("In import_wrapped_key.")?;
+        Self::check_key_quota(&key, Self::is_exempt_from_key_quota)
+            .context("In import_wrapped_key.")?;
 
         let super_key = SUPER_KEY.read().unwrap().get_per_boot_key_by_user_id(user_id);
 
         let (wrapping_key_id_guard, mut wrapping_key_entry) = DB
             .with(|db| {
-                LEGACY_IMPORTER.with_try_import(&key, caller_uid, super_key, || {
-                    db.borrow_mut().load_key_entry(
+                LEGACY_IMPORTER.with_try_import(wrapping_key, caller_uid, super_key, || {
+                    db.borrow_mut()?.load_key_entry(
                         wrapping_key,
                         KeyType::Client,
                         KeyEntryLoadBits::KM,
@@ -762,6 +1498,13 @@ impl KeystoreSecurityLevel {
             })
             .context("Failed to load wrapping key.")?;
 
+        if !wrapping_key_entry.key_parameters().iter().any(|kp| {
+            matches!(kp.key_parameter_value(), KsKeyParamValue::KeyPurpose(KeyPurpose::WRAP_KEY))
+        }) {
+            return Err(error::Error::Km(ErrorCode::INCOMPATIBLE_PURPOSE))
+                .context("In import_wrapped_key: Wrapping key lacks purpose WRAP_KEY.");
+        }
+
         let (wrapping_key_blob, wrapping_blob_metadata) = wrapping_key_entry
             .take_key_blob_info()
             .ok_or_else(error::Error::sys)
@@ -775,10 +1518,6 @@ impl KeystoreSecurityLevel {
                 "In import_wrapped_key. Failed to handle super encryption for wrapping key.",
             )?;
 
-        // km_dev.importWrappedKey does not return a certificate chain.
-        // TODO Do we assume that all wrapped keys are symmetric?
-        // let certificate_chain: Vec<KmCertificate> = Default::default();
-
         let pw_sid = authenticators
             .iter()
             .find_map(|a| match a.authenticatorType {
@@ -795,11 +1534,19 @@ impl KeystoreSecurityLevel {
             })
             .unwrap_or(-1);
 
-        let masking_key = masking_key.unwrap_or(ZERO_BLOB_32);
+        let masking_key = match masking_key {
+            Some(masking_key) if masking_key.len() != ZERO_BLOB_32.len() => {
+                return Err(error::Error::Km(ErrorCode::INVALID_ARGUMENT))
+                    .context("In import_wrapped_key: Masking key must be 32 bytes.")
+            }
+            Some(masking_key) => masking_key,
+            None => ZERO_BLOB_32,
+        };
 
+        let km_dev = self.keymint().context("In import_wrapped_key.")?;
         let (creation_result, _) = self
             .upgrade_keyblob_if_required_with(
-                &*self.keymint,
+                &*km_dev,
                 Some(wrapping_key_id_guard),
                 &wrapping_key_blob,
                 wrapping_blob_metadata.km_uuid().copied(),
@@ -809,7 +1556,8 @@ impl KeystoreSecurityLevel {
                         "In KeystoreSecurityLevel::import_wrapped_key: calling importWrappedKey.",
                         500,
                     );
-                    let creation_result = map_km_error(self.keymint.importWrappedKey(
+                    let _trace = trace::begin("KeyMint::importWrappedKey");
+                    let creation_result = map_km_error(km_dev.importWrappedKey(
                         wrapped_data,
                         wrapping_blob,
                         masking_key,
@@ -822,12 +1570,29 @@ impl KeystoreSecurityLevel {
             )
             .context("In import_wrapped_key.")?;
 
-        self.store_new_key(key, creation_result, user_id, None)
+        // KeyMint binds an auth-bound wrapped key to the secure ids given for its authenticator
+        // types. If the caller did not supply one, the key could never be used.
+        let unbound_sid = creation_result.keyCharacteristics.iter().any(|kc| {
+            kc.authorizations.iter().any(|kp| {
+                kp.tag == Tag::USER_SECURE_ID && kp.value == KeyParameterValue::LongInteger(-1)
+            })
+        });
+        if unbound_sid {
+            if let Err(e) = map_km_error(km_dev.deleteKey(&creation_result.keyBlob)) {
+                log::warn!("In import_wrapped_key: Failed to delete unusable key: {:?}", e);
+            }
+            return Err(error::Error::Km(ErrorCode::INVALID_ARGUMENT)).context(concat!(
+                "In import_wrapped_key: Wrapped key requires user authentication, ",
+                "but no matching authenticator was given."
+            ));
+        }
+
+        self.store_new_key(key, creation_result, user_id, None, None)
             .context("In import_wrapped_key: Trying to store the new key.")
     }
 
     fn store_upgraded_keyblob(
-        key_id_guard: KeyIdGuard,
+        key_id_guard: &KeyIdGuard,
         km_uuid: Option<Uuid>,
         key_blob: &KeyBlob,
         upgraded_blob: &[u8],
@@ -842,9 +1607,9 @@ impl KeystoreSecurityLevel {
         }
 
         DB.with(|db| {
-            let mut db = db.borrow_mut();
+            let mut db = db.borrow_mut()?;
             db.set_blob(
-                &key_id_guard,
+                key_id_guard,
                 SubComponentType::KEY_BLOB,
                 Some(&upgraded_blob_to_be_stored),
                 Some(&new_blob_metadata),
@@ -874,8 +1639,20 @@ impl KeystoreSecurityLevel {
                 if key_id_guard.is_some() {
                     // Unwrap cannot panic, because the is_some was true.
                     let kid = key_id_guard.take().unwrap();
-                    Self::store_upgraded_keyblob(kid, km_uuid, key_blob, upgraded_blob).context(
+                    // The upgrade may change the version parameters of the key, so the key
+                    // parameters stored in the database must follow. The journal lets
+                    // cleanup_leftovers roll back an upgrade that was interrupted in between.
+                    DB.with(|db| {
+                        db.borrow_mut()?.begin_blob_replacement(&kid, &[SubComponentType::KEY_BLOB])
+                    })
+                    .context("In upgrade_keyblob_if_required_with: Failed to begin replacement.")?;
+                    Self::store_upgraded_keyblob(&kid, km_uuid, key_blob, upgraded_blob).context(
                         "In upgrade_keyblob_if_required_with: store_upgraded_keyblob failed",
+                    )?;
+                    blob_upgrade::refresh_version_parameters(km_dev, &kid, upgraded_blob, params)
+                        .context("In upgrade_keyblob_if_required_with.")?;
+                    DB.with(|db| db.borrow_mut()?.commit_blob_replacement(&kid)).context(
+                        "In upgrade_keyblob_if_required_with: Failed to commit replacement.",
                     )
                 } else {
                     Ok(())
@@ -889,10 +1666,12 @@ impl KeystoreSecurityLevel {
         // upgrade was performed above and if one was given in the first place.
         if key_blob.force_reencrypt() {
             if let Some(kid) = key_id_guard {
-                Self::store_upgraded_keyblob(kid, km_uuid, key_blob, key_blob).context(concat!(
-                    "In upgrade_keyblob_if_required_with: ",
-                    "store_upgraded_keyblob failed in forced reencrypt"
-                ))?;
+                Self::store_upgraded_keyblob(&kid, km_uuid, key_blob, key_blob).context(
+                    concat!(
+                        "In upgrade_keyblob_if_required_with: ",
+                        "store_upgraded_keyblob failed in forced reencrypt"
+                    ),
+                )?;
             }
         }
         Ok((v, upgraded_blob))
@@ -920,7 +1699,7 @@ impl KeystoreSecurityLevel {
         check_key_permission(KeyPerm::ConvertStorageKeyToEphemeral, storage_key, &None)
             .context("In convert_storage_key_to_ephemeral: Check permission")?;
 
-        let km_dev = &self.keymint;
+        let km_dev = self.keymint().context("In convert_storage_key_to_ephemeral.")?;
         match {
             let _wp = self.watch_millis(
                 concat!(
@@ -979,18 +1758,31 @@ impl KeystoreSecurityLevel {
         check_key_permission(KeyPerm::Delete, key, &None)
             .context("In IKeystoreSecurityLevel delete_key: Checking delete permissions")?;
 
-        let km_dev = &self.keymint;
+        let km_dev = self.keymint().context("In IKeystoreSecurityLevel delete_key.")?;
         {
             let _wp =
                 self.watch_millis("In KeystoreSecuritylevel::delete_key: calling deleteKey", 500);
+            let _trace = trace::begin("KeyMint::deleteKey");
             map_km_error(km_dev.deleteKey(key_blob)).context("In keymint device deleteKey")
         }
     }
 }
 
-impl binder::Interface for KeystoreSecurityLevel {}
+/// The binder object of a security level. The security level itself is shared with
+/// `SECURITY_LEVELS`, so that other Keystore services can use it.
+struct KeystoreSecurityLevelBinder(Arc<KeystoreSecurityLevel>);
+
+impl Deref for KeystoreSecurityLevelBinder {
+    type Target = KeystoreSecurityLevel;
+
+    fn deref(&self) -> &KeystoreSecurityLevel {
+        &self.0
+    }
+}
+
+impl binder::Interface for KeystoreSecurityLevelBinder {}
 
-impl IKeystoreSecurityLevel for KeystoreSecurityLevel {
+impl IKeystoreSecurityLevel for KeystoreSecurityLevelBinder {
     fn createOperation(
         &self,
         key: &KeyDescriptor,
@@ -1059,3 +1851,97 @@ impl IKeystoreSecurityLevel for KeystoreSecurityLevel {
         map_or_log_err(result, Ok)
     }
 }
+
+/// The binder object that stands in for the StrongBox security level on devices without
+/// StrongBox. It creates keys in the wrapped TEE security level, but only if the caller
+/// explicitly requests this with `KEY_FLAG_STRONGBOX_FALLBACK`. All other calls fail as if
+/// there was no StrongBox security level.
+struct StrongBoxFallbackBinder(Arc<KeystoreSecurityLevel>);
+
+impl StrongBoxFallbackBinder {
+    fn check_fallback_requested(flags: i32) -> Result<()> {
+        if flags & KEY_FLAG_STRONGBOX_FALLBACK == 0 {
+            return Err(Error::Km(ErrorCode::HARDWARE_TYPE_UNAVAILABLE))
+                .context("In check_fallback_requested: StrongBox fallback was not requested.");
+        }
+        Ok(())
+    }
+
+    fn unavailable<T>() -> binder::Result<T> {
+        map_or_log_err(
+            Err(Error::Km(ErrorCode::HARDWARE_TYPE_UNAVAILABLE))
+                .context("In StrongBoxFallbackBinder: No StrongBox security level."),
+            Ok,
+        )
+    }
+}
+
+impl Deref for StrongBoxFallbackBinder {
+    type Target = KeystoreSecurityLevel;
+
+    fn deref(&self) -> &KeystoreSecurityLevel {
+        &self.0
+    }
+}
+
+impl binder::Interface for StrongBoxFallbackBinder {}
+
+impl IKeystoreSecurityLevel for StrongBoxFallbackBinder {
+    fn createOperation(
+        &self,
+        _key: &KeyDescriptor,
+        _operation_parameters: &[KeyParameter],
+        _forced: bool,
+    ) -> binder::Result<CreateOperationResponse> {
+        Self::unavailable()
+    }
+    fn generateKey(
+        &self,
+        key: &KeyDescriptor,
+        attestation_key: Option<&KeyDescriptor>,
+        params: &[KeyParameter],
+        flags: i32,
+        entropy: &[u8],
+    ) -> binder::Result<KeyMetadata> {
+        let _wp = self.watch_millis("IKeystoreSecurityLevel::generateKey", 5000);
+        let result = Self::check_fallback_requested(flags)
+            .and_then(|_| self.generate_key(key, attestation_key, params, flags, entropy));
+        log_key_creation_event_stats(self.security_level, params, &result);
+        log_key_generated(key, ThreadState::get_calling_uid(), result.is_ok());
+        map_or_log_err(result, Ok)
+    }
+    fn importKey(
+        &self,
+        key: &KeyDescriptor,
+        attestation_key: Option<&KeyDescriptor>,
+        params: &[KeyParameter],
+        flags: i32,
+        key_data: &[u8],
+    ) -> binder::Result<KeyMetadata> {
+        let _wp = self.watch_millis("IKeystoreSecurityLevel::importKey", 500);
+        let result = Self::check_fallback_requested(flags)
+            .and_then(|_| self.import_key(key, attestation_key, params, flags, key_data));
+        log_key_creation_event_stats(self.security_level, params, &result);
+        log_key_imported(key, ThreadState::get_calling_uid(), result.is_ok());
+        map_or_log_err(result, Ok)
+    }
+    fn importWrappedKey(
+        &self,
+        _key: &KeyDescriptor,
+        _wrapping_key: &KeyDescriptor,
+        _masking_key: Option<&[u8]>,
+        _params: &[KeyParameter],
+        _authenticators: &[AuthenticatorSpec],
+    ) -> binder::Result<KeyMetadata> {
+        Self::unavailable()
+    }
+    fn convertStorageKeyToEphemeral(
+        &self,
+        _storage_key: &KeyDescriptor,
+    ) -> binder::Result<EphemeralStorageKeyResponse> {
+        Self::unavailable()
+    }
+    fn deleteKey(&self, _key: &KeyDescriptor) -> binder::Result<()> {
+        Self::unavailable()
+    }
+}
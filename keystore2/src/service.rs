@@ -15,22 +15,27 @@
 //! This crate implement the core Keystore 2.0 service API as defined by the Keystore 2.0
 //! AIDL spec.
 
+use std::cell::RefCell;
 use std::collections::HashMap;
 
 use crate::audit_log::log_key_deleted;
+use crate::legacy_shadow;
 use crate::permission::{KeyPerm, KeystorePerm};
 use crate::security_level::KeystoreSecurityLevel;
 use crate::utils::{
-    check_grant_permission, check_key_permission, check_keystore_permission,
+    check_grant_permission, check_key_permission, check_keystore_permission, check_list_permission,
     key_parameters_to_authorizations, list_key_entries, uid_to_android_user, watchdog as wd,
 };
 use crate::{
     database::Uuid,
-    globals::{create_thread_local_db, DB, LEGACY_BLOB_LOADER, LEGACY_IMPORTER, SUPER_KEY},
+    globals::{
+        open_thread_local_db, DB, LEGACY_BLOB_LOADER, LEGACY_IMPORTER, SERVICE_RATE_LIMIT,
+        SUPER_KEY,
+    },
 };
 use crate::{database::KEYSTORE_UUID, permission};
 use crate::{
-    database::{KeyEntryLoadBits, KeyType, SubComponentType},
+    database::{KeyEntryLoadBits, KeyType},
     error::ResponseCode,
 };
 use crate::{
@@ -46,13 +51,14 @@ use android_system_keystore2::aidl::android::system::keystore2::{
 };
 use anyhow::{Context, Result};
 use error::Error;
-use keystore2_selinux as selinux;
+use keystore2_crypto::parse_public_key_from_certificate;
 
 /// Implementation of the IKeystoreService.
 #[derive(Default)]
 pub struct KeystoreService {
     i_sec_level_by_uuid: HashMap<Uuid, Strong<dyn IKeystoreSecurityLevel>>,
     uuid_by_sec_level: HashMap<SecurityLevel, Uuid>,
+    strongbox_fallback: Option<Strong<dyn IKeystoreSecurityLevel>>,
 }
 
 impl KeystoreService {
@@ -78,12 +84,17 @@ impl KeystoreService {
         {
             result.i_sec_level_by_uuid.insert(uuid, dev);
             result.uuid_by_sec_level.insert(SecurityLevel::STRONGBOX, uuid);
+        } else {
+            result.strongbox_fallback = Some(
+                KeystoreSecurityLevel::new_strongbox_fallback_binder()
+                    .context("In KeystoreService::new_native_binder.")?,
+            );
         }
 
         let uuid_by_sec_level = result.uuid_by_sec_level.clone();
         LEGACY_IMPORTER
             .set_init(move || {
-                (create_thread_local_db(), uuid_by_sec_level, LEGACY_BLOB_LOADER.clone())
+                Ok((open_thread_local_db()?, uuid_by_sec_level.clone(), LEGACY_BLOB_LOADER.clone()))
             })
             .context(
                 "In KeystoreService::new_native_binder: Trying to initialize the legacy migrator.",
@@ -122,6 +133,17 @@ impl KeystoreService {
             .and_then(|uuid| self.i_sec_level_by_uuid.get(uuid))
         {
             Ok(dev.clone())
+        } else if let (SecurityLevel::STRONGBOX, Some(fallback)) =
+            (sec_level, &self.strongbox_fallback)
+        {
+            // Callers that may fall back to the TEE get a stand-in for StrongBox, that creates
+            // keys in the TEE on explicit request only. Everyone else learns that there is no
+            // StrongBox as usual.
+            match check_keystore_permission(KeystorePerm::StrongBoxFallback) {
+                Ok(()) => Ok(fallback.clone()),
+                Err(_) => Err(error::Error::Km(ErrorCode::HARDWARE_TYPE_UNAVAILABLE))
+                    .context("In get_security_level: No StrongBox security level."),
+            }
         } else {
             Err(error::Error::Km(ErrorCode::HARDWARE_TYPE_UNAVAILABLE))
                 .context("In get_security_level: No such security level.")
@@ -130,6 +152,7 @@ impl KeystoreService {
 
     fn get_key_entry(&self, key: &KeyDescriptor) -> Result<KeyEntryResponse> {
         let caller_uid = ThreadState::get_calling_uid();
+        SERVICE_RATE_LIMIT.check(caller_uid).context("In get_key_entry.")?;
 
         let super_key =
             SUPER_KEY.read().unwrap().get_per_boot_key_by_user_id(uid_to_android_user(caller_uid));
@@ -137,7 +160,7 @@ impl KeystoreService {
         let (key_id_guard, mut key_entry) = DB
             .with(|db| {
                 LEGACY_IMPORTER.with_try_import(key, caller_uid, super_key, || {
-                    db.borrow_mut().load_key_entry(
+                    db.borrow_mut()?.load_key_entry(
                         key,
                         KeyType::Client,
                         KeyEntryLoadBits::PUBLIC,
@@ -186,12 +209,13 @@ impl KeystoreService {
         certificate_chain: Option<&[u8]>,
     ) -> Result<()> {
         let caller_uid = ThreadState::get_calling_uid();
+        SERVICE_RATE_LIMIT.check(caller_uid).context("In update_subcomponent.")?;
         let super_key =
             SUPER_KEY.read().unwrap().get_per_boot_key_by_user_id(uid_to_android_user(caller_uid));
 
         DB.with::<_, Result<()>>(|db| {
             let entry = match LEGACY_IMPORTER.with_try_import(key, caller_uid, super_key, || {
-                db.borrow_mut().load_key_entry(
+                db.borrow_mut()?.load_key_entry(
                     key,
                     KeyType::Client,
                     KeyEntryLoadBits::NONE,
@@ -210,13 +234,43 @@ impl KeystoreService {
             }
             .context("Failed to load key entry.")?;
 
-            let mut db = db.borrow_mut();
+            let mut db = db.borrow_mut()?;
             if let Some((key_id_guard, _key_entry)) = entry {
-                db.set_blob(&key_id_guard, SubComponentType::CERT, public_cert, None)
-                    .context("Failed to update cert subcomponent.")?;
-
-                db.set_blob(&key_id_guard, SubComponentType::CERT_CHAIN, certificate_chain, None)
-                    .context("Failed to update cert chain subcomponent.")?;
+                // The cert and the cert chain are replaced together in one transaction, and only
+                // if the new cert certifies the same public key as the current one, or as the
+                // stored public key of a key without certificate.
+                let new_public_key = public_cert
+                    .map(parse_public_key_from_certificate)
+                    .transpose()
+                    .map_err(|_| Error::Rc(ResponseCode::INVALID_ARGUMENT))
+                    .context("Failed to parse new certificate.")?;
+                db.replace_certificates(
+                    &key_id_guard,
+                    public_cert,
+                    certificate_chain,
+                    |current_cert, stored_public_key| {
+                        let new_public_key = match &new_public_key {
+                            Some(new_public_key) => new_public_key,
+                            None => return Ok(()),
+                        };
+                        // Certificates that cannot be parsed, e.g., those of legacy entries,
+                        // cannot be checked.
+                        let current = match current_cert {
+                            Some(current_cert) => {
+                                parse_public_key_from_certificate(current_cert).ok()
+                            }
+                            None => stored_public_key.map(|k| k.to_vec()),
+                        };
+                        match current {
+                            Some(current) if &current != new_public_key => {
+                                Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+                                    .context("New certificate does not match the public key.")
+                            }
+                            _ => Ok(()),
+                        }
+                    },
+                )
+                .context("Failed to replace certificates.")?;
                 return Ok(());
             }
 
@@ -259,53 +313,37 @@ impl KeystoreService {
     }
 
     fn list_entries(&self, domain: Domain, namespace: i64) -> Result<Vec<KeyDescriptor>> {
-        let mut k = match domain {
-            Domain::APP => KeyDescriptor {
-                domain,
-                nspace: ThreadState::get_calling_uid() as u64 as i64,
-                ..Default::default()
-            },
-            Domain::SELINUX => KeyDescriptor{domain, nspace: namespace, ..Default::default()},
-            _ => return Err(Error::perm()).context(
-                "In list_entries: List entries is only supported for Domain::APP and Domain::SELINUX."
-            ),
-        };
-
-        // First we check if the caller has the info permission for the selected domain/namespace.
-        // By default we use the calling uid as namespace if domain is Domain::APP.
-        // If the first check fails we check if the caller has the list permission allowing to list
-        // any namespace. In that case we also adjust the queried namespace if a specific uid was
-        // selected.
-        if let Err(e) = check_key_permission(KeyPerm::GetInfo, &k, &None) {
-            if let Some(selinux::Error::PermissionDenied) =
-                e.root_cause().downcast_ref::<selinux::Error>() {
-
-                check_keystore_permission(KeystorePerm::List)
-                    .context("In list_entries: While checking keystore permission.")?;
-                if namespace != -1 {
-                    k.nspace = namespace;
-                }
-            } else {
-                return Err(e).context("In list_entries: While checking key permission.")?;
-            }
-        }
-
-        DB.with(|db| list_key_entries(&mut db.borrow_mut(), k.domain, k.nspace))
+        SERVICE_RATE_LIMIT.check(ThreadState::get_calling_uid()).context("In list_entries.")?;
+        let k = check_list_permission(domain, namespace).context("In list_entries.")?;
+        DB.with(|db| list_key_entries(&mut db.borrow_mut()?, k.domain, k.nspace))
     }
 
     fn delete_key(&self, key: &KeyDescriptor) -> Result<()> {
         let caller_uid = ThreadState::get_calling_uid();
+        SERVICE_RATE_LIMIT.check(caller_uid).context("In delete_key.")?;
         let super_key =
             SUPER_KEY.read().unwrap().get_per_boot_key_by_user_id(uid_to_android_user(caller_uid));
 
+        // The access descriptor of the deleted key is needed to remove its legacy copy.
+        let deleted_key = RefCell::new(None);
         DB.with(|db| {
             LEGACY_IMPORTER.with_try_import(key, caller_uid, super_key, || {
-                db.borrow_mut().unbind_key(key, KeyType::Client, caller_uid, |k, av| {
-                    check_key_permission(KeyPerm::Delete, k, &av).context("During delete_key.")
+                db.borrow_mut()?.unbind_key(key, KeyType::Client, caller_uid, |k, av| {
+                    check_key_permission(KeyPerm::Delete, k, &av).context("During delete_key.")?;
+                    *deleted_key.borrow_mut() = Some(k.clone());
+                    Ok(())
                 })
             })
         })
         .context("In delete_key: Trying to unbind the key.")?;
+
+        if let Some(deleted_key) = deleted_key.into_inner() {
+            if let Err(e) =
+                DB.with(|db| legacy_shadow::shadow_delete_key(&mut db.borrow_mut()?, &deleted_key))
+            {
+                log::error!("In delete_key: Failed to remove legacy copy: {:?}", e);
+            }
+        }
         Ok(())
     }
 
@@ -316,12 +354,13 @@ impl KeystoreService {
         access_vector: permission::KeyPermSet,
     ) -> Result<KeyDescriptor> {
         let caller_uid = ThreadState::get_calling_uid();
+        SERVICE_RATE_LIMIT.check(caller_uid).context("In KeystoreService::grant.")?;
         let super_key =
             SUPER_KEY.read().unwrap().get_per_boot_key_by_user_id(uid_to_android_user(caller_uid));
 
         DB.with(|db| {
             LEGACY_IMPORTER.with_try_import(key, caller_uid, super_key, || {
-                db.borrow_mut().grant(
+                db.borrow_mut()?.grant(
                     key,
                     caller_uid,
                     grantee_uid as u32,
@@ -334,8 +373,11 @@ impl KeystoreService {
     }
 
     fn ungrant(&self, key: &KeyDescriptor, grantee_uid: i32) -> Result<()> {
+        SERVICE_RATE_LIMIT
+            .check(ThreadState::get_calling_uid())
+            .context("In KeystoreService::ungrant.")?;
         DB.with(|db| {
-            db.borrow_mut().ungrant(key, ThreadState::get_calling_uid(), grantee_uid as u32, |k| {
+            db.borrow_mut()?.ungrant(key, ThreadState::get_calling_uid(), grantee_uid as u32, |k| {
                 check_key_permission(KeyPerm::Grant, k, &None)
             })
         })
@@ -0,0 +1,109 @@
+// Copyright 2021, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements the IKeystoreSharedMemoryOperations AIDL interface, which allows
+//! clients to pass the data of an operation through shared memory instead of binder parcels.
+//! This avoids copying large payloads through the binder buffers. The data is streamed into
+//! the operation in chunks, see `operation::KeystoreOperation::update_shared_memory`.
+
+use crate::error::{map_or_log_err, Error};
+use crate::operation::KeystoreOperation;
+use crate::utils::watchdog as wd;
+use android_security_operation::aidl::android::security::operation::IKeystoreSharedMemoryOperations::{
+    BnKeystoreSharedMemoryOperations, IKeystoreSharedMemoryOperations,
+};
+use android_security_operation::binder::{
+    BinderFeatures, Interface, ParcelFileDescriptor, Result as BinderResult, Strong,
+};
+use android_system_keystore2::aidl::android::system::keystore2::{
+    IKeystoreOperation::IKeystoreOperation, ResponseCode::ResponseCode,
+};
+use anyhow::{Context, Result};
+use std::convert::TryInto;
+
+/// Implementation of the IKeystoreSharedMemoryOperations AIDL interface.
+pub struct SharedMemoryOperations;
+
+impl SharedMemoryOperations {
+    /// Creates a new instance of the shared memory operations service.
+    pub fn new_native_binder() -> Result<Strong<dyn IKeystoreSharedMemoryOperations>> {
+        Ok(BnKeystoreSharedMemoryOperations::new_binder(
+            Self,
+            BinderFeatures { set_requesting_sid: true, ..BinderFeatures::default() },
+        ))
+    }
+
+    fn input_length(input_length: i64) -> Result<u64> {
+        input_length
+            .try_into()
+            .map_err(|_| Error::Rc(ResponseCode::INVALID_ARGUMENT))
+            .context("In input_length: Negative input length.")
+    }
+
+    fn update(
+        operation: &Strong<dyn IKeystoreOperation>,
+        input: &ParcelFileDescriptor,
+        input_length: i64,
+        output: &ParcelFileDescriptor,
+    ) -> Result<i64> {
+        let input_length = Self::input_length(input_length).context("In update.")?;
+        let output_length = KeystoreOperation::from_binder(operation)
+            .context("In update.")?
+            .update_shared_memory(input.as_ref(), input_length, output.as_ref())
+            .context("In update.")?;
+        Ok(output_length as i64)
+    }
+
+    fn finish(
+        operation: &Strong<dyn IKeystoreOperation>,
+        input: &ParcelFileDescriptor,
+        input_length: i64,
+        signature: Option<&[u8]>,
+        output: &ParcelFileDescriptor,
+    ) -> Result<i64> {
+        let input_length = Self::input_length(input_length).context("In finish.")?;
+        let output_length = KeystoreOperation::from_binder(operation)
+            .context("In finish.")?
+            .finish_shared_memory(input.as_ref(), input_length, signature, output.as_ref())
+            .context("In finish.")?;
+        Ok(output_length as i64)
+    }
+}
+
+impl Interface for SharedMemoryOperations {}
+
+impl IKeystoreSharedMemoryOperations for SharedMemoryOperations {
+    fn update(
+        &self,
+        operation: &Strong<dyn IKeystoreOperation>,
+        input: &ParcelFileDescriptor,
+        input_length: i64,
+        output: &ParcelFileDescriptor,
+    ) -> BinderResult<i64> {
+        let _wp = wd::watch_millis("IKeystoreSharedMemoryOperations::update", 500);
+        map_or_log_err(Self::update(operation, input, input_length, output), Ok)
+    }
+
+    fn finish(
+        &self,
+        operation: &Strong<dyn IKeystoreOperation>,
+        input: &ParcelFileDescriptor,
+        input_length: i64,
+        signature: Option<&[u8]>,
+        output: &ParcelFileDescriptor,
+    ) -> BinderResult<i64> {
+        let _wp = wd::watch_millis("IKeystoreSharedMemoryOperations::finish", 500);
+        map_or_log_err(Self::finish(operation, input, input_length, signature, output), Ok)
+    }
+}
@@ -24,26 +24,107 @@ use android_hardware_security_sharedsecret::aidl::android::hardware::security::s
 use android_security_compat::aidl::android::security::compat::IKeystoreCompatService::IKeystoreCompatService;
 use anyhow::Result;
 use keystore2_vintf::{get_aidl_instances, get_hidl_instances};
+use lazy_static::lazy_static;
 use std::fmt::{self, Display, Formatter};
+use std::sync::Mutex;
 use std::time::Duration;
 
+/// Delay before the first retry. It doubles with every further attempt up to `MAX_RETRY_DELAY`.
+const INITIAL_RETRY_DELAY: Duration = Duration::from_millis(500);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(16);
+
+/// Number of times the negotiation is attempted if a participant fails to compute the shared
+/// secret.
+const MAX_NEGOTIATION_ATTEMPTS: u32 = 3;
+
+/// The stage the shared secret negotiation is in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NegotiationState {
+    /// The negotiation has not been started yet.
+    NotStarted,
+    /// Waiting for all participants to come up.
+    Connecting,
+    /// Exchanging parameters and computing the shared secret.
+    Negotiating,
+    /// All participants agreed on the shared secret.
+    Concluded,
+    /// The negotiation failed, auth tokens may not verify across security levels.
+    Failed,
+}
+
+impl Default for NegotiationState {
+    fn default() -> Self {
+        Self::NotStarted
+    }
+}
+
+/// Progress of the shared secret negotiation as shown by dumpsys.
+#[derive(Debug, Clone, Default)]
+pub struct NegotiationStatus {
+    /// The stage the negotiation is in.
+    pub state: NegotiationState,
+    /// All participants listed in the vintf manifest.
+    pub participants: Vec<String>,
+    /// Participants that could not be connected or did not respond yet.
+    pub pending: Vec<String>,
+    /// Number of retries so far.
+    pub retries: u32,
+    /// The most recent error, if any.
+    pub last_error: Option<String>,
+}
+
+lazy_static! {
+    static ref NEGOTIATION_STATUS: Mutex<NegotiationStatus> = Default::default();
+}
+
+/// Returns the current progress of the shared secret negotiation.
+pub fn negotiation_status() -> NegotiationStatus {
+    NEGOTIATION_STATUS.lock().unwrap().clone()
+}
+
+fn update_status<F: FnOnce(&mut NegotiationStatus)>(f: F) {
+    f(&mut NEGOTIATION_STATUS.lock().unwrap())
+}
+
+/// Sleeps before the given retry, backing off exponentially.
+fn back_off(retry: u32) {
+    update_status(|status| status.retries += 1);
+    let delay = INITIAL_RETRY_DELAY.saturating_mul(1 << retry.min(8)).min(MAX_RETRY_DELAY);
+    std::thread::sleep(delay);
+}
+
 /// This function initiates the shared secret negotiation. It starts a thread and then returns
 /// immediately. The thread consults the vintf manifest to enumerate expected negotiation
-/// participants. It then attempts to connect to all of these participants. If any connection
-/// fails the thread will retry once per second to connect to the failed instance(s) until all of
-/// the instances are connected. It then performs the negotiation.
+/// participants, i.e., all KeyMint and Keymaster instances as well as any other ISharedSecret
+/// instance such as Gatekeeper. It then attempts to connect to all of these participants. If any
+/// connection fails the thread will retry with exponential backoff to connect to the failed
+/// instance(s) until all of the instances are connected. It then performs the negotiation.
 ///
-/// During the first phase of the negotiation it will again try every second until
+/// During the first phase of the negotiation it will again retry with backoff until
 /// all instances have responded successfully to account for instances that register early but
 /// are not fully functioning at this time due to hardware delays or boot order dependency issues.
-/// An error during the second phase or a checksum mismatch leads to a panic.
+/// An error during the second phase restarts the negotiation a limited number of times. A
+/// checksum mismatch is final. The progress can be inspected with `negotiation_status`.
 pub fn perform_shared_secret_negotiation() {
     std::thread::spawn(|| {
         let participants = list_participants()
             .expect("In perform_shared_secret_negotiation: Trying to list participants.");
+        update_status(|status| {
+            status.state = NegotiationState::Connecting;
+            status.participants = participants.iter().map(|p| p.to_string()).collect();
+        });
         let connected = connect_participants(participants);
-        negotiate_shared_secret(connected);
-        log::info!("Shared secret negotiation concluded successfully.");
+        update_status(|status| status.state = NegotiationState::Negotiating);
+        match negotiate_shared_secret(connected) {
+            Ok(()) => {
+                update_status(|status| status.state = NegotiationState::Concluded);
+                log::info!("Shared secret negotiation concluded successfully.");
+            }
+            Err(e) => update_status(|status| {
+                status.state = NegotiationState::Failed;
+                status.last_error = Some(e.to_string());
+            }),
+        }
 
         // Once shared secret negotiation is done, the StrongBox and TEE have a common key that
         // can be used to authenticate a possible RootOfTrust transfer.
@@ -157,6 +238,7 @@ fn connect_participants(
 ) -> Vec<(Strong<dyn ISharedSecret>, SharedSecretParticipant)> {
     let mut connected_participants: Vec<(Strong<dyn ISharedSecret>, SharedSecretParticipant)> =
         vec![];
+    let mut retry = 0;
     loop {
         let (connected, not_connected) = participants.into_iter().fold(
             (connected_participants, vec![]),
@@ -224,18 +306,53 @@ fn connect_participants(
         );
         participants = not_connected;
         connected_participants = connected;
+        update_status(|status| {
+            status.pending = participants.iter().map(|p| p.to_string()).collect();
+        });
         if participants.is_empty() {
             break;
         }
-        std::thread::sleep(Duration::from_millis(1000));
+        back_off(retry);
+        retry += 1;
     }
     connected_participants
 }
 
 fn negotiate_shared_secret(
     participants: Vec<(Strong<dyn ISharedSecret>, SharedSecretParticipant)>,
-) {
+) -> Result<(), SharedSecretError> {
+    let mut attempt = 1;
+    loop {
+        match try_negotiate_shared_secret(&participants) {
+            Err(e @ SharedSecretError::Computation { .. })
+                if attempt < MAX_NEGOTIATION_ATTEMPTS =>
+            {
+                log::warn!("In negotiate_shared_secret: {:?}. Restarting negotiation.", e);
+                update_status(|status| status.last_error = Some(e.to_string()));
+                back_off(attempt);
+                attempt += 1;
+            }
+            Err(e) => {
+                log::error!("In negotiate_shared_secret: {:?}.", e);
+                if let SharedSecretError::Checksum(_) = e {
+                    log::error!(concat!(
+                        "This means that this device is NOT PROVISIONED CORRECTLY.\n",
+                        "User authorization and other security functions will not work\n",
+                        "as expected. Please contact your OEM for instructions.",
+                    ));
+                }
+                return Err(e);
+            }
+            Ok(()) => return Ok(()),
+        }
+    }
+}
+
+fn try_negotiate_shared_secret(
+    participants: &[(Strong<dyn ISharedSecret>, SharedSecretParticipant)],
+) -> Result<(), SharedSecretError> {
     // Phase 1: Get the sharing parameters from all participants.
+    let mut retry = 0;
     let mut params = loop {
         let result: Result<Vec<SharedSecretParameters>, SharedSecretError> = participants
             .iter()
@@ -248,40 +365,40 @@ fn negotiate_shared_secret(
         match result {
             Err(e) => {
                 log::warn!("{:?}", e);
-                log::warn!("Retrying in one second.");
-                std::thread::sleep(Duration::from_millis(1000));
+                log::warn!("Retrying later.");
+                update_status(|status| {
+                    if let SharedSecretError::ParameterRetrieval { p, .. } = &e {
+                        status.pending = vec![p.to_string()];
+                    }
+                    status.last_error = Some(e.to_string());
+                });
+                back_off(retry);
+                retry += 1;
             }
             Ok(params) => break params,
         }
     };
+    update_status(|status| status.pending.clear());
 
     params.sort_unstable();
 
     // Phase 2: Send the sorted sharing parameters to all participants.
-    let negotiation_result = participants.into_iter().try_fold(None, |acc, (s, p)| {
-        match (acc, map_binder_status(s.computeSharedSecret(&params))) {
-            (None, Ok(new_sum)) => Ok(Some(new_sum)),
-            (Some(old_sum), Ok(new_sum)) => {
-                if old_sum == new_sum {
-                    Ok(Some(old_sum))
-                } else {
-                    Err(SharedSecretError::Checksum(p))
+    participants
+        .iter()
+        .try_fold(None, |acc, (s, p)| {
+            match (acc, map_binder_status(s.computeSharedSecret(&params))) {
+                (None, Ok(new_sum)) => Ok(Some(new_sum)),
+                (Some(old_sum), Ok(new_sum)) => {
+                    if old_sum == new_sum {
+                        Ok(Some(old_sum))
+                    } else {
+                        Err(SharedSecretError::Checksum(p.clone()))
+                    }
                 }
+                (_, Err(e)) => Err(SharedSecretError::Computation { e, p: p.clone() }),
             }
-            (_, Err(e)) => Err(SharedSecretError::Computation { e, p }),
-        }
-    });
-
-    if let Err(e) = negotiation_result {
-        log::error!("In negotiate_shared_secret: {:?}.", e);
-        if let SharedSecretError::Checksum(_) = e {
-            log::error!(concat!(
-                "This means that this device is NOT PROVISIONED CORRECTLY.\n",
-                "User authorization and other security functions will not work\n",
-                "as expected. Please contact your OEM for instructions.",
-            ));
-        }
-    }
+        })
+        .map(|_| ())
 }
 
 /// Perform RootOfTrust transfer from TEE to StrongBox (if available).
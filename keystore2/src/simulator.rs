@@ -0,0 +1,530 @@
+// Copyright 2022, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements a simulator of Keystore 2.0 for tests. It runs the service logic in
+//! the test process, independent of the keystore2 daemon and the KeyMint HALs of the device:
+//! the persistent database lives in a shared in-memory SQLite database, all security levels
+//! but StrongBox are served by the software KeyMint backend, and the services are called
+//! directly instead of being registered with the service manager. There is no calling context
+//! for in-process calls, so SELinux permission checks are skipped. Everything else, including
+//! enforcements, operation pruning, garbage collection and database migrations, runs the same
+//! code as in the daemon.
+//!
+//! Tests can substitute mock KeyMint devices for the software backend, see
+//! `Simulator::start_with_connector`. The other process wide singletons of Keystore, such as the
+//! super key manager and the enforcements, are shared by everything running in the process.
+
+use crate::database::KeystoreDB;
+use crate::error::Error;
+use crate::globals::{
+    connect_software_keymint, set_db_path, set_keymint_connector, KeyMintConnection,
+    KeyMintConnector, DB_PATH,
+};
+use crate::id_rotation::IdRotationState;
+use crate::service::KeystoreService;
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
+    ErrorCode::ErrorCode, SecurityLevel::SecurityLevel,
+};
+use android_system_keystore2::aidl::android::system::keystore2::IKeystoreService::IKeystoreService;
+use anyhow::{Context, Result};
+use binder::Strong;
+use std::path::Path;
+
+/// URI of the shared in-memory database that stands in for the persistent database file.
+pub const SIMULATOR_DB_URI: &str = "file:keystore2_simulator?mode=memory&cache=shared";
+
+/// A running instance of the simulator. The in-memory database is discarded when it is
+/// dropped.
+pub struct Simulator {
+    // A shared in-memory database only lives as long as a connection is open.
+    _db: KeystoreDB,
+    service: Strong<dyn IKeystoreService>,
+}
+
+impl Simulator {
+    /// Starts the simulator. The given directory takes the place of the database directory,
+    /// it holds the legacy key blobs and the id rotation state. The simulator can only be
+    /// started once per process, because the security levels are process wide singletons.
+    pub fn start(db_dir: &Path) -> Result<Self> {
+        Self::start_with_connector(db_dir, Box::new(connect_simulated_keymint))
+    }
+
+    /// Like `start`, but connects to KeyMint instances through the given connector instead of
+    /// `connect_simulated_keymint`. This allows tests to run the services against mock KeyMint
+    /// devices.
+    pub fn start_with_connector(db_dir: &Path, connector: Box<KeyMintConnector>) -> Result<Self> {
+        set_db_path(db_dir);
+        set_keymint_connector(connector);
+        let db = KeystoreDB::new(&DB_PATH.read().unwrap(), None)
+            .context("In Simulator::start: Failed to open database.")?;
+        let service = KeystoreService::new_native_binder(IdRotationState::new(db_dir))
+            .context("In Simulator::start: Failed to create Keystore service.")?;
+        Ok(Self { _db: db, service })
+    }
+
+    /// Returns the Keystore service of the simulator.
+    pub fn service(&self) -> Strong<dyn IKeystoreService> {
+        self.service.clone()
+    }
+}
+
+/// Connects to the KeyMint instances of the simulator: all security levels but StrongBox are
+/// served by the software KeyMint backend.
+pub fn connect_simulated_keymint(security_level: &SecurityLevel) -> Result<KeyMintConnection> {
+    match *security_level {
+        SecurityLevel::STRONGBOX => Err(Error::Km(ErrorCode::HARDWARE_TYPE_UNAVAILABLE))
+            .context("In connect_simulated_keymint: The simulator has no StrongBox."),
+        _ => connect_software_keymint().context("In connect_simulated_keymint."),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::KeyMetaData;
+    use crate::error::ResponseCode;
+    use crate::globals::{ENFORCEMENTS, LEGACY_IMPORTER, SUPER_KEY};
+    use crate::key_parameter::{
+        KeyParameter as KsKeyParameter, KeyParameterValue as KsKeyParameterValue,
+    };
+    use crate::security_level::KeystoreSecurityLevel;
+    use crate::super_key::{SuperKeyManager, UserState, USER_SUPER_KEY, USER_SUPER_KEY_ESCROW};
+    use crate::utils::AesGcm;
+    use android_hardware_security_keymint::aidl::android::hardware::security::keymint::IKeyMintDevice::{
+        BnKeyMintDevice, IKeyMintDevice,
+    };
+    use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
+        Algorithm::Algorithm, AttestationKey::AttestationKey, BeginResult::BeginResult,
+        Digest::Digest, EcCurve::EcCurve, ErrorCode::ErrorCode,
+        HardwareAuthToken::HardwareAuthToken,
+        HardwareAuthenticatorType::HardwareAuthenticatorType,
+        KeyCharacteristics::KeyCharacteristics, KeyCreationResult::KeyCreationResult,
+        KeyFormat::KeyFormat, KeyMintHardwareInfo::KeyMintHardwareInfo,
+        KeyParameter::KeyParameter, KeyParameterValue::KeyParameterValue,
+        KeyPurpose::KeyPurpose, Tag::Tag,
+    };
+    use android_hardware_security_secureclock::aidl::android::hardware::security::secureclock::{
+        TimeStampToken::TimeStampToken, Timestamp::Timestamp,
+    };
+    use android_system_keystore2::aidl::android::system::keystore2::{
+        Domain::Domain, KeyDescriptor::KeyDescriptor,
+    };
+    use binder::BinderFeatures;
+    use keystore2_crypto::{generate_aes256_key, Password};
+    use keystore2_test_utils::TempDir;
+    use lazy_static::lazy_static;
+    use std::sync::Mutex;
+
+    lazy_static! {
+        // The simulator can only be started once per process, so all tests share it.
+        static ref SIMULATOR: Mutex<Option<(TempDir, Simulator)>> = Mutex::new(None);
+    }
+
+    /// Application data that makes `FaultInjectingKeyMint` fail the generation of a key.
+    const INJECT_GENERATE_FAILURE: &[u8] = b"simulator_inject_generate_failure";
+
+    /// Mock KeyMint instance backing the simulator in tests. It forwards all calls to the
+    /// software KeyMint backend, except that it fails the generation of keys bound to
+    /// `INJECT_GENERATE_FAILURE` with `ErrorCode::UNKNOWN_ERROR`.
+    struct FaultInjectingKeyMint {
+        soft: Strong<dyn IKeyMintDevice>,
+    }
+
+    impl FaultInjectingKeyMint {
+        fn connect(security_level: &SecurityLevel) -> Result<KeyMintConnection> {
+            let (soft, hw_info) = connect_simulated_keymint(security_level)?;
+            let mock = BnKeyMintDevice::new_binder(Self { soft }, BinderFeatures::default());
+            Ok((mock, hw_info))
+        }
+    }
+
+    impl binder::Interface for FaultInjectingKeyMint {}
+
+    impl IKeyMintDevice for FaultInjectingKeyMint {
+        fn getHardwareInfo(&self) -> binder::Result<KeyMintHardwareInfo> {
+            self.soft.getHardwareInfo()
+        }
+        fn addRngEntropy(&self, data: &[u8]) -> binder::Result<()> {
+            self.soft.addRngEntropy(data)
+        }
+        fn generateKey(
+            &self,
+            key_params: &[KeyParameter],
+            attestation_key: Option<&AttestationKey>,
+        ) -> binder::Result<KeyCreationResult> {
+            if key_params.iter().any(|p| {
+                p.tag == Tag::APPLICATION_DATA
+                    && p.value == KeyParameterValue::Blob(INJECT_GENERATE_FAILURE.to_vec())
+            }) {
+                return Err(binder::Status::new_service_specific_error(
+                    ErrorCode::UNKNOWN_ERROR.0,
+                    None,
+                ));
+            }
+            self.soft.generateKey(key_params, attestation_key)
+        }
+        fn importKey(
+            &self,
+            key_params: &[KeyParameter],
+            key_format: KeyFormat,
+            key_data: &[u8],
+            attestation_key: Option<&AttestationKey>,
+        ) -> binder::Result<KeyCreationResult> {
+            self.soft.importKey(key_params, key_format, key_data, attestation_key)
+        }
+        fn importWrappedKey(
+            &self,
+            wrapped_key_data: &[u8],
+            wrapping_key_blob: &[u8],
+            masking_key: &[u8],
+            unwrapping_params: &[KeyParameter],
+            password_sid: i64,
+            biometric_sid: i64,
+        ) -> binder::Result<KeyCreationResult> {
+            self.soft.importWrappedKey(
+                wrapped_key_data,
+                wrapping_key_blob,
+                masking_key,
+                unwrapping_params,
+                password_sid,
+                biometric_sid,
+            )
+        }
+        fn upgradeKey(
+            &self,
+            keyblob_to_upgrade: &[u8],
+            upgrade_params: &[KeyParameter],
+        ) -> binder::Result<Vec<u8>> {
+            self.soft.upgradeKey(keyblob_to_upgrade, upgrade_params)
+        }
+        fn deleteKey(&self, keyblob: &[u8]) -> binder::Result<()> {
+            self.soft.deleteKey(keyblob)
+        }
+        fn deleteAllKeys(&self) -> binder::Result<()> {
+            self.soft.deleteAllKeys()
+        }
+        fn destroyAttestationIds(&self) -> binder::Result<()> {
+            self.soft.destroyAttestationIds()
+        }
+        fn begin(
+            &self,
+            purpose: KeyPurpose,
+            keyblob: &[u8],
+            params: &[KeyParameter],
+            auth_token: Option<&HardwareAuthToken>,
+        ) -> binder::Result<BeginResult> {
+            self.soft.begin(purpose, keyblob, params, auth_token)
+        }
+        fn deviceLocked(
+            &self,
+            password_only: bool,
+            timestamp_token: Option<&TimeStampToken>,
+        ) -> binder::Result<()> {
+            self.soft.deviceLocked(password_only, timestamp_token)
+        }
+        fn earlyBootEnded(&self) -> binder::Result<()> {
+            self.soft.earlyBootEnded()
+        }
+        fn convertStorageKeyToEphemeral(&self, storage_keyblob: &[u8]) -> binder::Result<Vec<u8>> {
+            self.soft.convertStorageKeyToEphemeral(storage_keyblob)
+        }
+        fn getKeyCharacteristics(
+            &self,
+            keyblob: &[u8],
+            app_id: &[u8],
+            app_data: &[u8],
+        ) -> binder::Result<Vec<KeyCharacteristics>> {
+            self.soft.getKeyCharacteristics(keyblob, app_id, app_data)
+        }
+        fn getRootOfTrustChallenge(&self) -> binder::Result<[u8; 16]> {
+            self.soft.getRootOfTrustChallenge()
+        }
+        fn getRootOfTrust(&self, challenge: &[u8; 16]) -> binder::Result<Vec<u8>> {
+            self.soft.getRootOfTrust(challenge)
+        }
+        fn sendRootOfTrust(&self, root_of_trust: &[u8]) -> binder::Result<()> {
+            self.soft.sendRootOfTrust(root_of_trust)
+        }
+    }
+
+    fn shared_service() -> Result<Strong<dyn IKeystoreService>> {
+        let mut simulator = SIMULATOR.lock().unwrap();
+        if simulator.is_none() {
+            let temp_dir = TempDir::new("simulator_test")?;
+            let started = Simulator::start_with_connector(
+                temp_dir.path(),
+                Box::new(FaultInjectingKeyMint::connect),
+            )?;
+            *simulator = Some((temp_dir, started));
+        }
+        Ok(simulator.as_ref().unwrap().1.service())
+    }
+
+    fn param(tag: Tag, value: KeyParameterValue) -> KeyParameter {
+        KeyParameter { tag, value }
+    }
+
+    fn ec_signing_params() -> Vec<KeyParameter> {
+        vec![
+            param(Tag::ALGORITHM, KeyParameterValue::Algorithm(Algorithm::EC)),
+            param(Tag::EC_CURVE, KeyParameterValue::EcCurve(EcCurve::P_256)),
+            param(Tag::PURPOSE, KeyParameterValue::KeyPurpose(KeyPurpose::SIGN)),
+            param(Tag::DIGEST, KeyParameterValue::Digest(Digest::SHA_2_256)),
+            param(Tag::NO_AUTH_REQUIRED, KeyParameterValue::BoolValue(true)),
+        ]
+    }
+
+    #[test]
+    fn test_generate_list_and_delete_key() -> Result<()> {
+        let service = shared_service()?;
+        let sec_level = service.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT)?;
+        let key = KeyDescriptor {
+            domain: Domain::APP,
+            nspace: -1,
+            alias: Some("simulator_key".to_string()),
+            blob: None,
+        };
+        sec_level.generateKey(&key, None, &ec_signing_params(), 0, &[])?;
+
+        let entries = service.listEntries(Domain::APP, -1)?;
+        assert_eq!(1, entries.len());
+        assert_eq!(key.alias, entries[0].alias);
+
+        service.deleteKey(&key)?;
+        assert!(service.listEntries(Domain::APP, -1)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_certificate_request() -> Result<()> {
+        let service = shared_service()?;
+        let sec_level = service.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT)?;
+        // A namespace of its own keeps the key out of the other tests' listings.
+        let key = KeyDescriptor {
+            domain: Domain::SELINUX,
+            nspace: 100,
+            alias: Some("csr_key".to_string()),
+            blob: None,
+        };
+        sec_level.generateKey(&key, None, &ec_signing_params(), 0, &[])?;
+
+        // DER encoding of the name CN=test.
+        let subject = [
+            0x30, 0x0f, 0x31, 0x0d, 0x30, 0x0b, 0x06, 0x03, 0x55, 0x04, 0x03, 0x0c, 0x04, b't',
+            b'e', b's', b't',
+        ];
+        let request = KeystoreSecurityLevel::get(SecurityLevel::TRUSTED_ENVIRONMENT)
+            .expect("The simulator must have a TEE security level.")
+            .get_certificate_request(&key, &subject, None)?;
+        assert!(!request.is_empty());
+
+        service.deleteKey(&key)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_unlock_migrates_super_key_to_hw_bound_kdf() -> Result<()> {
+        shared_service()?;
+        let mut db = KeystoreDB::new(&DB_PATH.read().unwrap(), None)?;
+        // A user of its own, so that the other tests do not interfere.
+        let user_id = 42;
+        let pw: Password = b"correct horse battery staple"[..].into();
+        let super_key = generate_aes256_key()?;
+        // Super keys stored before the upgrade are wrapped without binding to the device.
+        let (blob, metadata) = SuperKeyManager::encrypt_with_password(&super_key, &pw)?;
+        db.store_super_key(user_id, &USER_SUPER_KEY, &blob, &metadata, &KeyMetaData::new())?;
+
+        let mut unlock = || {
+            SUPER_KEY.write().unwrap().check_and_unlock_super_key(
+                &mut db,
+                &LEGACY_IMPORTER,
+                user_id,
+                &pw,
+            )
+        };
+        assert!(matches!(unlock()?, UserState::LskfUnlocked(_)));
+        // The migrated super key can be unwrapped, too.
+        assert!(matches!(unlock()?, UserState::LskfUnlocked(_)));
+
+        let (_, entry) = db.load_super_key(&USER_SUPER_KEY, user_id)?.expect("No super key.");
+        let (_, metadata) = entry.key_blob_info().as_ref().expect("No super key blob.");
+        assert_eq!(metadata.hw_bound_kdf(), Some(&true));
+        Ok(())
+    }
+
+    #[test]
+    fn test_escrow_and_unlock_user_super_keys() -> Result<()> {
+        shared_service()?;
+        let mut db = KeystoreDB::new(&DB_PATH.read().unwrap(), None)?;
+        // A user of its own, so that the other tests do not interfere.
+        let user_id = 539;
+        let pw: Password = b"correct horse battery staple"[..].into();
+        let escrow_secret: Password = b"resume on reboot"[..].into();
+        let (blob, metadata) =
+            SuperKeyManager::encrypt_with_password(&generate_aes256_key()?, &pw)?;
+        db.store_super_key(user_id, &USER_SUPER_KEY, &blob, &metadata, &KeyMetaData::new())?;
+
+        // Unlock the user with the LSKF and escrow its super key before the reboot.
+        let mut skm = SuperKeyManager::default();
+        let super_key =
+            match skm.check_and_unlock_super_key(&mut db, &LEGACY_IMPORTER, user_id, &pw)? {
+                UserState::LskfUnlocked(super_key) => super_key,
+                _ => panic!("The user was not unlocked."),
+            };
+        let (data, iv, tag) = super_key.encrypt(b"super encrypted key blob")?;
+        skm.escrow_user_super_keys(&mut db, user_id, &escrow_secret)?;
+        assert!(db.load_super_key(&USER_SUPER_KEY_ESCROW, user_id)?.is_some());
+
+        // After the reboot, the escrowed key unlocks the user without the LSKF. There were no
+        // screen lock bound keys in memory, so none were restored.
+        let mut skm = SuperKeyManager::default();
+        assert!(!skm.unlock_user_with_escrow(&mut db, user_id, &escrow_secret)?);
+        let restored = skm.get_per_boot_key_by_user_id(user_id).expect("No per boot key.");
+        assert_eq!(&restored.decrypt(&data, &iv, &tag)?[..], b"super encrypted key blob");
+
+        // The escrowed copy can be used only once.
+        assert!(db.load_super_key(&USER_SUPER_KEY_ESCROW, user_id)?.is_none());
+        let e = SuperKeyManager::default()
+            .unlock_user_with_escrow(&mut db, user_id, &escrow_secret)
+            .unwrap_err();
+        assert_eq!(
+            Some(&Error::Rc(ResponseCode::KEY_NOT_FOUND)),
+            e.root_cause().downcast_ref::<Error>()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_operation_bound_auth_token_keeps_unbound_token() -> Result<()> {
+        shared_service()?;
+        // Auth tokens are process wide, so the test uses a secure user id of its own.
+        const SID: i64 = 608;
+        let hat = |challenge: i64| HardwareAuthToken {
+            challenge,
+            userId: SID,
+            authenticatorId: 0,
+            authenticatorType: HardwareAuthenticatorType::PASSWORD,
+            timestamp: Timestamp { milliSeconds: 0 },
+            mac: vec![],
+        };
+        let key_properties = |key_time_out: Option<i32>| {
+            let mut params = vec![
+                KsKeyParameterValue::KeyPurpose(KeyPurpose::SIGN),
+                KsKeyParameterValue::UserSecureID(SID),
+                KsKeyParameterValue::HardwareAuthenticatorType(HardwareAuthenticatorType::PASSWORD),
+            ];
+            params.extend(key_time_out.map(KsKeyParameterValue::AuthTimeout));
+            let params = params
+                .into_iter()
+                .map(|value| KsKeyParameter::new(value, SecurityLevel::TRUSTED_ENVIRONMENT))
+                .collect::<Vec<_>>();
+            (1, params)
+        };
+
+        ENFORCEMENTS.add_auth_token(hat(0));
+
+        // An auth per operation key waits for the token with the challenge of its operation.
+        let challenge = 0x608;
+        let (_, mut auth_info) = ENFORCEMENTS.authorize_create(
+            KeyPurpose::SIGN,
+            Some(&key_properties(None)),
+            &[],
+            false,
+        )?;
+        assert!(auth_info.finalize_create_authorization(challenge).is_some());
+        ENFORCEMENTS.add_auth_token(hat(challenge));
+
+        // The token consumed by the operation did not replace the token of the authenticator,
+        // so timeout bound keys can still be used.
+        ENFORCEMENTS.authorize_create(
+            KeyPurpose::SIGN,
+            Some(&key_properties(Some(60))),
+            &[],
+            false,
+        )?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_unauthorized_use_does_not_count_towards_max_uses_per_boot() -> Result<()> {
+        shared_service()?;
+        // Uses are counted by key id and auth tokens are process wide, so the test uses a key
+        // id and a secure user id of its own.
+        const KEY_ID: i64 = 531;
+        const SID: i64 = 531;
+        let key_properties = |auth: Vec<KsKeyParameterValue>| {
+            let params = vec![
+                KsKeyParameterValue::KeyPurpose(KeyPurpose::SIGN),
+                KsKeyParameterValue::MaxUsesPerBoot(1),
+            ]
+            .into_iter()
+            .chain(auth)
+            .map(|value| KsKeyParameter::new(value, SecurityLevel::TRUSTED_ENVIRONMENT))
+            .collect::<Vec<_>>();
+            (KEY_ID, params)
+        };
+        let authorize = |key_properties: &(i64, Vec<KsKeyParameter>)| {
+            ENFORCEMENTS.authorize_create(KeyPurpose::SIGN, Some(key_properties), &[], false)
+        };
+
+        // There is no auth token for the secure user id, so these uses are not authorized.
+        let auth_bound = key_properties(vec![
+            KsKeyParameterValue::UserSecureID(SID),
+            KsKeyParameterValue::HardwareAuthenticatorType(HardwareAuthenticatorType::PASSWORD),
+            KsKeyParameterValue::AuthTimeout(60),
+        ]);
+        for _ in 0..3 {
+            let e = authorize(&auth_bound).unwrap_err();
+            assert_eq!(
+                Some(&Error::Km(ErrorCode::KEY_USER_NOT_AUTHENTICATED)),
+                e.root_cause().downcast_ref::<Error>()
+            );
+        }
+
+        // The failed attempts did not use up the only use of the key.
+        let no_auth = key_properties(vec![KsKeyParameterValue::NoAuthRequired]);
+        authorize(&no_auth)?;
+        let e = authorize(&no_auth).unwrap_err();
+        assert_eq!(
+            Some(&Error::Km(ErrorCode::KEY_MAX_OPS_EXCEEDED)),
+            e.root_cause().downcast_ref::<Error>()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_failed_key_generation_leaves_no_entry() -> Result<()> {
+        let service = shared_service()?;
+        let sec_level = service.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT)?;
+        let key = KeyDescriptor {
+            domain: Domain::SELINUX,
+            nspace: 580,
+            alias: Some("failed_key".to_string()),
+            blob: None,
+        };
+        let mut params = ec_signing_params();
+        params.push(param(
+            Tag::APPLICATION_DATA,
+            KeyParameterValue::Blob(INJECT_GENERATE_FAILURE.to_vec()),
+        ));
+
+        // The KeyMint error is passed on to the caller, and no key entry is left behind.
+        let e = sec_level.generateKey(&key, None, &params, 0, &[]).unwrap_err();
+        assert_eq!(ErrorCode::UNKNOWN_ERROR.0, e.service_specific_error());
+        let e = service.getKeyEntry(&key).unwrap_err();
+        assert_eq!(ResponseCode::KEY_NOT_FOUND.0, e.service_specific_error());
+        assert!(service.listEntries(Domain::SELINUX, 580)?.is_empty());
+        Ok(())
+    }
+}
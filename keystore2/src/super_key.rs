@@ -13,7 +13,7 @@
 // limitations under the License.
 
 use crate::{
-    boot_level_keys::{get_level_zero_key, BootLevelKeyCache},
+    boot_level_keys::{get_level_zero_key, BootLevelKeyCache, MAX_MAX_BOOT_LEVEL},
     database::BlobMetaData,
     database::BlobMetaEntry,
     database::EncryptedBy,
@@ -24,11 +24,12 @@ use crate::{
     enforcements::Enforcements,
     error::Error,
     error::ResponseCode,
+    hw_bound_kdf,
     key_parameter::{KeyParameter, KeyParameterValue},
     legacy_blob::LegacyBlobLoader,
     legacy_importer::LegacyImporter,
     raw_device::KeyMintDevice,
-    utils::{watchdog as wd, AesGcm, AID_KEYSTORE},
+    utils::{watchdog as wd, AesGcm, AesGcmKey, AID_KEYSTORE},
 };
 use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
     Algorithm::Algorithm, BlockMode::BlockMode, HardwareAuthToken::HardwareAuthToken,
@@ -52,7 +53,6 @@ use std::{
 };
 use std::{convert::TryFrom, ops::Deref};
 
-const MAX_MAX_BOOT_LEVEL: usize = 1_000_000_000;
 /// Allow up to 15 seconds between the user unlocking using a biometric, and the auth
 /// token being used to unlock in [`SuperKeyManager::try_unlock_user_with_biometric`].
 /// This seems short enough for security purposes, while long enough that even the
@@ -99,6 +99,24 @@ pub const USER_SCREEN_LOCK_BOUND_P521_KEY: SuperKeyType = SuperKeyType {
     algorithm: SuperEncryptionAlgorithm::EcdhP521,
 };
 
+/// Copies of the above keys escrowed for Resume-on-Reboot. They are encrypted with an escrow
+/// secret provided by the recovery system service instead of the LSKF, and are deleted as soon
+/// as they were used to unlock the user after the reboot.
+pub const USER_SUPER_KEY_ESCROW: SuperKeyType = SuperKeyType {
+    alias: "USER_SUPER_KEY_ESCROW",
+    algorithm: SuperEncryptionAlgorithm::Aes256Gcm,
+};
+/// Escrowed copy of `USER_SCREEN_LOCK_BOUND_KEY`, see `USER_SUPER_KEY_ESCROW`.
+pub const USER_SCREEN_LOCK_BOUND_KEY_ESCROW: SuperKeyType = SuperKeyType {
+    alias: "USER_SCREEN_LOCK_BOUND_KEY_ESCROW",
+    algorithm: SuperEncryptionAlgorithm::Aes256Gcm,
+};
+/// Escrowed copy of `USER_SCREEN_LOCK_BOUND_P521_KEY`, see `USER_SUPER_KEY_ESCROW`.
+pub const USER_SCREEN_LOCK_BOUND_P521_KEY_ESCROW: SuperKeyType = SuperKeyType {
+    alias: "USER_SCREEN_LOCK_BOUND_P521_KEY_ESCROW",
+    algorithm: SuperEncryptionAlgorithm::EcdhP521,
+};
+
 /// Superencryption to apply to a new key.
 #[derive(Debug, Clone, Copy)]
 pub enum SuperEncryptionType {
@@ -173,6 +191,16 @@ impl AesGcm for SuperKey {
     }
 }
 
+/// The per-boot super key of a user as handed out by
+/// `SuperKeyManager::get_per_boot_key_by_user_id`. Per-boot keys are always AES keys.
+struct PerBootKey(Arc<SuperKey>);
+
+impl AesGcmKey for PerBootKey {
+    fn key(&self) -> &[u8] {
+        &self.0.key
+    }
+}
+
 /// A SuperKey that has been encrypted with an AES-GCM key. For
 /// encryption the key is in memory, and for decryption it is in KM.
 struct LockedKey {
@@ -213,7 +241,7 @@ impl LockedKey {
             KeyParameterValue::MacLength(128),
         ];
         let key_params: Vec<KmKeyParameter> = key_params.into_iter().map(|x| x.into()).collect();
-        let key = ZVec::try_from(km_dev.use_key_in_one_step(
+        let key = km_dev.use_key_in_one_step(
             db,
             key_id_guard,
             &key_blob,
@@ -221,7 +249,7 @@ impl LockedKey {
             &key_params,
             Some(auth_token),
             &self.ciphertext,
-        )?)?;
+        )?;
         Ok(Arc::new(SuperKey { algorithm: self.algorithm, key, id: self.id, reencrypt_with }))
     }
 }
@@ -349,13 +377,21 @@ impl SuperKeyManager {
         self.data
             .boot_level_key_cache
             .as_ref()
-            .map_or(false, |c| c.lock().unwrap().level_accessible(boot_level as usize))
+            .zip(usize::try_from(boot_level).ok())
+            .map_or(false, |(c, level)| c.lock().unwrap().level_accessible(level))
     }
 
     pub fn forget_all_keys_for_user(&mut self, user: UserId) {
         self.data.user_keys.remove(&user);
     }
 
+    /// Forgets the in-memory super keys of all users. This is used when all keys, including the
+    /// super keys, were deleted.
+    pub fn forget_all_keys(&mut self) {
+        self.data.user_keys.clear();
+        self.data.key_index.clear();
+    }
+
     fn install_per_boot_key_for_user(
         &mut self,
         user: UserId,
@@ -373,11 +409,13 @@ impl SuperKeyManager {
             SuperKeyIdentifier::DatabaseId(id) => {
                 self.data.key_index.get(id).and_then(|k| k.upgrade())
             }
+            // Negative boot levels are never accessible.
             SuperKeyIdentifier::BootLevel(level) => self
                 .data
                 .boot_level_key_cache
                 .as_ref()
-                .map(|b| b.lock().unwrap().aes_key(*level as usize))
+                .zip(usize::try_from(*level).ok())
+                .map(|(b, level)| b.lock().unwrap().aes_key(level))
                 .transpose()
                 .context("In lookup_key: aes_key failed")?
                 .flatten()
@@ -392,12 +430,15 @@ impl SuperKeyManager {
         })
     }
 
+    /// Returns the per-boot super key of the given user if the user is unlocked. The raw key
+    /// material is exposed through `AesGcmKey`, because the legacy importer needs it to decrypt
+    /// blobs that were encrypted with AES-CBC before Android P.
     pub fn get_per_boot_key_by_user_id(
         &self,
         user_id: UserId,
-    ) -> Option<Arc<dyn AesGcm + Send + Sync>> {
+    ) -> Option<Arc<dyn AesGcmKey + Send + Sync>> {
         self.get_per_boot_key_by_user_id_internal(user_id)
-            .map(|sk| -> Arc<dyn AesGcm + Send + Sync> { sk })
+            .map(|sk| -> Arc<dyn AesGcmKey + Send + Sync> { Arc::new(PerBootKey(sk)) })
     }
 
     fn get_per_boot_key_by_user_id_internal(&self, user_id: UserId) -> Option<Arc<SuperKey>> {
@@ -444,7 +485,7 @@ impl SuperKeyManager {
             )
             .context("In unlock_user_key: Failed to get key id.")?;
 
-        self.populate_cache_from_super_key_blob(user, USER_SUPER_KEY.algorithm, entry, pw)
+        self.populate_cache_from_super_key_blob(db, user, USER_SUPER_KEY.algorithm, entry, pw)
             .context("In unlock_user_key.")?;
         Ok(())
     }
@@ -553,9 +594,12 @@ impl SuperKeyManager {
             .context("In check_and_unlock_super_key. Failed to load super key")?;
 
         match result {
-            Some((_, entry)) => {
+            Some((key_id_guard, entry)) => {
+                // No guard may be held while the key is unwrapped, see
+                // hw_bound_kdf::bind_to_device.
+                drop(key_id_guard);
                 let super_key = self
-                    .populate_cache_from_super_key_blob(user_id, alias.algorithm, entry, pw)
+                    .populate_cache_from_super_key_blob(db, user_id, alias.algorithm, entry, pw)
                     .context("In check_and_unlock_super_key.")?;
                 Ok(UserState::LskfUnlocked(super_key))
             }
@@ -587,8 +631,9 @@ impl SuperKeyManager {
                 .context("In check_and_initialize_super_key: Failed to generate AES 256 key.")?;
             // Derive an AES256 key from the password and re-encrypt the super key
             // before we insert it in the database.
-            let (encrypted_super_key, blob_metadata) = Self::encrypt_with_password(&super_key, pw)
-                .context("In check_and_initialize_super_key.")?;
+            let (encrypted_super_key, blob_metadata) =
+                Self::encrypt_with_password_hw_bound(db, &super_key, pw)
+                    .context("In check_and_initialize_super_key.")?;
 
             let key_entry = db
                 .store_super_key(
@@ -602,6 +647,7 @@ impl SuperKeyManager {
 
             let super_key = self
                 .populate_cache_from_super_key_blob(
+                    db,
                     user_id,
                     USER_SUPER_KEY.algorithm,
                     key_entry,
@@ -617,21 +663,27 @@ impl SuperKeyManager {
     // Helper function to populate super key cache from the super key blob loaded from the database.
     fn populate_cache_from_super_key_blob(
         &mut self,
+        db: &mut KeystoreDB,
         user_id: UserId,
         algorithm: SuperEncryptionAlgorithm,
         entry: KeyEntry,
         pw: &Password,
     ) -> Result<Arc<SuperKey>> {
-        let super_key = Self::extract_super_key_from_key_entry(algorithm, entry, pw, None)
+        let needs_migration = Self::needs_hw_bound_kdf_migration(&entry);
+        let super_key = Self::extract_super_key_from_key_entry(db, algorithm, entry, pw, None)
             .context(
                 "In populate_cache_from_super_key_blob. Failed to extract super key from key entry",
             )?;
+        if needs_migration {
+            Self::migrate_to_hw_bound_kdf(db, &USER_SUPER_KEY, user_id, &super_key, pw);
+        }
         self.install_per_boot_key_for_user(user_id, super_key.clone())?;
         Ok(super_key)
     }
 
     /// Extracts super key from the entry loaded from the database.
     pub fn extract_super_key_from_key_entry(
+        db: &mut KeystoreDB,
         algorithm: SuperEncryptionAlgorithm,
         entry: KeyEntry,
         pw: &Password,
@@ -649,6 +701,12 @@ impl SuperKeyManager {
                     let key = pw.derive_key(Some(salt), AES_256_KEY_LENGTH).context(
                         "In extract_super_key_from_key_entry: Failed to generate key from password.",
                     )?;
+                    let key = match metadata.hw_bound_kdf() {
+                        Some(true) => hw_bound_kdf::bind_to_device(db, &key).context(
+                            "In extract_super_key_from_key_entry: Failed to bind key to device.",
+                        )?,
+                        _ => key,
+                    };
 
                     aes_gcm_decrypt(blob, iv, tag, &key).context(
                         "In extract_super_key_from_key_entry: Failed to decrypt key blob.",
@@ -683,14 +741,38 @@ impl SuperKeyManager {
     pub fn encrypt_with_password(
         super_key: &[u8],
         pw: &Password,
+    ) -> Result<(Vec<u8>, BlobMetaData)> {
+        Self::encrypt_with_password_internal(None, super_key, pw)
+    }
+
+    /// Like `encrypt_with_password`, but the key derived from the password is additionally bound
+    /// to this device, so that the password cannot be brute forced offline. Used for super keys
+    /// wrapped with the user's LSKF.
+    fn encrypt_with_password_hw_bound(
+        db: &mut KeystoreDB,
+        super_key: &[u8],
+        pw: &Password,
+    ) -> Result<(Vec<u8>, BlobMetaData)> {
+        Self::encrypt_with_password_internal(Some(db), super_key, pw)
+    }
+
+    fn encrypt_with_password_internal(
+        db: Option<&mut KeystoreDB>,
+        super_key: &[u8],
+        pw: &Password,
     ) -> Result<(Vec<u8>, BlobMetaData)> {
         let salt = generate_salt().context("In encrypt_with_password: Failed to generate salt.")?;
-        let derived_key = pw
+        let mut derived_key = pw
             .derive_key(Some(&salt), AES_256_KEY_LENGTH)
             .context("In encrypt_with_password: Failed to derive password.")?;
         let mut metadata = BlobMetaData::new();
         metadata.add(BlobMetaEntry::EncryptedBy(EncryptedBy::Password));
         metadata.add(BlobMetaEntry::Salt(salt));
+        if let Some(db) = db {
+            derived_key = hw_bound_kdf::bind_to_device(db, &derived_key)
+                .context("In encrypt_with_password: Failed to bind key to device.")?;
+            metadata.add(BlobMetaEntry::HwBoundKdf(true));
+        }
         let (encrypted_key, iv, tag) = aes_gcm_encrypt(super_key, &derived_key)
             .context("In encrypt_with_password: Failed to encrypt new super key.")?;
         metadata.add(BlobMetaEntry::Iv(iv));
@@ -698,6 +780,46 @@ impl SuperKeyManager {
         Ok((encrypted_key, metadata))
     }
 
+    /// Returns true if the entry holds a super key that is wrapped with a key derived from a
+    /// password, which is not bound to this device yet.
+    fn needs_hw_bound_kdf_migration(entry: &KeyEntry) -> bool {
+        entry.key_blob_info().as_ref().map_or(false, |(_, metadata)| {
+            matches!(metadata.encrypted_by(), Some(EncryptedBy::Password))
+                && metadata.hw_bound_kdf().is_none()
+        })
+    }
+
+    /// Super keys that were wrapped before the key derived from the password was bound to the
+    /// device are rewrapped as soon as they were unwrapped with the password. Failures are only
+    /// logged, so that the unlock succeeds regardless and the migration is retried with the
+    /// next unlock.
+    fn migrate_to_hw_bound_kdf(
+        db: &mut KeystoreDB,
+        key_type: &SuperKeyType,
+        user_id: UserId,
+        super_key: &SuperKey,
+        pw: &Password,
+    ) {
+        // The super key is wrapped before its entry is locked, see hw_bound_kdf::bind_to_device.
+        let result = Self::encrypt_with_password_hw_bound(db, &super_key.key, pw).and_then(
+            |(blob, blob_metadata)| {
+                let (key_id_guard, _) = db
+                    .load_super_key(key_type, user_id)
+                    .context("Trying to load super key.")?
+                    .ok_or_else(Error::sys)
+                    .context("No super key.")?;
+                db.replace_super_key_blobs(&[(key_id_guard, blob, blob_metadata)])
+            },
+        );
+        if let Err(e) = result {
+            log::error!(
+                "In migrate_to_hw_bound_kdf: Failed to migrate {}: {:?}",
+                key_type.alias,
+                e
+            );
+        }
+    }
+
     // Encrypt the given key blob with the user's super key, if the super key exists and the device
     // is unlocked. If the super key exists and the device is locked, or LSKF is not setup,
     // return error. Note that it is out of the scope of this function to check if super encryption
@@ -780,9 +902,12 @@ impl SuperKeyManager {
                         db.load_super_key(&USER_SCREEN_LOCK_BOUND_P521_KEY, user_id).context(
                             "In handle_super_encryption_on_key_init: load_super_key failed.",
                         )?;
-                    let (key_id_guard, key_entry) = loaded.ok_or_else(Error::sys).context(
-                        "In handle_super_encryption_on_key_init: User ECDH key missing.",
-                    )?;
+                    // The ECDH key is created when the user unlocks with their LSKF for the
+                    // first time. Until then, no screen lock bound keys can be created.
+                    let (key_id_guard, key_entry) =
+                        loaded.ok_or(Error::Rc(ResponseCode::LOCKED)).context(
+                            "In handle_super_encryption_on_key_init: User ECDH key missing.",
+                        )?;
                     let public_key =
                         key_entry.metadata().sec1_public_key().ok_or_else(Error::sys).context(
                             "In handle_super_encryption_on_key_init: sec1_public_key missing.",
@@ -847,13 +972,21 @@ impl SuperKeyManager {
         reencrypt_with: Option<Arc<SuperKey>>,
     ) -> Result<Arc<SuperKey>> {
         let loaded_key = db.load_super_key(key_type, user_id)?;
-        if let Some((_, key_entry)) = loaded_key {
-            Ok(Self::extract_super_key_from_key_entry(
+        if let Some((key_id_guard, key_entry)) = loaded_key {
+            // No guard may be held while the key is unwrapped, see hw_bound_kdf::bind_to_device.
+            drop(key_id_guard);
+            let needs_migration = Self::needs_hw_bound_kdf_migration(&key_entry);
+            let super_key = Self::extract_super_key_from_key_entry(
+                db,
                 key_type.algorithm,
                 key_entry,
                 password,
                 reencrypt_with,
-            )?)
+            )?;
+            if needs_migration {
+                Self::migrate_to_hw_bound_kdf(db, key_type, user_id, &super_key, password);
+            }
+            Ok(super_key)
         } else {
             let (super_key, public_key) = match key_type.algorithm {
                 SuperEncryptionAlgorithm::Aes256Gcm => (
@@ -877,7 +1010,7 @@ impl SuperKeyManager {
             // Derive an AES256 key from the password and re-encrypt the super key
             // before we insert it in the database.
             let (encrypted_super_key, blob_metadata) =
-                Self::encrypt_with_password(&super_key, password)
+                Self::encrypt_with_password_hw_bound(db, &super_key, password)
                     .context("In get_or_create_super_key.")?;
             let mut key_metadata = KeyMetaData::new();
             if let Some(pk) = public_key {
@@ -961,6 +1094,10 @@ impl SuperKeyManager {
     ) {
         log::info!("Locking screen bound for user {} sids {:?}", user_id, unlocking_sids);
         let mut entry = self.data.user_keys.entry(user_id).or_default();
+        // Only the sids given with the most recent lock event may unlock the device. Drop any
+        // biometric unlock state left over from an earlier lock, e.g., if biometric unlock was
+        // disabled in the meantime.
+        entry.biometric_unlock = None;
         if !unlocking_sids.is_empty() {
             if let (Some(aes), Some(ecdh)) = (
                 entry.screen_lock_bound.as_ref().cloned(),
@@ -1031,12 +1168,19 @@ impl SuperKeyManager {
 
     /// User has unlocked, not using a password. See if any of our stored auth tokens can be used
     /// to unlock the keys protecting UNLOCKED_DEVICE_REQUIRED keys.
+    /// Returns Ok(true) if these keys are available after the call. Ok(false) indicates a weak
+    /// unlock, e.g., by a trust agent or a biometric that is not strong enough to have been
+    /// enrolled for biometric unlock, which must not make UNLOCKED_DEVICE_REQUIRED keys usable.
     pub fn try_unlock_user_with_biometric(
         &mut self,
         db: &mut KeystoreDB,
         user_id: UserId,
-    ) -> Result<()> {
+    ) -> Result<bool> {
         let mut entry = self.data.user_keys.entry(user_id).or_default();
+        if entry.screen_lock_bound.is_some() && entry.screen_lock_bound_private.is_some() {
+            // Nothing to unlock.
+            return Ok(true);
+        }
         if let Some(biometric) = entry.biometric_unlock.as_ref() {
             let (key_id_guard, key_entry) = db
                 .load_key_entry(
@@ -1082,7 +1226,7 @@ impl SuperKeyManager {
                                 "In try_unlock_user_with_biometric: ",
                                 "Successfully unlocked with biometric"
                             ));
-                            return Ok(());
+                            return Ok(true);
                         }
                         Err(e) => {
                             log::warn!("In try_unlock_user_with_biometric: attempt failed: {:?}", e)
@@ -1091,7 +1235,7 @@ impl SuperKeyManager {
                 }
             }
         }
-        Ok(())
+        Ok(false)
     }
 
     /// Returns the keystore locked state of the given user. It requires the thread local
@@ -1123,7 +1267,8 @@ impl SuperKeyManager {
     /// If the given user is unlocked:
     /// * and `password` is None, the user is reset, all authentication bound keys are deleted and
     ///   `Ok(UserState::Uninitialized)` is returned.
-    /// * and `password` is Some, `Ok(UserState::LskfUnlocked)` is returned.
+    /// * and `password` is Some, the user's super keys are re-encrypted with the new password and
+    ///   `Ok(UserState::LskfUnlocked)` is returned.
     /// If the given user is locked:
     /// * and the user was initialized before, `Ok(UserState::Locked)` is returned.
     /// * and the user was not initialized before:
@@ -1148,8 +1293,13 @@ impl SuperKeyManager {
                 Ok(UserState::Uninitialized)
             }
             Some(super_key) => {
-                // Keystore won't be notified when changing to a new password when LSKF is
-                // already setup. Therefore, ideally this path wouldn't be reached.
+                // The user changed their password. Re-encrypt the super keys with the new
+                // password, so that the user's keys survive the change.
+                if let Some(password) = password {
+                    self.rewrap_super_keys_for_user(db, user_id, password).context(
+                        "In reset_or_init_user_and_get_user_state: Trying to rewrap super keys.",
+                    )?;
+                }
                 Ok(UserState::LskfUnlocked(super_key))
             }
             None => {
@@ -1162,6 +1312,213 @@ impl SuperKeyManager {
         }
     }
 
+    /// Re-encrypts the password protected super keys of the given user with a key derived from
+    /// the new password. All of these keys must be in memory, i.e., the user must be unlocked.
+    /// Super keys that were never created are skipped. All blobs are replaced in a single
+    /// transaction, so that an interrupted password change cannot leave the user with super keys
+    /// encrypted by different passwords.
+    fn rewrap_super_keys_for_user(
+        &self,
+        db: &mut KeystoreDB,
+        user_id: UserId,
+        password: &Password,
+    ) -> Result<()> {
+        let entry = self.data.user_keys.get(&user_id);
+        let keys = [
+            (&USER_SUPER_KEY, entry.and_then(|e| e.per_boot.clone())),
+            (&USER_SCREEN_LOCK_BOUND_KEY, entry.and_then(|e| e.screen_lock_bound.clone())),
+            (
+                &USER_SCREEN_LOCK_BOUND_P521_KEY,
+                entry.and_then(|e| e.screen_lock_bound_private.clone()),
+            ),
+        ];
+        // All keys are wrapped before their entries are locked, see
+        // hw_bound_kdf::bind_to_device.
+        let mut wrapped = Vec::new();
+        for (key_type, super_key) in keys.iter() {
+            if db
+                .load_super_key(key_type, user_id)
+                .context("In rewrap_super_keys_for_user: Trying to load super key.")?
+                .is_none()
+            {
+                continue;
+            }
+            let super_key = super_key.as_ref().ok_or(Error::Rc(ResponseCode::LOCKED)).context(
+                format!("In rewrap_super_keys_for_user: {} is not unlocked.", key_type.alias),
+            )?;
+            let (blob, blob_metadata) =
+                Self::encrypt_with_password_hw_bound(db, &super_key.key, password)
+                    .context("In rewrap_super_keys_for_user.")?;
+            wrapped.push((key_type, super_key, blob, blob_metadata));
+        }
+        let mut blobs = Vec::new();
+        for (key_type, super_key, blob, blob_metadata) in wrapped {
+            let key_id_guard = db
+                .load_super_key(key_type, user_id)
+                .context("In rewrap_super_keys_for_user: Trying to load super key.")?
+                .map(|(key_id_guard, _)| key_id_guard);
+            if !matches!(
+                (&super_key.id, &key_id_guard),
+                (SuperKeyIdentifier::DatabaseId(id), Some(guard)) if *id == guard.id()
+            ) {
+                return Err(Error::sys()).context(format!(
+                    "In rewrap_super_keys_for_user: {} in memory does not match the database.",
+                    key_type.alias
+                ));
+            }
+            // Unwrap cannot panic, because the match above requires a guard.
+            blobs.push((key_id_guard.unwrap(), blob, blob_metadata));
+        }
+        db.replace_super_key_blobs(&blobs).context("In rewrap_super_keys_for_user.")
+    }
+
+    /// Wraps the in-memory super keys of the given user with the escrow secret provided by the
+    /// recovery system service and stores them in the database. This is used by the
+    /// Resume-on-Reboot flow before rebooting for an OTA update, so that the user's keys can be
+    /// unlocked after the reboot without the LSKF. Previously escrowed keys are replaced in a
+    /// single transaction. The screen lock bound keys are only escrowed if they are in memory.
+    pub fn escrow_user_super_keys(
+        &self,
+        db: &mut KeystoreDB,
+        user_id: UserId,
+        escrow_secret: &Password,
+    ) -> Result<()> {
+        let entry = self.data.user_keys.get(&user_id);
+        if entry.and_then(|e| e.per_boot.as_ref()).is_none() {
+            return Err(Error::Rc(ResponseCode::LOCKED))
+                .context("In escrow_user_super_keys: The user's super key is not unlocked.");
+        }
+        let keys = [
+            (&USER_SUPER_KEY_ESCROW, entry.and_then(|e| e.per_boot.clone())),
+            (&USER_SCREEN_LOCK_BOUND_KEY_ESCROW, entry.and_then(|e| e.screen_lock_bound.clone())),
+            (
+                &USER_SCREEN_LOCK_BOUND_P521_KEY_ESCROW,
+                entry.and_then(|e| e.screen_lock_bound_private.clone()),
+            ),
+        ];
+        let mut escrowed = Vec::new();
+        for (escrow_type, super_key) in keys.iter() {
+            let blob = match super_key {
+                Some(super_key) => Some(
+                    Self::encrypt_with_password(&super_key.key, escrow_secret)
+                        .context("In escrow_user_super_keys.")?,
+                ),
+                None => None,
+            };
+            escrowed.push((*escrow_type, blob));
+        }
+        db.replace_super_keys(user_id, &escrowed)
+            .context("In escrow_user_super_keys: Trying to store escrowed keys.")
+    }
+
+    /// Unlocks the super keys of the given user that were escrowed by `escrow_user_super_keys`
+    /// before the reboot, without the user's LSKF. The escrowed copies are deleted once they
+    /// were unwrapped successfully, so that they can be used only once.
+    /// Returns Ok(true) if the screen lock bound keys were restored as well.
+    pub fn unlock_user_with_escrow(
+        &mut self,
+        db: &mut KeystoreDB,
+        user_id: UserId,
+        escrow_secret: &Password,
+    ) -> Result<bool> {
+        let mut restored = Vec::new();
+        for (key_type, escrow_type) in [
+            (&USER_SUPER_KEY, &USER_SUPER_KEY_ESCROW),
+            (&USER_SCREEN_LOCK_BOUND_KEY, &USER_SCREEN_LOCK_BOUND_KEY_ESCROW),
+            (&USER_SCREEN_LOCK_BOUND_P521_KEY, &USER_SCREEN_LOCK_BOUND_P521_KEY_ESCROW),
+        ]
+        .iter()
+        {
+            let escrowed = db
+                .load_super_key(escrow_type, user_id)
+                .context("In unlock_user_with_escrow: Trying to load escrowed key.")?;
+            let original = db
+                .load_super_key(key_type, user_id)
+                .context("In unlock_user_with_escrow: Trying to load super key.")?;
+            restored.push(match (escrowed, original) {
+                (Some((_, escrowed_entry)), Some((key_id_guard, _))) => {
+                    // The escrowed copy must take the identity of the original key, because
+                    // that is the id recorded in the metadata of the keys encrypted with it.
+                    // No guard may be held while the key is unwrapped, see
+                    // hw_bound_kdf::bind_to_device.
+                    let key_id = key_id_guard.id();
+                    drop(key_id_guard);
+                    let escrowed_key = Self::extract_super_key_from_key_entry(
+                        db,
+                        key_type.algorithm,
+                        escrowed_entry,
+                        escrow_secret,
+                        None,
+                    )
+                    .context("In unlock_user_with_escrow: Failed to unwrap escrowed key.")?;
+                    Some((
+                        key_type.algorithm,
+                        escrowed_key.key.try_clone().context("In unlock_user_with_escrow.")?,
+                        key_id,
+                    ))
+                }
+                _ => None,
+            });
+        }
+
+        let screen_lock_bound_private = restored.pop().flatten();
+        let screen_lock_bound = restored.pop().flatten();
+        let per_boot = match restored.pop().flatten() {
+            Some(per_boot) => per_boot,
+            None => {
+                return Err(Error::Rc(ResponseCode::KEY_NOT_FOUND))
+                    .context("In unlock_user_with_escrow: No escrowed super key.");
+            }
+        };
+
+        db.replace_super_keys(
+            user_id,
+            &[
+                (&USER_SUPER_KEY_ESCROW, None),
+                (&USER_SCREEN_LOCK_BOUND_KEY_ESCROW, None),
+                (&USER_SCREEN_LOCK_BOUND_P521_KEY_ESCROW, None),
+            ],
+        )
+        .context("In unlock_user_with_escrow: Trying to delete escrowed keys.")?;
+
+        let (algorithm, key, id) = per_boot;
+        self.install_per_boot_key_for_user(
+            user_id,
+            Arc::new(SuperKey {
+                algorithm,
+                key,
+                id: SuperKeyIdentifier::DatabaseId(id),
+                reencrypt_with: None,
+            }),
+        )
+        .context("In unlock_user_with_escrow.")?;
+
+        if let (Some((aes_alg, aes_key, aes_id)), Some((ecdh_alg, ecdh_key, ecdh_id))) =
+            (screen_lock_bound, screen_lock_bound_private)
+        {
+            let aes = Arc::new(SuperKey {
+                algorithm: aes_alg,
+                key: aes_key,
+                id: SuperKeyIdentifier::DatabaseId(aes_id),
+                reencrypt_with: None,
+            });
+            let ecdh = Arc::new(SuperKey {
+                algorithm: ecdh_alg,
+                key: ecdh_key,
+                id: SuperKeyIdentifier::DatabaseId(ecdh_id),
+                reencrypt_with: Some(aes.clone()),
+            });
+            self.data.add_key_to_key_index(&aes)?;
+            self.data.add_key_to_key_index(&ecdh)?;
+            let entry = self.data.user_keys.entry(user_id).or_default();
+            entry.screen_lock_bound = Some(aes);
+            entry.screen_lock_bound_private = Some(ecdh);
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
     /// Unlocks the given user with the given password. If the key was already unlocked or unlocking
     /// was successful, `Ok(UserState::LskfUnlocked)` is returned.
     /// If the user was never initialized `Ok(UserState::Uninitialized)` is returned.
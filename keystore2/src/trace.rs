@@ -0,0 +1,116 @@
+// Copyright 2021, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module provides ATrace instrumentation, so that Keystore's stages show up in systrace
+//! and perfetto traces. Trace points are always compiled in. When tracing is off, a trace
+//! point costs a check of the enabled tags, and the section names are not even formatted.
+
+#[cfg(target_os = "android")]
+mod ffi {
+    use std::os::raw::c_char;
+    use std::sync::atomic::AtomicBool;
+
+    // atrace_get_enabled_tags() and atrace_init() are static inline functions of cutils/trace.h
+    // and not exported by libcutils. The state they read is.
+    extern "C" {
+        pub static atrace_is_ready: AtomicBool;
+        pub static atrace_enabled_tags: u64;
+        pub fn atrace_setup();
+        pub fn atrace_begin_body(name: *const c_char);
+        pub fn atrace_end_body();
+        pub fn atrace_int_body(name: *const c_char, value: i32);
+    }
+}
+
+/// Keystore traces under the AIDL tag, because it is an AIDL service and its sections nest
+/// within the binder transactions of its clients.
+#[cfg(target_os = "android")]
+const ATRACE_TAG_AIDL: u64 = 1 << 24;
+
+/// Returns true if Keystore's trace tag is enabled.
+#[cfg(target_os = "android")]
+pub fn is_enabled() -> bool {
+    use std::sync::atomic::Ordering;
+    // Safety: This mirrors atrace_get_enabled_tags() of cutils/trace.h. atrace_setup()
+    // initializes libcutils' tracing state on first use and publishes it with atrace_is_ready.
+    // The enabled tags may be updated concurrently, hence the volatile read.
+    unsafe {
+        if !ffi::atrace_is_ready.load(Ordering::Acquire) {
+            ffi::atrace_setup();
+        }
+        let enabled_tags = std::ptr::read_volatile(std::ptr::addr_of!(ffi::atrace_enabled_tags));
+        enabled_tags & ATRACE_TAG_AIDL != 0
+    }
+}
+
+/// Returns true if Keystore's trace tag is enabled.
+#[cfg(not(target_os = "android"))]
+pub fn is_enabled() -> bool {
+    false
+}
+
+fn to_cstring(name: &str) -> std::ffi::CString {
+    std::ffi::CString::new(name.replace('\0', " ")).unwrap()
+}
+
+/// A trace section that ends when this object is dropped.
+#[must_use = "The trace section ends when this object is dropped."]
+pub struct ScopedTrace {
+    active: bool,
+}
+
+impl Drop for ScopedTrace {
+    fn drop(&mut self) {
+        if self.active {
+            #[cfg(target_os = "android")]
+            // Safety: Ends the section begun in `begin_with`.
+            unsafe {
+                ffi::atrace_end_body()
+            };
+        }
+    }
+}
+
+/// Begins a trace section with the given name. The section ends when the returned object is
+/// dropped.
+pub fn begin(name: &str) -> ScopedTrace {
+    begin_with(|| name.to_string())
+}
+
+/// Like `begin` but the name is only formatted if tracing is enabled.
+pub fn begin_with<F: FnOnce() -> String>(name: F) -> ScopedTrace {
+    if !is_enabled() {
+        return ScopedTrace { active: false };
+    }
+    let _name = to_cstring(&name());
+    #[cfg(target_os = "android")]
+    // Safety: `_name` is a valid nul terminated string, which outlives the call.
+    unsafe {
+        ffi::atrace_begin_body(_name.as_ptr())
+    };
+    ScopedTrace { active: true }
+}
+
+/// Sets the trace counter with the given name to `value`.
+pub fn counter(name: &str, value: i32) {
+    if !is_enabled() {
+        return;
+    }
+    let _name = to_cstring(name);
+    #[cfg(target_os = "android")]
+    // Safety: `_name` is a valid nul terminated string, which outlives the call.
+    unsafe {
+        ffi::atrace_int_body(_name.as_ptr(), value)
+    };
+}
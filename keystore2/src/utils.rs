@@ -15,7 +15,7 @@
 //! This module implements utility functions used by the Keystore 2.0 service
 //! implementation.
 
-use crate::error::{map_binder_status, map_km_error, Error, ErrorCode};
+use crate::error::{map_binder_status, map_km_error, Error, ErrorCode, ResponseCode};
 use crate::key_parameter::KeyParameter;
 use crate::permission;
 use crate::permission::{KeyPerm, KeyPermSet, KeystorePerm};
@@ -24,8 +24,9 @@ use crate::{
     globals::LEGACY_IMPORTER,
 };
 use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
-    IKeyMintDevice::IKeyMintDevice, KeyCharacteristics::KeyCharacteristics,
-    KeyParameter::KeyParameter as KmKeyParameter, Tag::Tag,
+    EcCurve::EcCurve, IKeyMintDevice::IKeyMintDevice, KeyCharacteristics::KeyCharacteristics,
+    KeyParameter::KeyParameter as KmKeyParameter, KeyParameterValue::KeyParameterValue,
+    KeyPurpose::KeyPurpose, Tag::Tag,
 };
 use android_os_permissions_aidl::aidl::android::os::IPermissionController;
 use android_security_apc::aidl::android::security::apc::{
@@ -43,12 +44,22 @@ use keystore2_apc_compat::{
     APC_COMPAT_ERROR_SYSTEM_ERROR,
 };
 use keystore2_crypto::{aes_gcm_decrypt, aes_gcm_encrypt, ZVec};
+use keystore2_selinux as selinux;
 use std::iter::IntoIterator;
 
+/// The simulator calls the services in process, so there is no calling context to check
+/// against. It skips all SELinux permission checks. This is never the case outside of tests.
+fn selinux_checks_skipped() -> bool {
+    cfg!(all(test, feature = "keystore2_simulator"))
+}
+
 /// This function uses its namesake in the permission module and in
 /// combination with with_calling_sid from the binder crate to check
 /// if the caller has the given keystore permission.
 pub fn check_keystore_permission(perm: KeystorePerm) -> anyhow::Result<()> {
+    if selinux_checks_skipped() {
+        return Ok(());
+    }
     ThreadState::with_calling_sid(|calling_sid| {
         permission::check_keystore_permission(
             calling_sid.ok_or_else(Error::sys).context(
@@ -63,6 +74,9 @@ pub fn check_keystore_permission(perm: KeystorePerm) -> anyhow::Result<()> {
 /// combination with with_calling_sid from the binder crate to check
 /// if the caller has the given grant permission.
 pub fn check_grant_permission(access_vec: KeyPermSet, key: &KeyDescriptor) -> anyhow::Result<()> {
+    if selinux_checks_skipped() {
+        return Ok(());
+    }
     ThreadState::with_calling_sid(|calling_sid| {
         permission::check_grant_permission(
             calling_sid.ok_or_else(Error::sys).context(
@@ -82,6 +96,9 @@ pub fn check_key_permission(
     key: &KeyDescriptor,
     access_vector: &Option<KeyPermSet>,
 ) -> anyhow::Result<()> {
+    if selinux_checks_skipped() {
+        return Ok(());
+    }
     ThreadState::with_calling_sid(|calling_sid| {
         permission::check_key_permission(
             ThreadState::get_calling_uid(),
@@ -95,7 +112,47 @@ pub fn check_key_permission(
     })
 }
 
+/// Checks that the caller may list the key entries of the given domain and namespace, and
+/// returns the descriptor of the namespace that is to be listed. Only Domain::APP and
+/// Domain::SELINUX can be listed. By default the calling uid is used as namespace if the
+/// domain is Domain::APP. A caller that does not have the `GET_INFO` permission for the
+/// selected namespace needs the keystore `List` permission, which allows listing any namespace.
+/// In that case the queried namespace is adjusted if a specific uid was selected.
+pub fn check_list_permission(domain: Domain, namespace: i64) -> Result<KeyDescriptor> {
+    let mut k = match domain {
+        Domain::APP => KeyDescriptor {
+            domain,
+            nspace: ThreadState::get_calling_uid() as u64 as i64,
+            ..Default::default()
+        },
+        Domain::SELINUX => KeyDescriptor { domain, nspace: namespace, ..Default::default() },
+        _ => {
+            return Err(Error::perm()).context(
+                "In check_list_permission: Only Domain::APP and Domain::SELINUX can be listed.",
+            )
+        }
+    };
+
+    if let Err(e) = check_key_permission(KeyPerm::GetInfo, &k, &None) {
+        if let Some(selinux::Error::PermissionDenied) =
+            e.root_cause().downcast_ref::<selinux::Error>()
+        {
+            check_keystore_permission(KeystorePerm::List)
+                .context("In check_list_permission: While checking keystore permission.")?;
+            if namespace != -1 {
+                k.nspace = namespace;
+            }
+        } else {
+            return Err(e).context("In check_list_permission: While checking key permission.");
+        }
+    }
+    Ok(k)
+}
+
 /// This function checks whether a given tag corresponds to the access of device identifiers.
+/// The IMEI of a second radio cannot be attested yet: ATTESTATION_ID_SECOND_IMEI was added in
+/// KeyMint V3, which requires version 3 of the keystore2 interface, while Keystore still
+/// implements version 2.
 pub fn is_device_id_attestation_tag(tag: Tag) -> bool {
     matches!(
         tag,
@@ -106,29 +163,194 @@ pub fn is_device_id_attestation_tag(tag: Tag) -> bool {
     )
 }
 
+/// Checks that the purposes requested for a Curve 25519 key select exactly one of the two
+/// flavors of the curve: Ed25519 for SIGN and ATTEST_KEY, or X25519 for AGREE_KEY. Keys on
+/// other curves are not checked. Returns `Error::Km(ErrorCode::INCOMPATIBLE_PURPOSE)` if the
+/// purposes mix the two flavors or include a purpose supported by neither.
+pub fn check_curve_25519_purposes(params: &[KmKeyParameter]) -> Result<()> {
+    if !params.iter().any(|kp| {
+        kp.tag == Tag::EC_CURVE
+            && matches!(kp.value, KeyParameterValue::EcCurve(EcCurve::CURVE_25519))
+    }) {
+        return Ok(());
+    }
+    let mut signing = false;
+    let mut agreement = false;
+    for kp in params.iter().filter(|kp| kp.tag == Tag::PURPOSE) {
+        match kp.value {
+            KeyParameterValue::KeyPurpose(KeyPurpose::SIGN)
+            | KeyParameterValue::KeyPurpose(KeyPurpose::ATTEST_KEY) => signing = true,
+            KeyParameterValue::KeyPurpose(KeyPurpose::AGREE_KEY) => agreement = true,
+            ref v => {
+                return Err(Error::Km(ErrorCode::INCOMPATIBLE_PURPOSE)).context(format!(
+                    "In check_curve_25519_purposes: Purpose {:?} not supported for Curve 25519.",
+                    v
+                ))
+            }
+        }
+    }
+    if signing && agreement {
+        return Err(Error::Km(ErrorCode::INCOMPATIBLE_PURPOSE)).context(
+            "In check_curve_25519_purposes: \
+            A Curve 25519 key cannot be used for both signing and key agreement.",
+        );
+    }
+    Ok(())
+}
+
+/// Returns the system properties that hold the value of the given attestation ID as it was
+/// provisioned into KeyMint, in order of precedence, or None if the ID is not recorded in a
+/// system property. The `_for_attestation` properties are set on devices that attest other
+/// product IDs than they report in the regular properties.
+fn attestation_id_properties(tag: Tag) -> Option<&'static [&'static str]> {
+    match tag {
+        Tag::ATTESTATION_ID_BRAND => {
+            Some(&["ro.product.brand_for_attestation", "ro.product.vendor.brand"])
+        }
+        Tag::ATTESTATION_ID_DEVICE => {
+            Some(&["ro.product.device_for_attestation", "ro.product.vendor.device"])
+        }
+        Tag::ATTESTATION_ID_PRODUCT => {
+            Some(&["ro.product.name_for_attestation", "ro.product.vendor.name"])
+        }
+        Tag::ATTESTATION_ID_MANUFACTURER => {
+            Some(&["ro.product.manufacturer_for_attestation", "ro.product.vendor.manufacturer"])
+        }
+        Tag::ATTESTATION_ID_MODEL => {
+            Some(&["ro.product.model_for_attestation", "ro.product.vendor.model"])
+        }
+        Tag::ATTESTATION_ID_SERIAL => Some(&["ro.serialno"]),
+        _ => None,
+    }
+}
+
+/// Reads the provisioned value of the given attestation ID, see `attestation_id_properties`.
+/// Returns None if none of the properties is set or can be read.
+fn read_provisioned_attestation_id(tag: Tag) -> Option<String> {
+    attestation_id_properties(tag)?.iter().find_map(|name| {
+        rustutils::system_properties::read(name).ok().flatten().filter(|v| !v.is_empty())
+    })
+}
+
+/// Returns true if `id` is well formed for the given device identifier tag. IMEIs consist of
+/// 15 decimal digits and MEIDs of 14 hexadecimal digits.
+fn is_well_formed_device_id(tag: Tag, id: &[u8]) -> bool {
+    match tag {
+        Tag::ATTESTATION_ID_IMEI => id.len() == 15 && id.iter().all(u8::is_ascii_digit),
+        Tag::ATTESTATION_ID_MEID => id.len() == 14 && id.iter().all(u8::is_ascii_hexdigit),
+        _ => !id.is_empty(),
+    }
+}
+
+const BOOT_ID_PATH: &str = "/proc/sys/kernel/random/boot_id";
+
+/// Returns true if the tag requests attestation of one of the device's hardware identifiers,
+/// i.e., its serial number, IMEI, or MEID.
+fn is_device_hardware_id_tag(tag: Tag) -> bool {
+    is_device_id_attestation_tag(tag) && tag != Tag::DEVICE_UNIQUE_ATTESTATION
+}
+
+/// Returns true if the tag requests attestation of a device hardware identifier or of one of
+/// the product identifiers, i.e., the brand, device, product, manufacturer, or model.
+fn is_attestation_id_tag(tag: Tag) -> bool {
+    is_device_hardware_id_tag(tag) || attestation_id_properties(tag).is_some()
+}
+
+/// Checks the attestation IDs that the caller asks to attest before they are passed to
+/// KeyMint. IMEIs and MEIDs must be well formed, see `is_well_formed_device_id`, and all other
+/// IDs must be non-empty. IDs that are recorded in system properties, see
+/// `attestation_id_properties`, must match the provisioned value if the latter can be read;
+/// otherwise KeyMint has the final say. Returns `Error::Km(CANNOT_ATTEST_IDS)` if the device
+/// cannot attest the requested IDs.
+pub fn check_device_id_attestation_params(params: &[KmKeyParameter]) -> Result<()> {
+    for kp in params.iter().filter(|kp| is_attestation_id_tag(kp.tag)) {
+        let id = match &kp.value {
+            KeyParameterValue::Blob(id) => id,
+            v => {
+                return Err(Error::Km(ErrorCode::INVALID_ARGUMENT)).context(format!(
+                    "In check_device_id_attestation_params: Invalid value {:?} for tag {:?}.",
+                    v, kp.tag
+                ))
+            }
+        };
+        if !is_well_formed_device_id(kp.tag, id) {
+            return Err(Error::Km(ErrorCode::CANNOT_ATTEST_IDS)).context(format!(
+                "In check_device_id_attestation_params: Malformed identifier for tag {:?}.",
+                kp.tag
+            ));
+        }
+        if let Some(provisioned) = read_provisioned_attestation_id(kp.tag) {
+            if provisioned.as_bytes() != id.as_slice() {
+                return Err(Error::Km(ErrorCode::CANNOT_ATTEST_IDS)).context(format!(
+                    "In check_device_id_attestation_params: {:?} does not match the device.",
+                    kp.tag
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Maps the error of a key creation request that attests device identifiers to
+/// `Error::Km(CANNOT_ATTEST_IDS)` if the backend reported a generic failure. Backends that have
+/// no or different identifiers provisioned tend to fail with unspecific errors, which leaves
+/// the caller unable to tell an unattestable identifier from any other failure.
+pub fn map_device_id_attestation_error(
+    params: &[KmKeyParameter],
+    e: anyhow::Error,
+) -> anyhow::Error {
+    if !params.iter().any(|kp| is_attestation_id_tag(kp.tag)) {
+        return e;
+    }
+    match e.root_cause().downcast_ref::<Error>() {
+        Some(Error::Km(ErrorCode::UNKNOWN_ERROR))
+        | Some(Error::Km(ErrorCode::INVALID_TAG))
+        | Some(Error::Km(ErrorCode::UNSUPPORTED_TAG))
+        | Some(Error::Rc(ResponseCode::SYSTEM_ERROR)) => {
+            anyhow::Error::new(Error::Km(ErrorCode::CANNOT_ATTEST_IDS)).context(format!(
+                "In map_device_id_attestation_error: Device ID attestation failed: {:?}",
+                e
+            ))
+        }
+        _ => e,
+    }
+}
+
 /// This function checks whether the calling app has the Android permissions needed to attest device
 /// identifiers. It throws an error if the permissions cannot be verified or if the caller doesn't
 /// have the right permissions. Otherwise it returns silently.
 pub fn check_device_attestation_permissions() -> anyhow::Result<()> {
-    check_android_permission("android.permission.READ_PRIVILEGED_PHONE_STATE")
+    check_android_permission(
+        "android.permission.READ_PRIVILEGED_PHONE_STATE",
+        Error::Km(ErrorCode::CANNOT_ATTEST_IDS),
+    )
 }
 
 /// This function checks whether the calling app has the Android permissions needed to attest the
 /// device-unique identifier. It throws an error if the permissions cannot be verified or if the
 /// caller doesn't have the right permissions. Otherwise it returns silently.
 pub fn check_unique_id_attestation_permissions() -> anyhow::Result<()> {
-    check_android_permission("android.permission.REQUEST_UNIQUE_ID_ATTESTATION")
+    check_android_permission(
+        "android.permission.REQUEST_UNIQUE_ID_ATTESTATION",
+        Error::Km(ErrorCode::CANNOT_ATTEST_IDS),
+    )
+}
+
+/// This function checks whether the calling app has the Android permission needed to dump
+/// Keystore's state, which includes per uid information. It throws an error if the permission
+/// cannot be verified or if the caller doesn't have the permission. Otherwise it returns silently.
+pub fn check_dump_permission() -> anyhow::Result<()> {
+    check_android_permission("android.permission.DUMP", Error::Rc(ResponseCode::PERMISSION_DENIED))
 }
 
-fn check_android_permission(permission: &str) -> anyhow::Result<()> {
+/// Checks the given Android permission of the caller, returning `err` if it is not granted.
+fn check_android_permission(permission: &str, err: Error) -> anyhow::Result<()> {
     let permission_controller: Strong<dyn IPermissionController::IPermissionController> =
         binder::get_interface("permission")?;
 
     let binder_result = {
-        let _wp = watchdog::watch_millis(
-            "In check_device_attestation_permissions: calling checkPermission.",
-            500,
-        );
+        let _wp =
+            watchdog::watch_millis("In check_android_permission: calling checkPermission.", 500);
         permission_controller.checkPermission(
             permission,
             ThreadState::get_calling_pid(),
@@ -136,12 +358,12 @@ fn check_android_permission(permission: &str) -> anyhow::Result<()> {
         )
     };
     let has_permissions = map_binder_status(binder_result)
-        .context("In check_device_attestation_permissions: checkPermission failed")?;
+        .context("In check_android_permission: checkPermission failed")?;
     match has_permissions {
         true => Ok(()),
-        false => Err(Error::Km(ErrorCode::CANNOT_ATTEST_IDS)).context(concat!(
-            "In check_device_attestation_permissions: ",
-            "caller does not have the permission to attest device IDs"
+        false => Err(err).context(format!(
+            "In check_android_permission: caller does not have the permission {}",
+            permission
         )),
     }
 }
@@ -212,17 +434,25 @@ pub fn key_parameters_to_authorizations(
     parameters.into_iter().map(|p| p.into_authorization()).collect()
 }
 
-/// This returns the current time (in milliseconds) as an instance of a monotonic clock,
-/// by invoking the system call since Rust does not support getting monotonic time instance
-/// as an integer.
+/// This returns the current time since boot (in milliseconds) including the time the device
+/// was suspended, by invoking the system call since Rust does not support getting the boot
+/// time as an integer.
 pub fn get_current_time_in_milliseconds() -> i64 {
     let mut current_time = libc::timespec { tv_sec: 0, tv_nsec: 0 };
-    // Following unsafe block includes one system call to get monotonic time.
+    // Following unsafe block includes one system call to get the boot time.
     // Therefore, it is not considered harmful.
-    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC_RAW, &mut current_time) };
+    unsafe { libc::clock_gettime(libc::CLOCK_BOOTTIME, &mut current_time) };
     current_time.tv_sec as i64 * 1000 + (current_time.tv_nsec as i64 / 1_000_000)
 }
 
+/// Returns the boot id of the running kernel. It changes on every boot, but not when Keystore
+/// is restarted.
+pub fn get_boot_id() -> Result<String> {
+    let boot_id =
+        std::fs::read_to_string(BOOT_ID_PATH).context("In get_boot_id: Failed to read boot id.")?;
+    Ok(boot_id.trim().to_string())
+}
+
 /// Converts a response code as returned by the Android Protected Confirmation HIDL compatibility
 /// module (keystore2_apc_compat) into a ResponseCode as defined by the APC AIDL
 /// (android.security.apc) spec.
@@ -331,7 +561,7 @@ pub trait AesGcmKey {
     fn key(&self) -> &[u8];
 }
 
-impl<T: AesGcmKey> AesGcm for T {
+impl<T: AesGcmKey + ?Sized> AesGcm for T {
     fn decrypt(&self, data: &[u8], iv: &[u8], tag: &[u8]) -> Result<ZVec> {
         aes_gcm_decrypt(data, iv, tag, self.key())
             .context("In AesGcm<T>::decrypt: Decryption failed")
@@ -377,4 +607,106 @@ mod tests {
             }
         })
     }
+
+    fn curve_25519_params(purposes: &[KeyPurpose]) -> Vec<KmKeyParameter> {
+        let mut params = vec![KmKeyParameter {
+            tag: Tag::EC_CURVE,
+            value: KeyParameterValue::EcCurve(EcCurve::CURVE_25519),
+        }];
+        params.extend(purposes.iter().map(|p| KmKeyParameter {
+            tag: Tag::PURPOSE,
+            value: KeyParameterValue::KeyPurpose(*p),
+        }));
+        params
+    }
+
+    fn assert_incompatible_purpose(result: Result<()>) {
+        assert_eq!(
+            Some(&Error::Km(ErrorCode::INCOMPATIBLE_PURPOSE)),
+            result.unwrap_err().root_cause().downcast_ref::<Error>()
+        );
+    }
+
+    #[test]
+    fn check_curve_25519_purposes_test() -> Result<()> {
+        check_curve_25519_purposes(&curve_25519_params(&[KeyPurpose::SIGN]))?;
+        check_curve_25519_purposes(&curve_25519_params(&[
+            KeyPurpose::SIGN,
+            KeyPurpose::ATTEST_KEY,
+        ]))?;
+        check_curve_25519_purposes(&curve_25519_params(&[KeyPurpose::AGREE_KEY]))?;
+        assert_incompatible_purpose(check_curve_25519_purposes(&curve_25519_params(&[
+            KeyPurpose::SIGN,
+            KeyPurpose::AGREE_KEY,
+        ])));
+        assert_incompatible_purpose(check_curve_25519_purposes(&curve_25519_params(&[
+            KeyPurpose::ENCRYPT,
+        ])));
+
+        // Purposes of keys on other curves are left to KeyMint.
+        let mut params = curve_25519_params(&[KeyPurpose::SIGN, KeyPurpose::AGREE_KEY]);
+        params[0].value = KeyParameterValue::EcCurve(EcCurve::P_256);
+        check_curve_25519_purposes(&params)
+    }
+
+    fn id_params(tag: Tag, id: &[u8]) -> Vec<KmKeyParameter> {
+        vec![KmKeyParameter { tag, value: KeyParameterValue::Blob(id.to_vec()) }]
+    }
+
+    fn root_error(e: &anyhow::Error) -> Option<&Error> {
+        e.root_cause().downcast_ref::<Error>()
+    }
+
+    #[test]
+    fn check_device_id_attestation_params_test() -> Result<()> {
+        let imei = b"490154203237518";
+        let meid = b"A10000009296F2";
+        check_device_id_attestation_params(&id_params(Tag::ATTESTATION_ID_IMEI, imei))?;
+        check_device_id_attestation_params(&id_params(Tag::ATTESTATION_ID_MEID, meid))?;
+        for (tag, id) in [
+            (Tag::ATTESTATION_ID_IMEI, &b""[..]),
+            (Tag::ATTESTATION_ID_IMEI, b"1234"),
+            (Tag::ATTESTATION_ID_IMEI, b"49015420323751X"),
+            (Tag::ATTESTATION_ID_MEID, b""),
+            (Tag::ATTESTATION_ID_MEID, b"A10000009296G2"),
+            (Tag::ATTESTATION_ID_SERIAL, b""),
+            (Tag::ATTESTATION_ID_BRAND, b""),
+        ] {
+            let e = check_device_id_attestation_params(&id_params(tag, id)).unwrap_err();
+            assert_eq!(Some(&Error::Km(ErrorCode::CANNOT_ATTEST_IDS)), root_error(&e));
+        }
+
+        // Product identifiers must match the provisioned values if those can be read.
+        let brand = read_provisioned_attestation_id(Tag::ATTESTATION_ID_BRAND);
+        let result = check_device_id_attestation_params(&id_params(
+            Tag::ATTESTATION_ID_BRAND,
+            b"not the brand of this device",
+        ));
+        assert_eq!(brand.is_some(), result.is_err());
+        if let Some(brand) = brand {
+            check_device_id_attestation_params(&id_params(
+                Tag::ATTESTATION_ID_BRAND,
+                brand.as_bytes(),
+            ))?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn map_device_id_attestation_error_test() {
+        let params = id_params(Tag::ATTESTATION_ID_IMEI, b"1234");
+        let e =
+            map_device_id_attestation_error(&params, Error::Km(ErrorCode::UNKNOWN_ERROR).into());
+        assert_eq!(Some(&Error::Km(ErrorCode::CANNOT_ATTEST_IDS)), root_error(&e));
+        let e = map_device_id_attestation_error(&params, Error::sys().into());
+        assert_eq!(Some(&Error::Km(ErrorCode::CANNOT_ATTEST_IDS)), root_error(&e));
+
+        // Specific errors are passed through.
+        let e = map_device_id_attestation_error(&params, Error::perm().into());
+        assert_eq!(Some(&Error::perm()), root_error(&e));
+
+        // Requests without device identifiers are not affected.
+        let e = map_device_id_attestation_error(&[], Error::Km(ErrorCode::UNKNOWN_ERROR).into());
+        assert_eq!(Some(&Error::Km(ErrorCode::UNKNOWN_ERROR)), root_error(&e));
+    }
 }